@@ -0,0 +1,100 @@
+//! Encoding of a large `get_entities` response, in each encoding with and
+//! without compression. Sizes of the bodies are printed before timing.
+//!
+//! Run with `cargo bench -p api --bench encoding`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+use api::{
+    model::Entities,
+    rpc::{Encoding, Response},
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use flate2::{write::GzEncoder, Compression};
+use isolanguage_1::LanguageCode;
+use mongodb::bson::Uuid;
+use sg_core::models::{Entity, Meta, Name, Profile};
+
+/// Number of vtbs in the response, as in a page of a busy deployment.
+const ENTITIES: usize = 5000;
+
+fn entities() -> Entities {
+    let vtbs = (0..ENTITIES)
+        .map(|i| Entity {
+            id: Uuid::new(),
+            meta: Meta {
+                name: Name {
+                    name: HashMap::from([
+                        (LanguageCode::En, format!("Vtuber {i}")),
+                        (LanguageCode::Ja, format!("ブイチューバー {i}")),
+                        (LanguageCode::Zh, format!("虚拟主播 {i}")),
+                    ]),
+                    default_language: LanguageCode::Ja,
+                    aliases: vec![format!("vtb{i}")],
+                },
+                group: Some(Uuid::new()),
+                profiles: BTreeMap::from([(
+                    "youtube".to_owned(),
+                    Profile {
+                        name: format!("Vtuber {i} Ch."),
+                        avatar: format!("https://yt3.ggpht.com/avatar/{i}").parse().ok(),
+                    },
+                )]),
+                accounts: BTreeMap::from([("youtube".to_owned(), format!("UC{i:022}"))]),
+            },
+            tasks: vec![Uuid::new(), Uuid::new()],
+            auto_tasks: vec![],
+        })
+        .collect();
+    Entities::new(vtbs, vec![], None, None)
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Brotli at its default quality and window size.
+fn brotli(body: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+    encoder.write_all(body).unwrap();
+    drop(encoder);
+    compressed
+}
+
+fn bench_encoding(c: &mut Criterion) {
+    let response = entities().packed();
+
+    for encoding in [Encoding::Json, Encoding::MessagePack] {
+        let body = response.to_bytes(encoding);
+        println!(
+            "{encoding:?}: {} bytes, {} gzipped, {} with brotli",
+            body.len(),
+            gzip(&body).len(),
+            brotli(&body).len()
+        );
+    }
+
+    let mut group = c.benchmark_group("get_entities");
+    for encoding in [Encoding::Json, Encoding::MessagePack] {
+        group.bench_function(BenchmarkId::new("encode", format!("{encoding:?}")), |b| {
+            b.iter(|| response.to_bytes(encoding));
+        });
+        let body = response.to_bytes(encoding);
+        group.bench_function(BenchmarkId::new("gzip", format!("{encoding:?}")), |b| {
+            b.iter(|| gzip(&body));
+        });
+        group.bench_function(BenchmarkId::new("brotli", format!("{encoding:?}")), |b| {
+            b.iter(|| brotli(&body));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encoding);
+criterion_main!(benches);
@@ -59,7 +59,10 @@
 //! [1600] 105.987ms / 118.933ms / 96.213ms
 //! ```
 
-use std::{collections::HashMap, env};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+};
 
 use color_eyre::Result;
 use fake::{faker::name::en::Name as FakeName, Fake, Faker};
@@ -81,6 +84,8 @@ const KINDS: &[&str] = &[
     "bilibili/forward_dynamic",
     "youtube/new_video",
     "youtube/live_start",
+    "youtube/members_live_start",
+    "youtube/premiere_start",
     "youtube/broadcast_scheduled",
     "youtube/30_min_before_broadcast",
 ];
@@ -93,6 +98,11 @@ fn gen_user(event_filter: EventFilter) -> User {
         id: id.into(),
         name: FakeName().fake(),
         event_filter,
+        quiet_hours: None,
+        max_per_hour: None,
+        link_id: None,
+        disabled: false,
+        token_generation: 0,
         avatar: "https://placekitten.com/114/514".parse().ok(),
         im: ["tg", "qq"].choose(&mut rng).unwrap().to_owned().to_owned(),
         im_payload: Faker.fake(),
@@ -105,12 +115,19 @@ fn gen_entity() -> Entity {
     let name = Name {
         name: HashMap::from_iter([(en, FakeName().fake())]),
         default_language: en,
+        aliases: vec![],
+    };
+    let meta = Meta {
+        name,
+        group: None,
+        profiles: BTreeMap::new(),
+        accounts: BTreeMap::new(),
     };
-    let meta = Meta { name, group: None };
     Entity {
         id: id.into(),
         meta,
         tasks: vec![],
+        auto_tasks: vec![],
     }
 }
 
@@ -125,7 +142,14 @@ fn gen_ef(rng: &mut ThreadRng, entities: &[uuid::Uuid]) -> EventFilter {
         .choose_multiple(rng, entities_len)
         .map(|x| (*x).into())
         .collect();
-    EventFilter { kinds, entities }
+    EventFilter {
+        kinds,
+        entities,
+        expiry: Vec::new(),
+        overrides: Vec::new(),
+        exclusions: Vec::new(),
+        language: None,
+    }
 }
 
 #[tokio::main]
@@ -1,13 +1,10 @@
-use tracing::metadata::LevelFilter;
+use sg_core::utils::init_tracing;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_max_level(LevelFilter::DEBUG)
-        .init();
+    let _guard = init_tracing("api", EnvFilter::new("debug"))?;
 
     api::server::serve().await?;
 
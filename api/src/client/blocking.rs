@@ -1,19 +1,40 @@
 //! Blocking version of the client.
 
+use std::{path::PathBuf, sync::Arc, time::Instant};
+
 use reqwest::{IntoUrl, Url};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    client::{Result, Shim},
-    rpc::{ApiResult, Request, ResponseObject},
+    client::{
+        parse_response,
+        response_encoding,
+        Error,
+        Interceptor,
+        Interceptors,
+        Outcome,
+        Result,
+        RetryPolicy,
+        Session,
+        SessionFile,
+        SharedAuth,
+    },
+    model::Login,
+    rpc::{Encoding, ErrorCode, Request},
 };
 
 /// Blocking version of the client to invoke API methods.
+///
+/// Clones of a client share its token and credentials.
 #[derive(Clone, Debug)]
 pub struct Client {
     client: reqwest::blocking::Client,
     url: Url,
-    token: Option<String>,
+    auth: Arc<SharedAuth>,
+    retry: RetryPolicy,
+    interceptors: Interceptors,
+    encoding: Encoding,
+    session: Option<SessionFile>,
 }
 
 impl Client {
@@ -37,14 +58,65 @@ impl Client {
     /// Fails on invalid URL.
     pub fn with_client(client: reqwest::blocking::Client, url: impl IntoUrl) -> Result<Self> {
         Ok(Self {
-            token: None,
             client,
             url: url.into_url()?,
+            auth: Arc::default(),
+            retry: RetryPolicy::default(),
+            interceptors: Interceptors::default(),
+            encoding: Encoding::default(),
+            session: None,
         })
     }
 
+    /// Set the policy of retrying requests that failed with a transient error.
+    #[must_use]
+    pub const fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Set the encoding responses are asked for. Defaults to JSON.
+    ///
+    /// Responses are compressed by the server regardless, and decompressed by
+    /// the client.
+    #[must_use]
+    pub const fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Add an interceptor, called after those added before.
+    #[must_use]
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Persist the session to the file at `path`, readable and writable by
+    /// its owner only, and reuse the session in it unless it has expired.
+    ///
+    /// The session is saved on [`login_and_store`](Self::login_and_store),
+    /// and removed on [`logout`](Self::logout). Credentials are not
+    /// persisted, so a client reusing a session can't login again once it
+    /// expires.
+    ///
+    /// # Errors
+    /// Fails if the file exists but can't be read.
+    pub fn with_session_file(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let file = SessionFile::new(path);
+        if let Some(session) = file.load()? {
+            self.auth.set_token(session.token);
+        }
+        self.session = Some(file);
+        Ok(self)
+    }
+
     /// Invoke an RPC method.
     ///
+    /// If the token has expired and credentials are stored by
+    /// [`login_and_store`](Self::login_and_store), login again and retry once.
+    /// Transient errors are retried according to the [`RetryPolicy`].
+    ///
     /// # Errors
     /// Fails on invalid `Request` method, bad request body, network issue or
     /// bad response.
@@ -53,46 +125,118 @@ impl Client {
         R: Request + Serialize,
         R::Res: DeserializeOwned,
     {
-        let mut req = self
-            .client
-            .post(self.url.join(R::METHOD)?)
-            .body(serde_json::to_vec(&req)?)
-            .header("Content-Type", "application/json");
+        let mut relogged = false;
+        self.retry.run_blocking_if(
+            || self.send_or_relogin(req, &mut relogged),
+            Error::is_transient,
+        )
+    }
 
-        if let Some(token) = &self.token {
-            req = req.bearer_auth(token);
+    /// Send a request. If the token has expired, login again with stored
+    /// credentials and send it once more, unless `relogged` already.
+    fn send_or_relogin<R>(&self, req: &R, relogged: &mut bool) -> Result<R::Res>
+    where
+        R: Request + Serialize,
+        R::Res: DeserializeOwned,
+    {
+        match self.send(req) {
+            Err(error) if !*relogged && error.matches_api_code(ErrorCode::BadToken) => {
+                let Some((username, password)) = self.auth.credentials() else {
+                    return Err(error);
+                };
+                *relogged = true;
+                self.login_and_store(username, password.0)?;
+                self.send(req)
+            }
+            result => result,
         }
+    }
 
-        let resp: ApiResult<_> = req
-            .send()?
-            .json::<ResponseObject<Shim<R::Res>>>()?
-            .data
-            .into();
+    fn send<R>(&self, req: &R) -> Result<R::Res>
+    where
+        R: Request + Serialize,
+        R::Res: DeserializeOwned,
+    {
+        let url = self.url.join(R::METHOD)?;
+        let body = serde_json::to_vec(&req)?;
+        let token = self.auth.token();
+        let headers = self
+            .interceptors
+            .before(R::METHOD, token.as_deref(), self.encoding);
 
-        Ok(resp?)
+        let start = Instant::now();
+        let mut status = None;
+        let result = self
+            .client
+            .post(url)
+            .headers(headers)
+            .body(body)
+            .send()
+            .map_err(Error::from)
+            .and_then(|resp| {
+                status = Some(resp.status());
+                let encoding = response_encoding(resp.headers());
+                parse_response(resp.status(), encoding, &resp.bytes()?)
+            });
+        self.interceptors.after(
+            R::METHOD,
+            &Outcome {
+                status,
+                elapsed: start.elapsed(),
+                error: result.as_ref().err(),
+            },
+        );
+        result
     }
 
-    pub fn set_token(&mut self, token: impl Into<String>) -> Option<String> {
-        self.token.replace(token.into())
+    pub fn set_token(&self, token: impl Into<String>) -> Option<String> {
+        self.auth.set_token(token.into())
     }
 
     #[must_use]
-    pub fn token(&self) -> Option<&str> {
-        self.token.as_deref()
+    pub fn token(&self) -> Option<String> {
+        self.auth.token()
     }
 
     /// Login and store the credential for future use.
     /// Returns `Some(Token)` if there's already one stored.
     ///
+    /// Username and password are kept to login again once the token expires.
+    /// The token is persisted if a session file is set by
+    /// [`with_session_file`](Self::with_session_file).
+    /// Accounts with TOTP enabled can't login this way; use `Login` directly
+    /// and [`set_token`](Self::set_token) instead.
+    ///
     /// # Errors
     /// Fails on invalid `Login` method, bad request body, network issue or bad
     /// response.
     pub fn login_and_store(
-        &mut self,
+        &self,
         username: impl Into<String>,
         password: impl Into<String>,
     ) -> Result<Option<String>> {
-        let token = self.login(username.into(), password.into())?;
-        Ok(self.token.replace(token.token))
+        let login = Login {
+            username: username.into(),
+            password: password.into(),
+            totp: None,
+        };
+        let token = self.send(&login)?;
+        if let Some(file) = &self.session {
+            file.save(&Session {
+                token: token.token.clone(),
+                valid_until: token.valid_until,
+            })?;
+        }
+        Ok(self.auth.store(token.token, login.username, login.password))
+    }
+
+    /// Forget the token and credentials, and remove the persisted session if
+    /// any. The token itself stays valid until it expires.
+    ///
+    /// # Errors
+    /// Fails if the session file can't be removed.
+    pub fn logout(&self) -> Result<()> {
+        self.auth.clear();
+        self.session.as_ref().map_or(Ok(()), SessionFile::remove)
     }
 }
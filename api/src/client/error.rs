@@ -1,16 +1,22 @@
 use http::StatusCode;
 use thiserror::Error;
 
+use crate::rpc::ApiErrorKind;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
     #[error("Serde Json error: {0}")]
     SerdeJson(#[from] serde_json::Error),
+    #[error("MessagePack error: {0}")]
+    MessagePack(#[from] rmp_serde::decode::Error),
     #[error("Unable to parse url: {0}")]
     Url(#[from] url::ParseError),
     #[error("API error: {0}")]
     Api(#[from] crate::rpc::ApiError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 impl Error {
@@ -34,6 +40,48 @@ impl Error {
             .map_or(false, |api_error| api_error.matches(msg))
     }
 
+    /// Returns the code of the API error, if any.
+    #[must_use]
+    pub const fn api_code(&self) -> Option<crate::rpc::ErrorCode> {
+        if let Error::Api(api_error) = self {
+            Some(api_error.code())
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
+    pub fn matches_api_code(&self, code: crate::rpc::ErrorCode) -> bool {
+        self.api_code() == Some(code)
+    }
+
+    /// Returns the category of the API error, if any.
+    #[must_use]
+    pub fn api_kind(&self) -> Option<ApiErrorKind> {
+        self.as_api().map(crate::rpc::ApiError::kind)
+    }
+
+    #[must_use]
+    pub fn matches_api_kind(&self, kind: ApiErrorKind) -> bool {
+        self.api_kind() == Some(kind)
+    }
+
+    /// Whether the request may succeed if retried, i.e. it failed with a
+    /// network error or a server error.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Reqwest(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.is_request()
+                    || e.status().is_some_and(|status| status.is_server_error())
+            }
+            Self::Api(api_error) => api_error.status().is_server_error(),
+            Self::SerdeJson(_) | Self::MessagePack(_) | Self::Url(_) | Self::Io(_) => false,
+        }
+    }
+
     #[must_use]
     pub fn matches_api_status(&self, status: impl TryInto<StatusCode>) -> bool {
         self.as_api()
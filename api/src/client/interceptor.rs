@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use http::{HeaderMap, StatusCode};
+use tracing::{debug, warn};
+
+use crate::client::Error;
+
+/// Hook into requests sent by a client, e.g. to log, collect metrics or
+/// inject headers.
+///
+/// Hooks are called on each attempt, so a request that is retried or sent
+/// again after login is seen more than once.
+pub trait Interceptor: Send + Sync {
+    /// Called before a request is sent with its headers, which already include
+    /// the token of the client. Headers set here are sent with the request.
+    fn before(&self, _method: &'static str, _headers: &mut HeaderMap) {}
+
+    /// Called after a request is done, successfully or not.
+    fn after(&self, _method: &'static str, _outcome: &Outcome<'_>) {}
+}
+
+/// Outcome of a request, passed to [`Interceptor::after`].
+#[derive(Debug)]
+pub struct Outcome<'a> {
+    /// Status of the response, or `None` if no response is received.
+    pub status: Option<StatusCode>,
+    /// Time taken from sending the request to parsing the response.
+    pub elapsed: Duration,
+    /// Error of the request, if it failed.
+    pub error: Option<&'a Error>,
+}
+
+/// Logs requests with `tracing`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogInterceptor;
+
+impl Interceptor for LogInterceptor {
+    fn after(&self, method: &'static str, outcome: &Outcome<'_>) {
+        let Outcome { status, elapsed, error } = outcome;
+        match error {
+            None => debug!(method, ?status, ?elapsed, "API request done"),
+            Some(error) => warn!(method, ?status, ?elapsed, %error, "API request failed"),
+        }
+    }
+}
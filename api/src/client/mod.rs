@@ -3,11 +3,30 @@
 //! This module requires either or both of `client` and `client_blocking`
 //! feature to use.
 
-use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Formatter},
+    sync::{Arc, PoisonError, RwLock},
+};
 
-use crate::rpc::{ApiError, ApiResult};
+use http::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap,
+    HeaderValue,
+    StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sg_core::utils::Redacted;
 
-mod_use::mod_use![error];
+use crate::rpc::{ApiError, ApiResult, Encoding, ResponseObject};
+
+/// Policy of retrying requests that failed with a transient error, i.e. a
+/// network error or a 5xx response.
+///
+/// Requests are not retried by default. Note that a retried request may have
+/// taken effect on the server before failing.
+pub use sg_core::utils::RetryPolicy;
+
+mod_use::mod_use![error, interceptor, session];
 
 #[cfg(feature = "client")]
 mod non_blocking;
@@ -32,3 +51,213 @@ impl<T> From<Shim<T>> for ApiResult<T> {
         }
     }
 }
+
+/// Credential of a client, shared by its clones.
+#[derive(Debug, Default)]
+struct Auth {
+    token: Option<String>,
+    /// Username and password to login again with once the token expires.
+    credentials: Option<(String, Redacted<String>)>,
+}
+
+#[derive(Debug, Default)]
+struct SharedAuth(RwLock<Auth>);
+
+impl SharedAuth {
+    fn token(&self) -> Option<String> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner).token.clone()
+    }
+
+    fn set_token(&self, token: String) -> Option<String> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner).token.replace(token)
+    }
+
+    fn credentials(&self) -> Option<(String, Redacted<String>)> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner).credentials.clone()
+    }
+
+    fn store(&self, token: String, username: String, password: String) -> Option<String> {
+        let mut auth = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        auth.credentials = Some((username, Redacted(password)));
+        auth.token.replace(token)
+    }
+
+    fn clear(&self) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Auth::default();
+    }
+}
+
+/// Interceptors of a client, called in the order they are added.
+#[derive(Clone, Default)]
+struct Interceptors(Vec<Arc<dyn Interceptor>>);
+
+impl Interceptors {
+    fn push(&mut self, interceptor: impl Interceptor + 'static) {
+        self.0.push(Arc::new(interceptor));
+    }
+
+    /// Headers of a request accepting responses in `encoding`, as modified by
+    /// the interceptors.
+    fn before(&self, method: &'static str, token: Option<&str>, encoding: Encoding) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(ACCEPT, HeaderValue::from_static(encoding.content_type()));
+        let bearer = token.map(|token| HeaderValue::try_from(format!("Bearer {token}")));
+        if let Some(Ok(mut value)) = bearer {
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        for interceptor in &self.0 {
+            interceptor.before(method, &mut headers);
+        }
+        headers
+    }
+
+    fn after(&self, method: &'static str, outcome: &Outcome<'_>) {
+        for interceptor in &self.0 {
+            interceptor.after(method, outcome);
+        }
+    }
+}
+
+impl Debug for Interceptors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Interceptors").field(&self.0.len()).finish()
+    }
+}
+
+/// Encoding of a response by its `Content-Type`, JSON if unknown.
+fn response_encoding(headers: &HeaderMap) -> Encoding {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(Encoding::of_content_type)
+        .unwrap_or_default()
+}
+
+/// Parse the body of a response. A server error without a valid body, e.g.
+/// from a gateway, is reported as an API error of its status.
+fn parse_response<T: DeserializeOwned>(
+    status: StatusCode,
+    encoding: Encoding,
+    body: &[u8],
+) -> Result<T> {
+    let resp: Result<ResponseObject<Shim<T>>> = match encoding {
+        Encoding::Json => serde_json::from_slice(body).map_err(Into::into),
+        Encoding::MessagePack => rmp_serde::from_slice(body).map_err(Into::into),
+    };
+    match resp {
+        Ok(resp) => Ok(ApiResult::from(resp.data)?),
+        Err(_) if status.is_server_error() => Err(ApiError::new(status).into()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use http::{
+        header::{ACCEPT, AUTHORIZATION},
+        HeaderMap,
+        HeaderValue,
+        StatusCode,
+    };
+
+    use crate::{
+        client::{parse_response, Interceptor, Interceptors, Outcome},
+        model::Token,
+        rpc::{ApiError, Encoding, ErrorCode, Response},
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        failed: AtomicUsize,
+    }
+
+    impl Interceptor for &'static Recorder {
+        fn before(&self, method: &'static str, headers: &mut HeaderMap) {
+            headers.insert("x-method", HeaderValue::from_static(method));
+        }
+
+        fn after(&self, _: &'static str, outcome: &Outcome<'_>) {
+            if outcome.error.is_some() {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let recorder: &'static Recorder = Box::leak(Box::default());
+        let mut interceptors = Interceptors::default();
+        interceptors.push(recorder);
+
+        let headers = interceptors.before("health", Some("token"), Encoding::Json);
+        assert_eq!(headers[AUTHORIZATION], "Bearer token");
+        assert_eq!(headers[ACCEPT], "application/json");
+        assert_eq!(headers["x-method"], "health");
+        let headers = interceptors.before("health", None, Encoding::MessagePack);
+        assert!(!headers.contains_key(AUTHORIZATION));
+        assert_eq!(headers[ACCEPT], "application/msgpack");
+
+        let error = parse_response::<()>(StatusCode::BAD_GATEWAY, Encoding::Json, b"").unwrap_err();
+        interceptors.after(
+            "health",
+            &Outcome {
+                status: Some(StatusCode::BAD_GATEWAY),
+                elapsed: Duration::ZERO,
+                error: Some(&error),
+            },
+        );
+        assert_eq!(recorder.failed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_transient() {
+        let gateway =
+            parse_response::<()>(StatusCode::BAD_GATEWAY, Encoding::Json, b"<html></html>")
+                .unwrap_err();
+        assert!(gateway.is_transient());
+
+        let body = serde_json::json!({
+            "data": { "error": ["Unauthorized"], "code": "bad_token", "status": 401 },
+            "success": false,
+            "time": "2022-01-01T00:00:00Z",
+        });
+        let bad_token = parse_response::<()>(
+            StatusCode::UNAUTHORIZED,
+            Encoding::Json,
+            body.to_string().as_bytes(),
+        )
+        .unwrap_err();
+        assert!(bad_token.matches_api_code(ErrorCode::BadToken));
+        assert!(!bad_token.is_transient());
+
+        let malformed = parse_response::<()>(StatusCode::OK, Encoding::Json, b"{}").unwrap_err();
+        assert!(!malformed.is_transient());
+    }
+
+    #[test]
+    fn test_msgpack() {
+        let token = Token::new(
+            "token".to_owned(),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        );
+        let body = token.packed().to_msgpack_bytes();
+        let parsed = parse_response::<Token>(StatusCode::OK, Encoding::MessagePack, &body).unwrap();
+        assert_eq!(parsed, token);
+
+        let body = ApiError::bad_token().packed().to_msgpack_bytes();
+        let bad_token =
+            parse_response::<()>(StatusCode::UNAUTHORIZED, Encoding::MessagePack, &body)
+                .unwrap_err();
+        assert!(bad_token.matches_api_code(ErrorCode::BadToken));
+
+        let malformed = parse_response::<()>(StatusCode::OK, Encoding::MessagePack, b"{}");
+        assert!(malformed.is_err());
+    }
+}
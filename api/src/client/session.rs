@@ -0,0 +1,123 @@
+//! Sessions persisted to a file, so that tools reuse tokens across runs.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::client::Result;
+
+/// Token of a logged in client, as persisted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    #[serde(with = "humantime_serde")]
+    pub valid_until: SystemTime,
+}
+
+impl Session {
+    #[must_use]
+    pub fn is_expired(&self) -> bool {
+        self.valid_until <= SystemTime::now()
+    }
+}
+
+/// File a session is persisted to, readable and writable by the owner only.
+#[derive(Debug, Clone)]
+pub(crate) struct SessionFile(PathBuf);
+
+impl SessionFile {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self(path.into())
+    }
+
+    /// Session in the file, unless it's missing or expired. A malformed file
+    /// is taken as missing, and overwritten by the next login.
+    pub(crate) fn load(&self) -> Result<Option<Session>> {
+        let bytes = match fs::read(&self.0) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        match serde_json::from_slice::<Session>(&bytes) {
+            Ok(session) => Ok((!session.is_expired()).then_some(session)),
+            Err(error) => {
+                warn!(path = ?self.0, ?error, "Ignoring malformed session file");
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, session: &Session) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.0)?;
+        // The mode only applies to new files.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(&serde_json::to_vec(session)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.0) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::client::{Session, SessionFile};
+
+    #[test]
+    fn test_session_file() {
+        let path = std::env::temp_dir().join(format!("session_{}", rand::random::<u64>()));
+        let file = SessionFile::new(&path);
+        assert_eq!(file.load().unwrap(), None);
+
+        let session = Session {
+            token: "token".to_owned(),
+            valid_until: SystemTime::now() + Duration::from_secs(60),
+        };
+        file.save(&session).unwrap();
+        assert_eq!(file.load().unwrap(), Some(session));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Expired sessions are not reused.
+        let expired = Session {
+            token: "token".to_owned(),
+            valid_until: SystemTime::now() - Duration::from_secs(1),
+        };
+        file.save(&expired).unwrap();
+        assert_eq!(file.load().unwrap(), None);
+
+        std::fs::write(&path, b"{").unwrap();
+        assert_eq!(file.load().unwrap(), None);
+
+        file.remove().unwrap();
+        assert!(!path.exists());
+        file.remove().unwrap();
+    }
+}
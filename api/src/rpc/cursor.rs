@@ -0,0 +1,348 @@
+//! Pagination of list methods.
+//!
+//! Items are sorted by a key made of one or more unique fields, and a page
+//! starts after the [`Cursor`] of the last item of the previous page. Unlike
+//! offsets, cursors are not shifted by items inserted or deleted while a
+//! client is iterating, so no item is returned twice or skipped.
+
+use std::fmt;
+
+use mongodb::{
+    bson::{doc, from_bson, to_document, Bson, Document, Uuid},
+    options::FindOptions,
+};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::rpc::{ApiError, ApiResult};
+
+/// Sort key of a list, as field names of the items in order of precedence.
+pub type SortKey = &'static [&'static str];
+
+/// Sort key of items identified by a unique `id` field.
+pub const BY_ID: SortKey = &["id"];
+
+/// Position in a list, after which the next page starts.
+///
+/// Opaque to clients: it's serialized as the base64 of the BSON-encoded sort
+/// key of the last item of a page.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cursor(Vec<u8>);
+
+impl Cursor {
+    /// Cursor at `item`, keyed by fields in `key`.
+    ///
+    /// # Errors
+    /// Fail if `item` cannot be serialized into a document or misses a field
+    /// in `key`.
+    pub fn at<T: Serialize>(item: &T, key: SortKey) -> ApiResult<Self> {
+        let mut item =
+            to_document(item).map_err(|e| ApiError::internal().explain(e.to_string()))?;
+        let mut doc = Document::new();
+        for field in key {
+            let value = item.remove(*field).ok_or_else(|| {
+                ApiError::internal().explain(format!("Missing sort key field `{field}`"))
+            })?;
+            doc.insert(*field, value);
+        }
+        Ok(Self::from_key(&doc))
+    }
+
+    fn from_key(key: &Document) -> Self {
+        let mut buf = Vec::new();
+        key.to_writer(&mut buf).expect("Writing to a `Vec` never fails");
+        Self(buf)
+    }
+
+    fn key(&self) -> Document {
+        Document::from_reader(self.0.as_slice()).expect("Cursor is validated on construction")
+    }
+
+    /// Filter matching items after the cursor in ascending order of `key`.
+    ///
+    /// # Errors
+    /// Fail if the cursor is not made from `key`, e.g. it's from another list.
+    pub fn filter(&self, key: SortKey) -> ApiResult<Document> {
+        let key_values = self.key();
+        let values = key
+            .iter()
+            .map(|field| key_values.get(field).cloned())
+            .collect::<Option<Vec<_>>>()
+            .filter(|_| key_values.len() == key.len())
+            .ok_or_else(|| ApiError::bad_request("Cursor does not belong to this list"))?;
+
+        // Lexicographic comparison: (a, b) > (x, y) iff a > x or (a = x and b > y)
+        let branches = (0..key.len())
+            .map(|i| {
+                let mut branch: Document = key[..i]
+                    .iter()
+                    .zip(&values)
+                    .map(|(field, value)| ((*field).to_string(), value.clone()))
+                    .collect();
+                branch.insert(key[i], doc! { "$gt": values[i].clone() });
+                Bson::Document(branch)
+            })
+            .collect::<Vec<_>>();
+        Ok(doc! { "$or": branches })
+    }
+
+    /// Id of the item at the cursor, for lists sorted by [`BY_ID`].
+    ///
+    /// # Errors
+    /// Fail if the cursor is not made from [`BY_ID`].
+    pub fn id(&self) -> ApiResult<Uuid> {
+        let mut key = self.key();
+        match key.remove("id") {
+            Some(id) if key.is_empty() => from_bson(id).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| ApiError::bad_request("Cursor does not belong to this list"))
+    }
+}
+
+impl fmt::Debug for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Cursor").field(&self.key()).finish()
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode_config(&self.0, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| de::Error::custom("invalid cursor"))?;
+        Document::from_reader(bytes.as_slice()).map_err(|_| de::Error::custom("invalid cursor"))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl JsonSchema for Cursor {
+    fn schema_name() -> String {
+        String::from("Cursor")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let mut schema = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            ..SchemaObject::default()
+        };
+        schema.metadata().description = Some(String::from("Opaque cursor of a page."));
+        schema.into()
+    }
+}
+
+/// Page requested from a list method.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Page {
+    /// Start after this cursor, or from the beginning if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<Cursor>,
+    /// Maximum number of items in the page, at least 1. All remaining items
+    /// are returned if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+impl Page {
+    /// First page with at most `limit` items.
+    #[must_use]
+    pub const fn first(limit: u32) -> Self {
+        Self {
+            after: None,
+            limit: Some(limit),
+        }
+    }
+
+    /// Page following the one that ended at `cursor`, of the same size.
+    #[must_use]
+    pub const fn next(&self, cursor: Cursor) -> Self {
+        Self {
+            after: Some(cursor),
+            limit: self.limit,
+        }
+    }
+
+    /// Build the filter and options of a query for this page, with `filter`
+    /// applied to the list.
+    ///
+    /// One more item than the limit is queried to tell if this is the last
+    /// page. Pass the result to [`Page::split`].
+    ///
+    /// # Errors
+    /// Fail if the limit is 0 or the cursor is not made from `key`.
+    pub fn query(&self, filter: Document, key: SortKey) -> ApiResult<(Document, FindOptions)> {
+        self.check_limit()?;
+        let filter = match &self.after {
+            Some(cursor) => doc! { "$and": [filter, cursor.filter(key)?] },
+            None => filter,
+        };
+        let sort = key.iter().map(|field| ((*field).to_string(), Bson::Int32(1))).collect();
+        let options = FindOptions::builder()
+            .sort(Some(sort))
+            .limit(self.limit.map(|limit| i64::from(limit) + 1))
+            .build();
+        Ok((filter, options))
+    }
+
+    /// Truncate items queried with [`Page::query`] to this page, and return
+    /// the cursor of the next page if there is one.
+    ///
+    /// # Errors
+    /// Fail if the cursor cannot be made from the last item.
+    pub fn split<T: Serialize>(
+        &self,
+        mut items: Vec<T>,
+        key: SortKey,
+    ) -> ApiResult<(Vec<T>, Option<Cursor>)> {
+        let Some(limit) = self.limit.map(|limit| limit as usize) else {
+            return Ok((items, None));
+        };
+        if items.len() <= limit {
+            return Ok((items, None));
+        }
+        items.truncate(limit);
+        let cursor = items.last().map(|item| Cursor::at(item, key)).transpose()?;
+        Ok((items, cursor))
+    }
+
+    /// Take this page from items kept in memory, sorted by `id` of them like
+    /// [`BY_ID`], and return the cursor of the next page if there is one.
+    ///
+    /// # Errors
+    /// Fail if the limit is 0 or the cursor is not made from [`BY_ID`].
+    pub fn take_by_id<T: Serialize>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        id: impl Fn(&T) -> Uuid,
+    ) -> ApiResult<(Vec<T>, Option<Cursor>)> {
+        self.check_limit()?;
+        let after = self.after.as_ref().map(Cursor::id).transpose()?;
+        let mut items: Vec<_> = items
+            .into_iter()
+            .filter(|item| after.map_or(true, |after| id(item).bytes() > after.bytes()))
+            .collect();
+        items.sort_by_key(|item| id(item).bytes());
+        self.split(items, BY_ID)
+    }
+
+    /// An empty page has no cursor to continue from, and would end the
+    /// iteration of a client as if the list were complete.
+    fn check_limit(&self) -> ApiResult<()> {
+        if self.limit == Some(0) {
+            return Err(ApiError::bad_request("Limit of a page must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mongodb::bson::{doc, Uuid};
+    use serde::Serialize;
+
+    use crate::rpc::{Cursor, Page, BY_ID};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+    struct Item {
+        id: Uuid,
+        time: i64,
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let item = Item {
+            id: Uuid::new(),
+            time: 1,
+        };
+        let cursor = Cursor::at(&item, BY_ID).unwrap();
+        let json = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(serde_json::from_str::<Cursor>(&json).unwrap(), cursor);
+
+        assert!(serde_json::from_str::<Cursor>(r#""not a cursor""#).is_err());
+        assert!(Cursor::at(&item, &["missing"]).is_err());
+    }
+
+    #[test]
+    fn test_cursor_filter() {
+        let id = Uuid::new();
+        let cursor = Cursor::at(&Item { id, time: 1 }, &["time", "id"]).unwrap();
+        assert_eq!(
+            cursor.filter(&["time", "id"]).unwrap(),
+            doc! { "$or": [
+                { "time": { "$gt": 1_i64 } },
+                { "time": 1_i64, "id": { "$gt": id } },
+            ] }
+        );
+        assert!(cursor.filter(BY_ID).is_err());
+    }
+
+    #[test]
+    fn test_page_split() {
+        let items: Vec<_> = (0..3)
+            .map(|time| Item {
+                id: Uuid::new(),
+                time,
+            })
+            .collect();
+
+        let (page, next) = Page::default().split(items.clone(), BY_ID).unwrap();
+        assert_eq!((page.len(), next), (3, None));
+
+        let (page, next) = Page::first(3).split(items.clone(), BY_ID).unwrap();
+        assert_eq!((page.len(), next), (3, None));
+
+        let (page, next) = Page::first(2).split(items.clone(), BY_ID).unwrap();
+        assert_eq!(page, items[..2]);
+        assert_eq!(next, Some(Cursor::at(&items[1], BY_ID).unwrap()));
+
+        let (_, options) = Page::first(2).query(doc! {}, BY_ID).unwrap();
+        assert_eq!(options.limit, Some(3));
+        assert_eq!(options.sort, Some(doc! { "id": 1 }));
+    }
+
+    #[test]
+    fn test_page_take_by_id() {
+        let items: Vec<_> = (0..5)
+            .map(|time| Item {
+                id: Uuid::new(),
+                time,
+            })
+            .collect();
+        let take = |page: &Page| page.take_by_id(items.clone(), |item| item.id).unwrap();
+
+        let page = Page::first(2);
+        let (first, next) = take(&page);
+        let (second, next) = take(&page.next(next.unwrap()));
+        let (third, next) = take(&page.next(next.unwrap()));
+        assert_eq!(next, None);
+
+        let mut sorted = items.clone();
+        sorted.sort_by_key(|item| item.id.bytes());
+        assert_eq!([first, second, third].concat(), sorted);
+
+        let cursor = Cursor::at(&items[0], &["time", "id"]).unwrap();
+        assert!(Page::default()
+            .next(cursor)
+            .take_by_id(items, |item| item.id)
+            .is_err());
+    }
+
+    #[test]
+    fn test_page_zero_limit() {
+        assert!(Page::first(0).query(doc! {}, BY_ID).is_err());
+        assert!(Page::first(0)
+            .take_by_id(Vec::<Item>::new(), |item| item.id)
+            .is_err());
+    }
+}
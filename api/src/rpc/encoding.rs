@@ -0,0 +1,96 @@
+//! Encodings of response bodies, negotiated by the `Accept` header of
+//! requests.
+
+/// Encoding of a response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// JSON, which is used unless another encoding is asked for.
+    #[default]
+    Json,
+    /// MessagePack, with structs encoded as maps of field names.
+    MessagePack,
+}
+
+impl Encoding {
+    /// Media type of bodies in the encoding.
+    #[must_use]
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// Encoding of a body of the given media type, if it's supported.
+    #[must_use]
+    pub fn of_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Self::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Encoding preferred by an `Accept` header, by quality and then by
+    /// order. JSON if no supported encoding is acceptable.
+    #[must_use]
+    pub fn negotiate(accept: &str) -> Self {
+        let mut preferred = (Self::Json, 0.0);
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let encoding = match media_type {
+                "*/*" | "application/*" => Self::Json,
+                _ => match Self::of_content_type(media_type) {
+                    Some(encoding) => encoding,
+                    None => continue,
+                },
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > preferred.1 {
+                preferred = (encoding, quality);
+            }
+        }
+        preferred.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::Encoding;
+
+    #[test]
+    fn test_negotiate() {
+        for (accept, expected) in [
+            ("", Encoding::Json),
+            ("*/*", Encoding::Json),
+            ("text/html", Encoding::Json),
+            ("application/msgpack", Encoding::MessagePack),
+            ("application/x-msgpack, */*;q=0.8", Encoding::MessagePack),
+            ("application/json, application/msgpack", Encoding::Json),
+            ("application/json;q=0.5, application/msgpack", Encoding::MessagePack),
+            ("application/msgpack;q=0", Encoding::Json),
+        ] {
+            assert_eq!(Encoding::negotiate(accept), expected, "{accept}");
+        }
+    }
+
+    #[test]
+    fn test_content_type() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            assert_eq!(
+                Encoding::of_content_type(encoding.content_type()),
+                Some(encoding)
+            );
+        }
+        assert_eq!(
+            Encoding::of_content_type("application/json; charset=utf-8"),
+            Some(Encoding::Json)
+        );
+        assert_eq!(Encoding::of_content_type("text/html"), None);
+    }
+}
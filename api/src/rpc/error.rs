@@ -5,6 +5,7 @@ use std::{
 
 use http::StatusCode;
 use mongodb::bson::Uuid;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::{model::UserQuery, rpc::Response};
@@ -19,7 +20,7 @@ Represents an API Error.
 ## Format into JSON
 ```rust
 # use api::{rpc::{ApiError,Response}, server::ResponseExt}; fn main() {
-let resp = r#"{"data":{"error":["Not Found","Cannot find user with ID `26721d57-37f5-458c-afea-2b18baf34925`"]},"success":false,"time":"2022-01-01T00:00:00.000000000Z"}"#;
+let resp = r#"{"data":{"error":["Not Found","Cannot find user with ID `26721d57-37f5-458c-afea-2b18baf34925`"],"code":"user_not_found","status":404},"success":false,"time":"2022-01-01T00:00:00.000000000Z"}"#;
 let mut resp_obj = ApiError::user_not_found_with_id(
     &mongodb::bson::uuid::Uuid::parse_str("26721d57-37f5-458c-afea-2b18baf34925").unwrap(),
 ).into_packed();
@@ -30,11 +31,132 @@ assert_eq!(resp, resp_obj.to_json());
 "##
 )]
 #[must_use]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ApiError {
     error: Vec<String>,
+    #[serde(default)]
+    code: ErrorCode,
     #[serde(with = "http_serde::status_code")]
+    #[schemars(with = "u16")]
     status: StatusCode,
+    /// Invalid fields of the request, if it's rejected for them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+/// Invalid field of a request, so that UIs can point at it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FieldError {
+    /// Name of the field, e.g. `channel_id`.
+    pub field: String,
+    /// Why the field is invalid.
+    pub message: String,
+}
+
+impl FieldError {
+    #[must_use]
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}`: {}", self.field, self.message)
+    }
+}
+
+/// Machine-readable code of an [`ApiError`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Token is either expired or in bad shape.
+    BadToken,
+    /// Token is missing.
+    MissingToken,
+    /// Account has TOTP enabled, but no code is given.
+    TotpRequired,
+    /// Not permitted to access.
+    Unauthorized,
+    /// Request from a browser doesn't repeat the CSRF token of its cookie.
+    BadCsrfToken,
+    /// User does not exist.
+    UserNotFound,
+    /// Entity does not exist.
+    EntityNotFound,
+    /// Task does not exist.
+    TaskNotFound,
+    /// Group does not exist.
+    GroupNotFound,
+    /// Resource already exists.
+    Conflict,
+    /// Request is malformed.
+    BadRequest,
+    /// Resource does not exist.
+    NotFound,
+    /// Server failed to handle the request.
+    Internal,
+    /// Error not covered by other codes.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl From<StatusCode> for ErrorCode {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest,
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::INTERNAL_SERVER_ERROR => Self::Internal,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Coarse category of an [`ApiError`], for callers that don't care which
+/// resource is missing or why the token is rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ApiErrorKind {
+    /// Token is missing, invalid or lacks privilege.
+    Unauthorized,
+    /// Requested user, entity, task or other resource does not exist.
+    NotFound,
+    /// Resource already exists.
+    Conflict,
+    /// Request is malformed.
+    BadRequest,
+    /// Server failed to handle the request.
+    Internal,
+    /// Error not covered by other kinds.
+    Other,
+}
+
+impl ErrorCode {
+    /// Category of the code, or `None` for [`ErrorCode::Unknown`].
+    #[must_use]
+    pub const fn kind(self) -> Option<ApiErrorKind> {
+        Some(match self {
+            Self::BadToken
+            | Self::MissingToken
+            | Self::TotpRequired
+            | Self::Unauthorized
+            | Self::BadCsrfToken => ApiErrorKind::Unauthorized,
+            Self::UserNotFound
+            | Self::EntityNotFound
+            | Self::TaskNotFound
+            | Self::GroupNotFound
+            | Self::NotFound => ApiErrorKind::NotFound,
+            Self::Conflict => ApiErrorKind::Conflict,
+            Self::BadRequest => ApiErrorKind::BadRequest,
+            Self::Internal => ApiErrorKind::Internal,
+            Self::Unknown => return None,
+        })
+    }
 }
 
 impl Display for ApiError {
@@ -55,7 +177,19 @@ impl ApiError {
             Some(reason) => vec![reason.to_owned()],
             None => vec![],
         };
-        Self { error, status }
+        Self {
+            error,
+            code: status.into(),
+            status,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Override the error code.
+    #[inline]
+    pub const fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
     }
 
     #[must_use]
@@ -80,12 +214,39 @@ impl ApiError {
         self.status.canonical_reason()
     }
 
+    /// Invalid fields of the request, if it's rejected for them.
+    #[inline]
+    #[must_use]
+    pub fn field_errors(&self) -> &[FieldError] {
+        &self.fields
+    }
+
     #[inline]
     #[must_use]
     pub const fn status(&self) -> StatusCode {
         self.status
     }
 
+    #[inline]
+    #[must_use]
+    pub const fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// Category of the error, by its code or by its status if the code is
+    /// unknown, e.g. sent by an older server.
+    #[must_use]
+    pub fn kind(&self) -> ApiErrorKind {
+        self.code.kind().unwrap_or_else(|| match self.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ApiErrorKind::Unauthorized,
+            StatusCode::NOT_FOUND => ApiErrorKind::NotFound,
+            StatusCode::CONFLICT => ApiErrorKind::Conflict,
+            StatusCode::BAD_REQUEST => ApiErrorKind::BadRequest,
+            status if status.is_server_error() => ApiErrorKind::Internal,
+            _ => ApiErrorKind::Other,
+        })
+    }
+
     /// Match the text with the error reasons.
     ///
     /// Returns `true` if the text is a substring of any of the errors.
@@ -124,12 +285,23 @@ impl ApiError {
 
     #[inline]
     pub fn bad_token() -> Self {
-        Self::new(StatusCode::UNAUTHORIZED).explain("Token is either expired or in bad shape")
+        Self::new(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::BadToken)
+            .explain("Token is either expired or in bad shape")
     }
 
     #[inline]
     pub fn missing_token() -> Self {
-        Self::new(StatusCode::UNAUTHORIZED).explain("Token is missing")
+        Self::new(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::MissingToken)
+            .explain("Token is missing")
+    }
+
+    #[inline]
+    pub fn totp_required() -> Self {
+        Self::new(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::TotpRequired)
+            .explain("TOTP code is required")
     }
 
     #[inline]
@@ -137,14 +309,23 @@ impl ApiError {
         Self::new(StatusCode::UNAUTHORIZED).explain("Not permitted to access")
     }
 
+    #[inline]
+    pub fn bad_csrf_token() -> Self {
+        Self::new(StatusCode::FORBIDDEN)
+            .with_code(ErrorCode::BadCsrfToken)
+            .explain("CSRF token is missing or doesn't match its cookie")
+    }
+
     #[inline]
     pub fn user_not_found_with_id(user_id: &Uuid) -> Self {
-        Self::new(StatusCode::NOT_FOUND).explain(format!("Cannot find user with ID `{}`", user_id))
+        Self::new(StatusCode::NOT_FOUND)
+            .with_code(ErrorCode::UserNotFound)
+            .explain(format!("Cannot find user with ID `{user_id}`"))
     }
 
     #[inline]
     pub fn user_not_found_with_im(im: impl AsRef<str>, im_payload: impl AsRef<str>) -> Self {
-        Self::new(StatusCode::NOT_FOUND).explain(format!(
+        Self::new(StatusCode::NOT_FOUND).with_code(ErrorCode::UserNotFound).explain(format!(
             "Cannot find user with im `{}` and im_payload `{}`",
             im.as_ref(),
             im_payload.as_ref()
@@ -171,12 +352,22 @@ impl ApiError {
     #[inline]
     pub fn entity_not_found(entity_id: &Uuid) -> Self {
         Self::new(StatusCode::NOT_FOUND)
-            .explain(format!("Cannot find entity with ID `{}`", entity_id))
+            .with_code(ErrorCode::EntityNotFound)
+            .explain(format!("Cannot find entity with ID `{entity_id}`"))
     }
 
     #[inline]
     pub fn task_not_found(task_id: &Uuid) -> Self {
-        Self::new(StatusCode::NOT_FOUND).explain(format!("Cannot find task with ID `{}`", task_id))
+        Self::new(StatusCode::NOT_FOUND)
+            .with_code(ErrorCode::TaskNotFound)
+            .explain(format!("Cannot find task with ID `{task_id}`"))
+    }
+
+    #[inline]
+    pub fn group_not_found(group_id: &Uuid) -> Self {
+        Self::new(StatusCode::NOT_FOUND)
+            .with_code(ErrorCode::GroupNotFound)
+            .explain(format!("Cannot find group with ID `{group_id}`"))
     }
 
     #[inline]
@@ -184,6 +375,21 @@ impl ApiError {
         Self::new(StatusCode::BAD_REQUEST).explain(error)
     }
 
+    #[inline]
+    pub fn invalid_link_code() -> Self {
+        Self::bad_request("Link code is invalid, expired or already used")
+    }
+
+    /// Reject a request for its invalid fields, listed both as structured
+    /// [`FieldError`]s and as error messages.
+    #[inline]
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let mut error =
+            Self::new(StatusCode::BAD_REQUEST).tirade(fields.iter().map(ToString::to_string));
+        error.fields = fields;
+        error
+    }
+
     #[inline]
     pub fn internal() -> Self {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR)
@@ -7,6 +7,6 @@ impl From<AddTask> for Task {
         let AddTask {
             entity_id, param, ..
         } = new_task;
-        param.into_task_with(entity_id)
+        param.into_task_with(entity_id.into())
     }
 }
@@ -44,8 +44,9 @@
 //!   it.
 //! - If `client` feature is enabled, generate methods for
 //!   [`Client`](crate::client::Client) to invoke RPC methods.
+//! - Define `openapi()`, which returns an `OpenAPI` document of all methods.
 
-mod_use::mod_use![wrapper, traits, error, ext];
+mod_use::mod_use![wrapper, traits, error, ext, cursor, openapi, encoding];
 
 pub mod model;
 
@@ -55,7 +56,11 @@ pub mod model;
 ///
 /// # Example
 ///
-/// ```
+/// With `client` features on, the generated client methods can only be
+/// implemented inside this crate, so the example is not run.
+///
+#[cfg_attr(any(feature = "client", feature = "client_blocking"), doc = "```ignore")]
+#[cfg_attr(not(any(feature = "client", feature = "client_blocking")), doc = "```")]
 /// # #[macro_use] extern crate api;
 /// #
 /// # use api::methods;
@@ -100,6 +105,7 @@ macro_rules! methods {
     )*) => {
         $(
             #[derive(Debug, Clone, PartialEq, Eq, ::serde::Serialize, ::serde::Deserialize)]
+            #[derive(::schemars::JsonSchema)]
             #[doc = concat!("Request param of RPC method `", stringify!($method), "`.")]
             #[doc = ""]
             $( #[ $method_meta ] )*
@@ -129,6 +135,7 @@ macro_rules! methods {
             $(
                 #[doc = concat!("Response of RPC method [`", stringify!($method), "`](", stringify!($req), ").")]
                 #[derive(Debug, Clone, PartialEq, Eq, ::serde::Serialize, ::serde::Deserialize)]
+                #[derive(::schemars::JsonSchema)]
                 pub struct $resp {
                     $(
                         $( #[ $res_field_meta ] )*
@@ -152,6 +159,14 @@ macro_rules! methods {
             )?
         )*
 
+        /// `OpenAPI` document of all RPC methods.
+        #[must_use]
+        pub fn openapi() -> ::serde_json::Value {
+            $crate::rpc::openapi_document(|gen| vec![
+                $( $crate::rpc::MethodSchema::of::<$req>(gen), )*
+            ])
+        }
+
         #[test]
         fn test_requests_size() {
             use ::std::mem::size_of;
@@ -254,7 +269,7 @@ mod test_macro {
     use mongodb::bson::Uuid;
 
     use crate::{
-        rpc::{ApiError, Request, Response},
+        rpc::{ApiError, ApiErrorKind, ErrorCode, Request, Response},
         timestamp,
     };
 
@@ -273,6 +288,22 @@ mod test_macro {
         assert_eq!(GetUser::METHOD, "get_user");
     }
 
+    #[test]
+    fn test_openapi() {
+        let doc = openapi();
+        let op = &doc["paths"]["/get_user"]["post"];
+        assert_eq!(op["operationId"], "get_user");
+        assert_eq!(
+            op["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/GetUser"
+        );
+
+        let schemas = &doc["components"]["schemas"];
+        assert_eq!(schemas["GetUser"]["required"][0], "user_id");
+        assert!(schemas["ResponseObject_for_DummyUser"].is_object());
+        assert!(schemas["ResponseObject_for_ApiError"].is_object());
+    }
+
     #[test]
     fn test_serialize_success() {
         let now = timestamp();
@@ -295,7 +326,7 @@ mod test_macro {
         let now = timestamp();
         let id = "26721d57-37f5-458c-afea-2b18baf34925";
         let resp = format!(
-            r#"{{"data":{{"error":["Not Found","Cannot find user with ID `{id}`"],"status":404}},"success":false,"time":"{now}"}}"#,
+            r#"{{"data":{{"error":["Not Found","Cannot find user with ID `{id}`"],"code":"user_not_found","status":404}},"success":false,"time":"{now}"}}"#,
         );
 
         let mut resp_obj =
@@ -304,4 +335,35 @@ mod test_macro {
 
         assert_eq!(resp, resp_obj.to_json());
     }
+
+    #[test]
+    fn test_deserialize_api_error_code() {
+        let err: ApiError =
+            serde_json::from_str(r#"{"error":["Conflict"],"code":"conflict","status":409}"#)
+                .unwrap();
+        assert_eq!(err.code(), ErrorCode::Conflict);
+
+        let err: ApiError =
+            serde_json::from_str(r#"{"error":["Teapot"],"code":"teapot","status":418}"#).unwrap();
+        assert_eq!(err.code(), ErrorCode::Unknown);
+
+        let err: ApiError = serde_json::from_str(r#"{"error":[],"status":404}"#).unwrap();
+        assert_eq!(err.code(), ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn test_api_error_kind() {
+        let id = Uuid::new();
+        assert_eq!(ApiError::user_not_found_with_id(&id).kind(), ApiErrorKind::NotFound);
+        assert_eq!(ApiError::task_not_found(&id).kind(), ApiErrorKind::NotFound);
+        assert_eq!(ApiError::bad_token().kind(), ApiErrorKind::Unauthorized);
+        assert_eq!(ApiError::user_already_exists("tg", "1").kind(), ApiErrorKind::Conflict);
+
+        // Falls back to the status if the code is unknown
+        let err: ApiError =
+            serde_json::from_str(r#"{"error":[],"code":"gone_fishing","status":409}"#).unwrap();
+        assert_eq!(err.kind(), ApiErrorKind::Conflict);
+        let err: ApiError = serde_json::from_str(r#"{"error":[],"status":418}"#).unwrap();
+        assert_eq!(err.kind(), ApiErrorKind::Other);
+    }
 }
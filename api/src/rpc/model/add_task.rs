@@ -1,9 +1,21 @@
 use mongodb::bson::Uuid;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use sg_core::models::Task;
+use sg_core::models::{kind, validate_task_kind, Task};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+use crate::{
+    rpc::{ApiResult, FieldError},
+    ApiError,
+};
+
+/// Length of youtube channel ids, e.g. `UCyl1z3jo3XHR1riLFKG5UAg`.
+const YOUTUBE_CHANNEL_ID_LEN: usize = 24;
+
+/// Max length of twitter screen names.
+const TWITTER_SCREEN_NAME_MAX_LEN: usize = 15;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "kind")]
 #[serde(rename_all = "lowercase")]
 pub enum AddTaskParam {
@@ -13,6 +25,66 @@ pub enum AddTaskParam {
 }
 
 impl AddTaskParam {
+    /// Kind of the task to be created.
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Youtube { .. } => kind::YOUTUBE,
+            Self::Bilibili { .. } => kind::BILILIVE,
+            Self::Twitter { .. } => kind::TWITTER,
+        }
+    }
+
+    /// Problems with the task, i.e. an unknown kind or parameters in a format
+    /// its worker doesn't accept. Empty if the task is valid.
+    #[must_use]
+    pub fn field_errors(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+        if let Err(e) = validate_task_kind(self.kind()) {
+            errors.push(FieldError::new("kind", e.to_string()));
+        }
+        match self {
+            Self::Youtube { channel_id } => {
+                if !is_youtube_channel_id(channel_id) {
+                    errors.push(FieldError::new(
+                        "channel_id",
+                        "must be `UC` followed by 22 letters, digits, `-` or `_`",
+                    ));
+                }
+            }
+            Self::Bilibili { uid } => {
+                if !is_numeric_id(uid) {
+                    errors.push(FieldError::new("uid", "must be a positive integer"));
+                }
+            }
+            Self::Twitter { id } => {
+                if !is_numeric_id(id) && !is_twitter_screen_name(id) {
+                    errors.push(FieldError::new(
+                        "id",
+                        "must be a numeric user id, or a screen name of at most 15 letters, \
+                         digits or `_`",
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Make sure the kind of the task is known and its parameters are well
+    /// formed.
+    ///
+    /// # Errors
+    /// Fails with the [field errors](Self::field_errors) of the task, if
+    /// there are any.
+    pub fn validate(&self) -> ApiResult<()> {
+        let errors = self.field_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::invalid_fields(errors))
+        }
+    }
+
     #[must_use]
     pub fn into_task_with(self, entity_id: Uuid) -> Task {
         match self {
@@ -22,3 +94,65 @@ impl AddTaskParam {
         }
     }
 }
+
+fn is_youtube_channel_id(id: &str) -> bool {
+    id.len() == YOUTUBE_CHANNEL_ID_LEN
+        && id.starts_with("UC")
+        && id[2..]
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn is_numeric_id(id: &str) -> bool {
+    id.bytes().all(|b| b.is_ascii_digit()) && matches!(id.parse::<u64>(), Ok(id) if id > 0)
+}
+
+fn is_twitter_screen_name(name: &str) -> bool {
+    (1..=TWITTER_SCREEN_NAME_MAX_LEN).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::AddTaskParam;
+
+    fn invalid_fields(param: &AddTaskParam) -> Vec<String> {
+        param
+            .field_errors()
+            .into_iter()
+            .map(|error| error.field)
+            .collect()
+    }
+
+    #[test]
+    fn must_validate_params() {
+        let bilibili = |uid: &str| AddTaskParam::Bilibili { uid: uid.to_owned() };
+        assert!(bilibili("434334701").validate().is_ok());
+        for uid in ["", "0", "+1", "abc", "18446744073709551616"] {
+            assert_eq!(invalid_fields(&bilibili(uid)), ["uid"], "{uid:?}");
+        }
+
+        let twitter = |id: &str| AddTaskParam::Twitter { id: id.to_owned() };
+        assert!(twitter("975275878673408001").validate().is_ok());
+        assert!(twitter("suisei_hosimati").validate().is_ok());
+        for id in ["", "suisei hosimati", "suisei_hosimati_", "@suisei"] {
+            assert_eq!(invalid_fields(&twitter(id)), ["id"], "{id:?}");
+        }
+
+        let youtube = |channel_id: &str| AddTaskParam::Youtube {
+            channel_id: channel_id.to_owned(),
+        };
+        assert!(youtube("UC5CwaMl1eIgY8h02uZw7u8A").validate().is_ok());
+        for channel_id in ["", "UC5CwaMl1eIgY8h02uZw7u8", "UC5CwaMl1eIgY8h02uZw7u8A.", "@suisei"] {
+            assert_eq!(invalid_fields(&youtube(channel_id)), ["channel_id"], "{channel_id:?}");
+        }
+        assert_eq!(
+            invalid_fields(&youtube("https://www.youtube.com/channel/UC5CwaMl1eIgY8h02uZw7u8A")),
+            ["channel_id"]
+        );
+
+        let error = bilibili("abc").validate().unwrap_err();
+        assert_eq!(error.field_errors()[0].field, "uid");
+        assert!(error.matches("`uid`: must be a positive integer"));
+    }
+}
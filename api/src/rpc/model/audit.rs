@@ -0,0 +1,64 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sg_core::models::Id;
+
+use crate::{
+    model::Privilege,
+    rpc::{ApiError, ErrorCode},
+};
+
+/// An admin RPC call recorded in the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditEntry {
+    /// Unique ID of the entry
+    pub id: Id,
+    /// Time of the call, as Unix timestamp in milliseconds
+    pub time: i64,
+    /// Name of the RPC method, e.g. `del_task`
+    pub method: String,
+    /// Claims of the token making the call
+    pub actor: AuditActor,
+    /// Request param of the call
+    pub request: Value,
+    /// Whether the call succeeded
+    pub outcome: AuditOutcome,
+}
+
+/// Claims of the token making an audited call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditActor {
+    /// User id of the token, nil for tokens issued by `login`
+    pub user_id: Id,
+    /// Privilege of the token
+    pub privilege: Privilege,
+}
+
+/// Outcome of an audited call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    /// The call succeeded.
+    Success,
+    /// The call failed with an error.
+    Failure {
+        /// Machine-readable code of the error
+        code: ErrorCode,
+        /// Messages of the error
+        errors: Vec<String>,
+    },
+}
+
+impl AuditOutcome {
+    /// Outcome of a call that returned `result`.
+    #[must_use]
+    pub fn of<T>(result: &Result<T, ApiError>) -> Self {
+        match result {
+            Ok(_) => Self::Success,
+            Err(error) => Self::Failure {
+                code: error.code(),
+                errors: error.errors().to_vec(),
+            },
+        }
+    }
+}
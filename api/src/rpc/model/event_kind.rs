@@ -0,0 +1,29 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use sg_core::models::KindSpec;
+
+/// A known event kind.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct EventKind {
+    /// Name of the kind, used in tasks and event filters
+    pub name: String,
+    /// Worker producing events of the kind
+    pub worker: String,
+    /// Human-readable description of the kind
+    pub description: String,
+    /// JSON Schema of fields of events of the kind
+    pub payload: Value,
+}
+
+impl From<&KindSpec> for EventKind {
+    fn from(spec: &KindSpec) -> Self {
+        Self {
+            name: spec.name.to_owned(),
+            worker: spec.worker.to_owned(),
+            description: spec.description.to_owned(),
+            payload: spec.payload_schema(),
+        }
+    }
+}
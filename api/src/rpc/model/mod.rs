@@ -1,15 +1,29 @@
 //! Contains all model definition and trait implementations.
 
-use std::time::SystemTime;
+use std::{collections::HashMap, time::SystemTime};
 
 // Core models
-use mongodb::bson::Uuid;
-use sg_core::models::{Entity, EventFilter, Group, Meta, Task, User};
+use sg_core::models::{
+    Entity,
+    Event,
+    EventFilter,
+    Group,
+    Id,
+    Meta,
+    Name,
+    Profile,
+    QuietHours,
+    Task,
+    TaskTemplate,
+    User,
+};
 use url::Url;
 
-use crate::successful_response;
+use crate::{rpc::{Cursor, FieldError, Page}, successful_response};
 
-mod_use::mod_use![bot, null, admin, add_task, user_query];
+mod_use::mod_use![
+    bot, null, admin, add_task, user_query, privilege, audit, event_kind, notification, stats
+];
 
 successful_response![Entity, Task, User, Group];
 
@@ -20,6 +34,11 @@ crate::methods! {
     /// Health check
     health := Health {} -> Null,
 
+    /// List known event kinds, which tasks and event filters may refer to.
+    get_event_kinds := GetEventKinds {} -> EventKinds {
+        kinds: Vec<EventKind>
+    },
+
     /// Login with Username and Password
     ///
     /// This method checks for login information stored in DB,
@@ -28,29 +47,127 @@ crate::methods! {
     /// The token is composed with a nil user id (UUID with all 0),
     /// which cannot be used to request some methods that require user information
     /// like `update_setting` or `auth_user`
+    ///
+    /// If the account has TOTP enabled, `totp` must be set to a code, or a
+    /// `totp_required` error is returned.
     login := Login {
         username: String,
         password: String,
+        /// TOTP code, required if the account has TOTP enabled
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        totp: Option<String>,
     } -> Token {
         token: String,
         #[serde(with = "humantime_serde")]
+        #[schemars(with = "sg_core::schema::Timestamp")]
         valid_until: SystemTime
     },
 
+    /// Login with an API key issued by `sg_auth`
+    ///
+    /// Like `login`, returns a token with a nil user id if the key is valid
+    /// and its scopes grant sufficient permission.
+    login_with_key := LoginWithKey {
+        key: String,
+    } -> Token,
+
+    /// Generate a TOTP secret for the account, to be added to an
+    /// authenticator app. It takes effect after confirmed by `verify_totp`.
+    ///
+    /// If the account has TOTP enabled already, `totp` must be set to a code
+    /// of the current secret, or a `totp_required` error is returned.
+    enroll_totp := EnrollTotp {
+        username: String,
+        password: String,
+        /// TOTP code of the current secret, required if the account has TOTP
+        /// enabled
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        totp: Option<String>,
+    } -> TotpSecret {
+        /// The secret in base32
+        secret: String,
+        /// `otpauth://` URI of the secret, usually shown as a QR code
+        uri: String
+    },
+
+    /// Confirm the TOTP secret generated by `enroll_totp` with a code from
+    /// the authenticator app. From then on, `login` requires a TOTP code.
+    verify_totp := VerifyTotp {
+        username: String,
+        password: String,
+        /// TOTP code generated with the new secret
+        code: String,
+    } -> Null,
+
     // ----------- //
     // User method //
     // ----------  //
-    /// Update user settings, return the updated `User`
+    /// Update user settings, return the updated `User`. Settings not set
+    /// are cleared.
     update_setting := UpdateSetting {
         /// New user preference
-        event_filter: EventFilter
+        event_filter: EventFilter,
+        /// Daily period in which events are held back until it ends
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quiet_hours: Option<QuietHours>,
+        /// Max number of events delivered in a clock hour
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_per_hour: Option<u32>
     } -> User,
 
-    /// Get all entities, include vtbs and groups
+    /// Issue a code for another user, e.g. of the same person in another
+    /// IM, to link with by `complete_link`. The code can be used once
+    /// within `LINK_CODE_TIMEOUT`.
+    start_link := StartLink {
+    } -> LinkCode {
+        /// Code to pass to `complete_link`
+        code: String,
+        #[serde(with = "humantime_serde")]
+        #[schemars(with = "sg_core::schema::Timestamp")]
+        valid_until: SystemTime
+    },
+
+    /// Link the user with the user who issued the code, along with users
+    /// already linked to either of them, and return the updated `User`.
+    ///
+    /// Linked users share one event filter, merged from theirs, which
+    /// `update_setting` of any of them changes for all.
+    complete_link := CompleteLink {
+        /// Code issued by `start_link`, case-insensitive
+        code: String
+    } -> User,
+
+    /// Get entities, include vtbs and groups, each sorted by id and paged
+    /// separately
     get_entities := GetEntities {
+        /// Page of vtbs
+        #[serde(default)]
+        vtbs: Page,
+        /// Page of groups
+        #[serde(default)]
+        groups: Page
     } -> Entities {
         vtbs: Vec<Entity>,
-        groups: Vec<Group>
+        groups: Vec<Group>,
+        /// Cursor of the next page of vtbs, if there is one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_vtbs: Option<Cursor>,
+        /// Cursor of the next page of groups, if there is one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_groups: Option<Cursor>
+    },
+
+    /// Search entities by their names and aliases, best matches first.
+    ///
+    /// Words are matched as a whole, ignoring case and diacritics, and an
+    /// entity matches if any of the words does.
+    search_entities := SearchEntities {
+        /// Words to search for
+        query: String,
+        /// Max count of entities to return, or all matching ones if not set
+        limit: Option<u32>
+    } -> SearchResult {
+        entities: Vec<Entity>
     },
 
     /// Authorize user
@@ -59,7 +176,29 @@ crate::methods! {
         /// Return info about user
         user: User,
         #[serde(with = "humantime_serde")]
-        valid_until: SystemTime
+        #[schemars(with = "sg_core::schema::Timestamp")]
+        valid_until: SystemTime,
+        /// Variants of running experiments assigned to the user
+        #[serde(default)]
+        experiments: HashMap<String, String>
+    },
+
+    /// Get events delivered, or failed to be delivered, to a user, oldest
+    /// first. Notifications are kept for `NOTIFICATION_RETENTION`.
+    ///
+    /// Users may only get their own notifications. Tokens of bots and admins
+    /// must set `user_id`.
+    get_notifications := GetNotifications {
+        /// The user, defaults to the user of the token
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<Id>,
+        #[serde(flatten)]
+        page: Page
+    } -> Notifications {
+        notifications: Vec<Notification>,
+        /// Cursor of the next page, if there is one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<Cursor>
     },
 
     // ---------- //
@@ -75,6 +214,12 @@ crate::methods! {
         query: UserQuery,
     } -> Token,
 
+    /// Get a group by id.
+    get_group := GetGroup {
+        /// The ID of the group
+        group_id: Id
+    } -> Group,
+
     /// Create a new user.
     add_user := AddUser {
         /// The IM that the user is in.
@@ -87,6 +232,31 @@ crate::methods! {
         name: String
     } -> User,
 
+    /// Register a user. If a user with the same `im` and `im_payload` already
+    /// exists, update its name and avatar and enable it again instead, keeping
+    /// its id and event filter. Return the user with a new token, rejecting
+    /// tokens issued to the user before.
+    register_or_restore := RegisterOrRestore {
+        /// The IM that the user is in.
+        im: String,
+        /// IM payload, e.g. Chat id in telegram
+        im_payload: String,
+        /// Avatar of the user.
+        avatar: Option<Url>,
+        /// Name of the user.
+        name: String
+    } -> Registered {
+        /// The registered user
+        user: User,
+        /// Whether the user already existed
+        restored: bool,
+        /// Token of the user, with `User` privilege
+        token: String,
+        #[serde(with = "humantime_serde")]
+        #[schemars(with = "sg_core::schema::Timestamp")]
+        valid_until: SystemTime
+    },
+
     /// Delete an existing user.
     del_user := DelUser {
         /// Either `user id` or `im` and `im_payload` of the user
@@ -96,15 +266,62 @@ crate::methods! {
 
     /// Query users that subscribed to specific events. This
     /// is filtered by the user's event filter and im.
+    ///
+    /// For `system/announcement` events, all users of the im are returned
+    /// regardless of their event filters, and `entity_id` is ignored.
+    ///
+    /// Users are sorted by id. A page may have fewer users than the limit
+    /// even if it's not the last one, since part of the filter is applied
+    /// after paging.
     get_interest := GetInterest {
-        entity_id: Uuid,
+        entity_id: Id,
         kind: String,
-        im: String
+        im: String,
+        #[serde(flatten)]
+        page: Page
     } -> Interest {
         /// List of users that interest in the event
-        users: Vec<User>
+        users: Vec<User>,
+        /// Cursor of the next page, if there is one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<Cursor>
     },
 
+    /// Record deliveries of an event to users, so that they show up in
+    /// `get_notifications`.
+    add_notifications := AddNotifications {
+        /// The delivered event, before localization
+        event: Event,
+        /// Users the event reached
+        #[serde(default)]
+        delivered: Vec<Id>,
+        /// Users the event failed to reach, e.g. after retries ran out
+        #[serde(default)]
+        failed: Vec<Id>
+    } -> Null,
+
+    /// Set the profile of an entity on a platform, e.g. its current name and
+    /// avatar on youtube, keeping other parts of its meta. Return the new
+    /// entity.
+    update_entity_meta := UpdateEntityMeta {
+        /// The ID of the entity
+        entity_id: Id,
+        /// Kind of the task the profile is fetched by, e.g. `youtube`
+        kind: String,
+        /// The profile on the platform
+        profile: Profile
+    } -> Entity,
+
+    /// Disable deliveries to a user, e.g. to a webhook endpoint failing
+    /// repeatedly, or enable them again. Disabled users are left out of
+    /// `get_interest`. Return the updated `User`.
+    set_user_disabled := SetUserDisabled {
+        /// The ID of the user
+        user_id: Id,
+        /// Whether deliveries to the user are disabled
+        disabled: bool
+    } -> User,
+
     // ------------ //
     // Admin method //
     // ------------ //
@@ -113,32 +330,127 @@ crate::methods! {
         /// Task parameter
         param: AddTaskParam,
         /// The ID of this entity which this task belongs to.
-        entity_id: Uuid,
+        entity_id: Id,
     } -> Task,
 
+    /// Check a task without adding it, e.g. while an admin fills it in.
+    /// Problems are returned instead of failing the call.
+    validate_task := ValidateTask {
+        #[serde(flatten)]
+        /// Task parameter
+        param: AddTaskParam,
+    } -> TaskValidation {
+        /// Invalid fields of the task, empty if it's valid
+        errors: Vec<FieldError>
+    },
+
     del_task := DelTask {
         /// The ID of the task going to be deleted.
-        task_id: Uuid
+        task_id: Id
     } -> Task,
 
     add_entity := AddEntity {
         /// Meta of the entity
         meta: Meta,
         /// List of tasks that this entity has.
-        tasks: Vec<AddTaskParam>
+        tasks: Vec<AddTaskParam>,
+        /// Templates of tasks derived from the meta, e.g. from its accounts.
+        /// Their tasks are added and removed as the meta changes.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        auto_tasks: Vec<TaskTemplate>
     } -> Entity,
 
-    /// Update the entity's meta. Return the new entity.
+    /// Update the entity's meta, and its task templates if set. Tasks
+    /// derived from the meta are added and removed to match it. Return the
+    /// new entity.
     update_entity := UpdateEntity {
         /// The ID of the entity
-        entity_id: Uuid,
+        entity_id: Id,
         /// Meta of the entity
         meta: Meta,
+        /// Templates of tasks derived from the meta, replacing the current ones
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auto_tasks: Option<Vec<TaskTemplate>>,
     } -> Entity,
 
     /// Update an entity. Return the deleted entity.
     del_entity := DelEntity {
         /// The ID of the entity
-        entity_id: Uuid
+        entity_id: Id
+    } -> Entity,
+
+    /// Create a new group.
+    add_group := AddGroup {
+        /// Name of the group
+        name: Name
+    } -> Group,
+
+    /// Rename a group. Return the new group.
+    update_group := UpdateGroup {
+        /// The ID of the group
+        group_id: Id,
+        /// New name of the group
+        name: Name
+    } -> Group,
+
+    /// Delete a group, and remove its entities from it. Return the deleted
+    /// group.
+    del_group := DelGroup {
+        /// The ID of the group
+        group_id: Id
+    } -> Group,
+
+    /// Move an entity into a group, or out of any group if `group_id` is not
+    /// set. Return the new entity.
+    set_entity_group := SetEntityGroup {
+        /// The ID of the entity
+        entity_id: Id,
+        /// The ID of the group, which must exist
+        group_id: Option<Id>
     } -> Entity,
+
+    /// Announce a message, e.g. of a maintenance, to all users of `im`, or of
+    /// every IM if not set. It's published as a `system/announcement` event,
+    /// which bots deliver to their users regardless of subscriptions. Return
+    /// the ID of the event.
+    broadcast := Broadcast {
+        /// Text of the announcement
+        message: String,
+        /// IM whose users the announcement is delivered to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        im: Option<String>
+    } -> Broadcasted {
+        event_id: Id
+    },
+
+    /// Get admin calls recorded in the audit log, oldest first.
+    get_audit_log := GetAuditLog {
+        #[serde(flatten)]
+        page: Page
+    } -> AuditLog {
+        entries: Vec<AuditEntry>,
+        /// Cursor of the next page, if there is one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<Cursor>
+    },
+
+    /// Get counts of events of an entity and of their deliveries recorded by
+    /// `add_notifications`, by kind and time bucket, oldest first. Buckets
+    /// without events are left out, and the first one starts at its boundary
+    /// before `since`.
+    get_entity_stats := GetEntityStats {
+        /// The ID of the entity
+        entity_id: Id,
+        /// Start of the range, as Unix timestamp in milliseconds
+        since: i64,
+        /// End of the range, exclusive, as Unix timestamp in milliseconds.
+        /// Defaults to now.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<i64>,
+        /// Length of the buckets
+        #[serde(default)]
+        bucket: StatsBucket
+    } -> EntityStatsList {
+        stats: Vec<EntityStats>
+    },
 }
@@ -0,0 +1,19 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use sg_core::models::{Event, Id};
+
+/// An event delivered, or failed to be delivered, to a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Notification {
+    /// Unique ID of the notification
+    pub id: Id,
+    /// The notified user
+    pub user_id: Id,
+    /// Time of the delivery, as Unix timestamp in milliseconds
+    pub time: i64,
+    /// The event, before localization
+    pub event: Event,
+    /// Whether the event reached the user
+    pub delivered: bool,
+}
@@ -0,0 +1,15 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Privilege of a token. Three levels: User, Bot, Admin.
+///
+/// - **User** can only access some API, mostly related to themselves.
+/// - **Bot** can access more API, include creating session for users.
+/// - **Admin** can access all API.
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema)]
+pub enum Privilege {
+    User,
+    Bot,
+    Admin,
+}
@@ -0,0 +1,63 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Length of the time buckets statistics are counted in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsBucket {
+    /// Clock hours
+    #[default]
+    Hour,
+    /// Days, in UTC
+    Day,
+}
+
+impl StatsBucket {
+    /// Length of the bucket in milliseconds.
+    #[must_use]
+    pub const fn millis(self) -> i64 {
+        match self {
+            Self::Hour => 60 * 60 * 1000,
+            Self::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+
+    /// Start of the bucket `time` falls in, both as Unix timestamps in
+    /// milliseconds.
+    #[must_use]
+    pub const fn start_of(self, time: i64) -> i64 {
+        time - time.rem_euclid(self.millis())
+    }
+}
+
+/// Counts of events of an entity of one kind, and of their deliveries, in a
+/// time bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct EntityStats {
+    /// Start of the bucket, as Unix timestamp in milliseconds
+    pub start: i64,
+    /// Kind of the events
+    pub kind: String,
+    /// Number of distinct events, whether they reached anyone or not
+    pub events: u64,
+    /// Number of users the events reached
+    pub delivered: u64,
+    /// Number of users the events failed to reach
+    pub failed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::StatsBucket;
+
+    #[test]
+    fn must_start_buckets() {
+        let time = 1_700_000_000_123;
+        assert_eq!(StatsBucket::Hour.start_of(time), 1_699_999_200_000);
+        assert_eq!(StatsBucket::Day.start_of(time), 1_699_920_000_000);
+        assert_eq!(
+            StatsBucket::Hour.start_of(1_699_999_200_000),
+            1_699_999_200_000
+        );
+    }
+}
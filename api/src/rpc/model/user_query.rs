@@ -1,4 +1,5 @@
-use mongodb::bson::{doc, Document, Uuid};
+use mongodb::bson::{doc, Document};
+use sg_core::models::Id;
 
 use crate::ApiError;
 
@@ -7,10 +8,12 @@ use crate::ApiError;
 /// - By IM: use `im` and `im_payload` to find the corresponding user. This is usually used by the bot.
 /// - By ID: use `id` to find the corresponding user. This is usually used by the admin.
 #[must_use]
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(untagged)]
 pub enum UserQuery {
-    ById { user_id: Uuid },
+    ById {
+        user_id: Id,
+    },
     ByIm { im: String, im_payload: String },
 }
 
@@ -57,8 +60,6 @@ impl From<UserQuery> for ApiError {
 
 #[cfg(test)]
 mod test {
-    use mongodb::bson::Uuid;
-
     use crate::model::UserQuery;
 
     #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -77,7 +78,7 @@ mod test {
         assert_eq!(
             test.query,
             UserQuery::ById {
-                user_id: Uuid::parse_str("5e9f8f8f-f8f8-f8f8-f8f8-f8f8f8f8f8f8").unwrap()
+                user_id: "5e9f8f8f-f8f8-f8f8-f8f8-f8f8f8f8f8f8".parse().unwrap()
             }
         );
     }
@@ -0,0 +1,86 @@
+//! `OpenAPI` document of RPC methods, generated from their JSON schemas.
+
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::Schema,
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+use crate::rpc::{ApiError, Request, ResponseObject};
+
+/// Schemas of the request and response of an RPC method.
+pub struct MethodSchema {
+    method: &'static str,
+    request: Schema,
+    response: Schema,
+}
+
+impl MethodSchema {
+    /// Schemas of method `Req`, registering definitions of types it refers to
+    /// in `gen`.
+    pub fn of<Req>(gen: &mut SchemaGenerator) -> Self
+    where
+        Req: Request + JsonSchema,
+        Req::Res: JsonSchema,
+    {
+        Self {
+            method: Req::METHOD,
+            request: gen.subschema_for::<Req>(),
+            response: gen.subschema_for::<ResponseObject<Req::Res>>(),
+        }
+    }
+}
+
+/// Build an `OpenAPI` document of methods listed by `methods`.
+///
+/// Each method is a `POST /:method` path under `/v1`. Methods requiring a
+/// token take it as a bearer token; which privilege a method requires is not
+/// described.
+pub fn openapi_document(methods: impl FnOnce(&mut SchemaGenerator) -> Vec<MethodSchema>) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let methods = methods(&mut gen);
+    let error = gen.subschema_for::<ResponseObject<ApiError>>();
+
+    let paths: Map<String, Value> = methods
+        .into_iter()
+        .map(|MethodSchema { method, request, response }| {
+            let operation = json!({
+                "operationId": method,
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": request } },
+                },
+                "responses": {
+                    "200": {
+                        "description": "Successful response",
+                        "content": { "application/json": { "schema": response } },
+                    },
+                    "default": {
+                        "description": "Error response",
+                        "content": { "application/json": { "schema": error } },
+                    },
+                },
+            });
+            (format!("/{method}"), json!({ "post": operation }))
+        })
+        .collect();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Stargazer API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/v1" }],
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            },
+        },
+        // Token is optional, e.g. for `health` and `login`.
+        "security": [{ "bearer": [] }, {}],
+    })
+}
@@ -3,12 +3,13 @@
 use std::ops::{Deref, DerefMut};
 
 use http::StatusCode;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{Response, rpc::ApiError, timestamp};
+use crate::{Response, rpc::{ApiError, Encoding}, timestamp};
 
 /// Wrapper for RPC response. Contains processed time, success indicator and payload. For more information, see [module doc](index.html#response).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[must_use]
 pub struct ResponseObject<T> {
     pub data: T,
@@ -62,6 +63,28 @@ impl<T: Serialize> ResponseObject<T> {
             }
         }
     }
+
+    /// Serializes response object into MessagePack, with structs as maps of
+    /// field names.
+    #[inline]
+    pub fn to_msgpack_bytes(&self) -> Vec<u8> {
+        match rmp_serde::to_vec_named(&self) {
+            Ok(res) => res,
+            Err(detail) => {
+                tracing::error!("Failed to serialize response object: {}", detail);
+                ApiError::internal().packed().to_msgpack_bytes()
+            }
+        }
+    }
+
+    /// Serializes response object in the given encoding.
+    #[inline]
+    pub fn to_bytes(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Json => self.to_json_bytes(),
+            Encoding::MessagePack => self.to_msgpack_bytes(),
+        }
+    }
 }
 
 impl<'a, T: Deserialize<'a>> ResponseObject<T> {
@@ -1,27 +1,91 @@
 //! Context of the server. Contains the configuration and database handle.
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::slice;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use color_eyre::Result;
 use futures::future::try_join;
 use futures::TryStreamExt;
+use humantime_serde::re::humantime::format_rfc3339_seconds;
 use mongodb::{
-    bson::{doc, to_document, Uuid},
-    options::{FindOneAndUpdateOptions, ReturnDocument},
-    Client, Collection, Database,
+    bson::{doc, to_document, Document, Uuid},
+    error::{Error as MongoError, ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateOptions},
+    Client, Collection, Database, IndexModel,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use url::Url;
 
-use sg_auth::AuthClient;
-use sg_core::models::{Entity, EventFilter, Group, Meta, Task, User};
+use sg_auth::{AuthClient, KeyRecord, PermissionSet};
+use sg_core::{
+    models::{
+        kind::ANNOUNCEMENT, validate_task_kind, AnnouncementPayload, Entity, Event, EventFilter,
+        Group, Id, Meta, Name, Profile, QuietHours, Task, TaskTemplate, User,
+    },
+    mq::{MessageQueue, Middlewares, RabbitMQ, Signing},
+};
 
 use crate::{
-    model::{AddTaskParam, Bot, UserQuery},
-    rpc::{ApiError, ApiResult},
-    server::{Claims, config::Config, JWTContext, Privilege},
+    model::{
+        AddTaskParam, AuditActor, AuditEntry, AuditOutcome, Bot, EntityStats, LinkCode,
+        Notification, StatsBucket, UserQuery,
+    },
+    rpc::{ApiError, ApiResult, Cursor, FieldError, Page, SortKey, BY_ID},
+    server::{
+        make_store, Claims, config::Config, EntityRepo, EntityUpdate, JWTContext, Privilege, Store,
+        TaskRepo, UserRepo,
+    },
 };
 use crate::model::Entities;
 
+/// Sort key of the audit log and notifications, in order of time.
+const BY_TIME: SortKey = &["time", "id"];
+
+/// Characters of link codes, leaving out ones easily mistaken for others.
+const LINK_CODE_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Field of events restricting delivery to the listed user ids, handled by
+/// bots.
+const DELIVER_TO: &str = "x-deliver-to";
+
+/// Error code of MongoDB on duplicate keys.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Code issued by `start_link`, kept until it's used or expires.
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkCodeRecord {
+    code: String,
+    /// The user who issued the code
+    user_id: Id,
+    /// Unix timestamp in milliseconds the code expires at
+    expires_at: i64,
+}
+
+/// Reminder sent of a subscription to expire, kept until it does.
+#[derive(Debug, Serialize, Deserialize)]
+struct ReminderRecord {
+    user_id: Id,
+    entity_id: Id,
+    /// Unix timestamp in milliseconds the subscription expires at
+    until: i64,
+}
+
+/// Counts of events of an entity of one kind, and of their deliveries, in an
+/// hour. Coarser buckets are summed from these.
+#[derive(Debug, Serialize, Deserialize)]
+struct StatsRecord {
+    entity_id: Id,
+    kind: String,
+    /// Unix timestamp in milliseconds the hour starts at
+    start: i64,
+    /// Events with deliveries recorded, kept to count each once however many
+    /// bots record it
+    event_ids: Vec<Id>,
+    delivered: i64,
+    failed: i64,
+}
+
 /// Context being shared between handlers. This will be cloned every time a handler is called.
 /// So all underlying data should be wrapped in Arc or similar shared reference thingy.
 ///
@@ -35,6 +99,8 @@ pub struct Context {
     jwt: Arc<JWTContext>,
     /// DB instance. Since DB is composed of [`Collection`](mongodb::Collection)s, cloning is cheap.
     db: Database,
+    /// Store of users, tasks and entities.
+    store: Arc<dyn Store>,
     /// Auth context.
     auth: AuthClient,
     /// Claims that are extracted from the JWT token header by auth middleware, optionally.
@@ -46,12 +112,67 @@ impl Context {
     /// # Errors
     /// Fail on invalid database url.
     pub async fn new(jwt: Arc<JWTContext>, config: Arc<Config>) -> Result<Self> {
-        let client = Client::with_uri_str(&config.mongo_uri).await?;
+        let client = Client::with_uri_str(&*config.mongo_uri).await?;
         let db = client.database(&config.mongo_db);
 
         Ok(Self::new_with_db(db, jwt, config))
     }
 
+    /// Create indexes needed by queries, if they don't exist yet.
+    ///
+    /// # Errors
+    /// Fail on database error, or if an index with the same name but different
+    /// options exists.
+    pub async fn create_indexes(&self) -> ApiResult<()> {
+        self.store.create_indexes().await?;
+        self.notifications()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "user_id": 1, "time": 1, "id": 1 })
+                    .build(),
+                None,
+            )
+            .await?;
+        self.entity_stats()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "entity_id": 1, "start": 1, "kind": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self.link_codes()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "code": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self.expiry_reminders()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "user_id": 1, "entity_id": 1, "until": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        self.auth.create_indexes().await?;
+        Ok(())
+    }
+
+    /// Ping the database.
+    ///
+    /// # Errors
+    /// Fail if the database is unreachable.
+    pub async fn ping(&self) -> Result<()> {
+        self.db.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
     #[inline]
     #[must_use]
     pub fn config(&self) -> &Config {
@@ -61,9 +182,27 @@ impl Context {
     /// Construct self with pre-connected database.
     #[inline]
     pub fn new_with_db(db: Database, jwt: Arc<JWTContext>, config: Arc<Config>) -> Self {
-        let auth = AuthClient::new(db.collection(&config.auth_collection));
+        let store = make_store(&db, &config);
+        Self::with_store(db, store, jwt, config)
+    }
+
+    /// Construct self with pre-connected database, keeping users, tasks and
+    /// entities in `store`.
+    #[inline]
+    pub fn with_store(
+        db: Database,
+        store: Arc<dyn Store>,
+        jwt: Arc<JWTContext>,
+        config: Arc<Config>,
+    ) -> Self {
+        let auth = AuthClient::new(
+            db.collection(&config.auth_collection),
+            db.collection(&config.keys_collection),
+            db.collection(&config.roles_collection),
+        );
         Self {
             db,
+            store,
             jwt,
             auth,
             config,
@@ -102,46 +241,90 @@ impl Context {
         self.claims.replace(claims)
     }
 
-    /// Encode the user id and corresponding privilege into a JWT token.
+    /// Encode the user id, corresponding privilege and token generation of the
+    /// user into a JWT token.
     ///
     /// # Errors
     /// Fails when encoding failed. This is unlikely to happen, but if it does, it's a bug.
     #[inline]
-    pub fn encode(&self, user_id: &Uuid, privilege: Privilege) -> ApiResult<(String, Claims)> {
-        self.jwt.encode(user_id, privilege).map_err(|detail| {
+    pub fn encode(
+        &self,
+        user_id: &Uuid,
+        privilege: Privilege,
+        generation: u32,
+    ) -> ApiResult<(String, Claims)> {
+        self.jwt.encode(user_id, privilege, generation).map_err(|detail| {
             tracing::error!(?detail, "Failed to encode JWT token");
             ApiError::internal()
         })
     }
 
+    /// Encode the privilege and permissions of a bot or admin account into a
+    /// JWT token.
+    ///
+    /// # Errors
+    /// Fails when encoding failed, which is a bug like in [`Context::encode`].
     #[inline]
-    #[must_use]
-    pub fn users(&self) -> Collection<User> {
-        self.db.collection(&self.config.users_collection)
+    pub fn encode_account(
+        &self,
+        privilege: Privilege,
+        permissions: PermissionSet,
+    ) -> ApiResult<(String, Claims)> {
+        self.jwt
+            .encode_account(privilege, permissions)
+            .map_err(|detail| {
+                tracing::error!(?detail, "Failed to encode JWT token");
+                ApiError::internal()
+            })
+    }
+
+    /// Encode the privilege and permissions of an API key into a JWT token,
+    /// which expires with the key at the latest.
+    ///
+    /// # Errors
+    /// Fails when encoding failed, which is a bug like in [`Context::encode`].
+    #[inline]
+    pub fn encode_key(&self, privilege: Privilege, key: &KeyRecord) -> ApiResult<(String, Claims)> {
+        self.jwt.encode_key(privilege, key).map_err(|detail| {
+            tracing::error!(?detail, "Failed to encode JWT token");
+            ApiError::internal()
+        })
     }
 
     #[inline]
     #[must_use]
-    pub fn tasks(&self) -> Collection<Task> {
-        self.db.collection(&self.config.tasks_collection)
+    pub fn groups(&self) -> Collection<Group> {
+        self.db.collection(&self.config.groups_collection)
     }
 
     #[inline]
     #[must_use]
-    pub fn entities(&self) -> Collection<Entity> {
-        self.db.collection(&self.config.entities_collection)
+    pub fn auth_db(&self) -> Collection<Bot> {
+        self.db.collection(&self.config.auth_collection)
     }
 
     #[inline]
     #[must_use]
-    pub fn groups(&self) -> Collection<Group> {
-        self.db.collection(&self.config.groups_collection)
+    pub fn audit_log(&self) -> Collection<AuditEntry> {
+        self.db.collection(&self.config.audit_collection)
     }
 
     #[inline]
     #[must_use]
-    pub fn auth_db(&self) -> Collection<Bot> {
-        self.db.collection(&self.config.auth_collection)
+    pub fn notifications(&self) -> Collection<Notification> {
+        self.db.collection(&self.config.notifications_collection)
+    }
+
+    fn entity_stats(&self) -> Collection<StatsRecord> {
+        self.db.collection(&self.config.entity_stats_collection)
+    }
+
+    fn link_codes(&self) -> Collection<LinkCodeRecord> {
+        self.db.collection(&self.config.link_codes_collection)
+    }
+
+    fn expiry_reminders(&self) -> Collection<ReminderRecord> {
+        self.db.collection(&self.config.expiry_reminders_collection)
     }
 
     #[inline]
@@ -153,10 +336,19 @@ impl Context {
     /// # Errors
     /// Fail on database error or user not found
     pub async fn find_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
-        self.users()
-            .find_one(query.as_document(), None)
-            .await
-            .map_err(Into::into)
+        self.store.find_user(query).await
+    }
+
+    /// A page of all users in `im`, sorted by id.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid cursor
+    pub async fn find_users_in(
+        &self,
+        im: &str,
+        page: &Page,
+    ) -> ApiResult<(Vec<User>, Option<Cursor>)> {
+        self.store.find_by_im(im, page).await
     }
 
     pub async fn add_user(
@@ -182,129 +374,526 @@ impl Context {
             im_payload,
             avatar,
             name,
-            event_filter: EventFilter {
-                entities: HashSet::default(),
-                kinds: HashSet::default(),
-            },
+            event_filter: EventFilter::default(),
+            quiet_hours: None,
+            max_per_hour: None,
+            link_id: None,
+            disabled: false,
+            token_generation: 0,
             id: Uuid::default(),
         };
 
-        self.users().insert_one(&user, None).await?;
+        self.store.insert_user(&user).await?;
         Ok(user)
     }
 
+    /// Add a user, or update name and avatar of the user with the same `im`
+    /// and `im_payload` if it exists, keeping its id and event filter.
+    ///
+    /// Return the user and whether it already existed.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn register_or_restore(
+        &self,
+        im: String,
+        im_payload: String,
+        avatar: Option<Url>,
+        name: String,
+    ) -> ApiResult<(User, bool)> {
+        let id = Uuid::new();
+        // Upsert in one operation, so that concurrent registrations don't
+        // create conflicting records.
+        let user = self
+            .store
+            .upsert_user(User {
+                id,
+                im,
+                im_payload,
+                name,
+                avatar,
+                event_filter: EventFilter::default(),
+                quiet_hours: None,
+                max_per_hour: None,
+                link_id: None,
+                disabled: false,
+                token_generation: 0,
+            })
+            .await?;
+        let restored = user.id != id;
+        Ok((user, restored))
+    }
+
     /// # Errors
     /// Fail on database error or user not found
     pub async fn del_user(&self, query: &UserQuery) -> ApiResult<User> {
-        self.users()
-            .find_one_and_delete(query.as_document(), None)
+        self.store
+            .delete_user(query)
             .await?
             .ok_or_else(|| query.as_error())
     }
 
     /// # Errors
     /// Fail on database error or user not found
-    pub async fn update_setting(&self, id: &Uuid, event_filter: &EventFilter) -> ApiResult<User> {
-        let serialized = to_document(&event_filter)?;
+    pub async fn update_setting(
+        &self,
+        id: &Uuid,
+        event_filter: &EventFilter,
+        quiet_hours: Option<QuietHours>,
+        max_per_hour: Option<u32>,
+    ) -> ApiResult<User> {
+        let mut event_filter = event_filter.clone();
+        event_filter.prune_expired(SystemTime::now());
 
-        self.users()
-            .find_one_and_update(
-                doc! { "id": id },
-                doc! { "$set": { "event_filter": serialized } },
-                FindOneAndUpdateOptions::builder()
-                    .return_document(ReturnDocument::After)
-                    .build(),
-            )
+        let user = self
+            .store
+            .update_setting(id, &event_filter, quiet_hours, max_per_hour)
+            .await?
+            .ok_or_else(|| ApiError::user_not_found_with_id(id))?;
+
+        // Linked users share the event filter.
+        if let Some(link_id) = user.link_id {
+            let linked: Vec<_> = self
+                .store
+                .find_linked(&link_id)
+                .await?
+                .into_iter()
+                .map(|user| user.id)
+                .collect();
+            self.store
+                .link_users(&linked, &link_id, &event_filter)
+                .await?;
+        }
+        Ok(user)
+    }
+
+    /// Disable deliveries to the user, or enable them again.
+    ///
+    /// # Errors
+    /// Fail on database error or user not found
+    pub async fn set_user_disabled(&self, id: &Uuid, disabled: bool) -> ApiResult<User> {
+        self.store
+            .set_disabled(id, disabled)
             .await?
             .ok_or_else(|| ApiError::user_not_found_with_id(id))
     }
 
-    pub async fn add_entity(&self, meta: Meta, tasks: Vec<AddTaskParam>) -> ApiResult<Entity> {
-        let mut ent = Entity {
-            id: Uuid::new(),
+    /// Issue a code for another user to link with the user by
+    /// [`Context::complete_link`].
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn start_link(&self, user_id: &Uuid) -> ApiResult<LinkCode> {
+        // Low bits of the second half of a random uuid are all random, unlike
+        // the version bits in the first half.
+        let code: String = Uuid::new().bytes()[8..]
+            .iter()
+            .map(|byte| char::from(LINK_CODE_ALPHABET[usize::from(byte % 32)]))
+            .collect();
+        let valid_until = SystemTime::now() + self.config.link_code_timeout;
+
+        self.link_codes()
+            .insert_one(
+                LinkCodeRecord {
+                    code: code.clone(),
+                    user_id: (*user_id).into(),
+                    expires_at: unix_millis(valid_until),
+                },
+                None,
+            )
+            .await?;
+        Ok(LinkCode { code, valid_until })
+    }
+
+    /// Link `user` with the user who issued `code`, along with users already
+    /// linked to either of them. Their event filters are merged into one,
+    /// keeping the language of the issuer if it's set. Return the updated
+    /// `user`.
+    ///
+    /// # Errors
+    /// Fail on database error, invalid code, or if the issuer no longer
+    /// exists or is `user` itself
+    pub async fn complete_link(&self, user: User, code: &str) -> ApiResult<User> {
+        let now = SystemTime::now();
+        // Codes are used once, so they're removed as they're looked up.
+        let record = self
+            .link_codes()
+            .find_one_and_delete(
+                doc! {
+                    "code": code.trim().to_ascii_uppercase(),
+                    "expires_at": { "$gt": unix_millis(now) },
+                },
+                None,
+            )
+            .await?
+            .ok_or_else(ApiError::invalid_link_code)?;
+        if Uuid::from(record.user_id) == user.id {
+            return Err(ApiError::bad_request("Cannot link a user with itself"));
+        }
+        let issuer = self
+            .find_user(&UserQuery::ById {
+                user_id: record.user_id,
+            })
+            .await?
+            .ok_or_else(|| ApiError::user_not_found_with_id(&record.user_id.into()))?;
+
+        let mut linked = vec![];
+        for member in [&issuer, &user] {
+            match &member.link_id {
+                Some(link_id) => linked.extend(
+                    self.store
+                        .find_linked(link_id)
+                        .await?
+                        .into_iter()
+                        .map(|user| user.id),
+                ),
+                None => linked.push(member.id),
+            }
+        }
+        let link_id = issuer.link_id.or(user.link_id).unwrap_or_else(Uuid::new);
+        let mut event_filter = issuer.event_filter;
+        event_filter.merge(&user.event_filter);
+        event_filter.prune_expired(now);
+
+        self.store
+            .link_users(&linked, &link_id, &event_filter)
+            .await?;
+        Ok(User {
+            event_filter,
+            link_id: Some(link_id),
+            ..user
+        })
+    }
+
+    /// Remove expired subscriptions of all users. Returns count of users affected.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn prune_expired_subscriptions(&self) -> ApiResult<usize> {
+        let now = SystemTime::now();
+        let users = self.store.users_with_expiry().await?;
+
+        let mut pruned = 0;
+        for user in users {
+            let expired: Vec<_> = user
+                .event_filter
+                .expiry
+                .into_iter()
+                .filter(|expiry| expiry.until <= now)
+                .collect();
+            if expired.is_empty() {
+                continue;
+            }
+            // Skip users who renewed the subscriptions in the meantime.
+            if self.store.prune_expired(&user.id, &expired).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Add an entity with `tasks`, and tasks rendered from `auto_tasks`.
+    pub async fn add_entity(
+        &self,
+        meta: Meta,
+        tasks: Vec<AddTaskParam>,
+        auto_tasks: Vec<TaskTemplate>,
+    ) -> ApiResult<Entity> {
+        tasks.iter().try_for_each(AddTaskParam::validate)?;
+        validate_templates(&auto_tasks)?;
+        if let Some(group) = &meta.group {
+            self.find_group(group).await?;
+        }
+
+        let id = Uuid::new();
+        let mut tasks = self.add_tasks(&id, tasks.into_iter()).await?;
+        let rendered = render_templates(id, &meta, &auto_tasks);
+        self.store.insert_tasks(&rendered).await?;
+        tasks.extend(rendered);
+
+        // The entity refers to all of its tasks once they are stored.
+        let ent = Entity {
+            id,
             meta,
-            tasks: vec![],
+            tasks: tasks.into_iter().map(|x| x.id).collect(),
+            auto_tasks,
         };
+        self.store.insert_entity(&ent).await?;
 
-        self.entities().insert_one(&ent, None).await?;
+        Ok(ent)
+    }
+
+    /// # Errors
+    /// Fail on database error or entity not found
+    pub async fn find_entity(&self, id: &Uuid) -> ApiResult<Entity> {
+        self.store
+            .find_entity(id)
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// Replace the meta of an entity, and its task templates if
+    /// `auto_tasks` is set, then [reconcile](Self::reconcile_tasks) its tasks.
+    ///
+    /// # Errors
+    /// Fail on database error, entity or its group not found, unknown task
+    /// kind or failed to serialize meta
+    pub async fn update_entity(
+        &self,
+        id: &Uuid,
+        meta: &Meta,
+        auto_tasks: Option<&[TaskTemplate]>,
+    ) -> ApiResult<Entity> {
+        if let Some(group) = &meta.group {
+            self.find_group(group).await?;
+        }
+        if let Some(auto_tasks) = auto_tasks {
+            validate_templates(auto_tasks)?;
+            self.store
+                .update_entity(id, EntityUpdate::AutoTasks(auto_tasks))
+                .await?
+                .ok_or_else(|| ApiError::entity_not_found(id))?;
+        }
+
+        let entity = self
+            .store
+            .update_entity(id, EntityUpdate::Meta(meta))
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(id))?;
+        self.reconcile_tasks(entity).await
+    }
 
-        ent.tasks = self
-            .add_tasks(&ent.id, tasks.into_iter())
+    /// Bring automatic tasks of an entity in line with its templates and meta:
+    /// add tasks rendered from the templates that are missing, and delete
+    /// automatic ones no template renders anymore, e.g. after an account
+    /// changed. Tasks added otherwise are kept. Return the updated entity.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn reconcile_tasks(&self, entity: Entity) -> ApiResult<Entity> {
+        let rendered = render_templates(entity.id, &entity.meta, &entity.auto_tasks);
+        let current: Vec<_> = self
+            .store
+            .find_tasks(&entity.id)
             .await?
             .into_iter()
-            .map(|x| x.id)
+            .filter(Task::is_auto)
             .collect();
 
-        Ok(ent)
+        let stale: Vec<_> = current
+            .iter()
+            .filter(|task| !rendered.iter().any(|new| new.same_as(task)))
+            .collect();
+        let missing: Vec<_> = rendered
+            .into_iter()
+            .filter(|new| !current.iter().any(|task| task.same_as(new)))
+            .collect();
+        if stale.is_empty() && missing.is_empty() {
+            return Ok(entity);
+        }
+
+        for task in stale {
+            tracing::info!(entity = %entity.id, task = %task.id, "Removing automatic task");
+            self.del_task(&task.id).await?;
+        }
+        for task in missing {
+            tracing::info!(entity = %entity.id, task = %task.id, "Adding automatic task");
+            self.add_task(&entity.id, task).await?;
+        }
+        self.find_entity(&entity.id).await
+    }
+
+    pub async fn del_entity(&self, id: &Uuid) -> ApiResult<Entity> {
+        // Get the entity, make sure it exists and get all related tasks
+        let entity = self
+            .store
+            .delete_entity(id)
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(&id))?;
+
+        // Delete all related tasks
+        self.store.delete_tasks(&entity.tasks).await?;
+
+        Ok(entity)
     }
 
     /// # Errors
-    /// Fail on database error or entity not found
-    pub async fn find_entity(&self, id: &Uuid) -> ApiResult<Entity> {
-        self.entities()
+    /// Fail on database error
+    pub async fn add_group(&self, name: Name) -> ApiResult<Group> {
+        let group = Group {
+            id: Uuid::new(),
+            name,
+        };
+        self.groups().insert_one(&group, None).await?;
+        Ok(group)
+    }
+
+    /// Groups of the ids, skipping ones not found.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn find_groups(&self, ids: &[Uuid]) -> ApiResult<Vec<Group>> {
+        Ok(self
+            .groups()
+            .find(doc! { "id": { "$in": ids } }, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// # Errors
+    /// Fail on database error or group not found
+    pub async fn find_group(&self, id: &Uuid) -> ApiResult<Group> {
+        self.groups()
             .find_one(doc! { "id": id }, None)
             .await?
-            .ok_or_else(|| ApiError::entity_not_found(id))
+            .ok_or_else(|| ApiError::group_not_found(id))
     }
 
     /// # Errors
-    /// Fail on database error, entity not found or failed to serialize meta
-    pub async fn update_entity(&self, id: &Uuid, meta: &Meta) -> ApiResult<Entity> {
-        self.entities()
+    /// Fail on database error, group not found or failed to serialize name
+    pub async fn update_group(&self, id: &Uuid, name: &Name) -> ApiResult<Group> {
+        self.groups()
             .find_one_and_update(
                 doc! { "id": id },
-                doc! { "meta": to_document(meta)? },
+                doc! { "$set": { "name": to_document(name)? } },
                 FindOneAndUpdateOptions::builder()
                     .return_document(ReturnDocument::After)
                     .build(),
             )
             .await?
-            .ok_or_else(|| ApiError::entity_not_found(id))
+            .ok_or_else(|| ApiError::group_not_found(id))
     }
 
-    pub async fn del_entity(&self, id: &Uuid) -> ApiResult<Entity> {
-        // Get the entity, make sure it exists and get all related tasks
-        let entity = self
-            .entities()
+    /// # Errors
+    /// Fail on database error or group not found
+    pub async fn del_group(&self, id: &Uuid) -> ApiResult<Group> {
+        let group = self
+            .groups()
             .find_one_and_delete(doc! { "id": id }, None)
             .await?
-            .ok_or_else(|| ApiError::entity_not_found(&id))?;
+            .ok_or_else(|| ApiError::group_not_found(id))?;
 
-        // Delete all related tasks
-        self.tasks()
-            .delete_many(doc! { "id": { "$in": &entity.tasks } }, None)
-            .await?;
+        // Neither entities nor subscriptions may refer to a deleted group
+        self.store.leave_group(id).await?;
+        self.store.unsubscribe_group(id).await?;
 
-        Ok(entity)
+        Ok(group)
+    }
+
+    /// # Errors
+    /// Fail on database error, entity or group not found
+    pub async fn set_entity_group(
+        &self,
+        entity_id: &Uuid,
+        group_id: Option<&Uuid>,
+    ) -> ApiResult<Entity> {
+        if let Some(group_id) = group_id {
+            self.find_group(group_id).await?;
+        }
+
+        self.store
+            .update_entity(entity_id, EntityUpdate::Group(group_id))
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(entity_id))
+    }
+
+    /// Set the profile of an entity on the platform of `kind`, keeping other
+    /// parts of its meta.
+    ///
+    /// # Errors
+    /// Fail on database error, entity not found or invalid kind
+    pub async fn update_entity_meta(
+        &self,
+        entity_id: &Uuid,
+        kind: &str,
+        profile: &Profile,
+    ) -> ApiResult<Entity> {
+        if kind.is_empty() || kind.contains(['.', '$']) {
+            return Err(ApiError::bad_request(format!("Invalid kind `{kind}`")));
+        }
+
+        self.store
+            .update_entity(entity_id, EntityUpdate::Profile(kind, profile))
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(entity_id))
     }
 
-    pub async fn get_entities(&self) -> ApiResult<Entities> {
-        let (vtbs, groups) = try_join(
-            async { self.entities().find(None, None).await?.try_collect().await },
-            async { self.groups().find(None, None).await?.try_collect().await },
+    pub async fn get_entities(&self, vtbs: &Page, groups: &Page) -> ApiResult<Entities> {
+        let ((vtbs, next_vtbs), (groups, next_groups)) = try_join(
+            self.store.find_entities(vtbs),
+            Self::find_page(&self.groups(), doc! {}, groups),
         )
             .await?;
 
-        Ok(Entities { vtbs, groups })
+        Ok(Entities {
+            vtbs,
+            groups,
+            next_vtbs,
+            next_groups,
+        })
+    }
+
+    /// A page of entities, sorted by id.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid cursor
+    pub async fn find_entities(&self, page: &Page) -> ApiResult<(Vec<Entity>, Option<Cursor>)> {
+        self.store.find_entities(page).await
+    }
+
+    /// Entities whose names or aliases contain any word of `query`, best
+    /// matches first, at most `limit` of them.
+    ///
+    /// # Errors
+    /// Fail on database error or blank query
+    pub async fn search_entities(&self, query: &str, limit: Option<u32>) -> ApiResult<Vec<Entity>> {
+        if query.trim().is_empty() {
+            return Err(ApiError::bad_request("Query must not be blank"));
+        }
+
+        self.store.search_entities(query, limit).await
+    }
+
+    /// Find a page of items matching `filter` in the collection, sorted by id.
+    /// Return the items and the cursor of the next page, if there is one.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid cursor
+    pub async fn find_page<T>(
+        collection: &Collection<T>,
+        filter: Document,
+        page: &Page,
+    ) -> ApiResult<(Vec<T>, Option<Cursor>)>
+    where
+        T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+    {
+        let (filter, options) = page.query(filter, BY_ID)?;
+        let items = collection.find(filter, options).await?.try_collect().await?;
+        page.split(items, BY_ID)
+    }
+
+    /// Tasks of any of the entities.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn find_tasks_of(&self, entity_ids: &[Uuid]) -> ApiResult<Vec<Task>> {
+        self.store.find_tasks_of(entity_ids).await
     }
 
     /// # Errors
     /// Fail on database error or task not found
     pub async fn add_task(&self, entity_id: &Uuid, task: Task) -> ApiResult<Task> {
         if self
-            .entities()
-            .update_one(
-                doc! { "id": entity_id },
-                doc! { "$push": { "tasks": task.id } },
-                None,
-            )
+            .store
+            .update_entity(entity_id, EntityUpdate::AddTask(&task.id))
             .await?
-            .modified_count
-            == 0
+            .is_none()
         {
             Err(ApiError::entity_not_found(entity_id))
         } else {
-            self.tasks().insert_one(&task, None).await?;
+            self.store.insert_tasks(slice::from_ref(&task)).await?;
             Ok(task)
         }
     }
@@ -320,7 +909,7 @@ impl Context {
             .map(|x| x.into_task_with(*entity_id))
             .collect::<Vec<_>>();
 
-        self.tasks().insert_many(&tasks, None).await?;
+        self.store.insert_tasks(&tasks).await?;
         Ok(tasks)
     }
 
@@ -329,42 +918,374 @@ impl Context {
     pub async fn del_task(&self, task_id: &Uuid) -> ApiResult<Task> {
         // Make sure this exists
         let task = self
-            .tasks()
-            .find_one_and_delete(doc! { "id": task_id }, None)
+            .store
+            .delete_task(task_id)
             .await?
             .ok_or_else(|| ApiError::task_not_found(task_id))?;
 
         // Delete the task from the entity that holds it
-        self.entities()
-            .update_one(
-                doc! { "id": task.entity },
-                doc! { "tasks": { "$pull": task_id } },
-                None,
-            )
+        self.store
+            .update_entity(&task.entity, EntityUpdate::RemoveTask(task_id))
             .await?;
 
         Ok(task)
     }
 
+    /// Users in `im` whose event filter passes events of `kind` from the
+    /// entity, directly or through its group, and the cursor of the next page.
+    /// Exclusions depending on event fields are left to the caller. Disabled
+    /// users are left out, of announcements too.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid cursor
     pub async fn get_interest(
         &self,
         entity_id: Uuid,
         kind: &str,
         im: &str,
-    ) -> ApiResult<Vec<User>> {
-        Ok(self
-            .users()
-            .find(
+        page: &Page,
+    ) -> ApiResult<(Vec<User>, Option<Cursor>)> {
+        if kind == ANNOUNCEMENT {
+            let (mut users, next) = self.store.find_by_im(im, page).await?;
+            users.retain(|user| !user.disabled);
+            return Ok((users, next));
+        }
+        // Members of subscribed groups are resolved for each event, so that
+        // they follow changes of groups.
+        let group_id = self
+            .store
+            .find_entity(&entity_id)
+            .await?
+            .and_then(|entity| entity.meta.group);
+        let (mut users, next) = self
+            .store
+            .find_interested(&entity_id, group_id.as_ref(), kind, im, page)
+            .await?;
+        // Filtered after paging, so that the cursor stays at the last user
+        // queried. Filters are returned expanded, for bots to match events
+        // against.
+        users.retain_mut(|user| {
+            user.event_filter.expand_group(entity_id, group_id);
+            !user.disabled && user.event_filter.matches_kind(entity_id, kind)
+        });
+        Ok((users, next))
+    }
+
+    /// Publish an announcement to users of `im`, or of every IM if not set.
+    /// Return the ID of the published event.
+    ///
+    /// # Errors
+    /// Fail if `amqp_url` is not set, the signing key is invalid, or the
+    /// broker is unreachable
+    pub async fn broadcast(&self, message: String, im: Option<String>) -> ApiResult<Uuid> {
+        let url = self.config.amqp_url.as_ref().ok_or_else(|| {
+            ApiError::bad_request("Broadcasting requires `amqp_url` to be configured")
+        })?;
+        let event = announcement(message, im)?;
+        let event_id = event.id;
+        self.publish(url, event).await?;
+        Ok(event_id)
+    }
+
+    /// Publish an event to the broker at `url`, signed if `signing_key` is
+    /// set.
+    async fn publish(&self, url: &str, event: Event) -> ApiResult<()> {
+        // Announcements are rare, so a connection is made for each of them
+        // instead of kept open.
+        let publish = async {
+            let mq = RabbitMQ::new(url, &self.config.amqp_exchange).await?;
+            let mq: Box<dyn MessageQueue> = match &self.config.signing_key {
+                Some(key) => Box::new(Signing::new(mq, key.parse()?)),
+                None => Box::new(mq),
+            };
+            mq.publish(event, Middlewares::default()).await?;
+            mq.close().await
+        };
+        publish.await.map_err(|detail| {
+            tracing::error!(?detail, "Failed to publish announcement");
+            ApiError::internal()
+        })
+    }
+
+    /// Remind users of subscriptions expiring within `expiry_reminder`, by an
+    /// announcement delivered to the user only. Each expiry is reminded of
+    /// once, even by several servers. Return the number of reminders sent.
+    ///
+    /// # Errors
+    /// Fail on database error, or if an announcement can't be published
+    pub async fn remind_expiring_subscriptions(&self) -> ApiResult<usize> {
+        let within = self.config.expiry_reminder;
+        let Some(url) = self.config.amqp_url.as_ref().filter(|_| !within.is_zero()) else {
+            return Ok(0);
+        };
+        let now = SystemTime::now();
+        // Records are needed only until the subscriptions expire.
+        self.expiry_reminders()
+            .delete_many(doc! { "until": { "$lte": unix_millis(now) } }, None)
+            .await?;
+
+        let mut reminded = 0;
+        for user in self.store.users_with_expiry().await? {
+            if user.disabled {
+                continue;
+            }
+            for expiry in user.event_filter.expiring(now, within) {
+                let Some(entity) = self.store.find_entity(&expiry.entity).await? else {
+                    continue;
+                };
+                let record = ReminderRecord {
+                    user_id: user.id.into(),
+                    entity_id: expiry.entity.into(),
+                    until: unix_millis(expiry.until),
+                };
+                // The record is inserted first, so that other servers skip
+                // the expiry.
+                match self.expiry_reminders().insert_one(&record, None).await {
+                    Ok(_) => {}
+                    Err(error) if is_duplicate_key(&error) => continue,
+                    Err(error) => return Err(error.into()),
+                }
+
+                let name = &entity.meta.name;
+                let name = name
+                    .name
+                    .get(&user.event_filter.language.unwrap_or(name.default_language))
+                    .or_else(|| name.name.get(&name.default_language))
+                    .map_or_else(|| entity.id.to_string(), Clone::clone);
+                let message = format!(
+                    "Your subscription to {name} expires at {}.",
+                    format_rfc3339_seconds(expiry.until)
+                );
+                let mut event = announcement(message, Some(user.im.clone()))?;
+                event
+                    .fields
+                    .insert(DELIVER_TO.to_owned(), vec![user.id.to_string()].into());
+                if let Err(error) = self.publish(url, event).await {
+                    // Removed to be retried on the next run.
+                    self.expiry_reminders()
+                        .delete_one(
+                            doc! {
+                                "user_id": record.user_id,
+                                "entity_id": record.entity_id,
+                                "until": record.until,
+                            },
+                            None,
+                        )
+                        .await?;
+                    return Err(error);
+                }
+                reminded += 1;
+            }
+        }
+
+        Ok(reminded)
+    }
+
+    /// Record a call to `method` with its request and outcome in the audit
+    /// log, on behalf of the claims of this context.
+    ///
+    /// # Errors
+    /// Fail on database error, or if the context has no claims
+    pub async fn record_audit(
+        &self,
+        method: &str,
+        request: serde_json::Value,
+        outcome: AuditOutcome,
+    ) -> ApiResult<()> {
+        let claims = self.claims().ok_or_else(ApiError::unauthorized)?;
+        let actor = AuditActor {
+            user_id: claims.id().into(),
+            privilege: claims.privilege(),
+        };
+        let entry = AuditEntry {
+            id: Id::new(),
+            time: unix_millis(SystemTime::now()),
+            method: method.to_owned(),
+            actor,
+            request,
+            outcome,
+        };
+
+        self.audit_log().insert_one(&entry, None).await?;
+        Ok(())
+    }
+
+    /// A page of the audit log in order of calls, and the cursor of the next
+    /// page.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid cursor
+    pub async fn get_audit_log(
+        &self,
+        page: &Page,
+    ) -> ApiResult<(Vec<AuditEntry>, Option<Cursor>)> {
+        let (filter, options) = page.query(doc! {}, BY_TIME)?;
+        let entries = self.audit_log().find(filter, options).await?.try_collect().await?;
+        page.split(entries, BY_TIME)
+    }
+
+    /// Record deliveries of `event` to users it reached and users it failed
+    /// to reach, and count them in the statistics of its entity.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn add_notifications(
+        &self,
+        event: &Event,
+        delivered: &[Id],
+        failed: &[Id],
+    ) -> ApiResult<()> {
+        let time = unix_millis(SystemTime::now());
+        let notifications: Vec<_> = delivered
+            .iter()
+            .map(|user_id| (user_id, true))
+            .chain(failed.iter().map(|user_id| (user_id, false)))
+            .map(|(user_id, delivered)| Notification {
+                id: Id::new(),
+                user_id: *user_id,
+                time,
+                event: event.clone(),
+                delivered,
+            })
+            .collect();
+        if !notifications.is_empty() {
+            self.notifications().insert_many(notifications, None).await?;
+        }
+        if event.kind != ANNOUNCEMENT {
+            self.count_deliveries(event, delivered.len(), failed.len(), time)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Count deliveries of `event` recorded at `time` in the hour of its
+    /// entity and kind.
+    async fn count_deliveries(
+        &self,
+        event: &Event,
+        delivered: usize,
+        failed: usize,
+        time: i64,
+    ) -> ApiResult<()> {
+        let count = |n: usize| i64::try_from(n).unwrap_or(i64::MAX);
+        self.entity_stats()
+            .update_one(
                 doc! {
-                  "event_filter.entities": entity_id,
-                  "event_filter.kinds": kind,
-                  "im": im,
+                    "entity_id": event.entity,
+                    "kind": &event.kind,
+                    "start": StatsBucket::Hour.start_of(time),
                 },
-                None,
+                doc! {
+                    "$addToSet": { "event_ids": event.id },
+                    "$inc": { "delivered": count(delivered), "failed": count(failed) },
+                },
+                UpdateOptions::builder().upsert(true).build(),
             )
+            .await?;
+        Ok(())
+    }
+
+    /// Statistics of an entity from `since` until `until`, both as Unix
+    /// timestamps in milliseconds, in buckets of `bucket`, oldest first. The
+    /// first bucket starts at its boundary before `since`.
+    ///
+    /// # Errors
+    /// Fail on database error or an empty range
+    pub async fn get_entity_stats(
+        &self,
+        entity_id: &Uuid,
+        since: i64,
+        until: Option<i64>,
+        bucket: StatsBucket,
+    ) -> ApiResult<Vec<EntityStats>> {
+        let until = until.unwrap_or_else(|| unix_millis(SystemTime::now()));
+        if since >= until {
+            return Err(ApiError::bad_request("`since` must be before `until`"));
+        }
+
+        let filter = doc! {
+            "entity_id": entity_id,
+            "start": {
+                "$gte": bucket.start_of(since),
+                "$lt": until,
+            },
+        };
+        let records: Vec<StatsRecord> = self
+            .entity_stats()
+            .find(filter, None)
             .await?
             .try_collect()
-            .await?)
+            .await?;
+
+        let mut buckets = BTreeMap::<_, (HashSet<Id>, i64, i64)>::new();
+        for record in records {
+            let (events, delivered, failed) = buckets
+                .entry((bucket.start_of(record.start), record.kind))
+                .or_default();
+            events.extend(record.event_ids);
+            *delivered += record.delivered;
+            *failed += record.failed;
+        }
+        let count = |n: i64| u64::try_from(n).unwrap_or_default();
+        Ok(buckets
+            .into_iter()
+            .map(|((start, kind), (events, delivered, failed))| EntityStats {
+                start,
+                kind,
+                events: events.len() as u64,
+                delivered: count(delivered),
+                failed: count(failed),
+            })
+            .collect())
+    }
+
+    /// A page of notifications of a user in order of time, and the cursor of
+    /// the next page.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid cursor
+    pub async fn get_notifications(
+        &self,
+        user_id: &Uuid,
+        page: &Page,
+    ) -> ApiResult<(Vec<Notification>, Option<Cursor>)> {
+        let (filter, options) = page.query(doc! { "user_id": user_id }, BY_TIME)?;
+        let notifications = self
+            .notifications()
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+        page.split(notifications, BY_TIME)
+    }
+
+    /// Remove notifications older than the retention. Return the number of
+    /// removed notifications.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn prune_notifications(&self) -> ApiResult<u64> {
+        let cutoff = SystemTime::now()
+            .checked_sub(self.config.notification_retention)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let result = self
+            .notifications()
+            .delete_many(doc! { "time": { "$lt": unix_millis(cutoff) } }, None)
+            .await?;
+        Ok(result.deleted_count)
+    }
+
+    /// Remove expired link codes. Return the number of removed codes.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn prune_link_codes(&self) -> ApiResult<u64> {
+        let now = unix_millis(SystemTime::now());
+        let result = self
+            .link_codes()
+            .delete_many(doc! { "expires_at": { "$lte": now } }, None)
+            .await?;
+        Ok(result.deleted_count)
     }
 
     /// # Errors
@@ -377,6 +1298,67 @@ impl Context {
         if user_id.bytes() == [0; 16] {
             return Err(ApiError::unauthorized());
         }
-        self.find_user(&UserQuery::ById { user_id }).await
+        self.find_user(&UserQuery::ById {
+            user_id: user_id.into(),
+        })
+        .await
+    }
+}
+
+/// Milliseconds since the Unix epoch of `time`.
+/// Announcement of `message` to users of `im`, or of every IM if not set.
+fn announcement(message: String, im: Option<String>) -> ApiResult<Event> {
+    Event::from_payload(
+        Uuid::from_bytes([0; 16]),
+        &AnnouncementPayload { message, im },
+    )
+    .map_err(|_| ApiError::internal())
+}
+
+fn is_duplicate_key(error: &MongoError) -> bool {
+    match &*error.kind {
+        ErrorKind::Command(error) => error.code == DUPLICATE_KEY,
+        ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    let millis = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    i64::try_from(millis).unwrap_or(i64::MAX)
+}
+
+/// Make sure task templates are of known kinds.
+fn validate_templates(templates: &[TaskTemplate]) -> ApiResult<()> {
+    let errors: Vec<_> = templates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, template)| {
+            validate_task_kind(&template.kind)
+                .err()
+                .map(|e| FieldError::new(format!("auto_tasks[{i}].kind"), e.to_string()))
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_fields(errors))
+    }
+}
+
+/// Tasks of an entity rendered from `templates`, leaving out duplicates.
+fn render_templates(entity_id: Uuid, meta: &Meta, templates: &[TaskTemplate]) -> Vec<Task> {
+    let mut tasks: Vec<Task> = vec![];
+    for task in templates
+        .iter()
+        .filter_map(|template| template.render(entity_id, meta))
+    {
+        if !tasks.iter().any(|other| other.same_as(&task)) {
+            tasks.push(task);
+        }
     }
+    tasks
 }
@@ -1,6 +1,9 @@
+use std::convert::Infallible;
+
 use axum::{
+    async_trait,
     body::{self, Body, Full},
-    extract::{Extension, Json},
+    extract::{Extension, FromRequest, Json, RequestParts},
     response::Response as AxumResponse,
     routing::{post, Router},
 };
@@ -9,8 +12,9 @@ use http::{header, HeaderValue};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    rpc::{ApiError, ApiResult, Request, Response},
-    server::Context,
+    model::AuditOutcome,
+    rpc::{ApiError, ApiResult, Encoding, Request, Response},
+    server::{authorize, Context},
 };
 
 /// Marker trait to ensure handlers are in a good shape.
@@ -30,6 +34,8 @@ impl<Req, Func, Fut> Method<Req, Fut> for Func
 }
 
 pub trait RouterExt {
+    /// Route `Req::METHOD` to the method, once the token of the request is
+    /// checked to grant the permission the method requires.
     #[must_use]
     fn mount<M, Req, Fut>(self, method: M) -> Self
         where
@@ -37,6 +43,16 @@ pub trait RouterExt {
             Fut: Future<Output=ApiResult<Req::Res>> + Send,
             Req: DeserializeOwned + Request + Send + 'static,
             Req::Res: Serialize;
+
+    /// Like [`RouterExt::mount`], but record every call of the method in the
+    /// audit log.
+    #[must_use]
+    fn mount_audited<M, Req, Fut>(self, method: M) -> Self
+        where
+            M: Method<Req, Fut> + Send + Clone + 'static,
+            Fut: Future<Output=ApiResult<Req::Res>> + Send,
+            Req: Serialize + DeserializeOwned + Request + Send + 'static,
+            Req::Res: Serialize;
 }
 
 impl RouterExt for Router<Body> {
@@ -47,17 +63,70 @@ impl RouterExt for Router<Body> {
             R: DeserializeOwned + Request + Send + 'static,
             R::Res: Serialize,
     {
-        let handler = move |Json(req): Json<R>, Extension(ctx): Extension<Context>| async {
-            match method.invoke(ctx, req).await {
-                Ok(res) => res.as_response(),
-                Err(e) => e.as_response(),
+        let handler = move |Json(req): Json<R>,
+                            Extension(ctx): Extension<Context>,
+                            encoding: Encoding| async move {
+            let res = match authorize::<R>(&ctx).await {
+                Ok(()) => method.invoke(ctx, req).await,
+                Err(e) => Err(e),
+            };
+            into_response(res, encoding)
+        };
+
+        self.route(&("/".to_owned() + R::METHOD), post(handler))
+    }
+
+    fn mount_audited<M, R, F>(self, method: M) -> Self
+        where
+            M: Method<R, F> + Send + Clone + 'static,
+            F: Future<Output=ApiResult<R::Res>> + Send,
+            R: Serialize + DeserializeOwned + Request + Send + 'static,
+            R::Res: Serialize,
+    {
+        let handler = move |Json(req): Json<R>,
+                            Extension(ctx): Extension<Context>,
+                            encoding: Encoding| async move {
+            let request = serde_json::to_value(&req).unwrap_or_default();
+            // `res` is dropped before recording, since `Req::Res` may not be `Send`.
+            let (outcome, response) = {
+                let res = match authorize::<R>(&ctx).await {
+                    Ok(()) => method.invoke(ctx.clone(), req).await,
+                    Err(e) => Err(e),
+                };
+                (AuditOutcome::of(&res), into_response(res, encoding))
+            };
+
+            if let Err(detail) = ctx.record_audit(R::METHOD, request, outcome).await {
+                tracing::error!(?detail, method = R::METHOD, "Failed to record audit log");
             }
+            response
         };
 
         self.route(&("/".to_owned() + R::METHOD), post(handler))
     }
 }
 
+fn into_response<R: Response + Serialize>(res: ApiResult<R>, encoding: Encoding) -> AxumResponse {
+    match res {
+        Ok(res) => res.as_response_in(encoding),
+        Err(e) => e.as_response_in(encoding),
+    }
+}
+
+/// Encoding of the response, negotiated by the `Accept` header.
+#[async_trait]
+impl<B: Send> FromRequest<B> for Encoding {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map_or_else(Self::default, Self::negotiate))
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for ApiError {
     fn from(e: jsonwebtoken::errors::Error) -> Self {
         tracing::warn!("{}", e);
@@ -98,18 +167,24 @@ impl From<sg_auth::Error> for ApiError {
 }
 
 pub trait ResponseExt: Response + Serialize {
-    fn as_response(&self) -> AxumResponse;
+    /// Response in JSON.
+    fn as_response(&self) -> AxumResponse {
+        self.as_response_in(Encoding::Json)
+    }
+
+    /// Response in the given encoding.
+    fn as_response_in(&self, encoding: Encoding) -> AxumResponse;
 }
 
 impl<R: Response + Serialize> ResponseExt for R {
-    fn as_response(&self) -> AxumResponse {
+    fn as_response_in(&self, encoding: Encoding) -> AxumResponse {
         AxumResponse::builder()
             .status(self.status())
             .header(
                 header::CONTENT_TYPE,
-                HeaderValue::from_static("application/json"),
+                HeaderValue::from_static(encoding.content_type()),
             )
-            .body(body::boxed(Full::from(self.packed().to_json_bytes())))
+            .body(body::boxed(Full::from(self.packed().to_bytes(encoding))))
             .expect("Status and header should be statically known and not having any parsing issue")
     }
 }
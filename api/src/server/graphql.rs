@@ -0,0 +1,362 @@
+//! Read-only GraphQL gateway, for dashboards to fetch entities along with
+//! their groups and tasks in one request.
+//!
+//! It's served at `/v1/graphql` over the same [`Context`] as the RPC API,
+//! behind the admin guard, and requires read access to `admin`. Queries
+//! nested deeper than `graphql_max_depth` are rejected before they're run.
+//! Groups and tasks of entities are loaded in batches per request, so that
+//! listing entities doesn't query them once for each.
+#![allow(clippy::unused_async)]
+
+use std::collections::{BTreeMap, HashMap};
+
+use async_graphql::{
+    dataloader::{DataLoader, Loader},
+    Context as GraphQLContext, EmptyMutation, EmptySubscription, Json as GraphQLJson, Object,
+    Request as GraphQLRequest, Result, Schema, SimpleObject, ID,
+};
+use async_trait::async_trait;
+use axum::{
+    extract::Extension,
+    response::IntoResponse,
+    routing::{post, MethodRouter},
+    Json,
+};
+use mongodb::bson::{doc, Uuid};
+use serde_json::{Map, Value};
+use sg_core::models::{Entity, EventFilter, Group, Name, Profile, QuietHours, Task, User};
+
+use crate::{
+    model::UserQuery,
+    rpc::{ApiError, ApiResult, Cursor, Page},
+    server::{authorize_method, Config, Context, ResponseExt, GRAPHQL},
+};
+
+async_graphql::scalar!(Cursor, "Cursor", "Opaque cursor of a page.");
+
+/// Schema of the gateway.
+pub type ReadSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// Build the schema of the gateway, limiting the depth of queries as
+/// configured.
+#[must_use]
+pub fn read_schema(config: &Config) -> ReadSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(config.graphql_max_depth)
+        .finish()
+}
+
+/// Route answering GraphQL queries posted as JSON.
+pub fn graphql_route(config: &Config) -> MethodRouter {
+    let schema = read_schema(config);
+    post(
+        move |Extension(ctx): Extension<Context>, Json(req): Json<GraphQLRequest>| async move {
+            if let Err(e) = authorize_method(&ctx, GRAPHQL).await {
+                return e.as_response();
+            }
+            let groups = DataLoader::new(GroupLoader(ctx.clone()), tokio::spawn);
+            let tasks = DataLoader::new(TaskLoader(ctx.clone()), tokio::spawn);
+            let req = req.data(ctx).data(groups).data(tasks);
+            Json(schema.execute(req).await).into_response()
+        },
+    )
+}
+
+fn context<'a>(ctx: &GraphQLContext<'a>) -> &'a Context {
+    ctx.data_unchecked()
+}
+
+fn parse_id(id: &ID) -> ApiResult<Uuid> {
+    Uuid::parse_str(id.as_str())
+        .map_err(|_| ApiError::bad_request(format!("Invalid id `{}`", id.as_str())))
+}
+
+fn to_id(id: Uuid) -> ID {
+    ID(id.to_string())
+}
+
+/// Root of queries.
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// A page of entities, sorted by id.
+    async fn entities(
+        &self,
+        ctx: &GraphQLContext<'_>,
+        after: Option<Cursor>,
+        limit: Option<u32>,
+    ) -> Result<EntityPage> {
+        let (entities, next) = context(ctx).find_entities(&Page { after, limit }).await?;
+        Ok(EntityPage {
+            items: entities.into_iter().map(EntityNode).collect(),
+            next,
+        })
+    }
+
+    /// The entity of the id.
+    async fn entity(&self, ctx: &GraphQLContext<'_>, id: ID) -> Result<EntityNode> {
+        let entity = context(ctx).find_entity(&parse_id(&id)?).await?;
+        Ok(EntityNode(entity))
+    }
+
+    /// Entities whose names or aliases contain any word of `query`, best
+    /// matches first.
+    async fn search_entities(
+        &self,
+        ctx: &GraphQLContext<'_>,
+        query: String,
+        limit: Option<u32>,
+    ) -> Result<Vec<EntityNode>> {
+        let entities = context(ctx).search_entities(&query, limit).await?;
+        Ok(entities.into_iter().map(EntityNode).collect())
+    }
+
+    /// A page of groups, sorted by id.
+    async fn groups(
+        &self,
+        ctx: &GraphQLContext<'_>,
+        after: Option<Cursor>,
+        limit: Option<u32>,
+    ) -> Result<GroupPage> {
+        let ctx = context(ctx);
+        let (groups, next) =
+            Context::find_page(&ctx.groups(), doc! {}, &Page { after, limit }).await?;
+        Ok(GroupPage {
+            items: groups.into_iter().map(GroupNode).collect(),
+            next,
+        })
+    }
+
+    /// The group of the id.
+    async fn group(&self, ctx: &GraphQLContext<'_>, id: ID) -> Result<GroupNode> {
+        let group = context(ctx).find_group(&parse_id(&id)?).await?;
+        Ok(GroupNode(group))
+    }
+
+    /// A page of users in the IM, sorted by id. With `entity` and `kind`, only
+    /// users whose event filter passes events of `kind` from the entity are
+    /// listed, though pages may be shorter than `limit` then.
+    async fn users(
+        &self,
+        ctx: &GraphQLContext<'_>,
+        im: String,
+        entity: Option<ID>,
+        kind: Option<String>,
+        after: Option<Cursor>,
+        limit: Option<u32>,
+    ) -> Result<UserPage> {
+        let ctx = context(ctx);
+        let page = Page { after, limit };
+        let (users, next) = match (entity, kind) {
+            (Some(entity), Some(kind)) => {
+                ctx.get_interest(parse_id(&entity)?, &kind, &im, &page)
+                    .await?
+            }
+            (None, None) => ctx.find_users_in(&im, &page).await?,
+            _ => {
+                return Err(ApiError::bad_request("`entity` and `kind` must be set together").into())
+            }
+        };
+        Ok(UserPage {
+            items: users.into_iter().map(UserNode).collect(),
+            next,
+        })
+    }
+
+    /// The user of the id.
+    async fn user(&self, ctx: &GraphQLContext<'_>, id: ID) -> Result<UserNode> {
+        let user_id = parse_id(&id)?;
+        let user = context(ctx)
+            .find_user(&UserQuery::ById {
+                user_id: user_id.into(),
+            })
+            .await?
+            .ok_or_else(|| ApiError::user_not_found_with_id(&user_id))?;
+        Ok(UserNode(user))
+    }
+}
+
+/// A page of entities.
+#[derive(SimpleObject)]
+pub struct EntityPage {
+    items: Vec<EntityNode>,
+    /// Cursor of the next page, if there is one.
+    next: Option<Cursor>,
+}
+
+/// A page of groups.
+#[derive(SimpleObject)]
+pub struct GroupPage {
+    items: Vec<GroupNode>,
+    /// Cursor of the next page, if there is one.
+    next: Option<Cursor>,
+}
+
+/// A page of users.
+#[derive(SimpleObject)]
+pub struct UserPage {
+    items: Vec<UserNode>,
+    /// Cursor of the next page, if there is one.
+    next: Option<Cursor>,
+}
+
+/// An entity, e.g. a vtuber.
+pub struct EntityNode(Entity);
+
+#[Object(name = "Entity")]
+impl EntityNode {
+    async fn id(&self) -> ID {
+        to_id(self.0.id)
+    }
+
+    /// Name of the entity, in languages.
+    async fn name(&self) -> GraphQLJson<&Name> {
+        GraphQLJson(&self.0.meta.name)
+    }
+
+    /// Profiles of the entity on platforms, keyed by task kind.
+    async fn profiles(&self) -> GraphQLJson<&BTreeMap<String, Profile>> {
+        GraphQLJson(&self.0.meta.profiles)
+    }
+
+    /// Ids of the entity's accounts on platforms.
+    async fn accounts(&self) -> GraphQLJson<&BTreeMap<String, String>> {
+        GraphQLJson(&self.0.meta.accounts)
+    }
+
+    /// The group the entity is in, if any.
+    async fn group(&self, ctx: &GraphQLContext<'_>) -> Result<Option<GroupNode>> {
+        let Some(id) = self.0.meta.group else {
+            return Ok(None);
+        };
+        let groups = ctx.data_unchecked::<DataLoader<GroupLoader>>();
+        Ok(groups.load_one(id).await?.map(GroupNode))
+    }
+
+    /// Tasks of the entity.
+    async fn tasks(&self, ctx: &GraphQLContext<'_>) -> Result<Vec<TaskNode>> {
+        let tasks = ctx.data_unchecked::<DataLoader<TaskLoader>>();
+        let tasks = tasks.load_one(self.0.id).await?.unwrap_or_default();
+        Ok(tasks.into_iter().map(TaskNode).collect())
+    }
+}
+
+/// A group of entities.
+pub struct GroupNode(Group);
+
+#[Object(name = "Group")]
+impl GroupNode {
+    async fn id(&self) -> ID {
+        to_id(self.0.id)
+    }
+
+    /// Name of the group, in languages.
+    async fn name(&self) -> GraphQLJson<&Name> {
+        GraphQLJson(&self.0.name)
+    }
+}
+
+/// A task of an entity.
+pub struct TaskNode(Task);
+
+#[Object(name = "Task")]
+impl TaskNode {
+    async fn id(&self) -> ID {
+        to_id(self.0.id)
+    }
+
+    /// Kind of the task, e.g. `youtube`.
+    async fn kind(&self) -> &str {
+        &self.0.kind
+    }
+
+    /// Parameters of the task.
+    async fn params(&self) -> GraphQLJson<&Map<String, Value>> {
+        GraphQLJson(&self.0.params)
+    }
+}
+
+/// A user of an IM.
+pub struct UserNode(User);
+
+#[Object(name = "User")]
+impl UserNode {
+    async fn id(&self) -> ID {
+        to_id(self.0.id)
+    }
+
+    /// The IM the user is in, e.g. `tg`.
+    async fn im(&self) -> &str {
+        &self.0.im
+    }
+
+    /// IM payload, e.g. chat id in telegram.
+    async fn im_payload(&self) -> &str {
+        &self.0.im_payload
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn avatar(&self) -> Option<String> {
+        self.0.avatar.as_ref().map(ToString::to_string)
+    }
+
+    /// The events the user is subscribed to.
+    async fn event_filter(&self) -> GraphQLJson<&EventFilter> {
+        GraphQLJson(&self.0.event_filter)
+    }
+
+    /// Daily period in which events are held back.
+    async fn quiet_hours(&self) -> Option<GraphQLJson<&QuietHours>> {
+        self.0.quiet_hours.as_ref().map(GraphQLJson)
+    }
+
+    /// Max number of events delivered in a clock hour.
+    async fn max_per_hour(&self) -> Option<u32> {
+        self.0.max_per_hour
+    }
+
+    /// Id shared by users linked as the same person in different IMs.
+    async fn link_id(&self) -> Option<ID> {
+        self.0.link_id.map(to_id)
+    }
+
+    /// Whether deliveries to the user are disabled.
+    async fn disabled(&self) -> bool {
+        self.0.disabled
+    }
+}
+
+/// Loads groups of entities by id, in batches.
+pub struct GroupLoader(Context);
+
+#[async_trait]
+impl Loader<Uuid> for GroupLoader {
+    type Value = Group;
+    type Error = ApiError;
+
+    async fn load(&self, ids: &[Uuid]) -> ApiResult<HashMap<Uuid, Group>> {
+        let groups = self.0.find_groups(ids).await?;
+        Ok(groups.into_iter().map(|group| (group.id, group)).collect())
+    }
+}
+
+/// Loads tasks by the id of their entity, in batches.
+pub struct TaskLoader(Context);
+
+#[async_trait]
+impl Loader<Uuid> for TaskLoader {
+    type Value = Vec<Task>;
+    type Error = ApiError;
+
+    async fn load(&self, entity_ids: &[Uuid]) -> ApiResult<HashMap<Uuid, Vec<Task>>> {
+        let mut tasks: HashMap<_, Vec<_>> = HashMap::new();
+        for task in self.0.find_tasks_of(entity_ids).await? {
+            tasks.entry(task.entity).or_default().push(task);
+        }
+        Ok(tasks)
+    }
+}
@@ -2,25 +2,41 @@
 
 use std::sync::Arc;
 
-use axum::{extract::Extension, Router};
+use axum::{extract::Extension, routing::get, Json, Router};
 use color_eyre::Result;
-use http::Method;
 use mongodb::{bson::Uuid, Database};
-use tower_http::{cors, trace};
+use tower_http::{compression::CompressionLayer, trace};
 
-use sg_auth::{Permission, PermissionSet};
+use sg_auth::{Authentication, Enrollment, KeyRecord, PermissionSet};
+use sg_core::{
+    experiment::assign_all,
+    models::{validate_kind, QuietHours, User, KINDS},
+};
 
 use crate::{
-    model::{GetInterest, Health, Interest, Login, Null, UserQuery},
+    model::{
+        openapi, AddNotifications, EnrollTotp, EventKind, EventKinds, GetEventKinds, GetInterest,
+        GetNotifications, Health, Interest, Login, LoginWithKey, Notifications, Null, TotpSecret,
+        UserQuery, VerifyTotp,
+    },
     rpc::{
         ApiError,
         ApiResult, model::{
-            AddEntity, AddTask, AddUser, Authorized, AuthUser, DelEntity, DelTask, DelUser,
-            GetEntities, NewToken, Token, UpdateEntity, UpdateSetting,
+            AddEntity, AddGroup, AddTask, AddUser, AuditLog, Authorized, AuthUser, Broadcast,
+            Broadcasted, CompleteLink, DelEntity, DelGroup, DelTask, DelUser, EntityStatsList,
+            GetAuditLog, GetEntities, GetEntityStats, GetGroup, LinkCode, NewToken, Registered,
+            RegisterOrRestore, SearchEntities, SearchResult, SetEntityGroup, SetUserDisabled,
+            StartLink, TaskValidation, Token, UpdateEntity, UpdateEntityMeta, UpdateGroup,
+            UpdateSetting, ValidateTask,
         },
     },
-    server::{Config, Context, JWTContext, JWTGuard, Privilege, RouterExt},
+    server::{
+        cors_layer, csrf_token, healthz, readyz, Config, Context, CsrfGuard, JWTContext,
+        JWTGuard, Privilege, RouterExt,
+    },
 };
+#[cfg(feature = "graphql")]
+use crate::server::graphql_route;
 
 /// Construct the router.
 ///
@@ -37,10 +53,7 @@ pub async fn make_app(config: Config) -> Result<Router> {
 pub async fn make_app_with(config: Config, db: Option<Database>) -> Result<Router> {
     let config = Arc::new(config);
 
-    let cors_layer = cors::CorsLayer::new()
-        .allow_methods(vec![Method::POST])
-        .allow_credentials(true)
-        .allow_origin(cors::Any);
+    let cors_layer = cors_layer(&config)?;
     let trace_layer = trace::TraceLayer::new_for_http();
 
     let jwt = Arc::new(JWTContext::new(&config));
@@ -53,8 +66,13 @@ pub async fn make_app_with(config: Config, db: Option<Database>) -> Result<Route
         None => Context::new(jwt, config).await?,
     };
 
+    ctx.create_indexes().await?;
+    tokio::spawn(prune(ctx.clone()));
+
+    let openapi = Json(openapi());
+
     let api = Router::new()
-        .mount(
+        .mount_audited(
             |AddUser {
                  im,
                  im_payload,
@@ -65,66 +83,243 @@ pub async fn make_app_with(config: Config, db: Option<Database>) -> Result<Route
                 async move { ctx.add_user(im, im_payload, avatar, name).await }
             },
         )
-        .mount(|AddEntity { meta, tasks }, ctx: Context| async move {
-            ctx.add_entity(meta, tasks).await
-        })
-        .mount(|req: AddTask, ctx: Context| async move {
+        .mount_audited(
+            |AddEntity {
+                 meta,
+                 tasks,
+                 auto_tasks,
+             },
+             ctx: Context| async move { ctx.add_entity(meta, tasks, auto_tasks).await },
+        )
+        .mount_audited(|req: AddTask, ctx: Context| async move {
+            req.param.validate()?;
             let id = req.entity_id;
             ctx.add_task(&id, req.into()).await
         })
-        .mount(
+        .mount(|ValidateTask { param }, _| async move {
+            Ok(TaskValidation {
+                errors: param.field_errors(),
+            })
+        })
+        .mount_audited(
             |DelEntity { entity_id }, ctx: Context| async move { ctx.del_entity(&entity_id).await },
         )
-        .mount(|DelTask { task_id }, ctx: Context| async move { ctx.del_task(&task_id).await })
-        .mount(
-            |UpdateEntity { entity_id, meta }, ctx: Context| async move {
-                ctx.update_entity(&entity_id, &meta).await
+        .mount_audited(
+            |DelTask { task_id }, ctx: Context| async move { ctx.del_task(&task_id).await },
+        )
+        .mount_audited(
+            |UpdateEntity {
+                 entity_id,
+                 meta,
+                 auto_tasks,
+             },
+             ctx: Context| async move {
+                ctx.update_entity(&entity_id, &meta, auto_tasks.as_deref())
+                    .await
             },
         )
+        .mount_audited(|AddGroup { name }, ctx: Context| async move { ctx.add_group(name).await })
+        .mount_audited(|UpdateGroup { group_id, name }, ctx: Context| async move {
+            ctx.update_group(&group_id, &name).await
+        })
+        .mount_audited(
+            |DelGroup { group_id }, ctx: Context| async move { ctx.del_group(&group_id).await },
+        )
+        .mount_audited(
+            |SetEntityGroup {
+                 entity_id,
+                 group_id,
+             },
+             ctx: Context| async move {
+                ctx.set_entity_group(&entity_id, group_id.as_deref()).await
+            },
+        )
+        .mount_audited(broadcast)
+        .mount(get_audit_log)
+        .mount(get_entity_stats);
+    // The gateway reads what admin methods do, so it's guarded like them.
+    #[cfg(feature = "graphql")]
+    let api = api.route("/graphql", graphql_route(ctx.config()));
+    let api = api
         .layer(admin_guard)
         .mount(
             |GetInterest {
                  entity_id,
                  kind,
                  im,
+                 page,
              },
              ctx: Context| async move {
-                ctx.get_interest(entity_id, &kind, &im)
+                ctx.get_interest(*entity_id, &kind, &im, &page)
                     .await
-                    .map(|users| Interest { users })
+                    .map(|(users, next)| Interest { users, next })
             },
         )
-        .mount(|GetEntities {}, ctx: Context| async move { ctx.get_entities().await })
+        .mount(|GetEntities { vtbs, groups }, ctx: Context| async move {
+            ctx.get_entities(&vtbs, &groups).await
+        })
+        .mount(search_entities)
+        .mount(|GetGroup { group_id }, ctx: Context| async move { ctx.find_group(&group_id).await })
         .mount(new_token)
-        .mount(|DelUser { query }, ctx: Context| async move { ctx.del_user(&query).await })
+        .mount(register_or_restore)
+        .mount(
+            |AddNotifications {
+                 event,
+                 delivered,
+                 failed,
+             },
+             ctx: Context| async move {
+                ctx.add_notifications(&event, &delivered, &failed)
+                    .await
+                    .map(|()| Null)
+            },
+        )
+        .mount(
+            |UpdateEntityMeta {
+                 entity_id,
+                 kind,
+                 profile,
+             },
+             ctx: Context| async move {
+                ctx.update_entity_meta(&entity_id, &kind, &profile).await
+            },
+        )
+        .mount_audited(|DelUser { query }, ctx: Context| async move { ctx.del_user(&query).await })
+        .mount_audited(
+            |SetUserDisabled { user_id, disabled }, ctx: Context| async move {
+                ctx.set_user_disabled(&user_id, disabled).await
+            },
+        )
         .layer(bot_guard)
-        .mount(|UpdateSetting { event_filter }, ctx: Context| async move {
-            let id = ctx.assert_user_claims()?.id();
-            ctx.update_setting(&id, &event_filter).await
-        })
+        .mount(
+            |UpdateSetting {
+                 event_filter,
+                 quiet_hours,
+                 max_per_hour,
+             },
+             ctx: Context| async move {
+                event_filter
+                    .referenced_kinds()
+                    .try_for_each(|kind| validate_kind(kind).map(drop))
+                    .and_then(|()| quiet_hours.as_ref().map_or(Ok(()), QuietHours::validate))
+                    .map_err(|e| ApiError::bad_request(e.to_string()))?;
+                for group_id in &event_filter.groups {
+                    ctx.find_group(group_id).await?;
+                }
+                let id = ctx.assert_user_claims()?.id();
+                ctx.update_setting(&id, &event_filter, quiet_hours, max_per_hour)
+                    .await
+            },
+        )
         .mount(auth_user)
+        .mount(get_notifications)
+        .mount(start_link)
+        .mount(complete_link)
         .layer(user_guard)
         .mount(|Health {}, _| async { Ok(Null) })
+        .mount(|GetEventKinds {}, _| async {
+            Ok(EventKinds {
+                kinds: KINDS.iter().map(EventKind::from).collect(),
+            })
+        })
         .mount(login)
-        .layer(Extension(ctx))
+        .mount(login_with_key)
+        .mount(enroll_totp)
+        .mount(verify_totp)
+        .route(
+            "/openapi.json",
+            get(move || std::future::ready(openapi.clone())),
+        )
+        .route("/csrf", get(csrf_token));
+    let api = if ctx.config().csrf_protection {
+        api.layer(CsrfGuard.into_layer())
+    } else {
+        api
+    };
+    let api = api
+        .layer(Extension(ctx.clone()))
+        // Responses are compressed as accepted by `Accept-Encoding`.
+        .layer(CompressionLayer::new())
         .layer(cors_layer)
         .layer(trace_layer);
 
-    Ok(Router::new().nest("/v1", api))
+    Ok(Router::new()
+        .nest("/v1", api)
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(Extension(ctx)))
+}
+
+async fn prune(ctx: Context) {
+    let mut interval = tokio::time::interval(ctx.config().prune_interval);
+    loop {
+        interval.tick().await;
+        match ctx.prune_expired_subscriptions().await {
+            Ok(0) => {}
+            Ok(users) => tracing::info!(users, "Pruned expired subscriptions"),
+            Err(error) => tracing::error!(?error, "Failed to prune expired subscriptions"),
+        }
+        match ctx.remind_expiring_subscriptions().await {
+            Ok(0) => {}
+            Ok(reminders) => tracing::info!(reminders, "Reminded of expiring subscriptions"),
+            Err(error) => tracing::error!(?error, "Failed to remind of expiring subscriptions"),
+        }
+        match ctx.prune_notifications().await {
+            Ok(0) => {}
+            Ok(notifications) => tracing::info!(notifications, "Pruned old notifications"),
+            Err(error) => tracing::error!(?error, "Failed to prune old notifications"),
+        }
+        match ctx.prune_link_codes().await {
+            Ok(0) => {}
+            Ok(codes) => tracing::info!(codes, "Pruned expired link codes"),
+            Err(error) => tracing::error!(?error, "Failed to prune expired link codes"),
+        }
+    }
 }
 
 async fn login(req: Login, ctx: Context) -> ApiResult<Token> {
-    let prv = match ctx
+    let permissions = match ctx
         .auth()
-        .look_up(req.username, req.password.as_bytes())
+        .authenticate(req.username, req.password.as_bytes(), req.totp.as_deref())
         .await?
     {
-        PermissionSet { admin: Some(p), .. } if p == Permission::ReadWrite => Privilege::Admin,
-        PermissionSet { api: Some(p), .. } if p == Permission::ReadWrite => Privilege::Bot,
+        Authentication::Granted(permissions) => permissions,
+        Authentication::TotpRequired => return Err(ApiError::totp_required()),
+        Authentication::Denied => return Err(ApiError::unauthorized()),
+    };
+    issue_token(permissions, None, &ctx)
+}
+
+async fn login_with_key(LoginWithKey { key }: LoginWithKey, ctx: Context) -> ApiResult<Token> {
+    let record = ctx
+        .auth()
+        .look_up_key(key)
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+
+    issue_token(record.scopes(), Some(&record), &ctx)
+}
+
+/// Issue a token with a nil user id, the highest privilege `permissions`
+/// grant any access to and the permissions themselves, which methods check.
+///
+/// Tokens issued for an API `key` expire with it at the latest, and are
+/// rejected once it's revoked.
+fn issue_token(
+    permissions: PermissionSet,
+    key: Option<&KeyRecord>,
+    ctx: &Context,
+) -> ApiResult<Token> {
+    let prv = match permissions {
+        PermissionSet { admin: Some(_), .. } => Privilege::Admin,
+        PermissionSet { api: Some(_), .. } => Privilege::Bot,
         _ => return Err(ApiError::unauthorized()),
     };
 
-    let (token, claims) = ctx.encode(&Uuid::from_bytes([0; 16]), prv)?;
+    let (token, claims) = match key {
+        Some(key) => ctx.encode_key(prv, key)?,
+        None => ctx.encode_account(prv, permissions)?,
+    };
 
     Ok(Token {
         token,
@@ -132,18 +327,161 @@ async fn login(req: Login, ctx: Context) -> ApiResult<Token> {
     })
 }
 
+async fn enroll_totp(
+    EnrollTotp {
+        username,
+        password,
+        totp,
+    }: EnrollTotp,
+    ctx: Context,
+) -> ApiResult<TotpSecret> {
+    let totp = match ctx
+        .auth()
+        .enroll_totp(&username, password.as_bytes(), totp.as_deref())
+        .await?
+    {
+        Enrollment::Enrolled(totp) => totp,
+        Enrollment::TotpRequired => return Err(ApiError::totp_required()),
+        Enrollment::Denied => return Err(ApiError::unauthorized()),
+    };
+
+    Ok(TotpSecret {
+        secret: totp.to_base32(),
+        uri: totp.uri("Stargazer", &username),
+    })
+}
+
+async fn verify_totp(
+    VerifyTotp {
+        username,
+        password,
+        code,
+    }: VerifyTotp,
+    ctx: Context,
+) -> ApiResult<Null> {
+    if ctx
+        .auth()
+        .verify_totp(username, password.as_bytes(), code)
+        .await?
+    {
+        Ok(Null)
+    } else {
+        Err(ApiError::unauthorized().explain("Invalid TOTP code or no TOTP secret enrolled"))
+    }
+}
+
 async fn auth_user(_: AuthUser, ctx: Context) -> ApiResult<Authorized> {
     let claims = ctx.assert_user_claims()?;
     let user = ctx
         .find_user(&UserQuery::ById {
-            user_id: claims.id(),
+            user_id: claims.id().into(),
         })
         .await?
         .ok_or_else(|| ApiError::user_not_found_with_id(&claims.id()))?;
 
+    let experiments = assign_all(user.id, &ctx.config().experiments);
+
     Ok(Authorized {
         user,
         valid_until: claims.valid_until(),
+        experiments,
+    })
+}
+
+async fn get_notifications(
+    GetNotifications { user_id, page }: GetNotifications,
+    ctx: Context,
+) -> ApiResult<Notifications> {
+    let claims = ctx.claims().ok_or_else(ApiError::unauthorized)?;
+    let user_id: Uuid = if claims.id().bytes() == [0; 16] {
+        // Tokens of bots and admins are not issued to a user.
+        user_id
+            .ok_or_else(|| ApiError::bad_request("`user_id` is required for this token"))?
+            .into()
+    } else {
+        match user_id {
+            Some(user_id) if user_id != claims.id() => return Err(ApiError::unauthorized()),
+            _ => claims.id(),
+        }
+    };
+
+    let (notifications, next) = ctx.get_notifications(&user_id, &page).await?;
+    Ok(Notifications {
+        notifications,
+        next,
+    })
+}
+
+async fn start_link(_: StartLink, ctx: Context) -> ApiResult<LinkCode> {
+    let user = ctx
+        .find_and_assert_claim()
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+    ctx.start_link(&user.id).await
+}
+
+async fn complete_link(CompleteLink { code }: CompleteLink, ctx: Context) -> ApiResult<User> {
+    let user = ctx
+        .find_and_assert_claim()
+        .await?
+        .ok_or_else(ApiError::unauthorized)?;
+    ctx.complete_link(user, &code).await
+}
+
+async fn search_entities(
+    SearchEntities { query, limit }: SearchEntities,
+    ctx: Context,
+) -> ApiResult<SearchResult> {
+    let entities = ctx.search_entities(&query, limit).await?;
+    Ok(SearchResult { entities })
+}
+
+async fn register_or_restore(req: RegisterOrRestore, ctx: Context) -> ApiResult<Registered> {
+    let RegisterOrRestore {
+        im,
+        im_payload,
+        avatar,
+        name,
+    } = req;
+
+    let (user, restored) = ctx
+        .register_or_restore(im, im_payload, avatar, name)
+        .await?;
+    let (token, claim) = ctx.encode(&user.id, Privilege::User, user.token_generation)?;
+
+    Ok(Registered {
+        user,
+        restored,
+        token,
+        valid_until: claim.valid_until(),
+    })
+}
+
+async fn get_audit_log(GetAuditLog { page }: GetAuditLog, ctx: Context) -> ApiResult<AuditLog> {
+    let (entries, next) = ctx.get_audit_log(&page).await?;
+    Ok(AuditLog { entries, next })
+}
+
+async fn get_entity_stats(
+    GetEntityStats {
+        entity_id,
+        since,
+        until,
+        bucket,
+    }: GetEntityStats,
+    ctx: Context,
+) -> ApiResult<EntityStatsList> {
+    let stats = ctx.get_entity_stats(&entity_id, since, until, bucket).await?;
+    Ok(EntityStatsList { stats })
+}
+
+async fn broadcast(Broadcast { message, im }: Broadcast, ctx: Context) -> ApiResult<Broadcasted> {
+    if message.trim().is_empty() {
+        return Err(ApiError::bad_request("`message` must not be empty"));
+    }
+    let event_id = ctx.broadcast(message, im).await?;
+    Ok(Broadcasted {
+        event_id: event_id.into(),
     })
 }
 
@@ -155,7 +493,7 @@ async fn new_token(req: NewToken, ctx: Context) -> ApiResult<Token> {
         .await?
         .ok_or_else(|| ApiError::user_not_found_with_query(query))?;
 
-    let (token, claim) = ctx.encode(&user.id, Privilege::User)?;
+    let (token, claim) = ctx.encode(&user.id, Privilege::User, user.token_generation)?;
 
     Ok(Token {
         token,
@@ -13,26 +13,15 @@ use jsonwebtoken::{
 };
 use mongodb::bson::Uuid;
 use serde::{Deserialize, Serialize};
+use sg_auth::{KeyRecord, Permission, PermissionSet};
 use tower_http::auth::{AuthorizeRequest, RequireAuthorizationLayer};
 
+pub use crate::model::Privilege;
 use crate::{
     rpc::ApiError,
     server::{Config, Context, ResponseExt},
 };
 
-/// Privilege of a token. Three levels: User, Bot, Admin.
-///
-/// - **User** can only access some API, mostly related to themselves.
-/// - **Bot** can access more API, include creating session for users.
-/// - **Admin** can access all API.
-#[must_use]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-pub enum Privilege {
-    User,
-    Bot,
-    Admin,
-}
-
 #[must_use]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 /// The JWT claim. Contains the user id and the expiry time.
@@ -43,6 +32,18 @@ pub struct Claims {
     exp: u64,
     /// Privilege of this token
     prv: Privilege,
+    /// Permissions of the account the token is issued to. Not set on tokens
+    /// of users.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scp: Option<PermissionSet>,
+    /// Bytes representation of the id of the API key the token is issued for,
+    /// if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<[u8; 16]>,
+    /// Token generation of the user the token is issued to. Zero on tokens of
+    /// bots and admins.
+    #[serde(default)]
+    gen: u32,
 }
 
 impl Claims {
@@ -58,6 +59,45 @@ impl Claims {
         self.exp
     }
 
+    /// Privilege of the token.
+    pub const fn privilege(&self) -> Privilege {
+        self.prv
+    }
+
+    /// Permissions of the account the token is issued to, checked against
+    /// those methods require. `None` for tokens of users.
+    ///
+    /// Tokens issued before permissions were carried get the permissions their
+    /// privilege used to require.
+    pub fn permissions(&self) -> Option<PermissionSet> {
+        if self.prv == Privilege::User {
+            return None;
+        }
+        self.scp.or_else(|| {
+            let mut permissions = PermissionSet::EMPTY;
+            if self.prv == Privilege::Admin {
+                permissions.admin = Some(Permission::ReadWrite);
+            } else {
+                permissions.api = Some(Permission::ReadWrite);
+            }
+            Some(permissions)
+        })
+    }
+
+    /// Id of the API key the token is issued for, which is checked on each
+    /// request. `None` for tokens issued by logging in with a password.
+    #[must_use]
+    pub fn key_id(&self) -> Option<Uuid> {
+        self.kid.map(Uuid::from_bytes)
+    }
+
+    /// Token generation of the user the token is issued to, which must still
+    /// be the one of the user.
+    #[must_use]
+    pub const fn generation(&self) -> u32 {
+        self.gen
+    }
+
     /// User id represented as [`Uuid`].
     #[must_use]
     pub const fn id(&self) -> Uuid {
@@ -107,15 +147,65 @@ impl JWTContext {
             .as_secs()
     }
 
-    /// Encode the user id and corresponding privilege into a JWT token.
-    pub fn encode(&self, user_id: &Uuid, privilege: Privilege) -> JwtResult<(String, Claims)> {
-        let claim = Claims {
+    /// Encode the user id, corresponding privilege and token generation of the
+    /// user into a JWT token.
+    pub fn encode(
+        &self,
+        user_id: &Uuid,
+        privilege: Privilege,
+        generation: u32,
+    ) -> JwtResult<(String, Claims)> {
+        self.encode_claims(Claims {
             aud: user_id.bytes(),
             exp: self.calculate_exp(),
             prv: privilege,
-        };
-        let token = jsonwebtoken::encode(&self.header, &claim, &self.encode_key)?;
-        Ok((token, claim))
+            scp: None,
+            kid: None,
+            gen: generation,
+        })
+    }
+
+    /// Encode the privilege and permissions of a bot or admin account into a
+    /// JWT token with a nil user id.
+    pub fn encode_account(
+        &self,
+        privilege: Privilege,
+        permissions: PermissionSet,
+    ) -> JwtResult<(String, Claims)> {
+        self.encode_claims(Claims {
+            aud: [0; 16],
+            exp: self.calculate_exp(),
+            prv: privilege,
+            scp: Some(permissions),
+            kid: None,
+            gen: 0,
+        })
+    }
+
+    /// Encode the privilege and permissions of an API key into a JWT token
+    /// with a nil user id, which expires with the key at the latest.
+    pub fn encode_key(&self, privilege: Privilege, key: &KeyRecord) -> JwtResult<(String, Claims)> {
+        let exp = self.calculate_exp();
+        let exp = key.expiry().map_or(exp, |expiry| {
+            let expiry = expiry
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            exp.min(expiry)
+        });
+        self.encode_claims(Claims {
+            aud: [0; 16],
+            exp,
+            prv: privilege,
+            scp: Some(key.scopes()),
+            kid: Some(key.id().bytes()),
+            gen: 0,
+        })
+    }
+
+    fn encode_claims(&self, claims: Claims) -> JwtResult<(String, Claims)> {
+        let token = jsonwebtoken::encode(&self.header, &claims, &self.encode_key)?;
+        Ok((token, claims))
     }
 
     /// Decode the token and validate the token is not expired, which is done automatically by [`jsonwebtoken`].
@@ -215,7 +305,7 @@ fn test_jwt() {
     let user_id = Uuid::parse_str("20bdc51a-a23e-4f38-bbff-739d2b8ded4d").unwrap();
 
     let config = Config {
-        jwt_secret: "Secret".to_string(),
+        jwt_secret: sg_core::utils::Redacted("Secret".to_string()),
         token_timeout: Duration::from_secs(1),
         ..Config::default()
     };
@@ -225,7 +315,7 @@ fn test_jwt() {
 
     println!("{:#?}", jwt);
 
-    let (token, _) = jwt.encode(&user_id, Privilege::User).unwrap();
+    let (token, _) = jwt.encode(&user_id, Privilege::User, 0).unwrap();
     println!("{}", token);
 
     // Valid and not expired
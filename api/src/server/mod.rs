@@ -3,7 +3,12 @@
 use color_eyre::Result;
 use sg_core::utils::FigmentExt;
 
-mod_use::mod_use![config, handler, jwt, context, ext];
+mod_use::mod_use![config, handler, jwt, context, ext, probe, repo, permission, origin];
+
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "graphql")]
+pub use graphql::*;
 
 #[allow(clippy::missing_errors_doc)]
 pub async fn serve_with_config(config: Config) -> Result<()> {
@@ -24,5 +29,5 @@ pub async fn serve_with_config(config: Config) -> Result<()> {
 
 #[allow(clippy::missing_errors_doc)]
 pub async fn serve() -> Result<()> {
-    serve_with_config(Config::from_env("API_")?).await
+    serve_with_config(Config::from_sources("API_")?).await
 }
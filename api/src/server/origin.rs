@@ -0,0 +1,169 @@
+//! Access from browsers on other origins, e.g. the settings page.
+//!
+//! Browsers are allowed in by CORS, and their requests are protected against
+//! CSRF by a double-submitted token: a request with an `Origin` header must
+//! repeat the token of its cookie in the `X-CSRF-Token` header.
+
+use axum::{body::BoxBody, http::Request, response::IntoResponse, Json};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use mongodb::bson::Uuid;
+use serde_json::json;
+use tower_http::{
+    auth::{AuthorizeRequest, RequireAuthorizationLayer},
+    cors::{self, AllowOrigin, CorsLayer},
+};
+
+use crate::{
+    rpc::ApiError,
+    server::{Config, ResponseExt},
+};
+
+/// Cookie the CSRF token is kept in.
+pub const CSRF_COOKIE: &str = "csrf_token";
+/// Header requests from browsers repeat the CSRF token in.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// CORS layer letting browsers on `cors_allowed_origins` call the API, or on
+/// any origin without credentials if there's none.
+///
+/// # Errors
+/// Fails on invalid origins or headers, or if credentials are allowed without
+/// allowed origins.
+pub fn cors_layer(config: &Config) -> Result<CorsLayer> {
+    let headers = config
+        .cors_allowed_headers
+        .iter()
+        .map(|name| {
+            name.parse::<HeaderName>()
+                .wrap_err_with(|| format!("Invalid CORS header `{name}`"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let layer = CorsLayer::new()
+        .allow_methods(vec![Method::GET, Method::POST])
+        .allow_headers(headers);
+
+    if config.cors_allowed_origins.is_empty() {
+        if config.cors_allow_credentials {
+            bail!("CORS credentials are only allowed with allowed origins");
+        }
+        return Ok(layer.allow_origin(cors::Any));
+    }
+    let origins = config
+        .cors_allowed_origins
+        .iter()
+        .map(|origin| {
+            origin
+                .parse::<HeaderValue>()
+                .wrap_err_with(|| format!("Invalid CORS origin `{origin}`"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(layer
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_credentials(config.cors_allow_credentials))
+}
+
+/// Issue a CSRF token, set in the cookie and returned in the body for the
+/// page to repeat in the header.
+pub async fn csrf_token() -> impl IntoResponse {
+    let token = Uuid::new().to_string();
+    let cookie = format!("{CSRF_COOKIE}={token}; Path=/; Secure; HttpOnly; SameSite=None");
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(json!({ "token": token })),
+    )
+}
+
+/// A guard that can be used with
+/// [`tower_http::auth::RequireAuthorizationLayer`] to reject requests from
+/// browsers not repeating their CSRF token.
+///
+/// Requests without an `Origin` header, e.g. from bots and tools, and safe
+/// methods pass.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CsrfGuard;
+
+impl CsrfGuard {
+    #[must_use]
+    pub fn into_layer(self) -> RequireAuthorizationLayer<Self> {
+        RequireAuthorizationLayer::custom(self)
+    }
+}
+
+impl<B> AuthorizeRequest<B> for CsrfGuard {
+    type ResponseBody = BoxBody;
+
+    fn authorize(
+        &mut self,
+        request: &mut Request<B>,
+    ) -> Result<(), http::Response<Self::ResponseBody>> {
+        let safe = matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS
+        );
+        if safe || !request.headers().contains_key(header::ORIGIN) || verify(request.headers()) {
+            Ok(())
+        } else {
+            tracing::debug!(path = ?request.uri().path(), "Rejecting request without CSRF token");
+            Err(ApiError::bad_csrf_token().as_response())
+        }
+    }
+}
+
+/// Whether the CSRF token in the header matches the one in the cookie.
+fn verify(headers: &HeaderMap) -> bool {
+    let submitted = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let cookie = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(name, value)| (name == CSRF_COOKIE).then_some(value));
+    match (submitted, cookie) {
+        (Some(submitted), Some(cookie)) => !cookie.is_empty() && submitted == cookie,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderName, HeaderValue};
+
+    use crate::server::origin::verify;
+
+    #[test]
+    fn test_verify_csrf() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            pairs
+                .iter()
+                .map(|&(name, value)| {
+                    (
+                        HeaderName::from_static(name),
+                        HeaderValue::from_static(value),
+                    )
+                })
+                .collect::<HeaderMap>()
+        };
+
+        assert!(verify(&headers(&[
+            ("cookie", "theme=dark; csrf_token=abc"),
+            ("x-csrf-token", "abc"),
+        ])));
+        assert!(!verify(&headers(&[
+            ("cookie", "csrf_token=abc"),
+            ("x-csrf-token", "abd"),
+        ])));
+        assert!(!verify(&headers(&[("cookie", "csrf_token=abc")])));
+        assert!(!verify(&headers(&[("x-csrf-token", "abc")])));
+        assert!(!verify(&headers(&[
+            ("cookie", "csrf_token="),
+            ("x-csrf-token", ""),
+        ])));
+    }
+}
@@ -0,0 +1,201 @@
+//! Permissions required by methods of bots and admins, checked against the
+//! permission set of the account a token is issued to.
+//!
+//! Guards only check the privilege of tokens, which is the highest one the
+//! account has any access to. Read-only accounts can call methods reading
+//! data, but not ones changing it. Methods listed nowhere are denied, so that
+//! one mounted without a permission isn't open to every token.
+
+use sg_auth::{Component, Permission, PermissionSet};
+
+use crate::{
+    model::{
+        AddEntity, AddGroup, AddNotifications, AddTask, AddUser, AuthUser, Broadcast,
+        CompleteLink, DelEntity, DelGroup, DelTask, DelUser, EnrollTotp, GetAuditLog,
+        GetEntities, GetEntityStats, GetEventKinds, GetGroup, GetInterest, GetNotifications,
+        Health, Login, LoginWithKey, NewToken, RegisterOrRestore, SearchEntities,
+        Privilege, SetEntityGroup, SetUserDisabled, StartLink, UpdateEntity, UpdateEntityMeta,
+        UpdateGroup, UpdateSetting, UserQuery, ValidateTask, VerifyTotp,
+    },
+    rpc::{ApiError, ApiResult, Request},
+    server::Context,
+};
+
+use Component::{Admin, Api};
+use Permission::{ReadOnly, ReadWrite};
+
+/// Permission each method requires, on `api` for methods of bots and on
+/// `admin` for methods of admins.
+pub const PERMISSIONS: &[(&str, Component, Permission)] = &[
+    // Bot methods
+    (GetInterest::METHOD, Api, ReadOnly),
+    (GetEntities::METHOD, Api, ReadOnly),
+    (SearchEntities::METHOD, Api, ReadOnly),
+    (GetGroup::METHOD, Api, ReadOnly),
+    (NewToken::METHOD, Api, ReadWrite),
+    (RegisterOrRestore::METHOD, Api, ReadWrite),
+    (AddNotifications::METHOD, Api, ReadWrite),
+    (UpdateEntityMeta::METHOD, Api, ReadWrite),
+    (DelUser::METHOD, Api, ReadWrite),
+    (SetUserDisabled::METHOD, Api, ReadWrite),
+    // Admin methods
+    (ValidateTask::METHOD, Admin, ReadOnly),
+    (GetAuditLog::METHOD, Admin, ReadOnly),
+    (GetEntityStats::METHOD, Admin, ReadOnly),
+    (AddUser::METHOD, Admin, ReadWrite),
+    (AddEntity::METHOD, Admin, ReadWrite),
+    (AddTask::METHOD, Admin, ReadWrite),
+    (DelEntity::METHOD, Admin, ReadWrite),
+    (DelTask::METHOD, Admin, ReadWrite),
+    (UpdateEntity::METHOD, Admin, ReadWrite),
+    (AddGroup::METHOD, Admin, ReadWrite),
+    (UpdateGroup::METHOD, Admin, ReadWrite),
+    (DelGroup::METHOD, Admin, ReadWrite),
+    (SetEntityGroup::METHOD, Admin, ReadWrite),
+    (Broadcast::METHOD, Admin, ReadWrite),
+    (GRAPHQL, Admin, ReadOnly),
+];
+
+/// Methods requiring no permission, only the privilege of their guard if
+/// any, i.e. methods of users and ones to log in with.
+pub const UNRESTRICTED: &[&str] = &[
+    // User methods
+    UpdateSetting::METHOD,
+    AuthUser::METHOD,
+    GetNotifications::METHOD,
+    StartLink::METHOD,
+    CompleteLink::METHOD,
+    // Public methods
+    Health::METHOD,
+    GetEventKinds::METHOD,
+    Login::METHOD,
+    LoginWithKey::METHOD,
+    EnrollTotp::METHOD,
+    VerifyTotp::METHOD,
+];
+
+/// Name the GraphQL gateway is checked by, as if it were a method.
+pub const GRAPHQL: &str = "graphql";
+
+/// Permission `method` requires, if any.
+#[must_use]
+pub fn required_permission(method: &str) -> Option<(Component, Permission)> {
+    PERMISSIONS
+        .iter()
+        .find(|(name, ..)| *name == method)
+        .map(|&(_, component, permission)| (component, permission))
+}
+
+/// Whether `permissions` grant `permission` on `component`. Permissions on
+/// `admin` cover methods of bots too, since admin tokens can call them.
+fn grants(permissions: &PermissionSet, component: Component, permission: Permission) -> bool {
+    permissions.grants(component, permission)
+        || (component == Api && permissions.grants(Admin, permission))
+}
+
+/// Check that the token of the request grants the permission `R` requires.
+///
+/// # Errors
+/// Fail if the method requires a permission the token doesn't grant.
+pub async fn authorize<R: Request>(ctx: &Context) -> ApiResult<()> {
+    authorize_method(ctx, R::METHOD).await
+}
+
+/// Check that the token of the request grants the permission `method`
+/// requires. Tokens issued for an API key are only good while the key is, and
+/// tokens of users until new ones are issued when the user is restored.
+///
+/// # Errors
+/// Fail if the method requires a permission the token doesn't grant, if the
+/// key the token is issued for is revoked or expired, if the token is of an
+/// older generation than the user, or if the method is listed in neither
+/// [`PERMISSIONS`] nor [`UNRESTRICTED`].
+pub async fn authorize_method(ctx: &Context, method: &str) -> ApiResult<()> {
+    if let Some(claims) = ctx
+        .claims()
+        .filter(|claims| claims.privilege() == Privilege::User)
+    {
+        let query = UserQuery::ById {
+            user_id: claims.id().into(),
+        };
+        let user = ctx.find_user(&query).await?;
+        if user.is_some_and(|user| user.token_generation != claims.generation()) {
+            return Err(ApiError::unauthorized());
+        }
+    }
+    let Some((component, permission)) = required_permission(method) else {
+        return if UNRESTRICTED.contains(&method) {
+            Ok(())
+        } else {
+            Err(ApiError::unauthorized())
+        };
+    };
+    let Some(claims) = ctx.claims() else {
+        return Err(ApiError::unauthorized());
+    };
+    match claims.permissions() {
+        Some(permissions) if grants(&permissions, component, permission) => {}
+        _ => return Err(ApiError::unauthorized()),
+    }
+    if let Some(key_id) = claims.key_id() {
+        if ctx.auth().find_key(key_id).await?.is_none() {
+            return Err(ApiError::unauthorized());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use sg_auth::{Component, Permission, PermissionSet};
+
+    use crate::{
+        model::openapi,
+        server::permission::{grants, required_permission, PERMISSIONS, UNRESTRICTED},
+    };
+
+    #[test]
+    fn must_list_methods_once() {
+        let methods: HashSet<_> = PERMISSIONS
+            .iter()
+            .map(|&(method, ..)| method)
+            .chain(UNRESTRICTED.iter().copied())
+            .collect();
+        assert_eq!(methods.len(), PERMISSIONS.len() + UNRESTRICTED.len());
+        assert_eq!(
+            required_permission("del_user"),
+            Some((Component::Api, Permission::ReadWrite))
+        );
+        assert_eq!(required_permission("auth_user"), None);
+    }
+
+    #[test]
+    fn must_list_every_method() {
+        let openapi = openapi();
+        let methods = openapi["paths"].as_object().unwrap().keys();
+        for method in methods.map(|path| path.trim_start_matches('/')) {
+            assert!(
+                required_permission(method).is_some() || UNRESTRICTED.contains(&method),
+                "{method} is denied to every token"
+            );
+        }
+    }
+
+    #[test]
+    fn must_grant() {
+        use Component::{Admin, Api};
+        use Permission::{ReadOnly, ReadWrite};
+
+        let mut permissions = PermissionSet::EMPTY;
+        permissions.admin = Some(ReadOnly);
+        assert!(grants(&permissions, Admin, ReadOnly));
+        assert!(!grants(&permissions, Admin, ReadWrite));
+        assert!(grants(&permissions, Api, ReadOnly));
+
+        permissions.api = Some(ReadWrite);
+        assert!(grants(&permissions, Api, ReadWrite));
+        assert!(!grants(&permissions, Admin, ReadWrite));
+    }
+}
@@ -0,0 +1,156 @@
+//! HTTP probes for orchestrators, e.g. liveness and readiness probes of
+//! Kubernetes.
+//!
+//! Unlike the `health` RPC method, probes actively check the dependencies of
+//! the server, and respond with `503 Service Unavailable` if any fails.
+
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use axum::{extract::Extension, http::StatusCode, Json};
+use color_eyre::{eyre::eyre, Result};
+use serde::Serialize;
+use tokio::{net::TcpStream, time::timeout};
+use url::Url;
+
+use crate::server::Context;
+
+/// Time a dependency check may take before it fails.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Default port of AMQP.
+const AMQP_PORT: u16 = 5672;
+
+/// Status of a dependency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+/// Result of checking a dependency.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: Status,
+    /// Reason of failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of probe responses.
+#[derive(Debug, Serialize)]
+pub struct Probe {
+    /// `up` if all dependencies are up.
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl FromIterator<(&'static str, Check)> for Probe {
+    fn from_iter<T: IntoIterator<Item = (&'static str, Check)>>(iter: T) -> Self {
+        let checks: BTreeMap<_, _> = iter.into_iter().collect();
+        let status = if checks.values().all(|check| check.status == Status::Up) {
+            Status::Up
+        } else {
+            Status::Down
+        };
+        Self { status, checks }
+    }
+}
+
+/// Liveness probe, checking the connection to `MongoDB`.
+pub async fn healthz(Extension(ctx): Extension<Context>) -> (StatusCode, Json<Probe>) {
+    respond([("mongodb", check(ctx.ping()).await)].into_iter().collect())
+}
+
+/// Readiness probe, checking the connection to `MongoDB`, access to the auth
+/// collection, and the AMQP broker if configured.
+pub async fn readyz(Extension(ctx): Extension<Context>) -> (StatusCode, Json<Probe>) {
+    let (mongodb, auth, amqp) = tokio::join!(
+        check(ctx.ping()),
+        check(async { ctx.auth().count().await.map(drop) }),
+        async {
+            match &ctx.config().amqp_url {
+                Some(url) => Some(check(connect(url)).await),
+                None => None,
+            }
+        },
+    );
+    let probe = [("mongodb", mongodb), ("auth", auth)]
+        .into_iter()
+        .chain(amqp.map(|amqp| ("amqp", amqp)))
+        .collect();
+    respond(probe)
+}
+
+fn respond(probe: Probe) -> (StatusCode, Json<Probe>) {
+    let code = match probe.status {
+        Status::Up => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(probe))
+}
+
+async fn check(fut: impl Future<Output = Result<()>>) -> Check {
+    let result = match timeout(CHECK_TIMEOUT, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(eyre!("Timed out after {CHECK_TIMEOUT:?}")),
+    };
+    match result {
+        Ok(()) => Check {
+            status: Status::Up,
+            error: None,
+        },
+        Err(error) => {
+            tracing::warn!(?error, "Dependency check failed");
+            Check {
+                status: Status::Down,
+                error: Some(error.to_string()),
+            }
+        }
+    }
+}
+
+/// Check the AMQP broker accepts connections, without logging in.
+async fn connect(url: &str) -> Result<()> {
+    let url = Url::parse(url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| eyre!("No host in AMQP url"))?;
+    let port = url.port().unwrap_or(AMQP_PORT);
+    TcpStream::connect((host, port)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::server::probe::{Check, Probe, Status};
+
+    fn check(status: Status) -> Check {
+        let error = (status == Status::Down).then(|| "unreachable".to_owned());
+        Check { status, error }
+    }
+
+    #[test]
+    fn must_report_down_if_any_is_down() {
+        let up: Probe = [("mongodb", check(Status::Up)), ("auth", check(Status::Up))]
+            .into_iter()
+            .collect();
+        assert_eq!(up.status, Status::Up);
+
+        let down: Probe = [("mongodb", check(Status::Up)), ("amqp", check(Status::Down))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            serde_json::to_value(down).unwrap(),
+            json!({
+                "status": "down",
+                "checks": {
+                    "amqp": { "status": "down", "error": "unreachable" },
+                    "mongodb": { "status": "up" },
+                },
+            })
+        );
+    }
+}
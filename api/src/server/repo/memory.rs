@@ -0,0 +1,574 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use async_trait::async_trait;
+use mongodb::bson::Uuid;
+use sg_core::models::{Entity, EventFilter, Expiry, QuietHours, Task, User};
+
+use crate::{
+    model::UserQuery,
+    rpc::{ApiResult, Cursor, Page},
+    server::{EntityRepo, EntityUpdate, TaskRepo, UserRepo},
+};
+
+/// Store in memory, lost on restart.
+///
+/// Unlike the text index of `MongoDB`, entity search doesn't ignore
+/// diacritics, and ranks entities by the count of matching words.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    users: Mutex<HashMap<Uuid, User>>,
+    tasks: Mutex<HashMap<Uuid, Task>>,
+    entities: Mutex<HashMap<Uuid, Entity>>,
+}
+
+/// Lock a collection. Collections are left consistent by every operation, so
+/// they're still usable after a panic.
+fn lock<T>(collection: &Mutex<T>) -> MutexGuard<'_, T> {
+    collection.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn matches(user: &User, query: &UserQuery) -> bool {
+    match query {
+        UserQuery::ById { user_id } => user.id == *user_id,
+        UserQuery::ByIm { im, im_payload } => user.im == *im && user.im_payload == *im_payload,
+    }
+}
+
+/// Lowercase words of `text`.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[async_trait]
+impl UserRepo for MemoryStore {
+    async fn find_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
+        Ok(lock(&self.users)
+            .values()
+            .find(|user| matches(user, query))
+            .cloned())
+    }
+
+    async fn insert_user(&self, user: &User) -> ApiResult<()> {
+        lock(&self.users).insert(user.id, user.clone());
+        Ok(())
+    }
+
+    async fn upsert_user(&self, user: User) -> ApiResult<User> {
+        let mut users = lock(&self.users);
+        let existing = users
+            .values()
+            .find(|existing| existing.im == user.im && existing.im_payload == user.im_payload)
+            .map(|existing| existing.id);
+        let user = match existing {
+            Some(id) => {
+                let existing = users.get_mut(&id).expect("found above");
+                existing.name = user.name;
+                existing.avatar = user.avatar;
+                existing.disabled = false;
+                existing
+            }
+            None => users.entry(user.id).or_insert(user),
+        };
+        user.token_generation += 1;
+        Ok(user.clone())
+    }
+
+    async fn delete_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
+        let mut users = lock(&self.users);
+        let id = users
+            .values()
+            .find(|user| matches(user, query))
+            .map(|user| user.id);
+        Ok(id.and_then(|id| users.remove(&id)))
+    }
+
+    async fn update_setting(
+        &self,
+        id: &Uuid,
+        event_filter: &EventFilter,
+        quiet_hours: Option<QuietHours>,
+        max_per_hour: Option<u32>,
+    ) -> ApiResult<Option<User>> {
+        Ok(lock(&self.users).get_mut(id).map(|user| {
+            user.event_filter = event_filter.clone();
+            user.quiet_hours = quiet_hours;
+            user.max_per_hour = max_per_hour;
+            user.clone()
+        }))
+    }
+
+    async fn set_disabled(&self, id: &Uuid, disabled: bool) -> ApiResult<Option<User>> {
+        Ok(lock(&self.users).get_mut(id).map(|user| {
+            user.disabled = disabled;
+            user.clone()
+        }))
+    }
+
+    async fn find_linked(&self, link_id: &Uuid) -> ApiResult<Vec<User>> {
+        Ok(lock(&self.users)
+            .values()
+            .filter(|user| user.link_id == Some(*link_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn link_users(
+        &self,
+        ids: &[Uuid],
+        link_id: &Uuid,
+        event_filter: &EventFilter,
+    ) -> ApiResult<()> {
+        let mut users = lock(&self.users);
+        for id in ids {
+            if let Some(user) = users.get_mut(id) {
+                user.link_id = Some(*link_id);
+                user.event_filter = event_filter.clone();
+            }
+        }
+        Ok(())
+    }
+
+    async fn users_with_expiry(&self) -> ApiResult<Vec<User>> {
+        Ok(lock(&self.users)
+            .values()
+            .filter(|user| !user.event_filter.expiry.is_empty())
+            .cloned()
+            .collect())
+    }
+
+    async fn prune_expired(&self, id: &Uuid, expired: &[Expiry]) -> ApiResult<bool> {
+        Ok(match lock(&self.users).get_mut(id) {
+            Some(user)
+                if expired
+                    .iter()
+                    .all(|expiry| user.event_filter.expiry.contains(expiry)) =>
+            {
+                let filter = &mut user.event_filter;
+                for expiry in expired {
+                    filter.entities.remove(&expiry.entity);
+                }
+                filter.expiry.retain(|expiry| !expired.contains(expiry));
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn find_interested(
+        &self,
+        entity_id: &Uuid,
+        group_id: Option<&Uuid>,
+        kind: &str,
+        im: &str,
+        page: &Page,
+    ) -> ApiResult<(Vec<User>, Option<Cursor>)> {
+        let users: Vec<_> = lock(&self.users)
+            .values()
+            .filter(|user| {
+                let filter = &user.event_filter;
+                user.im == im
+                    && (filter.entities.contains(entity_id)
+                        || group_id.is_some_and(|group_id| filter.groups.contains(group_id)))
+                    && (filter.kinds.contains(kind)
+                        || filter
+                            .overrides
+                            .iter()
+                            .any(|r#override| r#override.entity == *entity_id))
+            })
+            .cloned()
+            .collect();
+        page.take_by_id(users, |user| user.id)
+    }
+
+    async fn find_by_im(&self, im: &str, page: &Page) -> ApiResult<(Vec<User>, Option<Cursor>)> {
+        let users: Vec<_> = lock(&self.users)
+            .values()
+            .filter(|user| user.im == im)
+            .cloned()
+            .collect();
+        page.take_by_id(users, |user| user.id)
+    }
+
+    async fn unsubscribe_group(&self, group_id: &Uuid) -> ApiResult<()> {
+        for user in lock(&self.users).values_mut() {
+            user.event_filter.groups.remove(group_id);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TaskRepo for MemoryStore {
+    async fn insert_tasks(&self, tasks: &[Task]) -> ApiResult<()> {
+        lock(&self.tasks).extend(tasks.iter().map(|task| (task.id, task.clone())));
+        Ok(())
+    }
+
+    async fn find_tasks(&self, entity_id: &Uuid) -> ApiResult<Vec<Task>> {
+        Ok(lock(&self.tasks)
+            .values()
+            .filter(|task| task.entity == *entity_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn find_tasks_of(&self, entity_ids: &[Uuid]) -> ApiResult<Vec<Task>> {
+        Ok(lock(&self.tasks)
+            .values()
+            .filter(|task| entity_ids.contains(&task.entity))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_task(&self, id: &Uuid) -> ApiResult<Option<Task>> {
+        Ok(lock(&self.tasks).remove(id))
+    }
+
+    async fn delete_tasks(&self, ids: &[Uuid]) -> ApiResult<()> {
+        let mut tasks = lock(&self.tasks);
+        for id in ids {
+            tasks.remove(id);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EntityRepo for MemoryStore {
+    async fn insert_entity(&self, entity: &Entity) -> ApiResult<()> {
+        lock(&self.entities).insert(entity.id, entity.clone());
+        Ok(())
+    }
+
+    async fn find_entity(&self, id: &Uuid) -> ApiResult<Option<Entity>> {
+        Ok(lock(&self.entities).get(id).cloned())
+    }
+
+    async fn update_entity(
+        &self,
+        id: &Uuid,
+        update: EntityUpdate<'_>,
+    ) -> ApiResult<Option<Entity>> {
+        Ok(lock(&self.entities).get_mut(id).map(|entity| {
+            match update {
+                EntityUpdate::Meta(meta) => entity.meta = meta.clone(),
+                EntityUpdate::Group(group_id) => entity.meta.group = group_id.copied(),
+                EntityUpdate::Profile(kind, profile) => {
+                    entity
+                        .meta
+                        .profiles
+                        .insert(kind.to_owned(), profile.clone());
+                }
+                EntityUpdate::AddTask(task_id) => entity.tasks.push(*task_id),
+                EntityUpdate::RemoveTask(task_id) => entity.tasks.retain(|id| id != task_id),
+                EntityUpdate::AutoTasks(templates) => entity.auto_tasks = templates.to_vec(),
+            }
+            entity.clone()
+        }))
+    }
+
+    async fn delete_entity(&self, id: &Uuid) -> ApiResult<Option<Entity>> {
+        Ok(lock(&self.entities).remove(id))
+    }
+
+    async fn leave_group(&self, group_id: &Uuid) -> ApiResult<()> {
+        for entity in lock(&self.entities).values_mut() {
+            if entity.meta.group.as_ref() == Some(group_id) {
+                entity.meta.group = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn find_entities(&self, page: &Page) -> ApiResult<(Vec<Entity>, Option<Cursor>)> {
+        let entities: Vec<_> = lock(&self.entities).values().cloned().collect();
+        page.take_by_id(entities, |entity| entity.id)
+    }
+
+    async fn search_entities(&self, query: &str, limit: Option<u32>) -> ApiResult<Vec<Entity>> {
+        let query: HashSet<_> = words(query).collect();
+        let mut found: Vec<_> = lock(&self.entities)
+            .values()
+            .filter_map(|entity| {
+                let name = &entity.meta.name;
+                let words: HashSet<_> = name
+                    .name
+                    .values()
+                    .chain(&name.aliases)
+                    .flat_map(|text| words(text))
+                    .collect();
+                let score = query.intersection(&words).count();
+                (score > 0).then(|| (score, entity.clone()))
+            })
+            .collect();
+        found.sort_by(|(a, x), (b, y)| b.cmp(a).then_with(|| x.id.bytes().cmp(&y.id.bytes())));
+        let limit = limit.map_or(usize::MAX, |limit| limit as usize);
+        Ok(found
+            .into_iter()
+            .take(limit)
+            .map(|(_, entity)| entity)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        time::SystemTime,
+    };
+
+    use isolanguage_1::LanguageCode;
+    use mongodb::bson::Uuid;
+    use sg_core::models::{Entity, EventFilter, Expiry, KindOverride, Meta, Name, Profile, User};
+
+    use crate::{
+        model::UserQuery,
+        rpc::Page,
+        server::{EntityRepo, EntityUpdate, MemoryStore, UserRepo},
+    };
+
+    fn new_user(im: &str, event_filter: EventFilter) -> User {
+        User {
+            id: Uuid::new(),
+            im: im.to_owned(),
+            im_payload: Uuid::new().to_string(),
+            name: "Pop".to_owned(),
+            avatar: None,
+            event_filter,
+            quiet_hours: None,
+            max_per_hour: None,
+            link_id: None,
+            disabled: false,
+            token_generation: 0,
+        }
+    }
+
+    fn new_entity(name: &str, aliases: &[&str]) -> Entity {
+        let en = LanguageCode::En;
+        Entity {
+            id: Uuid::new(),
+            meta: Meta {
+                name: Name {
+                    name: HashMap::from([(en, name.to_owned())]),
+                    default_language: en,
+                    aliases: aliases.iter().map(|alias| (*alias).to_owned()).collect(),
+                },
+                group: None,
+                profiles: BTreeMap::new(),
+                accounts: BTreeMap::new(),
+            },
+            tasks: vec![],
+            auto_tasks: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn must_find_interested() {
+        let store = MemoryStore::default();
+        let entity = Uuid::new();
+        let subscribed = EventFilter {
+            entities: HashSet::from([entity]),
+            kinds: HashSet::from(["twitter".to_owned()]),
+            ..EventFilter::default()
+        };
+        let overridden = EventFilter {
+            overrides: vec![KindOverride {
+                entity,
+                kinds: HashSet::new(),
+            }],
+            kinds: HashSet::new(),
+            ..subscribed.clone()
+        };
+        let users = [
+            new_user("webhook", subscribed.clone()),
+            new_user("webhook", subscribed.clone()),
+            new_user("webhook", overridden),
+            new_user("tg", subscribed),
+            new_user("webhook", EventFilter::default()),
+        ];
+        for user in &users {
+            store.insert_user(user).await.unwrap();
+        }
+
+        let page = Page::first(2);
+        let (mut found, next) = store
+            .find_interested(&entity, None, "twitter", "webhook", &page)
+            .await
+            .unwrap();
+        let (rest, next) = store
+            .find_interested(
+                &entity,
+                None,
+                "twitter",
+                "webhook",
+                &page.next(next.unwrap()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(next, None);
+        found.extend(rest);
+
+        let mut expected = users[..3].to_vec();
+        expected.sort_by_key(|user| user.id.bytes());
+        assert_eq!(found, expected);
+
+        // Users of the IM regardless of their subscriptions
+        let (found, next) = store.find_by_im("webhook", &Page::first(10)).await.unwrap();
+        assert_eq!(next, None);
+        let mut expected: Vec<_> = users
+            .iter()
+            .filter(|user| user.im == "webhook")
+            .cloned()
+            .collect();
+        expected.sort_by_key(|user| user.id.bytes());
+        assert_eq!(found, expected);
+    }
+
+    #[tokio::test]
+    async fn must_find_interested_by_group() {
+        let store = MemoryStore::default();
+        let (entity, group) = (Uuid::new(), Uuid::new());
+        let user = new_user(
+            "webhook",
+            EventFilter {
+                groups: HashSet::from([group]),
+                kinds: HashSet::from(["twitter".to_owned()]),
+                ..EventFilter::default()
+            },
+        );
+        store.insert_user(&user).await.unwrap();
+
+        let find = |group_id: Option<Uuid>| {
+            let store = &store;
+            async move {
+                store
+                    .find_interested(
+                        &entity,
+                        group_id.as_ref(),
+                        "twitter",
+                        "webhook",
+                        &Page::first(10),
+                    )
+                    .await
+                    .unwrap()
+                    .0
+            }
+        };
+        assert_eq!(find(Some(group)).await, vec![user.clone()]);
+        assert!(find(Some(Uuid::new())).await.is_empty());
+        assert!(find(None).await.is_empty());
+
+        store.unsubscribe_group(&group).await.unwrap();
+        assert!(find(Some(group)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn must_upsert_user() {
+        let store = MemoryStore::default();
+        let user = new_user("tg", EventFilter::default());
+        let inserted = store.upsert_user(user.clone()).await.unwrap();
+        assert_eq!(
+            inserted,
+            User {
+                token_generation: 1,
+                ..user.clone()
+            }
+        );
+
+        // Only name and avatar of an existing user are updated, and it's
+        // enabled again with a new token generation.
+        store.set_disabled(&user.id, true).await.unwrap();
+        let renamed = User {
+            id: Uuid::new(),
+            name: "Suisei".to_owned(),
+            ..user.clone()
+        };
+        let upserted = store.upsert_user(renamed).await.unwrap();
+        assert_eq!((upserted.id, upserted.name.as_str()), (user.id, "Suisei"));
+        assert!(!upserted.disabled);
+        assert_eq!(upserted.token_generation, 2);
+
+        let query = UserQuery::ById {
+            user_id: user.id.into(),
+        };
+        assert_eq!(store.delete_user(&query).await.unwrap(), Some(upserted));
+        assert_eq!(store.find_user(&query).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn must_prune_expired() {
+        let store = MemoryStore::default();
+        let (kept, expiring) = (Uuid::new(), Uuid::new());
+        let expiry = Expiry {
+            entity: expiring,
+            until: SystemTime::UNIX_EPOCH,
+        };
+        let user = new_user(
+            "webhook",
+            EventFilter {
+                entities: HashSet::from([kept, expiring]),
+                expiry: vec![expiry.clone()],
+                ..EventFilter::default()
+            },
+        );
+        store.insert_user(&user).await.unwrap();
+
+        // Renewed in the meantime
+        let renewed = Expiry {
+            until: SystemTime::now(),
+            ..expiry.clone()
+        };
+        assert!(!store.prune_expired(&user.id, &[renewed]).await.unwrap());
+
+        assert!(store.prune_expired(&user.id, &[expiry]).await.unwrap());
+        let query = UserQuery::ById {
+            user_id: user.id.into(),
+        };
+        let pruned = store.find_user(&query).await.unwrap().unwrap();
+        assert_eq!(pruned.event_filter.entities, HashSet::from([kept]));
+        assert!(pruned.event_filter.expiry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn must_update_and_search_entities() {
+        let store = MemoryStore::default();
+        let suisei = new_entity("Hoshimachi Suisei", &["Suichan"]);
+        let marine = new_entity("Houshou Marine", &[]);
+        store.insert_entity(&suisei).await.unwrap();
+        store.insert_entity(&marine).await.unwrap();
+
+        let found = store.search_entities("suisei MARINE", None).await.unwrap();
+        assert_eq!(found.len(), 2);
+        let found = store.search_entities("hoshimachi suichan", None).await.unwrap();
+        assert_eq!(found, [suisei.clone()]);
+        assert!(store.search_entities("Sui", None).await.unwrap().is_empty());
+
+        let profile = Profile {
+            name: "Suisei Channel".to_owned(),
+            avatar: None,
+        };
+        let task = Uuid::new();
+        store
+            .update_entity(&suisei.id, EntityUpdate::Profile("youtube", &profile))
+            .await
+            .unwrap();
+        let updated = store
+            .update_entity(&suisei.id, EntityUpdate::AddTask(&task))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.meta.profiles["youtube"], profile);
+        assert_eq!(updated.tasks, [task]);
+        assert!(store
+            .update_entity(&Uuid::new(), EntityUpdate::RemoveTask(&task))
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,165 @@
+//! Storage of users, tasks and entities.
+//!
+//! [`Context`](crate::server::Context) keeps the logic of methods, e.g.
+//! validation and errors, and stores through these repositories, backed by
+//! `MongoDB` in production or by memory in tests and small deploys.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use mongodb::{bson::Uuid, Database};
+use sg_core::models::{
+    Entity, EventFilter, Expiry, Meta, Profile, QuietHours, Task, TaskTemplate, User,
+};
+
+use crate::{
+    model::UserQuery,
+    rpc::{ApiResult, Cursor, Page},
+    server::Config,
+};
+
+mod_use::mod_use![memory, mongo];
+
+/// Repository of users.
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    async fn find_user(&self, query: &UserQuery) -> ApiResult<Option<User>>;
+
+    async fn insert_user(&self, user: &User) -> ApiResult<()>;
+
+    /// Set name and avatar of the user with the same `im` and `im_payload` as
+    /// `user` and enable it again, or insert `user` if there's none, in one
+    /// operation. Either way, bump the token generation of the user. Return
+    /// the stored user.
+    async fn upsert_user(&self, user: User) -> ApiResult<User>;
+
+    async fn delete_user(&self, query: &UserQuery) -> ApiResult<Option<User>>;
+
+    /// Return the updated user, or `None` if not found.
+    async fn update_setting(
+        &self,
+        id: &Uuid,
+        event_filter: &EventFilter,
+        quiet_hours: Option<QuietHours>,
+        max_per_hour: Option<u32>,
+    ) -> ApiResult<Option<User>>;
+
+    /// Return the updated user, or `None` if not found.
+    async fn set_disabled(&self, id: &Uuid, disabled: bool) -> ApiResult<Option<User>>;
+
+    /// Users linked under `link_id`.
+    async fn find_linked(&self, link_id: &Uuid) -> ApiResult<Vec<User>>;
+
+    /// Link the users under `link_id`, setting their shared event filter.
+    async fn link_users(
+        &self,
+        ids: &[Uuid],
+        link_id: &Uuid,
+        event_filter: &EventFilter,
+    ) -> ApiResult<()>;
+
+    /// Users with subscriptions that expire.
+    async fn users_with_expiry(&self) -> ApiResult<Vec<User>>;
+
+    /// Unsubscribe the user from the entities of `expired`, if all of them
+    /// still expire as in `expired`. Return whether they're unsubscribed.
+    async fn prune_expired(&self, id: &Uuid, expired: &[Expiry]) -> ApiResult<bool>;
+
+    /// A page of users in `im` subscribed to the entity or to its group, who
+    /// either subscribe to `kind` or override kinds for the entity, sorted by
+    /// id.
+    async fn find_interested(
+        &self,
+        entity_id: &Uuid,
+        group_id: Option<&Uuid>,
+        kind: &str,
+        im: &str,
+        page: &Page,
+    ) -> ApiResult<(Vec<User>, Option<Cursor>)>;
+
+    /// A page of all users in `im`, sorted by id.
+    async fn find_by_im(&self, im: &str, page: &Page) -> ApiResult<(Vec<User>, Option<Cursor>)>;
+
+    /// Remove the group from event filters subscribing to it.
+    async fn unsubscribe_group(&self, group_id: &Uuid) -> ApiResult<()>;
+}
+
+/// Repository of tasks.
+#[async_trait]
+pub trait TaskRepo: Send + Sync {
+    async fn insert_tasks(&self, tasks: &[Task]) -> ApiResult<()>;
+
+    /// Tasks of the entity.
+    async fn find_tasks(&self, entity_id: &Uuid) -> ApiResult<Vec<Task>>;
+
+    /// Tasks of any of the entities.
+    async fn find_tasks_of(&self, entity_ids: &[Uuid]) -> ApiResult<Vec<Task>>;
+
+    async fn delete_task(&self, id: &Uuid) -> ApiResult<Option<Task>>;
+
+    async fn delete_tasks(&self, ids: &[Uuid]) -> ApiResult<()>;
+}
+
+/// Change to an entity.
+#[derive(Debug, Clone, Copy)]
+pub enum EntityUpdate<'a> {
+    /// Replace the meta.
+    Meta(&'a Meta),
+    /// Move into a group, or out of any group.
+    Group(Option<&'a Uuid>),
+    /// Set the profile on the platform of a task kind.
+    Profile(&'a str, &'a Profile),
+    /// Append a task.
+    AddTask(&'a Uuid),
+    /// Remove a task.
+    RemoveTask(&'a Uuid),
+    /// Replace the task templates.
+    AutoTasks(&'a [TaskTemplate]),
+}
+
+/// Repository of entities.
+#[async_trait]
+pub trait EntityRepo: Send + Sync {
+    /// Create indexes needed by queries, if they don't exist yet.
+    async fn create_indexes(&self) -> ApiResult<()> {
+        Ok(())
+    }
+
+    async fn insert_entity(&self, entity: &Entity) -> ApiResult<()>;
+
+    async fn find_entity(&self, id: &Uuid) -> ApiResult<Option<Entity>>;
+
+    /// Return the updated entity, or `None` if not found.
+    async fn update_entity(
+        &self,
+        id: &Uuid,
+        update: EntityUpdate<'_>,
+    ) -> ApiResult<Option<Entity>>;
+
+    async fn delete_entity(&self, id: &Uuid) -> ApiResult<Option<Entity>>;
+
+    /// Move entities in the group out of it.
+    async fn leave_group(&self, group_id: &Uuid) -> ApiResult<()>;
+
+    /// A page of entities, sorted by id.
+    async fn find_entities(&self, page: &Page) -> ApiResult<(Vec<Entity>, Option<Cursor>)>;
+
+    /// Entities whose names or aliases contain any word of `query`, ignoring
+    /// case, best matches first, at most `limit` of them.
+    async fn search_entities(&self, query: &str, limit: Option<u32>) -> ApiResult<Vec<Entity>>;
+}
+
+/// Storage of users, tasks and entities.
+pub trait Store: UserRepo + TaskRepo + EntityRepo {}
+
+impl<T: UserRepo + TaskRepo + EntityRepo> Store for T {}
+
+/// Store in the database, or in memory if `memory_store` is set.
+#[must_use]
+pub fn make_store(db: &Database, config: &Config) -> Arc<dyn Store> {
+    if config.memory_store {
+        Arc::new(MemoryStore::default())
+    } else {
+        Arc::new(MongoStore::new(db, config))
+    }
+}
@@ -0,0 +1,359 @@
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use isolanguage_1::LanguageCode;
+use mongodb::{
+    bson::{doc, to_bson, to_document, Document, Uuid},
+    options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument},
+    Collection,
+    Database,
+    IndexModel,
+};
+use sg_core::models::{Entity, EventFilter, Expiry, QuietHours, Task, User};
+
+use crate::{
+    model::UserQuery,
+    rpc::{ApiError, ApiResult, Cursor, Page, BY_ID},
+    server::{Config, Context, EntityRepo, EntityUpdate, TaskRepo, UserRepo},
+};
+
+/// Name of the text index over names and aliases of entities.
+const ENTITY_SEARCH_INDEX: &str = "entity_search";
+
+/// Store in collections of `MongoDB`.
+#[derive(Debug, Clone)]
+pub struct MongoStore {
+    users: Collection<User>,
+    tasks: Collection<Task>,
+    entities: Collection<Entity>,
+}
+
+impl MongoStore {
+    #[must_use]
+    pub fn new(db: &Database, config: &Config) -> Self {
+        Self {
+            users: db.collection(&config.users_collection),
+            tasks: db.collection(&config.tasks_collection),
+            entities: db.collection(&config.entities_collection),
+        }
+    }
+}
+
+fn after_update() -> FindOneAndUpdateOptions {
+    FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build()
+}
+
+#[async_trait]
+impl UserRepo for MongoStore {
+    async fn find_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
+        Ok(self.users.find_one(query.as_document(), None).await?)
+    }
+
+    async fn insert_user(&self, user: &User) -> ApiResult<()> {
+        self.users.insert_one(user, None).await?;
+        Ok(())
+    }
+
+    async fn upsert_user(&self, user: User) -> ApiResult<User> {
+        self.users
+            .find_one_and_update(
+                doc! { "im": &user.im, "im_payload": &user.im_payload },
+                doc! {
+                    "$set": {
+                        "name": &user.name,
+                        "avatar": to_bson(&user.avatar)?,
+                        "disabled": false,
+                    },
+                    "$inc": { "token_generation": 1 },
+                    "$setOnInsert": {
+                        "id": user.id,
+                        "event_filter": to_document(&user.event_filter)?,
+                    },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(ApiError::internal)
+    }
+
+    async fn delete_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
+        Ok(self
+            .users
+            .find_one_and_delete(query.as_document(), None)
+            .await?)
+    }
+
+    async fn update_setting(
+        &self,
+        id: &Uuid,
+        event_filter: &EventFilter,
+        quiet_hours: Option<QuietHours>,
+        max_per_hour: Option<u32>,
+    ) -> ApiResult<Option<User>> {
+        Ok(self
+            .users
+            .find_one_and_update(
+                doc! { "id": id },
+                doc! { "$set": {
+                    "event_filter": to_document(event_filter)?,
+                    "quiet_hours": to_bson(&quiet_hours)?,
+                    "max_per_hour": max_per_hour,
+                } },
+                after_update(),
+            )
+            .await?)
+    }
+
+    async fn set_disabled(&self, id: &Uuid, disabled: bool) -> ApiResult<Option<User>> {
+        Ok(self
+            .users
+            .find_one_and_update(
+                doc! { "id": id },
+                doc! { "$set": { "disabled": disabled } },
+                after_update(),
+            )
+            .await?)
+    }
+
+    async fn find_linked(&self, link_id: &Uuid) -> ApiResult<Vec<User>> {
+        Ok(self
+            .users
+            .find(doc! { "link_id": link_id }, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn link_users(
+        &self,
+        ids: &[Uuid],
+        link_id: &Uuid,
+        event_filter: &EventFilter,
+    ) -> ApiResult<()> {
+        self.users
+            .update_many(
+                doc! { "id": { "$in": ids } },
+                doc! { "$set": {
+                    "link_id": link_id,
+                    "event_filter": to_document(event_filter)?,
+                } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn users_with_expiry(&self) -> ApiResult<Vec<User>> {
+        Ok(self
+            .users
+            .find(doc! { "event_filter.expiry.0": { "$exists": true } }, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn prune_expired(&self, id: &Uuid, expired: &[Expiry]) -> ApiResult<bool> {
+        let entities: Vec<_> = expired.iter().map(|expiry| expiry.entity).collect();
+        let expired = to_bson(expired)?;
+        // Entries are matched one by one, since arrays of the filter are
+        // serialized from sets in no particular order.
+        let result = self
+            .users
+            .update_one(
+                doc! { "id": id, "event_filter.expiry": { "$all": expired.clone() } },
+                doc! { "$pull": {
+                    "event_filter.entities": { "$in": entities },
+                    "event_filter.expiry": { "$in": expired },
+                } },
+                None,
+            )
+            .await?;
+        Ok(result.modified_count > 0)
+    }
+
+    async fn find_interested(
+        &self,
+        entity_id: &Uuid,
+        group_id: Option<&Uuid>,
+        kind: &str,
+        im: &str,
+        page: &Page,
+    ) -> ApiResult<(Vec<User>, Option<Cursor>)> {
+        let mut subscribed = vec![doc! { "event_filter.entities": entity_id }];
+        if let Some(group_id) = group_id {
+            subscribed.push(doc! { "event_filter.groups": group_id });
+        }
+        Context::find_page(
+            &self.users,
+            doc! {
+              "$and": [
+                { "$or": subscribed },
+                { "$or": [
+                  { "event_filter.kinds": kind },
+                  { "event_filter.overrides.entity": entity_id },
+                ] },
+              ],
+              "im": im,
+            },
+            page,
+        )
+        .await
+    }
+
+    async fn find_by_im(&self, im: &str, page: &Page) -> ApiResult<(Vec<User>, Option<Cursor>)> {
+        Context::find_page(&self.users, doc! { "im": im }, page).await
+    }
+
+    async fn unsubscribe_group(&self, group_id: &Uuid) -> ApiResult<()> {
+        self.users
+            .update_many(
+                doc! { "event_filter.groups": group_id },
+                doc! { "$pull": { "event_filter.groups": group_id } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TaskRepo for MongoStore {
+    async fn insert_tasks(&self, tasks: &[Task]) -> ApiResult<()> {
+        if !tasks.is_empty() {
+            self.tasks.insert_many(tasks, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_task(&self, id: &Uuid) -> ApiResult<Option<Task>> {
+        Ok(self.tasks.find_one_and_delete(doc! { "id": id }, None).await?)
+    }
+
+    async fn find_tasks(&self, entity_id: &Uuid) -> ApiResult<Vec<Task>> {
+        Ok(self
+            .tasks
+            .find(doc! { "entity": entity_id }, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn find_tasks_of(&self, entity_ids: &[Uuid]) -> ApiResult<Vec<Task>> {
+        Ok(self
+            .tasks
+            .find(doc! { "entity": { "$in": entity_ids } }, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn delete_tasks(&self, ids: &[Uuid]) -> ApiResult<()> {
+        self.tasks
+            .delete_many(doc! { "id": { "$in": ids } }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EntityRepo for MongoStore {
+    async fn create_indexes(&self) -> ApiResult<()> {
+        // Names are keyed by language, so every language has to be listed.
+        // Text indexes can't be limited to some fields with a wildcard.
+        let mut keys: Document = LanguageCode::codes()
+            .map(|code| (format!("meta.name.name.{code}"), "text".into()))
+            .collect();
+        keys.insert("meta.name.aliases", "text");
+        // Names are not in any language the text index knows of, so they
+        // are neither stemmed nor filtered by stop words.
+        let options = IndexOptions::builder()
+            .name(ENTITY_SEARCH_INDEX.to_owned())
+            .default_language("none".to_owned())
+            .build();
+
+        self.entities
+            .create_index(IndexModel::builder().keys(keys).options(options).build(), None)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_entity(&self, entity: &Entity) -> ApiResult<()> {
+        self.entities.insert_one(entity, None).await?;
+        Ok(())
+    }
+
+    async fn find_entity(&self, id: &Uuid) -> ApiResult<Option<Entity>> {
+        Ok(self.entities.find_one(doc! { "id": id }, None).await?)
+    }
+
+    async fn update_entity(
+        &self,
+        id: &Uuid,
+        update: EntityUpdate<'_>,
+    ) -> ApiResult<Option<Entity>> {
+        let update = match update {
+            EntityUpdate::Meta(meta) => doc! { "$set": { "meta": to_document(meta)? } },
+            EntityUpdate::Group(group_id) => doc! { "$set": { "meta.group": group_id } },
+            EntityUpdate::Profile(kind, profile) => {
+                doc! { "$set": { format!("meta.profiles.{kind}"): to_bson(profile)? } }
+            }
+            EntityUpdate::AddTask(task_id) => doc! { "$push": { "tasks": task_id } },
+            EntityUpdate::RemoveTask(task_id) => doc! { "$pull": { "tasks": task_id } },
+            EntityUpdate::AutoTasks(templates) => {
+                doc! { "$set": { "auto_tasks": to_bson(templates)? } }
+            }
+        };
+        Ok(self
+            .entities
+            .find_one_and_update(doc! { "id": id }, update, after_update())
+            .await?)
+    }
+
+    async fn delete_entity(&self, id: &Uuid) -> ApiResult<Option<Entity>> {
+        Ok(self
+            .entities
+            .find_one_and_delete(doc! { "id": id }, None)
+            .await?)
+    }
+
+    async fn leave_group(&self, group_id: &Uuid) -> ApiResult<()> {
+        self.entities
+            .update_many(
+                doc! { "meta.group": group_id },
+                doc! { "$set": { "meta.group": null } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn find_entities(&self, page: &Page) -> ApiResult<(Vec<Entity>, Option<Cursor>)> {
+        let (filter, options) = page.query(doc! {}, BY_ID)?;
+        let entities = self
+            .entities
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+        page.split(entities, BY_ID)
+    }
+
+    async fn search_entities(&self, query: &str, limit: Option<u32>) -> ApiResult<Vec<Entity>> {
+        let score = doc! { "score": { "$meta": "textScore" } };
+        let options = FindOptions::builder()
+            .projection(score.clone())
+            .sort(score)
+            .limit(limit.map(i64::from))
+            .build();
+        Ok(self
+            .entities
+            .find(doc! { "$text": { "$search": query } }, options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
@@ -5,26 +5,39 @@
 //!
 //! Username: "test"
 //! Password: "test"
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use mongodb::bson::Uuid;
 use once_cell::sync::Lazy;
 use prep::prep;
 use rand::Rng;
 use reqwest::Url;
-use sg_core::models::{EventFilter, User};
+use sg_auth::{Permission, PermissionSet};
+use serde_json::{json, Map};
+use sg_core::models::{
+    Event, EventFilter, Exclusion, Id, Meta, Name, Profile, QuietHours, TaskTemplate, User,
+};
 
-use crate::model::UserQuery;
+use crate::{
+    client::blocking::Client,
+    model::{AddTaskParam, AuditOutcome, Privilege, StatsBucket, UserQuery},
+    rpc::Page,
+    ApiErrorKind, ErrorCode,
+};
 
 mod prep {
     use std::{
         ops::{Deref, DerefMut},
         sync::atomic::{AtomicBool, AtomicU16, Ordering},
-        time::Duration,
+        time::{Duration, SystemTime},
     };
 
     use once_cell::sync::OnceCell;
-    use sg_auth::{AuthClient, PermissionRecord, PermissionSet};
+    use sg_auth::{AuthClient, KeyRecord, PermissionRecord, PermissionSet, Role};
+    use sg_core::utils::Redacted;
     use tokio::{runtime::Runtime, time::timeout};
     use tracing::{info, metadata::LevelFilter};
 
@@ -103,8 +116,10 @@ mod prep {
                 .unwrap()
                 .database("stargazer-reborn");
             let col = db.collection::<PermissionRecord>("auth");
+            let keys = db.collection::<KeyRecord>("auth_keys");
+            let roles = db.collection::<Role>("auth_roles");
 
-            let auth = AuthClient::new(col);
+            let auth = AuthClient::new(col, keys, roles);
             timeout(
                 Duration::from_secs(1),
                 auth.new_record("test", "test", PermissionSet::FULL),
@@ -118,7 +133,10 @@ mod prep {
             let app = make_app_with(
                 Config {
                     token_timeout: Duration::from_secs(0),
-                    mongo_uri,
+                    mongo_uri: Redacted(mongo_uri),
+                    cors_allowed_origins: vec![String::from(super::ORIGIN)],
+                    cors_allow_credentials: true,
+                    csrf_protection: true,
                     ..Config::default()
                 },
                 Some(db),
@@ -156,12 +174,27 @@ mod prep {
             );
         }
 
-        let mut c = Client::new("http://127.0.0.1:8080/v1/").unwrap();
+        let c = Client::new("http://127.0.0.1:8080/v1/").unwrap();
         c.login_and_store("test", "test").unwrap();
         TestGuard::new(c)
     }
+
+    /// Issue an API key directly through the auth database.
+    pub fn create_key(scopes: PermissionSet, expiry: Option<SystemTime>) -> (String, KeyRecord) {
+        let (rt, auth) = CURRENT.get().unwrap();
+        rt.block_on(auth.create_key("test", scopes, expiry)).unwrap()
+    }
+
+    /// Revoke an API key directly through the auth database.
+    pub fn revoke_key(record: &KeyRecord) {
+        let (rt, auth) = CURRENT.get().unwrap();
+        rt.block_on(auth.revoke_key(record.id())).unwrap();
+    }
 }
 
+/// Origin of the page calling the API in cross-origin tests.
+const ORIGIN: &str = "https://settings.example.com";
+
 static URL: Lazy<Url> = Lazy::new(|| Url::parse("https://placekitten.com/114/514").unwrap());
 
 fn gen_payload() -> String {
@@ -172,7 +205,7 @@ fn gen_payload() -> String {
 
 #[test]
 fn test_new_user() {
-    let mut c = prep();
+    let c = prep();
     let payload = gen_payload();
 
     let res1 = c
@@ -191,6 +224,11 @@ fn test_new_user() {
         name,
         avatar,
         event_filter,
+        quiet_hours,
+        max_per_hour,
+        link_id,
+        disabled,
+        token_generation,
     } = &res1;
 
     assert_eq!(im, "tg");
@@ -205,8 +243,17 @@ fn test_new_user() {
         &EventFilter {
             entities: HashSet::default(),
             kinds: HashSet::default(),
+            expiry: Vec::new(),
+            overrides: Vec::new(),
+            exclusions: Vec::new(),
+            language: None,
         }
     );
+    assert_eq!(quiet_hours, &None);
+    assert_eq!(max_per_hour, &None);
+    assert_eq!(link_id, &None);
+    assert!(!disabled);
+    assert_eq!(token_generation, &0);
 
     tracing::info!(id = ?id, "New user added");
 
@@ -216,12 +263,17 @@ fn test_new_user() {
         .unwrap_err();
     match err {
         crate::client::Error::Api(err) => {
-            assert_eq!(err.error_reason(), Some("Conflict"));
+            assert_eq!(err.code(), ErrorCode::Conflict);
         }
         _ => panic!("Unexpected error: {:?}", err),
     }
 
-    let token = c.new_token(UserQuery::ById { user_id: *id }).unwrap().token;
+    let token = c
+        .new_token(UserQuery::ById {
+            user_id: (*id).into(),
+        })
+        .unwrap()
+        .token;
 
     // Pretend we are the new user
     let admin_token = c.set_token(token).unwrap();
@@ -233,7 +285,11 @@ fn test_new_user() {
 
     // Delete the new user
     c.set_token(admin_token).unwrap();
-    let res3 = c.del_user(UserQuery::ById { user_id: *id }).unwrap();
+    let res3 = c
+        .del_user(UserQuery::ById {
+            user_id: (*id).into(),
+        })
+        .unwrap();
 
     assert_eq!(res2, res3);
 
@@ -245,7 +301,339 @@ fn test_new_user() {
 fn test_get_entities() {
     let c = prep();
 
-    c.get_entities().unwrap();
+    let entities = c.get_entities(Page::default(), Page::first(1)).unwrap();
+    assert_eq!(entities.next_vtbs, None);
+    assert!(entities.groups.len() <= 1);
+}
+
+fn name(name: &str) -> Name {
+    let en = "en".parse().unwrap();
+    Name {
+        name: HashMap::from([(en, name.to_owned())]),
+        default_language: en,
+        aliases: vec![],
+    }
+}
+
+#[test]
+fn test_groups() {
+    let c = prep();
+
+    let group = c.add_group(name("Hololive")).unwrap();
+    assert_eq!(c.get_group(group.id).unwrap(), group);
+
+    let renamed = c.update_group(group.id, name("hololive")).unwrap();
+    assert_eq!(renamed.name, name("hololive"));
+
+    let entity = c
+        .add_entity(
+            Meta {
+                name: name("Suisei"),
+                group: None,
+                profiles: BTreeMap::new(),
+                accounts: BTreeMap::new(),
+            },
+            vec![],
+            vec![],
+        )
+        .unwrap();
+    let entity = c.set_entity_group(entity.id, Some(Id::from(group.id))).unwrap();
+    assert_eq!(entity.meta.group, Some(group.id));
+
+    // Entities can't be put into a nonexistent group
+    let res = c.set_entity_group(entity.id, Some(Id::new())).unwrap_err();
+    assert!(res.matches_api_code(ErrorCode::GroupNotFound));
+
+    // Deleting the group removes the entity from it
+    c.del_group(group.id).unwrap();
+    assert!(c
+        .get_group(group.id)
+        .unwrap_err()
+        .matches_api_kind(ApiErrorKind::NotFound));
+    let vtbs = c.get_entities(Page::default(), Page::first(1)).unwrap().vtbs;
+    let entity = vtbs.into_iter().find(|vtb| vtb.id == entity.id).unwrap();
+    assert_eq!(entity.meta.group, None);
+
+    c.del_entity(entity.id).unwrap();
+}
+
+#[test]
+fn test_update_entity_meta() {
+    let c = prep();
+
+    let entity = c
+        .add_entity(
+            Meta {
+                name: name("Suisei"),
+                group: None,
+                profiles: BTreeMap::new(),
+                accounts: BTreeMap::new(),
+            },
+            vec![],
+            vec![],
+        )
+        .unwrap();
+    let profile = Profile {
+        name: "Suisei Channel".to_owned(),
+        avatar: Some("https://example.com/suisei.png".parse().unwrap()),
+    };
+    let updated = c
+        .update_entity_meta(entity.id, "youtube".to_owned(), profile.clone())
+        .unwrap();
+    // Other parts of the meta are kept
+    assert_eq!(updated.meta.name, entity.meta.name);
+    assert_eq!(
+        updated.meta.profiles,
+        BTreeMap::from([("youtube".to_owned(), profile.clone())])
+    );
+
+    let res = c
+        .update_entity_meta(entity.id, "meta.name".to_owned(), profile.clone())
+        .unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+    let res = c
+        .update_entity_meta(Uuid::new(), "youtube".to_owned(), profile)
+        .unwrap_err();
+    assert!(res.matches_api_code(ErrorCode::EntityNotFound));
+
+    c.del_entity(entity.id).unwrap();
+}
+
+#[test]
+fn test_search_entities() {
+    let c = prep();
+
+    let mut suisei = name("Hoshimachi Suisei");
+    suisei.aliases = vec!["Suichan".to_owned()];
+    let entity = c
+        .add_entity(
+            Meta {
+                name: suisei,
+                group: None,
+                profiles: BTreeMap::new(),
+                accounts: BTreeMap::new(),
+            },
+            vec![],
+            vec![],
+        )
+        .unwrap();
+
+    // Matches any word of names and aliases, ignoring case and diacritics
+    for query in ["suisei", "HOSHIMACHI Marine", "Súichan"] {
+        let entities = c.search_entities(query.to_owned(), None).unwrap().entities;
+        assert!(entities.iter().any(|e| e.id == entity.id), "{query}");
+    }
+    let entities = c.search_entities("Marine".to_owned(), None).unwrap().entities;
+    assert!(entities.iter().all(|e| e.id != entity.id));
+
+    let res = c.search_entities("  ".to_owned(), None).unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+
+    c.del_entity(entity.id).unwrap();
+}
+
+#[test]
+fn test_auto_tasks() {
+    let c = prep();
+
+    let mut meta = Meta {
+        name: name("Suisei"),
+        group: None,
+        profiles: BTreeMap::new(),
+        accounts: BTreeMap::from([("youtube".to_owned(), "UC5CwaMl1eIgY8h02uZw7u8A".to_owned())]),
+    };
+    let templates: Vec<TaskTemplate> = serde_json::from_value(json!([
+        { "kind": "youtube", "params": { "channel_id": "{youtube}" } },
+        { "kind": "twitter", "params": { "id": "{twitter}" } },
+    ]))
+    .unwrap();
+    let bilibili = AddTaskParam::Bilibili {
+        uid: "434334701".to_owned(),
+    };
+    let entity = c
+        .add_entity(meta.clone(), vec![bilibili], templates.clone())
+        .unwrap();
+    // The bilibili task, and the youtube one since there's no twitter account
+    assert_eq!(entity.tasks.len(), 2);
+    assert_eq!(entity.auto_tasks, templates);
+    let (bilibili, youtube) = (entity.tasks[0], entity.tasks[1]);
+
+    // Adding an account adds its task
+    meta.accounts
+        .insert("twitter".to_owned(), "suisei_hosimati".to_owned());
+    let updated = c.update_entity(entity.id, meta.clone(), None).unwrap();
+    assert_eq!(updated.tasks.len(), 3);
+    assert!(updated.tasks.contains(&bilibili) && updated.tasks.contains(&youtube));
+
+    // Changing an account replaces its task
+    meta.accounts
+        .insert("youtube".to_owned(), "UCdn5BQ06XqgXoAxIhbqw5Rg".to_owned());
+    let updated = c.update_entity(entity.id, meta.clone(), None).unwrap();
+    assert_eq!(updated.tasks.len(), 3);
+    assert!(updated.tasks.contains(&bilibili) && !updated.tasks.contains(&youtube));
+
+    // Dropping templates removes their tasks, keeping others
+    let updated = c
+        .update_entity(entity.id, meta.clone(), Some(vec![]))
+        .unwrap();
+    assert_eq!(updated.tasks, [bilibili]);
+    assert!(updated.auto_tasks.is_empty());
+
+    // Templates of unknown kinds are rejected
+    let template = TaskTemplate {
+        kind: "mastodon".to_owned(),
+        params: Map::new(),
+    };
+    let res = c
+        .update_entity(entity.id, meta, Some(vec![template]))
+        .unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+
+    c.del_entity(entity.id).unwrap();
+}
+
+#[test]
+fn test_audit_log() {
+    let c = prep();
+
+    let group = c.add_group(name("Audited")).unwrap();
+    c.del_group(group.id).unwrap();
+    c.del_group(group.id).unwrap_err();
+
+    // Walk through the log page by page
+    let mut entries = vec![];
+    let mut page = Page::first(16);
+    loop {
+        let log = c.get_audit_log(page.clone()).unwrap();
+        entries.extend(log.entries);
+        match log.next {
+            Some(cursor) => page = page.next(cursor),
+            None => break,
+        }
+    }
+
+    let calls: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| entry.request["group_id"] == group.id.to_string())
+        .collect();
+    assert_eq!(calls.len(), 2);
+    for entry in &calls {
+        assert_eq!(entry.method, "del_group");
+        assert_eq!(entry.actor.privilege, Privilege::Admin);
+    }
+    assert_eq!(calls[0].outcome, AuditOutcome::Success);
+    assert!(matches!(
+        calls[1].outcome,
+        AuditOutcome::Failure { code: ErrorCode::GroupNotFound, .. }
+    ));
+}
+
+#[test]
+fn test_broadcast_without_amqp() {
+    let c = prep();
+
+    let res = c.broadcast("  ".to_owned(), None).unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+
+    // The test server has no broker to publish to.
+    let res = c
+        .broadcast("Maintenance".to_owned(), Some("webhook".to_owned()))
+        .unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+}
+
+#[test]
+fn test_login_with_key() {
+    let c = prep();
+
+    let mut scopes = PermissionSet::EMPTY;
+    scopes.api = Some(Permission::ReadWrite);
+    let (key, record) = prep::create_key(scopes, None);
+
+    // Key with bot scope can call bot methods, but not admin ones
+    let token = c.login_with_key(key.clone()).unwrap();
+    let bot = Client::new("http://127.0.0.1:8080/v1/").unwrap();
+    bot.set_token(token.token);
+    bot.get_entities(Page::default(), Page::default()).unwrap();
+    let res = bot.get_audit_log(Page::default()).unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::Unauthorized),
+        "Unexpected error: {:?}",
+        res
+    );
+
+    // Key with read-only bot scope can only call methods reading data
+    let mut scopes = PermissionSet::EMPTY;
+    scopes.api = Some(Permission::ReadOnly);
+    let (read_only_key, read_only_record) = prep::create_key(scopes, None);
+    let token = c.login_with_key(read_only_key).unwrap();
+    let reader = Client::new("http://127.0.0.1:8080/v1/").unwrap();
+    reader.set_token(token.token);
+    reader.get_entities(Page::default(), Page::default()).unwrap();
+    let res = reader
+        .del_user(UserQuery::ById {
+            user_id: Uuid::new().into(),
+        })
+        .unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::Unauthorized),
+        "Unexpected error: {:?}",
+        res
+    );
+    prep::revoke_key(&read_only_record);
+
+    // Token expires with the key
+    let expiry = SystemTime::now() + Duration::from_secs(60);
+    let (expiring_key, expiring_record) = prep::create_key(scopes, Some(expiry));
+    let token = c.login_with_key(expiring_key).unwrap();
+    assert!(token.valid_until <= expiry);
+    prep::revoke_key(&expiring_record);
+
+    // Revoked key can't login, and its tokens are rejected
+    prep::revoke_key(&record);
+    let res = c.login_with_key(key).unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::Unauthorized),
+        "Unexpected error: {:?}",
+        res
+    );
+    let res = bot.get_entities(Page::default(), Page::default()).unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::Unauthorized),
+        "Unexpected error: {:?}",
+        res
+    );
+}
+
+#[test]
+fn test_session_file() {
+    let _c = prep();
+    let path = std::env::temp_dir().join(format!("session_{}", rand::random::<u64>()));
+
+    let c = Client::new("http://127.0.0.1:8080/v1/")
+        .unwrap()
+        .with_session_file(&path)
+        .unwrap();
+    assert!(c.token().is_none());
+    c.login_and_store("test", "test").unwrap();
+
+    // Another client reuses the session without login
+    let reused = Client::new("http://127.0.0.1:8080/v1/")
+        .unwrap()
+        .with_session_file(&path)
+        .unwrap();
+    assert_eq!(reused.token(), c.token());
+    reused.get_audit_log(Page::default()).unwrap();
+
+    // Logout wipes the session
+    reused.logout().unwrap();
+    assert!(reused.token().is_none());
+    assert!(!path.exists());
+    let fresh = Client::new("http://127.0.0.1:8080/v1/")
+        .unwrap()
+        .with_session_file(&path)
+        .unwrap();
+    assert!(fresh.token().is_none());
 }
 
 #[test]
@@ -256,21 +644,84 @@ fn test_delete_nonexistent_user() {
 
     let res = c
         .del_user(UserQuery::ById {
-            user_id: Uuid::parse_str(id).unwrap(),
+            user_id: id.parse().unwrap(),
         })
         .unwrap_err();
 
-    match res {
-        crate::client::Error::Api(e) => {
-            assert!(e.errors()[1].contains(&format!("Cannot find user with ID `{id}`")));
-        }
-        _ => panic!("Unexpected error: {:?}", res),
-    }
+    assert!(
+        res.matches_api_code(ErrorCode::UserNotFound),
+        "Unexpected error: {:?}",
+        res
+    );
+}
+
+#[test]
+fn test_set_user_disabled() {
+    let c = prep();
+
+    let user = c
+        .add_user("webhook", gen_payload(), URL.clone(), "Endpoint")
+        .unwrap();
+    assert!(!user.disabled);
+
+    let disabled = c.set_user_disabled(user.id, true).unwrap();
+    assert!(disabled.disabled);
+    // Enabled again, e.g. by an admin once the endpoint is fixed
+    let enabled = c.set_user_disabled(user.id, false).unwrap();
+    assert_eq!(enabled, user);
+
+    let res = c.set_user_disabled(Uuid::new(), true).unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::UserNotFound),
+        "Unexpected error: {:?}",
+        res
+    );
+
+    c.del_user(UserQuery::ById {
+        user_id: user.id.into(),
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_register_or_restore() {
+    let c = prep();
+    let payload = gen_payload();
+
+    let registered = c
+        .register_or_restore("webhook", payload.clone(), URL.clone(), "Endpoint")
+        .unwrap();
+    assert!(!registered.restored);
+    let user = c.set_user_disabled(registered.user.id, true).unwrap();
+
+    // Restoring enables the user again and rejects its old tokens
+    let restored = c
+        .register_or_restore("webhook", payload, URL.clone(), "Endpoint")
+        .unwrap();
+    assert!(restored.restored);
+    assert_eq!(restored.user.id, user.id);
+    assert!(!restored.user.disabled);
+
+    let admin_token = c.set_token(registered.token).unwrap();
+    let res = c.auth_user().unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::Unauthorized),
+        "Unexpected error: {:?}",
+        res
+    );
+    c.set_token(restored.token).unwrap();
+    assert_eq!(c.auth_user().unwrap().user, restored.user);
+
+    c.set_token(admin_token).unwrap();
+    c.del_user(UserQuery::ById {
+        user_id: user.id.into(),
+    })
+    .unwrap();
 }
 
 #[test]
 fn test_update_user_settings() {
-    let mut c = prep();
+    let c = prep();
 
     // Generate a new user
     let user_id = c
@@ -284,7 +735,12 @@ fn test_update_user_settings() {
         .id;
 
     // Get a token with current admin privilege
-    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    let token = c
+        .new_token(UserQuery::ById {
+            user_id: user_id.into(),
+        })
+        .unwrap()
+        .token;
 
     // change to this user
     c.set_token(token).unwrap();
@@ -294,15 +750,368 @@ fn test_update_user_settings() {
         entities: HashSet::from_iter([
             Uuid::parse_str("a1e28c88-be24-48b0-b18a-81531e669905").unwrap()
         ]),
-        kinds: HashSet::from_iter(["twitter/new_tweet".to_owned()]),
+        kinds: HashSet::from_iter(["twitter".to_owned()]),
+        expiry: Vec::new(),
+        overrides: Vec::new(),
+        exclusions: vec![Exclusion {
+            entity: None,
+            kind: Some("twitter".to_owned()),
+            field: Some("is_rt".to_owned()),
+        }],
+        language: Some(isolanguage_1::LanguageCode::Zh),
+    };
+
+    let quiet_hours = QuietHours {
+        start: 23 * 60,
+        end: 7 * 60,
+        utc_offset: 8 * 60,
     };
 
     // Update setting on behalf of this user
-    c.update_setting(event_filter.clone()).unwrap();
+    c.update_setting(event_filter.clone(), quiet_hours, 10).unwrap();
 
     // Get new user info
     let user = c.auth_user().unwrap().user;
 
     // Assert they are the equal
     assert_eq!(user.event_filter, event_filter);
+    assert_eq!(user.quiet_hours, Some(quiet_hours));
+    assert_eq!(user.max_per_hour, Some(10));
+
+    // Settings not set are cleared
+    let user = c
+        .update_setting(event_filter.clone(), None::<QuietHours>, None::<u32>)
+        .unwrap();
+    assert_eq!(user.quiet_hours, None);
+    assert_eq!(user.max_per_hour, None);
+
+    // Invalid quiet hours are rejected
+    let res = c
+        .update_setting(
+            event_filter.clone(),
+            QuietHours {
+                start: 0,
+                end: 25 * 60,
+                utc_offset: 0,
+            },
+            None::<u32>,
+        )
+        .unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+
+    // Unknown kinds are rejected
+    let res = c
+        .update_setting(
+            EventFilter {
+                kinds: HashSet::from_iter(["twitter/new_tweet".to_owned()]),
+                ..event_filter
+            },
+            None::<QuietHours>,
+            None::<u32>,
+        )
+        .unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+}
+
+#[test]
+fn test_link_users() {
+    let c = prep();
+    let (tg_entity, discord_entity) = (Uuid::new(), Uuid::new());
+
+    // Add a user in each of two IMs, subscribing to an entity each, and keep
+    // their tokens
+    let [tg_token, discord_token] =
+        [("tg", tg_entity), ("discord", discord_entity)].map(|(im, entity)| {
+            let user_id = c
+                .add_user(im, gen_payload(), URL.clone(), "Pop")
+                .unwrap()
+                .id;
+            let token = c
+                .new_token(UserQuery::ById {
+                    user_id: user_id.into(),
+                })
+                .unwrap()
+                .token;
+            let admin_token = c.set_token(token).unwrap();
+            let event_filter = EventFilter {
+                entities: HashSet::from_iter([entity]),
+                kinds: HashSet::from_iter(["twitter".to_owned()]),
+                ..EventFilter::default()
+            };
+            c.update_setting(event_filter, None::<QuietHours>, None::<u32>)
+                .unwrap();
+            c.set_token(admin_token).unwrap()
+        });
+
+    // Issue a code as the telegram user, and use it as the discord user
+    c.set_token(tg_token.clone()).unwrap();
+    let code = c.start_link().unwrap().code;
+    c.set_token(discord_token).unwrap();
+
+    let res = c.complete_link("invalid").unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+
+    let user = c.complete_link(code.to_lowercase()).unwrap();
+    assert!(user.link_id.is_some());
+    assert_eq!(
+        user.event_filter.entities,
+        HashSet::from_iter([tg_entity, discord_entity])
+    );
+
+    // Codes can only be used once
+    let res = c.complete_link(code).unwrap_err();
+    assert!(res.matches_api_kind(ApiErrorKind::BadRequest));
+
+    // Settings of either user change the shared event filter
+    let event_filter = EventFilter {
+        entities: HashSet::from_iter([discord_entity]),
+        kinds: HashSet::from_iter(["twitter".to_owned()]),
+        ..EventFilter::default()
+    };
+    c.update_setting(event_filter.clone(), None::<QuietHours>, None::<u32>)
+        .unwrap();
+    c.set_token(tg_token).unwrap();
+    let tg_user = c.auth_user().unwrap().user;
+    assert_eq!(tg_user.link_id, user.link_id);
+    assert_eq!(tg_user.event_filter, event_filter);
+}
+
+#[test]
+fn test_get_event_kinds() {
+    let c = prep();
+
+    let kinds = c.get_event_kinds().unwrap().kinds;
+    let twitter = kinds.iter().find(|kind| kind.name == "twitter").unwrap();
+    assert_eq!(twitter.worker, "twitter");
+    assert_eq!(twitter.payload["properties"]["is_rt"]["type"], "boolean");
+}
+
+#[test]
+fn test_probes() {
+    let _c = prep();
+
+    for probe in ["healthz", "readyz"] {
+        let res = reqwest::blocking::get(format!("http://127.0.0.1:8080/{probe}")).unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = res.json().unwrap();
+        assert_eq!(body["status"], "up");
+        assert_eq!(body["checks"]["mongodb"]["status"], "up");
+    }
+}
+
+#[test]
+fn test_cross_origin() {
+    let _c = prep();
+    let http = reqwest::blocking::Client::new();
+
+    let preflight = http
+        .request(reqwest::Method::OPTIONS, "http://127.0.0.1:8080/v1/health")
+        .header("origin", ORIGIN)
+        .header("access-control-request-method", "POST")
+        .header(
+            "access-control-request-headers",
+            "content-type,x-csrf-token",
+        )
+        .send()
+        .unwrap();
+    let headers = preflight.headers();
+    assert_eq!(headers["access-control-allow-origin"], ORIGIN);
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+
+    // Browsers must repeat the token of their cookie.
+    let health = || {
+        http.post("http://127.0.0.1:8080/v1/health")
+            .header("origin", ORIGIN)
+            .json(&json!({}))
+    };
+    let res = health().send().unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+    let body: serde_json::Value = res.json().unwrap();
+    assert_eq!(body["data"]["code"], "bad_csrf_token");
+
+    let res = http.get("http://127.0.0.1:8080/v1/csrf").send().unwrap();
+    let cookie = res.headers()["set-cookie"].to_str().unwrap().to_owned();
+    let token = res.json::<serde_json::Value>().unwrap()["token"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    assert!(cookie.starts_with(&format!("csrf_token={token};")));
+
+    let res = health()
+        .header("cookie", format!("csrf_token={token}"))
+        .header("x-csrf-token", "forged")
+        .send()
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+    let res = health()
+        .header("cookie", format!("csrf_token={token}"))
+        .header("x-csrf-token", &token)
+        .send()
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+    // Other clients don't send an `Origin`.
+    let res = http
+        .post("http://127.0.0.1:8080/v1/health")
+        .json(&json!({}))
+        .send()
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+}
+
+#[test]
+fn test_notifications() {
+    let c = prep();
+
+    let notified = c.add_user("tg", gen_payload(), URL.clone(), "Pop").unwrap().id;
+    let missed = c.add_user("tg", gen_payload(), URL.clone(), "Pop").unwrap().id;
+
+    let event = Event::from_serializable("twitter", Uuid::new(), json!({ "text": "hi" })).unwrap();
+    c.add_notifications(event.clone(), vec![notified.into()], vec![missed.into()])
+        .unwrap();
+
+    // Tokens of bots and admins must name the user
+    let res = c.get_notifications(None::<Id>, Page::default()).unwrap_err();
+    assert!(
+        res.matches_api_kind(ApiErrorKind::BadRequest),
+        "Unexpected error: {:?}",
+        res
+    );
+    let notifications = c
+        .get_notifications(Id::from(notified), Page::default())
+        .unwrap()
+        .notifications;
+    assert_eq!(notifications.len(), 1);
+    assert_eq!(notifications[0].event, event);
+    assert!(notifications[0].delivered);
+
+    // Users get their own notifications, including failed deliveries
+    let token = c
+        .new_token(UserQuery::ById {
+            user_id: missed.into(),
+        })
+        .unwrap()
+        .token;
+    let admin_token = c.set_token(token).unwrap();
+    let notifications = c
+        .get_notifications(None::<Id>, Page::default())
+        .unwrap()
+        .notifications;
+    assert_eq!(notifications.len(), 1);
+    assert_eq!(notifications[0].user_id, missed);
+    assert!(!notifications[0].delivered);
+
+    // ... but not those of others
+    let res = c.get_notifications(Id::from(notified), Page::default()).unwrap_err();
+    assert!(
+        res.matches_api_code(ErrorCode::Unauthorized),
+        "Unexpected error: {:?}",
+        res
+    );
+    c.set_token(admin_token).unwrap();
+}
+
+#[test]
+fn test_entity_stats() {
+    let c = prep();
+
+    let notified = c.add_user("tg", gen_payload(), URL.clone(), "Pop").unwrap().id;
+    let missed = c.add_user("tg", gen_payload(), URL.clone(), "Pop").unwrap().id;
+    let since = i64::try_from(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis(),
+    )
+    .unwrap();
+
+    // Deliveries of the same event by different bots count it once
+    let entity = Uuid::new();
+    let event = Event::from_serializable("twitter", entity, json!({ "text": "hi" })).unwrap();
+    c.add_notifications(event.clone(), vec![notified.into()], vec![missed.into()])
+        .unwrap();
+    c.add_notifications(event, vec![missed.into()], vec![])
+        .unwrap();
+
+    let stats = c
+        .get_entity_stats(entity, since, None::<i64>, StatsBucket::Day)
+        .unwrap()
+        .stats;
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].start, StatsBucket::Day.start_of(since));
+    assert_eq!(stats[0].kind, "twitter");
+    assert_eq!(
+        (stats[0].events, stats[0].delivered, stats[0].failed),
+        (1, 2, 1)
+    );
+
+    // Ranges must not be empty
+    let res = c
+        .get_entity_stats(entity, since, Some(since), StatsBucket::Hour)
+        .unwrap_err();
+    assert!(
+        res.matches_api_kind(ApiErrorKind::BadRequest),
+        "Unexpected error: {:?}",
+        res
+    );
+}
+
+#[cfg(feature = "graphql")]
+#[test]
+fn test_graphql() {
+    let c = prep();
+    let http = reqwest::blocking::Client::new();
+    let query = |query: &str, token: Option<String>| {
+        let req = http
+            .post("http://127.0.0.1:8080/v1/graphql")
+            .json(&json!({ "query": query }));
+        match token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+        .send()
+        .unwrap()
+    };
+
+    let group = c.add_group(name("Hololive")).unwrap();
+    let meta = Meta {
+        name: name("Suisei"),
+        group: Some(group.id),
+        profiles: BTreeMap::new(),
+        accounts: BTreeMap::new(),
+    };
+    let bilibili = AddTaskParam::Bilibili {
+        uid: "434334701".to_owned(),
+    };
+    let entity = c.add_entity(meta, vec![bilibili], vec![]).unwrap();
+
+    let res = query(
+        &format!(
+            r#"{{ entity(id: "{}") {{ id group {{ id }} tasks {{ kind }} }} }}"#,
+            entity.id
+        ),
+        c.token(),
+    );
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+    let body: serde_json::Value = res.json().unwrap();
+    let found = &body["data"]["entity"];
+    assert_eq!(found["id"], entity.id.to_string());
+    assert_eq!(found["group"]["id"], group.id.to_string());
+    assert_eq!(found["tasks"], json!([{ "kind": "bililive" }]));
+
+    // Queries deeper than `graphql_max_depth` are rejected.
+    let res = query(
+        "{__schema{types{fields{type{ofType{ofType{ofType{ofType{name}}}}}}}}}",
+        c.token(),
+    );
+    let body: serde_json::Value = res.json().unwrap();
+    assert!(body["data"].is_null());
+    assert!(!body["errors"].as_array().unwrap().is_empty());
+
+    // The gateway is guarded like admin methods.
+    let res = query("{ groups { next } }", None);
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    c.del_entity(entity.id).unwrap();
+    c.del_group(group.id).unwrap();
 }
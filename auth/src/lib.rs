@@ -3,22 +3,65 @@
 use std::{
     fmt::{Debug, Formatter},
     sync::Arc,
+    time::{Instant, SystemTime},
 };
 
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHasher,
+        SaltString,
+    },
     Argon2,
     PasswordHash,
     PasswordVerifier,
 };
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
 use mongodb::{
-    bson::{doc, to_bson},
-    options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions},
+    bson::{doc, to_bson, Uuid},
+    options::{
+        FindOneAndUpdateOptions,
+        IndexOptions,
+        ReplaceOptions,
+        ReturnDocument,
+        UpdateOptions,
+    },
     Collection,
     Cursor,
+    IndexModel,
 };
+use ring::digest;
+
+mod_use::mod_use![model, error, role, totp];
+
+/// Prefix of API keys, to make them recognizable, e.g. by secret scanners.
+const KEY_PREFIX: &str = "sgk_";
+
+/// Result of [`AuthClient::authenticate`].
+#[must_use]
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Authentication {
+    /// Credentials are valid.
+    Granted(PermissionSet),
+    /// Username and password are valid, but the record has TOTP enabled and no
+    /// code is given.
+    TotpRequired,
+    /// Username, password or TOTP code is invalid.
+    Denied,
+}
 
-mod_use::mod_use![model, error];
+/// Result of [`AuthClient::enroll_totp`].
+#[must_use]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Enrollment {
+    /// A new secret is generated, pending verification.
+    Enrolled(Totp),
+    /// Username and password are valid, but the record has TOTP enabled and no
+    /// code of the current secret is given.
+    TotpRequired,
+    /// Username, password or TOTP code is invalid.
+    Denied,
+}
 
 /// Provides major functions that one will need.
 ///
@@ -27,6 +70,9 @@ mod_use::mod_use![model, error];
 #[derive(Clone)]
 pub struct AuthClient {
     collection: Collection<PermissionRecord>,
+    keys: Collection<KeyRecord>,
+    roles: Collection<Role>,
+    role_cache: Arc<RoleCache>,
     argon: Arc<Argon2<'static>>,
 }
 
@@ -41,6 +87,8 @@ impl Debug for AuthClient {
 
         f.debug_struct("AuthClient")
             .field("collection", &self.collection)
+            .field("keys", &self.keys)
+            .field("roles", &self.roles)
             .field(
                 "argon",
                 &Argon2 {
@@ -52,15 +100,50 @@ impl Debug for AuthClient {
 }
 
 impl AuthClient {
-    /// Create a new [`AuthClient`] with the given [`Collection`].
+    /// Create a new [`AuthClient`] with the given [`Collection`]s of records,
+    /// API keys and roles.
     #[must_use]
-    pub fn new(collection: Collection<PermissionRecord>) -> Self {
+    pub fn new(
+        collection: Collection<PermissionRecord>,
+        keys: Collection<KeyRecord>,
+        roles: Collection<Role>,
+    ) -> Self {
         Self {
             collection,
+            keys,
+            roles,
+            role_cache: Default::default(),
             argon: Default::default(),
         }
     }
 
+    /// Create indexes needed to look up API keys and roles efficiently.
+    ///
+    /// # Errors
+    /// Return an error if unable to create the indexes.
+    pub async fn create_indexes(&self) -> Result<()> {
+        let unique = IndexOptions::builder().unique(true).build();
+        self.keys
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "hash": 1 })
+                    .options(unique.clone())
+                    .build(),
+                None,
+            )
+            .await?;
+        self.roles
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "name": 1 })
+                    .options(unique)
+                    .build(),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
     /// Get the inner [`Collection`].
     #[must_use]
     pub fn collection(&self) -> Collection<PermissionRecord> {
@@ -140,7 +223,7 @@ impl AuthClient {
 
     /// Try update the permission set of a record.
     ///
-    /// Return the new permission set.
+    /// Return the new effective permission set, including those of its roles.
     /// If username or password is invalid, this will return `None` and no
     /// update will be done.
     ///
@@ -162,7 +245,7 @@ impl AuthClient {
         }
 
         let permission = to_bson(&permission)?;
-        let res = self
+        let record = self
             .collection
             .find_one_and_update(
                 doc! {
@@ -175,10 +258,12 @@ impl AuthClient {
                     .return_document(ReturnDocument::After)
                     .build(),
             )
-            .await?
-            .map(|x| x.permissions());
+            .await?;
 
-        Ok(res)
+        match record {
+            Some(rec) => Ok(Some(self.effective_permissions(&rec).await?)),
+            None => Ok(None),
+        }
     }
 
     /// Delete a record.
@@ -198,11 +283,16 @@ impl AuthClient {
             .map_err(Into::into)
     }
 
-    /// Look up permission of a user by username and password.
+    /// Look up permission of a user by username and password, including those
+    /// granted by its roles.
     ///
     /// When the username and password combination are invalid, this will return
     /// [`PermissionSet::EMPTY`].
     ///
+    /// TOTP second factor is not checked. Use [`authenticate`] to check it.
+    ///
+    /// [`authenticate`]: Self::authenticate
+    ///
     /// # Errors
     /// Return an error if unable to insert the record, or failed to compute the
     /// hash.
@@ -220,22 +310,374 @@ impl AuthClient {
             .unwrap_or_default())
     }
 
+    /// Authenticate a user by username, password and TOTP code if the record
+    /// has TOTP enabled. Each TOTP code is accepted only once.
+    ///
+    /// # Errors
+    /// Return an error if unable to query the database, or failed to compute
+    /// the hash.
+    pub async fn authenticate(
+        &self,
+        username: impl AsRef<str> + Send,
+        password: impl AsRef<[u8]> + Send,
+        totp: Option<&str>,
+    ) -> Result<Authentication> {
+        let Some(rec) = self.find_record(username.as_ref(), password.as_ref()).await? else {
+            return Ok(Authentication::Denied);
+        };
+
+        if rec.has_totp() {
+            let Some(code) = totp else {
+                return Ok(Authentication::TotpRequired);
+            };
+            if !self.accept_totp(&rec, code).await? {
+                return Ok(Authentication::Denied);
+            }
+        }
+
+        Ok(Authentication::Granted(
+            self.effective_permissions(&rec).await?,
+        ))
+    }
+
+    /// Generate a new TOTP secret for a record, which takes effect after
+    /// being confirmed by [`verify_totp`]. Enrolling again replaces the
+    /// unconfirmed secret.
+    ///
+    /// If the record has TOTP enabled already, `totp` must be a code of the
+    /// current secret, so that the password alone can't replace it.
+    ///
+    /// [`verify_totp`]: Self::verify_totp
+    ///
+    /// # Errors
+    /// Return an error if unable to update the record, or failed to compute
+    /// the hash.
+    pub async fn enroll_totp(
+        &self,
+        username: impl AsRef<str> + Send,
+        password: impl AsRef<[u8]> + Send,
+        totp: Option<&str>,
+    ) -> Result<Enrollment> {
+        let username = username.as_ref();
+
+        match self.authenticate(username, password, totp).await? {
+            Authentication::Granted(_) => {}
+            Authentication::TotpRequired => return Ok(Enrollment::TotpRequired),
+            Authentication::Denied => return Ok(Enrollment::Denied),
+        }
+
+        let totp = Totp::generate();
+        self.collection
+            .update_one(
+                doc! { "username": username },
+                doc! { "$set": { "pending_totp": totp.to_base32() } },
+                None,
+            )
+            .await?;
+
+        Ok(Enrollment::Enrolled(totp))
+    }
+
+    /// Enable the secret generated by [`enroll_totp`] if `code` is valid for
+    /// it, replacing the previous secret if there is.
+    ///
+    /// Return whether the secret is enabled. Nothing is changed if username,
+    /// password or code is invalid, or no secret is enrolled.
+    ///
+    /// [`enroll_totp`]: Self::enroll_totp
+    ///
+    /// # Errors
+    /// Return an error if unable to update the record, or failed to compute
+    /// the hash.
+    pub async fn verify_totp(
+        &self,
+        username: impl AsRef<str> + Send,
+        password: impl AsRef<[u8]> + Send,
+        code: impl AsRef<str> + Send,
+    ) -> Result<bool> {
+        let username = username.as_ref();
+
+        let pending = self
+            .find_record(username, password.as_ref())
+            .await?
+            .and_then(|rec| rec.pending_totp());
+        let Some((totp, step)) =
+            pending.and_then(|totp| totp.verify(code.as_ref(), None).map(|step| (totp, step)))
+        else {
+            return Ok(false);
+        };
+
+        self.collection
+            .update_one(
+                doc! { "username": username },
+                doc! {
+                    "$set": { "totp": totp.to_base32(), "totp_step": to_bson(&step)? },
+                    "$unset": { "pending_totp": "" },
+                },
+                None,
+            )
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Accept `code` of the TOTP secret of `rec` and record its step, unless
+    /// it's invalid or its step is accepted already, e.g. by a concurrent
+    /// login with the same code.
+    async fn accept_totp(&self, rec: &PermissionRecord, code: &str) -> Result<bool> {
+        let Some(step) = rec
+            .totp()
+            .and_then(|totp| totp.verify(code, rec.totp_step()))
+        else {
+            return Ok(false);
+        };
+
+        let step = to_bson(&step)?;
+        let res = self
+            .collection
+            .update_one(
+                doc! {
+                    "username": rec.username(),
+                    "$or": [
+                        { "totp_step": { "$exists": false } },
+                        { "totp_step": { "$lt": step.clone() } },
+                    ],
+                },
+                doc! { "$set": { "totp_step": step } },
+                None,
+            )
+            .await?;
+
+        Ok(res.matched_count == 1)
+    }
+
     async fn look_up_impl(&self, username: &str, password: &[u8]) -> Result<Option<PermissionSet>> {
+        match self.find_record(username, password).await? {
+            Some(rec) => Ok(Some(self.effective_permissions(&rec).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_record(
+        &self,
+        username: &str,
+        password: &[u8],
+    ) -> Result<Option<PermissionRecord>> {
         let record = self
             .collection
             .find_one(doc! { "username": username }, None)
             .await?;
 
         let res = match record {
-            Some(rec) if self.validate(&rec.decode()?, password.as_ref()).is_ok() => {
-                Some(rec.permissions())
-            }
+            Some(rec) if self.validate(&rec.decode()?, password).is_ok() => Some(rec),
             _ => None,
         };
 
         Ok(res)
     }
 
+    /// Effective permissions of a record, i.e. its own and those granted by
+    /// its roles.
+    ///
+    /// Roles are cached for [`ROLE_CACHE_TTL`], and loaded again once the
+    /// cache is stale or roles are changed by this client.
+    ///
+    /// # Errors
+    /// Return an error if unable to query the database.
+    pub async fn effective_permissions(&self, record: &PermissionRecord) -> Result<PermissionSet> {
+        if record.roles().is_empty() {
+            return Ok(record.permissions());
+        }
+
+        let now = Instant::now();
+        let granted = match self.role_cache.resolve(record.roles(), now) {
+            Some(granted) => granted,
+            None => {
+                let mut cursor = self.roles.find(None, None).await?;
+                let mut roles = Vec::new();
+                while cursor.advance().await? {
+                    roles.push(cursor.deserialize_current()?);
+                }
+                self.role_cache.fill(roles, now);
+                self.role_cache
+                    .resolve(record.roles(), now)
+                    .unwrap_or_default()
+            }
+        };
+
+        Ok(record.permissions().union(granted))
+    }
+
+    /// Create a role, or replace the permissions of an existing one.
+    ///
+    /// # Errors
+    /// Return an error if unable to update the database.
+    pub async fn set_role(&self, role: &Role) -> Result<()> {
+        self.roles
+            .replace_one(
+                doc! { "name": role.name() },
+                role,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        self.role_cache.invalidate();
+        Ok(())
+    }
+
+    /// Delete a role, and unassign it from all records.
+    ///
+    /// Return the deleted role, or `None` if it does not exist.
+    ///
+    /// # Errors
+    /// Return an error if unable to update the database.
+    pub async fn delete_role(&self, name: impl AsRef<str> + Send) -> Result<Option<Role>> {
+        let name = name.as_ref();
+        let role = self
+            .roles
+            .find_one_and_delete(doc! { "name": name }, None)
+            .await?;
+        self.collection
+            .update_many(
+                doc! { "roles": name },
+                doc! { "$pull": { "roles": name } },
+                None,
+            )
+            .await?;
+        self.role_cache.invalidate();
+        Ok(role)
+    }
+
+    /// List all roles in the database.
+    ///
+    /// # Errors
+    /// Return an error if unable to query the database.
+    pub async fn list_roles(&self) -> Result<Cursor<Role>> {
+        self.roles.find(None, None).await.map_err(Into::into)
+    }
+
+    /// Assign a role to a record. Roles not created yet grant nothing until
+    /// they are.
+    ///
+    /// Return whether the record exists.
+    ///
+    /// # Errors
+    /// Return an error if unable to update the record.
+    pub async fn assign_role(
+        &self,
+        username: impl AsRef<str> + Send,
+        role: impl AsRef<str> + Send,
+    ) -> Result<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "username": username.as_ref() },
+                doc! { "$addToSet": { "roles": role.as_ref() } },
+                None,
+            )
+            .await?;
+        Ok(res.matched_count > 0)
+    }
+
+    /// Unassign a role from a record.
+    ///
+    /// Return whether the role was assigned to the record.
+    ///
+    /// # Errors
+    /// Return an error if unable to update the record.
+    pub async fn unassign_role(
+        &self,
+        username: impl AsRef<str> + Send,
+        role: impl AsRef<str> + Send,
+    ) -> Result<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "username": username.as_ref() },
+                doc! { "$pull": { "roles": role.as_ref() } },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    /// Issue a new API key granting `scopes` until `expiry`, or forever if not
+    /// set. `name` describes who the key is issued to, e.g. a bot.
+    ///
+    /// Return the key and its record. The key is only available here; the
+    /// database only stores its hash.
+    ///
+    /// # Errors
+    /// Return an error if unable to insert the record.
+    pub async fn create_key(
+        &self,
+        name: impl Into<String> + Send,
+        scopes: PermissionSet,
+        expiry: Option<SystemTime>,
+    ) -> Result<(String, KeyRecord)> {
+        let mut secret = [0; 32];
+        OsRng.fill_bytes(&mut secret);
+        let key = format!("{KEY_PREFIX}{}", BASE64URL_NOPAD.encode(&secret));
+
+        let record = KeyRecord::new(hash_key(&key), name.into(), scopes, expiry);
+        self.keys.insert_one(&record, None).await?;
+
+        Ok((key, record))
+    }
+
+    /// Revoke an API key by its id.
+    ///
+    /// Return the revoked record, or `None` if it does not exist.
+    ///
+    /// # Errors
+    /// Return an error if unable to delete the record.
+    pub async fn revoke_key(&self, id: Uuid) -> Result<Option<KeyRecord>> {
+        self.keys
+            .find_one_and_delete(doc! { "id": id }, None)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// List all API keys in the database.
+    ///
+    /// # Errors
+    /// Return an error if unable to query the database.
+    pub async fn list_keys(&self) -> Result<Cursor<KeyRecord>> {
+        self.keys.find(None, None).await.map_err(Into::into)
+    }
+
+    /// Look up the record of an API key.
+    ///
+    /// Return `None` if the key does not exist, is revoked or expired.
+    ///
+    /// # Errors
+    /// Return an error if unable to query the database.
+    pub async fn look_up_key(&self, key: impl AsRef<str> + Send) -> Result<Option<KeyRecord>> {
+        let key = key.as_ref();
+        if !key.starts_with(KEY_PREFIX) {
+            return Ok(None);
+        }
+
+        let record = self
+            .keys
+            .find_one(doc! { "hash": hash_key(key) }, None)
+            .await?;
+
+        Ok(record.filter(|rec| !rec.is_expired_at(SystemTime::now())))
+    }
+
+    /// Look up the record of an API key by its id, e.g. to check that a token
+    /// issued for it is still good.
+    ///
+    /// Return `None` if the key does not exist, is revoked or expired.
+    ///
+    /// # Errors
+    /// Return an error if unable to query the database.
+    pub async fn find_key(&self, id: Uuid) -> Result<Option<KeyRecord>> {
+        let record = self.keys.find_one(doc! { "id": id }, None).await?;
+
+        Ok(record.filter(|rec| !rec.is_expired_at(SystemTime::now())))
+    }
+
     /// Validate if a password is correct
     ///
     /// # Errors
@@ -247,8 +689,15 @@ impl AuthClient {
     }
 }
 
+/// Hash of an API key. Keys have enough entropy so a fast hash is enough.
+fn hash_key(key: &str) -> String {
+    HEXLOWER.encode(digest::digest(&digest::SHA256, key.as_bytes()).as_ref())
+}
+
 #[cfg(test)]
 mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
     use futures::StreamExt;
 
     use crate::*;
@@ -263,11 +712,14 @@ mod test {
 
         let db = client.database("test");
         let col = db.collection("permissions");
+        let keys = db.collection("keys");
+        let roles = db.collection("roles");
 
         col.drop(None).await.unwrap();
+        keys.drop(None).await.unwrap();
 
         // Begin testing
-        let client = AuthClient::new(col);
+        let client = AuthClient::new(col, keys, roles);
         let username = "test_user";
         let password = b"test_password";
         let per = PermissionSet {
@@ -318,7 +770,213 @@ mod test {
         let res = client.look_up(username, password).await.unwrap();
         assert_eq!(res, PermissionSet::FULL);
 
+        // Without TOTP, password is enough
+        let res = client.authenticate(username, password, None).await.unwrap();
+        assert_eq!(res, Authentication::Granted(PermissionSet::FULL));
+
+        // Enrolled TOTP takes effect only after being verified
+        let res = client
+            .enroll_totp(username, b"bad_password", None)
+            .await
+            .unwrap();
+        assert_eq!(res, Enrollment::Denied);
+        let Enrollment::Enrolled(totp) =
+            client.enroll_totp(username, password, None).await.unwrap()
+        else {
+            panic!("TOTP not enrolled");
+        };
+        let res = client.authenticate(username, password, None).await.unwrap();
+        assert_eq!(res, Authentication::Granted(PermissionSet::FULL));
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let code = totp.code_at(now);
+        let next = totp.code_at(now + 30);
+        let wrong = totp.code_at(now + 3600);
+        assert!(!client.verify_totp(username, password, &wrong).await.unwrap());
+        assert!(client.verify_totp(username, password, &code).await.unwrap());
+
+        // Now TOTP code is required
+        let res = client.authenticate(username, password, None).await.unwrap();
+        assert_eq!(res, Authentication::TotpRequired);
+        let res = client
+            .authenticate(username, password, Some(&wrong))
+            .await
+            .unwrap();
+        assert_eq!(res, Authentication::Denied);
+        let res = client
+            .authenticate(username, b"bad_password", Some(&next))
+            .await
+            .unwrap();
+        assert_eq!(res, Authentication::Denied);
+
+        // Codes are accepted once, and not before the last accepted one
+        let res = client
+            .authenticate(username, password, Some(&code))
+            .await
+            .unwrap();
+        assert_eq!(res, Authentication::Denied);
+        let res = client
+            .authenticate(username, password, Some(&next))
+            .await
+            .unwrap();
+        assert_eq!(res, Authentication::Granted(PermissionSet::FULL));
+        let res = client
+            .authenticate(username, password, Some(&next))
+            .await
+            .unwrap();
+        assert_eq!(res, Authentication::Denied);
+
+        // Enrolling again requires a code of the current secret
+        let res = client.enroll_totp(username, password, None).await.unwrap();
+        assert_eq!(res, Enrollment::TotpRequired);
+        let res = client
+            .enroll_totp(username, password, Some(&next))
+            .await
+            .unwrap();
+        assert_eq!(res, Enrollment::Denied);
+
+        // Clean up
+        client.collection().drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keys() {
+        let client = mongodb::Client::with_uri_str(
+            std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_owned()),
+        )
+        .await
+        .unwrap();
+
+        let db = client.database("test");
+        let col = db.collection("key_permissions");
+        let keys = db.collection("key_records");
+        let roles = db.collection("key_roles");
+
+        keys.drop(None).await.unwrap();
+
+        let client = AuthClient::new(col, keys, roles);
+        client.create_indexes().await.unwrap();
+        let scopes = PermissionSet {
+            api: Some(Permission::ReadWrite),
+            ..PermissionSet::EMPTY
+        };
+
+        // Issued key can be looked up
+        let (key, record) = client.create_key("bot", scopes, None).await.unwrap();
+        let found = client.look_up_key(&key).await.unwrap().unwrap();
+        assert_eq!(found, record);
+        assert_eq!(found.scopes(), scopes);
+        assert_eq!(found.name(), "bot");
+
+        // Unknown keys are not found
+        assert!(client.look_up_key("sgk_unknown").await.unwrap().is_none());
+        assert!(client.look_up_key("unknown").await.unwrap().is_none());
+
+        // Expired keys are not found
+        let past = SystemTime::now() - std::time::Duration::from_secs(60);
+        let (expired, expired_record) = client.create_key("old", scopes, Some(past)).await.unwrap();
+        assert!(client.look_up_key(&expired).await.unwrap().is_none());
+
+        // Keys can be found by id unless expired
+        assert_eq!(
+            client.find_key(record.id()).await.unwrap(),
+            Some(record.clone())
+        );
+        assert!(
+            client
+                .find_key(expired_record.id())
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // Revoked keys are not found
+        let revoked = client.revoke_key(record.id()).await.unwrap();
+        assert_eq!(revoked, Some(record.clone()));
+        assert!(client.look_up_key(&key).await.unwrap().is_none());
+        assert!(client.find_key(record.id()).await.unwrap().is_none());
+        assert!(client.revoke_key(Uuid::new()).await.unwrap().is_none());
+
+        // Clean up
+        client.keys.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_roles() {
+        let client = mongodb::Client::with_uri_str(
+            std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_owned()),
+        )
+        .await
+        .unwrap();
+
+        let db = client.database("test");
+        let col = db.collection("role_permissions");
+        let keys = db.collection("role_keys");
+        let roles = db.collection("roles");
+
+        col.drop(None).await.unwrap();
+        roles.drop(None).await.unwrap();
+
+        let client = AuthClient::new(col, keys, roles);
+        client.create_indexes().await.unwrap();
+        let username = "dashboard";
+        let password = b"test_password";
+        let own = PermissionSet {
+            api: Some(Permission::ReadOnly),
+            ..PermissionSet::EMPTY
+        };
+        let readonly = PermissionSet {
+            admin: Some(Permission::ReadOnly),
+            api: Some(Permission::ReadOnly),
+            ..PermissionSet::EMPTY
+        };
+        let bot = PermissionSet {
+            api: Some(Permission::ReadWrite),
+            ..PermissionSet::EMPTY
+        };
+        client.new_record(username, password, own).await.unwrap();
+
+        // Roles can be assigned to existing records only, and before they are
+        // created
+        assert!(!client.assign_role("nobody", "readonly").await.unwrap());
+        assert!(client.assign_role(username, "readonly").await.unwrap());
+        let res = client.look_up(username, password).await.unwrap();
+        assert_eq!(res, own);
+
+        // Permissions of roles are granted on top of the record's own
+        client
+            .set_role(&Role::new("readonly", readonly))
+            .await
+            .unwrap();
+        client.set_role(&Role::new("bot", bot)).await.unwrap();
+        client.assign_role(username, "bot").await.unwrap();
+        let res = client.look_up(username, password).await.unwrap();
+        assert_eq!(res, own.union(readonly).union(bot));
+        let res = client.authenticate(username, password, None).await.unwrap();
+        assert_eq!(res, Authentication::Granted(own.union(readonly).union(bot)));
+
+        // Changes of roles take effect at once
+        client
+            .set_role(&Role::new("readonly", PermissionSet::EMPTY))
+            .await
+            .unwrap();
+        let res = client.look_up(username, password).await.unwrap();
+        assert_eq!(res, bot);
+        assert!(client.unassign_role(username, "bot").await.unwrap());
+        assert!(!client.unassign_role(username, "bot").await.unwrap());
+        let res = client.look_up(username, password).await.unwrap();
+        assert_eq!(res, own);
+
+        // Deleted roles are unassigned
+        assert!(client.delete_role("readonly").await.unwrap().is_some());
+        assert!(client.delete_role("readonly").await.unwrap().is_none());
+        let record = client.list().await.unwrap().next().await.unwrap().unwrap();
+        assert!(record.roles().is_empty());
+        let roles: Vec<_> = client.list_roles().await.unwrap().collect().await;
+        assert_eq!(roles.len(), 1);
+
         // Clean up
         client.collection().drop(None).await.unwrap();
+        client.roles.drop(None).await.unwrap();
     }
 }
@@ -1,9 +1,12 @@
 #![allow(clippy::use_self)]
 
+use std::time::SystemTime;
+
 use argon2::password_hash::{Encoding, PasswordHash};
+use mongodb::bson::{DateTime, Uuid};
 use serde::{Deserialize, Serialize};
 
-use crate::Result;
+use crate::{Result, Totp};
 
 /// Permission of either read-only and read-write
 #[must_use]
@@ -15,6 +18,15 @@ pub enum Permission {
     ReadWrite,
 }
 
+/// Central component a [`PermissionSet`] grants access to.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+pub enum Component {
+    Api,
+    Admin,
+    Mq,
+    Coordinator,
+}
+
 /// A partial map whose domain are central components and co-domain are read-only and read-write.
 #[must_use]
 #[non_exhaustive]
@@ -44,6 +56,35 @@ impl PermissionSet {
     /// Full permission set, has access to all of the components.
     pub const FULL: Self = Self::full();
 
+    /// Permission on the component, if any.
+    #[must_use]
+    pub const fn get(&self, component: Component) -> Option<Permission> {
+        match component {
+            Component::Api => self.api,
+            Component::Admin => self.admin,
+            Component::Mq => self.mq,
+            Component::Coordinator => self.coordinator,
+        }
+    }
+
+    /// Whether the set grants at least `permission` on the component.
+    #[must_use]
+    pub fn grants(&self, component: Component, permission: Permission) -> bool {
+        self.get(component)
+            .is_some_and(|granted| granted >= permission)
+    }
+
+    /// Union of two sets, granting the higher permission of both on each
+    /// component.
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            api: self.api.max(other.api),
+            admin: self.admin.max(other.admin),
+            mq: self.mq.max(other.mq),
+            coordinator: self.coordinator.max(other.coordinator),
+        }
+    }
+
     pub(crate) const fn empty() -> Self {
         Self {
             api: None,
@@ -76,6 +117,19 @@ pub struct PermissionRecord {
     hash: String,
     username: String,
     permissions: PermissionSet,
+    /// Names of roles assigned to the record, granting their permissions on
+    /// top of its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<String>,
+    /// TOTP secret in base32, if second factor is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp: Option<String>,
+    /// TOTP secret enrolled but not verified yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_totp: Option<String>,
+    /// Step of the last TOTP code accepted, so that it can't be used again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp_step: Option<u64>,
 }
 
 impl PermissionRecord {
@@ -88,6 +142,10 @@ impl PermissionRecord {
             hash: hash.serialize().as_str().into(),
             username: username.into(),
             permissions,
+            roles: Vec::new(),
+            totp: None,
+            pending_totp: None,
+            totp_step: None,
         }
     }
 
@@ -103,11 +161,44 @@ impl PermissionRecord {
         &self.username
     }
 
-    /// Get the permissions
+    /// Get the permissions of the record itself, without those of its roles.
+    /// Use [`AuthClient::effective_permissions`] to resolve them.
+    ///
+    /// [`AuthClient::effective_permissions`]: crate::AuthClient::effective_permissions
     pub const fn permissions(&self) -> PermissionSet {
         self.permissions
     }
 
+    /// Get the names of roles assigned to the record
+    #[must_use]
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    /// Whether TOTP second factor is enabled.
+    #[must_use]
+    pub const fn has_totp(&self) -> bool {
+        self.totp.is_some()
+    }
+
+    /// Get the TOTP secret, if second factor is enabled.
+    #[must_use]
+    pub fn totp(&self) -> Option<Totp> {
+        self.totp.as_deref().and_then(Totp::from_base32)
+    }
+
+    /// Get the TOTP secret enrolled but not verified yet.
+    #[must_use]
+    pub fn pending_totp(&self) -> Option<Totp> {
+        self.pending_totp.as_deref().and_then(Totp::from_base32)
+    }
+
+    /// Get the step of the last TOTP code accepted.
+    #[must_use]
+    pub const fn totp_step(&self) -> Option<u64> {
+        self.totp_step
+    }
+
     /// Decode hash with default [`Encoding`].
     /// To use a different encoding, see [`decode_with`].
     ///
@@ -133,3 +224,91 @@ impl PermissionRecord {
         PasswordHash::parse(&self.hash, encoding).map_err(Into::into)
     }
 }
+
+/// A named permission set, e.g. `admin` or `readonly-dashboard`, assigned to
+/// records instead of managing their permission sets one by one.
+#[must_use]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Role {
+    name: String,
+    permissions: PermissionSet,
+}
+
+impl Role {
+    pub fn new(name: impl Into<String>, permissions: PermissionSet) -> Self {
+        Self {
+            name: name.into(),
+            permissions,
+        }
+    }
+
+    /// Get the name, which records refer to the role by
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the permissions granted by the role
+    pub const fn permissions(&self) -> PermissionSet {
+        self.permissions
+    }
+}
+
+/// Record of an API key in the database. The key itself is not stored, only
+/// its hash.
+#[must_use]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRecord {
+    id: Uuid,
+    hash: String,
+    name: String,
+    scopes: PermissionSet,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiry: Option<DateTime>,
+}
+
+impl KeyRecord {
+    pub(crate) fn new(
+        hash: String,
+        name: String,
+        scopes: PermissionSet,
+        expiry: Option<SystemTime>,
+    ) -> Self {
+        Self {
+            id: Uuid::new(),
+            hash,
+            name,
+            scopes,
+            expiry: expiry.map(DateTime::from_system_time),
+        }
+    }
+
+    /// Get the id, used to revoke the key
+    #[must_use]
+    pub const fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Get the name describing who the key is issued to
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the permissions granted to the key
+    pub const fn scopes(&self) -> PermissionSet {
+        self.scopes
+    }
+
+    /// Get the time the key expires at, if it does
+    #[must_use]
+    pub fn expiry(&self) -> Option<SystemTime> {
+        self.expiry.map(DateTime::to_system_time)
+    }
+
+    /// Whether the key is expired at `now`
+    #[must_use]
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expiry().is_some_and(|expiry| expiry <= now)
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    sync::{PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{PermissionSet, Role};
+
+/// Time roles are cached for. Changes of roles made by other clients, e.g.
+/// other replicas of the API server, take effect after this at the latest.
+pub const ROLE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Permissions of all roles, loaded at once and kept for [`ROLE_CACHE_TTL`],
+/// so that looking up a record doesn't query its roles every time.
+#[derive(Debug, Default)]
+pub(crate) struct RoleCache(RwLock<Option<CachedRoles>>);
+
+#[derive(Debug)]
+struct CachedRoles {
+    permissions: HashMap<String, PermissionSet>,
+    loaded_at: Instant,
+}
+
+impl RoleCache {
+    /// Permissions granted by roles of `names` together, or `None` if the
+    /// cache is empty or stale at `now`. Unknown roles grant nothing.
+    pub(crate) fn resolve(&self, names: &[String], now: Instant) -> Option<PermissionSet> {
+        let cached = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        let cached = cached
+            .as_ref()
+            .filter(|cached| now.saturating_duration_since(cached.loaded_at) < ROLE_CACHE_TTL)?;
+        Some(
+            names
+                .iter()
+                .filter_map(|name| cached.permissions.get(name))
+                .fold(PermissionSet::EMPTY, |acc, permissions| {
+                    acc.union(*permissions)
+                }),
+        )
+    }
+
+    /// Replace the cache with all roles, loaded at `now`.
+    pub(crate) fn fill(&self, roles: impl IntoIterator<Item = Role>, now: Instant) {
+        let permissions = roles
+            .into_iter()
+            .map(|role| (role.name().to_string(), role.permissions()))
+            .collect();
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Some(CachedRoles {
+            permissions,
+            loaded_at: now,
+        });
+    }
+
+    /// Drop the cache, e.g. after roles are changed.
+    pub(crate) fn invalidate(&self) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use crate::{role::RoleCache, Permission, PermissionSet, Role, ROLE_CACHE_TTL};
+
+    #[test]
+    fn test_role_cache() {
+        let cache = RoleCache::default();
+        let now = Instant::now();
+        let names = |names: &[&str]| names.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(cache.resolve(&names(&["bot"]), now), None);
+
+        let bot = PermissionSet {
+            api: Some(Permission::ReadWrite),
+            mq: Some(Permission::ReadOnly),
+            ..PermissionSet::EMPTY
+        };
+        let dashboard = PermissionSet {
+            admin: Some(Permission::ReadOnly),
+            mq: Some(Permission::ReadWrite),
+            ..PermissionSet::EMPTY
+        };
+        cache.fill(
+            [Role::new("bot", bot), Role::new("dashboard", dashboard)],
+            now,
+        );
+
+        // Roles grant the higher permission of each.
+        assert_eq!(
+            cache.resolve(&names(&["bot", "dashboard", "unknown"]), now),
+            Some(PermissionSet {
+                api: Some(Permission::ReadWrite),
+                admin: Some(Permission::ReadOnly),
+                mq: Some(Permission::ReadWrite),
+                ..PermissionSet::EMPTY
+            })
+        );
+        assert_eq!(cache.resolve(&names(&[]), now), Some(PermissionSet::EMPTY));
+
+        // Stale or invalidated caches resolve nothing.
+        assert_eq!(cache.resolve(&names(&["bot"]), now + ROLE_CACHE_TTL), None);
+        cache.invalidate();
+        assert_eq!(cache.resolve(&names(&["bot"]), now), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use data_encoding::BASE32_NOPAD;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use ring::hmac;
+
+/// Length of a TOTP code in digits.
+const DIGITS: u32 = 6;
+
+/// Lifetime of a TOTP code in seconds.
+const STEP: u64 = 30;
+
+/// Steps before and after current one whose codes are also accepted, to
+/// tolerate clock skew.
+const SKEW: u64 = 1;
+
+/// Characters escaped in components of `otpauth://` URIs, i.e. all but the
+/// unreserved ones of RFC 3986.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// TOTP secret, as specified in [RFC 6238] with HMAC-SHA1, 6 digits and 30
+/// seconds step, which is what most authenticator apps support.
+///
+/// [RFC 6238]: https://www.rfc-editor.org/rfc/rfc6238
+#[derive(Clone, PartialEq, Eq)]
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for Totp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Totp").finish_non_exhaustive()
+    }
+}
+
+impl Totp {
+    /// Generate a random 160-bit secret.
+    #[must_use]
+    pub fn generate() -> Self {
+        let mut secret = vec![0; 20];
+        OsRng.fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    /// Decode a secret in unpadded base32, the format of [`to_base32`].
+    ///
+    /// [`to_base32`]: Self::to_base32
+    #[must_use]
+    pub fn from_base32(secret: &str) -> Option<Self> {
+        BASE32_NOPAD
+            .decode(secret.as_bytes())
+            .ok()
+            .map(|secret| Self { secret })
+    }
+
+    /// Encode the secret in unpadded base32, the format authenticator apps
+    /// take.
+    #[must_use]
+    pub fn to_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    /// `otpauth://` URI of the secret, usually shown as a QR code to be
+    /// scanned by authenticator apps.
+    #[must_use]
+    pub fn uri(&self, issuer: &str, account: &str) -> String {
+        let issuer = utf8_percent_encode(issuer, COMPONENT);
+        let account = utf8_percent_encode(account, COMPONENT);
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}",
+            self.to_base32()
+        )
+    }
+
+    /// Code of the step containing `time`, in seconds since Unix epoch.
+    #[must_use]
+    pub fn code_at(&self, time: u64) -> String {
+        self.code_of_step(time / STEP)
+    }
+
+    /// Step of `code` if it's valid at this moment and later than `after`,
+    /// the step of the last code accepted, so that no code is accepted twice.
+    #[must_use]
+    pub fn verify(&self, code: &str, after: Option<u64>) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.verify_at(code, now, after)
+    }
+
+    /// Step of `code` if it's valid at `time`, in seconds since Unix epoch,
+    /// and later than `after`.
+    #[must_use]
+    pub fn verify_at(&self, code: &str, time: u64, after: Option<u64>) -> Option<u64> {
+        let step = time / STEP;
+        let first = after
+            .map_or(0, |after| after + 1)
+            .max(step.saturating_sub(SKEW));
+        (first..=step + SKEW).find(|step| self.code_of_step(*step) == code)
+    }
+
+    fn code_of_step(&self, step: u64) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &self.secret);
+        let tag = hmac::sign(&key, &step.to_be_bytes());
+        let hash = tag.as_ref();
+
+        // Dynamic truncation, see RFC 4226 section 5.3
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let bin = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+
+        format!("{:0width$}", bin % 10_u32.pow(DIGITS), width = DIGITS as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Totp;
+
+    #[test]
+    fn test_rfc_vectors() {
+        // Test vectors of RFC 6238, appendix B, truncated to 6 digits
+        let totp = Totp {
+            secret: b"12345678901234567890".to_vec(),
+        };
+        assert_eq!(totp.code_at(59), "287082");
+        assert_eq!(totp.code_at(1_111_111_109), "081804");
+        assert_eq!(totp.code_at(1_234_567_890), "005924");
+        assert_eq!(totp.code_at(2_000_000_000), "279037");
+    }
+
+    #[test]
+    fn test_verify() {
+        let totp = Totp {
+            secret: b"12345678901234567890".to_vec(),
+        };
+        let code = totp.code_at(1_000_000);
+        let step = 1_000_000 / 30;
+
+        assert_eq!(totp.verify_at(&code, 1_000_000, None), Some(step));
+        assert_eq!(totp.verify_at(&code, 1_000_000 + 30, None), Some(step));
+        assert_eq!(totp.verify_at(&code, 1_000_000 - 30, None), Some(step));
+        assert_eq!(totp.verify_at(&code, 1_000_000 + 90, None), None);
+
+        // Codes at or before the last accepted step are rejected
+        assert_eq!(totp.verify_at(&code, 1_000_000, Some(step - 1)), Some(step));
+        assert_eq!(totp.verify_at(&code, 1_000_000, Some(step)), None);
+        assert_eq!(totp.verify_at(&code, 1_000_000 + 30, Some(step + 1)), None);
+
+        let totp = Totp::generate();
+        let decoded = Totp::from_base32(&totp.to_base32()).unwrap();
+        assert_eq!(decoded, totp);
+        assert!(Totp::from_base32("not base32!").is_none());
+    }
+
+    #[test]
+    fn test_uri() {
+        let totp = Totp {
+            secret: b"12345678901234567890".to_vec(),
+        };
+        assert_eq!(
+            totp.uri("Star gazer", "suisei@hoshimachi:sui-chan"),
+            "otpauth://totp/Star%20gazer:suisei%40hoshimachi%3Asui-chan?\
+             secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Star%20gazer"
+        );
+    }
+}
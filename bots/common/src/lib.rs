@@ -0,0 +1,417 @@
+//! Fan-out of events to users of an IM, shared by bots.
+//!
+//! Every bot consumes final events, looks up users interested in each of
+//! them with the `get_interest` method of the api, then renders and sends the
+//! event to each user. Bots only differ in rendering and sending, which they
+//! provide by implementing [`Renderer`] and [`Sender`].
+//!
+//! Announcements of admins are delivered to all users of the IM instead,
+//! paced to stay within rate limits of the platform.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use api::{
+    client::{Client, LogInterceptor},
+    rpc::Page,
+};
+use async_trait::async_trait;
+use eyre::{Result, WrapErr};
+use futures_util::{future::join_all, StreamExt};
+use sg_core::{
+    models::{kind, AnnouncementPayload, Event, Id, User},
+    mq::{MessageQueue, Middlewares, RabbitMQ},
+    signing::VerifyingKey,
+    store::{resolve, MongoBodyStore},
+    utils::{stable_hash, Redacted, RetryPolicy},
+};
+use tracing::{error, info, warn, Instrument};
+use url::Url;
+
+use crate::throttle::Throttle;
+
+pub mod throttle;
+
+/// Number of users queried at a time.
+const PAGE_SIZE: u32 = 100;
+
+/// Field restricting delivery of an event to the listed user ids. Set on
+/// events held back by quiet hours or rate limits, which come back through
+/// the delay middleware.
+pub const DELIVER_TO: &str = "x-deliver-to";
+
+/// Connections and checks shared by bots.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// AMQP connection url.
+    pub amqp_url: Redacted<String>,
+    /// AMQP exchange name.
+    pub amqp_exchange: String,
+    /// Api url.
+    pub api_url: Url,
+    /// Api username.
+    pub api_username: String,
+    /// Api password.
+    pub api_password: Redacted<String>,
+    /// Key to verify event signatures with. Events aren't verified if not
+    /// set.
+    pub verify_key: Option<Redacted<String>>,
+    /// Connection string of the store that workers offload large event
+    /// fields to. Offloaded fields are left as references if not set.
+    pub body_store_uri: Option<Redacted<String>>,
+    /// Consumer group to join. Replicas in the same group split final events
+    /// between them, each event going to one replica. Every replica receives
+    /// every event if not set.
+    pub consumer_group: Option<String>,
+    /// Exchange that expired events are routed to, e.g. to be archived.
+    /// They're dropped if not set.
+    pub dead_letter_exchange: Option<String>,
+}
+
+/// Renders events into messages of an IM.
+pub trait Renderer: Send + Sync {
+    type Message: Send + Sync;
+
+    /// Render an event, already localized for the user, into a message to
+    /// the user.
+    ///
+    /// # Errors
+    /// Returns an error if the event can't be rendered. It's not sent then.
+    fn render(&self, event: &Event, user: &User) -> Result<Self::Message>;
+}
+
+/// Sends messages to users of an IM.
+#[async_trait]
+pub trait Sender: Send + Sync {
+    type Message: Send + Sync;
+
+    /// IM of users messages are sent to, e.g. `webhook`.
+    const IM: &'static str;
+
+    /// Max number of users an announcement is sent to per second.
+    const BROADCAST_RATE: usize = 20;
+
+    /// Whether messages can be sent to the user at all. Rejected users count
+    /// as failed deliveries.
+    fn accepts(&self, _user: &User) -> bool {
+        true
+    }
+
+    /// Make one attempt to send a message to the user. Failed attempts are
+    /// retried with backoff.
+    ///
+    /// # Errors
+    /// Returns an error if the message didn't reach the user.
+    async fn send(&self, user: &User, message: &Self::Message) -> Result<()>;
+
+    /// Called with the outcome of sending to the user, after all retries.
+    /// `client` is logged in to the api, e.g. to disable the user with.
+    async fn report(&self, _client: &Client, _user: &User, _delivered: bool) {}
+}
+
+/// Renders and sends events to users.
+pub struct Fanout<R, S> {
+    renderer: R,
+    sender: S,
+    retry: RetryPolicy,
+}
+
+impl<R, S> Fanout<R, S>
+where
+    R: Renderer,
+    S: Sender<Message = R::Message>,
+{
+    pub const fn new(renderer: R, sender: S, retry: RetryPolicy) -> Self {
+        Self {
+            renderer,
+            sender,
+            retry,
+        }
+    }
+
+    /// Deliver an event to users concurrently. Return ids of users it
+    /// reached and of users it failed to reach.
+    pub async fn deliver(
+        &self,
+        client: &Client,
+        event: &Event,
+        users: &[User],
+    ) -> (Vec<Id>, Vec<Id>) {
+        let deliveries = users.iter().map(|user| async move {
+            (
+                Id::from(user.id),
+                self.deliver_to(client, event, user).await,
+            )
+        });
+        let (mut delivered, mut failed) = (vec![], vec![]);
+        for (user_id, success) in join_all(deliveries).await {
+            if success {
+                delivered.push(user_id);
+            } else {
+                failed.push(user_id);
+            }
+        }
+        (delivered, failed)
+    }
+
+    /// Deliver an announcement to users, at most [`Sender::BROADCAST_RATE`]
+    /// of them per second. Return ids of users it reached and of users it
+    /// failed to reach.
+    pub async fn broadcast(
+        &self,
+        client: &Client,
+        event: &Event,
+        users: &[User],
+    ) -> (Vec<Id>, Vec<Id>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let (mut delivered, mut failed) = (vec![], vec![]);
+        for users in users.chunks(S::BROADCAST_RATE.max(1)) {
+            interval.tick().await;
+            let (reached, missed) = self.deliver(client, event, users).await;
+            delivered.extend(reached);
+            failed.extend(missed);
+        }
+        (delivered, failed)
+    }
+
+    async fn deliver_to(&self, client: &Client, event: &Event, user: &User) -> bool {
+        let user_id = user.id;
+        if !self.sender.accepts(user) {
+            return false;
+        }
+        let event = match user.event_filter.language {
+            Some(language) => event.localized(language),
+            None => event.clone(),
+        };
+        let message = match self.renderer.render(&event, user) {
+            Ok(message) => message,
+            Err(error) => {
+                warn!(%user_id, event_id = %event.id, ?error, "Failed to render event");
+                return false;
+            }
+        };
+
+        let success = match self.retry.run(|| self.sender.send(user, &message)).await {
+            Ok(()) => {
+                info!(%user_id, event_id = %event.id, "Event delivered");
+                true
+            }
+            Err(error) => {
+                warn!(%user_id, event_id = %event.id, ?error, "Delivery failed");
+                false
+            }
+        };
+        self.sender.report(client, user, success).await;
+        success
+    }
+}
+
+/// Deliver final events to users of the IM of the fan-out until the AMQP
+/// connection closes.
+///
+/// # Errors
+/// Returns error if the api, AMQP or the event body store is unreachable, or
+/// the verify key is invalid.
+pub async fn run<R, S>(options: Options, fanout: Fanout<R, S>) -> Result<()>
+where
+    R: Renderer + 'static,
+    S: Sender<Message = R::Message> + 'static,
+{
+    let verify_key: Option<VerifyingKey> = options
+        .verify_key
+        .as_ref()
+        .map(|key| key.parse())
+        .transpose()
+        .wrap_err("Invalid verify key")?;
+
+    let client = Client::new(options.api_url.clone())
+        .wrap_err("Invalid api url")?
+        .with_retry(RetryPolicy::new(3))
+        .with_interceptor(LogInterceptor);
+    client
+        .login_and_store(&options.api_username, &*options.api_password)
+        .await
+        .wrap_err("Failed to login to api")?;
+
+    let body_store = match &options.body_store_uri {
+        Some(uri) => Some(MongoBodyStore::new(uri).await?),
+        None => None,
+    };
+    let fanout = Arc::new(fanout);
+    let throttle = Throttle::new();
+
+    let mut mq = RabbitMQ::new(&options.amqp_url, &options.amqp_exchange)
+        .await
+        .wrap_err("Failed to connect to AMQP")?;
+    if let Some(exchange) = &options.dead_letter_exchange {
+        mq = mq.with_dead_letter_exchange(exchange);
+    }
+    let mq = Arc::new(mq);
+    let mut consumer = mq.consume(None, options.consumer_group.as_deref()).await;
+
+    while let Some(Ok((_, mut event, delivery))) = consumer.next().await {
+        let event_id = event.id;
+        // Stale events are rejected instead, to be dead-lettered if the queue
+        // has an exchange for them.
+        if event.is_expired(SystemTime::now()) {
+            info!(%event_id, kind = %event.kind, "Dropping expired event");
+            if let Err(error) = delivery.nack(false).await {
+                warn!(%event_id, ?error, "Failed to reject event");
+            }
+            continue;
+        }
+        // Deliveries run in the background and failed ones are recorded, so
+        // events are acknowledged on receipt.
+        if let Err(error) = delivery.ack().await {
+            warn!(%event_id, ?error, "Failed to acknowledge event");
+        }
+        if let Some(key) = &verify_key {
+            if let Err(error) = event.verify(key) {
+                warn!(%event_id, kind = %event.kind, %error, "Dropping event failing verification");
+                continue;
+            }
+        }
+        // Offloaded fields are resolved after verification, since the
+        // signature covers references instead of their content.
+        if let Some(store) = &body_store {
+            if let Err(error) = resolve(&mut event, store).await {
+                warn!(%event_id, ?error, "Dropping event with unresolvable fields");
+                continue;
+            }
+        }
+        let deliver_to = match event
+            .fields
+            .remove(DELIVER_TO)
+            .map(serde_json::from_value::<HashSet<String>>)
+        {
+            None => None,
+            Some(Ok(users)) => Some(users),
+            Some(Err(error)) => {
+                warn!(%event_id, %error, "Dropping event with invalid `{DELIVER_TO}`");
+                continue;
+            }
+        };
+        let announcement = event.kind == kind::ANNOUNCEMENT;
+        if announcement {
+            match event.decode_as::<AnnouncementPayload>() {
+                Ok(payload) if !payload.targets(S::IM) => continue,
+                Ok(_) => {}
+                Err(error) => {
+                    warn!(%event_id, ?error, "Dropping malformed announcement");
+                    continue;
+                }
+            }
+        }
+        let span = event.consume_span();
+        let mut users = match interested_users(&client, S::IM, &event, deliver_to.as_ref())
+            .instrument(span.clone())
+            .await
+        {
+            Ok(users) => users,
+            Err(error) => {
+                error!(%event_id, ?error, "Failed to query interested users");
+                continue;
+            }
+        };
+
+        // Hold back events for users in quiet hours or over their rate limit.
+        // Announcements are paced by the platform's limit instead.
+        let now = SystemTime::now();
+        let mut deferred = BTreeMap::<_, Vec<_>>::new();
+        if !announcement {
+            users.retain(|user| match throttle.admit(user, now) {
+                Some(until) => {
+                    deferred.entry(until).or_default().push(user.id.to_string());
+                    false
+                }
+                None => true,
+            });
+        }
+        // Users held back past the expiry of the event never receive it.
+        deferred.retain(|until, _| !event.is_expired(*until));
+        let deferred_count: usize = deferred.values().map(Vec::len).sum();
+        info!(%event_id, count = users.len(), deferred = deferred_count, "Delivering event");
+
+        let fanout = fanout.clone();
+        let client = client.clone();
+        let mq = mq.clone();
+        tokio::spawn(
+            async move {
+                for (until, users) in deferred {
+                    if let Err(error) = defer(&*mq, &event, until, users).await {
+                        error!(%event_id, ?error, "Failed to defer event");
+                    }
+                }
+
+                let (delivered, failed) = if announcement {
+                    fanout.broadcast(&client, &event, &users).await
+                } else {
+                    fanout.deliver(&client, &event, &users).await
+                };
+
+                // Record deliveries for users to look back on.
+                if let Err(error) = client.add_notifications(event, delivered, failed).await {
+                    error!(%event_id, ?error, "Failed to record notifications");
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    Ok(())
+}
+
+/// Publish a copy of the event to be delivered to `users` at `until`, through
+/// the delay middleware.
+async fn defer(
+    mq: &impl MessageQueue,
+    event: &Event,
+    until: SystemTime,
+    users: Vec<String>,
+) -> Result<()> {
+    let at = until.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    // Deferring the same event to the same time again replaces the earlier
+    // one, even from a restarted or rebuilt bot.
+    let id = event.id.to_string();
+    #[allow(clippy::cast_possible_wrap)]
+    let delay_id = stable_hash(id.bytes().chain(at.to_le_bytes())) as i64;
+
+    let mut event = event.clone();
+    event.fields.insert("x-delay-id".to_owned(), delay_id.into());
+    event.fields.insert("x-delay-at".to_owned(), at.into());
+    event.fields.insert(DELIVER_TO.to_owned(), users.into());
+    let middlewares = "delay".parse::<Middlewares>().unwrap();
+    mq.publish(event, middlewares).await
+}
+
+/// Query users in `im` interested in the event page by page. Exclusions on
+/// event fields are applied here, as well as `deliver_to` if set. All users in
+/// `im` are interested in announcements.
+async fn interested_users(
+    client: &Client,
+    im: &str,
+    event: &Event,
+    deliver_to: Option<&HashSet<String>>,
+) -> Result<Vec<User>> {
+    let mut users = Vec::new();
+    let mut page = Page::first(PAGE_SIZE);
+    loop {
+        let interest = client
+            .get_interest(event.entity, event.kind.as_str(), im, page.clone())
+            .await?;
+        users.extend(
+            interest
+                .users
+                .into_iter()
+                .filter(|user| event.kind == kind::ANNOUNCEMENT || user.event_filter.matches(event))
+                .filter(|user| deliver_to.map_or(true, |to| to.contains(&user.id.to_string()))),
+        );
+        match interest.next {
+            Some(cursor) => page = page.next(cursor),
+            None => return Ok(users),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Quiet hours and rate limits of users.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::Mutex;
+use sg_core::models::User;
+use uuid::Uuid;
+
+const HOUR: u64 = 60 * 60;
+
+/// Events delivered to users in the current clock hour.
+///
+/// Counts are kept in memory, so they reset when the bot restarts.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    sent: Mutex<HashMap<Uuid, (u64, u32)>>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decide whether an event may be delivered to the user at `now`. Return
+    /// `None` and count the delivery if so, or the time to defer it to if the
+    /// user is in quiet hours or has reached `max_per_hour`.
+    pub fn admit(&self, user: &User, now: SystemTime) -> Option<SystemTime> {
+        if let Some(end) = user.quiet_hours.and_then(|quiet| quiet.end_after(now)) {
+            return Some(end);
+        }
+        let Some(max) = user.max_per_hour else {
+            return None;
+        };
+
+        let hour = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / HOUR;
+        let mut sent = self.sent.lock();
+        let (counted_hour, count) = sent.entry(Uuid::from(user.id)).or_default();
+        if *counted_hour != hour {
+            *counted_hour = hour;
+            *count = 0;
+        }
+        if *count >= max {
+            return Some(SystemTime::UNIX_EPOCH + Duration::from_secs((hour + 1) * HOUR));
+        }
+        *count += 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use sg_core::models::{QuietHours, User};
+    use uuid::Uuid;
+
+    use crate::throttle::Throttle;
+
+    fn at(minutes: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(minutes * 60)
+    }
+
+    fn new_user(quiet_hours: Option<QuietHours>, max_per_hour: Option<u32>) -> User {
+        User {
+            id: Uuid::new_v4().into(),
+            im: "webhook".to_owned(),
+            im_payload: "https://example.com".to_owned(),
+            name: "Pop".to_owned(),
+            avatar: None,
+            event_filter: Default::default(),
+            quiet_hours,
+            max_per_hour,
+            link_id: None,
+            disabled: false,
+            token_generation: 0,
+        }
+    }
+
+    #[test]
+    fn must_admit_unlimited() {
+        let throttle = Throttle::new();
+        let user = new_user(None, None);
+        for _ in 0..100 {
+            assert_eq!(throttle.admit(&user, at(0)), None);
+        }
+    }
+
+    #[test]
+    fn must_defer_in_quiet_hours() {
+        let throttle = Throttle::new();
+        let quiet_hours = QuietHours {
+            start: 60,
+            end: 120,
+            utc_offset: 0,
+        };
+        let user = new_user(Some(quiet_hours), Some(1));
+
+        assert_eq!(throttle.admit(&user, at(90)), Some(at(120)));
+        // Deferred events aren't counted.
+        assert_eq!(throttle.admit(&user, at(120)), None);
+    }
+
+    #[test]
+    fn must_defer_over_limit() {
+        let throttle = Throttle::new();
+        let user = new_user(None, Some(2));
+
+        assert_eq!(throttle.admit(&user, at(10)), None);
+        assert_eq!(throttle.admit(&user, at(20)), None);
+        assert_eq!(throttle.admit(&user, at(30)), Some(at(60)));
+        // Counts reset every clock hour.
+        assert_eq!(throttle.admit(&user, at(60)), None);
+        // Other users are counted separately.
+        assert_eq!(throttle.admit(&new_user(None, Some(2)), at(30)), None);
+    }
+}
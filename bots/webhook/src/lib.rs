@@ -0,0 +1,47 @@
+//! Webhook delivery bot.
+
+use std::time::Duration;
+
+use bot_common::{Fanout, Options};
+use eyre::Result;
+use sg_core::utils::RetryPolicy;
+
+use crate::{
+    config::Config,
+    webhook::{Signer, Webhook},
+};
+
+pub mod config;
+pub mod webhook;
+
+impl From<&Config> for Options {
+    fn from(config: &Config) -> Self {
+        Self {
+            amqp_url: config.amqp_url.clone(),
+            amqp_exchange: config.amqp_exchange.clone(),
+            api_url: config.api_url.clone(),
+            api_username: config.api_username.clone(),
+            api_password: config.api_password.clone(),
+            verify_key: config.verify_key.clone(),
+            body_store_uri: config.body_store_uri.clone(),
+            consumer_group: config.consumer_group.clone(),
+            dead_letter_exchange: config.dead_letter_exchange.clone(),
+        }
+    }
+}
+
+/// Deliver final events to webhook endpoints until the AMQP connection
+/// closes.
+///
+/// # Errors
+/// Returns error if the api, AMQP or the event body store is unreachable, or
+/// the verify key is invalid.
+pub async fn run(config: Config) -> Result<()> {
+    // Deliveries to an endpoint that is down fail together, so their retries
+    // are spread out.
+    let retry = RetryPolicy::new(config.max_retries)
+        .with_backoff(config.retry_backoff, Duration::MAX)
+        .with_jitter(0.2);
+    let fanout = Fanout::new(Signer::new(&config), Webhook::new(&config)?, retry);
+    bot_common::run(Options::from(&config), fanout).await
+}
@@ -0,0 +1,14 @@
+use eyre::{Result, WrapErr};
+use sg_core::utils::{init_tracing, FigmentExt};
+use tracing_subscriber::EnvFilter;
+use webhook::config::Config;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _guard = init_tracing("webhook", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("BOT_").wrap_err("Failed to load config")?;
+
+    webhook::run(config).await
+}
@@ -0,0 +1,233 @@
+//! Signed webhook delivery.
+
+use std::collections::HashMap;
+
+use api::client::Client;
+use async_trait::async_trait;
+use bot_common::{Renderer, Sender};
+use eyre::{bail, eyre, Result, WrapErr};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use sg_core::models::{Event, User};
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// IM name of webhook users.
+pub const IM: &str = "webhook";
+
+/// Header carrying the payload signature, in the form of `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Stargazer-Signature";
+
+/// Header carrying the event id.
+pub const EVENT_HEADER: &str = "X-Stargazer-Event";
+
+/// Sign a payload with HMAC-SHA256.
+#[must_use]
+pub fn sign(secret: &[u8], payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Consecutive failures of endpoints.
+///
+/// Endpoints are disabled for good by disabling their users in the api. The
+/// count only keeps an endpoint disabled here until then, or until restart if
+/// that fails.
+#[derive(Debug)]
+pub struct Endpoints {
+    disable_after: u32,
+    failures: Mutex<HashMap<Uuid, u32>>,
+}
+
+impl Endpoints {
+    pub fn new(disable_after: u32) -> Self {
+        Self {
+            disable_after,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the endpoint of the user is disabled.
+    pub fn is_disabled(&self, user: Uuid) -> bool {
+        self.failures
+            .lock()
+            .get(&user)
+            .is_some_and(|failures| *failures >= self.disable_after)
+    }
+
+    /// Record the result of a delivery. Return true if the endpoint has just
+    /// been disabled.
+    pub fn record(&self, user: Uuid, success: bool) -> bool {
+        let mut failures = self.failures.lock();
+        if success {
+            failures.remove(&user);
+            false
+        } else {
+            let count = failures.entry(user).or_default();
+            *count += 1;
+            *count == self.disable_after
+        }
+    }
+
+    /// Forget failures of the user, e.g. once disabled in the api, so that
+    /// the endpoint is delivered to again when enabled there.
+    pub fn reset(&self, user: Uuid) {
+        self.failures.lock().remove(&user);
+    }
+}
+
+/// Signed payload of an event.
+pub struct Payload {
+    event_id: String,
+    signature: String,
+    body: Vec<u8>,
+}
+
+/// Serializes and signs events.
+pub struct Signer {
+    secret: Vec<u8>,
+}
+
+impl Signer {
+    /// Create a signer from config.
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        Self {
+            secret: config.signing_secret.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl Renderer for Signer {
+    type Message = Payload;
+
+    fn render(&self, event: &Event, _user: &User) -> Result<Payload> {
+        let body = serde_json::to_vec(event).wrap_err("Failed to serialize event")?;
+        Ok(Payload {
+            event_id: event.id.to_string(),
+            signature: sign(&self.secret, &body),
+            body,
+        })
+    }
+}
+
+/// Delivers payloads to webhook endpoints.
+pub struct Webhook {
+    client: reqwest::Client,
+    endpoints: Endpoints,
+}
+
+impl Webhook {
+    /// Create a webhook deliverer from config.
+    ///
+    /// # Errors
+    /// Fails if the http client can't be built.
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(config.timeout)
+                .build()
+                .wrap_err("Failed to build http client")?,
+            endpoints: Endpoints::new(config.disable_after),
+        })
+    }
+}
+
+/// Url of the endpoint of a user, if it's a HTTPS one.
+fn endpoint(user: &User) -> Option<Url> {
+    Url::parse(&user.im_payload)
+        .ok()
+        .filter(|url| url.scheme() == "https")
+}
+
+#[async_trait]
+impl Sender for Webhook {
+    type Message = Payload;
+
+    const IM: &'static str = IM;
+
+    fn accepts(&self, user: &User) -> bool {
+        let user_id = Uuid::from(user.id);
+        if user.disabled || self.endpoints.is_disabled(user_id) {
+            debug!(%user_id, "Endpoint disabled, skip");
+            return false;
+        }
+        if endpoint(user).is_none() {
+            warn!(%user_id, url = %user.im_payload, "Not a HTTPS url, skip");
+            return false;
+        }
+        true
+    }
+
+    async fn send(&self, user: &User, payload: &Payload) -> Result<()> {
+        let url = endpoint(user).ok_or_else(|| eyre!("Not a HTTPS url"))?;
+        let resp = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, &payload.signature)
+            .header(EVENT_HEADER, &payload.event_id)
+            .body(payload.body.clone())
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            bail!("Endpoint responded with {}", resp.status());
+        }
+        Ok(())
+    }
+
+    async fn report(&self, client: &Client, user: &User, delivered: bool) {
+        let user_id = Uuid::from(user.id);
+        if !self.endpoints.record(user_id, delivered) {
+            return;
+        }
+        warn!(
+            %user_id,
+            url = %user.im_payload,
+            "Too many failed deliveries, endpoint disabled"
+        );
+        match client.set_user_disabled(user.id, true).await {
+            Ok(_) => self.endpoints.reset(user_id),
+            Err(error) => error!(
+                %user_id,
+                ?error,
+                "Failed to disable user, endpoint disabled until restart"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::webhook::{sign, Endpoints};
+
+    #[test]
+    fn must_sign() {
+        assert_eq!(
+            sign(b"key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn must_disable_after_failures() {
+        let endpoints = Endpoints::new(2);
+        let user = Uuid::new_v4();
+
+        assert!(!endpoints.record(user, false));
+        assert!(!endpoints.is_disabled(user));
+        assert!(!endpoints.record(user, true), "Success resets failures");
+        assert!(!endpoints.record(user, false));
+        assert!(endpoints.record(user, false), "Disabled on second failure");
+        assert!(endpoints.is_disabled(user));
+        endpoints.reset(user);
+        assert!(!endpoints.is_disabled(user));
+    }
+}
@@ -0,0 +1,39 @@
+//! Client of the coordinator admin API.
+
+use eyre::{Result, WrapErr};
+use reqwest::{blocking::Client, Url};
+use serde_json::Value;
+
+/// Client of the admin API of a coordinator.
+pub struct Coordinator {
+    http: Client,
+    url: Url,
+    token: String,
+}
+
+impl Coordinator {
+    #[must_use]
+    pub fn new(url: Url, token: String) -> Self {
+        Self {
+            http: Client::new(),
+            url,
+            token,
+        }
+    }
+
+    /// Get a resource of the admin API, e.g. `groups`.
+    ///
+    /// # Errors
+    /// Fails on network issue, or if the coordinator rejects the request.
+    pub fn get(&self, path: &str) -> Result<Value> {
+        let url = self.url.join(path)?;
+        let resp = self
+            .http
+            .get(url)
+            .bearer_auth(&self.token)
+            .send()
+            .wrap_err("Failed to reach coordinator")?
+            .error_for_status()?;
+        Ok(resp.json()?)
+    }
+}
@@ -0,0 +1,501 @@
+//! Command line tool administrating stargazer through its API, and the admin
+//! API of the coordinator.
+
+use std::{
+    env,
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use api::{
+    client::{blocking::Client, Error},
+    model::{AddTaskParam, UserQuery},
+    rpc::Page,
+    ErrorCode,
+};
+use clap::{Args, Parser, Subcommand};
+use eyre::{bail, eyre, Result, WrapErr};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use sg_core::models::{Id, Meta};
+
+use crate::{
+    coordinator::Coordinator,
+    output::{Column, Format},
+};
+
+mod coordinator;
+mod output;
+
+const ENTITY: &[Column] = &[
+    Column::Field("ID", "/id"),
+    Column::Name("NAME", "/meta/name"),
+    Column::Field("GROUP", "/meta/group"),
+    Column::Field("TASKS", "/tasks"),
+];
+const TASK: &[Column] = &[
+    Column::Field("ID", "/id"),
+    Column::Field("ENTITY", "/entity"),
+    Column::Field("KIND", "/kind"),
+    Column::Field("PARAMS", "/params"),
+];
+const USER: &[Column] = &[
+    Column::Field("ID", "/id"),
+    Column::Field("IM", "/im"),
+    Column::Field("IM_PAYLOAD", "/im_payload"),
+    Column::Field("NAME", "/name"),
+];
+
+#[derive(Debug, Parser)]
+#[command(name = "sgctl", version, about)]
+struct Cli {
+    /// URL of the API, with the API version and a trailing slash.
+    #[arg(
+        long,
+        env = "SGCTL_API_URL",
+        default_value = "http://127.0.0.1:8080/v1/"
+    )]
+    api_url: Url,
+    /// File the session is kept in between runs. Defaults to
+    /// `~/.sgctl-session`.
+    #[arg(long, env = "SGCTL_SESSION_FILE")]
+    session_file: Option<PathBuf>,
+    /// URL of the admin API of the coordinator.
+    #[arg(
+        long,
+        env = "SGCTL_COORDINATOR_URL",
+        default_value = "http://127.0.0.1:7001/"
+    )]
+    coordinator_url: Url,
+    /// Bearer token of the admin API of the coordinator.
+    #[arg(long, env = "SGCTL_COORDINATOR_TOKEN", hide_env_values = true)]
+    coordinator_token: Option<String>,
+    /// Format results are printed in.
+    #[arg(long, short, value_enum, default_value_t)]
+    output: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Login, keeping the session for later commands. Accounts with TOTP
+    /// enabled are not supported.
+    Login {
+        #[arg(long, short)]
+        username: String,
+        /// Read from stdin if not set.
+        #[arg(long, env = "SGCTL_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+    },
+    /// Forget the session.
+    Logout,
+    /// Manage entities.
+    #[command(subcommand)]
+    Entity(EntityCommand),
+    /// Manage tasks of entities.
+    #[command(subcommand)]
+    Task(TaskCommand),
+    /// Manage users.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Create a token of a user.
+    Token(UserArgs),
+    /// Announce a message to all users of an IM, or of every IM.
+    Broadcast {
+        message: String,
+        #[arg(long)]
+        im: Option<String>,
+    },
+    /// Inspect worker groups of the coordinator.
+    #[command(subcommand)]
+    Coordinator(CoordinatorCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum EntityCommand {
+    /// List entities, sorted by id.
+    List {
+        #[arg(long)]
+        limit: Option<u32>,
+    },
+    /// Search entities by their names and aliases.
+    Search {
+        query: String,
+        #[arg(long)]
+        limit: Option<u32>,
+    },
+    /// Add an entity.
+    Add {
+        /// Meta of the entity as JSON, or `@<path>` of a JSON file.
+        #[arg(long)]
+        meta: String,
+        /// Task of the entity as `<kind>:<id>`, e.g. `youtube:<channel id>`.
+        #[arg(long = "task", value_parser = task_param)]
+        tasks: Vec<AddTaskParam>,
+        /// Templates of tasks derived from the meta as a JSON array, or
+        /// `@<path>` of a JSON file.
+        #[arg(long)]
+        auto_tasks: Option<String>,
+    },
+    /// Replace the meta of an entity, and its task templates if given.
+    Update {
+        id: Id,
+        /// Meta of the entity as JSON, or `@<path>` of a JSON file.
+        #[arg(long)]
+        meta: String,
+        /// Templates of tasks derived from the meta as a JSON array, or
+        /// `@<path>` of a JSON file.
+        #[arg(long)]
+        auto_tasks: Option<String>,
+    },
+    /// Delete an entity and its tasks.
+    Delete { id: Id },
+    /// Move an entity into a group, or out of any group if not set.
+    SetGroup {
+        id: Id,
+        #[arg(long)]
+        group: Option<Id>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TaskCommand {
+    /// Add a task to an entity.
+    Add {
+        entity: Id,
+        /// Task as `<kind>:<id>`, e.g. `twitter:<screen name>`.
+        #[arg(value_parser = task_param)]
+        task: AddTaskParam,
+    },
+    /// Check a task without adding it.
+    Validate {
+        /// Task as `<kind>:<id>`, e.g. `twitter:<screen name>`.
+        #[arg(value_parser = task_param)]
+        task: AddTaskParam,
+    },
+    /// Delete a task.
+    Delete { id: Id },
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Show a user.
+    Get(UserArgs),
+    /// Delete a user.
+    Delete(UserArgs),
+    /// Enable deliveries to a user, e.g. to a webhook endpoint disabled
+    /// after failing repeatedly.
+    Enable { id: Id },
+    /// Disable deliveries to a user.
+    Disable { id: Id },
+}
+
+/// A user, by id or by IM.
+#[derive(Debug, Args)]
+struct UserArgs {
+    #[arg(long, required_unless_present = "im", conflicts_with = "im")]
+    id: Option<Id>,
+    #[arg(long, requires = "im_payload")]
+    im: Option<String>,
+    #[arg(long, requires = "im")]
+    im_payload: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum CoordinatorCommand {
+    /// List worker groups.
+    Groups,
+    /// Show workers and tasks of a worker group.
+    Group { kind: String },
+    /// List tasks reported failing.
+    Failing,
+    /// List tasks producing no activity within their SLA.
+    Stale,
+    /// List tasks quarantined after workers rejected them.
+    Quarantined,
+    /// List live worker connections.
+    Connections,
+}
+
+impl From<&UserArgs> for UserQuery {
+    fn from(args: &UserArgs) -> Self {
+        match args.id {
+            Some(user_id) => Self::ById { user_id },
+            None => Self::ByIm {
+                im: args.im.clone().unwrap_or_default(),
+                im_payload: args.im_payload.clone().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// Parse a task given as `<kind>:<id>`.
+fn task_param(arg: &str) -> Result<AddTaskParam, String> {
+    let (kind, id) = arg
+        .split_once(':')
+        .ok_or_else(|| String::from("expected `<kind>:<id>`"))?;
+    let id = id.to_string();
+    match kind {
+        "youtube" => Ok(AddTaskParam::Youtube { channel_id: id }),
+        "bilibili" | "bililive" => Ok(AddTaskParam::Bilibili { uid: id }),
+        "twitter" => Ok(AddTaskParam::Twitter { id }),
+        _ => Err(format!("unknown task kind `{kind}`")),
+    }
+}
+
+/// Parse a JSON argument, or the JSON file at `@<path>`.
+fn json_arg<T: DeserializeOwned>(arg: &str) -> Result<T> {
+    let json = match arg.strip_prefix('@') {
+        Some(path) => {
+            fs::read_to_string(path).wrap_err_with(|| format!("Failed to read {path}"))?
+        }
+        None => arg.to_string(),
+    };
+    serde_json::from_str(&json).wrap_err("Malformed JSON")
+}
+
+fn read_password() -> Result<String> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl Cli {
+    fn client(&self) -> Result<Client> {
+        let home = || env::var_os("HOME").map(|home| PathBuf::from(home).join(".sgctl-session"));
+        let session_file = self.session_file.clone().or_else(home);
+        let client = Client::new(self.api_url.clone())?;
+        Ok(match session_file {
+            Some(path) => client.with_session_file(path)?,
+            None => client,
+        })
+    }
+
+    fn coordinator(&self) -> Result<Coordinator> {
+        let token = self
+            .coordinator_token
+            .clone()
+            .ok_or_else(|| eyre!("Coordinator token not set, see `--coordinator-token`"))?;
+        Ok(Coordinator::new(self.coordinator_url.clone(), token))
+    }
+
+    fn run(&self) -> Result<()> {
+        let output = self.output;
+        let client = self.client()?;
+        match &self.command {
+            Command::Login { username, password } => {
+                let password = match password {
+                    Some(password) => password.clone(),
+                    None => read_password()?,
+                };
+                client.login_and_store(username, password)?;
+                eprintln!("Logged in as {username}");
+            }
+            Command::Logout => {
+                client.logout()?;
+                eprintln!("Logged out");
+            }
+            Command::Entity(command) => entity(&client, output, command)?,
+            Command::Task(command) => task(&client, output, command)?,
+            Command::User(UserCommand::Get(user)) => {
+                let token = client.new_token(UserQuery::from(user))?;
+                let as_user = Client::new(self.api_url.clone())?;
+                as_user.set_token(token.token);
+                output.item(&as_user.auth_user()?.user, USER)?;
+            }
+            Command::User(UserCommand::Delete(user)) => {
+                output.item(&client.del_user(UserQuery::from(user))?, USER)?;
+            }
+            Command::User(UserCommand::Enable { id }) => {
+                output.item(&client.set_user_disabled(*id, false)?, USER)?;
+            }
+            Command::User(UserCommand::Disable { id }) => {
+                output.item(&client.set_user_disabled(*id, true)?, USER)?;
+            }
+            Command::Token(user) => {
+                let token = client.new_token(UserQuery::from(user))?;
+                let columns = [
+                    Column::Field("TOKEN", "/token"),
+                    Column::Field("VALID_UNTIL", "/valid_until"),
+                ];
+                output.item(&token, &columns)?;
+            }
+            Command::Broadcast { message, im } => {
+                let broadcasted = client.broadcast(message.clone(), im.clone())?;
+                output.item(&broadcasted, &[Column::Field("EVENT", "/event_id")])?;
+            }
+            Command::Coordinator(command) => {
+                coordinator(&self.coordinator()?, output, command)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn entity(client: &Client, output: Format, command: &EntityCommand) -> Result<()> {
+    match command {
+        EntityCommand::List { limit } => {
+            let page = limit.map_or_else(Page::default, Page::first);
+            // Groups are not listed, so take as few as a page allows.
+            let entities = client.get_entities(page, Page::first(1))?;
+            output.list(&entities.vtbs, ENTITY)
+        }
+        EntityCommand::Search { query, limit } => {
+            let result = client.search_entities(query.clone(), *limit)?;
+            output.list(&result.entities, ENTITY)
+        }
+        EntityCommand::Add {
+            meta,
+            tasks,
+            auto_tasks,
+        } => {
+            let meta: Meta = json_arg(meta)?;
+            let auto_tasks = auto_tasks.as_deref().map(json_arg).transpose()?;
+            output.item(
+                &client.add_entity(meta, tasks.clone(), auto_tasks.unwrap_or_default())?,
+                ENTITY,
+            )
+        }
+        EntityCommand::Update {
+            id,
+            meta,
+            auto_tasks,
+        } => {
+            let meta: Meta = json_arg(meta)?;
+            let auto_tasks = auto_tasks.as_deref().map(json_arg).transpose()?;
+            output.item(&client.update_entity(*id, meta, auto_tasks)?, ENTITY)
+        }
+        EntityCommand::Delete { id } => output.item(&client.del_entity(*id)?, ENTITY),
+        EntityCommand::SetGroup { id, group } => {
+            output.item(&client.set_entity_group(*id, *group)?, ENTITY)
+        }
+    }
+}
+
+fn task(client: &Client, output: Format, command: &TaskCommand) -> Result<()> {
+    match command {
+        TaskCommand::Add { entity, task } => {
+            output.item(&client.add_task(task.clone(), *entity)?, TASK)
+        }
+        TaskCommand::Validate { task } => {
+            let validation = client.validate_task(task.clone())?;
+            let columns = [
+                Column::Field("FIELD", "/field"),
+                Column::Field("MESSAGE", "/message"),
+            ];
+            output.list(&validation.errors, &columns)?;
+            if !validation.errors.is_empty() {
+                bail!("Invalid task");
+            }
+            Ok(())
+        }
+        TaskCommand::Delete { id } => output.item(&client.del_task(*id)?, TASK),
+    }
+}
+
+fn coordinator(
+    coordinator: &Coordinator,
+    output: Format,
+    command: &CoordinatorCommand,
+) -> Result<()> {
+    match command {
+        CoordinatorCommand::Groups => {
+            let columns = [
+                Column::Field("KIND", "/kind"),
+                Column::Field("WORKERS", "/workers"),
+                Column::Field("TASKS", "/tasks"),
+            ];
+            output.list(&coordinator.get("groups")?, &columns)
+        }
+        CoordinatorCommand::Group { kind } => {
+            let group = coordinator.get(&format!("groups/{kind}"))?;
+            if output == Format::Json {
+                return output.item(&group, &[]);
+            }
+            let workers = [
+                Column::Field("WORKER", "/id"),
+                Column::Field("ZONE", "/zone"),
+                Column::Field("VERSION", "/version"),
+                Column::Field("DRAINING", "/draining"),
+                Column::Field("TASKS", "/tasks"),
+            ];
+            output.list(&group["workers"], &workers)?;
+            println!();
+            let tasks = [
+                Column::Field("TASK", "/id"),
+                Column::Field("ENTITY", "/entity"),
+                Column::Field("WORKER", "/worker"),
+                Column::Field("FAILURE", "/failure/reason"),
+                Column::Field("PAUSED", "/paused"),
+                Column::Field("STALE", "/activity/stale"),
+            ];
+            output.list(&group["tasks"], &tasks)
+        }
+        CoordinatorCommand::Failing => {
+            let columns = [
+                Column::Field("KIND", "/kind"),
+                Column::Field("ID", "/id"),
+                Column::Field("ENTITY", "/entity"),
+                Column::Field("REASON", "/failure/reason"),
+                Column::Field("SINCE", "/failure/since"),
+                Column::Field("PAUSED", "/paused"),
+            ];
+            output.list(&coordinator.get("failing")?, &columns)
+        }
+        CoordinatorCommand::Stale => {
+            let columns = [
+                Column::Field("KIND", "/kind"),
+                Column::Field("ID", "/id"),
+                Column::Field("ENTITY", "/entity"),
+                Column::Field("WORKER", "/activity/worker"),
+                Column::Field("LAST_ACTIVITY", "/activity/last_activity"),
+                Column::Field("ERRORS", "/activity/errors"),
+            ];
+            output.list(&coordinator.get("stale")?, &columns)
+        }
+        CoordinatorCommand::Quarantined => {
+            let columns = [
+                Column::Field("KIND", "/kind"),
+                Column::Field("ID", "/id"),
+                Column::Field("ENTITY", "/entity"),
+                Column::Field("WORKER", "/quarantine/worker"),
+                Column::Field("REJECTIONS", "/quarantine/rejections"),
+                Column::Field("UNTIL", "/quarantine/until"),
+            ];
+            output.list(&coordinator.get("quarantined")?, &columns)
+        }
+        CoordinatorCommand::Connections => {
+            let connections = coordinator.get("connections")?;
+            let columns = [
+                Column::Field("KIND", "/kind"),
+                Column::Field("WORKER", "/worker_id"),
+                Column::Field("ADDR", "/addr"),
+                Column::Field("RX_BYTES", "/rx_bytes"),
+                Column::Field("TX_BYTES", "/tx_bytes"),
+            ];
+            match output {
+                Format::Table => output.list(&connections["connections"], &columns),
+                Format::Json => output.item(&connections, &[]),
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    Cli::parse().run().map_err(|error| {
+        let unauthorized = error.downcast_ref::<Error>().is_some_and(|error| {
+            error.matches_api_code(ErrorCode::BadToken)
+                || error.matches_api_code(ErrorCode::MissingToken)
+        });
+        if unauthorized {
+            error.wrap_err("Not logged in, or the session has expired; run `sgctl login`")
+        } else {
+            error
+        }
+    })
+}
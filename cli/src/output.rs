@@ -0,0 +1,156 @@
+//! Output of results, as tables or JSON.
+
+use clap::ValueEnum;
+use eyre::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Format results are printed in.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Selected fields, aligned in columns.
+    #[default]
+    Table,
+    /// Whole results, as pretty printed JSON.
+    Json,
+}
+
+/// Column of a table, taken from each row.
+#[derive(Debug, Copy, Clone)]
+pub enum Column {
+    /// Value at a JSON pointer, e.g. `/meta/group`.
+    Field(&'static str, &'static str),
+    /// Name in its default language, of the `Name` at a JSON pointer.
+    Name(&'static str, &'static str),
+}
+
+impl Column {
+    const fn header(self) -> &'static str {
+        match self {
+            Self::Field(header, _) | Self::Name(header, _) => header,
+        }
+    }
+
+    fn cell(self, row: &Value) -> String {
+        match self {
+            Self::Field(_, pointer) => cell(row.pointer(pointer)),
+            Self::Name(_, pointer) => {
+                let name = row.pointer(pointer);
+                let language = name.and_then(|name| name["default_language"].as_str());
+                let text = name
+                    .zip(language)
+                    .map(|(name, language)| &name["name"][language]);
+                cell(text)
+            }
+        }
+    }
+}
+
+impl Format {
+    /// Print items as rows of a table, or as a JSON array.
+    ///
+    /// # Errors
+    /// Fails if the items can't be serialized.
+    pub fn list(self, items: &impl Serialize, columns: &[Column]) -> Result<()> {
+        let value = serde_json::to_value(items)?;
+        match self {
+            Self::Table => {
+                let rows = value.as_array().map_or(&[][..], Vec::as_slice);
+                print!("{}", table(columns, rows));
+            }
+            Self::Json => println!("{}", serde_json::to_string_pretty(&value)?),
+        }
+        Ok(())
+    }
+
+    /// Print an item as a table of one row, or as a JSON object.
+    ///
+    /// # Errors
+    /// Fails if the item can't be serialized.
+    pub fn item(self, item: &impl Serialize, columns: &[Column]) -> Result<()> {
+        match self {
+            Self::Table => self.list(&[item], columns),
+            Self::Json => {
+                println!("{}", serde_json::to_string_pretty(item)?);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Text of a cell. Strings are printed as is, lists of strings joined, and
+/// other values as JSON.
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(items)) if items.iter().all(Value::is_string) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(","),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Rows aligned in columns, under a header.
+fn table(columns: &[Column], rows: &[Value]) -> String {
+    let header = columns.iter().map(|c| c.header().to_string()).collect();
+    let mut lines: Vec<Vec<String>> = vec![header];
+    lines.extend(
+        rows.iter()
+            .map(|row| columns.iter().map(|c| c.cell(row)).collect()),
+    );
+
+    let mut widths = vec![0; columns.len()];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for line in lines {
+        let text = line
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        output.push_str(text.trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::output::{table, Column};
+
+    #[test]
+    fn must_render_table() {
+        let rows = [
+            json!({
+                "id": "a",
+                "meta": {
+                    "name": { "name": { "en": "Suisei", "ja": "すいせい" }, "default_language": "ja" },
+                    "group": null,
+                },
+                "tasks": ["t1", "t2"],
+            }),
+            json!({ "id": "bb", "meta": {}, "tasks": [], "paused": true }),
+        ];
+        let columns = [
+            Column::Field("ID", "/id"),
+            Column::Name("NAME", "/meta/name"),
+            Column::Field("TASKS", "/tasks"),
+            Column::Field("PAUSED", "/paused"),
+        ];
+        assert_eq!(
+            table(&columns, &rows),
+            "ID  NAME  TASKS  PAUSED\na   すいせい  t1,t2\nbb               true\n"
+        );
+    }
+}
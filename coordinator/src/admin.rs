@@ -0,0 +1,395 @@
+//! Admin HTTP API.
+use std::collections::HashMap;
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    routing::{get, post},
+    Extension,
+    Json,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use sg_core::models::Labels;
+use tower_http::auth::RequireAuthorizationLayer;
+use uuid::Uuid;
+
+use crate::{
+    app::App,
+    connection::ConnectionStat,
+    worker::{Quarantine, TaskActivity, TaskFailure, TaskMove, WorkerGroupImpl},
+};
+
+/// Summary of a worker group.
+#[derive(Debug, Serialize)]
+pub struct GroupSummary {
+    /// Kind of the worker group.
+    pub kind: String,
+    /// Count of connected workers.
+    pub workers: usize,
+    /// Count of tasks.
+    pub tasks: usize,
+}
+
+/// Detail of a worker group.
+#[derive(Debug, Serialize)]
+pub struct GroupDetail {
+    /// Kind of the worker group.
+    pub kind: String,
+    /// Connected workers.
+    pub workers: Vec<WorkerInfo>,
+    /// Tasks and their assignment.
+    pub tasks: Vec<TaskInfo>,
+}
+
+/// State of a worker.
+#[derive(Debug, Serialize)]
+pub struct WorkerInfo {
+    /// Worker ID.
+    pub id: Uuid,
+    /// Zone the worker is in.
+    pub zone: Option<String>,
+    /// Labels of the worker.
+    pub labels: Labels,
+    /// Version of the worker.
+    pub version: String,
+    /// Newest protocol version the worker speaks.
+    pub protocol: u32,
+    /// Protocol version spoken with the worker, negotiated when it joined.
+    pub negotiated_protocol: u32,
+    /// Task kinds the worker supports.
+    pub kinds: Vec<String>,
+    /// Whether the worker is being drained.
+    pub draining: bool,
+    /// Tasks assigned to the worker.
+    pub tasks: Vec<Uuid>,
+}
+
+/// State of a task.
+#[derive(Debug, Serialize)]
+pub struct TaskInfo {
+    /// Task ID.
+    pub id: Uuid,
+    /// Entity the task belongs to.
+    pub entity: Uuid,
+    /// Worker the task is assigned to.
+    pub worker: Option<Uuid>,
+    /// Failure reported by the worker running the task.
+    pub failure: Option<TaskFailure>,
+    /// Liveness in heartbeats of the worker running the task.
+    pub activity: Option<TaskActivity>,
+    /// Whether the task is paused for failing too long.
+    pub paused: bool,
+    /// Quarantine of the task after workers rejected it.
+    pub quarantine: Option<Quarantine>,
+}
+
+/// A task reported failing.
+#[derive(Debug, Serialize)]
+pub struct FailingTask {
+    /// Kind of the task.
+    pub kind: String,
+    /// Task ID.
+    pub id: Uuid,
+    /// Entity the task belongs to.
+    pub entity: Uuid,
+    /// Failure reported by the worker running the task.
+    pub failure: Option<TaskFailure>,
+    /// Whether the task is paused for failing too long.
+    pub paused: bool,
+}
+
+/// A task producing no activity within the SLA of its kind.
+#[derive(Debug, Serialize)]
+pub struct StaleTask {
+    /// Kind of the task.
+    pub kind: String,
+    /// Task ID.
+    pub id: Uuid,
+    /// Entity the task belongs to.
+    pub entity: Uuid,
+    /// Liveness in heartbeats of the worker running the task.
+    pub activity: TaskActivity,
+}
+
+/// A task quarantined after workers rejected it.
+#[derive(Debug, Serialize)]
+pub struct QuarantinedTask {
+    /// Kind of the task.
+    pub kind: String,
+    /// Task ID.
+    pub id: Uuid,
+    /// Entity the task belongs to.
+    pub entity: Uuid,
+    /// Quarantine of the task.
+    pub quarantine: Quarantine,
+}
+
+/// Workers to join a group in a balance preview.
+#[derive(Debug, Default, Deserialize)]
+pub struct PreviewRequest {
+    /// Workers to add, none by default.
+    #[serde(default)]
+    pub workers: Vec<PreviewWorker>,
+}
+
+/// Workers of the same zone and labels to add in a balance preview.
+#[derive(Debug, Deserialize)]
+pub struct PreviewWorker {
+    /// Zone the workers are in.
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Labels of the workers.
+    #[serde(default)]
+    pub labels: Labels,
+    /// Count of the workers, 1 by default.
+    #[serde(default = "one")]
+    pub count: usize,
+}
+
+const fn one() -> usize {
+    1
+}
+
+/// Tasks a balance would move.
+#[derive(Debug, Serialize)]
+pub struct BalancePreview {
+    /// Ids made up for the added workers, which moves refer to.
+    pub added: Vec<Uuid>,
+    /// Tasks that would move, ordered by id.
+    pub moves: Vec<TaskMove>,
+}
+
+/// Live worker connections.
+#[derive(Debug, Serialize)]
+pub struct ConnectionSummary {
+    /// Count of connections per worker kind.
+    pub kinds: HashMap<String, usize>,
+    /// Statistics of each connection.
+    pub connections: Vec<ConnectionStat>,
+}
+
+impl GroupDetail {
+    fn new(kind: String, group: &WorkerGroupImpl) -> Self {
+        let mut assignment: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        let tasks = group
+            .tasks
+            .iter()
+            .map(|(id, bound_task)| {
+                if let Some(worker) = bound_task.worker {
+                    assignment.entry(worker).or_default().push(*id);
+                }
+                TaskInfo {
+                    id: *id,
+                    entity: bound_task.task.entity.into(),
+                    worker: bound_task.worker,
+                    failure: bound_task.failure.clone(),
+                    activity: bound_task.activity.clone(),
+                    paused: bound_task.paused,
+                    quarantine: bound_task.quarantine.clone(),
+                }
+            })
+            .collect();
+        let workers = group
+            .workers
+            .iter()
+            .map(|(id, worker)| WorkerInfo {
+                id: *id,
+                zone: worker.zone.clone(),
+                labels: worker.hello.labels.clone(),
+                version: worker.hello.version.clone(),
+                protocol: worker.hello.protocol,
+                negotiated_protocol: worker.protocol,
+                kinds: worker.hello.kinds.clone(),
+                draining: group.draining.contains(id),
+                tasks: assignment.remove(id).unwrap_or_default(),
+            })
+            .collect();
+        Self {
+            kind,
+            workers,
+            tasks,
+        }
+    }
+}
+
+/// Build the admin router. All routes require the given bearer token.
+pub fn router(app: App, token: &str) -> Router {
+    Router::new()
+        .route("/groups", get(list_groups))
+        .route("/groups/:kind", get(get_group))
+        .route("/groups/:kind/balance", post(balance_group))
+        .route("/groups/:kind/balance/preview", post(preview_balance))
+        .route("/groups/:kind/workers/:id/drain", post(drain_worker))
+        .route("/groups/:kind/tasks/:id/resume", post(resume_task))
+        .route("/failing", get(list_failing))
+        .route("/stale", get(list_stale))
+        .route("/quarantined", get(list_quarantined))
+        .route("/connections", get(list_connections))
+        .layer(Extension(app))
+        .layer(RequireAuthorizationLayer::bearer(token))
+}
+
+async fn list_groups(Extension(app): Extension<App>) -> Json<Vec<GroupSummary>> {
+    let worker_groups = app.worker_groups.lock().await;
+    let mut summaries = Vec::with_capacity(worker_groups.len());
+    for (kind, group) in worker_groups.iter() {
+        let (workers, tasks) = group
+            .with(|group| (group.worker_len(), group.task_len()))
+            .await;
+        summaries.push(GroupSummary {
+            kind: kind.clone(),
+            workers,
+            tasks,
+        });
+    }
+    Json(summaries)
+}
+
+async fn get_group(
+    Path(kind): Path<String>,
+    Extension(app): Extension<App>,
+) -> Result<Json<GroupDetail>, StatusCode> {
+    let worker_groups = app.worker_groups.lock().await;
+    let group = worker_groups.get(&kind).ok_or(StatusCode::NOT_FOUND)?;
+    let detail = group.with(|group| GroupDetail::new(kind, group)).await;
+    Ok(Json(detail))
+}
+
+async fn balance_group(
+    Path(kind): Path<String>,
+    Extension(app): Extension<App>,
+) -> Result<StatusCode, StatusCode> {
+    let worker_groups = app.worker_groups.lock().await;
+    let group = worker_groups.get(&kind).ok_or(StatusCode::NOT_FOUND)?;
+    group.with(|group| group.schedule_balance()).await;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn preview_balance(
+    Path(kind): Path<String>,
+    Extension(app): Extension<App>,
+    Json(req): Json<PreviewRequest>,
+) -> Result<Json<BalancePreview>, StatusCode> {
+    let extra: Vec<_> = req
+        .workers
+        .into_iter()
+        .flat_map(|worker| {
+            (0..worker.count)
+                .map(move |_| (Uuid::new_v4(), worker.zone.clone(), worker.labels.clone()))
+        })
+        .collect();
+    let worker_groups = app.worker_groups.lock().await;
+    let group = worker_groups.get(&kind).ok_or(StatusCode::NOT_FOUND)?;
+    let moves = group.with(|group| group.preview_balance(&extra)).await;
+    Ok(Json(BalancePreview {
+        added: extra.into_iter().map(|(id, ..)| id).collect(),
+        moves,
+    }))
+}
+
+async fn drain_worker(
+    Path((kind, id)): Path<(String, Uuid)>,
+    Extension(app): Extension<App>,
+) -> Result<StatusCode, StatusCode> {
+    let worker_groups = app.worker_groups.lock().await;
+    let group = worker_groups.get(&kind).ok_or(StatusCode::NOT_FOUND)?;
+    group
+        .with(|group| {
+            group
+                .workers
+                .contains_key(&id)
+                .then(|| group.drain_worker(id))
+        })
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn resume_task(
+    Path((kind, id)): Path<(String, Uuid)>,
+    Extension(app): Extension<App>,
+) -> Result<StatusCode, StatusCode> {
+    let worker_groups = app.worker_groups.lock().await;
+    let group = worker_groups.get(&kind).ok_or(StatusCode::NOT_FOUND)?;
+    group
+        .with(|group| group.resume_task(id))
+        .await
+        .then_some(StatusCode::ACCEPTED)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn list_failing(Extension(app): Extension<App>) -> Json<Vec<FailingTask>> {
+    let worker_groups = app.worker_groups.lock().await;
+    let mut failing = Vec::new();
+    for (kind, group) in worker_groups.iter() {
+        group
+            .with(|group| {
+                failing.extend(
+                    group
+                        .tasks
+                        .iter()
+                        .filter(|(_, bound_task)| bound_task.failure.is_some() || bound_task.paused)
+                        .map(|(id, bound_task)| FailingTask {
+                            kind: kind.clone(),
+                            id: *id,
+                            entity: bound_task.task.entity.into(),
+                            failure: bound_task.failure.clone(),
+                            paused: bound_task.paused,
+                        }),
+                );
+            })
+            .await;
+    }
+    Json(failing)
+}
+
+async fn list_stale(Extension(app): Extension<App>) -> Json<Vec<StaleTask>> {
+    let worker_groups = app.worker_groups.lock().await;
+    let mut stale = Vec::new();
+    for (kind, group) in worker_groups.iter() {
+        group
+            .with(|group| {
+                stale.extend(group.tasks.iter().filter_map(|(id, bound_task)| {
+                    let activity = bound_task.activity.as_ref().filter(|activity| {
+                        activity.stale && bound_task.worker == Some(activity.worker)
+                    })?;
+                    Some(StaleTask {
+                        kind: kind.clone(),
+                        id: *id,
+                        entity: bound_task.task.entity.into(),
+                        activity: activity.clone(),
+                    })
+                }));
+            })
+            .await;
+    }
+    Json(stale)
+}
+
+async fn list_quarantined(Extension(app): Extension<App>) -> Json<Vec<QuarantinedTask>> {
+    let worker_groups = app.worker_groups.lock().await;
+    let mut quarantined = Vec::new();
+    for (kind, group) in worker_groups.iter() {
+        group
+            .with(|group| {
+                quarantined.extend(group.tasks.iter().filter_map(|(id, bound_task)| {
+                    Some(QuarantinedTask {
+                        kind: kind.clone(),
+                        id: *id,
+                        entity: bound_task.task.entity.into(),
+                        quarantine: bound_task.quarantine.clone()?,
+                    })
+                }));
+            })
+            .await;
+    }
+    Json(quarantined)
+}
+
+async fn list_connections(Extension(app): Extension<App>) -> Json<ConnectionSummary> {
+    Json(ConnectionSummary {
+        kinds: app.connections.count_by_kind(),
+        connections: app.connections.stats(),
+    })
+}
@@ -2,14 +2,21 @@
 use std::{
     collections::HashMap,
     error::Error,
+    net::SocketAddr,
     ops::Deref,
     result::Result as StdResult,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, RwLock},
+    time::SystemTime,
 };
 
 use eyre::Result;
-use sg_core::models::Task;
+use futures_util::{future, Sink, Stream};
+use sg_core::{
+    adapter::LongPoll,
+    models::Task,
+    protocol::{Hello, HELLO_HEADER, PROTOCOL_HEADER},
+};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::Mutex,
@@ -17,12 +24,17 @@ use tokio::{
 use tokio_tungstenite::tungstenite::{
     handshake::server::{ErrorResponse, Request, Response},
     http::{HeaderMap, StatusCode},
+    Error as WsError,
+    Message,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 use crate::{
+    admin,
     config::Config,
+    connection::{Connections, Metered},
+    poll::{self, Sessions},
     worker::{Worker, WorkerGroup},
 };
 
@@ -42,9 +54,10 @@ impl App {
     /// # Errors
     /// Return error if failed to bind to the given address.
     pub async fn serve(self) -> Result<()> {
-        info!("Listening on {}", self.config.bind);
+        let bind = self.config().bind;
+        info!("Listening on {}", bind);
 
-        let socket = TcpListener::bind(self.config.bind).await?;
+        let socket = TcpListener::bind(bind).await?;
         loop {
             if let Ok((socket, addr)) = socket.accept().await {
                 info!(addr = %addr, "Accepting connection");
@@ -57,6 +70,53 @@ impl App {
             }
         }
     }
+
+    /// Serve the admin HTTP API.
+    ///
+    /// Never returns if admin token is not configured.
+    ///
+    /// # Errors
+    /// Return error if failed to bind to the given address.
+    pub async fn serve_admin(self) -> Result<()> {
+        let config = self.config();
+        let Some(token) = config.admin_token.clone() else {
+            info!("Admin API disabled");
+            return future::pending().await;
+        };
+        info!("Admin API listening on {}", config.admin_bind);
+
+        let bind = config.admin_bind;
+        axum::Server::try_bind(&bind)?
+            .serve(admin::router(self, &token).into_make_service())
+            .await?;
+        Ok(())
+    }
+
+    /// Serve workers joining over HTTP long polling.
+    ///
+    /// Never returns if long polling is not enabled.
+    ///
+    /// # Errors
+    /// Return error if failed to bind to the given address.
+    pub async fn serve_poll(self) -> Result<()> {
+        let config = self.config();
+        let Some(bind) = config.poll_bind else {
+            info!("Long polling disabled");
+            return future::pending().await;
+        };
+        info!("Long polling listening on {}", bind);
+
+        let sessions = Arc::new(Sessions::new(config.poll_timeout));
+        let server = axum::Server::try_bind(&bind)?.serve(
+            poll::router(self, sessions.clone())
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        );
+        tokio::select! {
+            r = server => r?,
+            () = sessions.expire() => {},
+        };
+        Ok(())
+    }
 }
 
 impl Deref for App {
@@ -72,12 +132,17 @@ impl Deref for App {
 pub struct AppImpl {
     /// Worker groups.
     pub worker_groups: Mutex<HashMap<String, WorkerGroup>>,
-    config: Config,
+    /// Live worker connections.
+    pub connections: Arc<Connections>,
+    config: RwLock<Arc<Config>>,
 }
 
 struct WorkerMeta {
     id: Uuid,
     kind: String,
+    zone: Option<String>,
+    hello: Hello,
+    protocol: u32,
 }
 
 impl TryFrom<&HeaderMap> for WorkerMeta {
@@ -95,7 +160,24 @@ impl TryFrom<&HeaderMap> for WorkerMeta {
             .ok_or("missing header: Sg-Worker-Kind")?
             .to_str()?
             .to_string();
-        Ok(Self { id, kind })
+        let zone = headers
+            .get("Sg-Worker-Zone")
+            .map(|zone| zone.to_str().map(ToString::to_string))
+            .transpose()?;
+        let hello: Hello = serde_json::from_str(
+            headers
+                .get(HELLO_HEADER)
+                .ok_or("missing header: Sg-Worker-Hello, the worker may be too old")?
+                .to_str()?,
+        )?;
+        let protocol = hello.check(&kind)?;
+        Ok(Self {
+            id,
+            kind,
+            zone,
+            hello,
+            protocol,
+        })
     }
 }
 
@@ -105,21 +187,102 @@ impl AppImpl {
     pub fn new(config: Config) -> Self {
         Self {
             worker_groups: Default::default(),
-            config,
+            connections: Default::default(),
+            config: RwLock::new(Arc::new(config)),
         }
     }
 
+    /// Current config.
+    ///
+    /// # Panics
+    /// Panic if the lock is poisoned.
+    #[must_use]
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Apply a reloaded config.
+    ///
+    /// Settings of worker groups, i.e. ping intervals, placement, balance
+    /// limits, stickiness, pausing of failing tasks and activity SLAs, take
+    /// effect at once without dropping worker connections, and so do
+    /// connection limits.
+    /// Other fields only take effect after restart.
+    ///
+    /// # Panics
+    /// Panic if the lock is poisoned.
+    pub async fn reload(&self, config: Config) {
+        let worker_groups = self.worker_groups.lock().await;
+        for (kind, group) in worker_groups.iter() {
+            let settings = config.group_settings(kind);
+            group.with(|group| group.apply(settings)).await;
+        }
+        // Swapped while groups are locked, so that no group is created with
+        // the old config meanwhile.
+        *self.config.write().unwrap() = Arc::new(config);
+        drop(worker_groups);
+        info!("Config reloaded");
+    }
+
+    /// Create a worker group of given kind.
+    fn new_group(&self, kind: &str) -> WorkerGroup {
+        WorkerGroup::with_settings(self.config().group_settings(kind))
+    }
+
     /// Add a task to worker group of its kind.
     pub async fn add_task(&self, task: Task) {
         self.worker_groups
             .lock()
             .await
             .entry(task.kind.clone())
-            .or_insert_with(WorkerGroup::new)
+            .or_insert_with(|| self.new_group(&task.kind))
             .with(|group| group.add_task(task))
             .await;
     }
 
+    /// Add a task to worker group of its kind, and backfill its activity since
+    /// given time.
+    pub async fn add_task_with_backfill(&self, task: Task, since: SystemTime) {
+        self.worker_groups
+            .lock()
+            .await
+            .entry(task.kind.clone())
+            .or_insert_with(|| self.new_group(&task.kind))
+            .with(|group| group.add_task_with_backfill(task, since))
+            .await;
+    }
+
+    /// All tasks in worker groups.
+    pub async fn tasks(&self) -> HashMap<Uuid, Task> {
+        let mut tasks = HashMap::new();
+        for group in self.worker_groups.lock().await.values() {
+            group
+                .with(|group| {
+                    tasks.extend(
+                        group
+                            .tasks
+                            .iter()
+                            .map(|(id, bound)| (*id, bound.task.clone())),
+                    );
+                })
+                .await;
+        }
+        tasks
+    }
+
+    /// Find a task in worker groups.
+    pub async fn task(&self, id: Uuid) -> Option<Task> {
+        for group in self.worker_groups.lock().await.values() {
+            if let Some(task) = group
+                .with(|group| group.tasks.get(&id).map(|bound| bound.task.clone()))
+                .await
+            {
+                return Some(task);
+            }
+        }
+        None
+    }
+
     /// Remove a task from worker groups.
     pub async fn remove_task(&self, id: Uuid) {
         for group in self.worker_groups.lock().await.values_mut() {
@@ -127,45 +290,125 @@ impl AppImpl {
         }
     }
 
-    /// Accept a new worker.
+    /// Accept a new worker over websocket.
     ///
     /// # Errors
     /// Forward error if failed to accept websocket connection.
     ///
     /// # Panics
     /// Panic if internal state is poisoned.
+    #[allow(clippy::result_large_err)]
     pub async fn accept_connection(&self, socket: TcpStream) -> Result<()> {
-        // Accept stream and extract metadata from HTTP headers.
-        let (worker_meta, stream) = {
-            let mut worker_meta = None;
+        let addr = socket.peer_addr()?;
+        let config = self.config();
+
+        // Accept stream and extract metadata from HTTP headers. Connection
+        // limits are checked before the upgrade, so that rejected workers get
+        // an HTTP error instead of a websocket to be closed.
+        let (worker_meta, guard, stream) = {
+            let mut accepted = None;
             let stream = tokio_tungstenite::accept_hdr_async(
                 socket,
-                |req: &Request, resp: Response| -> Result<Response, ErrorResponse> {
-                    worker_meta = Some(WorkerMeta::try_from(req.headers()).map_err(|e| {
+                |req: &Request, mut resp: Response| -> Result<Response, ErrorResponse> {
+                    let meta = WorkerMeta::try_from(req.headers()).map_err(|e| {
                         error!("Invalid header: {}", e);
                         let mut resp = ErrorResponse::new(Some(e.to_string()));
                         *resp.status_mut() = StatusCode::BAD_REQUEST;
                         resp
-                    })?);
+                    })?;
+                    let guard = self
+                        .connections
+                        .register(addr, &meta.kind, meta.id, &config)
+                        .map_err(|rejection| {
+                            warn!(worker_id = %meta.id, %addr, ?rejection, "Worker rejected");
+                            let mut resp = ErrorResponse::new(Some(rejection.reason().into()));
+                            *resp.status_mut() = rejection.status();
+                            resp
+                        })?;
+                    resp.headers_mut()
+                        .insert(PROTOCOL_HEADER, meta.protocol.into());
+                    accepted = Some((meta, guard));
                     Ok(resp)
                 },
             )
             .await?;
-            (worker_meta.unwrap(), stream)
+            let (worker_meta, guard) = accepted.unwrap();
+            (worker_meta, guard, stream)
         };
 
-        debug!(worker_id = %worker_meta.id, "Worker accepted");
+        self.add_worker(worker_meta, Metered::new(stream, guard))
+            .await;
+        Ok(())
+    }
+
+    /// Accept a new worker joining over HTTP long polling, with metadata in
+    /// given headers. Return the end of its stream to be pumped by HTTP
+    /// requests, and the protocol version negotiated.
+    ///
+    /// # Errors
+    /// Return the status and reason if the worker is rejected.
+    pub async fn accept_long_poll(
+        &self,
+        addr: SocketAddr,
+        headers: &HeaderMap,
+    ) -> StdResult<(LongPoll, u32), (StatusCode, String)> {
+        let worker_meta = WorkerMeta::try_from(headers).map_err(|e| {
+            error!("Invalid header: {}", e);
+            (StatusCode::BAD_REQUEST, e.to_string())
+        })?;
+
+        let guard = self
+            .connections
+            .register(addr, &worker_meta.kind, worker_meta.id, &self.config())
+            .map_err(|rejection| {
+                warn!(worker_id = %worker_meta.id, %addr, ?rejection, "Worker rejected");
+                (rejection.status(), rejection.reason().to_string())
+            })?;
+        let (local, remote) = LongPoll::pair();
+        let protocol = worker_meta.protocol;
 
-        // Spawn worker and add worker to a worker group.
-        let mut worker_groups = self.worker_groups.lock().await;
-        let worker_group = worker_groups
-            .entry(worker_meta.kind)
-            .or_insert_with(WorkerGroup::new);
-        let worker = Worker::new(worker_meta.id, stream, worker_group.weak(), &self.config);
-        worker_group
-            .with(|worker_group| worker_group.add_worker(worker))
+        self.add_worker(worker_meta, Metered::new(local, guard))
             .await;
+        Ok((remote, protocol))
+    }
 
-        Ok(())
+    /// Spawn an accepted worker and add it to the worker group of its kind.
+    async fn add_worker<S>(&self, worker_meta: WorkerMeta, stream: S)
+    where
+        S: Stream<Item = StdResult<Message, WsError>>
+            + Sink<Message, Error = WsError>
+            + Unpin
+            + Send
+            + 'static,
+    {
+        debug!(worker_id = %worker_meta.id, protocol = worker_meta.protocol, "Worker accepted");
+
+        let (worker, parent) = {
+            let mut worker_groups = self.worker_groups.lock().await;
+            let worker_group = worker_groups
+                .entry(worker_meta.kind.clone())
+                .or_insert_with(|| self.new_group(&worker_meta.kind));
+            let ping_interval = worker_group.with(|group| group.ping_interval()).await;
+            let worker = Worker::new(
+                worker_meta.id,
+                worker_meta.zone,
+                worker_meta.hello,
+                worker_meta.protocol,
+                stream,
+                worker_group.weak(),
+                ping_interval,
+            );
+            (worker, worker_group.weak())
+        };
+
+        // A worker rejoining after a lost connection keeps its tasks, which
+        // are adopted instead of assigned afresh. Query them without holding
+        // any lock.
+        let running = worker.fetch_tasks().await;
+        if let Some(worker_group) = parent.upgrade() {
+            worker_group
+                .with(|worker_group| worker_group.add_worker(worker, &running))
+                .await;
+        }
     }
 }
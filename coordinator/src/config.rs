@@ -1,61 +1,288 @@
 //! Coordinator config.
 
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use eyre::Result;
 use figment::{
-    providers::{Env, Serialized},
+    providers::{Env, Format, Serialized, Toml},
     Figment,
 };
 use serde::{Deserialize, Serialize};
+use sg_core::utils::Redacted;
+
+use crate::{
+    placement::{Placement, Strategy},
+    worker::{
+        BalanceLimits,
+        GroupSettings,
+        DEFAULT_PING_INTERVAL,
+        DEFAULT_QUARANTINE_BACKOFF,
+        DEFAULT_QUARANTINE_MAX_BACKOFF,
+    },
+};
 
 /// Coordinator config.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Config {
+    /// TOML file overriding fields set by environment variables. It's read
+    /// again on SIGHUP, see [`App::reload`](crate::app::AppImpl::reload) for
+    /// fields applied without restart.
+    pub config_file: Option<PathBuf>,
     /// Bind address for coordinator.
     pub bind: SocketAddr,
+    /// Bind address for admin HTTP API.
+    pub admin_bind: SocketAddr,
+    /// Bearer token required by admin HTTP API. Admin API is disabled if not
+    /// set.
+    pub admin_token: Option<Redacted<String>>,
+    /// Bind address for workers joining over HTTP long polling, for networks
+    /// where websocket is blocked. Long polling is disabled if not set.
+    pub poll_bind: Option<SocketAddr>,
+    /// Max time a long polling request is held before responding with no
+    /// message. Sessions not polled for twice this long are closed.
+    #[serde(with = "humantime_serde")]
+    pub poll_timeout: Duration,
+    /// Max count of worker connections.
+    pub max_connections: usize,
+    /// Max count of worker connections from the same IP.
+    pub max_connections_per_ip: usize,
     /// Determine how often coordinator sends ping to workers.
     #[serde(with = "humantime_serde")]
     pub ping_interval: Duration,
+    /// Newly added tasks backfill activity within this window. Backfill is
+    /// disabled if not set.
+    #[serde(with = "humantime_serde")]
+    pub backfill_window: Option<Duration>,
+    /// Zone the coordinator is in.
+    pub zone: Option<String>,
+    /// Strategy to place tasks across worker zones.
+    pub placement: Strategy,
+    /// Max count of RPCs a balance issues to workers at the same time.
+    pub balance_concurrency: usize,
+    /// Max count of tasks a balance adds to or removes from a worker in one
+    /// RPC.
+    pub balance_batch_size: usize,
+    /// Keep tasks on their current worker in a balance while it holds at
+    /// most this many percent more tasks than its fair share, e.g. `20`, so
+    /// that joining workers don't move tasks around needlessly. Tasks always
+    /// move to the worker picked by the ring if not set.
+    pub balance_stickiness: Option<u32>,
+    /// Pause tasks reported failing by workers for this long, until resumed
+    /// by the admin API. Failing tasks are never paused if not set.
+    #[serde(with = "humantime_serde")]
+    pub pause_failing_after: Option<Duration>,
+    /// Flag tasks producing no activity, e.g. polls or messages, for this
+    /// long as stale in the admin API. Tasks are never flagged if not set.
+    #[serde(with = "humantime_serde")]
+    pub activity_sla: Option<Duration>,
+    /// Quarantine tasks rejected by a worker, e.g. for invalid params, for
+    /// this long before retrying them. Doubled on each rejection in a row.
+    #[serde(with = "humantime_serde")]
+    pub quarantine_backoff: Duration,
+    /// Max time a task rejected by workers is quarantined for.
+    #[serde(with = "humantime_serde")]
+    pub quarantine_max_backoff: Duration,
+    /// Overrides for specific worker kinds, e.g.
+    /// `COORDINATOR_KINDS__TWITTER__PING_INTERVAL`.
+    pub kinds: HashMap<String, KindConfig>,
     /// MongoDB connection string.
-    pub mongo_uri: String,
+    pub mongo_uri: Redacted<String>,
     /// MongoDB database name.
     pub mongo_db: String,
     /// MongoDB collection name.
     pub mongo_collection: String,
+    /// MongoDB collection names of more tasks, watched along with
+    /// `mongo_collection`, e.g. to keep tasks of experimental kinds apart.
+    pub mongo_extra_collections: Vec<String>,
+    /// Kinds of tasks to schedule. Tasks of other kinds are left in the
+    /// database, e.g. for staged rollouts of new worker kinds.
+    pub task_kinds: KindFilter,
+    /// MongoDB collection name of the lease coordinators compete for. Only
+    /// the holder serves workers.
+    pub lease_collection: String,
+    /// Time a lease lasts unless renewed. A standby takes over this long after
+    /// the leader fails at most.
+    #[serde(with = "humantime_serde")]
+    pub lease_ttl: Duration,
+}
+
+/// Config overrides for a worker kind. Unset fields fall back to the global
+/// ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct KindConfig {
+    /// Determine how often coordinator sends ping to workers of this kind.
+    #[serde(default, with = "humantime_serde")]
+    pub ping_interval: Option<Duration>,
+    /// Strategy to place tasks of this kind across worker zones.
+    #[serde(default)]
+    pub placement: Option<Strategy>,
+    /// Flag tasks of this kind producing no activity for this long as stale.
+    #[serde(default, with = "humantime_serde")]
+    pub activity_sla: Option<Duration>,
+}
+
+/// Allowlist and denylist of task kinds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct KindFilter {
+    /// Kinds allowed, or every kind if empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Kinds denied, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl KindFilter {
+    /// Whether tasks of given kind pass the filter.
+    #[must_use]
+    pub fn allows(&self, kind: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|allowed| allowed == kind))
+            && !self.deny.iter().any(|denied| denied == kind)
+    }
 }
 
 impl Config {
     /// Load config from environment variables.
     ///
+    /// Nested fields are separated by `__`.
+    ///
     /// # Errors
     /// Returns error if part of the config is invalid.
     pub fn from_env() -> Result<Self> {
-        Ok(Figment::from(Serialized::defaults(Self::default()))
-            .merge(Env::prefixed("COORDINATOR_"))
+        Self::from_env_layered(&["COORDINATOR_"])
+    }
+
+    /// Load config from environment variables of several prefixes, with later
+    /// prefixes taking precedence.
+    ///
+    /// # Errors
+    /// Returns error if part of the config is invalid.
+    pub fn from_env_layered(prefixes: &[&str]) -> Result<Self> {
+        let config: Self = prefixes
+            .iter()
+            .fold(
+                Figment::from(Serialized::defaults(Self::default())),
+                |figment, prefix| figment.merge(Env::prefixed(prefix).split("__")),
+            )
+            .extract()?;
+        config.reloaded()
+    }
+
+    /// Merge the config file, if any, over this config.
+    ///
+    /// Fields removed from the file keep their current values until restart.
+    ///
+    /// # Errors
+    /// Returns error if the file can't be read or part of it is invalid.
+    pub fn reloaded(&self) -> Result<Self> {
+        let Some(path) = &self.config_file else {
+            return Ok(self.clone());
+        };
+        Ok(Figment::from(Serialized::defaults(self))
+            .merge(Toml::file(path))
             .extract()?)
     }
+
+    /// Ping interval of workers of given kind.
+    #[must_use]
+    pub fn ping_interval(&self, kind: &str) -> Duration {
+        self.kinds
+            .get(kind)
+            .and_then(|overrides| overrides.ping_interval)
+            .unwrap_or(self.ping_interval)
+    }
+
+    /// Placement policy of worker group of given kind.
+    #[must_use]
+    pub fn placement(&self, kind: &str) -> Placement {
+        let strategy = self
+            .kinds
+            .get(kind)
+            .and_then(|overrides| overrides.placement)
+            .unwrap_or(self.placement);
+        Placement::new(strategy, self.zone.as_deref())
+    }
+
+    /// SLA of activity of tasks of given kind.
+    #[must_use]
+    pub fn activity_sla(&self, kind: &str) -> Option<Duration> {
+        self.kinds
+            .get(kind)
+            .and_then(|overrides| overrides.activity_sla)
+            .or(self.activity_sla)
+    }
+
+    /// Limits of RPCs issued by a balance.
+    #[must_use]
+    pub const fn balance_limits(&self) -> BalanceLimits {
+        BalanceLimits {
+            concurrency: self.balance_concurrency,
+            batch_size: self.balance_batch_size,
+        }
+    }
+
+    /// Settings of worker group of given kind.
+    #[must_use]
+    pub fn group_settings(&self, kind: &str) -> GroupSettings {
+        GroupSettings {
+            placement: self.placement(kind),
+            limits: self.balance_limits(),
+            ping_interval: self.ping_interval(kind),
+            pause_failing_after: self.pause_failing_after,
+            stickiness: self.balance_stickiness,
+            activity_sla: self.activity_sla(kind),
+            quarantine_backoff: self.quarantine_backoff,
+            quarantine_max_backoff: self.quarantine_max_backoff,
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
+        let balance_limits = BalanceLimits::default();
         Self {
+            config_file: None,
             bind: "127.0.0.1:7000".parse().unwrap(),
-            ping_interval: Duration::from_secs(10),
-            mongo_uri: String::from("mongodb://localhost:27017"),
+            admin_bind: "127.0.0.1:7001".parse().unwrap(),
+            admin_token: None,
+            poll_bind: None,
+            poll_timeout: Duration::from_secs(30),
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            backfill_window: None,
+            zone: None,
+            placement: Strategy::Any,
+            balance_concurrency: balance_limits.concurrency,
+            balance_batch_size: balance_limits.batch_size,
+            balance_stickiness: None,
+            pause_failing_after: None,
+            activity_sla: None,
+            quarantine_backoff: DEFAULT_QUARANTINE_BACKOFF,
+            quarantine_max_backoff: DEFAULT_QUARANTINE_MAX_BACKOFF,
+            kinds: HashMap::new(),
+            mongo_uri: Redacted(String::from("mongodb://localhost:27017")),
             mongo_db: String::from("stargazer-reborn"),
             mongo_collection: String::from("tasks"),
+            mongo_extra_collections: vec![],
+            task_kinds: KindFilter::default(),
+            lease_collection: String::from("coordinator_lease"),
+            lease_ttl: Duration::from_secs(15),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{collections::HashMap, time::Duration};
 
     use figment::Jail;
+    use sg_core::utils::Redacted;
 
-    use crate::config::Config;
+    use crate::{
+        config::{Config, KindConfig, KindFilter},
+        placement::{Placement, Strategy},
+    };
 
     #[test]
     fn must_default() {
@@ -69,21 +296,170 @@ mod tests {
     fn must_from_env() {
         Jail::expect_with(|jail| {
             jail.set_env("COORDINATOR_BIND", "0.0.0.0:8080");
+            jail.set_env("COORDINATOR_ADMIN_BIND", "0.0.0.0:8081");
+            jail.set_env("COORDINATOR_ADMIN_TOKEN", "token");
+            jail.set_env("COORDINATOR_POLL_BIND", "0.0.0.0:8082");
+            jail.set_env("COORDINATOR_POLL_TIMEOUT", "10s");
+            jail.set_env("COORDINATOR_MAX_CONNECTIONS", "16");
+            jail.set_env("COORDINATOR_MAX_CONNECTIONS_PER_IP", "4");
             jail.set_env("COORDINATOR_PING_INTERVAL", "1s");
+            jail.set_env("COORDINATOR_BACKFILL_WINDOW", "1d");
+            jail.set_env("COORDINATOR_ZONE", "ap-east");
+            jail.set_env("COORDINATOR_PLACEMENT", "same_zone");
+            jail.set_env("COORDINATOR_BALANCE_CONCURRENCY", "4");
+            jail.set_env("COORDINATOR_BALANCE_BATCH_SIZE", "32");
+            jail.set_env("COORDINATOR_BALANCE_STICKINESS", "20");
+            jail.set_env("COORDINATOR_PAUSE_FAILING_AFTER", "1d");
+            jail.set_env("COORDINATOR_ACTIVITY_SLA", "1h");
+            jail.set_env("COORDINATOR_QUARANTINE_BACKOFF", "1m");
+            jail.set_env("COORDINATOR_QUARANTINE_MAX_BACKOFF", "1d");
+            jail.set_env("COORDINATOR_KINDS__TWITTER__PING_INTERVAL", "5s");
+            jail.set_env("COORDINATOR_KINDS__TWITTER__ACTIVITY_SLA", "10m");
+            jail.set_env("COORDINATOR_KINDS__BILILIVE__PLACEMENT", "spread");
             jail.set_env("COORDINATOR_MONGO_URI", "mongodb://suichan:27017");
             jail.set_env("COORDINATOR_MONGO_DB", "db");
             jail.set_env("COORDINATOR_MONGO_COLLECTION", "coll");
+            jail.set_env("COORDINATOR_MONGO_EXTRA_COLLECTIONS", "[staging]");
+            jail.set_env("COORDINATOR_TASK_KINDS__ALLOW", "[twitter, youtube]");
+            jail.set_env("COORDINATOR_TASK_KINDS__DENY", "[youtube]");
+            jail.set_env("COORDINATOR_LEASE_COLLECTION", "lease");
+            jail.set_env("COORDINATOR_LEASE_TTL", "30s");
             assert_eq!(
                 Config::from_env().unwrap(),
                 Config {
+                    config_file: None,
                     bind: "0.0.0.0:8080".parse().unwrap(),
+                    admin_bind: "0.0.0.0:8081".parse().unwrap(),
+                    admin_token: Some(Redacted(String::from("token"))),
+                    poll_bind: Some("0.0.0.0:8082".parse().unwrap()),
+                    poll_timeout: Duration::from_secs(10),
+                    max_connections: 16,
+                    max_connections_per_ip: 4,
                     ping_interval: Duration::from_secs(1),
-                    mongo_uri: String::from("mongodb://suichan:27017"),
+                    backfill_window: Some(Duration::from_secs(24 * 60 * 60)),
+                    zone: Some(String::from("ap-east")),
+                    placement: Strategy::SameZone,
+                    balance_concurrency: 4,
+                    balance_batch_size: 32,
+                    balance_stickiness: Some(20),
+                    pause_failing_after: Some(Duration::from_secs(24 * 60 * 60)),
+                    activity_sla: Some(Duration::from_secs(60 * 60)),
+                    quarantine_backoff: Duration::from_secs(60),
+                    quarantine_max_backoff: Duration::from_secs(24 * 60 * 60),
+                    kinds: HashMap::from([
+                        (
+                            String::from("twitter"),
+                            KindConfig {
+                                ping_interval: Some(Duration::from_secs(5)),
+                                placement: None,
+                                activity_sla: Some(Duration::from_secs(10 * 60)),
+                            },
+                        ),
+                        (
+                            String::from("bililive"),
+                            KindConfig {
+                                ping_interval: None,
+                                placement: Some(Strategy::Spread),
+                                activity_sla: None,
+                            },
+                        ),
+                    ]),
+                    mongo_uri: Redacted(String::from("mongodb://suichan:27017")),
                     mongo_db: String::from("db"),
                     mongo_collection: String::from("coll"),
+                    mongo_extra_collections: vec![String::from("staging")],
+                    task_kinds: KindFilter {
+                        allow: vec![String::from("twitter"), String::from("youtube")],
+                        deny: vec![String::from("youtube")],
+                    },
+                    lease_collection: String::from("lease"),
+                    lease_ttl: Duration::from_secs(30),
                 }
             );
             Ok(())
         });
     }
+
+    #[test]
+    fn must_reload_file() {
+        Jail::expect_with(|jail| {
+            jail.set_env("COORDINATOR_CONFIG_FILE", "coordinator.toml");
+            jail.set_env("COORDINATOR_PING_INTERVAL", "1s");
+            jail.set_env("COORDINATOR_BALANCE_CONCURRENCY", "4");
+            jail.create_file(
+                "coordinator.toml",
+                r#"
+                    ping_interval = "5s"
+
+                    [kinds.twitter]
+                    ping_interval = "30s"
+                "#,
+            )?;
+            let config = Config::from_env().unwrap();
+            // The file takes precedence over environment variables.
+            assert_eq!(config.ping_interval, Duration::from_secs(5));
+            assert_eq!(config.ping_interval("twitter"), Duration::from_secs(30));
+            assert_eq!(config.balance_concurrency, 4);
+
+            jail.create_file("coordinator.toml", "balance_batch_size = 32")?;
+            let reloaded = config.reloaded().unwrap();
+            assert_eq!(reloaded.balance_batch_size, 32);
+            // Removed fields keep their values.
+            assert_eq!(reloaded.ping_interval, Duration::from_secs(5));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn must_override_per_kind() {
+        let config = Config {
+            zone: Some(String::from("ap-east")),
+            placement: Strategy::SameZone,
+            activity_sla: Some(Duration::from_secs(60 * 60)),
+            kinds: HashMap::from([(
+                String::from("twitter"),
+                KindConfig {
+                    ping_interval: Some(Duration::from_secs(5)),
+                    placement: Some(Strategy::Spread),
+                    activity_sla: Some(Duration::from_secs(10 * 60)),
+                },
+            )]),
+            ..Default::default()
+        };
+        assert_eq!(config.ping_interval("twitter"), Duration::from_secs(5));
+        assert_eq!(config.placement("twitter"), Placement::Spread);
+        assert_eq!(
+            config.activity_sla("twitter"),
+            Some(Duration::from_secs(10 * 60))
+        );
+        assert_eq!(
+            config.activity_sla("bililive"),
+            Some(Duration::from_secs(60 * 60))
+        );
+        assert_eq!(config.ping_interval("bililive"), Duration::from_secs(10));
+        assert_eq!(
+            config.placement("bililive"),
+            Placement::PreferZone(String::from("ap-east"))
+        );
+    }
+
+    #[test]
+    fn must_filter_kinds() {
+        assert!(KindFilter::default().allows("twitter"));
+
+        let filter = KindFilter {
+            allow: vec![String::from("twitter"), String::from("youtube")],
+            deny: vec![String::from("youtube")],
+        };
+        assert!(filter.allows("twitter"));
+        assert!(!filter.allows("youtube"));
+        assert!(!filter.allows("bililive"));
+
+        let filter = KindFilter {
+            allow: vec![],
+            deny: vec![String::from("bililive")],
+        };
+        assert!(filter.allows("twitter"));
+        assert!(!filter.allows("bililive"));
+    }
 }
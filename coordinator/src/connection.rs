@@ -0,0 +1,252 @@
+//! Worker connection accounting and limits.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::{ready, Sink, SinkExt, Stream, StreamExt};
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::{http::StatusCode, Error as WsError, Message};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// Registry of live worker connections.
+#[derive(Debug, Default)]
+pub struct Connections {
+    inner: Mutex<ConnectionsImpl>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionsImpl {
+    next_id: u64,
+    conns: HashMap<u64, Connection>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    addr: SocketAddr,
+    kind: String,
+    worker_id: Uuid,
+    traffic: Arc<Traffic>,
+}
+
+/// Traffic counters of a connection.
+#[derive(Debug, Default)]
+struct Traffic {
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+/// Reason to reject a connection.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Rejection {
+    /// Total connection limit exceeded.
+    TooManyConnections,
+    /// Connection limit of the remote IP exceeded.
+    TooManyConnectionsFromIp,
+}
+
+impl Rejection {
+    /// Reason given to the rejected worker.
+    #[must_use]
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Self::TooManyConnections => "Too many connections",
+            Self::TooManyConnectionsFromIp => "Too many connections from this address",
+        }
+    }
+
+    /// Status responded to the rejected worker, instead of upgrading to
+    /// websocket or joining over long polling.
+    #[must_use]
+    pub const fn status(self) -> StatusCode {
+        match self {
+            Self::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyConnectionsFromIp => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+/// Snapshot of a live connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStat {
+    /// Remote address.
+    pub addr: SocketAddr,
+    /// Kind of the worker.
+    pub kind: String,
+    /// ID of the worker.
+    pub worker_id: Uuid,
+    /// Bytes received from the worker.
+    pub rx_bytes: u64,
+    /// Bytes sent to the worker.
+    pub tx_bytes: u64,
+}
+
+impl Connections {
+    /// Register a new connection if limits in config allow.
+    ///
+    /// The connection is unregistered when the returned guard is dropped.
+    ///
+    /// # Errors
+    /// Return the reason if the connection should be rejected.
+    ///
+    /// # Panics
+    /// Panic if the registry is poisoned.
+    pub fn register(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        kind: &str,
+        worker_id: Uuid,
+        config: &Config,
+    ) -> Result<ConnectionGuard, Rejection> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.conns.len() >= config.max_connections {
+            return Err(Rejection::TooManyConnections);
+        }
+        let conns_from_ip = inner
+            .conns
+            .values()
+            .filter(|conn| conn.addr.ip() == addr.ip())
+            .count();
+        if conns_from_ip >= config.max_connections_per_ip {
+            return Err(Rejection::TooManyConnectionsFromIp);
+        }
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let traffic = Arc::new(Traffic::default());
+        inner.conns.insert(
+            id,
+            Connection {
+                addr,
+                kind: kind.to_string(),
+                worker_id,
+                traffic: traffic.clone(),
+            },
+        );
+
+        Ok(ConnectionGuard {
+            id,
+            registry: self.clone(),
+            traffic,
+        })
+    }
+
+    /// Statistics of all live connections.
+    ///
+    /// # Panics
+    /// Panic if the registry is poisoned.
+    #[must_use]
+    pub fn stats(&self) -> Vec<ConnectionStat> {
+        self.inner
+            .lock()
+            .unwrap()
+            .conns
+            .values()
+            .map(|conn| ConnectionStat {
+                addr: conn.addr,
+                kind: conn.kind.clone(),
+                worker_id: conn.worker_id,
+                rx_bytes: conn.traffic.rx_bytes.load(Ordering::Relaxed),
+                tx_bytes: conn.traffic.tx_bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Count of live connections per worker kind.
+    ///
+    /// # Panics
+    /// Panic if the registry is poisoned.
+    #[must_use]
+    pub fn count_by_kind(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for conn in self.inner.lock().unwrap().conns.values() {
+            *counts.entry(conn.kind.clone()).or_default() += 1;
+        }
+        counts
+    }
+}
+
+/// A registered connection. Unregister the connection when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    id: u64,
+    registry: Arc<Connections>,
+    traffic: Arc<Traffic>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.registry.inner.lock() {
+            inner.conns.remove(&self.id);
+        }
+    }
+}
+
+/// A websocket stream that accounts its traffic to a registered connection.
+#[derive(Debug)]
+pub struct Metered<S> {
+    inner: S,
+    guard: ConnectionGuard,
+}
+
+impl<S> Metered<S> {
+    /// Wrap a stream.
+    pub const fn new(inner: S, guard: ConnectionGuard) -> Self {
+        Self { inner, guard }
+    }
+}
+
+impl<S> Stream for Metered<S>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.inner.poll_next_unpin(cx));
+        if let Some(Ok(msg)) = &item {
+            self.guard
+                .traffic
+                .rx_bytes
+                .fetch_add(msg.len() as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl<S> Sink<Message> for Metered<S>
+where
+    S: Sink<Message, Error = WsError> + Unpin,
+{
+    type Error = WsError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.guard
+            .traffic
+            .tx_bytes
+            .fetch_add(item.len() as u64, Ordering::Relaxed);
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
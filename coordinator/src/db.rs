@@ -1,28 +1,93 @@
 //! Database access.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
 
-use eyre::Result;
+use eyre::{Result, WrapErr};
 use futures_util::StreamExt;
 use mongodb::{
     bson,
-    bson::oid::ObjectId,
-    change_stream::event::OperationType,
+    bson::{doc, oid::ObjectId, Document},
+    change_stream::{
+        event::{ChangeStreamEvent, OperationType, ResumeToken},
+        ChangeStream,
+    },
     options::{ChangeStreamOptions, FullDocumentType},
     Client,
     Collection,
+    Database,
+};
+use sg_core::{
+    change_events::{Checkpoint, CheckpointStore, MongoCheckpointStore},
+    models::{InDB, Task},
 };
-use sg_core::models::{InDB, Task};
-use tracing::{error, info};
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::{App, Config};
+use crate::{config::KindFilter, App, Config};
+
+/// Delay before reopening a broken change stream.
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
 
 /// Database instance.
+///
+/// Tasks are watched in `mongo_collection` and `mongo_extra_collections`,
+/// with one change stream on the database. Only tasks of kinds passing
+/// `task_kinds` are scheduled.
 pub struct DB {
     app: App,
-    collection: Collection<InDB<Task>>,
+    db: Database,
+    collections: Vec<Collection<InDB<Task>>>,
+    kinds: KindFilter,
     oid_map: HashMap<ObjectId, Uuid>,
+    backfill_window: Option<Duration>,
+    /// Position of the last change applied, to resume the change stream from
+    /// after it breaks.
+    resume_token: Option<ResumeToken>,
+    /// Where `resume_token` is saved, so that the change stream is resumed
+    /// after a restart or by the next leader instead of reconciling tasks.
+    checkpoints: MongoCheckpointStore,
+}
+
+/// Changes that bring tasks in memory in line with those in the database.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TaskDelta {
+    /// Tasks only in the database.
+    pub added: Vec<Task>,
+    /// Tasks changed in the database.
+    pub updated: Vec<Task>,
+    /// Tasks no longer in the database.
+    pub removed: Vec<Uuid>,
+}
+
+impl TaskDelta {
+    /// Compute the changes from `current` tasks to `desired` ones.
+    #[must_use]
+    pub fn between(current: &HashMap<Uuid, Task>, desired: &HashMap<Uuid, Task>) -> Self {
+        let mut delta = Self::default();
+        for (id, task) in desired {
+            match current.get(id) {
+                None => delta.added.push(task.clone()),
+                Some(old) if old != task => delta.updated.push(task.clone()),
+                Some(_) => {}
+            }
+        }
+        delta.removed = current
+            .keys()
+            .filter(|id| !desired.contains_key(id))
+            .copied()
+            .collect();
+        delta
+    }
+
+    /// Whether there's no change.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
 }
 
 impl DB {
@@ -31,14 +96,27 @@ impl DB {
     /// # Errors
     /// Returns an error if the database connection fails.
     pub async fn new(app: App, config: Config) -> Result<Self> {
-        let client = Client::with_uri_str(config.mongo_uri).await?;
+        let client = Client::with_uri_str(&*config.mongo_uri)
+            .await
+            .wrap_err_with(|| format!("Failed to connect to MongoDB at {}", config.mongo_uri))?;
         let db = client.database(&config.mongo_db);
-        let collection = db.collection(&config.mongo_collection);
+        let collections = std::iter::once(&config.mongo_collection)
+            .chain(&config.mongo_extra_collections)
+            .map(|name| db.collection(name))
+            .collect();
+        // Shared by coordinators watching the same tasks.
+        let checkpoints =
+            MongoCheckpointStore::new(&db, format!("coordinator:{}", config.mongo_collection));
 
         Ok(Self {
             app,
-            collection,
+            db,
+            collections,
+            kinds: config.task_kinds,
             oid_map: HashMap::new(),
+            backfill_window: config.backfill_window,
+            resume_token: None,
+            checkpoints,
         })
     }
 
@@ -47,95 +125,267 @@ impl DB {
     /// # Errors
     /// Returns an error if the database query fails.
     pub async fn init_tasks(&mut self) -> Result<()> {
-        let mut count = 0;
-        let mut tasks = self.collection.find(None, None).await?;
-
-        while let Some(task) = tasks.next().await {
-            let task = task?;
+        let delta = self.reconcile(false).await?;
 
-            self.oid_map.insert(task.id(), task.id.into());
-            self.app.add_task(task.inner()).await;
+        info!("{} task(s) loaded from database", delta.added.len());
+        Ok(())
+    }
 
-            count += 1;
+    /// Diff tasks in the database against those in memory, and apply the
+    /// changes. Tasks only in the database are backfilled if `backfill` is
+    /// set.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub async fn reconcile(&mut self, backfill: bool) -> Result<TaskDelta> {
+        let mut oid_map = HashMap::new();
+        let mut desired = HashMap::new();
+        for collection in &self.collections {
+            let mut tasks = collection.find(self.kind_query(), None).await?;
+            while let Some(task) = tasks.next().await {
+                let task = task?;
+                oid_map.insert(task.id(), task.id.into());
+                desired.insert(task.id.into(), task.inner());
+            }
         }
+        self.oid_map = oid_map;
 
-        info!("{} task(s) loaded from database", count);
-        Ok(())
+        let delta = TaskDelta::between(&self.app.tasks().await, &desired);
+        for id in &delta.removed {
+            self.app.remove_task(*id).await;
+        }
+        for task in &delta.updated {
+            self.app.remove_task(task.id.into()).await;
+            self.app.add_task(task.clone()).await;
+        }
+        for task in &delta.added {
+            if backfill {
+                self.add_new_task(task.clone()).await;
+            } else {
+                self.app.add_task(task.clone()).await;
+            }
+        }
+        Ok(delta)
     }
 
     /// Watch for changes in the database, and add/remove tasks as necessary.
     ///
+    /// The change stream is resumed after the last applied change if it
+    /// breaks. If it can't be resumed, e.g. it's invalidated or the change is
+    /// no longer in the oplog, tasks are reconciled with the database instead.
+    ///
     /// # Errors
-    /// Returns an error if the database query fails.
+    /// Never returns unless the future is dropped.
     pub async fn watch_tasks(&mut self) -> Result<()> {
-        let mut changes = self
-            .collection
-            .watch(
-                None,
-                ChangeStreamOptions::builder()
-                    .full_document(Some(FullDocumentType::UpdateLookup))
-                    .build(),
-            )
-            .await?;
-
-        info!("Watching database for task changes");
-
-        while let Some(event) = changes.next().await {
-            let event = event?;
-            match event.operation_type {
-                OperationType::Insert => {
-                    let task = event
-                        .full_document
-                        .expect("Full document must be available");
+        loop {
+            let mut changes = match self.open_change_stream().await {
+                Ok(changes) => changes,
+                Err(error) => {
+                    error!(?error, "Failed to watch database for task changes");
+                    sleep(REOPEN_DELAY).await;
+                    continue;
+                }
+            };
+            info!("Watching database for task changes");
 
-                    info!(task_id = %task.id, "Task added");
+            while let Some(event) = changes.next().await {
+                match event {
+                    Ok(event) => {
+                        let invalidated = self.apply_change(event).await;
+                        self.resume_token = if invalidated {
+                            None
+                        } else {
+                            changes.resume_token()
+                        };
+                        self.save_resume_token().await;
+                        if invalidated {
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        error!(?error, "Change stream broken");
+                        break;
+                    }
+                }
+            }
+
+            sleep(REOPEN_DELAY).await;
+        }
+    }
+
+    /// Query of tasks of kinds passing the filter.
+    fn kind_query(&self) -> Document {
+        let mut kind = Document::new();
+        if !self.kinds.allow.is_empty() {
+            kind.insert("$in", self.kinds.allow.clone());
+        }
+        if !self.kinds.deny.is_empty() {
+            kind.insert("$nin", self.kinds.deny.clone());
+        }
+        if kind.is_empty() {
+            doc! {}
+        } else {
+            doc! { "kind": kind }
+        }
+    }
+
+    /// Open a change stream of the task collections on the database.
+    ///
+    /// Changes aren't filtered by kind in the stream, since a task updated
+    /// to a kind not scheduled must still be removed.
+    async fn watch(
+        &self,
+        start_after: Option<ResumeToken>,
+    ) -> Result<ChangeStream<ChangeStreamEvent<InDB<Task>>>> {
+        let names: Vec<_> = self.collections.iter().map(Collection::name).collect();
+        let pipeline = [doc! { "$match": { "ns.coll": { "$in": names } } }];
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .start_after(start_after)
+            .build();
+        Ok(self.db.watch(pipeline, options).await?.with_type())
+    }
+
+    /// Load the saved position of the change stream, if any.
+    pub(crate) async fn load_resume_token(&self) -> Option<ResumeToken> {
+        match self.checkpoints.load().await {
+            Ok(checkpoint) => checkpoint.and_then(|checkpoint| checkpoint.resume_token),
+            Err(error) => {
+                warn!(?error, "Failed to load resume token");
+                None
+            }
+        }
+    }
 
-                    self.oid_map.insert(task.id(), task.id.into());
-                    self.app.add_task(task.inner()).await;
+    /// Save the position of the change stream. Tasks aren't saved, since
+    /// they're loaded from the database on start anyway.
+    async fn save_resume_token(&self) {
+        let checkpoint = Checkpoint {
+            resume_token: self.resume_token.clone(),
+            tasks: vec![],
+        };
+        if let Err(error) = self.checkpoints.save(&checkpoint).await {
+            warn!(?error, "Failed to save resume token");
+        }
+    }
+
+    /// Open a change stream, resumed after the last applied change if possible,
+    /// including one saved before a restart. Otherwise reconcile tasks after
+    /// opening a new one, so that no change is missed in between.
+    async fn open_change_stream(&mut self) -> Result<ChangeStream<ChangeStreamEvent<InDB<Task>>>> {
+        if self.resume_token.is_none() {
+            self.resume_token = self.load_resume_token().await;
+        }
+        if let Some(token) = self.resume_token.take() {
+            match self.watch(Some(token)).await {
+                Ok(changes) => {
+                    self.resume_token = changes.resume_token();
+                    return Ok(changes);
                 }
-                OperationType::Update => {
-                    let task = event
-                        .full_document
-                        .expect("Full document must be available");
+                Err(error) => warn!(?error, "Failed to resume change stream"),
+            }
+        }
 
-                    info!(task_id = %task.id, "Task updated");
+        let changes = self.watch(None).await?;
+        let delta = self.reconcile(true).await?;
+        if !delta.is_empty() {
+            info!(
+                added = delta.added.len(),
+                updated = delta.updated.len(),
+                removed = delta.removed.len(),
+                "Tasks reconciled with database"
+            );
+        }
+        self.resume_token = changes.resume_token();
+        self.save_resume_token().await;
+        Ok(changes)
+    }
 
-                    self.app.remove_task(task.id.into()).await;
-                    self.app.add_task(task.inner()).await;
+    /// Apply a change to tasks. Changes already applied, e.g. by
+    /// reconciliation, are skipped. Return whether the stream is invalidated,
+    /// or a task collection is dropped or renamed, so that tasks must be
+    /// reconciled.
+    async fn apply_change(&mut self, event: ChangeStreamEvent<InDB<Task>>) -> bool {
+        match event.operation_type {
+            OperationType::Insert => {
+                let task = event
+                    .full_document
+                    .expect("Full document must be available");
+                if !self.kinds.allows(&task.kind) {
+                    debug!(task_id = %task.id, kind = %task.kind, "Unscheduled task skipped");
+                    return false;
                 }
-                OperationType::Replace => {
-                    let task = event
-                        .full_document
-                        .expect("Full document must be available");
 
-                    info!(task_id = %task.id, "Task updated");
+                self.oid_map.insert(task.id(), task.id.into());
+                let task = task.inner();
+                if self.app.task(task.id.into()).await.as_ref() != Some(&task) {
+                    info!(task_id = %task.id, "Task added");
 
                     self.app.remove_task(task.id.into()).await;
-                    self.app.add_task(task.inner()).await;
+                    self.add_new_task(task).await;
                 }
-                OperationType::Delete => {
-                    let task: InDB<()> = bson::from_document(
-                        event.document_key.expect("DocumentKey must be available"),
-                    )
-                    .expect("_id must be available");
-
+            }
+            OperationType::Update | OperationType::Replace => {
+                let Some(task) = event.full_document else {
+                    // Deleted before the lookup, and removed on the delete event.
+                    return false;
+                };
+                if !self.kinds.allows(&task.kind) {
+                    // The kind may have changed from one that is scheduled.
                     if let Some(id) = self.oid_map.remove(&task.id()) {
-                        info!(task_id = %id, "Task removed");
+                        info!(task_id = %id, kind = %task.kind, "Unscheduled task removed");
 
                         self.app.remove_task(id).await;
-                    } else {
-                        error!("Task not found in oid map: {:?}.", task.id());
                     }
+                    return false;
                 }
-                OperationType::Invalidate => {
-                    error!("Change stream invalidated.");
+
+                self.oid_map.insert(task.id(), task.id.into());
+                let task = task.inner();
+                if self.app.task(task.id.into()).await.as_ref() != Some(&task) {
+                    info!(task_id = %task.id, "Task updated");
+
+                    self.app.remove_task(task.id.into()).await;
+                    self.app.add_task(task).await;
                 }
-                ty => {
-                    error!("Unexpected event type: {:?}", ty);
+            }
+            OperationType::Delete => {
+                let task: InDB<()> =
+                    bson::from_document(event.document_key.expect("DocumentKey must be available"))
+                        .expect("_id must be available");
+
+                if let Some(id) = self.oid_map.remove(&task.id()) {
+                    info!(task_id = %id, "Task removed");
+
+                    self.app.remove_task(id).await;
+                } else {
+                    // E.g. of a kind not scheduled.
+                    debug!("Task not found in oid map: {:?}.", task.id());
                 }
             }
+            OperationType::Invalidate => {
+                error!("Change stream invalidated.");
+                return true;
+            }
+            OperationType::Drop | OperationType::Rename | OperationType::DropDatabase => {
+                warn!("Task collection dropped or renamed.");
+                return true;
+            }
+            ty => {
+                error!("Unexpected event type: {:?}", ty);
+            }
         }
+        false
+    }
 
-        Ok(())
+    /// Add a task created after the coordinator started, backfilling its
+    /// activity if enabled.
+    async fn add_new_task(&self, task: Task) {
+        if let Some(window) = self.backfill_window {
+            let since = SystemTime::now() - window;
+            self.app.add_task_with_backfill(task, since).await;
+        } else {
+            self.app.add_task(task).await;
+        }
     }
 }
@@ -0,0 +1,273 @@
+//! Leader election among coordinators.
+//!
+//! Coordinators sharing a database compete for a lease stored in it. The
+//! holder serves workers and renews the lease every third of its ttl, while
+//! the others stand by and take over once it expires. The holder steps down
+//! once a renewal is due that may not land before the lease expires, so that
+//! it never serves alongside the one taking over.
+
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use eyre::{bail, eyre, Result, WrapErr};
+use mongodb::{
+    bson::{doc, DateTime},
+    error::{Error as MongoError, ErrorKind, WriteFailure},
+    options::FindOneAndUpdateOptions,
+    Client,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// Id of the lease document.
+const LEASE_ID: &str = "coordinator";
+
+/// Error code of MongoDB on duplicate keys.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Lease document.
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    #[serde(rename = "_id")]
+    id: String,
+    holder: String,
+    expires_at: DateTime,
+}
+
+/// Candidate for the leader of coordinators.
+#[derive(Debug, Clone)]
+pub struct Election {
+    collection: Collection<Lease>,
+    candidate: Uuid,
+    ttl: Duration,
+}
+
+impl Election {
+    /// Create a candidate with a random id.
+    ///
+    /// # Errors
+    /// Returns an error if the database connection fails.
+    pub async fn new(config: &Config) -> Result<Self> {
+        let client = Client::with_uri_str(&*config.mongo_uri)
+            .await
+            .wrap_err_with(|| format!("Failed to connect to MongoDB at {}", config.mongo_uri))?;
+        let collection = client
+            .database(&config.mongo_db)
+            .collection(&config.lease_collection);
+        Ok(Self::with_collection(collection, config.lease_ttl))
+    }
+
+    fn with_collection(collection: Collection<Lease>, ttl: Duration) -> Self {
+        Self {
+            collection,
+            candidate: Uuid::new_v4(),
+            ttl,
+        }
+    }
+
+    /// Id of the candidate.
+    #[must_use]
+    pub const fn candidate(&self) -> Uuid {
+        self.candidate
+    }
+
+    /// Take the lease if it's free or expired, or renew it if already held.
+    /// Return whether the lease is held afterwards.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub async fn try_acquire(&self) -> Result<bool> {
+        let now = SystemTime::now();
+        let holder = self.candidate.to_string();
+        let result = self
+            .collection
+            .find_one_and_update(
+                doc! {
+                    "_id": LEASE_ID,
+                    "$or": [
+                        { "holder": &holder },
+                        { "expires_at": { "$lte": DateTime::from_system_time(now) } },
+                    ],
+                },
+                doc! {
+                    "$set": {
+                        "holder": &holder,
+                        "expires_at": DateTime::from_system_time(now + self.ttl),
+                    },
+                },
+                FindOneAndUpdateOptions::builder().upsert(true).build(),
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            // Held by another candidate, so the upsert conflicts with it.
+            Err(error) if is_duplicate_key(&error) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Wait until the lease is acquired.
+    pub async fn acquire(&self) {
+        let mut standby = false;
+        loop {
+            match self.try_acquire().await {
+                Ok(true) => return,
+                Ok(false) if !standby => {
+                    info!(
+                        candidate = %self.candidate,
+                        "Lease held by another coordinator, standing by"
+                    );
+                    standby = true;
+                }
+                Ok(false) => debug!("Lease still held by another coordinator"),
+                Err(error) => warn!(?error, "Failed to acquire lease"),
+            }
+            sleep(self.ttl / 3).await;
+        }
+    }
+
+    /// Keep renewing the lease.
+    ///
+    /// # Errors
+    /// Returns an error once the lease is lost, either taken over by another
+    /// candidate or not renewed a renewal interval before it expires.
+    pub async fn hold(&self) -> Result<()> {
+        hold_with(self.ttl, || self.try_acquire()).await
+    }
+
+    /// Give up the lease if held, so that a standby takes over at once.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub async fn release(&self) -> Result<()> {
+        self.collection
+            .delete_one(
+                doc! { "_id": LEASE_ID, "holder": self.candidate.to_string() },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Renew a lease of `ttl` with `renew` every third of it, until it's lost.
+/// Renewals failing or hanging are retried as long as the last successful one
+/// is more than a renewal interval from expiring.
+async fn hold_with<F, Fut>(ttl: Duration, mut renew: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let interval = ttl / 3;
+    let step_down = ttl - interval;
+    let mut renewed = Instant::now();
+    loop {
+        sleep(interval).await;
+        let left = step_down.saturating_sub(renewed.elapsed());
+        let result = timeout(left, renew()).await;
+        match result.unwrap_or_else(|_| Err(eyre!("Renewal timed out"))) {
+            Ok(true) => renewed = Instant::now(),
+            Ok(false) => bail!("Lease taken over by another coordinator"),
+            Err(error) if renewed.elapsed() >= step_down => {
+                return Err(error).wrap_err("Failed to renew lease before it expires");
+            }
+            Err(error) => warn!(?error, "Failed to renew lease"),
+        }
+    }
+}
+
+fn is_duplicate_key(error: &MongoError) -> bool {
+    match &*error.kind {
+        ErrorKind::Command(error) => error.code == DUPLICATE_KEY,
+        ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, time::Duration};
+
+    use eyre::eyre;
+    use mongodb::Client;
+    use tokio::time::{sleep, Instant};
+
+    use crate::election::{hold_with, Election};
+
+    #[tokio::test]
+    async fn must_elect_one_leader() {
+        let client = Client::with_uri_str("mongodb://localhost:27017/")
+            .await
+            .unwrap();
+        let collection = client.database("test").collection("coordinator_lease");
+        collection.drop(None).await.unwrap();
+
+        let ttl = Duration::from_millis(500);
+        let a = Election::with_collection(collection.clone(), ttl);
+        let b = Election::with_collection(collection, ttl);
+
+        assert!(a.try_acquire().await.unwrap());
+        assert!(!b.try_acquire().await.unwrap());
+        // Renewed by the holder.
+        assert!(a.try_acquire().await.unwrap());
+
+        // Released leases are free at once.
+        a.release().await.unwrap();
+        assert!(b.try_acquire().await.unwrap());
+        assert!(!a.try_acquire().await.unwrap());
+
+        // Expired leases are taken over.
+        sleep(ttl).await;
+        assert!(a.try_acquire().await.unwrap());
+        assert!(!b.try_acquire().await.unwrap());
+        assert!(b.hold().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn must_step_down_before_expiry() {
+        let ttl = Duration::from_secs(30);
+
+        // Renewed once at 10s, so the lease expires at 40s.
+        let start = Instant::now();
+        let mut renewals = 0;
+        let result = hold_with(ttl, || {
+            renewals += 1;
+            let renewed = renewals == 1;
+            async move {
+                if renewed {
+                    Ok(true)
+                } else {
+                    Err(eyre!("Database unavailable"))
+                }
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(renewals, 3);
+        assert!(start.elapsed() <= Duration::from_secs(30));
+
+        // Renewals hanging are given up on as well.
+        let start = Instant::now();
+        let mut renewals = 0;
+        let result = hold_with(ttl, || {
+            renewals += 1;
+            let renewed = renewals == 1;
+            async move {
+                if !renewed {
+                    future::pending::<()>().await;
+                }
+                Ok(true)
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert!(start.elapsed() <= Duration::from_secs(30));
+    }
+}
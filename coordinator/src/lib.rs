@@ -0,0 +1,67 @@
+//! Coordinator of workers.
+#![allow(
+    clippy::module_name_repetitions,
+    clippy::default_trait_access,
+    clippy::redundant_pub_crate
+)]
+#![deny(missing_docs)]
+
+use eyre::Result;
+use sg_core::utils::Shutdown;
+use tracing::info;
+
+use crate::{app::App, config::Config, db::DB, election::Election, reload::reload_on_hangup};
+
+pub mod admin;
+pub mod app;
+pub mod config;
+pub mod connection;
+pub mod db;
+pub mod election;
+pub mod placement;
+pub mod poll;
+pub mod reload;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod worker;
+
+#[cfg(test)]
+mod tests;
+
+/// Run the coordinator until any of its services fails or it's shut down.
+///
+/// The coordinator stands by until it's elected as the leader, and only then
+/// loads tasks and serves workers.
+///
+/// # Errors
+/// Returns error if the database is unreachable, any service fails or the
+/// leadership is lost.
+pub async fn run(config: Config, shutdown: Shutdown) -> Result<()> {
+    let election = Election::new(&config).await?;
+    shutdown.on_shutdown("coordinator: lease", {
+        let election = election.clone();
+        move || async move { election.release().await }
+    });
+
+    let serve = async {
+        election.acquire().await;
+        info!(candidate = %election.candidate(), "Elected as leader");
+
+        let app = App::new(config.clone());
+        let mut db = DB::new(app.clone(), config.clone()).await?;
+
+        db.init_tasks().await?;
+
+        tokio::select! {
+            r = app.clone().serve_admin() => r,
+            r = app.clone().serve_poll() => r,
+            r = reload_on_hangup(app.clone(), config) => r,
+            r = app.serve() => r,
+            r = db.watch_tasks() => r,
+            r = election.hold() => r,
+        }
+    };
+    shutdown.until(serve).await.transpose()?;
+
+    Ok(())
+}
@@ -1,42 +1,18 @@
 //! Coordinator binary.
-#![allow(
-    clippy::module_name_repetitions,
-    clippy::default_trait_access,
-    clippy::redundant_pub_crate
-)]
-#![deny(missing_docs)]
 
+use coordinator::config::Config;
 use eyre::Result;
-use tracing::level_filters::LevelFilter;
-
-use crate::{app::App, config::Config, db::DB};
-
-pub mod app;
-pub mod config;
-pub mod db;
-pub mod worker;
-
-#[cfg(test)]
-mod tests;
+use sg_core::utils::{init_tracing, Shutdown};
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::DEBUG)
-        .init();
-
-    let config = Config::from_env()?;
-
-    let app = App::new(config.clone());
-    let mut db = DB::new(app.clone(), config).await?;
-
-    db.init_tasks().await?;
-
-    tokio::select! {
-        r = app.serve() => r?,
-        r = db.watch_tasks() => r?,
-    };
+    let _guard = init_tracing("coordinator", EnvFilter::new("debug"))?;
 
-    Ok(())
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
+    let result = coordinator::run(Config::from_env()?, shutdown.clone()).await;
+    shutdown.run_hooks().await;
+    result
 }
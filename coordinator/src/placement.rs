@@ -0,0 +1,306 @@
+//! Zone-aware task placement.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{Debug, Formatter},
+    hash::{Hash, Hasher},
+};
+
+use consistent_hash_ring::Ring;
+use serde::{Deserialize, Serialize};
+use sg_core::models::Labels;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Strategy to place tasks across zones.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Ignore zones.
+    #[default]
+    Any,
+    /// Prefer workers in the same zone as the coordinator, falling back to
+    /// other zones if there's none.
+    SameZone,
+    /// Spread tasks evenly across zones.
+    Spread,
+}
+
+/// Placement policy of a worker group.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub enum Placement {
+    /// Ignore zones.
+    #[default]
+    Any,
+    /// Prefer workers in the given zone.
+    PreferZone(String),
+    /// Spread tasks evenly across zones.
+    Spread,
+}
+
+impl Placement {
+    /// Placement policy of given strategy in the zone of the coordinator.
+    #[must_use]
+    pub fn new(strategy: Strategy, zone: Option<&str>) -> Self {
+        match (strategy, zone) {
+            (Strategy::SameZone, Some(zone)) => Self::PreferZone(zone.to_string()),
+            (Strategy::Spread, _) => Self::Spread,
+            _ => Self::Any,
+        }
+    }
+}
+
+/// Consistent hash rings of available workers, both as a whole and per zone.
+#[derive(Default)]
+pub struct Rings {
+    placement: Placement,
+    all: Ring</* worker */ Uuid>,
+    zones: HashMap<Option<String>, Ring<Uuid>>,
+    zone_of: HashMap<Uuid, Option<String>>,
+    labels_of: HashMap<Uuid, Labels>,
+}
+
+impl Debug for Rings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rings")
+            .field("placement", &self.placement)
+            .field("zone_of", &self.zone_of)
+            .field("labels_of", &self.labels_of)
+            .finish()
+    }
+}
+
+impl Rings {
+    /// Create empty rings with the given placement policy.
+    #[must_use]
+    pub fn new(placement: Placement) -> Self {
+        Self {
+            placement,
+            ..Self::default()
+        }
+    }
+
+    /// Placement policy of the rings.
+    #[must_use]
+    pub const fn placement(&self) -> &Placement {
+        &self.placement
+    }
+
+    /// Add a worker.
+    pub fn insert(&mut self, worker: Uuid, zone: Option<String>, labels: Labels) {
+        self.all.insert(worker);
+        self.zones.entry(zone.clone()).or_default().insert(worker);
+        self.zone_of.insert(worker, zone);
+        self.labels_of.insert(worker, labels);
+    }
+
+    /// Remove a worker.
+    pub fn remove(&mut self, worker: &Uuid) {
+        self.all.remove(worker);
+        self.labels_of.remove(worker);
+        if let Some(zone) = self.zone_of.remove(worker) {
+            if let Some(ring) = self.zones.get_mut(&zone) {
+                ring.remove(worker);
+                if ring.is_empty() {
+                    self.zones.remove(&zone);
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if there's no available worker.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty()
+    }
+
+    /// Available workers.
+    pub fn workers(&self) -> impl Iterator<Item = &Uuid> {
+        self.all.resident_ranges().map(|resident| resident.node())
+    }
+
+    /// Pick the worker a task should be placed on.
+    ///
+    /// # Panics
+    /// Panics if there's no available worker.
+    #[must_use]
+    pub fn get(&self, task: &Uuid) -> &Uuid {
+        self.zone_for(task)
+            .and_then(|zone| self.zones.get(zone))
+            .unwrap_or(&self.all)
+            .get(task)
+    }
+
+    /// Zone a task should be placed in, or `None` if it may be placed in any.
+    fn zone_for(&self, task: &Uuid) -> Option<&Option<String>> {
+        match &self.placement {
+            Placement::Any => None,
+            Placement::PreferZone(zone) => self
+                .zones
+                .get_key_value(&Some(zone.clone()))
+                .map(|(zone, _)| zone),
+            // Rendezvous hashing, so that only tasks of a gone zone are moved.
+            Placement::Spread => self.zones.keys().max_by_key(|zone| rendezvous(task, zone)),
+        }
+    }
+
+    /// Pick the worker a task should be placed on, among workers having all
+    /// labels in `constraint`. Falls back to [`Rings::get`] if there's none.
+    ///
+    /// # Panics
+    /// Panics if there's no available worker.
+    #[must_use]
+    pub fn get_constrained(&self, task: &Uuid, constraint: Option<&Labels>) -> &Uuid {
+        let Some(constraint) = constraint else {
+            return self.get(task);
+        };
+        // Rendezvous hashing, so that only tasks of a gone worker are moved.
+        self.labels_of
+            .iter()
+            .filter(|(_, labels)| satisfies(labels, constraint))
+            .max_by_key(|(worker, _)| rendezvous(task, worker))
+            .map_or_else(
+                || {
+                    warn!(
+                        task_id = %task,
+                        ?constraint,
+                        "No worker satisfies placement constraint, fall back"
+                    );
+                    self.get(task)
+                },
+                |(worker, _)| worker,
+            )
+    }
+
+    /// Whether a task may stay on `worker` instead of moving to the one
+    /// picked by [`Rings::get_constrained`]. The worker must be available and
+    /// satisfy the constraint if any worker does, or be in the zone the task
+    /// should be placed in otherwise.
+    #[must_use]
+    pub fn eligible(&self, worker: &Uuid, task: &Uuid, constraint: Option<&Labels>) -> bool {
+        let Some(labels) = self.labels_of.get(worker) else {
+            return false;
+        };
+        match constraint {
+            Some(constraint)
+                if self
+                    .labels_of
+                    .values()
+                    .any(|labels| satisfies(labels, constraint)) =>
+            {
+                satisfies(labels, constraint)
+            }
+            _ => self
+                .zone_for(task)
+                .map_or(true, |zone| self.zone_of.get(worker) == Some(zone)),
+        }
+    }
+}
+
+/// Whether a worker has all labels in `constraint`.
+fn satisfies(labels: &Labels, constraint: &Labels) -> bool {
+    constraint
+        .iter()
+        .all(|(key, value)| labels.get(key) == Some(value))
+}
+
+fn rendezvous(task: &Uuid, node: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (task, node).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use sg_core::models::Labels;
+    use uuid::Uuid;
+
+    use crate::placement::{Placement, Rings};
+
+    fn zone(name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    #[test]
+    fn must_prefer_zone() {
+        let mut rings = Rings::new(Placement::PreferZone(String::from("a")));
+        let (local, remote) = (Uuid::new_v4(), Uuid::new_v4());
+        rings.insert(local, zone("a"), Labels::new());
+        rings.insert(remote, zone("b"), Labels::new());
+
+        for _ in 0..100 {
+            assert_eq!(rings.get(&Uuid::new_v4()), &local);
+        }
+
+        // Fall back to other zones.
+        rings.remove(&local);
+        assert_eq!(rings.get(&Uuid::new_v4()), &remote);
+    }
+
+    #[test]
+    fn must_spread() {
+        let mut rings = Rings::new(Placement::Spread);
+        // Zone `a` has much more workers than zone `b`.
+        for _ in 0..9 {
+            rings.insert(Uuid::new_v4(), zone("a"), Labels::new());
+        }
+        let lonely = Uuid::new_v4();
+        rings.insert(lonely, zone("b"), Labels::new());
+
+        let mut counts: HashMap<bool, usize> = HashMap::new();
+        for _ in 0..1000 {
+            *counts
+                .entry(rings.get(&Uuid::new_v4()) == &lonely)
+                .or_default() += 1;
+        }
+        // Roughly half of the tasks are placed in zone `b`.
+        assert!((400..600).contains(&counts[&true]), "{counts:?}");
+    }
+
+    #[test]
+    fn must_satisfy_constraint() {
+        let mut rings = Rings::new(Placement::Any);
+        let region = |region: &str| Labels::from([(String::from("region"), region.to_string())]);
+        let cn = Uuid::new_v4();
+        rings.insert(cn, None, region("cn"));
+        for _ in 0..9 {
+            rings.insert(Uuid::new_v4(), None, region("jp"));
+        }
+
+        for _ in 0..100 {
+            assert_eq!(
+                rings.get_constrained(&Uuid::new_v4(), Some(&region("cn"))),
+                &cn
+            );
+        }
+
+        // Fall back to any worker.
+        rings.remove(&cn);
+        let task = Uuid::new_v4();
+        assert_eq!(
+            rings.get_constrained(&task, Some(&region("cn"))),
+            rings.get(&task)
+        );
+    }
+
+    #[test]
+    fn must_check_eligible() {
+        let mut rings = Rings::new(Placement::PreferZone(String::from("a")));
+        let region = |region: &str| Labels::from([(String::from("region"), region.to_string())]);
+        let (local, remote, gone) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        rings.insert(local, zone("a"), region("jp"));
+        rings.insert(remote, zone("b"), region("cn"));
+        let task = Uuid::new_v4();
+
+        assert!(rings.eligible(&local, &task, None));
+        assert!(!rings.eligible(&remote, &task, None));
+        assert!(!rings.eligible(&gone, &task, None));
+
+        // Constraints take precedence over zones.
+        assert!(rings.eligible(&remote, &task, Some(&region("cn"))));
+        assert!(!rings.eligible(&local, &task, Some(&region("cn"))));
+        // Unsatisfiable constraints are ignored, like in placement.
+        assert!(rings.eligible(&local, &task, Some(&region("us"))));
+    }
+}
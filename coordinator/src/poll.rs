@@ -0,0 +1,189 @@
+//! HTTP long polling transport for workers behind networks that block
+//! websocket.
+//!
+//! A worker opens a session with `POST /join`, carrying the same headers as a
+//! websocket handshake, and gets its ID in the response body, with the
+//! negotiated protocol version in a header like the handshake. Then it keeps
+//! `GET /sessions/:id` to receive messages, `POST /sessions/:id` to send them,
+//! and `DELETE /sessions/:id` to leave.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path},
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Extension,
+    Router,
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    FutureExt,
+    SinkExt,
+    StreamExt,
+};
+use sg_core::{
+    adapter::{decode_batch, encode_batch, LongPoll},
+    protocol::PROTOCOL_HEADER,
+};
+use tokio::time::{interval, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::app::App;
+
+/// Open long polling sessions.
+#[derive(Debug)]
+pub struct Sessions {
+    inner: Mutex<HashMap<Uuid, Arc<Session>>>,
+    poll_timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Session {
+    sink: tokio::sync::Mutex<SplitSink<LongPoll, Message>>,
+    stream: tokio::sync::Mutex<SplitStream<LongPoll>>,
+    last_seen: Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+}
+
+impl Sessions {
+    /// Create an empty session registry.
+    #[must_use]
+    pub fn new(poll_timeout: Duration) -> Self {
+        Self {
+            inner: Mutex::default(),
+            poll_timeout,
+        }
+    }
+
+    fn open(&self, stream: LongPoll) -> Uuid {
+        let (sink, stream) = stream.split();
+        let id = Uuid::new_v4();
+        self.inner.lock().unwrap().insert(
+            id,
+            Arc::new(Session {
+                sink: tokio::sync::Mutex::new(sink),
+                stream: tokio::sync::Mutex::new(stream),
+                last_seen: Mutex::new(Instant::now()),
+            }),
+        );
+        id
+    }
+
+    fn get(&self, id: Uuid) -> Option<Arc<Session>> {
+        let session = self.inner.lock().unwrap().get(&id).cloned()?;
+        session.touch();
+        Some(session)
+    }
+
+    /// Close a session, ending the stream of its worker.
+    fn close(&self, id: Uuid) -> bool {
+        self.inner.lock().unwrap().remove(&id).is_some()
+    }
+
+    /// Close sessions not polled for twice the poll timeout. Never returns.
+    ///
+    /// # Panics
+    /// Panic if the registry is poisoned.
+    pub async fn expire(&self) {
+        let mut ticker = interval(self.poll_timeout);
+        loop {
+            ticker.tick().await;
+            let deadline = self.poll_timeout * 2;
+            self.inner.lock().unwrap().retain(|id, session| {
+                let alive = session.last_seen.lock().unwrap().elapsed() < deadline;
+                if !alive {
+                    info!(session = %id, "Long polling session expired");
+                }
+                alive
+            });
+        }
+    }
+}
+
+/// Build the long polling router.
+pub fn router(app: App, sessions: Arc<Sessions>) -> Router {
+    Router::new()
+        .route("/join", post(join))
+        .route(
+            "/sessions/:id",
+            post(push_messages).get(poll_messages).delete(leave),
+        )
+        .layer(Extension(app))
+        .layer(Extension(sessions))
+}
+
+async fn join(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(app): Extension<App>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+) -> Result<([(&'static str, String); 1], String), (StatusCode, String)> {
+    let (stream, protocol) = app.accept_long_poll(addr, &headers).await?;
+    let id = sessions.open(stream);
+    debug!(session = %id, %addr, "Long polling session opened");
+    Ok(([(PROTOCOL_HEADER, protocol.to_string())], id.to_string()))
+}
+
+/// Wait for messages to the worker, up to the poll timeout.
+async fn poll_messages(
+    Path(id): Path<Uuid>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+) -> Result<Vec<u8>, StatusCode> {
+    let session = sessions.get(id).ok_or(StatusCode::NOT_FOUND)?;
+    let mut stream = session.stream.lock().await;
+
+    let first = match timeout(sessions.poll_timeout, stream.next()).await {
+        Err(_) => return Ok(Vec::new()),
+        Ok(Some(Ok(msg))) => msg,
+        Ok(_) => {
+            sessions.close(id);
+            return Err(StatusCode::GONE);
+        }
+    };
+    let mut batch = vec![first];
+    while let Some(Some(Ok(msg))) = stream.next().now_or_never() {
+        batch.push(msg);
+    }
+    session.touch();
+    Ok(encode_batch(batch))
+}
+
+async fn push_messages(
+    Path(id): Path<Uuid>,
+    Extension(sessions): Extension<Arc<Sessions>>,
+    body: Bytes,
+) -> StatusCode {
+    let Some(session) = sessions.get(id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    let mut sink = session.sink.lock().await;
+    for msg in decode_batch(&body) {
+        if sink.send(msg).await.is_err() {
+            sessions.close(id);
+            return StatusCode::GONE;
+        }
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn leave(Path(id): Path<Uuid>, Extension(sessions): Extension<Arc<Sessions>>) -> StatusCode {
+    if sessions.close(id) {
+        debug!(session = %id, "Long polling session closed by worker");
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
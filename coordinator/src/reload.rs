@@ -0,0 +1,47 @@
+//! Hot reload of the config file.
+
+use std::future;
+
+use eyre::Result;
+use tracing::{info, warn};
+
+use crate::{app::App, config::Config};
+
+/// Read the config file again on every SIGHUP, and apply it to the running
+/// application.
+///
+/// Never returns if there's no config file, or on platforms without SIGHUP.
+///
+/// # Errors
+/// Return error if failed to listen for SIGHUP.
+#[cfg_attr(not(unix), allow(unused_variables, unused_mut))]
+pub async fn reload_on_hangup(app: App, mut config: Config) -> Result<()> {
+    let Some(path) = config.config_file.clone() else {
+        info!("Config reload disabled");
+        return future::pending().await;
+    };
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        info!(path = %path.display(), "Reloading config on SIGHUP");
+        while hangup.recv().await.is_some() {
+            match config.reloaded() {
+                Ok(reloaded) if reloaded == config => info!("Config unchanged"),
+                Ok(reloaded) => {
+                    app.reload(reloaded.clone()).await;
+                    config = reloaded;
+                }
+                Err(error) => warn!(?error, "Failed to reload config, keeping the current one"),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        warn!(path = %path.display(), "Config reload is not supported on this platform");
+        future::pending().await
+    }
+}
@@ -0,0 +1,701 @@
+//! Simulation harness to test integration with the coordinator.
+//!
+//! [`Harness`] runs a coordinator on a local port, lets [`SimWorker`]s join
+//! it and changes the cluster, e.g. adds tasks or kills workers. After any
+//! change, [`Harness::assert_converged`] waits for the coordinator to converge,
+//! i.e. every task runs on exactly the worker it's assigned to. Faults, e.g.
+//! failed or slow RPCs, can be injected into workers with [`Faults`].
+//!
+//! Choices of the harness, e.g. ids of tasks, which worker to kill and which
+//! RPC fails, are drawn from a seeded RNG, and the seed is logged so that a
+//! failing run can be replayed. Timing of RPCs is still up to the runtime.
+//!
+//! Available to tests of this crate, and to other crates with the `testing`
+//! feature.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    net::UdpSocket,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use eyre::Result;
+use rand::{rngs::StdRng, seq::IteratorRandom, Rng, SeedableRng};
+use sg_core::{
+    models::{Labels, Task},
+    protocol::{JoinOptions, TaskLiveness, TaskReporter, WorkerRpc, WorkerRpcExt},
+    utils::ScopedJoinHandle,
+};
+use tarpc::context::Context;
+use tokio::{
+    sync::oneshot::{channel, Sender},
+    task::JoinHandle,
+    time::sleep,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{config::Config, App};
+
+/// Delay before a [`SimWorker`] joins again after losing its connection.
+const REJOIN_DELAY: Duration = Duration::from_millis(100);
+
+/// Interval between checks of [`Harness::assert_converged`].
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Faults injected into RPCs served by [`SimWorker`]s.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// Probability that an RPC adding or removing tasks fails, from 0 to 1.
+    /// A failed RPC answers with no result, which the coordinator treats as a
+    /// broken worker.
+    pub failure_rate: f64,
+    /// Delay before answering each RPC, drawn uniformly from this range.
+    pub latency: Option<Range<Duration>>,
+}
+
+/// Source of faults shared by [`SimWorker`]s, drawing from a seeded RNG.
+#[derive(Debug, Clone)]
+pub struct FaultInjector(Arc<Mutex<(Faults, StdRng)>>);
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl FaultInjector {
+    /// Create an injector injecting no faults until [`set`](Self::set).
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new((
+            Faults::default(),
+            StdRng::seed_from_u64(seed),
+        ))))
+    }
+
+    /// Replace faults to inject from now on.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub fn set(&self, faults: Faults) {
+        self.0.lock().unwrap().0 = faults;
+    }
+
+    /// Wait for the injected latency, and return whether the RPC fails.
+    async fn inject(&self) -> bool {
+        let (fail, delay) = {
+            let (faults, rng) = &mut *self.0.lock().unwrap();
+            let fail = faults.failure_rate > 0.0 && rng.gen_bool(faults.failure_rate.min(1.0));
+            let delay = match &faults.latency {
+                Some(latency) if !latency.is_empty() => rng.gen_range(latency.clone()),
+                Some(latency) => latency.start,
+                None => Duration::ZERO,
+            };
+            (fail, delay)
+        };
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        fail
+    }
+}
+
+/// Simulated worker, keeping tasks assigned to it in memory.
+///
+/// Like a real worker, it keeps its tasks when its connection is lost, for the
+/// coordinator to adopt when it joins again, and drops them if it fails to
+/// join. Workers are compared by id.
+#[derive(Clone)]
+pub struct SimWorker {
+    ws: String,
+    id: Uuid,
+    kind: String,
+    zone: Option<String>,
+    labels: Labels,
+    faults: FaultInjector,
+    tasks: Arc<Mutex<HashMap<Uuid, Task>>>,
+    /// Bumped whenever the connection is lost, so that RPCs in flight from
+    /// it don't apply.
+    epoch: Arc<AtomicU64>,
+    backfilled: Arc<Mutex<Vec<Uuid>>>,
+    /// Size of the largest batch of tasks added or removed.
+    max_batch: Arc<AtomicUsize>,
+    /// Number of tasks added.
+    added: Arc<AtomicUsize>,
+    /// Tasks the worker rejects to add.
+    rejecting: Arc<Mutex<HashSet<Uuid>>>,
+    reporter: TaskReporter,
+}
+
+impl PartialEq for SimWorker {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for SimWorker {}
+
+impl Hash for SimWorker {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl SimWorker {
+    /// Create a worker of `kind` joining the coordinator at `ws`, with a
+    /// random id.
+    pub fn new(ws: impl Display, kind: impl Display) -> Self {
+        Self {
+            ws: ws.to_string(),
+            id: Uuid::new_v4(),
+            kind: kind.to_string(),
+            zone: None,
+            labels: Labels::new(),
+            faults: FaultInjector::default(),
+            tasks: Default::default(),
+            epoch: Default::default(),
+            backfilled: Default::default(),
+            max_batch: Default::default(),
+            added: Default::default(),
+            rejecting: Default::default(),
+            reporter: TaskReporter::new(),
+        }
+    }
+
+    /// Set id of the worker.
+    #[must_use]
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Set zone of the worker.
+    #[must_use]
+    pub fn in_zone(mut self, zone: impl Display) -> Self {
+        self.zone = Some(zone.to_string());
+        self
+    }
+
+    /// Add a label to the worker.
+    #[must_use]
+    pub fn with_label(mut self, key: impl Display, value: impl Display) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Inject faults from `faults` into RPCs served by the worker.
+    #[must_use]
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Id of the worker.
+    #[must_use]
+    pub const fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Tasks the worker is running.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    #[must_use]
+    pub fn tasks(&self) -> HashMap<Uuid, Task> {
+        self.tasks.lock().unwrap().clone()
+    }
+
+    /// Tasks the worker is asked to backfill, in order.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    #[must_use]
+    pub fn backfilled(&self) -> Vec<Uuid> {
+        self.backfilled.lock().unwrap().clone()
+    }
+
+    /// Size of the largest batch of tasks added or removed.
+    #[must_use]
+    pub fn max_batch(&self) -> usize {
+        self.max_batch.load(Ordering::Relaxed)
+    }
+
+    /// Number of tasks added to the worker, including ones it already ran.
+    #[must_use]
+    pub fn added(&self) -> usize {
+        self.added.load(Ordering::Relaxed)
+    }
+
+    /// Make the worker reject adding task `id`, like a real one does for
+    /// invalid params, or stop rejecting it.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub fn set_rejecting(&self, id: Uuid, rejecting: bool) {
+        let mut ids = self.rejecting.lock().unwrap();
+        if rejecting {
+            ids.insert(id);
+        } else {
+            ids.remove(&id);
+        }
+    }
+
+    /// Reporter of task status to the coordinator joined.
+    #[must_use]
+    pub const fn reporter(&self) -> &TaskReporter {
+        &self.reporter
+    }
+
+    /// Join the coordinator until the connection is lost. Tasks are kept
+    /// unless failed to join.
+    ///
+    /// # Errors
+    /// Returns error if failed to connect to the coordinator.
+    pub async fn join_remote(self) -> Result<()> {
+        let options = JoinOptions {
+            zone: self.zone.clone(),
+            labels: self.labels.clone(),
+            version: String::from("0.1.0"),
+            reporter: self.reporter.clone(),
+            ..JoinOptions::default()
+        };
+        let result = self
+            .clone()
+            .join_with(self.ws.clone(), self.id, self.kind.clone(), options)
+            .await;
+        // RPCs in flight from the lost connection must not apply.
+        let mut tasks = self.tasks.lock().unwrap();
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        if result.is_err() {
+            tasks.clear();
+        }
+        drop(tasks);
+        result
+    }
+
+    /// Join the coordinator, and join again whenever the connection is lost.
+    pub async fn run(self) {
+        loop {
+            if let Err(error) = self.clone().join_remote().await {
+                warn!(worker_id = %self.id, ?error, "Simulated worker failed to join");
+            }
+            sleep(REJOIN_DELAY).await;
+        }
+    }
+
+    /// Inject faults into an RPC. Return the tasks to apply it to, or `None`
+    /// if the RPC fails or its connection is lost meanwhile.
+    async fn tasks_for_rpc(&self) -> Option<MutexGuard<'_, HashMap<Uuid, Task>>> {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        if self.faults.inject().await {
+            return None;
+        }
+        let tasks = self.tasks.lock().unwrap();
+        (self.epoch.load(Ordering::SeqCst) == epoch).then_some(tasks)
+    }
+}
+
+#[tarpc::server]
+impl WorkerRpc for SimWorker {
+    async fn heartbeat(self, _: Context) -> Vec<TaskLiveness> {
+        self.faults.inject().await;
+        let running: Vec<Uuid> = self.tasks.lock().unwrap().keys().copied().collect();
+        self.reporter.liveness(running)
+    }
+
+    async fn add_task(self, _: Context, task: Task) -> bool {
+        self.tasks_for_rpc().await.is_some_and(|mut tasks| {
+            self.added.fetch_add(1, Ordering::Relaxed);
+            let rejecting = self.rejecting.lock().unwrap();
+            !rejecting.contains(&task.id.into()) && tasks.insert(task.id.into(), task).is_none()
+        })
+    }
+
+    async fn remove_task(self, _: Context, id: Uuid) -> bool {
+        self.tasks_for_rpc()
+            .await
+            .is_some_and(|mut tasks| tasks.remove(&id).is_some())
+    }
+
+    async fn add_tasks(self, _: Context, batch: Vec<Task>) -> Vec<bool> {
+        self.max_batch.fetch_max(batch.len(), Ordering::Relaxed);
+        let Some(mut tasks) = self.tasks_for_rpc().await else {
+            return vec![];
+        };
+        self.added.fetch_add(batch.len(), Ordering::Relaxed);
+        let rejecting = self.rejecting.lock().unwrap();
+        batch
+            .into_iter()
+            .map(|task| {
+                !rejecting.contains(&task.id.into()) && tasks.insert(task.id.into(), task).is_none()
+            })
+            .collect()
+    }
+
+    async fn remove_tasks(self, _: Context, ids: Vec<Uuid>) -> Vec<bool> {
+        self.max_batch.fetch_max(ids.len(), Ordering::Relaxed);
+        let Some(mut tasks) = self.tasks_for_rpc().await else {
+            return vec![];
+        };
+        ids.iter().map(|id| tasks.remove(id).is_some()).collect()
+    }
+
+    async fn tasks(self, _: Context) -> Vec<Task> {
+        self.tasks.lock().unwrap().values().cloned().collect()
+    }
+
+    async fn backfill(self, _: Context, task: Task, _: SystemTime) -> bool {
+        self.backfilled.lock().unwrap().push(task.id.into());
+        true
+    }
+}
+
+/// Get a free local port.
+///
+/// # Panics
+/// Panics if no port is available.
+#[must_use]
+pub fn free_port() -> u16 {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.local_addr().unwrap().port()
+}
+
+/// A coordinator with simulated workers. See [module doc](self).
+pub struct Harness {
+    app: App,
+    server_stop: Sender<()>,
+    server_handle: JoinHandle<Result<()>>,
+    port: u16,
+    seed: u64,
+    rng: StdRng,
+    faults: FaultInjector,
+    timeout: Duration,
+
+    tasks: BTreeMap<String, BTreeSet<Uuid>>,
+    workers: BTreeMap<String, Vec<(SimWorker, ScopedJoinHandle<()>)>>,
+}
+
+impl Harness {
+    /// Start a coordinator with default config and seed 0.
+    pub async fn new() -> Self {
+        Self::with_config(Config::default()).await
+    }
+
+    /// Start a coordinator with `config` and seed 0. `bind` is replaced by a
+    /// free local port.
+    pub async fn with_config(config: Config) -> Self {
+        Self::seeded(config, 0).await
+    }
+
+    /// Start a coordinator with `config` and `seed`. `bind` is replaced by a
+    /// free local port.
+    pub async fn seeded(config: Config, seed: u64) -> Self {
+        info!(seed, "Start simulation");
+        let port = free_port();
+        let app = App::new(Config {
+            bind: format!("127.0.0.1:{port}").parse().unwrap(),
+            ping_interval: Duration::from_millis(100),
+            ..config
+        });
+        let (tx, rx) = channel();
+        let server_handle = {
+            let app = app.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    r = app.serve() => r,
+                    _ = rx => Ok(())
+                }
+            })
+        };
+        sleep(Duration::from_millis(100)).await;
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        Self {
+            app,
+            server_stop: tx,
+            server_handle,
+            port,
+            seed,
+            faults: FaultInjector::new(rng.gen()),
+            rng,
+            timeout: Duration::from_secs(5),
+            tasks: BTreeMap::new(),
+            workers: BTreeMap::new(),
+        }
+    }
+
+    /// Set how long [`assert_converged`](Self::assert_converged) waits.
+    /// Defaults to 5 seconds.
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The coordinator.
+    #[must_use]
+    pub const fn app(&self) -> &App {
+        &self.app
+    }
+
+    /// Seed of the simulation.
+    #[must_use]
+    pub const fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Address workers join the coordinator at.
+    #[must_use]
+    pub fn ws(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    /// Live workers of `kind`.
+    pub fn workers(&self, kind: &str) -> impl Iterator<Item = &SimWorker> {
+        self.workers.get(kind).into_iter().flatten().map(|(worker, _)| worker)
+    }
+
+    /// Inject faults into all workers from now on.
+    pub fn set_faults(&self, faults: Faults) {
+        debug!(?faults, "Set faults");
+        self.faults.set(faults);
+    }
+
+    /// Stop injecting faults.
+    pub fn clear_faults(&self) {
+        self.set_faults(Faults::default());
+    }
+
+    /// Add `count` workers of `kind`, which join again whenever they lose
+    /// their connection.
+    pub fn add_workers(&mut self, kind: impl Display + Send, count: usize) {
+        let kind = kind.to_string();
+        debug!(count, %kind, "Add workers");
+
+        for _ in 0..count {
+            let worker = SimWorker::new(self.ws(), &kind).with_id(Uuid::from_u128(self.rng.gen()));
+            self.add_worker(worker);
+        }
+    }
+
+    /// Add a worker, which joins again whenever it loses its connection.
+    /// Faults set on the harness are injected into it.
+    pub fn add_worker(&mut self, worker: SimWorker) {
+        let worker = worker.with_faults(self.faults.clone());
+        let handle = ScopedJoinHandle(tokio::spawn(worker.clone().run()));
+        self.workers
+            .entry(worker.kind.clone())
+            .or_default()
+            .push((worker, handle));
+    }
+
+    /// Kill `count` random workers of `kind`.
+    pub fn kill_workers(&mut self, kind: impl Display + Send, count: usize) {
+        let kind = kind.to_string();
+        debug!(count, %kind, "Kill workers");
+
+        let Some(workers) = self.workers.get_mut(&kind) else {
+            return;
+        };
+        for _ in 0..count.min(workers.len()) {
+            let index = self.rng.gen_range(0..workers.len());
+            // Dropping the handle aborts the worker and closes its connection.
+            drop(workers.swap_remove(index));
+        }
+    }
+
+    /// Drain `count` random workers of `kind`, and return them.
+    ///
+    /// # Panics
+    /// Panics if the coordinator doesn't know a live worker.
+    pub async fn drain_workers(
+        &mut self,
+        kind: impl Display + Send,
+        count: usize,
+    ) -> Vec<SimWorker> {
+        let kind = kind.to_string();
+        debug!(count, %kind, "Drain workers");
+
+        let drained: Vec<_> = self
+            .workers
+            .get(&kind)
+            .into_iter()
+            .flatten()
+            .map(|(worker, _)| worker.clone())
+            .choose_multiple(&mut self.rng, count);
+        if let Some(group) = self.app.worker_groups.lock().await.get(&kind) {
+            for worker in &drained {
+                assert!(
+                    group.with(|group| group.drain_worker(worker.id)).await,
+                    "Worker {} is not in the group",
+                    worker.id
+                );
+            }
+        }
+        drained
+    }
+
+    /// Add `count` tasks of `kind`.
+    pub async fn add_tasks(&mut self, kind: impl Display + Send, count: usize) {
+        let kind = kind.to_string();
+        debug!(count, %kind, "Add tasks");
+
+        for _ in 0..count {
+            let task = Task {
+                id: Uuid::from_u128(self.rng.gen()).into(),
+                entity: Uuid::from_u128(self.rng.gen()).into(),
+                kind: kind.clone(),
+                params: Default::default(),
+            };
+            self.add_task(task).await;
+        }
+    }
+
+    /// Add a task.
+    pub async fn add_task(&mut self, task: Task) {
+        self.add_task_with(task, None).await;
+    }
+
+    /// Add a task, and request backfill of its activity since `since` if set.
+    pub async fn add_task_with(&mut self, task: Task, since: Option<SystemTime>) {
+        self.tasks
+            .entry(task.kind.clone())
+            .or_default()
+            .insert(task.id.into());
+        match since {
+            Some(since) => self.app.add_task_with_backfill(task, since).await,
+            None => self.app.add_task(task).await,
+        }
+    }
+
+    /// Remove `count` random tasks of `kind`.
+    pub async fn remove_tasks(&mut self, kind: impl Display + Send, count: usize) {
+        let kind = kind.to_string();
+        debug!(count, %kind, "Remove tasks");
+
+        let Some(tasks) = self.tasks.get_mut(&kind) else {
+            return;
+        };
+        let removed: Vec<_> = tasks.iter().copied().choose_multiple(&mut self.rng, count);
+        for id in removed {
+            tasks.remove(&id);
+            self.app.remove_task(id).await;
+        }
+    }
+
+    /// Check convergence invariants once:
+    /// - The coordinator has the same tasks as the harness.
+    /// - Each task runs on at most one worker.
+    /// - Tasks run on the workers the coordinator assigned them to.
+    /// - Draining workers run no task.
+    /// - If there's a worker not draining, every task not quarantined is
+    ///   assigned.
+    ///
+    /// # Errors
+    /// Returns the first invariant violated.
+    pub async fn check(&self) -> Result<(), String> {
+        let mut server_tasks: BTreeMap<String, BTreeSet<Uuid>> = BTreeMap::new();
+        let mut assigned: HashMap<Uuid, Uuid> = HashMap::new();
+        let mut quarantined: HashSet<Uuid> = HashSet::new();
+        let mut draining: HashSet<Uuid> = HashSet::new();
+        let mut serving: HashSet<String> = HashSet::new();
+        for (kind, group) in &*self.app.worker_groups.lock().await {
+            group
+                .with(|group| {
+                    for (id, bound_task) in &group.tasks {
+                        server_tasks.entry(kind.clone()).or_default().insert(*id);
+                        if let Some(worker) = bound_task.worker {
+                            assigned.insert(*id, worker);
+                        }
+                        if bound_task.quarantine.is_some() {
+                            quarantined.insert(*id);
+                        }
+                    }
+                    draining.extend(&group.draining);
+                    if group.workers.keys().any(|id| !group.draining.contains(id)) {
+                        serving.insert(kind.clone());
+                    }
+                })
+                .await;
+        }
+
+        let local_tasks: BTreeMap<_, _> = self
+            .tasks
+            .iter()
+            .filter(|(_, tasks)| !tasks.is_empty())
+            .map(|(kind, tasks)| (kind.clone(), tasks.clone()))
+            .collect();
+        if server_tasks != local_tasks {
+            return Err(format!(
+                "Server and local tasks do not match: {server_tasks:?} != {local_tasks:?}"
+            ));
+        }
+
+        let mut running: HashMap<Uuid, Uuid> = HashMap::new();
+        for worker in self.workers.values().flatten().map(|(worker, _)| worker) {
+            let tasks = worker.tasks();
+            if draining.contains(&worker.id) && !tasks.is_empty() {
+                return Err(format!("Draining worker {} still has tasks", worker.id));
+            }
+            for id in tasks.keys() {
+                if let Some(other) = running.insert(*id, worker.id) {
+                    return Err(format!(
+                        "Task {id} runs on both worker {other} and {}",
+                        worker.id
+                    ));
+                }
+            }
+        }
+
+        if assigned != running {
+            return Err(format!(
+                "Server and client task distribution don't match: {assigned:?} != {running:?}"
+            ));
+        }
+
+        for kind in &serving {
+            let tasks = server_tasks.get(kind).into_iter().flatten();
+            let unassigned = |id: &&Uuid| !assigned.contains_key(*id) && !quarantined.contains(*id);
+            if let Some(id) = tasks.clone().find(unassigned) {
+                return Err(format!("Task {id} of {kind} is not assigned"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for convergence invariants to hold. See [`check`](Self::check).
+    ///
+    /// # Panics
+    /// Panics if they don't hold before timeout.
+    pub async fn assert_converged(&self) {
+        let start = Instant::now();
+        loop {
+            match self.check().await {
+                Ok(()) => return,
+                Err(violation) if start.elapsed() >= self.timeout => {
+                    panic!("Not converged with seed {}: {violation}", self.seed)
+                }
+                Err(_) => sleep(CHECK_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Stop the coordinator.
+    ///
+    /// # Panics
+    /// Panics if the coordinator failed.
+    pub async fn finish(self) {
+        self.server_stop.send(()).unwrap();
+        self.server_handle.await.unwrap().unwrap();
+    }
+}
@@ -1,286 +1,245 @@
 use std::{
     collections::{HashMap, HashSet},
-    fmt::Display,
-    net::UdpSocket,
-    sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
-use educe::Educe;
-use eyre::Result;
-use mongodb::{bson::doc, Client, Collection};
+use mongodb::{
+    bson::{doc, Document},
+    Client,
+    Collection,
+};
+use serde_json::json;
 use sg_core::{
-    models::Task,
-    protocol::{WorkerRpc, WorkerRpcExt},
-    utils::ScopedJoinHandle,
+    models::{Labels, Task},
+    protocol::{Hello, HELLO_HEADER, MIN_PROTOCOL_VERSION, PROTOCOL_HEADER, PROTOCOL_VERSION},
+    utils::{Redacted, ScopedJoinHandle},
 };
-use tarpc::context::Context;
-use tokio::{
-    sync::oneshot::{channel, Sender},
-    task::JoinHandle,
-    time::{sleep, timeout},
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::StatusCode,
+    Error as WsError,
 };
 use uuid::Uuid;
 
-use crate::{config::Config, db::DB, App};
-
-#[derive(Clone, Educe)]
-#[educe(Hash, Eq, PartialEq)]
-struct DummyWorker {
-    #[educe(Hash(ignore), Eq(ignore), PartialEq(ignore))]
-    ws: String,
-    id: Uuid,
-    #[educe(Hash(ignore), Eq(ignore), PartialEq(ignore))]
-    kind: String,
-    #[educe(Hash(ignore), Eq(ignore), PartialEq(ignore))]
-    tasks: Arc<Mutex<HashMap<Uuid, Task>>>,
-}
+use crate::{
+    config::{Config, KindFilter},
+    db::{TaskDelta, DB},
+    placement::Strategy,
+    testing::{free_port, Faults, Harness, SimWorker},
+    App,
+};
 
-impl DummyWorker {
-    pub fn new(ws: impl Display, kind: impl Display) -> Self {
-        Self {
-            ws: ws.to_string(),
-            id: Uuid::new_v4(),
-            kind: kind.to_string(),
-            tasks: Default::default(),
-        }
-    }
+#[tokio::test]
+async fn must_consistent() {
+    let mut harness = Harness::new().await;
 
-    pub async fn join_remote(self) -> Result<()> {
-        self.clone().join(self.ws, self.id, self.kind).await
-    }
-}
+    harness.add_tasks("test", 100).await;
+    harness.assert_converged().await;
+    harness.add_workers("test", 5);
+    harness.assert_converged().await;
+    harness.remove_tasks("test", 20).await;
+    harness.assert_converged().await;
+    harness.add_workers("test", 5);
+    harness.assert_converged().await;
+    harness.add_tasks("test", 50).await;
+    harness.assert_converged().await;
+    harness.add_workers("test", 10);
+    harness.assert_converged().await;
+    harness.add_tasks("test", 50).await;
+    harness.assert_converged().await;
+    harness.kill_workers("test", 7);
+    harness.assert_converged().await;
+    harness.remove_tasks("test", 20).await;
+    harness.assert_converged().await;
+    harness.add_tasks("test", 50).await;
+    harness.assert_converged().await;
 
-#[tarpc::server]
-impl WorkerRpc for DummyWorker {
-    async fn ping(self, _: Context, id: u64) -> u64 {
-        id
-    }
+    harness.finish().await;
+}
 
-    async fn add_task(self, _: Context, task: Task) -> bool {
-        self.tasks
-            .lock()
-            .unwrap()
-            .insert(task.id.into(), task)
-            .is_none()
-    }
+#[tokio::test]
+async fn must_consistent_after_empty_group() {
+    let mut harness = Harness::new().await;
 
-    async fn remove_task(self, _: Context, id: Uuid) -> bool {
-        self.tasks.lock().unwrap().remove(&id).is_some()
-    }
+    harness.add_tasks("test", 10).await;
+    harness.assert_converged().await;
+    harness.add_workers("test", 1);
+    harness.assert_converged().await;
+    harness.kill_workers("test", 1);
+    harness.assert_converged().await;
+    harness.add_workers("test", 1);
+    harness.assert_converged().await;
 
-    async fn tasks(self, _: Context) -> Vec<Task> {
-        self.tasks.lock().unwrap().values().cloned().collect()
-    }
+    harness.finish().await;
 }
 
-fn free_port() -> u16 {
-    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
-    sock.local_addr().unwrap().port()
-}
+#[tokio::test]
+async fn must_consistent_after_drain() {
+    let mut harness = Harness::new().await;
 
-struct Tester {
-    server: App,
-    server_stop: Sender<()>,
-    server_handle: JoinHandle<Result<()>>,
-    port: u16,
+    harness.add_tasks("test", 50).await;
+    harness.add_workers("test", 5);
+    harness.assert_converged().await;
+    let drained = harness.drain_workers("test", 2).await;
+    harness.assert_converged().await;
+    assert!(drained.iter().all(|worker| worker.tasks().is_empty()));
+    harness.add_tasks("test", 20).await;
+    harness.assert_converged().await;
+    harness.kill_workers("test", 1);
+    harness.assert_converged().await;
 
-    tasks: HashMap<String, HashSet<Uuid>>,
-    clients: HashMap<String, HashMap<DummyWorker, ScopedJoinHandle<()>>>,
+    harness.finish().await;
 }
 
-impl Tester {
-    pub async fn new() -> Self {
-        let port = free_port();
-        let server = App::new(Config {
-            bind: format!("127.0.0.1:{}", port).parse().unwrap(),
-            ping_interval: Duration::from_millis(100),
-            ..Default::default()
-        });
-        let (tx, rx) = channel();
-        let server_handle = {
-            let server = server.clone();
-            tokio::spawn(async move {
-                tokio::select! {
-                    r = server.serve() => r,
-                    _ = rx => Ok(())
-                }
-            })
-        };
-        sleep(Duration::from_millis(100)).await;
-
-        Self {
-            server,
-            server_stop: tx,
-            server_handle,
-            port,
-            tasks: Default::default(),
-            clients: Default::default(),
-        }
-    }
+#[tokio::test]
+async fn must_adopt_tasks_of_rejoined_worker() {
+    let mut harness = Harness::new().await;
 
-    pub async fn finish(self) {
-        self.server_stop.send(()).unwrap();
-        self.server_handle.await.unwrap().unwrap();
-    }
+    harness.add_tasks("test", 20).await;
+    harness.add_workers("test", 1);
+    harness.assert_converged().await;
+    let worker = harness.workers("test").next().unwrap().clone();
+    assert_eq!(worker.added(), 20);
 
-    async fn validate(&self) {
-        let mut server_side: HashMap<String, HashMap<Uuid, Uuid>> = HashMap::new();
-        let mut remote_tasks: HashMap<String, HashSet<Uuid>> = HashMap::new();
-        for (kind, workers) in &*self.server.worker_groups.lock().await {
-            workers
-                .with(|workers| {
-                    for (id, bound_task) in &workers.tasks {
-                        remote_tasks.entry(kind.clone()).or_default().insert(*id);
-                        if let Some(bound_worker) = bound_task.worker {
-                            server_side
-                                .entry(kind.clone())
-                                .or_default()
-                                .insert(*id, bound_worker);
-                        }
-                    }
-                })
-                .await;
-        }
+    // The worker loses its connection and keeps its tasks, some of which are
+    // removed meanwhile.
+    harness.kill_workers("test", 1);
+    harness.remove_tasks("test", 5).await;
+    harness.assert_converged().await;
 
-        assert_eq!(
-            self.tasks, remote_tasks,
-            "Server and local tasks do not match"
-        );
-
-        let mut client_side: HashMap<String, HashMap<Uuid, Uuid>> = HashMap::new();
-        for (kind, workers) in &self.clients {
-            for worker in workers.keys() {
-                #[allow(clippy::significant_drop_in_scrutinee)]
-                for task in worker.tasks.lock().unwrap().values() {
-                    client_side
-                        .entry(kind.clone())
-                        .or_default()
-                        .insert(task.id.into(), worker.id);
-                }
-            }
-        }
+    // Once it joins again, the rest are adopted instead of added again.
+    harness.add_worker(worker.clone());
+    harness.assert_converged().await;
+    assert_eq!(worker.tasks().len(), 15);
+    assert_eq!(worker.added(), 20);
 
-        assert_eq!(
-            server_side, client_side,
-            "Server and client task distribution don't match"
-        );
-    }
+    harness.finish().await;
+}
 
-    pub async fn increase_workers(&mut self, kind: impl Display + Send, count: usize) {
-        let kind = kind.to_string();
-        eprintln!("Increase {} {} workers", count, kind);
-
-        for _ in 0..count {
-            let ws = format!("ws://127.0.0.1:{}", self.port);
-            let worker = DummyWorker::new(ws, kind.clone());
-
-            let handle = {
-                let worker = worker.clone();
-                ScopedJoinHandle(tokio::spawn(async move {
-                    worker.join_remote().await.unwrap();
-                }))
-            };
-            self.clients
-                .entry(kind.clone())
-                .or_default()
-                .insert(worker, handle);
-        }
+#[tokio::test]
+async fn must_converge_with_faults() {
+    for seed in 0..3 {
+        let mut harness = Harness::seeded(Config::default(), seed).await;
 
-        sleep(Duration::from_millis(150)).await;
-        self.validate().await;
-    }
+        harness.add_tasks("test", 50).await;
+        harness.add_workers("test", 5);
+        harness.assert_converged().await;
 
-    pub async fn decrease_workers(&mut self, kind: impl Display + Send, count: usize) {
-        let kind = kind.to_string();
-        eprintln!("Decrease {} {} workers", count, kind);
-
-        for _ in 0..count {
-            if let Some(map) = self.clients.get_mut(&kind) {
-                if let Some((client, handle)) = map
-                    .iter()
-                    .map(|(client, handle)| (client.clone(), handle))
-                    .next()
-                {
-                    handle.abort();
-                    map.remove(&client);
-                }
-            }
-        }
+        // Workers failing RPCs are kicked and join again.
+        harness.set_faults(Faults {
+            failure_rate: 0.2,
+            latency: Some(Duration::ZERO..Duration::from_millis(20)),
+        });
+        harness.add_tasks("test", 30).await;
+        harness.add_workers("test", 3);
+        harness.remove_tasks("test", 20).await;
+        harness.kill_workers("test", 2);
+        sleep(Duration::from_millis(300)).await;
+
+        harness.clear_faults();
+        harness.assert_converged().await;
 
-        sleep(Duration::from_millis(150)).await;
-        self.validate().await;
+        harness.finish().await;
     }
+}
 
-    pub async fn increase_tasks(&mut self, kind: impl Display + Send, count: usize) {
-        let kind = kind.to_string();
-        eprintln!("Increase {} {} tasks", count, kind);
+#[tokio::test]
+async fn must_balance_in_batches() {
+    let mut harness = Harness::with_config(Config {
+        balance_concurrency: 2,
+        balance_batch_size: 8,
+        ..Default::default()
+    })
+    .await;
 
-        for _ in 0..count {
-            let task = Task {
-                id: Uuid::new_v4().into(),
-                entity: Uuid::new_v4().into(),
-                kind: kind.clone(),
-                params: Default::default(),
-            };
+    harness.add_tasks("test", 100).await;
+    harness.assert_converged().await;
+    harness.add_workers("test", 1);
+    harness.assert_converged().await;
+    harness.add_workers("test", 2);
+    harness.assert_converged().await;
+    harness.remove_tasks("test", 50).await;
+    harness.assert_converged().await;
 
-            self.tasks
-                .entry(kind.clone())
-                .or_default()
-                .insert(task.id.into());
-            self.server.add_task(task).await;
-        }
+    let max_batches: Vec<_> = harness.workers("test").map(SimWorker::max_batch).collect();
+    assert!(max_batches.iter().all(|size| *size <= 8), "{max_batches:?}");
+    // Tasks present before the first worker joins are added all at once.
+    assert!(max_batches.contains(&8), "{max_batches:?}");
 
-        sleep(Duration::from_millis(250)).await;
-        self.validate().await;
-    }
+    harness.finish().await;
+}
 
-    pub async fn decrease_tasks(&mut self, kind: impl Display + Send, count: usize) {
-        let kind = kind.to_string();
-        eprintln!("Decrease {} {} tasks", count, kind);
-
-        for _ in 0..count {
-            if let Some(tasks) = self.tasks.get_mut(&kind) {
-                if let Some(id) = tasks.iter().copied().next() {
-                    tasks.remove(&id);
-                    self.server.remove_task(id).await;
-                }
-            }
-        }
+/// Count tasks moved to another worker when a worker joins `workers` others.
+async fn moved_on_join(config: Config, workers: usize) -> usize {
+    let assignments = |harness: &Harness| -> HashMap<Uuid, Uuid> {
+        harness
+            .workers("test")
+            .flat_map(|worker| worker.tasks().into_keys().map(|task| (task, worker.id())))
+            .collect()
+    };
 
-        sleep(Duration::from_millis(150)).await;
-        self.validate().await;
-    }
+    let mut harness = Harness::seeded(config, 0).await;
+    harness.add_tasks("test", 100).await;
+    harness.add_workers("test", workers);
+    harness.assert_converged().await;
+    let before = assignments(&harness);
+
+    harness.add_workers("test", 1);
+    harness.assert_converged().await;
+    let after = assignments(&harness);
+    harness.finish().await;
+
+    before
+        .iter()
+        .filter(|(task, worker)| after.get(task) != Some(worker))
+        .count()
 }
 
 #[tokio::test]
-async fn must_consistent() {
-    let mut tester = Tester::new().await;
-
-    tester.increase_tasks("test", 100).await;
-    tester.increase_workers("test", 5).await;
-    tester.decrease_tasks("test", 20).await;
-    tester.increase_workers("test", 5).await;
-    tester.increase_tasks("test", 50).await;
-    tester.increase_workers("test", 10).await;
-    tester.increase_tasks("test", 50).await;
-    tester.decrease_workers("test", 7).await;
-    tester.decrease_tasks("test", 20).await;
-    tester.increase_tasks("test", 50).await;
-
-    tester.finish().await;
+async fn must_stick_to_current_workers() {
+    let moved = moved_on_join(Config::default(), 4).await;
+    let moved_sticky = moved_on_join(
+        Config {
+            balance_stickiness: Some(20),
+            ..Config::default()
+        },
+        4,
+    )
+    .await;
+    assert!(moved_sticky < moved, "{moved_sticky} >= {moved}");
 }
 
 #[tokio::test]
-async fn must_consistent_after_empty_group() {
-    let mut tester = Tester::new().await;
+async fn must_preview_balance() {
+    let mut harness = Harness::new().await;
+    harness.add_tasks("test", 100).await;
+    harness.add_workers("test", 4);
+    harness.assert_converged().await;
 
-    tester.increase_tasks("test", 10).await;
-    tester.increase_workers("test", 1).await;
-    tester.decrease_workers("test", 1).await;
-    tester.increase_workers("test", 1).await;
+    let extra = Uuid::new_v4();
+    let (unchanged, moves) = {
+        let worker_groups = harness.app().worker_groups.lock().await;
+        worker_groups["test"]
+            .with(|group| {
+                (
+                    group.preview_balance(&[]),
+                    group.preview_balance(&[(extra, None, Labels::new())]),
+                )
+            })
+            .await
+    };
+    assert!(unchanged.is_empty(), "{unchanged:?}");
+    assert!(!moves.is_empty());
+    // Only tasks picked by the new worker on the ring move.
+    assert!(
+        moves
+            .iter()
+            .all(|task_move| task_move.from.is_some() && task_move.to == Some(extra)),
+        "{moves:?}"
+    );
 
-    tester.finish().await;
+    harness.finish().await;
 }
 
 #[tokio::test]
@@ -305,12 +264,8 @@ async fn must_consistent_after_repeated_join() {
         .await;
 
     // A client joined the remote, ...
-    let client = DummyWorker {
-        ws: format!("ws://127.0.0.1:{}", port),
-        id: Default::default(),
-        kind: String::from("test"),
-        tasks: Arc::new(Mutex::new(Default::default())),
-    };
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let client = SimWorker::new(&ws, "test").with_id(Uuid::default());
     // gets a task, and quits immediately before next ping.
     assert!(
         timeout(Duration::from_millis(300), client.clone().join_remote())
@@ -318,12 +273,9 @@ async fn must_consistent_after_repeated_join() {
             .is_err(),
         "unable to join remote"
     );
-    assert!(!client.tasks.lock().unwrap().is_empty(), "no task received");
+    assert!(!client.tasks().is_empty(), "no task received");
 
-    let client = DummyWorker {
-        tasks: Arc::new(Mutex::new(Default::default())),
-        ..client
-    };
+    let client = SimWorker::new(&ws, "test").with_id(Uuid::default());
     // Then it joined coordinator again.
     assert!(
         timeout(Duration::from_millis(500), client.clone().join_remote())
@@ -331,7 +283,7 @@ async fn must_consistent_after_repeated_join() {
             .is_err(),
         "unable to join remote"
     );
-    assert!(!client.tasks.lock().unwrap().is_empty(), "no task received");
+    assert!(!client.tasks().is_empty(), "no task received");
 
     // The worker group shouldn't be poisoned.
     server.worker_groups.lock().await["test"]
@@ -339,6 +291,512 @@ async fn must_consistent_after_repeated_join() {
         .await;
 }
 
+#[tokio::test]
+async fn must_backfill_once() {
+    let mut harness = Harness::new().await;
+
+    harness.add_workers("test", 1);
+    harness.assert_converged().await;
+    let task = Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: Default::default(),
+    };
+    harness
+        .add_task_with(task.clone(), Some(SystemTime::now()))
+        .await;
+    harness.assert_converged().await;
+
+    // Migrate the task to other workers.
+    harness.add_workers("test", 5);
+    sleep(Duration::from_millis(150)).await;
+    harness.assert_converged().await;
+
+    let backfilled: Vec<Uuid> = harness
+        .workers("test")
+        .flat_map(SimWorker::backfilled)
+        .collect();
+    assert_eq!(backfilled, vec![task.id.into()]);
+
+    harness.finish().await;
+}
+
+#[tokio::test]
+async fn must_reject_over_limit() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        max_connections_per_ip: 1,
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let _first = ScopedJoinHandle(tokio::spawn(SimWorker::new(&ws, "test").join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    // The second worker is rejected before the upgrade to websocket.
+    let hello = Hello {
+        version: String::from("0.1.0"),
+        protocol: PROTOCOL_VERSION,
+        min_protocol: None,
+        kinds: vec![String::from("test")],
+        labels: Labels::new(),
+    };
+    let mut req = ws.as_str().into_client_request().unwrap();
+    req.headers_mut()
+        .insert("Sg-Worker-ID", Uuid::new_v4().to_string().parse().unwrap());
+    req.headers_mut()
+        .insert("Sg-Worker-Kind", "test".parse().unwrap());
+    req.headers_mut().insert(
+        HELLO_HEADER,
+        serde_json::to_string(&hello).unwrap().parse().unwrap(),
+    );
+    let joined = timeout(
+        Duration::from_millis(500),
+        tokio_tungstenite::connect_async(req),
+    )
+    .await
+    .expect("worker not rejected");
+    match joined {
+        Err(WsError::Http(resp)) => assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS),
+        _ => panic!("worker not rejected"),
+    }
+
+    assert_eq!(
+        server.connections.count_by_kind(),
+        HashMap::from([(String::from("test"), 1)])
+    );
+    assert_eq!(
+        server.worker_groups.lock().await["test"]
+            .with(|wg| wg.worker_len())
+            .await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn must_prefer_same_zone() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        zone: Some(String::from("local")),
+        placement: Strategy::SameZone,
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let local = SimWorker::new(&ws, "test").in_zone("local");
+    let remote = SimWorker::new(&ws, "test").in_zone("remote");
+    let _local = ScopedJoinHandle(tokio::spawn(local.clone().join_remote()));
+    let _remote = ScopedJoinHandle(tokio::spawn(remote.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..20 {
+        server
+            .add_task(Task {
+                id: Uuid::new_v4().into(),
+                entity: Uuid::new_v4().into(),
+                kind: String::from("test"),
+                params: Default::default(),
+            })
+            .await;
+    }
+    sleep(Duration::from_millis(250)).await;
+
+    assert_eq!(local.tasks().len(), 20);
+    assert!(remote.tasks().is_empty());
+}
+
+#[tokio::test]
+async fn must_reload_placement() {
+    let port = free_port();
+    let config = Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        zone: Some(String::from("local")),
+        ..Default::default()
+    };
+    let server = App::new(config.clone());
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let local = SimWorker::new(&ws, "test").in_zone("local");
+    let remote = SimWorker::new(&ws, "test").in_zone("remote");
+    let _local = ScopedJoinHandle(tokio::spawn(local.clone().join_remote()));
+    let _remote = ScopedJoinHandle(tokio::spawn(remote.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..20 {
+        server
+            .add_task(Task {
+                id: Uuid::new_v4().into(),
+                entity: Uuid::new_v4().into(),
+                kind: String::from("test"),
+                params: Default::default(),
+            })
+            .await;
+    }
+    sleep(Duration::from_millis(250)).await;
+    assert!(!remote.tasks().is_empty());
+
+    server
+        .reload(Config {
+            placement: Strategy::SameZone,
+            ping_interval: Duration::from_millis(100),
+            ..config
+        })
+        .await;
+    sleep(Duration::from_millis(250)).await;
+
+    // Tasks are migrated without dropping connections.
+    assert_eq!(local.tasks().len(), 20);
+    assert!(remote.tasks().is_empty());
+    assert_eq!(server.connections.stats().len(), 2);
+    assert_eq!(server.config().placement, Strategy::SameZone);
+}
+
+/// Last reported failure of a task and whether it's paused.
+async fn failure_of(server: &App, kind: &str, id: Uuid) -> (Option<String>, bool) {
+    server.worker_groups.lock().await[kind]
+        .with(|group| {
+            let bound_task = &group.tasks[&id];
+            let reason = bound_task.failure.as_ref().map(|f| f.reason.clone());
+            (reason, bound_task.paused)
+        })
+        .await
+}
+
+#[tokio::test]
+async fn must_pause_failing_tasks() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        pause_failing_after: Some(Duration::from_millis(200)),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let worker = SimWorker::new(format!("ws://127.0.0.1:{}", port), "test");
+    let _worker = ScopedJoinHandle(tokio::spawn(worker.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    let task = Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: Default::default(),
+    };
+    let id = task.id.into();
+    server.add_task(task).await;
+    sleep(Duration::from_millis(250)).await;
+    assert!(worker.tasks().contains_key(&id));
+
+    // Recovered tasks are forgotten.
+    worker.reporter().failing(id, "channel deleted").await;
+    assert_eq!(
+        failure_of(&server, "test", id).await,
+        (Some(String::from("channel deleted")), false)
+    );
+    worker.reporter().healthy(id).await;
+    assert_eq!(failure_of(&server, "test", id).await, (None, false));
+
+    // Tasks failing for too long are paused, and taken back from workers.
+    worker.reporter().failing(id, "channel deleted").await;
+    sleep(Duration::from_millis(250)).await;
+    worker.reporter().failing(id, "channel deleted").await;
+    assert!(failure_of(&server, "test", id).await.1);
+    sleep(Duration::from_millis(250)).await;
+    assert!(!worker.tasks().contains_key(&id));
+
+    // Reports of tasks not assigned to the worker are ignored.
+    worker.reporter().healthy(id).await;
+    assert!(failure_of(&server, "test", id).await.0.is_some());
+
+    let resumed = server.worker_groups.lock().await["test"]
+        .with(|group| group.resume_task(id))
+        .await;
+    assert!(resumed);
+    sleep(Duration::from_millis(250)).await;
+    assert!(worker.tasks().contains_key(&id));
+    assert_eq!(failure_of(&server, "test", id).await, (None, false));
+}
+
+/// Count of rejections of a task in its quarantine, if any.
+async fn rejections_of(server: &App, kind: &str, id: Uuid) -> Option<u32> {
+    server.worker_groups.lock().await[kind]
+        .with(|group| Some(group.tasks[&id].quarantine.as_ref()?.rejections))
+        .await
+}
+
+#[tokio::test]
+async fn must_quarantine_rejected_tasks() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        quarantine_backoff: Duration::from_millis(100),
+        quarantine_max_backoff: Duration::from_millis(200),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let worker = SimWorker::new(format!("ws://127.0.0.1:{}", port), "test");
+    let _worker = ScopedJoinHandle(tokio::spawn(worker.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    let task = |id| Task {
+        id: Uuid::from_u128(id).into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: Default::default(),
+    };
+    let (rejected, accepted) = (Uuid::from_u128(1), Uuid::from_u128(2));
+    worker.set_rejecting(rejected, true);
+    server.add_task(task(1)).await;
+    server.add_task(task(2)).await;
+    sleep(Duration::from_millis(250)).await;
+
+    // Rejected tasks are quarantined, and the worker keeps running others.
+    assert!(!worker.tasks().contains_key(&rejected));
+    assert!(worker.tasks().contains_key(&accepted));
+    assert!(rejections_of(&server, "test", rejected).await.is_some());
+    assert_eq!(rejections_of(&server, "test", accepted).await, None);
+    assert_eq!(server.connections.stats().len(), 1);
+
+    // Quarantined tasks are retried until accepted.
+    sleep(Duration::from_millis(500)).await;
+    assert!(rejections_of(&server, "test", rejected).await.unwrap() > 1);
+    worker.set_rejecting(rejected, false);
+    sleep(Duration::from_millis(500)).await;
+    assert!(worker.tasks().contains_key(&rejected));
+    assert_eq!(rejections_of(&server, "test", rejected).await, None);
+}
+
+/// Whether a task is stale and its errors in the last heartbeat, if any.
+async fn activity_of(server: &App, kind: &str, id: Uuid) -> Option<(bool, u64)> {
+    server.worker_groups.lock().await[kind]
+        .with(|group| {
+            let activity = group.tasks[&id].activity.as_ref()?;
+            Some((activity.stale, activity.errors))
+        })
+        .await
+}
+
+#[tokio::test]
+async fn must_flag_stale_tasks() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        ping_interval: Duration::from_millis(50),
+        activity_sla: Some(Duration::from_millis(300)),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let worker = SimWorker::new(format!("ws://127.0.0.1:{}", port), "test");
+    let _worker = ScopedJoinHandle(tokio::spawn(worker.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    let task = Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: Default::default(),
+    };
+    let id = task.id.into();
+    server.add_task(task).await;
+    sleep(Duration::from_millis(250)).await;
+    assert!(worker.tasks().contains_key(&id));
+
+    worker.reporter().active(id);
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(activity_of(&server, "test", id).await, Some((false, 0)));
+
+    // Tasks idle for longer than the SLA are flagged, but kept running.
+    sleep(Duration::from_millis(400)).await;
+    assert_eq!(activity_of(&server, "test", id).await, Some((true, 0)));
+    assert!(worker.tasks().contains_key(&id));
+
+    worker.reporter().failing(id, "rate limited").await;
+    worker.reporter().healthy(id).await;
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(activity_of(&server, "test", id).await, Some((false, 1)));
+}
+
+#[tokio::test]
+async fn must_respect_placement_constraint() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let cn = SimWorker::new(&ws, "test").with_label("region", "cn");
+    let jp = SimWorker::new(&ws, "test").with_label("region", "jp");
+    let _cn = ScopedJoinHandle(tokio::spawn(cn.clone().join_remote()));
+    let _jp = ScopedJoinHandle(tokio::spawn(jp.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    let task = |region: &str| Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: json!({ "placement": { "region": region } })
+            .as_object()
+            .unwrap()
+            .clone(),
+    };
+    for _ in 0..10 {
+        server.add_task(task("cn")).await;
+    }
+    // No worker satisfies the constraint, placed anywhere.
+    let unsatisfiable = task("us");
+    let unsatisfiable_id = Uuid::from(unsatisfiable.id);
+    server.add_task(unsatisfiable).await;
+    sleep(Duration::from_millis(250)).await;
+
+    let cn_tasks = cn.tasks();
+    let jp_tasks = jp.tasks();
+    assert_eq!(cn_tasks.len() + jp_tasks.len(), 11);
+    assert!(jp_tasks.len() <= 1, "{jp_tasks:?}");
+    assert!(jp_tasks.keys().all(|id| *id == unsatisfiable_id));
+}
+
+#[tokio::test]
+async fn must_join_over_long_poll() {
+    let (port, poll_port) = (free_port(), free_port());
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        poll_bind: Some(format!("127.0.0.1:{}", poll_port).parse().unwrap()),
+        poll_timeout: Duration::from_millis(200),
+        ping_interval: Duration::from_millis(100),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve_poll());
+    sleep(Duration::from_millis(100)).await;
+
+    let worker = SimWorker::new(format!("http://127.0.0.1:{}", poll_port), "test");
+    let handle = ScopedJoinHandle(tokio::spawn(worker.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..5 {
+        server
+            .add_task(Task {
+                id: Uuid::new_v4().into(),
+                entity: Uuid::new_v4().into(),
+                kind: String::from("test"),
+                params: Default::default(),
+            })
+            .await;
+    }
+    sleep(Duration::from_millis(250)).await;
+    assert_eq!(worker.tasks().len(), 5);
+    assert_eq!(
+        server.connections.count_by_kind(),
+        HashMap::from([(String::from("test"), 1)])
+    );
+
+    // The worker quits and leaves its session.
+    drop(handle);
+    sleep(Duration::from_millis(300)).await;
+    assert!(server.connections.count_by_kind().is_empty());
+    assert_eq!(
+        server.worker_groups.lock().await["test"]
+            .with(|wg| wg.worker_len())
+            .await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn must_negotiate_on_join() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    let hello = Hello {
+        version: String::from("0.1.0"),
+        protocol: PROTOCOL_VERSION,
+        min_protocol: None,
+        kinds: vec![String::from("test")],
+        labels: Labels::new(),
+    };
+    let join = |hello: Option<&Hello>| {
+        let mut req = format!("ws://127.0.0.1:{}", port)
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert("Sg-Worker-ID", Uuid::new_v4().to_string().parse().unwrap());
+        req.headers_mut()
+            .insert("Sg-Worker-Kind", "test".parse().unwrap());
+        if let Some(hello) = hello {
+            req.headers_mut().insert(
+                HELLO_HEADER,
+                serde_json::to_string(hello).unwrap().parse().unwrap(),
+            );
+        }
+        tokio_tungstenite::connect_async(req)
+    };
+    let reason = |r: Result<_, WsError>| match r {
+        Err(WsError::Http(resp)) => {
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            String::from_utf8(resp.body().clone().unwrap()).unwrap()
+        }
+        _ => panic!("worker not rejected"),
+    };
+
+    let (_conn, resp) = join(Some(&hello)).await.unwrap();
+    assert_eq!(
+        resp.headers()[PROTOCOL_HEADER],
+        PROTOCOL_VERSION.to_string()
+    );
+    sleep(Duration::from_millis(100)).await;
+    server.worker_groups.lock().await["test"]
+        .with(|wg| {
+            let worker = wg.workers.values().next().unwrap();
+            assert_eq!(worker.hello, hello);
+            assert_eq!(worker.protocol, PROTOCOL_VERSION);
+        })
+        .await;
+
+    // Workers of older versions still supported are spoken to in theirs.
+    let older = Hello {
+        protocol: MIN_PROTOCOL_VERSION,
+        ..hello.clone()
+    };
+    let (_older_conn, resp) = join(Some(&older)).await.unwrap();
+    assert_eq!(
+        resp.headers()[PROTOCOL_HEADER],
+        MIN_PROTOCOL_VERSION.to_string()
+    );
+
+    assert!(reason(join(None).await).contains(HELLO_HEADER));
+    let newer = Hello {
+        protocol: PROTOCOL_VERSION + 1,
+        ..hello.clone()
+    };
+    assert!(reason(join(Some(&newer)).await).contains("protocol version"));
+    let other_kind = Hello {
+        kinds: vec![String::from("other")],
+        ..hello
+    };
+    assert!(reason(join(Some(&other_kind)).await).contains("doesn't support"));
+}
+
 #[tokio::test]
 async fn must_db() {
     let client = Client::with_uri_str("mongodb://localhost:27017/")
@@ -347,14 +805,18 @@ async fn must_db() {
     let db = client.database("test");
     let collection: Collection<Task> = db.collection("coordinator");
     let config = Config {
-        mongo_uri: String::from("mongodb://localhost:27017/"),
+        mongo_uri: Redacted(String::from("mongodb://localhost:27017/")),
         mongo_db: String::from("test"),
         mongo_collection: String::from("coordinator"),
         ..Default::default()
     };
 
-    // Clear test collection before test.
+    // Clear test collection and saved resume token before test.
     collection.drop(None).await.unwrap();
+    db.collection::<Document>("task_checkpoints")
+        .delete_one(doc! { "_id": "coordinator:coordinator" }, None)
+        .await
+        .unwrap();
 
     // Add some initial tasks.
     let mut tasks: Vec<_> = (0..5)
@@ -370,7 +832,7 @@ async fn must_db() {
 
     // Create app and db instance.
     let app = App::new(config.clone());
-    let mut db = DB::new(app.clone(), config).await.unwrap();
+    let mut db = DB::new(app.clone(), config.clone()).await.unwrap();
 
     // Initial tasks must be added.
     db.init_tasks().await.unwrap();
@@ -428,6 +890,132 @@ async fn must_db() {
         .unwrap();
     sleep(Duration::from_millis(200)).await;
     assert_task_ids(&app, &tasks).await;
+
+    // Changes missed by a stale instance are applied on reconciliation.
+    let stale_app = App::new(config.clone());
+    let mut stale_db = DB::new(stale_app.clone(), config).await.unwrap();
+    // The position of the change stream is saved for the next instance.
+    assert!(stale_db.load_resume_token().await.is_some());
+    stale_db.init_tasks().await.unwrap();
+    let removed = tasks.remove(0);
+    collection
+        .delete_one(doc! { "id": removed.id }, None)
+        .await
+        .unwrap();
+    let added = Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: Default::default(),
+    };
+    tasks.push(added.clone());
+    collection.insert_one(&added, None).await.unwrap();
+
+    let delta = stale_db.reconcile(false).await.unwrap();
+    assert_eq!(delta.added, vec![added]);
+    assert!(delta.updated.is_empty());
+    assert_eq!(delta.removed, vec![removed.id.into()]);
+    assert_task_ids(&stale_app, &tasks).await;
+}
+
+#[tokio::test]
+async fn must_db_filter_kinds() {
+    let client = Client::with_uri_str("mongodb://localhost:27017/")
+        .await
+        .unwrap();
+    let db = client.database("test");
+    let collection: Collection<Task> = db.collection("coordinator_kinds");
+    let staging: Collection<Task> = db.collection("coordinator_kinds_staging");
+    let config = Config {
+        mongo_uri: Redacted(String::from("mongodb://localhost:27017/")),
+        mongo_db: String::from("test"),
+        mongo_collection: String::from("coordinator_kinds"),
+        mongo_extra_collections: vec![String::from("coordinator_kinds_staging")],
+        task_kinds: KindFilter {
+            allow: vec![],
+            deny: vec![String::from("experimental")],
+        },
+        ..Default::default()
+    };
+    collection.drop(None).await.unwrap();
+    staging.drop(None).await.unwrap();
+
+    let task = |kind: &str| Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from(kind),
+        params: Default::default(),
+    };
+
+    // Tasks of both collections are loaded, except those of denied kinds.
+    let (main, staged, experimental) = (task("test"), task("test"), task("experimental"));
+    collection.insert_one(&main, None).await.unwrap();
+    staging
+        .insert_many([&staged, &experimental], None)
+        .await
+        .unwrap();
+
+    let app = App::new(config.clone());
+    let mut db = DB::new(app.clone(), config).await.unwrap();
+    db.init_tasks().await.unwrap();
+    assert_eq!(scheduled_ids(&app).await, ids_of(&[&main, &staged]));
+
+    tokio::spawn(async move {
+        db.watch_tasks().await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // Changes of both collections are applied.
+    let (added, denied) = (task("test"), task("experimental"));
+    staging.insert_one(&added, None).await.unwrap();
+    collection.insert_one(&denied, None).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(scheduled_ids(&app).await, ids_of(&[&main, &staged, &added]));
+
+    // Tasks changed to a denied kind are removed.
+    staging
+        .update_one(
+            doc! { "id": staged.id },
+            doc! { "$set": { "kind": "experimental" } },
+            None,
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(scheduled_ids(&app).await, ids_of(&[&main, &added]));
+}
+
+#[test]
+fn must_diff_tasks() {
+    let task = |kind: &str| Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from(kind),
+        params: Default::default(),
+    };
+    let (kept, changed, removed, added) = (task("a"), task("b"), task("c"), task("d"));
+    let mut updated = changed.clone();
+    updated.params.insert("k".into(), "v".into());
+
+    let map = |tasks: &[&Task]| -> HashMap<Uuid, Task> {
+        tasks
+            .iter()
+            .map(|task| (task.id.into(), (*task).clone()))
+            .collect()
+    };
+    let current = map(&[&kept, &changed, &removed]);
+    let desired = map(&[&kept, &updated, &added]);
+
+    let delta = TaskDelta::between(&current, &desired);
+    assert_eq!(
+        delta,
+        TaskDelta {
+            added: vec![added],
+            updated: vec![updated],
+            removed: vec![removed.id.into()],
+        }
+    );
+    assert!(TaskDelta::between(&desired, &desired).is_empty());
 }
 
 async fn assert_task_ids(app: &App, expected: &[Task]) {
@@ -440,3 +1028,11 @@ async fn assert_task_ids(app: &App, expected: &[Task]) {
         })
         .await;
 }
+
+async fn scheduled_ids(app: &App) -> HashSet<Uuid> {
+    app.tasks().await.into_keys().collect()
+}
+
+fn ids_of(tasks: &[&Task]) -> HashSet<Uuid> {
+    tasks.iter().map(|task| task.id.into()).collect()
+}
@@ -5,27 +5,41 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::{Debug, Formatter},
     sync::{Arc, Weak},
+    time::{Duration, SystemTime},
 };
 
-use consistent_hash_ring::Ring;
-use futures_util::{Sink, Stream};
+use futures_util::{future::join_all, Sink, Stream};
+use serde::Serialize;
 use sg_core::{
-    adapter::WsTransport,
-    models::Task,
-    protocol::WorkerRpcClient,
+    adapter::{multiplex, WsTransport},
+    models::{Labels, Task},
+    protocol::{CoordinatorRpc, Hello, TaskLiveness, TaskStatus, WorkerRpcClient},
     utils::ScopedJoinHandle,
 };
 use tap::TapFallible;
 use tarpc::{
     client::{Config as ClientConfig, RpcError},
     context::Context,
+    server::{BaseChannel, Channel},
+};
+use tokio::{
+    sync::{watch, Mutex, Notify, Semaphore},
+    time::{interval, interval_at, timeout, Instant},
 };
-use tokio::sync::{Mutex, Notify};
 use tokio_tungstenite::tungstenite::{Error as WsError, Message};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::placement::{Placement, Rings};
+
+/// Default interval between pings to workers.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default time a task rejected by a worker is first quarantined for.
+pub const DEFAULT_QUARANTINE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Default max time a task rejected by workers is quarantined for.
+pub const DEFAULT_QUARANTINE_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
 
 /// Worker group for homogeneous workers.
 #[derive(Debug)]
@@ -44,19 +58,53 @@ impl WorkerGroup {
     /// Create a new worker group.
     #[must_use]
     pub fn new() -> Self {
+        Self::with_placement(Placement::Any)
+    }
+
+    /// Create a new worker group with given placement policy.
+    #[must_use]
+    pub fn with_placement(placement: Placement) -> Self {
+        Self::with_limits(placement, BalanceLimits::default())
+    }
+
+    /// Create a new worker group with given placement policy and limits of
+    /// balance RPCs.
+    #[must_use]
+    pub fn with_limits(placement: Placement, limits: BalanceLimits) -> Self {
+        Self::with_settings(GroupSettings {
+            placement,
+            limits,
+            ..GroupSettings::default()
+        })
+    }
+
+    /// Create a new worker group with given settings.
+    #[must_use]
+    pub fn with_settings(settings: GroupSettings) -> Self {
         let balance_notify = Arc::new(Notify::new());
-        let inner = Arc::new(Mutex::new(WorkerGroupImpl::new(balance_notify.clone())));
+        let inner = Arc::new(Mutex::new(WorkerGroupImpl::new(
+            balance_notify.clone(),
+            settings,
+        )));
 
         let task = {
             let inner = inner.clone();
             async move {
+                let mut retry_in = None;
                 loop {
-                    balance_notify.notified().await;
+                    if let Some(retry_in) = retry_in {
+                        // Balance again once the next quarantine ends, to retry the task.
+                        let _ = timeout(retry_in, balance_notify.notified()).await;
+                    } else {
+                        balance_notify.notified().await;
+                    }
 
-                    if !inner.lock().await.balance().await {
+                    let mut group = inner.lock().await;
+                    if !group.balance().await {
                         // Balance failed, schedule a balance immediately.
                         balance_notify.notify_one();
                     }
+                    retry_in = group.next_retry(SystemTime::now());
                 }
             }
         };
@@ -84,7 +132,7 @@ impl WorkerGroup {
 }
 
 /// Weak reference to a worker group.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WeakWorkerGroup {
     inner: Weak<Mutex<WorkerGroupImpl>>,
     balance_job: Weak<ScopedJoinHandle<()>>,
@@ -104,17 +152,114 @@ impl WeakWorkerGroup {
 #[derive(Debug)]
 pub(crate) struct BoundTask {
     /// Task struct.
-    task: Task,
+    pub(crate) task: Task,
     /// The worker that is currently executing the task.
     pub(crate) worker: Option<Uuid>,
+    /// Pending backfill request, sent to the first worker the task is
+    /// assigned to.
+    pub(crate) backfill_since: Option<SystemTime>,
+    /// Failure reported by the worker running the task.
+    pub(crate) failure: Option<TaskFailure>,
+    /// Liveness in heartbeats of the worker running the task.
+    pub(crate) activity: Option<TaskActivity>,
+    /// Whether the task is paused for failing too long. Paused tasks aren't
+    /// assigned to any worker until resumed.
+    pub(crate) paused: bool,
+    /// Quarantine of the task after workers rejected it, kept until a worker
+    /// accepts it.
+    pub(crate) quarantine: Option<Quarantine>,
+}
+
+impl BoundTask {
+    /// Whether the task is quarantined at `now`. Quarantined tasks aren't
+    /// assigned to any worker until the quarantine ends.
+    pub(crate) fn quarantined(&self, now: SystemTime) -> bool {
+        self.quarantine
+            .as_ref()
+            .is_some_and(|quarantine| quarantine.until > now)
+    }
+}
+
+/// Failure of a task reported by the worker running it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct TaskFailure {
+    /// Reason of the last report.
+    pub reason: String,
+    /// Worker that reported the failure last.
+    pub worker: Uuid,
+    /// Time of the first report since the task last ran normally.
+    #[serde(with = "humantime_serde")]
+    pub since: SystemTime,
+}
+
+/// Quarantine of a task rejected by workers, e.g. for invalid params.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Quarantine {
+    /// Count of rejections in a row.
+    pub rejections: u32,
+    /// Worker that rejected the task last.
+    pub worker: Uuid,
+    /// Time the task is retried at. Each rejection doubles the time between
+    /// retries, up to a cap.
+    #[serde(with = "humantime_serde")]
+    pub until: SystemTime,
+}
+
+/// Liveness of a task in heartbeats of the worker running it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct TaskActivity {
+    /// Worker that sent the heartbeats.
+    pub worker: Uuid,
+    /// Last time the task produced activity, by the clock of the worker.
+    #[serde(with = "humantime_serde")]
+    pub last_activity: Option<SystemTime>,
+    /// Count of errors of the task since it started on the worker.
+    pub errors: u64,
+    /// Time of the first heartbeat of the worker with the task, which the
+    /// SLA counts from until the task produces activity.
+    #[serde(with = "humantime_serde")]
+    pub since: SystemTime,
+    /// Whether the task produced no activity within the SLA of its kind.
+    pub stale: bool,
+}
+
+impl TaskActivity {
+    /// Time the task has been idle for at `now`.
+    #[must_use]
+    pub fn idle_for(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.last_activity.unwrap_or(self.since))
+            .unwrap_or_default()
+    }
+}
+
+/// Move of a task planned by a balance.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct TaskMove {
+    /// Task ID.
+    pub task: Uuid,
+    /// Worker the task runs on, if any.
+    pub from: Option<Uuid>,
+    /// Worker the task would run on, or `None` if it would be taken back.
+    pub to: Option<Uuid>,
 }
 
 /// Worker group implementation.
 pub struct WorkerGroupImpl {
     pub(crate) workers: HashMap<Uuid, Arc<Worker>>,
     pub(crate) tasks: HashMap<Uuid, BoundTask>,
-    ring: Ring</* worker */ Uuid>,
+    ring: Rings,
+    /// Workers excluded from the ring. Their tasks are migrated away on next
+    /// balance, but the connection is kept.
+    pub(crate) draining: HashSet<Uuid>,
     balance_notify: Arc<Notify>,
+    limits: BalanceLimits,
+    /// Interval between pings, watched by watchdogs of workers.
+    ping_interval: watch::Sender<Duration>,
+    pause_failing_after: Option<Duration>,
+    stickiness: Option<u32>,
+    activity_sla: Option<Duration>,
+    quarantine_backoff: Duration,
+    quarantine_max_backoff: Duration,
 
     #[cfg(debug_assertions)]
     poison: AtomicBool,
@@ -122,49 +267,172 @@ pub struct WorkerGroupImpl {
 
 impl Debug for WorkerGroupImpl {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let ring_debug: Vec<_> = self
-            .ring
-            .resident_ranges()
-            .map(|resident| resident.node())
-            .collect();
+        let ring_debug: Vec<_> = self.ring.workers().collect();
 
         f.debug_struct("WorkerGroupImpl")
             .field("workers", &self.workers)
             .field("tasks", &self.tasks)
             .field("ring", &ring_debug)
+            .field("draining", &self.draining)
             .finish()
     }
 }
 
-fn check_resp(
-    resp: Result<bool, RpcError>,
-    task_id: Uuid,
+/// Check the response of a batch RPC, which answers each task in order.
+fn check_batch_resp(
+    resp: Result<Vec<bool>, RpcError>,
+    task_ids: &[Uuid],
     worker_id: Uuid,
     false_msg: &str,
     err_msg: &str,
 ) -> Result<(), Uuid> {
+    let rejected = rejected_in_batch(resp, task_ids, worker_id, err_msg)?;
+    for task_id in &rejected {
+        error!(%task_id, %worker_id, false_msg);
+    }
+    rejected.is_empty().then_some(()).ok_or(worker_id)
+}
+
+/// Tasks answered `false` in the response of a batch RPC.
+///
+/// # Errors
+/// Return id of the worker if the RPC failed or the response doesn't answer
+/// each task.
+fn rejected_in_batch(
+    resp: Result<Vec<bool>, RpcError>,
+    task_ids: &[Uuid],
+    worker_id: Uuid,
+    err_msg: &str,
+) -> Result<Vec<Uuid>, Uuid> {
     match resp {
-        Ok(true) => Ok(()),
-        Ok(false) => {
-            error!(%task_id, %worker_id, false_msg);
+        Ok(oks) if oks.len() == task_ids.len() => Ok(task_ids
+            .iter()
+            .zip(oks)
+            .filter(|(_, ok)| !ok)
+            .map(|(task_id, _)| *task_id)
+            .collect()),
+        Ok(oks) => {
+            error!(
+                %worker_id,
+                expected = task_ids.len(),
+                got = oks.len(),
+                "{}: response length mismatch",
+                err_msg
+            );
             Err(worker_id)
         }
         Err(e) => {
-            error!(%task_id, %worker_id, "{}: {}", err_msg, e);
+            error!(%worker_id, "{}: {}", err_msg, e);
             Err(worker_id)
         }
     }
 }
 
+/// Worker a task is planned to run on in a balance.
+struct TaskPlacement {
+    task_id: Uuid,
+    /// Worker the task is expected on, or `None` if it runs nowhere.
+    expected: Option<Uuid>,
+    /// Current worker of the task, if the task may stay there instead of
+    /// moving to the expected one.
+    sticky: Option<Uuid>,
+}
+
+/// Time a task is quarantined for after `rejections` rejections in a row,
+/// doubling from `base` up to `max`.
+fn quarantine_backoff(base: Duration, max: Duration, rejections: u32) -> Duration {
+    let factor = 1_u32
+        .checked_shl(rejections.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    base.saturating_mul(factor).min(max)
+}
+
+/// Split tasks of each worker into batches of at most `size` tasks.
+fn batches(tasks: &HashMap<Uuid, Vec<Uuid>>, size: usize) -> impl Iterator<Item = (Uuid, &[Uuid])> {
+    tasks.iter().flat_map(move |(worker_id, task_ids)| {
+        task_ids
+            .chunks(size.max(1))
+            .map(move |batch| (*worker_id, batch))
+    })
+}
+
+/// Settings of a worker group, which can be changed while it's running.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GroupSettings {
+    /// Placement policy of tasks.
+    pub placement: Placement,
+    /// Limits of balance RPCs.
+    pub limits: BalanceLimits,
+    /// Interval between pings to workers.
+    pub ping_interval: Duration,
+    /// Pause tasks reported failing for this long. Failing tasks are never
+    /// paused if not set.
+    pub pause_failing_after: Option<Duration>,
+    /// Keep tasks on their current worker in a balance while it holds at
+    /// most this many percent more tasks than its fair share, instead of
+    /// moving them to the worker picked by the ring. Tasks always follow the
+    /// ring if not set.
+    pub stickiness: Option<u32>,
+    /// Flag tasks producing no activity for this long as stale. Tasks are
+    /// never flagged if not set.
+    pub activity_sla: Option<Duration>,
+    /// Quarantine tasks rejected by a worker for this long before retrying
+    /// them, doubled on each rejection in a row.
+    pub quarantine_backoff: Duration,
+    /// Max time a task rejected by workers is quarantined for.
+    pub quarantine_max_backoff: Duration,
+}
+
+impl Default for GroupSettings {
+    fn default() -> Self {
+        Self {
+            placement: Placement::Any,
+            limits: BalanceLimits::default(),
+            ping_interval: DEFAULT_PING_INTERVAL,
+            pause_failing_after: None,
+            stickiness: None,
+            activity_sla: None,
+            quarantine_backoff: DEFAULT_QUARANTINE_BACKOFF,
+            quarantine_max_backoff: DEFAULT_QUARANTINE_MAX_BACKOFF,
+        }
+    }
+}
+
+/// Limits of RPCs a balance issues to workers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BalanceLimits {
+    /// Max count of RPCs in flight.
+    pub concurrency: usize,
+    /// Max count of tasks added to or removed from a worker in one RPC.
+    pub batch_size: usize,
+}
+
+impl Default for BalanceLimits {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            batch_size: 256,
+        }
+    }
+}
+
 impl WorkerGroupImpl {
     /// Create a new worker group implementation.
     #[must_use]
-    pub fn new(balance_notify: Arc<Notify>) -> Self {
+    pub fn new(balance_notify: Arc<Notify>, settings: GroupSettings) -> Self {
         Self {
             workers: HashMap::new(),
             tasks: HashMap::new(),
-            ring: Ring::default(),
+            ring: Rings::new(settings.placement),
+            draining: HashSet::new(),
             balance_notify,
+            limits: settings.limits,
+            ping_interval: watch::channel(settings.ping_interval).0,
+            pause_failing_after: settings.pause_failing_after,
+            stickiness: settings.stickiness,
+            activity_sla: settings.activity_sla,
+            quarantine_backoff: settings.quarantine_backoff,
+            quarantine_max_backoff: settings.quarantine_max_backoff,
 
             #[cfg(debug_assertions)]
             poison: AtomicBool::new(false),
@@ -184,9 +452,15 @@ impl WorkerGroupImpl {
     }
 
     /// Add a new worker to the group.
-    pub fn add_worker(&mut self, worker: Arc<Worker>) {
+    ///
+    /// `running` are tasks the worker reports running, e.g. ones kept from a
+    /// lost connection, already recorded in its tasks. They are adopted if
+    /// not running on another worker meanwhile, and removed by the next
+    /// balance otherwise.
+    pub fn add_worker(&mut self, worker: Arc<Worker>, running: &HashSet<Uuid>) {
         debug!(worker_id = %worker.id, "Add worker to group");
         let id = worker.id;
+        let (zone, labels) = (worker.zone.clone(), worker.hello.labels.clone());
         if self.workers.insert(id, worker).is_some() {
             warn!(worker_id = %id, "Worker already exists in group. It might be crashed and rejoined the coordinator before a ping was sent.");
 
@@ -198,7 +472,29 @@ impl WorkerGroupImpl {
             });
         } else {
             // Add the worker to the ring.
-            self.ring.insert(id);
+            self.ring.insert(id, zone, labels);
+        }
+
+        // Bind tasks the worker already runs, so that they aren't removed and
+        // added again, nor started on another worker.
+        let mut adopted = 0;
+        for task_id in running {
+            let Some(bound_task) = self.tasks.get_mut(task_id) else {
+                continue;
+            };
+            let taken = bound_task
+                .worker
+                .is_some_and(|worker_id| worker_id != id && self.workers.contains_key(&worker_id));
+            if bound_task.paused || taken {
+                continue;
+            }
+            bound_task.worker = Some(id);
+            // The worker has run the task before, so it's backfilled already.
+            bound_task.backfill_since = None;
+            adopted += 1;
+        }
+        if !running.is_empty() {
+            debug!(worker_id = %id, adopted, reported = running.len(), "Adopt running tasks");
         }
 
         self.balance_notify.notify_one();
@@ -209,15 +505,105 @@ impl WorkerGroupImpl {
         debug!(worker_id = %id, "Remove worker from group");
         self.ring.remove(&id);
         self.workers.remove(&id);
+        self.draining.remove(&id);
+
+        self.balance_notify.notify_one();
+    }
+
+    /// Drain a worker. The worker is removed from the ring so that all its
+    /// tasks will be migrated to other workers, but its connection is kept.
+    ///
+    /// Return `false` if the worker doesn't exist or is already draining.
+    pub fn drain_worker(&mut self, id: Uuid) -> bool {
+        if !self.workers.contains_key(&id) || !self.draining.insert(id) {
+            return false;
+        }
+
+        debug!(worker_id = %id, "Drain worker");
+        self.ring.remove(&id);
+
+        self.balance_notify.notify_one();
+        true
+    }
+
+    /// Schedule a balance of the group.
+    pub fn schedule_balance(&self) {
+        self.balance_notify.notify_one();
+    }
+
+    /// Apply changed settings. Workers are kept connected.
+    pub fn apply(&mut self, settings: GroupSettings) {
+        self.set_placement(settings.placement);
+        self.set_limits(settings.limits);
+        self.set_ping_interval(settings.ping_interval);
+        self.pause_failing_after = settings.pause_failing_after;
+        self.stickiness = settings.stickiness;
+        self.activity_sla = settings.activity_sla;
+        self.quarantine_backoff = settings.quarantine_backoff;
+        self.quarantine_max_backoff = settings.quarantine_max_backoff;
+    }
+
+    /// Change the placement policy. Tasks are migrated to match it on next
+    /// balance.
+    pub fn set_placement(&mut self, placement: Placement) {
+        if *self.ring.placement() == placement {
+            return;
+        }
+
+        debug!(?placement, "Change placement of group");
+        let mut ring = Rings::new(placement);
+        for (id, worker) in &self.workers {
+            if !self.draining.contains(id) {
+                ring.insert(*id, worker.zone.clone(), worker.hello.labels.clone());
+            }
+        }
+        self.ring = ring;
 
         self.balance_notify.notify_one();
     }
 
+    /// Change the limits of RPCs issued by later balances.
+    pub fn set_limits(&mut self, limits: BalanceLimits) {
+        self.limits = limits;
+    }
+
+    /// Change the interval between pings to workers of the group, including
+    /// those already connected.
+    pub fn set_ping_interval(&self, ping_interval: Duration) {
+        self.ping_interval.send_if_modified(|current| {
+            std::mem::replace(current, ping_interval) != ping_interval
+        });
+    }
+
+    /// Watch the interval between pings to workers of the group.
+    #[must_use]
+    pub fn ping_interval(&self) -> watch::Receiver<Duration> {
+        self.ping_interval.subscribe()
+    }
+
     /// Add a task to the group.
     pub fn add_task(&mut self, task: Task) {
+        self.insert_task(task, None);
+    }
+
+    /// Add a task to the group, and request its worker to backfill activity
+    /// since given time once assigned.
+    pub fn add_task_with_backfill(&mut self, task: Task, since: SystemTime) {
+        self.insert_task(task, Some(since));
+    }
+
+    fn insert_task(&mut self, task: Task, backfill_since: Option<SystemTime>) {
         let id = task.id;
         debug!(task_id = %id, "Add task to group");
-        let bound_task = BoundTask { task, worker: None };
+        let bound_task = BoundTask {
+            task,
+            worker: None,
+            backfill_since,
+            failure: None,
+            activity: None,
+            paused: false,
+            quarantine: None,
+        };
         self.tasks.insert(id.into(), bound_task);
 
         self.balance_notify.notify_one();
@@ -231,6 +617,126 @@ impl WorkerGroupImpl {
         self.balance_notify.notify_one();
     }
 
+    /// Record the status of a task reported by a worker at `now`, and pause
+    /// the task if it has been failing for too long.
+    ///
+    /// Reports of tasks not assigned to the worker are ignored, since they
+    /// may be sent before the task migrated away.
+    pub fn report_task_status(
+        &mut self,
+        worker_id: Uuid,
+        id: Uuid,
+        status: TaskStatus,
+        now: SystemTime,
+    ) {
+        let Some(bound_task) = self.tasks.get_mut(&id) else {
+            return;
+        };
+        if bound_task.worker != Some(worker_id) {
+            debug!(task_id = %id, %worker_id, "Ignore report of task not assigned to worker");
+            return;
+        }
+
+        let TaskStatus::Failing { reason } = status else {
+            if bound_task.failure.take().is_some() {
+                info!(task_id = %id, %worker_id, "Task recovered");
+            }
+            return;
+        };
+        let failure = bound_task.failure.get_or_insert_with(|| {
+            warn!(task_id = %id, %worker_id, %reason, "Task failing");
+            TaskFailure {
+                reason: reason.clone(),
+                worker: worker_id,
+                since: now,
+            }
+        });
+        failure.reason = reason;
+        failure.worker = worker_id;
+
+        let failing_for = now.duration_since(failure.since).unwrap_or_default();
+        if matches!(self.pause_failing_after, Some(after) if failing_for >= after) {
+            warn!(task_id = %id, ?failing_for, "Pause failing task");
+            bound_task.paused = true;
+            self.balance_notify.notify_one();
+        }
+    }
+
+    /// Record liveness of tasks in a heartbeat of a worker received at `now`,
+    /// and flag tasks idle for longer than the SLA as stale.
+    ///
+    /// Tasks not assigned to the worker are ignored, like in
+    /// [`report_task_status`](Self::report_task_status).
+    pub fn record_heartbeat(
+        &mut self,
+        worker_id: Uuid,
+        liveness: Vec<TaskLiveness>,
+        now: SystemTime,
+    ) {
+        for TaskLiveness {
+            id,
+            last_activity,
+            errors,
+        } in liveness
+        {
+            let Some(bound_task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            if bound_task.worker != Some(worker_id) {
+                continue;
+            }
+
+            // Tasks moved to the worker are given the SLA from now on.
+            bound_task.activity = bound_task
+                .activity
+                .take()
+                .filter(|activity| activity.worker == worker_id);
+            let activity = bound_task.activity.get_or_insert_with(|| TaskActivity {
+                worker: worker_id,
+                last_activity: None,
+                errors: 0,
+                since: now,
+                stale: false,
+            });
+            activity.last_activity = last_activity;
+            activity.errors = errors;
+
+            let idle_for = activity.idle_for(now);
+            let stale = matches!(self.activity_sla, Some(sla) if idle_for >= sla);
+            if stale && !activity.stale {
+                warn!(task_id = %id, %worker_id, ?idle_for, errors, "Task stale");
+            } else if !stale && activity.stale {
+                info!(task_id = %id, %worker_id, "Task active again");
+            }
+            activity.stale = stale;
+        }
+    }
+
+    /// Resume a paused or quarantined task and forget its failure and
+    /// rejections. Return `false` if the task doesn't exist.
+    pub fn resume_task(&mut self, id: Uuid) -> bool {
+        let Some(bound_task) = self.tasks.get_mut(&id) else {
+            return false;
+        };
+        debug!(task_id = %id, "Resume task");
+        bound_task.failure = None;
+        bound_task.paused = false;
+        bound_task.quarantine = None;
+
+        self.balance_notify.notify_one();
+        true
+    }
+
+    /// Time from `now` until the next quarantine of a task ends, if any.
+    #[must_use]
+    pub fn next_retry(&self, now: SystemTime) -> Option<Duration> {
+        self.tasks
+            .values()
+            .filter_map(|bound_task| bound_task.quarantine.as_ref())
+            .filter_map(|quarantine| quarantine.until.duration_since(now).ok())
+            .min()
+    }
+
     /// Balance the group.
     ///
     /// Workers not responding or inconsistent will be removed. Return `false`
@@ -248,6 +754,10 @@ impl WorkerGroupImpl {
 
     /// Core implementation to balance the group.
     ///
+    /// Tasks to move are planned first, then removed from their old workers
+    /// and added to new ones in batches, with RPCs to different workers
+    /// issued concurrently.
+    ///
     /// # Errors
     /// If a worker is not responding or inconsistent, return id of that worker.
     ///
@@ -256,114 +766,381 @@ impl WorkerGroupImpl {
     async fn balance_impl(&mut self) -> Result<(), Uuid> {
         // TODO instrument this future
 
-        // Remove gone tasks.
-        for worker in self.workers.values_mut() {
-            // Note that we collect tasks_gone first to avoid holding the lock across
-            // awaits.
+        // Tasks to remove from and add to each worker.
+        let mut removals: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        let mut additions: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
 
-            // Do RPC to remove tasks from remote worker.
+        // Remove gone tasks, and ones a worker reported running on joining
+        // that are bound to another worker.
+        for worker in self.workers.values() {
             let tasks_gone: Vec<_> = worker
                 .tasks
                 .lock()
                 .await
                 .iter()
-                .filter(|task| !self.tasks.contains_key(task))
+                .filter(|task| {
+                    self.tasks
+                        .get(task)
+                        .map_or(true, |bound_task| bound_task.worker != Some(worker.id))
+                })
                 .copied()
                 .collect();
-            for task in tasks_gone {
-                // This task is gone, we remove it from the worker.
-                debug!(task_id=%task, worker_id=%worker.id, "Task is gone, remove from worker");
-                let resp = worker.client.remove_task(Context::current(), task).await;
-                check_resp(
-                    resp,
-                    task,
-                    worker.id,
-                    "Task not found on worker",
-                    "Error removing task from worker",
-                )?;
+            if !tasks_gone.is_empty() {
+                debug!(
+                    worker_id=%worker.id,
+                    count=tasks_gone.len(),
+                    "Tasks are gone, remove from worker"
+                );
+                removals.entry(worker.id).or_default().extend(tasks_gone);
             }
-
-            // Remove tasks from local map.
-            worker
-                .tasks
-                .lock()
-                .await
-                .retain(|task| self.tasks.contains_key(task));
         }
 
         if self.ring.is_empty() {
-            error!("Balance: No worker in worker group");
+            error!("Balance: No available worker in worker group");
+        }
+        let placements = self.plan(&self.ring, SystemTime::now());
 
-            // All tasks are orphaned.
-            for bound_task in self.tasks.values_mut() {
-                bound_task.worker = None;
+        for TaskPlacement {
+            task_id,
+            expected: expected_worker_id,
+            ..
+        } in placements
+        {
+            let Some(bound_task) = self.tasks.get_mut(&task_id) else {
+                continue;
+            };
+            if bound_task.worker == expected_worker_id {
+                continue;
             }
-        } else {
-            // Migrate tasks to new workers.
-            for (task_id, bound_task) in &mut self.tasks {
-                // Calculate expected worker using the ring.
-                let expected_worker_id = self.ring.get(&task_id);
-                // Currently assigned worker.
-                let bound_worker_id = &mut bound_task.worker;
 
+            // If the task has already assigned to a worker, remove it.
+            match bound_task.worker {
+                Some(old_worker_id) if self.workers.contains_key(&old_worker_id) => {
+                    removals.entry(old_worker_id).or_default().push(task_id);
+                }
+                // The worker is gone with the task.
+                _ => bound_task.worker = None,
+            }
+            if let Some(expected_worker_id) = expected_worker_id {
                 debug!(%task_id, worker_id=%expected_worker_id, "Migrating task");
+                additions
+                    .entry(expected_worker_id)
+                    .or_default()
+                    .push(task_id);
+            }
+        }
 
-                if *bound_worker_id != Some(*expected_worker_id) {
-                    // If task is not assigned to the expected worker ...
-
-                    // If the task has already assigned to a worker, remove it.
-                    if let Some(old_worker) =
-                        bound_worker_id.and_then(|id| self.workers.get_mut(&id))
-                    {
-                        // Do RPC to remove tasks from remote worker.
-                        let resp = old_worker
-                            .client
-                            .remove_task(Context::current(), *task_id)
-                            .await;
-                        check_resp(
-                            resp,
-                            *task_id,
-                            old_worker.id,
-                            "Task not found on worker",
-                            "Error removing task from worker",
-                        )?;
-
-                        // Remove tasks from local map.
-                        old_worker.tasks.lock().await.remove(task_id);
-                    }
+        // Tasks must be removed from old workers before being added to new
+        // ones, so that no task runs on two workers.
+        self.remove_from_workers(&removals).await?;
+        self.add_to_workers(&additions).await?;
 
-                    // Assign the task to the expected worker.
-                    let expected_worker = self
-                        .workers
-                        .get_mut(expected_worker_id)
-                        .expect("Migration target worker must exist");
-                    // Do RPC to add tasks to remote worker.
-                    let resp = expected_worker
+        if cfg!(debug_assertions) {
+            self.validate().await;
+        }
+
+        Ok(())
+    }
+
+    /// Tasks a balance would move if workers in `extra`, given by their id,
+    /// zone and labels, joined first. No RPC is issued and nothing changes.
+    #[must_use]
+    pub fn preview_balance(&self, extra: &[(Uuid, Option<String>, Labels)]) -> Vec<TaskMove> {
+        let mut ring = Rings::new(self.ring.placement().clone());
+        for (id, worker) in &self.workers {
+            if !self.draining.contains(id) {
+                ring.insert(*id, worker.zone.clone(), worker.hello.labels.clone());
+            }
+        }
+        for (id, zone, labels) in extra {
+            ring.insert(*id, zone.clone(), labels.clone());
+        }
+
+        let mut moves: Vec<_> = self
+            .plan(&ring, SystemTime::now())
+            .into_iter()
+            .filter_map(|placement| {
+                let from = self.tasks[&placement.task_id]
+                    .worker
+                    .filter(|worker_id| self.workers.contains_key(worker_id));
+                (from != placement.expected).then_some(TaskMove {
+                    task: placement.task_id,
+                    from,
+                    to: placement.expected,
+                })
+            })
+            .collect();
+        moves.sort_unstable_by_key(|task_move| task_move.task);
+        moves
+    }
+
+    /// Plan the worker each task should run on at `now`, picked from `ring`.
+    fn plan(&self, ring: &Rings, now: SystemTime) -> Vec<TaskPlacement> {
+        let ring_empty = ring.is_empty();
+        let mut placements = Vec::with_capacity(self.tasks.len());
+        for (task_id, bound_task) in &self.tasks {
+            let placement = if ring_empty || bound_task.paused || bound_task.quarantined(now) {
+                // All tasks are orphaned, or the task is paused or quarantined.
+                // Take them back from their workers.
+                TaskPlacement {
+                    task_id: *task_id,
+                    expected: None,
+                    sticky: None,
+                }
+            } else {
+                // Calculate expected worker using the ring.
+                let constraint = bound_task.task.placement().unwrap_or_else(|error| {
+                    warn!(%task_id, ?error, "Invalid placement constraint, ignored");
+                    None
+                });
+                let expected = *ring.get_constrained(task_id, constraint.as_ref());
+                let sticky = bound_task.worker.filter(|&worker_id| {
+                    worker_id != expected
+                        && ring.eligible(&worker_id, task_id, constraint.as_ref())
+                });
+                TaskPlacement {
+                    task_id: *task_id,
+                    expected: Some(expected),
+                    sticky,
+                }
+            };
+            placements.push(placement);
+        }
+        self.stick(ring, &mut placements);
+        placements
+    }
+
+    /// Keep tasks on their current worker instead of the one picked by the
+    /// ring, as long as the worker holds at most `stickiness` percent more
+    /// tasks than its fair share. Tasks that must move, and those already on
+    /// their expected worker, count towards the share first.
+    fn stick(&self, ring: &Rings, placements: &mut [TaskPlacement]) {
+        let Some(stickiness) = self.stickiness else {
+            return;
+        };
+        let workers = ring.workers().count();
+        let placed = placements.iter().filter(|p| p.expected.is_some()).count();
+        if workers == 0 || placed == 0 {
+            return;
+        }
+        let fair = placed.div_ceil(workers);
+        let cap = fair + fair * stickiness as usize / 100;
+
+        let mut load: HashMap<Uuid, usize> = HashMap::new();
+        for placement in placements.iter().filter(|p| p.sticky.is_none()) {
+            if let Some(worker_id) = placement.expected {
+                *load.entry(worker_id).or_default() += 1;
+            }
+        }
+        let mut kept = 0;
+        for placement in placements.iter_mut() {
+            let Some(current) = placement.sticky else {
+                continue;
+            };
+            let current_load = load.entry(current).or_default();
+            if *current_load < cap {
+                *current_load += 1;
+                placement.expected = Some(current);
+                kept += 1;
+            } else if let Some(worker_id) = placement.expected {
+                *load.entry(worker_id).or_default() += 1;
+            }
+        }
+        if kept > 0 {
+            debug!(kept, cap, "Keep tasks on their current workers");
+        }
+    }
+
+    /// Remove tasks from workers in batches, and unbind them.
+    ///
+    /// # Errors
+    /// Return id of the first worker that failed. Tasks removed from other
+    /// workers are still unbound.
+    async fn remove_from_workers(
+        &mut self,
+        removals: &HashMap<Uuid, Vec<Uuid>>,
+    ) -> Result<(), Uuid> {
+        let semaphore = Semaphore::new(self.limits.concurrency.max(1));
+        let workers = &self.workers;
+        let results = join_all(batches(removals, self.limits.batch_size).map(
+            |(worker_id, task_ids)| {
+                let worker = &workers[&worker_id];
+                let semaphore = &semaphore;
+                async move {
+                    let _permit = semaphore
+                        .acquire()
+                        .await
+                        .expect("Semaphore is never closed");
+                    // Do RPC to remove tasks from remote worker.
+                    let resp = worker
                         .client
-                        .add_task(Context::current(), bound_task.task.clone())
+                        .remove_tasks(Context::current(), task_ids.to_vec())
                         .await;
-                    check_resp(
+                    let result = check_batch_resp(
                         resp,
-                        *task_id,
-                        *expected_worker_id,
-                        "Task already exists on worker",
-                        "Error adding task to worker",
-                    )?;
+                        task_ids,
+                        worker_id,
+                        "Task not found on worker",
+                        "Error removing tasks from worker",
+                    );
+                    (worker, task_ids, result)
+                }
+            },
+        ))
+        .await;
 
-                    // Add tasks to local map.
-                    expected_worker.tasks.lock().await.insert(*task_id);
+        let mut bad_worker = Ok(());
+        for (worker, task_ids, result) in results {
+            if let Err(worker_id) = result {
+                bad_worker = bad_worker.and(Err(worker_id));
+                continue;
+            }
 
-                    // Update the task's bound info.
-                    *bound_worker_id = Some(*expected_worker_id);
+            // Remove tasks from local map.
+            let mut worker_tasks = worker.tasks.lock().await;
+            for task_id in task_ids {
+                worker_tasks.remove(task_id);
+                if let Some(bound_task) = self.tasks.get_mut(task_id) {
+                    if bound_task.worker == Some(worker.id) {
+                        bound_task.worker = None;
+                    }
                 }
             }
         }
+        bad_worker
+    }
 
-        if cfg!(debug_assertions) {
-            self.validate().await;
+    /// Add tasks to workers in batches, bind them, and request backfill for
+    /// newly added ones.
+    ///
+    /// Tasks a worker rejects are taken back from it, in case it runs them
+    /// already, and quarantined instead of failing the worker, so that a task
+    /// with e.g. invalid params doesn't get workers removed over and over.
+    ///
+    /// # Errors
+    /// Return id of the first worker that failed. Tasks added to other workers
+    /// are still bound.
+    async fn add_to_workers(&mut self, additions: &HashMap<Uuid, Vec<Uuid>>) -> Result<(), Uuid> {
+        let semaphore = Semaphore::new(self.limits.concurrency.max(1));
+        let (workers, tasks) = (&self.workers, &self.tasks);
+        let results = join_all(batches(additions, self.limits.batch_size).map(
+            |(worker_id, task_ids)| {
+                let worker = workers
+                    .get(&worker_id)
+                    .expect("Migration target worker must exist");
+                let semaphore = &semaphore;
+                async move {
+                    let _permit = semaphore
+                        .acquire()
+                        .await
+                        .expect("Semaphore is never closed");
+                    // Do RPC to add tasks to remote worker.
+                    let batch = task_ids.iter().map(|id| tasks[id].task.clone()).collect();
+                    let resp = worker.client.add_tasks(Context::current(), batch).await;
+                    let result = rejected_in_batch(
+                        resp,
+                        task_ids,
+                        worker_id,
+                        "Error adding tasks to worker",
+                    );
+                    let result = match result {
+                        Ok(rejected) if !rejected.is_empty() => {
+                            let resp = worker
+                                .client
+                                .remove_tasks(Context::current(), rejected.clone())
+                                .await;
+                            let err_msg = "Error removing rejected tasks from worker";
+                            rejected_in_batch(resp, &rejected, worker_id, err_msg).map(|_| rejected)
+                        }
+                        result => result,
+                    };
+                    (worker, task_ids, result)
+                }
+            },
+        ))
+        .await;
+
+        let mut bad_worker = Ok(());
+        let mut backfills = vec![];
+        let now = SystemTime::now();
+        for (worker, task_ids, result) in results {
+            let rejected = match result {
+                Ok(rejected) => rejected,
+                Err(worker_id) => {
+                    bad_worker = bad_worker.and(Err(worker_id));
+                    continue;
+                }
+            };
+
+            // Add tasks to local map and update their bound info.
+            let mut worker_tasks = worker.tasks.lock().await;
+            for task_id in task_ids {
+                let bound_task = self.tasks.get_mut(task_id).expect("Added task must exist");
+                if rejected.contains(task_id) {
+                    let quarantine = bound_task.quarantine.get_or_insert(Quarantine {
+                        rejections: 0,
+                        worker: worker.id,
+                        until: now,
+                    });
+                    quarantine.rejections = quarantine.rejections.saturating_add(1);
+                    quarantine.worker = worker.id;
+                    let retry_in = quarantine_backoff(
+                        self.quarantine_backoff,
+                        self.quarantine_max_backoff,
+                        quarantine.rejections,
+                    );
+                    quarantine.until = now + retry_in;
+                    warn!(
+                        %task_id,
+                        worker_id = %worker.id,
+                        rejections = quarantine.rejections,
+                        ?retry_in,
+                        "Task rejected by worker, quarantined"
+                    );
+                    continue;
+                }
+
+                worker_tasks.insert(*task_id);
+                bound_task.worker = Some(worker.id);
+                if let Some(quarantine) = bound_task.quarantine.take() {
+                    info!(
+                        %task_id,
+                        worker_id = %worker.id,
+                        rejections = quarantine.rejections,
+                        "Task accepted after quarantine"
+                    );
+                }
+
+                // Request backfill if the task is newly added.
+                if let Some(since) = bound_task.backfill_since.take() {
+                    backfills.push((worker, bound_task.task.clone(), since));
+                }
+            }
         }
 
-        Ok(())
+        // Failure of backfill is not fatal.
+        join_all(backfills.into_iter().map(|(worker, task, since)| {
+            let semaphore = &semaphore;
+            async move {
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .expect("Semaphore is never closed");
+                let task_id = task.id;
+                match worker
+                    .client
+                    .backfill(Context::current(), task, since)
+                    .await
+                {
+                    Ok(true) => debug!(%task_id, "Backfill requested"),
+                    Ok(false) => debug!(%task_id, "Backfill not supported by worker"),
+                    Err(error) => warn!(%task_id, ?error, "Failed to request backfill"),
+                }
+            }
+        }))
+        .await;
+
+        bad_worker
     }
 
     /// Validate if the internal state of the group is consistent.
@@ -391,9 +1168,11 @@ impl WorkerGroupImpl {
             tasks,
             self.tasks
                 .iter()
-                .filter_map(|(id, BoundTask { task: _, worker })| (worker.is_some()
-                    || count_unallocated_task)
-                    .then_some(id))
+                .filter_map(|(id, bound_task)| {
+                    let unallocated = !bound_task.paused && bound_task.quarantine.is_none();
+                    (bound_task.worker.is_some() || (count_unallocated_task && unallocated))
+                        .then_some(id)
+                })
                 .copied()
                 .collect(),
             "tasks are not synchronized between worker-task and task-worker maps"
@@ -409,15 +1188,12 @@ impl WorkerGroupImpl {
             unknown_workers
         );
 
-        // Ring must have the same workers as the workers map.
-        let ring_nodes: HashSet<_> = self
-            .ring
-            .resident_ranges()
-            .map(|resident| resident.node())
-            .copied()
-            .collect();
+        // Ring must have the same workers as the workers map, except draining
+        // ones.
+        let ring_nodes: HashSet<_> = self.ring.workers().copied().collect();
         assert_eq!(
-            ring_nodes, workers,
+            ring_nodes,
+            &workers - &self.draining,
             "ring nodes are not the same as workers"
         );
 
@@ -454,6 +1230,12 @@ impl WorkerGroupImpl {
 pub struct Worker {
     /// Worker ID.
     id: Uuid,
+    /// Zone the worker is in.
+    pub(crate) zone: Option<String>,
+    /// Capabilities the worker announced when joining.
+    pub(crate) hello: Hello,
+    /// Protocol version negotiated with the worker.
+    pub(crate) protocol: u32,
     /// Reference to the worker group.
     parent: WeakWorkerGroup,
     /// RPC client to the worker.
@@ -461,13 +1243,24 @@ pub struct Worker {
     /// Watchdog task.
     #[allow(dead_code)]
     watchdog_job: ScopedJoinHandle<()>,
+    /// Server of reports from the worker.
+    #[allow(dead_code)]
+    report_job: ScopedJoinHandle<()>,
     /// Tasks assigned to the worker.
     tasks: Mutex<HashSet<Uuid>>,
 }
 
 impl Worker {
     /// Create a new worker from given stream and worker group.
-    pub fn new<S>(id: Uuid, stream: S, parent: WeakWorkerGroup, config: &Config) -> Arc<Self>
+    pub fn new<S>(
+        id: Uuid,
+        zone: Option<String>,
+        hello: Hello,
+        protocol: u32,
+        stream: S,
+        parent: WeakWorkerGroup,
+        mut ping_interval: watch::Receiver<Duration>,
+    ) -> Arc<Self>
     where
         S: Stream<Item = Result<Message, WsError>>
             + Sink<Message, Error = WsError>
@@ -477,22 +1270,34 @@ impl Worker {
     {
         Arc::new_cyclic(|this: &Weak<Self>| {
             let this = this.clone();
-            let ping_interval = config.ping_interval;
             let watchdog_job = tokio::spawn(async move {
-                let mut check_interval = tokio::time::interval(ping_interval);
+                let mut check_interval = interval(*ping_interval.borrow_and_update());
                 loop {
-                    check_interval.tick().await;
+                    tokio::select! {
+                        _ = check_interval.tick() => {}
+                        Ok(()) = ping_interval.changed() => {
+                            let period = *ping_interval.borrow_and_update();
+                            check_interval = interval_at(Instant::now() + period, period);
+                            continue;
+                        }
+                    }
 
                     if let Some(this) = this.upgrade() {
-                        let tag = rand::random();
-                        let resp = this.client.ping(tarpc::context::current(), tag).await;
+                        let resp = this.client.heartbeat(tarpc::context::current()).await;
 
-                        if !matches!(resp, Ok(_tag)) {
-                            // ping failed, remove node from worker group.
-                            error!(worker_id = %this.id, "Ping failed");
+                        let Ok(liveness) = resp else {
+                            // heartbeat failed, remove node from worker group.
+                            error!(worker_id = %this.id, "Heartbeat failed");
                             this.remove_self().await;
 
                             break;
+                        };
+                        if let Some(parent) = this.parent.upgrade() {
+                            parent
+                                .with(|parent| {
+                                    parent.record_heartbeat(this.id, liveness, SystemTime::now());
+                                })
+                                .await;
                         }
                     } else {
                         // self is dropped, so we can stop the watchdog.
@@ -501,12 +1306,29 @@ impl Worker {
                 }
             });
 
+            let (worker_lane, coordinator_lane) = multiplex(stream);
+            let reports = ReportServer {
+                worker_id: id,
+                parent: parent.clone(),
+            };
+            let report_job = tokio::spawn(
+                BaseChannel::with_defaults(WsTransport::with_version(coordinator_lane, protocol))
+                    .execute(reports.serve()),
+            );
+
             Self {
                 id,
+                zone,
+                hello,
+                protocol,
                 parent,
-                client: WorkerRpcClient::new(ClientConfig::default(), WsTransport::new(stream))
-                    .spawn(),
+                client: WorkerRpcClient::new(
+                    ClientConfig::default(),
+                    WsTransport::with_version(worker_lane, protocol),
+                )
+                .spawn(),
                 watchdog_job: ScopedJoinHandle(watchdog_job),
+                report_job: ScopedJoinHandle(report_job),
                 tasks: Default::default(),
             }
         })
@@ -518,4 +1340,39 @@ impl Worker {
             parent.with(|parent| parent.remove_worker(self.id)).await;
         }
     }
+
+    /// Ask the worker for tasks it's already running, e.g. ones kept from a
+    /// lost connection, and record them as its tasks. Return their ids.
+    pub async fn fetch_tasks(&self) -> HashSet<Uuid> {
+        let running: HashSet<Uuid> = match self.client.tasks(Context::current()).await {
+            Ok(tasks) => tasks.into_iter().map(|task| task.id.into()).collect(),
+            Err(error) => {
+                warn!(worker_id = %self.id, %error, "Failed to query running tasks of worker");
+                HashSet::new()
+            }
+        };
+        self.tasks.lock().await.extend(&running);
+        running
+    }
+}
+
+/// Server of [`CoordinatorRpc`] for a worker, recording its reports in the
+/// worker group.
+#[derive(Clone)]
+struct ReportServer {
+    worker_id: Uuid,
+    parent: WeakWorkerGroup,
+}
+
+#[tarpc::server]
+impl CoordinatorRpc for ReportServer {
+    async fn report_task_status(self, _: Context, id: Uuid, status: TaskStatus) {
+        if let Some(parent) = self.parent.upgrade() {
+            parent
+                .with(|group| {
+                    group.report_task_status(self.worker_id, id, status, SystemTime::now());
+                })
+                .await;
+        }
+    }
 }
@@ -0,0 +1,199 @@
+//! End-to-end test of the task pipeline: a task inserted into MongoDB is
+//! picked up by the coordinator, dispatched to a worker over RPC, and results
+//! in events published to the message queue.
+//!
+//! The worker speaks the real protocol but reads from a fake platform source,
+//! and ids are fixed, so the published events are the same on every run.
+
+use std::{
+    collections::HashMap,
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use coordinator::{app::App, config::Config, db::DB};
+use futures_util::StreamExt;
+use mongodb::{bson::doc, Client, Collection};
+use serde_json::json;
+use sg_core::{
+    models::{Event, Task},
+    mq::{mock::MockMQ, MessageQueue, Middlewares},
+    protocol::{add_each, remove_each, TaskLiveness, WorkerRpc, WorkerRpcExt},
+    utils::{Redacted, ScopedJoinHandle},
+};
+use tarpc::context::Context;
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
+
+const MONGO_URI: &str = "mongodb://localhost:27017/";
+const KIND: &str = "fake";
+const TASK_ID: Uuid = Uuid::from_u128(0x5167_0000_0000_0000_0000_0000_0000_0001);
+const ENTITY_ID: Uuid = Uuid::from_u128(0x5167_0000_0000_0000_0000_0000_0000_0002);
+
+/// Items the fake platform yields for every task.
+const ITEMS: [&str; 3] = ["first", "second", "third"];
+
+/// Event a worker publishes for the `seq`-th item of a task.
+fn item_event(task: &Task, seq: usize) -> Event {
+    let id = Uuid::from_u128(Uuid::from(task.id).as_u128() + seq as u128 + 1);
+    Event::from_serializable_with_id(id, &task.kind, task.entity, json!({ "text": ITEMS[seq] }))
+        .unwrap()
+}
+
+/// Tasks running on a worker, with handles of their sources.
+type RunningTasks = HashMap<Uuid, (Task, ScopedJoinHandle<()>)>;
+
+/// A worker that publishes an event for each item of the fake platform source
+/// when a task is added.
+#[derive(Clone)]
+struct FakeWorker {
+    mq: Arc<dyn MessageQueue>,
+    tasks: Arc<Mutex<RunningTasks>>,
+}
+
+impl FakeWorker {
+    fn new(mq: Arc<dyn MessageQueue>) -> Self {
+        Self {
+            mq,
+            tasks: Default::default(),
+        }
+    }
+}
+
+#[tarpc::server]
+impl WorkerRpc for FakeWorker {
+    async fn heartbeat(self, _: Context) -> Vec<TaskLiveness> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|id| TaskLiveness::new(*id))
+            .collect()
+    }
+
+    async fn add_task(self, _: Context, task: Task) -> bool {
+        let handle = {
+            let mq = self.mq.clone();
+            let task = task.clone();
+            ScopedJoinHandle(tokio::spawn(async move {
+                for seq in 0..ITEMS.len() {
+                    mq.publish(item_event(&task, seq), Middlewares::default())
+                        .await
+                        .unwrap();
+                }
+            }))
+        };
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert(task.id.into(), (task, handle))
+            .is_none()
+    }
+
+    async fn remove_task(self, _: Context, id: Uuid) -> bool {
+        self.tasks.lock().unwrap().remove(&id).is_some()
+    }
+
+    async fn add_tasks(self, ctx: Context, tasks: Vec<Task>) -> Vec<bool> {
+        add_each(self, ctx, tasks).await
+    }
+
+    async fn remove_tasks(self, ctx: Context, ids: Vec<Uuid>) -> Vec<bool> {
+        remove_each(self, ctx, ids).await
+    }
+
+    async fn tasks(self, _: Context) -> Vec<Task> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(task, _)| task.clone())
+            .collect()
+    }
+
+    async fn backfill(self, _: Context, _: Task, _: SystemTime) -> bool {
+        true
+    }
+}
+
+fn free_port() -> u16 {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn must_publish_events_of_new_task() {
+    let port = free_port();
+    let config = Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        ping_interval: Duration::from_millis(100),
+        mongo_uri: Redacted(String::from(MONGO_URI)),
+        mongo_db: String::from("test"),
+        mongo_collection: String::from("coordinator_pipeline"),
+        ..Default::default()
+    };
+    let collection: Collection<Task> = Client::with_uri_str(MONGO_URI)
+        .await
+        .unwrap()
+        .database(&config.mongo_db)
+        .collection(&config.mongo_collection);
+    collection.drop(None).await.unwrap();
+
+    // Start the coordinator with an empty collection.
+    let app = App::new(config.clone());
+    let mut db = DB::new(app.clone(), config).await.unwrap();
+    db.init_tasks().await.unwrap();
+    let _server = ScopedJoinHandle(tokio::spawn(async move {
+        app.serve().await.unwrap();
+    }));
+    let _watcher = ScopedJoinHandle(tokio::spawn(async move {
+        db.watch_tasks().await.unwrap();
+    }));
+    sleep(Duration::from_millis(100)).await;
+
+    // Join a worker.
+    let mq: Arc<dyn MessageQueue> = Arc::new(MockMQ::default());
+    let mut consumer = mq.consume(None, None).await;
+    let worker = FakeWorker::new(mq.clone());
+    let _worker = {
+        let worker = worker.clone();
+        ScopedJoinHandle(tokio::spawn(async move {
+            worker
+                .join(format!("ws://127.0.0.1:{}", port), Uuid::new_v4(), KIND)
+                .await
+                .unwrap();
+        }))
+    };
+    sleep(Duration::from_millis(150)).await;
+
+    // Add a task in the database.
+    let task = Task {
+        id: TASK_ID.into(),
+        entity: ENTITY_ID.into(),
+        kind: String::from(KIND),
+        params: Default::default(),
+    };
+    collection.insert_one(&task, None).await.unwrap();
+
+    // Events of all items must be published in order.
+    for seq in 0..ITEMS.len() {
+        let (middlewares, event, _) = timeout(Duration::from_secs(5), consumer.next())
+            .await
+            .expect("Event not published in time")
+            .unwrap()
+            .unwrap();
+        assert!(middlewares.is_empty());
+        assert_eq!(event.entity, task.entity);
+        assert_eq!(event.kind, KIND);
+        assert_eq!(event, item_event(&task, seq));
+    }
+
+    // The task must be removed from the worker once deleted.
+    collection
+        .delete_one(doc! { "id": task.id }, None)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert!(worker.tasks.lock().unwrap().is_empty());
+}
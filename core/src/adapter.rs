@@ -1,23 +1,53 @@
 //! Transport adapter.
 use std::{
     marker::PhantomData,
-    pin::Pin,
+    pin::{pin, Pin},
     task::{Context, Poll},
 };
 
-use futures_util::{ready, sink::Sink, SinkExt, Stream, StreamExt};
+use futures_channel::mpsc;
+use futures_util::{
+    future,
+    ready,
+    sink::Sink,
+    stream::select_all,
+    SinkExt,
+    Stream,
+    StreamExt,
+};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio_tungstenite::tungstenite::{Error, Message};
+use tracing::debug;
+
+use crate::{compat::Shim, error::TransportError, protocol::PROTOCOL_VERSION};
+
+/// Max count of messages buffered in each direction of a [`LongPoll`] pair.
+const LONG_POLL_BUFFER: usize = 64;
 
-use crate::error::TransportError;
+/// Tag of messages of [`WorkerRpc`](crate::protocol::WorkerRpc), called by
+/// coordinators.
+pub const WORKER_LANE: u8 = b'w';
+
+/// Tag of messages of [`CoordinatorRpc`](crate::protocol::CoordinatorRpc),
+/// called by workers.
+pub const COORDINATOR_LANE: u8 = b'c';
 
 /// A transport adapter that implements `Transport` for Websocket stream.
-pub struct WsTransport<S, Item>(S, PhantomData<Item>);
+///
+/// Messages are rewritten by a [`Shim`] if the connection speaks an older
+/// protocol version.
+pub struct WsTransport<S, Item>(S, Shim, PhantomData<Item>);
 
 impl<S, Item> WsTransport<S, Item> {
-    /// Create a new `WsTransport`.
+    /// Create a new `WsTransport`, speaking the current protocol version.
     pub const fn new(stream: S) -> Self {
-        Self(stream, PhantomData)
+        Self::with_version(stream, PROTOCOL_VERSION)
+    }
+
+    /// Create a new `WsTransport`, speaking the protocol version negotiated
+    /// when the worker joined.
+    pub const fn with_version(stream: S, version: u32) -> Self {
+        Self(stream, Shim::new(version), PhantomData)
     }
 }
 
@@ -33,7 +63,7 @@ where
         Poll::Ready(match ready!(self.0.poll_next_unpin(cx)) {
             Some(Ok(e)) => {
                 if let Message::Binary(data) = e {
-                    Some(Ok(serde_json::from_slice(&data)?))
+                    Some(Ok(self.1.decode(&data)?))
                 } else {
                     return Poll::Pending;
                 }
@@ -57,7 +87,7 @@ where
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> Result<(), Self::Error> {
-        let item = serde_json::to_vec(&item)?;
+        let item = self.1.encode(&item)?;
         Ok(self.0.start_send_unpin(Message::Binary(item))?)
     }
 
@@ -70,15 +100,294 @@ where
     }
 }
 
+/// One end of an in-memory message stream, standing in for a websocket stream
+/// when messages are carried by HTTP long polling.
+///
+/// One end is given to [`WsTransport`] like a websocket stream, and the other
+/// is pumped by HTTP requests. Closing or dropping either end ends the stream
+/// of the other.
+#[derive(Debug)]
+pub struct LongPoll {
+    rx: mpsc::Receiver<Message>,
+    tx: mpsc::Sender<Message>,
+}
+
+impl LongPoll {
+    /// Create both ends of a stream.
+    #[must_use]
+    pub fn pair() -> (Self, Self) {
+        let (tx_a, rx_a) = mpsc::channel(LONG_POLL_BUFFER);
+        let (tx_b, rx_b) = mpsc::channel(LONG_POLL_BUFFER);
+        (Self { rx: rx_a, tx: tx_b }, Self { rx: rx_b, tx: tx_a })
+    }
+}
+
+impl Stream for LongPoll {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx).map(|msg| msg.map(Ok))
+    }
+}
+
+impl Sink<Message> for LongPoll {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.tx.poll_ready(cx).map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.tx
+            .start_send(item)
+            .map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Carry messages of two lanes over one websocket stream, so that both sides
+/// of a connection can serve RPCs of the other.
+///
+/// Binary messages are prefixed with the tag of their lane, and messages of
+/// unknown lanes are dropped. Return the ends of the [`WORKER_LANE`] and the
+/// [`COORDINATOR_LANE`], which are given to [`WsTransport`] like the stream
+/// itself, and end once it closes.
+pub fn multiplex<S>(stream: S) -> (LongPoll, LongPoll)
+where
+    S: Stream<Item = Result<Message, Error>>
+        + Sink<Message, Error = Error>
+        + Unpin
+        + Send
+        + 'static,
+{
+    let (worker, worker_remote) = LongPoll::pair();
+    let (coordinator, coordinator_remote) = LongPoll::pair();
+    tokio::spawn(pump_lanes(
+        stream,
+        [
+            (WORKER_LANE, worker_remote),
+            (COORDINATOR_LANE, coordinator_remote),
+        ],
+    ));
+    (worker, coordinator)
+}
+
+/// Move messages between the stream and remote ends of lanes, until the stream
+/// or all lanes close.
+async fn pump_lanes<S>(stream: S, lanes: [(u8, LongPoll); 2])
+where
+    S: Stream<Item = Result<Message, Error>> + Sink<Message, Error = Error> + Unpin,
+{
+    let (mut sink, mut stream) = stream.split();
+    let mut senders = Vec::with_capacity(lanes.len());
+    let mut receivers = Vec::with_capacity(lanes.len());
+    for (tag, lane) in lanes {
+        let (tx, rx) = lane.split();
+        senders.push((tag, tx));
+        receivers.push(rx.map(move |msg| (tag, msg)));
+    }
+
+    let incoming = async {
+        while let Some(Ok(msg)) = stream.next().await {
+            let Message::Binary(mut data) = msg else {
+                continue;
+            };
+            if data.is_empty() {
+                continue;
+            }
+            let tag = data.remove(0);
+            match senders.iter_mut().find(|(lane, _)| *lane == tag) {
+                // Lanes closed on this side just drop their messages.
+                Some((_, tx)) => drop(tx.send(Message::Binary(data)).await),
+                None => debug!(tag, "Message of unknown lane dropped"),
+            }
+        }
+    };
+    let outgoing = async {
+        let mut receivers = select_all(receivers);
+        while let Some((tag, Ok(msg))) = receivers.next().await {
+            let Message::Binary(mut data) = msg else {
+                continue;
+            };
+            data.insert(0, tag);
+            if sink.send(Message::Binary(data)).await.is_err() {
+                return;
+            }
+        }
+        drop(sink.close().await);
+    };
+    future::select(pin!(incoming), pin!(outgoing)).await;
+}
+
+/// Encode binary messages as the body of a long polling request, one per
+/// line. Other messages are skipped.
+///
+/// Messages written by [`WsTransport`] are compact JSON, which never contains
+/// a raw line break.
+#[must_use]
+pub fn encode_batch(messages: impl IntoIterator<Item = Message>) -> Vec<u8> {
+    let mut body = Vec::new();
+    for msg in messages {
+        if let Message::Binary(data) = msg {
+            body.extend_from_slice(&data);
+            body.push(b'\n');
+        }
+    }
+    body
+}
+
+/// Decode the body of a long polling request into binary messages.
+#[must_use]
+pub fn decode_batch(body: &[u8]) -> Vec<Message> {
+    body.split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| Message::Binary(line.to_vec()))
+        .collect()
+}
+
+#[cfg(feature = "long-poll")]
+mod long_poll {
+    use std::{pin::pin, time::Duration};
+
+    use eyre::Result;
+    use futures_util::{future, FutureExt, SinkExt, StreamExt};
+    use reqwest::{Client, StatusCode, Url};
+    use tokio_tungstenite::tungstenite::{handshake::client::Request, Message};
+    use tracing::{debug, warn};
+
+    use crate::{
+        adapter::{decode_batch, encode_batch, LongPoll},
+        protocol::{negotiated, refused},
+        utils::RetryPolicy,
+    };
+
+    /// Retries of failed polls before giving up the session. Jittered so that
+    /// workers cut off together don't hit the coordinator together.
+    fn poll_retry() -> RetryPolicy {
+        RetryPolicy::new(2)
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(4))
+            .with_jitter(0.5)
+    }
+
+    impl LongPoll {
+        /// Join a coordinator over HTTP long polling, with the URL and headers
+        /// of the given request. Return the local end of the stream, and the
+        /// protocol version negotiated.
+        ///
+        /// # Errors
+        /// Returns an error if the coordinator refuses to open a session.
+        pub async fn connect(req: Request) -> Result<(Self, u32)> {
+            let mut base = Url::parse(&req.uri().to_string())?;
+            if !base.path().ends_with('/') {
+                base.set_path(&format!("{}/", base.path()));
+            }
+
+            let http = Client::new();
+            let resp = http
+                .post(base.join("join")?)
+                .headers(req.headers().clone())
+                .send()
+                .await?;
+            let status = resp.status();
+            let version = negotiated(resp.headers());
+            let session = resp.text().await?;
+            if !status.is_success() {
+                return Err(refused(status, &session));
+            }
+            let version = version?;
+            let url = base.join(&format!("sessions/{}", session.trim()))?;
+            debug!(%url, "Long polling session opened");
+
+            let (local, remote) = Self::pair();
+            tokio::spawn(pump(http, url, remote));
+            Ok((local, version))
+        }
+    }
+
+    /// Move messages between the remote end of a stream and the coordinator,
+    /// until either side closes.
+    async fn pump(http: Client, url: Url, remote: LongPoll) {
+        let (mut sink, mut stream) = remote.split();
+
+        let poll = async {
+            let retry = poll_retry();
+            loop {
+                match retry.run(|| poll_once(&http, &url)).await {
+                    Ok(Some(messages)) => {
+                        for msg in messages {
+                            if sink.send(msg).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        debug!("Long polling session closed by coordinator");
+                        return;
+                    }
+                    Err(error) => {
+                        warn!(?error, "Failed to poll coordinator, giving up");
+                        return;
+                    }
+                }
+            }
+        };
+        let push = async {
+            while let Some(Ok(first)) = stream.next().await {
+                let mut batch = vec![first];
+                while let Some(Some(Ok(msg))) = stream.next().now_or_never() {
+                    batch.push(msg);
+                }
+                let r = http
+                    .post(url.clone())
+                    .body(encode_batch(batch))
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status);
+                if let Err(error) = r {
+                    warn!(?error, "Failed to send messages to coordinator");
+                    return;
+                }
+            }
+        };
+        future::select(pin!(poll), pin!(push)).await;
+
+        // Leave the session in case it's the worker that quits.
+        drop(http.delete(url).send().await);
+    }
+
+    /// Wait for messages from the coordinator. Return `None` if the session is
+    /// gone.
+    async fn poll_once(http: &Client, url: &Url) -> reqwest::Result<Option<Vec<Message>>> {
+        let resp = http.get(url.clone()).send().await?;
+        if matches!(resp.status(), StatusCode::NOT_FOUND | StatusCode::GONE) {
+            return Ok(None);
+        }
+        let body = resp.error_for_status()?.bytes().await?;
+        Ok(Some(decode_batch(&body)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(dead_code)]
 
+    use futures_util::{SinkExt, StreamExt};
     use tarpc::{ClientMessage, Response, Transport};
     use tokio::net::TcpStream;
-    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 
-    use crate::adapter::WsTransport;
+    use crate::adapter::{decode_batch, encode_batch, multiplex, LongPoll, WsTransport};
 
     const fn assert_transport<T>()
     where
@@ -89,5 +398,63 @@ mod tests {
     #[test]
     const fn must_adapter_transport() {
         assert_transport::<WsTransport<WebSocketStream<TcpStream>, _>>();
+        assert_transport::<WsTransport<LongPoll, _>>();
+    }
+
+    #[tokio::test]
+    async fn must_long_poll_pair() {
+        let (mut a, mut b) = LongPoll::pair();
+        a.send(Message::Binary(b"ping".to_vec())).await.unwrap();
+        assert_eq!(
+            b.next().await.unwrap().unwrap(),
+            Message::Binary(b"ping".to_vec())
+        );
+
+        a.close().await.unwrap();
+        assert!(b.next().await.is_none());
+        drop(b);
+        assert!(a.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn must_multiplex() {
+        let (a, b) = LongPoll::pair();
+        let (mut worker_a, mut coordinator_a) = multiplex(a);
+        let (mut worker_b, mut coordinator_b) = multiplex(b);
+
+        worker_a.send(Message::Binary(b"ping".to_vec())).await.unwrap();
+        coordinator_b.send(Message::Binary(b"report".to_vec())).await.unwrap();
+        assert_eq!(
+            worker_b.next().await.unwrap().unwrap(),
+            Message::Binary(b"ping".to_vec())
+        );
+        assert_eq!(
+            coordinator_a.next().await.unwrap().unwrap(),
+            Message::Binary(b"report".to_vec())
+        );
+
+        // Lanes end once the other side is gone.
+        drop((worker_b, coordinator_b));
+        assert!(worker_a.next().await.is_none());
+        assert!(coordinator_a.next().await.is_none());
+    }
+
+    #[test]
+    fn must_encode_batch() {
+        let messages = vec![
+            Message::Binary(br#"{"a":"x\ny"}"#.to_vec()),
+            Message::Ping(vec![]),
+            Message::Binary(b"[]".to_vec()),
+        ];
+        let body = encode_batch(messages);
+        assert_eq!(body, b"{\"a\":\"x\\ny\"}\n[]\n");
+        assert_eq!(
+            decode_batch(&body),
+            [
+                Message::Binary(br#"{"a":"x\ny"}"#.to_vec()),
+                Message::Binary(b"[]".to_vec())
+            ]
+        );
+        assert!(decode_batch(b"").is_empty());
     }
 }
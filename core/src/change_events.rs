@@ -0,0 +1,403 @@
+//! Typed changes of tasks in the database.
+//!
+//! Workers usually receive their tasks from the coordinator. Standalone
+//! workers, e.g. ones sharing tasks among themselves, follow the task
+//! collection directly instead. [`TaskChanges`] turns the `MongoDB` change
+//! stream of the collection into [`TaskChange`]s, resuming after the last
+//! change if the stream breaks, and from a [`Checkpoint`] across restarts if
+//! given a [`CheckpointStore`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use eyre::Result;
+use futures_util::{stream, Stream, StreamExt};
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document, Uuid},
+    change_stream::{
+        event::{ChangeStreamEvent, OperationType, ResumeToken},
+        ChangeStream,
+    },
+    options::{ChangeStreamOptions, FullDocumentType, ReplaceOptions},
+    Collection,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::models::{InDB, Task};
+
+/// Collection of [`MongoCheckpointStore`].
+const COLLECTION: &str = "task_checkpoints";
+
+/// Delay before reopening a broken change stream.
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+type Changes = ChangeStream<ChangeStreamEvent<InDB<Task>>>;
+
+/// Change of a task in the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskChange {
+    /// A task was added.
+    Added(Task),
+    /// A task was changed, and is to be restarted with its new content.
+    Updated(Task),
+    /// The task with the id was removed.
+    Removed(Uuid),
+}
+
+/// Tasks known after the last change, and where the change stream is at.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Position of the last change, to resume the change stream after.
+    pub resume_token: Option<ResumeToken>,
+    /// Known tasks with their `ObjectId`s, by which removals are reported.
+    pub tasks: Vec<(ObjectId, Task)>,
+}
+
+/// Storage of checkpoints, so that changes can be followed across restarts.
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    /// Load the last saved checkpoint, if any.
+    ///
+    /// # Errors
+    /// Returns an error if the checkpoint can't be read.
+    async fn load(&self) -> Result<Option<Checkpoint>>;
+    /// Save a checkpoint, replacing the last one.
+    ///
+    /// # Errors
+    /// Returns an error if the checkpoint can't be written.
+    async fn save(&self, checkpoint: &Checkpoint) -> Result<()>;
+}
+
+/// Checkpoints stored in the `task_checkpoints` collection, one document for
+/// each name.
+pub struct MongoCheckpointStore {
+    collection: Collection<Document>,
+    name: String,
+}
+
+impl MongoCheckpointStore {
+    /// Store checkpoints in `db` under `name`, e.g. the id of the worker.
+    #[must_use]
+    pub fn new(db: &Database, name: impl Into<String>) -> Self {
+        Self {
+            collection: db.collection(COLLECTION),
+            name: name.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for MongoCheckpointStore {
+    async fn load(&self) -> Result<Option<Checkpoint>> {
+        let checkpoint = self
+            .collection
+            .find_one(doc! { "_id": &self.name }, None)
+            .await?;
+        Ok(checkpoint.map(bson::from_document).transpose()?)
+    }
+
+    async fn save(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let mut document = bson::to_document(checkpoint)?;
+        document.insert("_id", &self.name);
+        self.collection
+            .replace_one(
+                doc! { "_id": &self.name },
+                document,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Changes of tasks in a collection, starting with all tasks in it as added.
+pub struct TaskChanges {
+    collection: Collection<InDB<Task>>,
+    store: Option<Box<dyn CheckpointStore>>,
+    /// Tasks as of the last change.
+    tasks: HashMap<ObjectId, Task>,
+    resume_token: Option<ResumeToken>,
+    changes: Option<Changes>,
+    /// Changes not yet taken.
+    pending: VecDeque<TaskChange>,
+    loaded: bool,
+}
+
+impl TaskChanges {
+    /// Follow changes of tasks in `collection`.
+    #[must_use]
+    pub fn new(collection: Collection<InDB<Task>>) -> Self {
+        Self {
+            collection,
+            store: None,
+            tasks: HashMap::new(),
+            resume_token: None,
+            changes: None,
+            pending: VecDeque::new(),
+            loaded: false,
+        }
+    }
+
+    /// Save a checkpoint to `store` after each change, and start from the
+    /// saved one if there's any.
+    ///
+    /// Tasks of the checkpoint are reported as added first, followed by
+    /// changes since, so the checkpoint need not be saved in step with
+    /// changes taken.
+    #[must_use]
+    pub fn with_checkpoints(mut self, store: impl CheckpointStore + 'static) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// Wait for the next change. Failures of the database are logged and
+    /// retried.
+    pub async fn next_change(&mut self) -> TaskChange {
+        if !self.loaded {
+            self.load().await;
+        }
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return change;
+            }
+            let Some(changes) = &mut self.changes else {
+                match self.open().await {
+                    Ok(changes) => {
+                        info!("Watching database for task changes");
+                        self.changes = Some(changes);
+                        self.save().await;
+                    }
+                    Err(error) => {
+                        error!(?error, "Failed to watch database for task changes");
+                        sleep(REOPEN_DELAY).await;
+                    }
+                }
+                continue;
+            };
+            match changes.next().await {
+                Some(Ok(event)) => {
+                    let resume_token = changes.resume_token();
+                    if self.apply(event) {
+                        self.resume_token = None;
+                        self.changes = None;
+                    } else {
+                        self.resume_token = resume_token;
+                    }
+                    self.save().await;
+                }
+                Some(Err(error)) => {
+                    error!(?error, "Change stream broken");
+                    self.changes = None;
+                    sleep(REOPEN_DELAY).await;
+                }
+                None => {
+                    self.changes = None;
+                    sleep(REOPEN_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Changes as an endless stream.
+    pub fn into_stream(self) -> impl Stream<Item = TaskChange> {
+        stream::unfold(self, |mut changes| async move {
+            let change = changes.next_change().await;
+            Some((change, changes))
+        })
+    }
+
+    /// Start from the saved checkpoint, if any.
+    async fn load(&mut self) {
+        self.loaded = true;
+        let Some(store) = &self.store else {
+            return;
+        };
+        match store.load().await {
+            Ok(Some(checkpoint)) => {
+                info!(tasks = checkpoint.tasks.len(), "Resuming from checkpoint");
+                self.resume_token = checkpoint.resume_token;
+                for (oid, task) in checkpoint.tasks {
+                    self.pending.push_back(TaskChange::Added(task.clone()));
+                    self.tasks.insert(oid, task);
+                }
+            }
+            Ok(None) => {}
+            Err(error) => warn!(?error, "Failed to load checkpoint, starting over"),
+        }
+    }
+
+    /// Save a checkpoint of the current state, if there's a store.
+    async fn save(&self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let checkpoint = Checkpoint {
+            resume_token: self.resume_token.clone(),
+            tasks: self
+                .tasks
+                .iter()
+                .map(|(oid, task)| (*oid, task.clone()))
+                .collect(),
+        };
+        if let Err(error) = store.save(&checkpoint).await {
+            warn!(?error, "Failed to save checkpoint");
+        }
+    }
+
+    /// Open a change stream, resumed after the last change if possible.
+    /// Otherwise diff tasks in the database against known ones after opening
+    /// a new one, so that no change is missed in between.
+    async fn open(&mut self) -> Result<Changes> {
+        let options = |start_after| {
+            ChangeStreamOptions::builder()
+                .full_document(Some(FullDocumentType::UpdateLookup))
+                .start_after(start_after)
+                .build()
+        };
+
+        if let Some(token) = self.resume_token.take() {
+            match self.collection.watch(None, options(Some(token))).await {
+                Ok(changes) => {
+                    self.resume_token = changes.resume_token();
+                    return Ok(changes);
+                }
+                Err(error) => warn!(?error, "Failed to resume change stream"),
+            }
+        }
+
+        let changes = self.collection.watch(None, options(None)).await?;
+        let mut desired = HashMap::new();
+        let mut tasks = self.collection.find(None, None).await?;
+        while let Some(task) = tasks.next().await {
+            let task = task?;
+            desired.insert(task.id(), task.inner());
+        }
+        self.pending.extend(diff(&self.tasks, &desired));
+        self.tasks = desired;
+        self.resume_token = changes.resume_token();
+        Ok(changes)
+    }
+
+    /// Apply a change to known tasks, queueing it if anything changed. Return
+    /// whether the stream is invalidated.
+    fn apply(&mut self, event: ChangeStreamEvent<InDB<Task>>) -> bool {
+        match event.operation_type {
+            OperationType::Insert | OperationType::Update | OperationType::Replace => {
+                let Some(task) = event.full_document else {
+                    // Deleted before the lookup, and removed on the delete event.
+                    return false;
+                };
+                let oid = task.id();
+                let task = task.inner();
+                match self.tasks.insert(oid, task.clone()) {
+                    None => self.pending.push_back(TaskChange::Added(task)),
+                    Some(old) if old != task => self.pending.push_back(TaskChange::Updated(task)),
+                    Some(_) => {}
+                }
+            }
+            OperationType::Delete => {
+                let Some(key) = event.document_key else {
+                    return false;
+                };
+                match bson::from_document::<InDB<()>>(key) {
+                    Ok(task) => {
+                        if let Some(task) = self.tasks.remove(&task.id()) {
+                            self.pending.push_back(TaskChange::Removed(task.id));
+                        }
+                    }
+                    Err(error) => error!(?error, "Deleted task without `_id`"),
+                }
+            }
+            OperationType::Invalidate => {
+                error!("Change stream invalidated");
+                return true;
+            }
+            ty => error!("Unexpected event type: {:?}", ty),
+        }
+        false
+    }
+}
+
+/// Changes from `known` tasks to `desired` ones, removals first so that a
+/// task replaced under a new `ObjectId` ends up added.
+fn diff(known: &HashMap<ObjectId, Task>, desired: &HashMap<ObjectId, Task>) -> Vec<TaskChange> {
+    let removed = known
+        .iter()
+        .filter(|(oid, _)| !desired.contains_key(oid))
+        .map(|(_, task)| TaskChange::Removed(task.id));
+    let changed = desired
+        .iter()
+        .filter_map(|(oid, task)| match known.get(oid) {
+            None => Some(TaskChange::Added(task.clone())),
+            Some(old) if old != task => Some(TaskChange::Updated(task.clone())),
+            Some(_) => None,
+        });
+    removed.chain(changed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mongodb::bson::{self, oid::ObjectId, Uuid};
+    use serde_json::Map;
+
+    use crate::{
+        change_events::{diff, Checkpoint, TaskChange},
+        models::Task,
+    };
+
+    fn task(kind: &str) -> Task {
+        Task {
+            id: Uuid::new(),
+            entity: Uuid::new(),
+            kind: kind.to_string(),
+            params: Map::new(),
+        }
+    }
+
+    #[test]
+    fn must_diff() {
+        let (kept, updated, removed) = (task("twitter"), task("youtube"), task("bililive"));
+        let (kept_oid, updated_oid) = (ObjectId::new(), ObjectId::new());
+        let known = HashMap::from([
+            (kept_oid, kept.clone()),
+            (updated_oid, updated.clone()),
+            (ObjectId::new(), removed.clone()),
+        ]);
+
+        let changed = Task {
+            kind: "twitter".to_string(),
+            ..updated
+        };
+        let added = task("twitter");
+        let desired = HashMap::from([
+            (kept_oid, kept),
+            (updated_oid, changed.clone()),
+            (ObjectId::new(), added.clone()),
+        ]);
+
+        let changes = diff(&known, &desired);
+        assert_eq!(changes[0], TaskChange::Removed(removed.id), "Removals come first");
+        assert_eq!(changes.len(), 3);
+        assert!(changes.contains(&TaskChange::Updated(changed)));
+        assert!(changes.contains(&TaskChange::Added(added)));
+        assert!(diff(&desired, &desired).is_empty());
+    }
+
+    #[test]
+    fn must_store_checkpoint() {
+        let checkpoint = Checkpoint {
+            resume_token: None,
+            tasks: vec![(ObjectId::new(), task("twitter"))],
+        };
+        let document = bson::to_document(&checkpoint).unwrap();
+        assert_eq!(bson::from_document::<Checkpoint>(document).unwrap(), checkpoint);
+    }
+}
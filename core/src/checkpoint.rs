@@ -0,0 +1,146 @@
+//! Per task checkpoints of workers.
+//!
+//! Polling workers remember where they're at for each task, e.g. the id of
+//! the last seen item, so that they pick up from there after a restart
+//! instead of skipping or repeating items. Checkpoints of tasks not running
+//! anymore expire after a while.
+
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use eyre::{bail, Result, WrapErr};
+use mongodb::{
+    bson::{doc, DateTime, Document, Uuid},
+    options::{IndexOptions, UpdateOptions},
+    Client,
+    Collection,
+    IndexModel,
+};
+use serde_json::Value;
+
+use crate::utils::Redacted;
+
+/// Collection of [`MongoTaskCheckpointStore`].
+const COLLECTION: &str = "worker_checkpoints";
+
+/// Storage of checkpoints by task id.
+#[async_trait]
+pub trait TaskCheckpointStore: Send + Sync {
+    /// Get the checkpoint of a task, if any and not expired.
+    ///
+    /// # Errors
+    /// Returns an error if the checkpoint can't be read.
+    async fn get(&self, task_id: &Uuid) -> Result<Option<Value>>;
+    /// Set the checkpoint of a task, replacing the last one and renewing its
+    /// expiry.
+    ///
+    /// # Errors
+    /// Returns an error if the checkpoint can't be written.
+    async fn set(&self, task_id: &Uuid, value: &Value) -> Result<()>;
+}
+
+/// Checkpoints stored in the `worker_checkpoints` collection of MongoDB, one
+/// document for each task, and removed once not set for `ttl`.
+pub struct MongoTaskCheckpointStore {
+    collection: Collection<Document>,
+    ttl: Duration,
+}
+
+impl MongoTaskCheckpointStore {
+    /// Connect to the database named in `uri`, e.g.
+    /// `mongodb://localhost:27017/stargazer-reborn`.
+    ///
+    /// # Errors
+    /// Returns an error if the uri has no database or the database is
+    /// unreachable.
+    pub async fn new(uri: &str, ttl: Duration) -> Result<Self> {
+        let client = Client::with_uri_str(uri)
+            .await
+            .wrap_err_with(|| format!("Failed to connect to MongoDB at {}", Redacted(uri)))?;
+        let Some(db) = client.default_database() else {
+            bail!("No database in MongoDB uri of the checkpoint store");
+        };
+        let collection = db.collection(COLLECTION);
+        collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        Ok(Self { collection, ttl })
+    }
+}
+
+#[async_trait]
+impl TaskCheckpointStore for MongoTaskCheckpointStore {
+    async fn get(&self, task_id: &Uuid) -> Result<Option<Value>> {
+        // Expired documents linger until MongoDB gets to removing them.
+        let filter = doc! { "_id": task_id, "expires_at": { "$gt": DateTime::now() } };
+        let Some(checkpoint) = self.collection.find_one(filter, None).await? else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(checkpoint.get_str("value")?)?))
+    }
+
+    async fn set(&self, task_id: &Uuid, value: &Value) -> Result<()> {
+        let expires_at = DateTime::from_system_time(SystemTime::now() + self.ttl);
+        self.collection
+            .update_one(
+                doc! { "_id": task_id },
+                doc! {
+                    "$set": {
+                        "value": serde_json::to_string(value)?,
+                        "expires_at": expires_at,
+                    },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mongodb::{
+        bson::{Document, Uuid},
+        Client,
+    };
+    use serde_json::json;
+
+    use crate::checkpoint::{MongoTaskCheckpointStore, TaskCheckpointStore, COLLECTION};
+
+    #[tokio::test]
+    async fn must_get_and_set() {
+        let uri = "mongodb://localhost:27017/test";
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let collection = client.database("test").collection::<Document>(COLLECTION);
+        collection.drop(None).await.unwrap();
+
+        let store = MongoTaskCheckpointStore::new(uri, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let task_id = Uuid::new();
+        assert_eq!(store.get(&task_id).await.unwrap(), None);
+
+        store.set(&task_id, &json!({ "since_id": 1 })).await.unwrap();
+        store.set(&task_id, &json!({ "since_id": 2 })).await.unwrap();
+        assert_eq!(
+            store.get(&task_id).await.unwrap(),
+            Some(json!({ "since_id": 2 }))
+        );
+        assert_eq!(store.get(&Uuid::new()).await.unwrap(), None);
+
+        // Expired checkpoints are gone even before they're removed.
+        let expired = MongoTaskCheckpointStore::new(uri, Duration::ZERO)
+            .await
+            .unwrap();
+        expired.set(&task_id, &json!({ "since_id": 3 })).await.unwrap();
+        assert_eq!(store.get(&task_id).await.unwrap(), None);
+    }
+}
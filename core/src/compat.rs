@@ -0,0 +1,201 @@
+//! Compatibility shims between protocol versions.
+//!
+//! A coordinator and a worker agree on the newest protocol version both speak
+//! when the worker joins, see [`Hello::check`](crate::protocol::Hello::check).
+//! The newer of them speaks the older version by rewriting messages on its
+//! [`WsTransport`](crate::adapter::WsTransport): outgoing messages are
+//! downgraded from [`PROTOCOL_VERSION`] to the agreed version, and incoming
+//! ones upgraded back. This way `WorkerRpc` and `CoordinatorRpc` can evolve
+//! without redeploying every worker at once.
+//!
+//! Every bump of [`PROTOCOL_VERSION`] adds a step from the previous version
+//! here, until [`MIN_PROTOCOL_VERSION`] is raised past it.
+//!
+//! [`MIN_PROTOCOL_VERSION`]: crate::protocol::MIN_PROTOCOL_VERSION
+
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+
+use crate::protocol::PROTOCOL_VERSION;
+
+/// Rewrites messages of a connection speaking an older protocol version.
+#[derive(Debug, Clone)]
+pub struct Shim {
+    version: u32,
+    /// Tags of pings upgraded to heartbeats, by request ID, to be echoed in
+    /// their responses.
+    pings: BTreeMap<u64, Value>,
+}
+
+impl Default for Shim {
+    fn default() -> Self {
+        Self::new(PROTOCOL_VERSION)
+    }
+}
+
+impl Shim {
+    /// Create a shim for a connection speaking `version`, which is at least
+    /// [`MIN_PROTOCOL_VERSION`](crate::protocol::MIN_PROTOCOL_VERSION).
+    /// Messages of the current version pass as is.
+    #[must_use]
+    pub const fn new(version: u32) -> Self {
+        Self {
+            version,
+            pings: BTreeMap::new(),
+        }
+    }
+
+    /// Protocol version spoken on the connection.
+    #[must_use]
+    pub const fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Decode an incoming message, upgrading it to the current version.
+    ///
+    /// # Errors
+    /// Returns an error if the message is not valid JSON of the expected type.
+    pub fn decode<T: DeserializeOwned>(&mut self, data: &[u8]) -> serde_json::Result<T> {
+        if self.version >= PROTOCOL_VERSION {
+            return serde_json::from_slice(data);
+        }
+        let mut msg = serde_json::from_slice(data)?;
+        self.upgrade(&mut msg);
+        serde_json::from_value(msg)
+    }
+
+    /// Encode an outgoing message, downgrading it to the spoken version.
+    ///
+    /// # Errors
+    /// Returns an error if the message can't be serialized.
+    pub fn encode<T: Serialize>(&mut self, item: &T) -> serde_json::Result<Vec<u8>> {
+        if self.version >= PROTOCOL_VERSION {
+            return serde_json::to_vec(item);
+        }
+        let mut msg = serde_json::to_value(item)?;
+        self.downgrade(&mut msg);
+        serde_json::to_vec(&msg)
+    }
+
+    /// Upgrade a message from the spoken version, one version at a time.
+    fn upgrade(&mut self, msg: &mut Value) {
+        for from in self.version..PROTOCOL_VERSION {
+            if from == 4 {
+                self.heartbeat_from_ping(msg);
+            }
+        }
+    }
+
+    /// Downgrade a message to the spoken version, one version at a time.
+    fn downgrade(&mut self, msg: &mut Value) {
+        for from in (self.version..PROTOCOL_VERSION).rev() {
+            if from == 4 {
+                self.ping_from_heartbeat(msg);
+            }
+        }
+    }
+
+    /// Version 5 replaced `ping(id) -> id` with `heartbeat() -> liveness`.
+    /// Pings become heartbeats, and pongs heartbeats without liveness.
+    fn heartbeat_from_ping(&mut self, msg: &mut Value) {
+        if let Some(request) = msg.get_mut("Request") {
+            let id = request.get("id").and_then(Value::as_u64);
+            if let Some(tag) = variant(request.get_mut("message"), "Ping") {
+                let tag = tag.get("id").cloned().unwrap_or_default();
+                self.pings.insert(id.unwrap_or_default(), tag);
+                request["message"] = json!({ "Heartbeat": {} });
+            }
+        } else if let Some(resp) = msg.pointer_mut("/message/Ok") {
+            if variant(Some(resp), "Ping").is_some() {
+                *resp = json!({ "Heartbeat": [] });
+            }
+        }
+    }
+
+    /// Inverse of [`heartbeat_from_ping`](Self::heartbeat_from_ping).
+    /// Heartbeats become pings, and their responses pongs echoing the tag.
+    fn ping_from_heartbeat(&mut self, msg: &mut Value) {
+        if let Some(request) = msg.get_mut("Request") {
+            let id = request.get("id").cloned().unwrap_or_default();
+            if variant(request.get_mut("message"), "Heartbeat").is_some() {
+                request["message"] = json!({ "Ping": { "id": id } });
+            }
+        } else {
+            let id = msg.get("request_id").and_then(Value::as_u64);
+            if let Some(resp) = msg.pointer_mut("/message/Ok") {
+                if variant(Some(resp), "Heartbeat").is_some() {
+                    let tag = id.and_then(|id| self.pings.remove(&id)).unwrap_or_default();
+                    *resp = json!({ "Ping": tag });
+                }
+            }
+        }
+    }
+}
+
+/// Payload of a request or response of a `tarpc` service if it's of the
+/// method `name`, e.g. `{}` of `{"Heartbeat":{}}`.
+fn variant<'a>(message: Option<&'a mut Value>, name: &str) -> Option<&'a mut Value> {
+    message?
+        .as_object_mut()
+        .filter(|message| message.len() == 1)?
+        .get_mut(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{compat::Shim, protocol::PROTOCOL_VERSION};
+
+    fn roundtrip(shim: &mut Shim, msg: &Value, upgrade: bool) -> Value {
+        if upgrade {
+            shim.decode(&serde_json::to_vec(msg).unwrap()).unwrap()
+        } else {
+            serde_json::from_slice(&shim.encode(msg).unwrap()).unwrap()
+        }
+    }
+
+    #[test]
+    fn must_pass_current_version() {
+        let mut shim = Shim::new(PROTOCOL_VERSION);
+        let msg = json!({ "Request": { "id": 1, "message": { "Heartbeat": {} } } });
+        assert_eq!(roundtrip(&mut shim, &msg, true), msg);
+        assert_eq!(roundtrip(&mut shim, &msg, false), msg);
+    }
+
+    #[test]
+    fn must_shim_heartbeat_as_ping() {
+        // A coordinator speaking to a worker of version 4.
+        let mut shim = Shim::new(4);
+        let heartbeat = json!({ "Request": { "id": 7, "message": { "Heartbeat": {} } } });
+        assert_eq!(
+            roundtrip(&mut shim, &heartbeat, false),
+            json!({ "Request": { "id": 7, "message": { "Ping": { "id": 7 } } } })
+        );
+        let pong = json!({ "request_id": 7, "message": { "Ok": { "Ping": 7 } } });
+        assert_eq!(
+            roundtrip(&mut shim, &pong, true),
+            json!({ "request_id": 7, "message": { "Ok": { "Heartbeat": [] } } })
+        );
+
+        // A worker speaking to a coordinator of version 4.
+        let mut shim = Shim::new(4);
+        let ping = json!({ "Request": { "id": 3, "message": { "Ping": { "id": 42 } } } });
+        assert_eq!(
+            roundtrip(&mut shim, &ping, true),
+            json!({ "Request": { "id": 3, "message": { "Heartbeat": {} } } })
+        );
+        let heartbeat = json!({ "request_id": 3, "message": { "Ok": { "Heartbeat": [] } } });
+        assert_eq!(
+            roundtrip(&mut shim, &heartbeat, false),
+            json!({ "request_id": 3, "message": { "Ok": { "Ping": 42 } } })
+        );
+
+        // Other methods pass as is.
+        let add = json!({ "Request": { "id": 4, "message": { "AddTasks": { "tasks": [] } } } });
+        assert_eq!(roundtrip(&mut shim, &add, false), add);
+        assert_eq!(roundtrip(&mut shim, &add, true), add);
+    }
+}
@@ -1,5 +1,120 @@
 //! Errors for the core library.
+//!
+//! Errors are sorted into [`Category`]s, so that binaries decide the same way
+//! whether a failed operation is worth retrying. Errors of the message queue,
+//! the database and HTTP clients are categorized by [`Categorized`], and
+//! converted into an [`Error`] keeping their category. An
+//! [`eyre::Report`] takes the category of the first error in its chain that
+//! has one.
+use std::{
+    error::Error as StdError,
+    fmt::{Display, Formatter},
+};
+
 use thiserror::Error;
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+/// Error code of `MongoDB` on unauthorized commands.
+const MONGO_UNAUTHORIZED: i32 = 13;
+/// Error code of `MongoDB` on failed authentication.
+const MONGO_AUTHENTICATION_FAILED: i32 = 18;
+
+/// Category of an error, telling whether the failed operation is worth
+/// retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// The operation may succeed later, e.g. on timeouts, unavailable
+    /// services or rate limits.
+    Transient,
+    /// The operation fails the same way every time, e.g. on malformed data
+    /// or missing resources.
+    Permanent,
+    /// The component is misconfigured, e.g. with an invalid URL or a queue
+    /// declared with other arguments.
+    Config,
+    /// Credentials are missing, invalid or lack permissions.
+    Auth,
+}
+
+impl Category {
+    /// Whether operations failing with errors of this category are worth
+    /// retrying. Only transient ones are.
+    #[must_use]
+    pub const fn is_retryable(self) -> bool {
+        matches!(self, Self::Transient)
+    }
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Transient => "transient",
+            Self::Permanent => "permanent",
+            Self::Config => "config",
+            Self::Auth => "auth",
+        })
+    }
+}
+
+/// Errors of which the [`Category`] is known.
+pub trait Categorized {
+    /// Category of the error.
+    fn category(&self) -> Category;
+
+    /// Whether the failed operation is worth retrying.
+    fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
+}
+
+/// An error of any source, with its category.
+#[derive(Debug, Error)]
+#[error("{category} error")]
+pub struct Error {
+    category: Category,
+    source: Box<dyn StdError + Send + Sync>,
+}
+
+impl Error {
+    /// Wrap `source`, e.g. an error or a message, as an error of `category`.
+    #[must_use]
+    pub fn new(category: Category, source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self {
+            category,
+            source: source.into(),
+        }
+    }
+
+    /// Wrap `source` as a transient error.
+    #[must_use]
+    pub fn transient(source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self::new(Category::Transient, source)
+    }
+
+    /// Wrap `source` as a permanent error.
+    #[must_use]
+    pub fn permanent(source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self::new(Category::Permanent, source)
+    }
+
+    /// Wrap `source` as a configuration error.
+    #[must_use]
+    pub fn config(source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self::new(Category::Config, source)
+    }
+
+    /// Wrap `source` as an authentication or authorization error.
+    #[must_use]
+    pub fn auth(source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self::new(Category::Auth, source)
+    }
+}
+
+impl Categorized for Error {
+    fn category(&self) -> Category {
+        self.category
+    }
+}
 
 /// Errors that may occur during transport.
 #[derive(Debug, Error)]
@@ -11,3 +126,205 @@ pub enum TransportError {
     #[error("Websocket error")]
     Websocket(#[from] tokio_tungstenite::tungstenite::Error),
 }
+
+impl Categorized for TransportError {
+    fn category(&self) -> Category {
+        match self {
+            Self::Serialize(_) => Category::Permanent,
+            Self::Websocket(WsError::Url(_)) => Category::Config,
+            Self::Websocket(WsError::Http(resp)) => http_category(resp.status().as_u16()),
+            Self::Websocket(_) => Category::Transient,
+        }
+    }
+}
+
+/// Category of a failed HTTP request by its response status.
+const fn http_category(status: u16) -> Category {
+    match status {
+        401 | 403 => Category::Auth,
+        408 | 429 | 500..=599 => Category::Transient,
+        _ => Category::Permanent,
+    }
+}
+
+#[cfg(feature = "mq")]
+impl Categorized for lapin::Error {
+    fn category(&self) -> Category {
+        use lapin::protocol::{AMQPErrorKind, AMQPHardError, AMQPSoftError};
+
+        match self {
+            // Channels and connections are not reopened once closed.
+            Self::InvalidChannelState(_)
+            | Self::InvalidConnectionState(_)
+            | Self::ParsingError(_)
+            | Self::SerialisationError(_) => Category::Permanent,
+            Self::ProtocolError(error) => match error.kind() {
+                AMQPErrorKind::Soft(AMQPSoftError::ACCESSREFUSED)
+                | AMQPErrorKind::Hard(AMQPHardError::NOTALLOWED) => Category::Auth,
+                AMQPErrorKind::Soft(
+                    AMQPSoftError::NOTFOUND | AMQPSoftError::PRECONDITIONFAILED,
+                ) => Category::Config,
+                _ => Category::Transient,
+            },
+            _ => Category::Transient,
+        }
+    }
+}
+
+impl Categorized for mongodb::error::Error {
+    fn category(&self) -> Category {
+        use mongodb::error::{ErrorKind, WriteFailure};
+
+        match &*self.kind {
+            ErrorKind::Authentication { .. } => Category::Auth,
+            ErrorKind::Command(error)
+                if matches!(error.code, MONGO_UNAUTHORIZED | MONGO_AUTHENTICATION_FAILED) =>
+            {
+                Category::Auth
+            }
+            ErrorKind::InvalidArgument { .. }
+            | ErrorKind::InvalidTlsConfig { .. }
+            | ErrorKind::DnsResolve { .. } => Category::Config,
+            ErrorKind::Io(_)
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::Write(WriteFailure::WriteConcernError(_)) => Category::Transient,
+            ErrorKind::BsonSerialization(_)
+            | ErrorKind::BsonDeserialization(_)
+            | ErrorKind::Write(WriteFailure::WriteError(_)) => Category::Permanent,
+            _ if self.contains_label("RetryableWriteError")
+                || self.contains_label("TransientTransactionError") =>
+            {
+                Category::Transient
+            }
+            ErrorKind::Command(_) => Category::Permanent,
+            _ => Category::Transient,
+        }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl Categorized for reqwest::Error {
+    fn category(&self) -> Category {
+        if let Some(status) = self.status() {
+            http_category(status.as_u16())
+        } else if self.is_builder() {
+            Category::Config
+        } else if self.is_decode() || self.is_body() || self.is_redirect() {
+            Category::Permanent
+        } else {
+            Category::Transient
+        }
+    }
+}
+
+/// Reports take the category of the first error in their chain that has one.
+/// Errors of unknown categories are taken as transient, so that they are
+/// retried as before.
+impl Categorized for eyre::Report {
+    fn category(&self) -> Category {
+        self.chain()
+            .find_map(category_of)
+            .unwrap_or(Category::Transient)
+    }
+}
+
+/// Category of an error of a type known to have one.
+fn category_of(error: &(dyn StdError + 'static)) -> Option<Category> {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        return Some(error.category());
+    }
+    if let Some(error) = error.downcast_ref::<TransportError>() {
+        return Some(error.category());
+    }
+    #[cfg(feature = "mq")]
+    if let Some(error) = error.downcast_ref::<lapin::Error>() {
+        return Some(error.category());
+    }
+    if let Some(error) = error.downcast_ref::<mongodb::error::Error>() {
+        return Some(error.category());
+    }
+    #[cfg(feature = "reqwest")]
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return Some(error.category());
+    }
+    None
+}
+
+impl From<TransportError> for Error {
+    fn from(error: TransportError) -> Self {
+        Self::new(error.category(), error)
+    }
+}
+
+#[cfg(feature = "mq")]
+impl From<lapin::Error> for Error {
+    fn from(error: lapin::Error) -> Self {
+        Self::new(error.category(), error)
+    }
+}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(error: mongodb::error::Error) -> Self {
+        Self::new(error.category(), error)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Self::new(error.category(), error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+
+    use eyre::WrapErr;
+
+    use crate::error::{Categorized, Category, Error, TransportError};
+
+    #[test]
+    fn must_retry_transient_only() {
+        assert!(Category::Transient.is_retryable());
+        for category in [Category::Permanent, Category::Config, Category::Auth] {
+            assert!(!category.is_retryable(), "{category}");
+        }
+    }
+
+    #[test]
+    fn must_categorize() {
+        let error = Error::auth("Token revoked");
+        assert_eq!(error.category(), Category::Auth);
+        assert_eq!(error.to_string(), "auth error");
+        assert_eq!(error.source().unwrap().to_string(), "Token revoked");
+
+        let json = serde_json::from_str::<u8>("-").unwrap_err();
+        assert_eq!(TransportError::from(json).category(), Category::Permanent);
+        let ws = tokio_tungstenite::tungstenite::Error::ConnectionClosed;
+        let error = Error::from(TransportError::from(ws));
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn must_categorize_reports() {
+        let report = Err::<(), _>(Error::config("Missing exchange"))
+            .wrap_err("Failed to consume")
+            .unwrap_err();
+        assert_eq!(report.category(), Category::Config);
+
+        // Errors of unknown categories are retried.
+        assert!(eyre::eyre!("Something went wrong").is_retryable());
+    }
+
+    #[cfg(feature = "mq")]
+    #[test]
+    fn must_categorize_amqp() {
+        let closed = lapin::Error::InvalidChannelState(lapin::ChannelState::Closed);
+        assert_eq!(closed.category(), Category::Permanent);
+        let io = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        let io = lapin::Error::IOError(std::sync::Arc::new(io));
+        assert!(io.is_retryable());
+    }
+}
@@ -0,0 +1,96 @@
+//! User-level experiments.
+//!
+//! A user is assigned to a variant of an experiment by a stable hash of the
+//! user id and the experiment name, so the assignment is the same across
+//! processes and restarts without being stored anywhere.
+use std::collections::HashMap;
+
+use mongodb::bson::Uuid;
+use tracing::debug;
+
+use crate::utils::stable_hash;
+
+/// Experiments keyed by name, each with its variants.
+pub type Experiments = HashMap<String, Vec<String>>;
+
+/// Assign a user to one of the variants of an experiment.
+///
+/// Returns `None` if the experiment has no variants.
+#[must_use]
+pub fn assign<'a>(user: Uuid, experiment: &str, variants: &'a [String]) -> Option<&'a str> {
+    if variants.is_empty() {
+        return None;
+    }
+    let hash = stable_hash(user.bytes().into_iter().chain(experiment.bytes()));
+    #[allow(clippy::cast_possible_truncation)]
+    let idx = (hash % variants.len() as u64) as usize;
+    Some(&variants[idx])
+}
+
+/// Assign a user to all experiments. Experiments without variants are
+/// skipped.
+///
+/// Each assignment is logged for analysis.
+#[must_use]
+pub fn assign_all(user: Uuid, experiments: &Experiments) -> HashMap<String, String> {
+    experiments
+        .iter()
+        .filter_map(|(experiment, variants)| {
+            let variant = assign(user, experiment, variants)?;
+            debug!(%user, experiment, variant, "Experiment assigned");
+            Some((experiment.clone(), variant.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mongodb::bson::Uuid;
+
+    use crate::experiment::{assign, assign_all};
+
+    #[test]
+    fn must_assign_stably() {
+        let variants = vec![
+            String::from("control"),
+            String::from("compact"),
+            String::from("verbose"),
+        ];
+        let user = Uuid::from_bytes([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(assign(user, "tweet_format", &variants), Some("control"));
+        assert_eq!(assign(user, "digest", &variants), Some("compact"));
+        let user = Uuid::from_bytes([0xff; 16]);
+        assert_eq!(assign(user, "tweet_format", &variants), Some("verbose"));
+        assert_eq!(assign(user, "digest", &variants), Some("verbose"));
+    }
+
+    #[test]
+    fn must_assign() {
+        let variants = vec![String::from("control"), String::from("compact")];
+        assert_eq!(assign(Uuid::new(), "tweet_format", &[]), None);
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for _ in 0..1000 {
+            let user = Uuid::new();
+            let variant = assign(user, "tweet_format", &variants).unwrap();
+            assert_eq!(assign(user, "tweet_format", &variants), Some(variant));
+            *counts.entry(variant).or_default() += 1;
+        }
+        assert!((400..600).contains(&counts["control"]), "{counts:?}");
+
+        let experiments = HashMap::from([
+            (String::from("tweet_format"), variants.clone()),
+            (String::from("empty"), vec![]),
+        ]);
+        let user = Uuid::new();
+        assert_eq!(
+            assign_all(user, &experiments),
+            HashMap::from([(
+                String::from("tweet_format"),
+                assign(user, "tweet_format", &variants).unwrap().to_string()
+            )])
+        );
+    }
+}
@@ -5,9 +5,19 @@
 pub use async_trait;
 
 pub mod adapter;
+pub mod change_events;
+pub mod checkpoint;
+pub mod compat;
 pub mod error;
+pub mod experiment;
+pub mod lifecycle;
 pub mod models;
 #[cfg(feature = "mq")]
 pub mod mq;
 pub mod protocol;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod store;
 pub mod utils;
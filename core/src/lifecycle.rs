@@ -0,0 +1,284 @@
+//! Hooks into the lifecycle of a worker.
+//!
+//! A worker joining coordinators with [`WorkerRpcExt`] passes its hooks in
+//! [`JoinOptions::hooks`], to run code when it starts, when tasks are added
+//! to or removed from it, when it joins or loses a coordinator, and on
+//! shutdown, without reimplementing the join loop.
+//!
+//! [`WorkerRpcExt`]: crate::protocol::WorkerRpcExt
+//! [`JoinOptions::hooks`]: crate::protocol::JoinOptions::hooks
+
+use std::{
+    fmt::{Debug, Formatter},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use eyre::Result;
+use tarpc::context::Context;
+use uuid::Uuid;
+
+#[cfg(feature = "shutdown")]
+use crate::utils::Shutdown;
+use crate::{
+    models::Task,
+    protocol::{TaskLiveness, WorkerRpc},
+};
+
+/// Lifecycle hooks of a worker. Every hook does nothing by default.
+#[async_trait]
+pub trait WorkerHooks: Send + Sync {
+    /// Called once before joining the first coordinator.
+    async fn on_start(&self) {}
+
+    /// Called after a task is added to the worker. Tasks the worker already
+    /// runs are not added again.
+    async fn on_task_added(&self, _task: &Task) {}
+
+    /// Called after a task is removed from the worker, by the coordinator or
+    /// because joining failed.
+    async fn on_task_removed(&self, _id: Uuid) {}
+
+    /// Called when the worker joins the coordinator at `coordinator`, or
+    /// loses it with `None`.
+    async fn on_membership_change(&self, _coordinator: Option<&str>) {}
+
+    /// Called on shutdown, once the worker stops serving coordinators, e.g.
+    /// to flush pending events. See [`Hooks::on_shutdown_of`].
+    ///
+    /// # Errors
+    /// Returns an error if cleaning up fails. It's logged.
+    async fn on_shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WorkerHooks for () {}
+
+/// Shared handle to the [`WorkerHooks`] of a worker. Defaults to hooks doing
+/// nothing.
+#[derive(Clone)]
+pub struct Hooks(Arc<dyn WorkerHooks>);
+
+impl Hooks {
+    /// Wrap hooks to pass in [`JoinOptions`](crate::protocol::JoinOptions).
+    #[must_use]
+    pub fn new(hooks: impl WorkerHooks + 'static) -> Self {
+        Self(Arc::new(hooks))
+    }
+
+    /// Run [`on_shutdown`](WorkerHooks::on_shutdown) as a hook of `shutdown`
+    /// named `name`.
+    #[cfg(feature = "shutdown")]
+    pub fn on_shutdown_of(&self, shutdown: &Shutdown, name: impl Into<String>) {
+        let hooks = self.0.clone();
+        shutdown.on_shutdown(name, move || async move { hooks.on_shutdown().await });
+    }
+
+    pub(crate) async fn on_start(&self) {
+        self.0.on_start().await;
+    }
+
+    pub(crate) async fn on_membership_change(&self, coordinator: Option<&str>) {
+        self.0.on_membership_change(coordinator).await;
+    }
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new(())
+    }
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Hooks {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Hooks {}
+
+/// A worker calling its hooks after RPCs changing its tasks.
+#[derive(Clone)]
+pub(crate) struct Hooked<T> {
+    pub(crate) worker: T,
+    pub(crate) hooks: Hooks,
+}
+
+#[tarpc::server]
+impl<T> WorkerRpc for Hooked<T>
+where
+    T: WorkerRpc + Clone + Send + 'static,
+    <T as WorkerRpc>::HeartbeatFut: Send,
+    <T as WorkerRpc>::AddTaskFut: Send,
+    <T as WorkerRpc>::RemoveTaskFut: Send,
+    <T as WorkerRpc>::AddTasksFut: Send,
+    <T as WorkerRpc>::RemoveTasksFut: Send,
+    <T as WorkerRpc>::TasksFut: Send,
+    <T as WorkerRpc>::BackfillFut: Send,
+{
+    async fn heartbeat(self, ctx: Context) -> Vec<TaskLiveness> {
+        self.worker.heartbeat(ctx).await
+    }
+
+    async fn add_task(self, ctx: Context, task: Task) -> bool {
+        let added = self.worker.add_task(ctx, task.clone()).await;
+        if added {
+            self.hooks.0.on_task_added(&task).await;
+        }
+        added
+    }
+
+    async fn remove_task(self, ctx: Context, id: Uuid) -> bool {
+        let removed = self.worker.remove_task(ctx, id).await;
+        if removed {
+            self.hooks.0.on_task_removed(id).await;
+        }
+        removed
+    }
+
+    async fn add_tasks(self, ctx: Context, tasks: Vec<Task>) -> Vec<bool> {
+        let added = self.worker.add_tasks(ctx, tasks.clone()).await;
+        for (task, _) in tasks.iter().zip(&added).filter(|(_, added)| **added) {
+            self.hooks.0.on_task_added(task).await;
+        }
+        added
+    }
+
+    async fn remove_tasks(self, ctx: Context, ids: Vec<Uuid>) -> Vec<bool> {
+        let removed = self.worker.remove_tasks(ctx, ids.clone()).await;
+        for (id, _) in ids
+            .into_iter()
+            .zip(&removed)
+            .filter(|(_, removed)| **removed)
+        {
+            self.hooks.0.on_task_removed(id).await;
+        }
+        removed
+    }
+
+    async fn tasks(self, ctx: Context) -> Vec<Task> {
+        self.worker.tasks(ctx).await
+    }
+
+    async fn backfill(self, ctx: Context, task: Task, since: SystemTime) -> bool {
+        self.worker.backfill(ctx, task, since).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    use serde_json::Map;
+    use tarpc::context;
+    use uuid::Uuid;
+
+    use crate::{
+        async_trait::async_trait,
+        lifecycle::{Hooked, Hooks, WorkerHooks},
+        models::Task,
+        protocol::{add_each, remove_each, TaskLiveness, WorkerRpc},
+    };
+
+    /// A worker accepting tasks with odd IDs only.
+    #[derive(Clone)]
+    struct OddWorker;
+
+    #[tarpc::server]
+    impl WorkerRpc for OddWorker {
+        async fn heartbeat(self, _: context::Context) -> Vec<TaskLiveness> {
+            vec![]
+        }
+
+        async fn add_task(self, _: context::Context, task: Task) -> bool {
+            Uuid::from(task.id).as_u128() % 2 == 1
+        }
+
+        async fn remove_task(self, _: context::Context, id: Uuid) -> bool {
+            id.as_u128() % 2 == 1
+        }
+
+        async fn add_tasks(self, ctx: context::Context, tasks: Vec<Task>) -> Vec<bool> {
+            add_each(self, ctx, tasks).await
+        }
+
+        async fn remove_tasks(self, ctx: context::Context, ids: Vec<Uuid>) -> Vec<bool> {
+            remove_each(self, ctx, ids).await
+        }
+
+        async fn tasks(self, _: context::Context) -> Vec<Task> {
+            vec![]
+        }
+
+        async fn backfill(self, _: context::Context, _: Task, _: SystemTime) -> bool {
+            false
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl WorkerHooks for Recorder {
+        async fn on_task_added(&self, task: &Task) {
+            let id = Uuid::from(task.id).as_u128();
+            self.0.lock().unwrap().push(format!("added {id}"));
+        }
+
+        async fn on_task_removed(&self, id: Uuid) {
+            let id = id.as_u128();
+            self.0.lock().unwrap().push(format!("removed {id}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn must_call_task_hooks() {
+        let recorder = Recorder::default();
+        let worker = Hooked {
+            worker: OddWorker,
+            hooks: Hooks::new(recorder.clone()),
+        };
+        let task = |id| Task {
+            id: Uuid::from_u128(id).into(),
+            entity: Uuid::from_u128(0).into(),
+            kind: String::from("bililive"),
+            params: Map::new(),
+        };
+
+        assert!(worker.clone().add_task(context::current(), task(1)).await);
+        assert!(!worker.clone().add_task(context::current(), task(2)).await);
+        assert_eq!(
+            worker
+                .clone()
+                .add_tasks(context::current(), vec![task(3), task(4)])
+                .await,
+            [true, false]
+        );
+        assert_eq!(
+            worker
+                .remove_tasks(
+                    context::current(),
+                    vec![Uuid::from_u128(1), Uuid::from_u128(2)]
+                )
+                .await,
+            [true, false]
+        );
+        // Only changes the worker accepted are seen by the hooks.
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["added 1", "added 3", "removed 1"]
+        );
+    }
+}
@@ -1,51 +1,217 @@
 //! Models for the entity collection.
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Display, Formatter},
     ops::{Deref, DerefMut},
+    str::FromStr,
+    time::{Duration, SystemTime},
 };
 
 use eyre::{bail, Result, WrapErr};
 use isolanguage_1::LanguageCode;
-use mongodb::bson::{oid::ObjectId, Uuid};
-use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
+use mongodb::bson::{oid::ObjectId, Bson, Uuid};
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    sdk::propagation::TraceContextPropagator,
+    trace::TraceContextExt,
+    Context,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use url::Url;
 
 use crate::utils::map;
 
+/// Field of an event carrying the W3C trace context of its publisher.
+const TRACE_CONTEXT: &str = "x-trace-context";
+
+/// Field of an event carrying the Unix timestamp in seconds after which it's
+/// stale, e.g. a live start nobody would want to hear of hours later.
+pub const EXPIRES_AT: &str = "x-expires-at";
+
+/// Field of an event carrying translations of its fields, written by the
+/// translate middleware as `{ "<language>": { "<json pointer>": "<text>" } }`.
+pub const TRANSLATIONS: &str = "translations";
+
+/// Id of a user, entity, task or other record, as taken and returned by the
+/// api.
+///
+/// Serialized the same as the [`Uuid`] it wraps, i.e. as a binary in BSON and
+/// as a hyphenated string elsewhere, and converts from and into both `Uuid`
+/// of `bson` and of the `uuid` crate, so that ids of models and of workers can
+/// be passed as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Id(Uuid);
+
+impl Id {
+    /// A new random id.
+    #[allow(clippy::new_without_default)]
+    #[must_use]
+    pub fn new() -> Self {
+        Self(Uuid::new())
+    }
+
+    /// The nil id, with all bits zero, e.g. of tokens not issued to a user.
+    #[must_use]
+    pub const fn nil() -> Self {
+        Self(Uuid::from_bytes([0; 16]))
+    }
+
+    /// Whether it's the [nil](Self::nil) id.
+    #[must_use]
+    pub fn is_nil(&self) -> bool {
+        self.0.bytes() == [0; 16]
+    }
+}
+
+impl Deref for Id {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for Id {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Id {
+    type Err = mongodb::bson::uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(Self)
+    }
+}
+
+impl From<Uuid> for Id {
+    fn from(id: Uuid) -> Self {
+        Self(id)
+    }
+}
+
+impl From<Id> for Uuid {
+    fn from(id: Id) -> Self {
+        id.0
+    }
+}
+
+impl From<uuid::Uuid> for Id {
+    fn from(id: uuid::Uuid) -> Self {
+        Self(id.into())
+    }
+}
+
+impl From<Id> for uuid::Uuid {
+    fn from(id: Id) -> Self {
+        id.0.into()
+    }
+}
+
+impl From<Id> for Bson {
+    fn from(id: Id) -> Self {
+        id.0.into()
+    }
+}
+
+impl PartialEq<Uuid> for Id {
+    fn eq(&self, other: &Uuid) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<Id> for Uuid {
+    fn eq(&self, other: &Id) -> bool {
+        *self == other.0
+    }
+}
+
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for Id {
+    fn schema_name() -> String {
+        crate::schema::Uuid::schema_name()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        crate::schema::Uuid::json_schema(gen)
+    }
+}
+
 /// Entity for a vtuber.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Entity {
     /// The unique identifier of the entity.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub id: Uuid,
     /// Metadata about the entity.
     pub meta: Meta,
     /// Tasks to be scheduled.
+    #[cfg_attr(feature = "schema", schemars(with = "Vec<crate::schema::Uuid>"))]
     pub tasks: Vec<Uuid>,
+    /// Templates of tasks derived from the meta, kept in sync with it by the
+    /// api.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub auto_tasks: Vec<TaskTemplate>,
 }
 
 /// Meta of the vtuber.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Meta {
     /// Vtuber's name.
     pub name: Name,
     /// Affiliation of the vtuber.
+    #[cfg_attr(feature = "schema", schemars(with = "Option<crate::schema::Uuid>"))]
     pub group: Option<Uuid>,
+    /// Profiles of the vtuber on platforms, keyed by task kind, e.g.
+    /// `youtube`. Kept up to date by the enrichment worker.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Ids of the vtuber's accounts, e.g. `{ "youtube": "UC..." }`, that
+    /// [task templates](TaskTemplate) refer to.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub accounts: BTreeMap<String, String>,
+}
+
+/// Profile of a vtuber on a platform, as shown there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Profile {
+    /// Display name, e.g. the channel title on youtube.
+    pub name: String,
+    /// Avatar of the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<Url>,
 }
 
 /// Name of a vtuber/group.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Name {
     /// Name in different languages. The key must be in ISO 639-1.
+    #[cfg_attr(feature = "schema", schemars(with = "HashMap<String, String>"))]
     pub name: HashMap<LanguageCode, String>,
     /// Preferred language of the name. Must be in ISO 639-1.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::LanguageCode"))]
     pub default_language: LanguageCode,
+    /// Other names in no particular language, e.g. romanized names and
+    /// nicknames.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
 }
 
 /// A group/organization of vtubers.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Group {
     /// The unique identifier of the group.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub id: Uuid,
     /// Group's name.
     pub name: Name,
@@ -53,10 +219,13 @@ pub struct Group {
 
 /// Defined task for a vtuber.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Task {
     /// The unique identifier of the task.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub id: Uuid,
     /// Parent entity id.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub entity: Uuid,
     /// Kind of the task.
     pub kind: String,
@@ -64,7 +233,42 @@ pub struct Task {
     pub params: Map<String, Value>,
 }
 
+/// Labels of a worker, e.g. `region=cn`.
+pub type Labels = BTreeMap<String, String>;
+
+/// Param marking tasks rendered from a [`TaskTemplate`], which the api creates
+/// and removes by itself.
+pub const AUTO_TASK_PARAM: &str = "auto";
+
 impl Task {
+    /// Labels a worker must have to run this task, read from the `placement`
+    /// param, e.g. `{ "placement": { "region": "cn" } }`.
+    ///
+    /// # Errors
+    /// Returns an error if the param is not a map of strings.
+    pub fn placement(&self) -> Result<Option<Labels>> {
+        self.params
+            .get("placement")
+            .map(|constraint| {
+                serde_json::from_value(constraint.clone())
+                    .wrap_err("placement constraint must be a map of strings")
+            })
+            .transpose()
+    }
+
+    /// Whether the task is rendered from a [`TaskTemplate`] of its entity.
+    #[must_use]
+    pub fn is_auto(&self) -> bool {
+        self.params.get(AUTO_TASK_PARAM) == Some(&Value::Bool(true))
+    }
+
+    /// Whether the task is of the same kind and params as `other`, regardless
+    /// of ids.
+    #[must_use]
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.params == other.params
+    }
+
     /// Create a new youtube task with `channel_id` and `parent`,
     ///
     /// # Params
@@ -75,7 +279,7 @@ impl Task {
         Self {
             id: Uuid::new(),
             entity: parent,
-            kind: "youtube".to_string(),
+            kind: kind::YOUTUBE.to_string(),
             params: map!("channel_id", channel_id),
         }
     }
@@ -89,7 +293,7 @@ impl Task {
         Self {
             id: Uuid::new(),
             entity: parent,
-            kind: "bililive".to_string(),
+            kind: kind::BILILIVE.to_string(),
             params: map!("uid", uid),
         }
     }
@@ -103,24 +307,179 @@ impl Task {
         Self {
             id: Uuid::new(),
             entity: parent,
-            kind: "twitter".to_string(),
+            kind: kind::TWITTER.to_string(),
             params: map!("id", id),
         }
     }
 }
 
+/// Template of a task derived from the meta of its entity, e.g. `{ "kind":
+/// "youtube", "params": { "channel_id": "{youtube}" } }`.
+///
+/// `{name}` in strings of the params is replaced by the account `name` in
+/// [`Meta::accounts`]. While the entity has no such account, there's no task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TaskTemplate {
+    /// Kind of the task.
+    pub kind: String,
+    /// Parameters of the task, with placeholders.
+    pub params: Map<String, Value>,
+}
+
+impl TaskTemplate {
+    /// Render a task of `entity` from the template and the meta of the entity,
+    /// marked with [`AUTO_TASK_PARAM`]. Return `None` if an account it refers
+    /// to is missing.
+    #[must_use]
+    pub fn render(&self, entity: Uuid, meta: &Meta) -> Option<Task> {
+        let mut params = self
+            .params
+            .iter()
+            .map(|(name, value)| Some((name.clone(), render_value(value, &meta.accounts)?)))
+            .collect::<Option<Map<_, _>>>()?;
+        params.insert(AUTO_TASK_PARAM.to_string(), Value::Bool(true));
+        Some(Task {
+            id: Uuid::new(),
+            entity,
+            kind: self.kind.clone(),
+            params,
+        })
+    }
+
+    /// Names of accounts the template refers to.
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+        let mut names = vec![];
+        self.params
+            .values()
+            .for_each(|value| collect_placeholders(value, &mut names));
+        names.into_iter()
+    }
+}
+
+/// Replace placeholders in strings of `value`, or `None` if an account is
+/// missing.
+fn render_value(value: &Value, accounts: &BTreeMap<String, String>) -> Option<Value> {
+    Some(match value {
+        Value::String(template) => {
+            let mut rendered = String::new();
+            let mut rest = template.as_str();
+            while let Some((before, name, after)) = split_placeholder(rest) {
+                rendered.push_str(before);
+                rendered.push_str(accounts.get(name)?);
+                rest = after;
+            }
+            rendered.push_str(rest);
+            Value::String(rendered)
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render_value(item, accounts))
+                .collect::<Option<_>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, field)| Some((name.clone(), render_value(field, accounts)?)))
+                .collect::<Option<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+fn collect_placeholders<'a>(value: &'a Value, names: &mut Vec<&'a str>) {
+    match value {
+        Value::String(template) => {
+            let mut rest = template.as_str();
+            while let Some((_, name, after)) = split_placeholder(rest) {
+                names.push(name);
+                rest = after;
+            }
+        }
+        Value::Array(items) => items
+            .iter()
+            .for_each(|item| collect_placeholders(item, names)),
+        Value::Object(fields) => fields
+            .values()
+            .for_each(|field| collect_placeholders(field, names)),
+        _ => {}
+    }
+}
+
+/// Split `s` around its first placeholder, a name of letters, digits or `_`
+/// in braces. Other braces are kept as is.
+fn split_placeholder(s: &str) -> Option<(&str, &str, &str)> {
+    let mut from = 0;
+    loop {
+        let start = from + s[from..].find('{')?;
+        let name_len = s[start + 1..]
+            .bytes()
+            .take_while(|b| b.is_ascii_alphanumeric() || *b == b'_')
+            .count();
+        let end = start + 1 + name_len;
+        if name_len > 0 && s[end..].starts_with('}') {
+            return Some((&s[..start], &s[start + 1..end], &s[end + 1..]));
+        }
+        from = start + 1;
+    }
+}
+
 /// Event pushed by workers (or addons) to the message queue and received by IM
 /// agents.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Event {
     /// The unique identifier of the event.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub id: Uuid,
     /// Kind of the event.
     pub kind: String,
     /// Entity affected by the event.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub entity: Uuid,
     /// Fields of the event.
     pub fields: Map<String, Value>,
+    /// Signature of the publishing worker, if signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+    /// Id of the first event of the processing chain this event is part of.
+    /// `None` if this event starts the chain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<crate::schema::Uuid>"))]
+    pub correlation_id: Option<Uuid>,
+    /// Id of the event this event was derived from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<crate::schema::Uuid>"))]
+    pub caused_by: Option<Uuid>,
+}
+
+/// Algorithm of an event signature.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureAlg {
+    /// HMAC with SHA-256, keyed by a secret shared with verifiers.
+    HmacSha256,
+    /// Ed25519, verified by the public key of the signer.
+    Ed25519,
+}
+
+/// Signature of an event.
+///
+/// It covers the id, kind, entity and fields of the event, except meta fields
+/// (`x-*`), translations and fields in `unsigned`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Signature {
+    /// Algorithm of the signature.
+    pub alg: SignatureAlg,
+    /// JSON pointers of fields left unsigned since middlewares may rewrite
+    /// them, e.g. those to be translated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsigned: Vec<String>,
+    /// Base64 encoded signature.
+    pub value: String,
 }
 
 impl Event {
@@ -135,20 +494,7 @@ impl Event {
         entity: impl Into<Uuid>,
         fields: impl Serialize,
     ) -> Result<Self> {
-        let value = serde_json::to_value(fields)
-            .wrap_err("event fields can't be converted into json value")?;
-        let fields = match value {
-            Value::Null => Map::new(),
-            Value::Object(m) => m,
-            _ => bail!("event field is not a map"),
-        };
-
-        Ok(Self {
-            id: id.into(),
-            kind: kind.to_string(),
-            entity: entity.into(),
-            fields,
-        })
+        Self::builder(kind, entity).id(id).fields(fields).build()
     }
 
     /// Create a new event with its fields set by a serializable object.
@@ -162,13 +508,789 @@ impl Event {
     ) -> Result<Self> {
         Self::from_serializable_with_id(Uuid::new(), kind, entity, fields)
     }
+
+    /// Start building an event with a new id and no fields.
+    pub fn builder(kind: &str, entity: impl Into<Uuid>) -> EventBuilder {
+        EventBuilder {
+            event: Ok(Self {
+                id: Uuid::new(),
+                kind: kind.to_string(),
+                entity: entity.into(),
+                fields: Map::new(),
+                signature: None,
+                correlation_id: None,
+                caused_by: None,
+            }),
+        }
+    }
+
+    /// Id of the first event of the processing chain, which is this event's
+    /// own id if it starts the chain.
+    #[must_use]
+    pub fn correlation(&self) -> Uuid {
+        self.correlation_id.unwrap_or(self.id)
+    }
+
+    /// Mark the event as a backfill of past activity, so that it may be
+    /// rendered as a digest instead of a notification.
+    #[must_use]
+    pub fn into_backfill(mut self) -> Self {
+        self.fields.insert("x-backfill".into(), Value::Bool(true));
+        self
+    }
+
+    /// Whether the event is a backfill of past activity.
+    #[must_use]
+    pub fn is_backfill(&self) -> bool {
+        self.fields.get("x-backfill") == Some(&Value::Bool(true))
+    }
+
+    /// Mark the event as stale after `at`, so that it's dropped instead of
+    /// delivered late. See [`EXPIRES_AT`].
+    #[must_use]
+    pub fn with_expiry(mut self, at: SystemTime) -> Self {
+        let secs = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.fields.insert(EXPIRES_AT.into(), secs.into());
+        self
+    }
+
+    /// Mark the event as stale after `ttl` from now.
+    #[must_use]
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.with_expiry(SystemTime::now() + ttl)
+    }
+
+    /// Time after which the event is stale, if it expires.
+    #[must_use]
+    pub fn expiry(&self) -> Option<SystemTime> {
+        let secs = self.fields.get(EXPIRES_AT)?.as_u64()?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Whether the event is stale at `now`.
+    #[must_use]
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiry().is_some_and(|expiry| expiry <= now)
+    }
+
+    /// Create the span of publishing the event, and attach its trace context
+    /// to the event.
+    ///
+    /// The span is a child of the current span if there's one. Otherwise it
+    /// continues the trace already attached to the event, e.g. when a delayed
+    /// event is finally published.
+    pub fn publish_span(&mut self) -> Span {
+        let span = info_span!(
+            "publish",
+            event_id = %self.id,
+            event_kind = %self.kind,
+            correlation_id = %self.correlation()
+        );
+        if !Span::current().context().span().span_context().is_valid() {
+            span.set_parent(self.trace_context());
+        }
+        self.set_trace_context(&span.context());
+        span
+    }
+
+    /// Create the span of handling a consumed event, continuing the trace of
+    /// its publisher.
+    #[must_use]
+    pub fn consume_span(&self) -> Span {
+        let span = info_span!(
+            "consume",
+            event_id = %self.id,
+            event_kind = %self.kind,
+            correlation_id = %self.correlation()
+        );
+        span.set_parent(self.trace_context());
+        span
+    }
+
+    /// Trace context attached to the event by its publisher. Empty if there's
+    /// none.
+    #[must_use]
+    pub fn trace_context(&self) -> Context {
+        let carrier: HashMap<String, String> = self
+            .fields
+            .get(TRACE_CONTEXT)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+            .unwrap_or_default();
+        TraceContextPropagator::new().extract(&carrier)
+    }
+
+    fn set_trace_context(&mut self, cx: &Context) {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(cx, &mut carrier);
+        if !carrier.is_empty() {
+            self.fields.insert(TRACE_CONTEXT.into(), json!(carrier));
+        }
+    }
+
+    /// Translation of the field at `pointer` into `language`, if there's one.
+    #[must_use]
+    pub fn translation(&self, pointer: &str, language: LanguageCode) -> Option<&str> {
+        self.fields
+            .get(TRANSLATIONS)?
+            .get(language.code())?
+            .get(pointer)?
+            .as_str()
+    }
+
+    /// Copy of the event with translated fields replaced by their
+    /// translations into `language`. Fields not translated into the language
+    /// are kept as is.
+    #[must_use]
+    pub fn localized(&self, language: LanguageCode) -> Self {
+        let mut event = self.clone();
+        let Some(Value::Object(translations)) = self
+            .fields
+            .get(TRANSLATIONS)
+            .and_then(|translations| translations.get(language.code()))
+        else {
+            return event;
+        };
+
+        let mut fields = Value::Object(event.fields);
+        for (pointer, text) in translations {
+            if let (Some(field), Value::String(_)) = (fields.pointer_mut(pointer), text) {
+                *field = text.clone();
+            }
+        }
+        event.fields = match fields {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        };
+        event
+    }
+
+    /// Create a new event of the payload's kind.
+    ///
+    /// # Errors
+    /// Returns an error if the payload cannot be serialized into a map.
+    pub fn from_payload<T: Payload>(entity: impl Into<Uuid>, payload: &T) -> Result<Self> {
+        Self::from_serializable(T::KIND, entity, payload)
+    }
+
+    /// Decode fields of the event as a typed payload. Meta fields (`x-*`) are
+    /// ignored.
+    ///
+    /// # Errors
+    /// Returns an error if the event is not of the payload's kind, or the
+    /// fields don't match the payload.
+    pub fn decode_as<T: Payload>(&self) -> Result<T> {
+        if self.kind != T::KIND {
+            bail!("expected event of kind `{}`, got `{}`", T::KIND, self.kind);
+        }
+        T::deserialize(&Value::Object(self.fields.clone()))
+            .wrap_err_with(|| format!("fields of `{}` event are malformed", self.kind))
+    }
+}
+
+/// Builder of an [`Event`], started by [`Event::builder`].
+///
+/// Events derived from another, e.g. a confirmation of a delivered event,
+/// should be built with [`EventBuilder::caused_by`], so that the processing
+/// chain can be traced back from logs.
+///
+/// ```
+/// # use sg_core::models::{Event, Id};
+/// let tweet = Event::builder("twitter", Id::nil())
+///     .field("text", "hello")
+///     .build()
+///     .unwrap();
+/// let reply = Event::builder("twitter", Id::nil())
+///     .caused_by(&tweet)
+///     .build()
+///     .unwrap();
+/// assert_eq!(reply.caused_by, Some(tweet.id));
+/// assert_eq!(reply.correlation(), tweet.id);
+/// ```
+#[derive(Debug)]
+#[must_use]
+pub struct EventBuilder {
+    /// Event built so far, or the first error building it.
+    event: Result<Event>,
+}
+
+impl EventBuilder {
+    fn and_then(mut self, f: impl FnOnce(&mut Event) -> Result<()>) -> Self {
+        if let Ok(event) = &mut self.event {
+            if let Err(error) = f(event) {
+                self.event = Err(error);
+            }
+        }
+        self
+    }
+
+    /// Set the id of the event.
+    pub fn id(self, id: impl Into<Uuid>) -> Self {
+        let id = id.into();
+        self.and_then(|event| {
+            event.id = id;
+            Ok(())
+        })
+    }
+
+    /// Set a field of the event.
+    pub fn field(self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        let (name, value) = (name.into(), value.into());
+        self.and_then(|event| {
+            event.fields.insert(name, value);
+            Ok(())
+        })
+    }
+
+    /// Set fields of the event by a serializable object, which must be a map
+    /// or nothing.
+    pub fn fields(self, fields: impl Serialize) -> Self {
+        let value = serde_json::to_value(fields);
+        self.and_then(|event| {
+            match value.wrap_err("event fields can't be converted into json value")? {
+                Value::Null => {}
+                Value::Object(fields) => event.fields.extend(fields),
+                _ => bail!("event field is not a map"),
+            }
+            Ok(())
+        })
+    }
+
+    /// Mark the event as derived from `cause`, continuing its processing
+    /// chain.
+    pub fn caused_by(self, cause: &Event) -> Self {
+        let (caused_by, correlation_id) = (cause.id, cause.correlation());
+        self.and_then(|event| {
+            event.caused_by = Some(caused_by);
+            event.correlation_id = Some(correlation_id);
+            Ok(())
+        })
+    }
+
+    /// Mark the event as a backfill of past activity. See
+    /// [`Event::into_backfill`].
+    pub fn backfill(self) -> Self {
+        self.field("x-backfill", true)
+    }
+
+    /// Build the event.
+    ///
+    /// # Errors
+    /// Returns the first error of setting fields.
+    pub fn build(self) -> Result<Event> {
+        self.event
+    }
+}
+
+/// Kinds of events.
+pub mod kind {
+    /// A new tweet.
+    pub const TWITTER: &str = "twitter";
+    /// A bilibili live started.
+    pub const BILILIVE: &str = "bililive";
+    /// Kind of tasks watching youtube channels. Their events are of the
+    /// `youtube/*` kinds below.
+    pub const YOUTUBE: &str = "youtube";
+    /// A youtube live stream or premiere was scheduled, or rescheduled.
+    pub const YOUTUBE_BROADCAST_SCHEDULED: &str = "youtube/broadcast_scheduled";
+    /// A scheduled youtube live stream or premiere starts in 30 minutes.
+    pub const YOUTUBE_BROADCAST_REMINDER: &str = "youtube/30_min_before_broadcast";
+    /// A youtube live stream started.
+    pub const YOUTUBE_LIVE_START: &str = "youtube/live_start";
+    /// A youtube live stream for members of the channel only started.
+    pub const YOUTUBE_MEMBERS_LIVE_START: &str = "youtube/members_live_start";
+    /// A youtube premiere started.
+    pub const YOUTUBE_PREMIERE_START: &str = "youtube/premiere_start";
+    /// An announcement of admins to all users, e.g. of a maintenance. It's
+    /// published by the api instead of a worker and has a nil entity, so it's
+    /// not in [`KINDS`](super::KINDS) and can't be subscribed to.
+    pub const ANNOUNCEMENT: &str = "system/announcement";
+    /// A source of events, i.e. the task publishing events of a kind for an
+    /// entity, suddenly floods. It's published by the anomaly middleware for
+    /// operators, so it's not in [`KINDS`](super::KINDS) either.
+    pub const ANOMALY: &str = "system/anomaly";
+    /// A delayed event was delivered. It's published by the delay middleware
+    /// if asked to confirm deliveries, and matches no subscription, so bots
+    /// don't render it.
+    pub const DELAY_DELIVERED: &str = "system/delay_delivered";
+}
+
+/// Type of a field of an event payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    /// A boolean.
+    Boolean,
+    /// An integer.
+    Integer,
+    /// A string.
+    String,
+    /// An array of values of the given type.
+    Array(&'static FieldType),
+}
+
+impl FieldType {
+    /// JSON Schema of values of this type.
+    #[must_use]
+    pub fn schema(self) -> Value {
+        match self {
+            Self::Boolean => json!({ "type": "boolean" }),
+            Self::Integer => json!({ "type": "integer" }),
+            Self::String => json!({ "type": "string" }),
+            Self::Array(items) => json!({ "type": "array", "items": items.schema() }),
+        }
+    }
+}
+
+/// Field of an event payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldSpec {
+    /// Name of the field.
+    pub name: &'static str,
+    /// Type of the field.
+    pub ty: FieldType,
+    /// Whether the field may be `null`.
+    pub optional: bool,
+    /// Human-readable description of the field.
+    pub description: &'static str,
+}
+
+/// Description of a known event kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KindSpec {
+    /// Name of the kind, as in [`Event::kind`] and [`Task::kind`].
+    pub name: &'static str,
+    /// Worker producing events of the kind.
+    pub worker: &'static str,
+    /// Human-readable description of the kind.
+    pub description: &'static str,
+    /// Fields of the payload of events of the kind, except meta fields
+    /// (`x-*`).
+    pub fields: &'static [FieldSpec],
+}
+
+impl KindSpec {
+    /// JSON Schema of the payload of events of the kind.
+    #[must_use]
+    pub fn payload_schema(&self) -> Value {
+        let properties: Map<String, Value> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let mut schema = field.ty.schema();
+                if field.optional {
+                    schema["type"] = json!([schema["type"], "null"]);
+                }
+                schema["description"] = json!(field.description);
+                (field.name.to_owned(), schema)
+            })
+            .collect();
+        let required: Vec<_> = self.fields.iter().map(|field| field.name).collect();
+
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })
+    }
+}
+
+/// Registry of known event kinds. Tasks and event filters referring to other
+/// kinds are rejected, since no event would ever match them.
+pub const KINDS: &[KindSpec] = &[
+    KindSpec {
+        name: kind::TWITTER,
+        worker: "twitter",
+        description: "A new tweet.",
+        fields: &[
+            FieldSpec {
+                name: "id",
+                ty: FieldType::Integer,
+                optional: false,
+                description: "The tweet's unique identifier.",
+            },
+            FieldSpec {
+                name: "text",
+                ty: FieldType::String,
+                optional: false,
+                description: "The tweet's text.",
+            },
+            FieldSpec {
+                name: "photos",
+                ty: FieldType::Array(&FieldType::String),
+                optional: false,
+                description: "URLs of media attached to the tweet.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the tweet.",
+            },
+            FieldSpec {
+                name: "is_rt",
+                ty: FieldType::Boolean,
+                optional: false,
+                description: "Whether the tweet is a retweet.",
+            },
+        ],
+    },
+    KindSpec {
+        name: kind::BILILIVE,
+        worker: "bililive",
+        description: "A bilibili live started.",
+        fields: &[
+            FieldSpec {
+                name: "title",
+                ty: FieldType::String,
+                optional: false,
+                description: "Title of the live room.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the live room.",
+            },
+            FieldSpec {
+                name: "cover",
+                ty: FieldType::String,
+                optional: true,
+                description: "Cover of the live room.",
+            },
+        ],
+    },
+    KindSpec {
+        name: kind::YOUTUBE_BROADCAST_SCHEDULED,
+        worker: kind::YOUTUBE,
+        description: "A youtube live stream or premiere was scheduled, or rescheduled.",
+        fields: &[
+            FieldSpec {
+                name: "title",
+                ty: FieldType::String,
+                optional: false,
+                description: "Title of the broadcast.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the broadcast.",
+            },
+            FieldSpec {
+                name: "cover",
+                ty: FieldType::String,
+                optional: true,
+                description: "Thumbnail of the broadcast.",
+            },
+            FieldSpec {
+                name: "start_at",
+                ty: FieldType::Integer,
+                optional: false,
+                description: "Scheduled start time, in seconds since the unix epoch.",
+            },
+        ],
+    },
+    KindSpec {
+        name: kind::YOUTUBE_BROADCAST_REMINDER,
+        worker: kind::YOUTUBE,
+        description: "A scheduled youtube live stream or premiere starts in 30 minutes.",
+        fields: &[
+            FieldSpec {
+                name: "title",
+                ty: FieldType::String,
+                optional: false,
+                description: "Title of the broadcast.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the broadcast.",
+            },
+            FieldSpec {
+                name: "cover",
+                ty: FieldType::String,
+                optional: true,
+                description: "Thumbnail of the broadcast.",
+            },
+            FieldSpec {
+                name: "start_at",
+                ty: FieldType::Integer,
+                optional: false,
+                description: "Scheduled start time, in seconds since the unix epoch.",
+            },
+        ],
+    },
+    KindSpec {
+        name: kind::YOUTUBE_LIVE_START,
+        worker: kind::YOUTUBE,
+        description: "A youtube live stream started.",
+        fields: &[
+            FieldSpec {
+                name: "title",
+                ty: FieldType::String,
+                optional: false,
+                description: "Title of the broadcast.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the broadcast.",
+            },
+            FieldSpec {
+                name: "cover",
+                ty: FieldType::String,
+                optional: true,
+                description: "Thumbnail of the broadcast.",
+            },
+            FieldSpec {
+                name: "start_at",
+                ty: FieldType::Integer,
+                optional: false,
+                description: "Start time, in seconds since the unix epoch.",
+            },
+        ],
+    },
+    KindSpec {
+        name: kind::YOUTUBE_MEMBERS_LIVE_START,
+        worker: kind::YOUTUBE,
+        description: "A youtube live stream for members of the channel only started.",
+        fields: &[
+            FieldSpec {
+                name: "title",
+                ty: FieldType::String,
+                optional: false,
+                description: "Title of the broadcast.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the broadcast.",
+            },
+            FieldSpec {
+                name: "cover",
+                ty: FieldType::String,
+                optional: true,
+                description: "Thumbnail of the broadcast.",
+            },
+            FieldSpec {
+                name: "start_at",
+                ty: FieldType::Integer,
+                optional: false,
+                description: "Start time, in seconds since the unix epoch.",
+            },
+        ],
+    },
+    KindSpec {
+        name: kind::YOUTUBE_PREMIERE_START,
+        worker: kind::YOUTUBE,
+        description: "A youtube premiere started.",
+        fields: &[
+            FieldSpec {
+                name: "title",
+                ty: FieldType::String,
+                optional: false,
+                description: "Title of the broadcast.",
+            },
+            FieldSpec {
+                name: "link",
+                ty: FieldType::String,
+                optional: false,
+                description: "The url of the broadcast.",
+            },
+            FieldSpec {
+                name: "cover",
+                ty: FieldType::String,
+                optional: true,
+                description: "Thumbnail of the broadcast.",
+            },
+            FieldSpec {
+                name: "start_at",
+                ty: FieldType::Integer,
+                optional: false,
+                description: "Start time, in seconds since the unix epoch.",
+            },
+        ],
+    },
+];
+
+/// Look up a kind in [`KINDS`].
+///
+/// # Errors
+/// Returns an error if the kind is unknown.
+pub fn validate_kind(kind: &str) -> Result<&'static KindSpec> {
+    KINDS.iter().find(|spec| spec.name == kind).ok_or_else(|| {
+        let known: Vec<_> = KINDS.iter().map(|spec| spec.name).collect();
+        eyre::eyre!("unknown event kind `{kind}`, expected one of {known:?}")
+    })
+}
+
+/// Look up kinds of events produced by the worker of tasks of `kind`.
+///
+/// # Errors
+/// Returns an error if no known kind is produced for such tasks.
+pub fn validate_task_kind(kind: &str) -> Result<Vec<&'static KindSpec>> {
+    let specs: Vec<_> = KINDS.iter().filter(|spec| spec.worker == kind).collect();
+    if specs.is_empty() {
+        let mut known: Vec<_> = KINDS.iter().map(|spec| spec.worker).collect();
+        known.dedup();
+        eyre::bail!("unknown task kind `{kind}`, expected one of {known:?}");
+    }
+    Ok(specs)
+}
+
+/// Typed fields of events of a specific kind.
+pub trait Payload: Serialize + DeserializeOwned {
+    /// Kind of events carrying this payload.
+    const KIND: &'static str;
+}
+
+/// Payload of [`kind::TWITTER`] events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TweetPayload {
+    /// The tweet's unique identifier.
+    pub id: u64,
+    /// The tweet's text.
+    pub text: String,
+    /// URLs of media attached to the tweet.
+    pub photos: Vec<String>,
+    /// The url of the tweet.
+    pub link: String,
+    /// Whether the tweet is a retweet.
+    pub is_rt: bool,
+}
+
+impl Payload for TweetPayload {
+    const KIND: &'static str = kind::TWITTER;
+}
+
+/// Payload of [`kind::BILILIVE`] events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LiveStartPayload {
+    /// Title of the live room.
+    pub title: String,
+    /// The url of the live room.
+    pub link: String,
+    /// Cover of the live room.
+    pub cover: Option<String>,
+}
+
+impl Payload for LiveStartPayload {
+    const KIND: &'static str = kind::BILILIVE;
+}
+
+/// Payload of [`kind::YOUTUBE_BROADCAST_SCHEDULED`] events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BroadcastPayload {
+    /// Title of the broadcast.
+    pub title: String,
+    /// The url of the broadcast.
+    pub link: String,
+    /// Thumbnail of the broadcast.
+    pub cover: Option<String>,
+    /// Scheduled start time, in seconds since the unix epoch.
+    pub start_at: u64,
+}
+
+impl Payload for BroadcastPayload {
+    const KIND: &'static str = kind::YOUTUBE_BROADCAST_SCHEDULED;
+}
+
+/// Payload of [`kind::YOUTUBE_BROADCAST_REMINDER`] events, the same as of the
+/// scheduled event it reminds of.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BroadcastReminderPayload(pub BroadcastPayload);
+
+impl Payload for BroadcastReminderPayload {
+    const KIND: &'static str = kind::YOUTUBE_BROADCAST_REMINDER;
+}
+
+/// Payload of [`kind::YOUTUBE_LIVE_START`] events, the same as of the
+/// scheduled event but with the actual start time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BroadcastStartPayload(pub BroadcastPayload);
+
+impl Payload for BroadcastStartPayload {
+    const KIND: &'static str = kind::YOUTUBE_LIVE_START;
+}
+
+/// Payload of [`kind::YOUTUBE_MEMBERS_LIVE_START`] events, like
+/// [`BroadcastStartPayload`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MembersBroadcastStartPayload(pub BroadcastPayload);
+
+impl Payload for MembersBroadcastStartPayload {
+    const KIND: &'static str = kind::YOUTUBE_MEMBERS_LIVE_START;
+}
+
+/// Payload of [`kind::YOUTUBE_PREMIERE_START`] events, like
+/// [`BroadcastStartPayload`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PremiereStartPayload(pub BroadcastPayload);
+
+impl Payload for PremiereStartPayload {
+    const KIND: &'static str = kind::YOUTUBE_PREMIERE_START;
+}
+
+/// Payload of [`kind::ANNOUNCEMENT`] events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AnnouncementPayload {
+    /// Text of the announcement.
+    pub message: String,
+    /// IM whose users the announcement is delivered to, or all IMs if not
+    /// set.
+    pub im: Option<String>,
+}
+
+impl Payload for AnnouncementPayload {
+    const KIND: &'static str = kind::ANNOUNCEMENT;
+}
+
+impl AnnouncementPayload {
+    /// Whether the announcement is delivered to users of `im`.
+    #[must_use]
+    pub fn targets(&self, im: &str) -> bool {
+        self.im.as_deref().map_or(true, |target| target == im)
+    }
+}
+
+/// Payload of [`kind::ANOMALY`] events, whose entity is the one of the
+/// flooding source.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AnomalyPayload {
+    /// Kind of events of the flooding source.
+    pub kind: String,
+    /// Count of events from the source in the window when the flood was
+    /// detected.
+    pub count: u64,
+    /// Max count of events from the source in the window.
+    pub limit: u64,
+    /// Length of the window in seconds.
+    pub window: u64,
+    /// Whether further events from the source are dropped until the flood
+    /// is over.
+    pub suppressed: bool,
+}
+
+impl Payload for AnomalyPayload {
+    const KIND: &'static str = kind::ANOMALY;
 }
 
 /// IM subscriber.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct User {
     /// The unique identifier of the user. The same physical user in different
     /// IMs should have different id.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
     pub id: Uuid,
     /// The IM that the user is in, e.g. "tg" for telegram
     pub im: String,
@@ -180,17 +1302,303 @@ pub struct User {
     pub avatar: Option<Url>,
     /// The events that the user is subscribed to.
     pub event_filter: EventFilter,
+    /// Daily period in which events are held back until it ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    /// Max number of events delivered in a clock hour. Further events are
+    /// held back until the next hour.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_hour: Option<u32>,
+    /// Id shared by users linked as the same person in different IMs, who
+    /// share one event filter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<crate::schema::Uuid>"))]
+    pub link_id: Option<Uuid>,
+    /// Whether deliveries to the user are disabled, e.g. by a bot after
+    /// repeated failures. Disabled users receive no events until enabled
+    /// again.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Bumped whenever the user is registered again, so that tokens issued to
+    /// the user before are rejected.
+    #[serde(default)]
+    pub token_generation: u32,
+}
+
+/// Daily period in which a user doesn't want to be notified, in local time of
+/// the user.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct QuietHours {
+    /// Start of the period, in minutes after midnight.
+    pub start: u16,
+    /// End of the period, in minutes after midnight. Periods across midnight
+    /// end before they start, e.g. 23:00 to 07:00 is `{ "start": 1380,
+    /// "end": 420 }`.
+    pub end: u16,
+    /// Offset of the local time from UTC in minutes, e.g. `480` for UTC+8.
+    #[serde(default)]
+    pub utc_offset: i16,
+}
+
+impl QuietHours {
+    /// Minutes in a day.
+    const DAY: i64 = 24 * 60;
+    /// Max offset of a time zone from UTC, in minutes.
+    const MAX_OFFSET: i16 = 14 * 60;
+
+    /// Check that times are within a day and the offset is a valid time zone.
+    ///
+    /// # Errors
+    /// Returns an error describing the invalid field.
+    pub fn validate(&self) -> Result<()> {
+        for (name, minutes) in [("start", self.start), ("end", self.end)] {
+            if i64::from(minutes) >= Self::DAY {
+                bail!("Quiet hours `{name}` must be less than {} minutes", Self::DAY);
+            }
+        }
+        if self.utc_offset.abs() > Self::MAX_OFFSET {
+            bail!("Quiet hours `utc_offset` must be within {} minutes", Self::MAX_OFFSET);
+        }
+        Ok(())
+    }
+
+    /// If `time` is in the period, the time it ends. Otherwise `None`.
+    #[must_use]
+    pub fn end_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+        let minutes = i64::try_from(secs / 60).ok()?;
+        let local = (minutes + i64::from(self.utc_offset)).rem_euclid(Self::DAY);
+        let (start, end) = (i64::from(self.start), i64::from(self.end));
+        let quiet = if start <= end {
+            (start..end).contains(&local)
+        } else {
+            local >= start || local < end
+        };
+        if !quiet {
+            return None;
+        }
+        let end_minutes = minutes + (end - local).rem_euclid(Self::DAY);
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(end_minutes).ok()? * 60))
+    }
 }
 
 /// Filter for events.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct EventFilter {
     /// Event must be related to these entities.
+    #[cfg_attr(feature = "schema", schemars(with = "HashSet<crate::schema::Uuid>"))]
     pub entities: HashSet<Uuid>,
+    /// Event must be related to members of these groups, including entities
+    /// joining them later. Members are subscribed like `entities`.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    #[cfg_attr(feature = "schema", schemars(with = "HashSet<crate::schema::Uuid>"))]
+    pub groups: HashSet<Uuid>,
+    /// Event must be in these kinds.
+    pub kinds: HashSet<String>,
+    /// Subscriptions to entities that end at a given time.
+    #[serde(default)]
+    pub expiry: Vec<Expiry>,
+    /// Kinds subscribed for specific entities, taking precedence over `kinds`.
+    #[serde(default)]
+    pub overrides: Vec<KindOverride>,
+    /// Events matching any of these rules are dropped.
+    #[serde(default)]
+    pub exclusions: Vec<Exclusion>,
+    /// Language to receive events in. Translated fields are replaced by their
+    /// translations into it, if there are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<crate::schema::LanguageCode>"))]
+    pub language: Option<LanguageCode>,
+}
+
+impl EventFilter {
+    /// Kinds referred to by the filter, with duplicates.
+    pub fn referenced_kinds(&self) -> impl Iterator<Item = &str> {
+        self.kinds
+            .iter()
+            .chain(self.overrides.iter().flat_map(|r#override| &r#override.kinds))
+            .map(String::as_str)
+            .chain(self.exclusions.iter().filter_map(|exclusion| exclusion.kind.as_deref()))
+    }
+
+    /// Whether events of `kind` from `entity` pass the filter.
+    ///
+    /// Exclusions depending on event fields are not checked. Use
+    /// [`EventFilter::matches`] if the event is available.
+    #[must_use]
+    pub fn matches_kind(&self, entity: Uuid, kind: &str) -> bool {
+        self.entity_kinds(entity).is_some_and(|kinds| kinds.contains(kind))
+            && !self
+                .exclusions
+                .iter()
+                .any(|exclusion| exclusion.field.is_none() && exclusion.applies(entity, kind))
+    }
+
+    /// Whether the event passes the filter.
+    #[must_use]
+    pub fn matches(&self, event: &Event) -> bool {
+        self.matches_kind(event.entity, &event.kind)
+            && !self.exclusions.iter().any(|exclusion| {
+                exclusion.field.as_ref().is_some_and(|field| {
+                    exclusion.applies(event.entity, &event.kind)
+                        && event.fields.get(field) == Some(&Value::Bool(true))
+                })
+            })
+    }
+
+    /// Subscribe `entity` if it's a member of a subscribed group, so that its
+    /// events pass the filter. Returns whether it's added.
+    ///
+    /// Groups aren't expanded in stored filters, so that members follow
+    /// changes of groups. Filters are expanded for an event once its entity
+    /// and group are known instead.
+    pub fn expand_group(&mut self, entity: Uuid, group: Option<Uuid>) -> bool {
+        group.is_some_and(|group| self.groups.contains(&group)) && self.entities.insert(entity)
+    }
+
+    /// Kinds subscribed for `entity`, or `None` if it's not subscribed.
+    fn entity_kinds(&self, entity: Uuid) -> Option<&HashSet<String>> {
+        self.entities.contains(&entity).then(|| {
+            self.overrides
+                .iter()
+                .find(|r#override| r#override.entity == entity)
+                .map_or(&self.kinds, |r#override| &r#override.kinds)
+        })
+    }
+
+    /// Merge `other` into the filter, so that every kind either of them
+    /// subscribes for an entity is subscribed.
+    ///
+    /// A subscription expires at the later expiry, or never if it doesn't
+    /// expire in either filter. Groups of both are subscribed. Exclusions are
+    /// kept only if both filters have them, and the language is taken from
+    /// `other` if not set.
+    pub fn merge(&mut self, other: &Self) {
+        let entities: HashSet<Uuid> = self.entities.union(&other.entities).copied().collect();
+        let kinds: HashSet<String> = self.kinds.union(&other.kinds).cloned().collect();
+
+        let mut overrides = vec![];
+        let mut expiry = vec![];
+        for &entity in &entities {
+            let entity_kinds: HashSet<String> = [&*self, other]
+                .into_iter()
+                .filter_map(|filter| filter.entity_kinds(entity))
+                .flatten()
+                .cloned()
+                .collect();
+            if entity_kinds != kinds {
+                overrides.push(KindOverride {
+                    entity,
+                    kinds: entity_kinds,
+                });
+            }
+
+            let until: Option<Vec<SystemTime>> = [&*self, other]
+                .into_iter()
+                .filter(|filter| filter.entities.contains(&entity))
+                .map(|filter| {
+                    filter
+                        .expiry
+                        .iter()
+                        .find(|expiry| expiry.entity == entity)
+                        .map(|expiry| expiry.until)
+                })
+                .collect();
+            if let Some(until) = until.and_then(|until| until.into_iter().max()) {
+                expiry.push(Expiry { entity, until });
+            }
+        }
+
+        self.entities = entities;
+        self.groups.extend(other.groups.iter().copied());
+        self.kinds = kinds;
+        self.overrides = overrides;
+        self.expiry = expiry;
+        self.exclusions
+            .retain(|exclusion| other.exclusions.contains(exclusion));
+        self.language = self.language.or(other.language);
+    }
+
+    /// Remove entities whose subscription has expired at `now`, along with
+    /// expiry entries of entities no longer subscribed.
+    ///
+    /// Returns removed entities.
+    pub fn prune_expired(&mut self, now: SystemTime) -> Vec<Uuid> {
+        let mut removed = vec![];
+        let entities = &mut self.entities;
+        self.expiry.retain(|expiry| {
+            if expiry.until <= now {
+                if entities.remove(&expiry.entity) {
+                    removed.push(expiry.entity);
+                }
+                false
+            } else {
+                entities.contains(&expiry.entity)
+            }
+        });
+        removed
+    }
+
+    /// Expiry entries of subscribed entities that expire after `now` but
+    /// `within` it.
+    pub fn expiring(&self, now: SystemTime, within: Duration) -> impl Iterator<Item = &Expiry> {
+        self.expiry.iter().filter(move |expiry| {
+            expiry.until > now
+                && expiry.until <= now + within
+                && self.entities.contains(&expiry.entity)
+        })
+    }
+}
+
+/// Expiry time of a subscription to an entity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Expiry {
+    /// The subscribed entity.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
+    pub entity: Uuid,
+    /// The subscription is removed after this time.
+    #[serde(with = "humantime_serde")]
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Timestamp"))]
+    pub until: SystemTime,
+}
+
+/// Kinds subscribed for an entity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct KindOverride {
+    /// The subscribed entity.
+    #[cfg_attr(feature = "schema", schemars(with = "crate::schema::Uuid"))]
+    pub entity: Uuid,
     /// Event must be in these kinds.
     pub kinds: HashSet<String>,
 }
 
+/// Rule to drop events. Unset conditions match any event, e.g. `{ "kind":
+/// "twitter", "field": "is_rt" }` drops retweets of all entities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Exclusion {
+    /// Event is related to this entity.
+    #[serde(default)]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<crate::schema::Uuid>"))]
+    pub entity: Option<Uuid>,
+    /// Event is in this kind.
+    #[serde(default)]
+    pub kind: Option<String>,
+    /// Top level field of the event is `true`.
+    #[serde(default)]
+    pub field: Option<String>,
+}
+
+impl Exclusion {
+    fn applies(&self, entity: Uuid, kind: &str) -> bool {
+        self.entity.is_none_or(|e| e == entity) && self.kind.as_deref().is_none_or(|k| k == kind)
+    }
+}
+
 /// Wrapper for model providing `MongoDB` `ObjectId`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InDB<T> {
@@ -226,3 +1634,622 @@ impl<T> DerefMut for InDB<T> {
         &mut self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, SystemTime},
+    };
+
+    use isolanguage_1::LanguageCode;
+    use mongodb::bson::{to_bson, Uuid};
+    use opentelemetry::{
+        trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+        Context,
+    };
+    use serde_json::json;
+
+    use crate::models::{
+        AnnouncementPayload,
+        BroadcastPayload,
+        BroadcastReminderPayload,
+        BroadcastStartPayload,
+        Event,
+        EventFilter,
+        Exclusion,
+        Expiry,
+        Id,
+        KindOverride,
+        Labels,
+        LiveStartPayload,
+        MembersBroadcastStartPayload,
+        Meta,
+        Name,
+        Payload,
+        PremiereStartPayload,
+        QuietHours,
+        Task,
+        TaskTemplate,
+        TweetPayload,
+        AUTO_TASK_PARAM,
+        EXPIRES_AT,
+        TRANSLATIONS,
+        kind,
+        validate_kind,
+        validate_task_kind,
+    };
+
+    #[test]
+    fn must_convert_ids() {
+        let uuid = Uuid::new();
+        let id = Id::from(uuid);
+        assert_eq!(id, uuid);
+        assert_eq!(Uuid::from(id), uuid);
+        assert_eq!(Id::from(uuid::Uuid::from(id)), id);
+        assert_eq!(id.to_string().parse::<Id>().unwrap(), id);
+        assert!(Id::nil().is_nil());
+
+        // Serialized the same as the wrapped uuid.
+        assert_eq!(
+            serde_json::to_value(id).unwrap(),
+            serde_json::to_value(uuid).unwrap()
+        );
+        assert_eq!(to_bson(&id).unwrap(), to_bson(&uuid).unwrap());
+    }
+
+    #[test]
+    fn must_end_quiet_hours() {
+        // 23:00 to 07:00 in UTC+8
+        let quiet = QuietHours {
+            start: 23 * 60,
+            end: 7 * 60,
+            utc_offset: 8 * 60,
+        };
+        quiet.validate().unwrap();
+        let at = |hour: u64, minute: u64| {
+            SystemTime::UNIX_EPOCH + Duration::from_secs((hour * 60 + minute) * 60)
+        };
+
+        // 15:00 UTC is 23:00 local, 22:59 UTC is 06:59 local the next day.
+        assert_eq!(quiet.end_after(at(15, 0)), Some(at(23, 0)));
+        assert_eq!(quiet.end_after(at(22, 59)), Some(at(23, 0)));
+        assert_eq!(quiet.end_after(at(18, 30)), Some(at(23, 0)));
+        assert_eq!(quiet.end_after(at(14, 59)), None);
+        assert_eq!(quiet.end_after(at(23, 0)), None);
+
+        // Periods within a day
+        let quiet = QuietHours {
+            start: 60,
+            end: 120,
+            utc_offset: 0,
+        };
+        assert_eq!(quiet.end_after(at(24 + 1, 30)), Some(at(24 + 2, 0)));
+        assert_eq!(quiet.end_after(at(24 + 2, 0)), None);
+
+        assert!(QuietHours { start: 24 * 60, ..quiet }.validate().is_err());
+        assert!(QuietHours { utc_offset: -15 * 60, ..quiet }.validate().is_err());
+    }
+
+    #[test]
+    fn must_prune_expired() {
+        let now = SystemTime::now();
+        let (expired, active, unexpiring, unsubscribed) =
+            (Uuid::new(), Uuid::new(), Uuid::new(), Uuid::new());
+        let mut filter = EventFilter {
+            entities: [expired, active, unexpiring].into_iter().collect(),
+            groups: Default::default(),
+            kinds: Default::default(),
+            overrides: vec![],
+            exclusions: vec![],
+            expiry: vec![
+                Expiry {
+                    entity: expired,
+                    until: now - Duration::from_secs(1),
+                },
+                Expiry {
+                    entity: active,
+                    until: now + Duration::from_secs(1),
+                },
+                Expiry {
+                    entity: unsubscribed,
+                    until: now + Duration::from_secs(1),
+                },
+            ],
+            language: None,
+        };
+
+        assert_eq!(filter.prune_expired(now), vec![expired]);
+        assert_eq!(filter.entities, [active, unexpiring].into_iter().collect());
+        assert_eq!(
+            filter.expiry,
+            vec![Expiry {
+                entity: active,
+                until: now + Duration::from_secs(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn must_find_expiring() {
+        let now = SystemTime::now();
+        let (expired, soon, later, unsubscribed) =
+            (Uuid::new(), Uuid::new(), Uuid::new(), Uuid::new());
+        let expiry = |entity, until| Expiry { entity, until };
+        let filter = EventFilter {
+            entities: [expired, soon, later].into_iter().collect(),
+            groups: Default::default(),
+            kinds: Default::default(),
+            overrides: vec![],
+            exclusions: vec![],
+            expiry: vec![
+                expiry(expired, now),
+                expiry(soon, now + Duration::from_secs(60)),
+                expiry(later, now + Duration::from_secs(120)),
+                expiry(unsubscribed, now + Duration::from_secs(60)),
+            ],
+            language: None,
+        };
+
+        assert_eq!(
+            filter
+                .expiring(now, Duration::from_secs(60))
+                .collect::<Vec<_>>(),
+            vec![&expiry(soon, now + Duration::from_secs(60))]
+        );
+        assert_eq!(filter.expiring(now, Duration::ZERO).count(), 0);
+    }
+
+    #[test]
+    fn must_merge() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60);
+        let (shared, expiring, overridden, only) =
+            (Uuid::new(), Uuid::new(), Uuid::new(), Uuid::new());
+        let (ours, theirs) = (Uuid::new(), Uuid::new());
+        let rt = Exclusion {
+            entity: None,
+            kind: Some("twitter".to_owned()),
+            field: Some("is_rt".to_owned()),
+        };
+        let mut filter = EventFilter {
+            entities: [shared, expiring, overridden].into_iter().collect(),
+            groups: [ours].into_iter().collect(),
+            kinds: ["twitter".to_owned()].into_iter().collect(),
+            expiry: vec![Expiry {
+                entity: expiring,
+                until: now,
+            }],
+            overrides: vec![KindOverride {
+                entity: overridden,
+                kinds: ["youtube".to_owned()].into_iter().collect(),
+            }],
+            exclusions: vec![rt],
+            language: None,
+        };
+        filter.merge(&EventFilter {
+            entities: [shared, expiring, only].into_iter().collect(),
+            groups: [theirs].into_iter().collect(),
+            kinds: ["bilibili".to_owned()].into_iter().collect(),
+            expiry: vec![
+                Expiry {
+                    entity: expiring,
+                    until: later,
+                },
+                Expiry {
+                    entity: shared,
+                    until: later,
+                },
+            ],
+            overrides: vec![],
+            exclusions: vec![],
+            language: Some(LanguageCode::Ja),
+        });
+
+        assert_eq!(
+            filter.entities,
+            [shared, expiring, overridden, only].into_iter().collect()
+        );
+        for entity in [shared, expiring] {
+            assert!(filter.matches_kind(entity, "twitter"));
+            assert!(filter.matches_kind(entity, "bilibili"));
+        }
+        assert!(filter.matches_kind(overridden, "youtube"));
+        assert!(!filter.matches_kind(overridden, "twitter"));
+        assert!(filter.matches_kind(only, "bilibili"));
+        assert!(!filter.matches_kind(only, "twitter"));
+        assert_eq!(
+            filter.expiry,
+            vec![Expiry {
+                entity: expiring,
+                until: later,
+            }],
+            "Subscriptions expire only if they do in both filters"
+        );
+        assert_eq!(filter.groups, [ours, theirs].into_iter().collect());
+        assert!(filter.exclusions.is_empty());
+        assert_eq!(filter.language, Some(LanguageCode::Ja));
+    }
+
+    #[test]
+    fn must_expand_groups() {
+        let (group, other_group) = (Uuid::new(), Uuid::new());
+        let (member, excluded) = (Uuid::new(), Uuid::new());
+        let filter = EventFilter {
+            groups: [group].into_iter().collect(),
+            kinds: [String::from("twitter")].into_iter().collect(),
+            exclusions: vec![Exclusion {
+                entity: Some(excluded),
+                kind: None,
+                field: None,
+            }],
+            ..EventFilter::default()
+        };
+        assert!(!filter.matches_kind(member, "twitter"));
+
+        let mut expanded = filter.clone();
+        assert!(expanded.expand_group(member, Some(group)));
+        assert!(expanded.matches_kind(member, "twitter"));
+        assert!(!expanded.matches_kind(member, "youtube"));
+        assert!(!expanded.expand_group(member, Some(group)));
+
+        // Exclusions apply to members too.
+        assert!(expanded.expand_group(excluded, Some(group)));
+        assert!(!expanded.matches_kind(excluded, "twitter"));
+
+        let mut expanded = filter;
+        assert!(!expanded.expand_group(member, Some(other_group)));
+        assert!(!expanded.expand_group(member, None));
+        assert!(!expanded.matches_kind(member, "twitter"));
+    }
+
+    fn assert_described<T: Payload>(payload: &T) {
+        let spec = validate_kind(T::KIND).unwrap();
+        let fields = serde_json::to_value(payload).unwrap();
+        let mut names: Vec<_> = fields.as_object().unwrap().keys().collect();
+        let mut described: Vec<_> = spec.fields.iter().map(|field| field.name).collect();
+        names.sort_unstable();
+        described.sort_unstable();
+        assert_eq!(names, described, "fields of `{}` are not described", T::KIND);
+    }
+
+    #[test]
+    fn must_describe_payloads() {
+        assert_described(&TweetPayload {
+            id: 42,
+            text: String::from("Hello"),
+            photos: vec![],
+            link: String::from("https://twitter.com/suisei_hosimati/status/42"),
+            is_rt: false,
+        });
+        assert_described(&LiveStartPayload {
+            title: String::from("Live"),
+            link: String::from("https://live.bilibili.com/42"),
+            cover: None,
+        });
+        let broadcast = BroadcastPayload {
+            title: String::from("Premiere"),
+            link: String::from("https://www.youtube.com/watch?v=42"),
+            cover: None,
+            start_at: 1_700_000_000,
+        };
+        assert_described(&broadcast);
+        assert_described(&BroadcastReminderPayload(broadcast.clone()));
+        assert_described(&BroadcastStartPayload(broadcast.clone()));
+        assert_described(&MembersBroadcastStartPayload(broadcast.clone()));
+        assert_described(&PremiereStartPayload(broadcast));
+
+        let schema = validate_kind("bililive").unwrap().payload_schema();
+        assert_eq!(schema["properties"]["cover"]["type"], json!(["string", "null"]));
+        assert_eq!(schema["properties"]["title"]["type"], "string");
+    }
+
+    #[test]
+    fn must_target_ims() {
+        let mut payload = AnnouncementPayload {
+            message: String::from("Maintenance at 02:00 UTC"),
+            im: None,
+        };
+        assert!(payload.targets("tg"));
+        payload.im = Some(String::from("webhook"));
+        assert!(payload.targets("webhook"));
+        assert!(!payload.targets("tg"));
+
+        let event = Event::from_payload(Uuid::from_bytes([0; 16]), &payload).unwrap();
+        assert_eq!(event.decode_as::<AnnouncementPayload>().unwrap(), payload);
+        // Announcements can't be subscribed to.
+        assert!(validate_kind(kind::ANNOUNCEMENT).is_err());
+    }
+
+    #[test]
+    fn must_validate_kinds() {
+        assert!(validate_kind("twitter").is_ok());
+        let err = validate_kind("twitter/new_tweet").unwrap_err();
+        assert!(err.to_string().contains("twitter/new_tweet"));
+
+        assert_eq!(validate_task_kind("twitter").unwrap().len(), 1);
+        assert_eq!(validate_task_kind(kind::YOUTUBE).unwrap().len(), 5);
+        assert!(validate_kind(kind::YOUTUBE).is_err());
+        assert!(validate_task_kind(kind::YOUTUBE_BROADCAST_SCHEDULED).is_err());
+
+        let filter = EventFilter {
+            kinds: [String::from("twitter")].into_iter().collect(),
+            overrides: vec![KindOverride {
+                entity: Uuid::new(),
+                kinds: [String::from("bililive")].into_iter().collect(),
+            }],
+            exclusions: vec![Exclusion {
+                entity: None,
+                kind: Some(String::from("youtube")),
+                field: None,
+            }],
+            ..EventFilter::default()
+        };
+        let mut kinds: Vec<_> = filter.referenced_kinds().collect();
+        kinds.sort_unstable();
+        assert_eq!(kinds, ["bililive", "twitter", "youtube"]);
+    }
+
+    #[test]
+    fn must_render_task_templates() {
+        let mut meta = Meta {
+            name: Name {
+                name: [(LanguageCode::Ja, String::from("星街すいせい"))].into(),
+                default_language: LanguageCode::Ja,
+                aliases: vec![],
+            },
+            group: None,
+            profiles: BTreeMap::new(),
+            accounts: [(
+                String::from("youtube"),
+                String::from("UC5CwaMl1eIgY8h02uZw7u8A"),
+            )]
+            .into(),
+        };
+        let template = |params: serde_json::Value| TaskTemplate {
+            kind: String::from("youtube"),
+            params: serde_json::from_value(params).unwrap(),
+        };
+        let entity = Uuid::new();
+
+        let youtube = template(json!({
+            "channel_id": "{youtube}",
+            "placement": { "region": "jp" },
+            "url": "https://www.youtube.com/channel/{youtube}/{ {}",
+        }));
+        assert_eq!(
+            youtube.placeholders().collect::<Vec<_>>(),
+            ["youtube", "youtube"]
+        );
+        let task = youtube.render(entity, &meta).unwrap();
+        assert_eq!(task.entity, entity);
+        assert_eq!(task.kind, "youtube");
+        assert!(task.is_auto());
+        assert_eq!(
+            serde_json::Value::Object(task.params.clone()),
+            json!({
+                "channel_id": "UC5CwaMl1eIgY8h02uZw7u8A",
+                "placement": { "region": "jp" },
+                "url": "https://www.youtube.com/channel/UC5CwaMl1eIgY8h02uZw7u8A/{ {}",
+                AUTO_TASK_PARAM: true,
+            })
+        );
+        assert!(task.same_as(&youtube.render(Uuid::new(), &meta).unwrap()));
+
+        // No task without the account.
+        let bilibili = template(json!({ "uid": "{bilibili}" }));
+        assert_eq!(bilibili.render(entity, &meta), None);
+        meta.accounts
+            .insert(String::from("bilibili"), String::from("434334701"));
+        assert_eq!(
+            bilibili.render(entity, &meta).unwrap().params["uid"],
+            "434334701"
+        );
+    }
+
+    #[test]
+    fn must_decode_payload() {
+        let payload = TweetPayload {
+            id: 42,
+            text: String::from("Hello"),
+            photos: vec![],
+            link: String::from("https://twitter.com/suisei_hosimati/status/42"),
+            is_rt: false,
+        };
+        let mut event = Event::from_payload(Uuid::new(), &payload).unwrap();
+        assert_eq!(event.kind, "twitter");
+
+        // Meta fields are ignored.
+        event
+            .fields
+            .insert(String::from("x-translate-fields"), json!(["/text"]));
+        assert_eq!(event.decode_as::<TweetPayload>().unwrap(), payload);
+
+        assert!(event.decode_as::<LiveStartPayload>().is_err());
+    }
+
+    #[test]
+    fn must_localize() {
+        let mut event = Event::from_serializable(
+            "twitter",
+            Uuid::new(),
+            json!({ "text": "Hello", "quote": { "text": "World" }, "id": 42 }),
+        )
+        .unwrap();
+        assert_eq!(event.localized(LanguageCode::Zh), event);
+
+        event.fields.insert(
+            String::from(TRANSLATIONS),
+            json!({
+                "zh": { "/text": "你好", "/quote/text": "世界", "/missing": "无" },
+                "ja": { "/text": "こんにちは" },
+            }),
+        );
+        assert_eq!(
+            event.translation("/text", LanguageCode::Ja),
+            Some("こんにちは")
+        );
+        assert_eq!(event.translation("/quote/text", LanguageCode::Ja), None);
+
+        let zh = event.localized(LanguageCode::Zh);
+        assert_eq!(zh.fields["text"], "你好");
+        assert_eq!(zh.fields["quote"]["text"], "世界");
+        assert_eq!(zh.fields["id"], 42);
+        assert!(!zh.fields.contains_key("missing"));
+
+        let ja = event.localized(LanguageCode::Ja);
+        assert_eq!(ja.fields["text"], "こんにちは");
+        assert_eq!(ja.fields["quote"]["text"], "World");
+
+        assert_eq!(event.localized(LanguageCode::En), event);
+    }
+
+    #[test]
+    fn must_expire() {
+        let event = Event::from_serializable("bililive", Uuid::new(), json!({})).unwrap();
+        let now = SystemTime::now();
+        assert_eq!(event.expiry(), None);
+        assert!(!event.is_expired(now));
+
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let event = event.with_expiry(at);
+        assert_eq!(event.fields[EXPIRES_AT], 1_700_000_000);
+        assert_eq!(event.expiry(), Some(at));
+        assert!(!event.is_expired(at - Duration::from_secs(1)));
+        assert!(event.is_expired(at));
+
+        let event = event.with_ttl(Duration::from_secs(60));
+        assert!(!event.is_expired(now));
+        assert!(event.is_expired(now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn must_carry_trace_context() {
+        let mut event = Event::from_serializable("twitter", Uuid::new(), json!({})).unwrap();
+        assert!(!event.trace_context().has_active_span());
+
+        // Without an OpenTelemetry layer, spans have no context to attach.
+        drop(event.publish_span());
+        assert!(event.fields.is_empty());
+
+        let span_context = SpanContext::new(
+            TraceId::from_bytes([1; 16]),
+            SpanId::from_bytes([2; 8]),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        event.set_trace_context(&Context::new().with_remote_span_context(span_context.clone()));
+        assert_eq!(
+            event.fields["x-trace-context"],
+            json!({
+                "traceparent": "00-01010101010101010101010101010101-0202020202020202-01",
+                "tracestate": "",
+            })
+        );
+        assert_eq!(event.trace_context().span().span_context(), &span_context);
+    }
+
+    #[test]
+    fn must_chain_events() {
+        let entity = Uuid::new();
+        let source = Event::builder("twitter", entity)
+            .fields(json!({ "text": "hello" }))
+            .field("x-translate-fields", json!(["/text"]))
+            .build()
+            .unwrap();
+        assert_eq!(source.correlation(), source.id);
+        // Lineage is omitted from events starting a chain.
+        let value = serde_json::to_value(&source).unwrap();
+        assert!(value.get("correlation_id").is_none() && value.get("caused_by").is_none());
+
+        let derived = Event::builder("twitter", entity)
+            .caused_by(&source)
+            .build()
+            .unwrap();
+        let confirmation = Event::builder("twitter", entity)
+            .caused_by(&derived)
+            .build()
+            .unwrap();
+        assert_eq!(confirmation.caused_by, Some(derived.id));
+        assert_eq!(confirmation.correlation(), source.id);
+        let value = serde_json::to_value(&confirmation).unwrap();
+        assert_eq!(
+            serde_json::from_value::<Event>(value).unwrap(),
+            confirmation
+        );
+
+        assert!(Event::builder("twitter", entity).fields(1).build().is_err());
+    }
+
+    #[test]
+    fn must_deserialize_legacy_filter() {
+        let entity = Uuid::new();
+        let filter: EventFilter = serde_json::from_value(json!({
+            "entities": [entity],
+            "kinds": ["twitter"],
+        }))
+        .unwrap();
+        assert!(filter.overrides.is_empty());
+        assert!(filter.exclusions.is_empty());
+        assert!(filter.matches_kind(entity, "twitter"));
+        assert!(!filter.matches_kind(entity, "bililive"));
+    }
+
+    #[test]
+    fn must_match_filter() {
+        let (a, b) = (Uuid::new(), Uuid::new());
+        let filter = EventFilter {
+            entities: [a, b].into_iter().collect(),
+            groups: Default::default(),
+            kinds: [String::from("twitter")].into_iter().collect(),
+            expiry: vec![],
+            overrides: vec![KindOverride {
+                entity: b,
+                kinds: [String::from("bililive")].into_iter().collect(),
+            }],
+            exclusions: vec![Exclusion {
+                entity: Some(a),
+                kind: Some(String::from("twitter")),
+                field: Some(String::from("is_rt")),
+            }],
+            language: None,
+        };
+
+        let tweet = |entity, is_rt| {
+            Event::from_serializable("twitter", entity, json!({ "is_rt": is_rt })).unwrap()
+        };
+        assert!(filter.matches(&tweet(a, false)));
+        assert!(
+            !filter.matches(&tweet(a, true)),
+            "Retweets of `a` are excluded"
+        );
+        assert!(
+            filter.matches_kind(a, "twitter"),
+            "Field exclusions are skipped"
+        );
+        assert!(
+            !filter.matches(&tweet(b, false)),
+            "`b` only subscribes bililive"
+        );
+        assert!(filter.matches_kind(b, "bililive"));
+        assert!(!filter.matches_kind(Uuid::new(), "twitter"));
+    }
+
+    #[test]
+    fn must_parse_placement() {
+        let mut task = Task::new_bilibili("42", Uuid::new());
+        assert_eq!(task.placement().unwrap(), None);
+
+        task.params
+            .insert(String::from("placement"), json!({ "region": "cn" }));
+        assert_eq!(
+            task.placement().unwrap(),
+            Some(Labels::from([(String::from("region"), String::from("cn"))]))
+        );
+
+        task.params
+            .insert(String::from("placement"), json!({ "region": 1 }));
+        assert!(task.placement().is_err());
+    }
+}
@@ -1,26 +1,602 @@
 //! RPC protocol.
 
-use std::{fmt::Display, future::Future, pin::Pin};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display, Formatter},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use eyre::Result;
-use tarpc::server::{BaseChannel, Channel, Serve};
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tracing::{debug, info};
+use eyre::{bail, eyre, Result};
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use tarpc::{
+    client::Config as ClientConfig,
+    context::{self, Context},
+    server::{BaseChannel, Channel, Serve},
+};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    handshake::client::Request,
+    http::HeaderMap,
+    Error as WsError,
+    Message,
+};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::{adapter::WsTransport, models::Task};
+#[cfg(feature = "long-poll")]
+use crate::adapter::LongPoll;
+use crate::{
+    adapter::{multiplex, WsTransport},
+    lifecycle::{Hooked, Hooks},
+    models::{Labels, Task},
+};
 
 /// RPC protocol for worker-coordinator communication.
 #[tarpc::service]
 pub trait WorkerRpc {
-    /// Ping the worker.
-    async fn ping(id: u64) -> u64;
+    /// Check that the worker is alive, and get the liveness of each task it
+    /// runs. Sent by the coordinator every ping interval.
+    ///
+    /// Workers recording activity with their [`TaskReporter`] can implement
+    /// this with [`TaskReporter::liveness`].
+    async fn heartbeat() -> Vec<TaskLiveness>;
     /// Add a task to the worker. Return `false` if the task already exists.
     async fn add_task(task: Task) -> bool;
     /// Remove a task from the worker. Return `false` if the task was not found.
     async fn remove_task(id: Uuid) -> bool;
-    /// Get the list of tasks running on the worker.
+    /// Add tasks to the worker. Return whether each task is added, in the
+    /// same order, like [`add_task`](WorkerRpc::add_task).
+    ///
+    /// Workers adding tasks one by one can implement this with [`add_each`].
+    async fn add_tasks(tasks: Vec<Task>) -> Vec<bool>;
+    /// Remove tasks from the worker. Return whether each task is removed, in
+    /// the same order, like [`remove_task`](WorkerRpc::remove_task).
+    ///
+    /// Workers removing tasks one by one can implement this with
+    /// [`remove_each`].
+    async fn remove_tasks(ids: Vec<Uuid>) -> Vec<bool>;
+    /// Get the list of tasks running on the worker. Queried by the
+    /// coordinator when the worker joins, to adopt tasks it kept from a lost
+    /// connection.
     async fn tasks() -> Vec<Task>;
+    /// Publish recent activity of a task since given time as backfill events
+    /// in the background. Return `false` if the worker doesn't support
+    /// backfill or the task is invalid.
+    async fn backfill(task: Task, since: SystemTime) -> bool;
+}
+
+/// RPC protocol for workers to report to the coordinator they joined, over
+/// the same connection as [`WorkerRpc`].
+#[tarpc::service]
+pub trait CoordinatorRpc {
+    /// Report the status of a task running on the worker.
+    async fn report_task_status(id: Uuid, status: TaskStatus);
+}
+
+/// Status of a task reported by the worker running it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// The task runs normally again.
+    Healthy,
+    /// The task keeps failing, e.g. the channel it watches is deleted.
+    Failing {
+        /// Reason of the failure.
+        reason: String,
+    },
+}
+
+/// Liveness of a task running on a worker, sent in heartbeats.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TaskLiveness {
+    /// Task ID.
+    pub id: Uuid,
+    /// Last time the task produced activity, e.g. polled a timeline, by the
+    /// clock of the worker. `None` if it hasn't yet.
+    pub last_activity: Option<SystemTime>,
+    /// Count of errors of the task since it started on the worker.
+    pub errors: u64,
+}
+
+impl TaskLiveness {
+    /// Liveness of a task without activity yet.
+    #[must_use]
+    pub const fn new(id: Uuid) -> Self {
+        Self {
+            id,
+            last_activity: None,
+            errors: 0,
+        }
+    }
+}
+
+/// Handle for a worker to report the status of its tasks to the coordinator
+/// it joined.
+///
+/// Clones share the same connection. Pass one in [`JoinOptions`], and reports
+/// are sent once joined. Reports are dropped while not connected.
+///
+/// It also records activity and errors of tasks, answered in heartbeats, which
+/// are kept across connections.
+#[derive(Clone, Default)]
+pub struct TaskReporter(Arc<Mutex<ReporterState>>);
+
+#[derive(Default)]
+struct ReporterState {
+    client: Option<CoordinatorRpcClient>,
+    /// Tasks reported failing to the current coordinator.
+    failing: HashSet<Uuid>,
+    /// Liveness of tasks with activity or errors recorded.
+    activity: HashMap<Uuid, TaskLiveness>,
+}
+
+impl ReporterState {
+    fn liveness(&mut self, id: Uuid) -> &mut TaskLiveness {
+        self.activity
+            .entry(id)
+            .or_insert_with(|| TaskLiveness::new(id))
+    }
+}
+
+impl Debug for TaskReporter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TaskReporter").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for TaskReporter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for TaskReporter {}
+
+impl TaskReporter {
+    /// Create a reporter not connected yet.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report a task failing, and count it as an error of the task.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub async fn failing(&self, id: Uuid, reason: impl Display + Send) {
+        let client = {
+            let mut state = self.0.lock().unwrap();
+            state.liveness(id).errors += 1;
+            state.failing.insert(id);
+            state.client.clone()
+        };
+        let status = TaskStatus::Failing {
+            reason: reason.to_string(),
+        };
+        report(client, id, status).await;
+    }
+
+    /// Report a task running normally, and record it as activity of the
+    /// task. Only sent if the task was reported failing, so that workers may
+    /// call it on every success.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub async fn healthy(&self, id: Uuid) {
+        let client = {
+            let mut state = self.0.lock().unwrap();
+            state.liveness(id).last_activity = Some(SystemTime::now());
+            if !state.failing.remove(&id) {
+                return;
+            }
+            state.client.clone()
+        };
+        report(client, id, TaskStatus::Healthy).await;
+    }
+
+    /// Record activity of a task without reporting anything, e.g. a message
+    /// received on a connection it keeps open.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub fn active(&self, id: Uuid) {
+        self.0.lock().unwrap().liveness(id).last_activity = Some(SystemTime::now());
+    }
+
+    /// Liveness of `running` tasks, in the same order. Records of other tasks,
+    /// e.g. removed ones, are forgotten.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub fn liveness(&self, running: impl IntoIterator<Item = Uuid>) -> Vec<TaskLiveness> {
+        let mut state = self.0.lock().unwrap();
+        let mut activity = std::mem::take(&mut state.activity);
+        let liveness: Vec<_> = running
+            .into_iter()
+            .map(|id| {
+                activity
+                    .remove(&id)
+                    .unwrap_or_else(|| TaskLiveness::new(id))
+            })
+            .collect();
+        state.activity = liveness
+            .iter()
+            .map(|liveness| (liveness.id, liveness.clone()))
+            .collect();
+        liveness
+    }
+
+    fn connect(&self, client: Option<CoordinatorRpcClient>) {
+        let mut state = self.0.lock().unwrap();
+        state.client = client;
+        state.failing.clear();
+    }
+}
+
+async fn report(client: Option<CoordinatorRpcClient>, id: Uuid, status: TaskStatus) {
+    let Some(client) = client else {
+        debug!(task_id = %id, ?status, "Not connected to coordinator, report dropped");
+        return;
+    };
+    if let Err(error) = client
+        .report_task_status(context::current(), id, status)
+        .await
+    {
+        warn!(task_id = %id, ?error, "Failed to report task status");
+    }
+}
+
+/// Fallback of [`WorkerRpc::add_tasks`] calling
+/// [`add_task`](WorkerRpc::add_task) for each task in order.
+pub async fn add_each<T>(worker: T, ctx: Context, tasks: Vec<Task>) -> Vec<bool>
+where
+    T: WorkerRpc + Clone,
+{
+    let mut added = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        added.push(worker.clone().add_task(ctx, task).await);
+    }
+    added
+}
+
+/// Fallback of [`WorkerRpc::remove_tasks`] calling
+/// [`remove_task`](WorkerRpc::remove_task) for each task in order.
+pub async fn remove_each<T>(worker: T, ctx: Context, ids: Vec<Uuid>) -> Vec<bool>
+where
+    T: WorkerRpc + Clone,
+{
+    let mut removed = Vec::with_capacity(ids.len());
+    for id in ids {
+        removed.push(worker.clone().remove_task(ctx, id).await);
+    }
+    removed
+}
+
+/// Version of the worker-coordinator protocol, bumped on incompatible changes
+/// to `WorkerRpc`, `CoordinatorRpc`, the join handshake or the framing.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Oldest protocol version still spoken, through the shims of
+/// [`compat`](crate::compat).
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// Delay before joining again with [`WorkerRpcExt::join_any`] if
+/// `reconnect_delay` is not set.
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Time to wait for a coordinator to accept a TCP connection when probing.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Header carrying the [`Hello`] message of a joining worker.
+pub const HELLO_HEADER: &str = "Sg-Worker-Hello";
+
+/// Header of the coordinator's response to a join, carrying the protocol
+/// version negotiated with the worker.
+pub const PROTOCOL_HEADER: &str = "Sg-Protocol-Version";
+
+/// Options a worker announces when joining a coordinator.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct JoinOptions {
+    /// Zone the worker is in.
+    pub zone: Option<String>,
+    /// Labels tasks can be constrained to.
+    pub labels: Labels,
+    /// Version of the worker.
+    pub version: String,
+    /// Task kinds the worker supports. Defaults to the kind it joins as.
+    pub kinds: Vec<String>,
+    /// Join again after this delay when failed to join or disconnected, e.g.
+    /// when coordinators fail over. Unless joining with
+    /// [`join_any`](WorkerRpcExt::join_any), the worker returns on
+    /// disconnection if not set.
+    pub reconnect_delay: Option<Duration>,
+    /// Reporter of task status, connected to the coordinator joined.
+    pub reporter: TaskReporter,
+    /// Hooks called as the worker starts, its tasks change and it joins or
+    /// loses coordinators.
+    pub hooks: Hooks,
+}
+
+/// Capabilities a worker sends as JSON in [`HELLO_HEADER`] when joining a
+/// coordinator.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    /// Version of the worker.
+    pub version: String,
+    /// Newest protocol version the worker speaks.
+    pub protocol: u32,
+    /// Oldest protocol version the worker speaks. Workers before negotiation
+    /// leave it out, speaking only `protocol`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_protocol: Option<u32>,
+    /// Task kinds the worker supports.
+    pub kinds: Vec<String>,
+    /// Labels tasks can be constrained to.
+    pub labels: Labels,
+}
+
+impl Hello {
+    /// Check if a worker joining as given kind is compatible with this
+    /// coordinator, and return the protocol version to speak with it, the
+    /// newest one both speak.
+    ///
+    /// # Errors
+    /// Returns the reason if the worker is incompatible.
+    pub fn check(&self, kind: &str) -> Result<u32> {
+        let min_protocol = self.min_protocol.unwrap_or(self.protocol);
+        let version = self.protocol.min(PROTOCOL_VERSION);
+        if version < min_protocol.max(MIN_PROTOCOL_VERSION) {
+            bail!(
+                "incompatible protocol version {}..={} of worker {}, coordinator speaks {}..={}",
+                min_protocol,
+                self.protocol,
+                self.version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            );
+        }
+        if !self.kinds.iter().any(|k| k == kind) {
+            bail!("worker joining as {kind} doesn't support it");
+        }
+        Ok(version)
+    }
+}
+
+/// Protocol version negotiated by a coordinator, in the headers of its
+/// response to a join. Coordinators before negotiation leave it out, having
+/// accepted [`PROTOCOL_VERSION`] only.
+pub(crate) fn negotiated(headers: &HeaderMap) -> Result<u32> {
+    let Some(value) = headers.get(PROTOCOL_HEADER) else {
+        return Ok(PROTOCOL_VERSION);
+    };
+    let version: u32 = value.to_str()?.parse()?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        bail!("Coordinator negotiated unsupported protocol version {version}");
+    }
+    Ok(version)
+}
+
+/// Error of a coordinator refusing a worker to join.
+pub(crate) fn refused(status: impl Display, reason: &str) -> eyre::Report {
+    eyre!("Coordinator refused to join: {status} {reason}")
+}
+
+/// Copy a client request. `Request` isn't `Clone` since its body may not be.
+fn copy_request(req: &Request) -> Request {
+    let mut copy = Request::new(());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+/// Build the request joining a coordinator at `addr`, carrying the worker
+/// metadata in headers.
+fn join_request(
+    addr: impl IntoClientRequest,
+    id: Uuid,
+    kind: &str,
+    options: &JoinOptions,
+) -> Result<Request> {
+    let mut req = addr.into_client_request()?;
+
+    let hello = Hello {
+        version: options.version.clone(),
+        protocol: PROTOCOL_VERSION,
+        min_protocol: Some(MIN_PROTOCOL_VERSION),
+        kinds: if options.kinds.is_empty() {
+            vec![kind.to_string()]
+        } else {
+            options.kinds.clone()
+        },
+        labels: options.labels.clone(),
+    };
+    req.headers_mut()
+        .insert(HELLO_HEADER, serde_json::to_string(&hello)?.parse()?);
+    req.headers_mut().insert("Sg-Worker-Kind", kind.parse()?);
+    req.headers_mut()
+        .insert("Sg-Worker-ID", id.to_string().parse()?);
+    if let Some(zone) = &options.zone {
+        req.headers_mut().insert("Sg-Worker-Zone", zone.parse()?);
+    }
+    Ok(req)
+}
+
+/// Whether a coordinator accepts TCP connections. Standby coordinators don't
+/// listen until elected.
+async fn probe(req: &Request) -> bool {
+    let uri = req.uri();
+    let Some(host) = uri.host() else {
+        return false;
+    };
+    let port = uri
+        .port_u16()
+        .unwrap_or(match uri.scheme_str() {
+            Some("wss" | "https") => 443,
+            _ => 80,
+        });
+    matches!(
+        timeout(PROBE_TIMEOUT, TcpStream::connect((host, port))).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Serve the first responding coordinator of `reqs`, and join again after
+/// `delay` once disconnected, trying coordinators in order from the one last
+/// joined. Tasks are kept on disconnection, and adopted by the coordinator
+/// joined next, which removes ones it assigned elsewhere meanwhile. They are
+/// dropped if joining fails, since they are likely assigned elsewhere by then.
+async fn serve_any<T>(
+    worker: T,
+    reqs: Vec<Request>,
+    delay: Duration,
+    options: &JoinOptions,
+) -> Result<()>
+where
+    T: WorkerRpc + Clone + Send,
+    ServeWorkerRpc<T>: Serve<WorkerRpcRequest, Resp = WorkerRpcResponse, Fut = WorkerRpcResponseFut<T>>
+        + Send
+        + 'static,
+    WorkerRpcResponseFut<T>: Send + 'static,
+    <T as WorkerRpc>::TasksFut: Send,
+    <T as WorkerRpc>::RemoveTasksFut: Send,
+{
+    let mut last = 0;
+    loop {
+        // No need to probe a single coordinator, which is joined anyway.
+        let mut alive = None;
+        for i in (0..reqs.len()).map(|i| (last + i) % reqs.len()) {
+            if reqs.len() == 1 || probe(&reqs[i]).await {
+                alive = Some(i);
+                break;
+            }
+            debug!(coordinator = %reqs[i].uri(), "Coordinator not responding");
+        }
+
+        match alive {
+            Some(i) => {
+                last = i;
+                let coordinator = reqs[i].uri().clone();
+                match connect(worker.clone(), copy_request(&reqs[i]), options).await {
+                    Ok(()) => warn!(%coordinator, ?delay, "Coordinator disconnected, rejoin later"),
+                    Err(error) => {
+                        warn!(
+                            %coordinator,
+                            ?error,
+                            ?delay,
+                            "Failed to join coordinator, retry later"
+                        );
+                        drop_tasks(worker.clone()).await;
+                    }
+                }
+            }
+            None => {
+                warn!(?delay, "No coordinator responding, retry later");
+                drop_tasks(worker.clone()).await;
+            }
+        }
+        sleep(delay).await;
+    }
+}
+
+/// Remove all tasks from a worker that failed to join, since the coordinator
+/// it joins next has probably assigned them to other workers.
+async fn drop_tasks<T: WorkerRpc + Clone>(worker: T) {
+    let ids = worker
+        .clone()
+        .tasks(context::current())
+        .await
+        .into_iter()
+        .map(|task| task.id.into())
+        .collect();
+    worker.remove_tasks(context::current(), ids).await;
+}
+
+/// Serve a coordinator over a single connection until it closes, calling
+/// membership hooks once joined and once disconnected.
+async fn connect<T>(worker: T, req: Request, options: &JoinOptions) -> Result<()>
+where
+    T: WorkerRpc + Clone + Send,
+    ServeWorkerRpc<T>: Serve<WorkerRpcRequest, Resp = WorkerRpcResponse, Fut = WorkerRpcResponseFut<T>>
+        + Send
+        + 'static,
+    WorkerRpcResponseFut<T>: Send + 'static,
+{
+    debug!("Connecting to coordinator");
+    let coordinator = req.uri().to_string();
+    if matches!(req.uri().scheme_str(), Some("http" | "https")) {
+        #[cfg(feature = "long-poll")]
+        {
+            let (stream, version) = LongPoll::connect(req).await?;
+
+            info!(
+                version,
+                "Coordinator connected over long polling, ready to receive tasks."
+            );
+            options.hooks.on_membership_change(Some(&coordinator)).await;
+            serve_stream(worker, stream, version, &options.reporter).await;
+            options.hooks.on_membership_change(None).await;
+            return Ok(());
+        }
+        #[cfg(not(feature = "long-poll"))]
+        bail!("Long polling transport is not enabled");
+    }
+
+    let (stream, resp) = tokio_tungstenite::connect_async(req)
+        .await
+        .map_err(|e| match e {
+            WsError::Http(resp) => refused(
+                resp.status(),
+                &String::from_utf8_lossy(resp.body().as_deref().unwrap_or_default()),
+            ),
+            e => e.into(),
+        })?;
+    let version = negotiated(resp.headers())?;
+
+    info!(version, "Coordinator connected, ready to receive tasks.");
+    options.hooks.on_membership_change(Some(&coordinator)).await;
+    serve_stream(worker, stream, version, &options.reporter).await;
+    options.hooks.on_membership_change(None).await;
+    Ok(())
+}
+
+/// Serve a coordinator over a connected stream, speaking the negotiated
+/// protocol version, with task reports sent back over the same stream, until
+/// it closes.
+async fn serve_stream<T, S>(worker: T, stream: S, version: u32, reporter: &TaskReporter)
+where
+    T: WorkerRpc + Clone + Send,
+    ServeWorkerRpc<T>: Serve<WorkerRpcRequest, Resp = WorkerRpcResponse, Fut = WorkerRpcResponseFut<T>>
+        + Send
+        + 'static,
+    WorkerRpcResponseFut<T>: Send + 'static,
+    S: Stream<Item = Result<Message, WsError>>
+        + Sink<Message, Error = WsError>
+        + Unpin
+        + Send
+        + 'static,
+{
+    let (worker_lane, coordinator_lane) = multiplex(stream);
+    let client = CoordinatorRpcClient::new(
+        ClientConfig::default(),
+        WsTransport::with_version(coordinator_lane, version),
+    )
+    .spawn();
+    reporter.connect(Some(client));
+
+    let channel = BaseChannel::with_defaults(WsTransport::with_version(worker_lane, version));
+    channel.execute(worker.serve()).await;
+
+    reporter.connect(None);
 }
 
 /// Extension trait for `WorkerRpc`.
@@ -32,15 +608,50 @@ pub trait WorkerRpcExt {
         id: Uuid,
         ty: impl Display + Send + 'static,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Join a coordinator, announcing the zone the worker is in.
+    fn join_with_zone(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        zone: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Join a coordinator, announcing the zone and labels of the worker.
+    fn join_with(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        options: JoinOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Join the first responding coordinator of `addrs`, and fail over to
+    /// another one once disconnected. Coordinators are tried in order,
+    /// starting from the one last joined.
+    ///
+    /// Joins again after `reconnect_delay` of `options`, or 5 seconds if not
+    /// set. Never returns unless `addrs` is empty or invalid.
+    fn join_any(
+        self,
+        addrs: Vec<String>,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        options: JoinOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
 }
 
 impl<T> WorkerRpcExt for T
 where
-    T: WorkerRpc + Clone + Send,
-    ServeWorkerRpc<T>: Serve<WorkerRpcRequest, Resp = WorkerRpcResponse, Fut = WorkerRpcResponseFut<Self>>
-        + Send
-        + 'static,
-    WorkerRpcResponseFut<Self>: Send + 'static,
+    T: WorkerRpc + Clone + Send + 'static,
+    <T as WorkerRpc>::HeartbeatFut: Send,
+    <T as WorkerRpc>::AddTaskFut: Send,
+    <T as WorkerRpc>::RemoveTaskFut: Send,
+    <T as WorkerRpc>::AddTasksFut: Send,
+    <T as WorkerRpc>::RemoveTasksFut: Send,
+    <T as WorkerRpc>::TasksFut: Send,
+    <T as WorkerRpc>::BackfillFut: Send,
 {
     fn join(
         self,
@@ -48,21 +659,171 @@ where
         id: Uuid,
         ty: impl Display + Send + 'static,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        Box::pin(async move {
-            let mut req = addr.into_client_request()?;
+        self.join_with_zone(addr, id, ty, None)
+    }
 
-            req.headers_mut()
-                .insert("Sg-Worker-Kind", ty.to_string().parse()?);
-            req.headers_mut()
-                .insert("Sg-Worker-ID", id.to_string().parse()?);
+    fn join_with_zone(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        zone: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.join_with(
+            addr,
+            id,
+            ty,
+            JoinOptions {
+                zone,
+                ..JoinOptions::default()
+            },
+        )
+    }
 
-            debug!("Connecting to coordinator");
-            let (stream, _) = tokio_tungstenite::connect_async(req).await?;
-            let channel = BaseChannel::with_defaults(WsTransport::new(stream));
+    fn join_with(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        options: JoinOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        Box::pin(async move {
+            let req = join_request(addr, id, &ty.to_string(), &options)?;
+            let worker = Hooked {
+                worker: self,
+                hooks: options.hooks.clone(),
+            };
+            options.hooks.on_start().await;
+            match options.reconnect_delay {
+                Some(delay) => serve_any(worker, vec![req], delay, &options).await,
+                None => connect(worker, req, &options).await,
+            }
+        })
+    }
 
-            info!("Coordinator connected, ready to receive tasks.");
-            channel.execute(self.serve()).await;
-            Ok(())
+    fn join_any(
+        self,
+        addrs: Vec<String>,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        options: JoinOptions,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        Box::pin(async move {
+            if addrs.is_empty() {
+                bail!("No coordinator to join");
+            }
+            let kind = ty.to_string();
+            let reqs = addrs
+                .into_iter()
+                .map(|addr| join_request(addr, id, &kind, &options))
+                .collect::<Result<_>>()?;
+            let delay = options.reconnect_delay.unwrap_or(DEFAULT_RECONNECT_DELAY);
+            let worker = Hooked {
+                worker: self,
+                hooks: options.hooks.clone(),
+            };
+            options.hooks.on_start().await;
+            serve_any(worker, reqs, delay, &options).await
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderMap};
+    use uuid::Uuid;
+
+    use crate::protocol::{
+        negotiated,
+        probe,
+        Hello,
+        TaskReporter,
+        MIN_PROTOCOL_VERSION,
+        PROTOCOL_HEADER,
+        PROTOCOL_VERSION,
+    };
+
+    #[tokio::test]
+    async fn must_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let req = format!("ws://{}", listener.local_addr().unwrap())
+            .into_client_request()
+            .unwrap();
+        assert!(probe(&req).await);
+
+        drop(listener);
+        assert!(!probe(&req).await);
+    }
+
+    #[test]
+    fn must_check_hello() {
+        let hello = Hello {
+            version: String::from("0.1.0"),
+            protocol: PROTOCOL_VERSION,
+            kinds: vec![String::from("twitter")],
+            ..Hello::default()
+        };
+        assert_eq!(hello.check("twitter").unwrap(), PROTOCOL_VERSION);
+
+        let err = hello.check("bililive").unwrap_err();
+        assert!(err.to_string().contains("bililive"), "{err}");
+
+        // The newest version both speak is negotiated.
+        let newer = Hello {
+            protocol: PROTOCOL_VERSION + 1,
+            min_protocol: Some(PROTOCOL_VERSION),
+            ..hello.clone()
+        };
+        assert_eq!(newer.check("twitter").unwrap(), PROTOCOL_VERSION);
+        let older = Hello {
+            protocol: MIN_PROTOCOL_VERSION,
+            ..hello.clone()
+        };
+        assert_eq!(older.check("twitter").unwrap(), MIN_PROTOCOL_VERSION);
+
+        for protocol in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let err = Hello {
+                protocol,
+                ..hello.clone()
+            }
+            .check("twitter")
+            .unwrap_err();
+            assert!(err.to_string().contains("protocol version"), "{err}");
+        }
+    }
+
+    #[test]
+    fn must_read_negotiated_version() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(negotiated(&headers).unwrap(), PROTOCOL_VERSION);
+
+        headers.insert(PROTOCOL_HEADER, MIN_PROTOCOL_VERSION.into());
+        assert_eq!(negotiated(&headers).unwrap(), MIN_PROTOCOL_VERSION);
+        headers.insert(PROTOCOL_HEADER, (PROTOCOL_VERSION + 1).into());
+        assert!(negotiated(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn must_record_liveness() {
+        let reporter = TaskReporter::new();
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+        reporter.failing(a, "timeout").await;
+        reporter.failing(a, "timeout").await;
+        reporter.healthy(a).await;
+        reporter.active(b);
+
+        let liveness = reporter.liveness([a, b]);
+        assert_eq!(liveness[0].errors, 2);
+        assert!(liveness[0].last_activity.is_some());
+        assert_eq!(liveness[1].errors, 0);
+        assert!(liveness[1].last_activity.is_some());
+
+        // Records of tasks no longer running are forgotten.
+        assert_eq!(reporter.liveness([b]).len(), 1);
+        let liveness = reporter.liveness([a]);
+        assert_eq!(liveness[0].errors, 0);
+        assert!(liveness[0].last_activity.is_none());
+    }
+}
@@ -0,0 +1,64 @@
+//! JSON schemas of foreign types in models.
+//!
+//! These types are never constructed. They only stand in for foreign types
+//! without a [`JsonSchema`] implementation, e.g.
+//! `#[schemars(with = "sg_core::schema::Uuid")]`.
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
+
+/// Schema of a UUID, serialized as a hyphenated string.
+pub struct Uuid;
+
+impl JsonSchema for Uuid {
+    fn schema_name() -> String {
+        String::from("Uuid")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        string_schema("uuid")
+    }
+}
+
+/// Schema of an ISO 639-1 language code, e.g. `en`.
+pub struct LanguageCode;
+
+impl JsonSchema for LanguageCode {
+    fn schema_name() -> String {
+        String::from("LanguageCode")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        let mut schema = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            ..SchemaObject::default()
+        };
+        schema.string().pattern = Some(String::from("^[a-z]{2}$"));
+        schema.metadata().description = Some(String::from("ISO 639-1 language code."));
+        schema.into()
+    }
+}
+
+/// Schema of a time, serialized as a RFC 3339 string by `humantime_serde`.
+pub struct Timestamp;
+
+impl JsonSchema for Timestamp {
+    fn schema_name() -> String {
+        String::from("Timestamp")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        string_schema("date-time")
+    }
+}
+
+fn string_schema(format: &str) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        format: Some(format.to_owned()),
+        ..SchemaObject::default()
+    }
+    .into()
+}
@@ -0,0 +1,362 @@
+//! Signing and verification of events.
+//!
+//! Workers sign events before publishing them, so that bots can tell events
+//! forged by a compromised middleware from genuine ones. Keys are written as
+//! `<alg>:<base64>`:
+//!
+//! - `hmac:<secret>` for HMAC-SHA256. Signers and verifiers share the secret.
+//! - `ed25519:<seed>` for an Ed25519 signing key, where the seed is 32 bytes.
+//! - `ed25519:<public key>` for an Ed25519 verifying key.
+//!
+//! Middlewares may still rewrite meta fields (`x-*`), fields listed in
+//! `x-translate-fields` and translations, which are left out of the signature.
+use std::{fmt, str::FromStr};
+
+use eyre::{bail, ensure, eyre, ContextCompat, Report, Result, WrapErr};
+use ring::{
+    hmac,
+    signature::{self, Ed25519KeyPair, KeyPair},
+};
+use serde_json::{json, Map, Value};
+
+use crate::models::{Event, Signature, SignatureAlg, TRANSLATIONS};
+
+/// Field listing JSON pointers of fields to be translated.
+const TRANSLATE_FIELDS: &str = "x-translate-fields";
+
+/// Key to sign events with.
+pub enum SigningKey {
+    /// HMAC-SHA256 secret.
+    Hmac(hmac::Key),
+    /// Ed25519 key pair.
+    Ed25519(Ed25519KeyPair),
+}
+
+/// Key to verify event signatures with.
+#[derive(Clone)]
+pub enum VerifyingKey {
+    /// HMAC-SHA256 secret.
+    Hmac(hmac::Key),
+    /// Ed25519 public key.
+    Ed25519(Vec<u8>),
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SigningKey").field(&self.alg()).finish()
+    }
+}
+
+impl fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("VerifyingKey").field(&self.alg()).finish()
+    }
+}
+
+/// Split a key into its algorithm and decoded bytes.
+fn parse_key(s: &str) -> Result<(SignatureAlg, Vec<u8>)> {
+    let (alg, key) = s
+        .split_once(':')
+        .wrap_err("key must be in the form of `<alg>:<base64>`")?;
+    let alg = match alg {
+        "hmac" => SignatureAlg::HmacSha256,
+        "ed25519" => SignatureAlg::Ed25519,
+        _ => bail!("unknown key algorithm: {}", alg),
+    };
+    let key = base64::decode(key.trim()).wrap_err("key is not valid base64")?;
+    ensure!(!key.is_empty(), "key is empty");
+    Ok((alg, key))
+}
+
+impl FromStr for SigningKey {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match parse_key(s)? {
+            (SignatureAlg::HmacSha256, secret) => {
+                Self::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+            }
+            (SignatureAlg::Ed25519, seed) => Self::Ed25519(
+                Ed25519KeyPair::from_seed_unchecked(&seed)
+                    .map_err(|_| eyre!("ed25519 seed must be 32 bytes"))?,
+            ),
+        })
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match parse_key(s)? {
+            (SignatureAlg::HmacSha256, secret) => {
+                Self::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+            }
+            (SignatureAlg::Ed25519, public) => {
+                ensure!(public.len() == 32, "ed25519 public key must be 32 bytes");
+                Self::Ed25519(public)
+            }
+        })
+    }
+}
+
+impl SigningKey {
+    /// Algorithm of the key.
+    #[must_use]
+    pub const fn alg(&self) -> SignatureAlg {
+        match self {
+            Self::Hmac(_) => SignatureAlg::HmacSha256,
+            Self::Ed25519(_) => SignatureAlg::Ed25519,
+        }
+    }
+
+    /// Key to verify signatures made by this key.
+    #[must_use]
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            Self::Hmac(key) => VerifyingKey::Hmac(key.clone()),
+            Self::Ed25519(pair) => VerifyingKey::Ed25519(pair.public_key().as_ref().to_vec()),
+        }
+    }
+
+    fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Hmac(key) => hmac::sign(key, payload).as_ref().to_vec(),
+            Self::Ed25519(pair) => pair.sign(payload).as_ref().to_vec(),
+        }
+    }
+}
+
+impl VerifyingKey {
+    /// Algorithm of the key.
+    #[must_use]
+    pub const fn alg(&self) -> SignatureAlg {
+        match self {
+            Self::Hmac(_) => SignatureAlg::HmacSha256,
+            Self::Ed25519(_) => SignatureAlg::Ed25519,
+        }
+    }
+
+    fn verify(&self, payload: &[u8], sig: &[u8]) -> bool {
+        match self {
+            Self::Hmac(key) => hmac::verify(key, payload, sig).is_ok(),
+            Self::Ed25519(public) => signature::UnparsedPublicKey::new(&signature::ED25519, public)
+                .verify(payload, sig)
+                .is_ok(),
+        }
+    }
+}
+
+impl Event {
+    /// Sign the event, replacing any existing signature.
+    ///
+    /// Fields listed in `x-translate-fields` are left unsigned.
+    pub fn sign(&mut self, key: &SigningKey) {
+        let unsigned: Vec<String> = self
+            .fields
+            .get(TRANSLATE_FIELDS)
+            .and_then(Value::as_array)
+            .map(|pointers| {
+                pointers
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let value = key.sign(&self.signed_payload(&unsigned));
+        self.signature = Some(Signature {
+            alg: key.alg(),
+            unsigned,
+            value: base64::encode(value),
+        });
+    }
+
+    /// Verify the signature of the event.
+    ///
+    /// # Errors
+    /// Returns an error if the event is unsigned, signed with another
+    /// algorithm, or the signature doesn't match.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<()> {
+        let signature = self.signature.as_ref().wrap_err("event is not signed")?;
+        ensure!(
+            signature.alg == key.alg(),
+            "event is signed with {:?}, expected {:?}",
+            signature.alg,
+            key.alg()
+        );
+        let value = base64::decode(&signature.value).wrap_err("signature is not valid base64")?;
+        ensure!(
+            key.verify(&self.signed_payload(&signature.unsigned), &value),
+            "signature mismatch"
+        );
+        Ok(())
+    }
+
+    /// Canonical bytes covered by the signature.
+    fn signed_payload(&self, unsigned: &[String]) -> Vec<u8> {
+        let mut fields = Value::Object(
+            self.fields
+                .iter()
+                .filter(|(key, _)| !key.starts_with("x-") && *key != TRANSLATIONS)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<Map<_, _>>(),
+        );
+        for pointer in unsigned {
+            if let Some(value) = fields.pointer_mut(pointer) {
+                *value = Value::Null;
+            }
+        }
+        let payload = json!({
+            "id": self.id.to_string(),
+            "kind": self.kind,
+            "entity": self.entity.to_string(),
+            "fields": fields,
+            "unsigned": unsigned,
+        });
+
+        let mut buf = Vec::new();
+        write_canonical(&payload, &mut buf);
+        buf
+    }
+}
+
+/// Write a json value with object keys sorted, so that the output doesn't
+/// depend on field order.
+fn write_canonical(value: &Value, buf: &mut Vec<u8>) {
+    match value {
+        Value::Array(values) => {
+            buf.push(b'[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                write_canonical(value, buf);
+            }
+            buf.push(b']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by_key(|(key, _)| *key);
+            buf.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                serde_json::to_writer(&mut *buf, key).expect("write to vec");
+                buf.push(b':');
+                write_canonical(value, buf);
+            }
+            buf.push(b'}');
+        }
+        scalar => {
+            serde_json::to_writer(&mut *buf, scalar).expect("write to vec");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::Uuid;
+    use serde_json::json;
+
+    use super::{SigningKey, VerifyingKey};
+    use crate::models::{Event, SignatureAlg};
+
+    const HMAC_KEY: &str = "hmac:c2VjcmV0";
+    const ED25519_SEED: &str = "ed25519:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn event() -> Event {
+        let mut event = Event::from_serializable(
+            "twitter",
+            Uuid::new(),
+            json!({ "text": "hello", "id": 1, "nested": { "b": 1, "a": [1, 2] } }),
+        )
+        .unwrap();
+        event
+            .fields
+            .insert(String::from("x-translate-fields"), json!(["/text"]));
+        event
+    }
+
+    #[test]
+    fn must_sign_and_verify() {
+        for key in [HMAC_KEY, ED25519_SEED] {
+            let key: SigningKey = key.parse().unwrap();
+            let mut event = event();
+            event.sign(&key);
+
+            let signature = event.signature.as_ref().unwrap();
+            assert_eq!(signature.alg, key.alg());
+            assert_eq!(signature.unsigned, ["/text"]);
+
+            // Survives serialization with reordered fields.
+            let mut event: Event =
+                serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+            event.fields = event.fields.into_iter().rev().collect();
+            event.verify(&key.verifying_key()).unwrap();
+        }
+    }
+
+    #[test]
+    fn must_tolerate_middlewares() {
+        let key: SigningKey = HMAC_KEY.parse().unwrap();
+        let mut event = event();
+        event.sign(&key);
+
+        event.fields.remove("x-translate-fields");
+        event.fields.insert(String::from("text"), json!("你好"));
+        event.fields.insert(
+            String::from("translations"),
+            json!({ "zh": { "/text": "你好" } }),
+        );
+        event.fields.insert(String::from("x-delay-id"), json!(1));
+        event.verify(&key.verifying_key()).unwrap();
+    }
+
+    #[test]
+    fn must_reject_forged() {
+        let key: SigningKey = ED25519_SEED.parse().unwrap();
+        let verifying_key = key.verifying_key();
+
+        let mut event = event();
+        assert!(event.verify(&verifying_key).is_err());
+
+        event.sign(&key);
+        let mut forged = event.clone();
+        forged.fields.insert(String::from("id"), json!(2));
+        assert!(forged.verify(&verifying_key).is_err());
+
+        let mut forged = event.clone();
+        forged
+            .signature
+            .as_mut()
+            .unwrap()
+            .unsigned
+            .push(String::from("/id"));
+        assert!(forged.verify(&verifying_key).is_err());
+
+        let other: SigningKey = HMAC_KEY.parse().unwrap();
+        assert!(event.verify(&other.verifying_key()).is_err());
+        let mut forged = event;
+        forged.sign(&other);
+        assert!(forged.verify(&verifying_key).is_err());
+    }
+
+    #[test]
+    fn must_parse_keys() {
+        let key: SigningKey = ED25519_SEED.parse().unwrap();
+        let public = match key.verifying_key() {
+            VerifyingKey::Ed25519(public) => base64::encode(public),
+            VerifyingKey::Hmac(_) => unreachable!(),
+        };
+        let verifying_key: VerifyingKey = format!("ed25519:{}", public).parse().unwrap();
+        assert_eq!(verifying_key.alg(), SignatureAlg::Ed25519);
+
+        assert!("c2VjcmV0".parse::<SigningKey>().is_err());
+        assert!("rsa:c2VjcmV0".parse::<SigningKey>().is_err());
+        assert!("hmac:".parse::<SigningKey>().is_err());
+        assert!("ed25519:c2VjcmV0".parse::<SigningKey>().is_err());
+        assert!("ed25519:c2VjcmV0".parse::<VerifyingKey>().is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! Storage of large event fields outside of messages.
+//!
+//! Publishers replace fields above a size threshold by references, e.g.
+//! `{ "$body": "<key>" }`, so that events stay within frame limits of the
+//! message queue. Consumers needing the content resolve references with the
+//! same store.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use eyre::{bail, Result, WrapErr};
+use mongodb::{
+    bson::{doc, DateTime, Document, Uuid},
+    options::IndexOptions,
+    Client,
+    Collection,
+    IndexModel,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    models::{Event, TRANSLATIONS},
+    utils::Redacted,
+};
+
+/// Key of the object referring to an offloaded field.
+pub const BODY_REF: &str = "$body";
+
+/// Collection of [`MongoBodyStore`].
+const COLLECTION: &str = "event_bodies";
+
+/// Time offloaded fields are kept in [`MongoBodyStore`], long enough for
+/// events delayed by middlewares to be consumed.
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Store of offloaded event fields.
+#[async_trait]
+pub trait EventBodyStore: Send + Sync {
+    /// Store a field value. Return the key to get it back with.
+    ///
+    /// # Errors
+    /// Returns an error if the value can't be stored.
+    async fn put(&self, value: &Value) -> Result<String>;
+    /// Get a stored field value.
+    ///
+    /// # Errors
+    /// Returns an error if the value is not found or can't be read.
+    async fn get(&self, key: &str) -> Result<Value>;
+}
+
+/// Key of the stored value if `value` is a reference.
+#[must_use]
+pub fn body_ref(value: &Value) -> Option<&str> {
+    match value.as_object() {
+        Some(object) if object.len() == 1 => object.get(BODY_REF)?.as_str(),
+        _ => None,
+    }
+}
+
+/// Replace fields serialized to more than `threshold` bytes by references to
+/// the store.
+///
+/// Meta fields (`x-*`) and translations are kept, since middlewares read and
+/// rewrite them.
+///
+/// # Errors
+/// Returns an error if a field can't be stored.
+pub async fn offload(
+    event: &mut Event,
+    store: &(impl EventBodyStore + ?Sized),
+    threshold: usize,
+) -> Result<()> {
+    for (name, value) in &mut event.fields {
+        if name.starts_with("x-") || name == TRANSLATIONS || body_ref(value).is_some() {
+            continue;
+        }
+        if serde_json::to_vec(value)?.len() > threshold {
+            let key = store
+                .put(value)
+                .await
+                .wrap_err_with(|| format!("Failed to offload field `{name}`"))?;
+            *value = json!({ BODY_REF: key });
+        }
+    }
+    Ok(())
+}
+
+/// Resolve a field of the event in place if it's a reference, and return it.
+///
+/// # Errors
+/// Returns an error if the stored value can't be got.
+pub async fn resolve_field<'a>(
+    event: &'a mut Event,
+    name: &str,
+    store: &(impl EventBodyStore + ?Sized),
+) -> Result<Option<&'a Value>> {
+    let Some(value) = event.fields.get_mut(name) else {
+        return Ok(None);
+    };
+    if let Some(key) = body_ref(value) {
+        *value = store
+            .get(key)
+            .await
+            .wrap_err_with(|| format!("Failed to resolve field `{name}`"))?;
+    }
+    Ok(Some(value))
+}
+
+/// Resolve all references of the event in place.
+///
+/// # Errors
+/// Returns an error if any stored value can't be got.
+pub async fn resolve(event: &mut Event, store: &(impl EventBodyStore + ?Sized)) -> Result<()> {
+    for (name, value) in &mut event.fields {
+        if let Some(key) = body_ref(value) {
+            *value = store
+                .get(key)
+                .await
+                .wrap_err_with(|| format!("Failed to resolve field `{name}`"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Offloaded fields stored in the `event_bodies` collection of MongoDB, and
+/// removed after 30 days.
+pub struct MongoBodyStore {
+    collection: Collection<Document>,
+}
+
+impl MongoBodyStore {
+    /// Connect to the database named in `uri`, e.g.
+    /// `mongodb://localhost:27017/stargazer-reborn`.
+    ///
+    /// # Errors
+    /// Returns an error if the uri has no database or the database is
+    /// unreachable.
+    pub async fn new(uri: &str) -> Result<Self> {
+        let client = Client::with_uri_str(uri)
+            .await
+            .wrap_err_with(|| format!("Failed to connect to MongoDB at {}", Redacted(uri)))?;
+        let Some(db) = client.default_database() else {
+            bail!("No database in MongoDB uri of the event body store");
+        };
+        let collection = db.collection(COLLECTION);
+        collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "created_at": 1 })
+                    .options(IndexOptions::builder().expire_after(RETENTION).build())
+                    .build(),
+                None,
+            )
+            .await?;
+        Ok(Self { collection })
+    }
+}
+
+#[async_trait]
+impl EventBodyStore for MongoBodyStore {
+    async fn put(&self, value: &Value) -> Result<String> {
+        let key = Uuid::new().to_string();
+        self.collection
+            .insert_one(
+                doc! {
+                    "_id": &key,
+                    "value": serde_json::to_string(value)?,
+                    "created_at": DateTime::now(),
+                },
+                None,
+            )
+            .await?;
+        Ok(key)
+    }
+
+    async fn get(&self, key: &str) -> Result<Value> {
+        let Some(body) = self.collection.find_one(doc! { "_id": key }, None).await? else {
+            bail!("Event body {key} not found, it may have expired");
+        };
+        Ok(serde_json::from_str(body.get_str("value")?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use async_trait::async_trait;
+    use eyre::{eyre, Result};
+    use mongodb::bson::Uuid;
+    use serde_json::{json, Value};
+
+    use crate::{
+        models::Event,
+        store::{body_ref, offload, resolve, resolve_field, EventBodyStore},
+    };
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<String, Value>>);
+
+    #[async_trait]
+    impl EventBodyStore for MemoryStore {
+        async fn put(&self, value: &Value) -> Result<String> {
+            let mut bodies = self.0.lock().unwrap();
+            let key = bodies.len().to_string();
+            bodies.insert(key.clone(), value.clone());
+            Ok(key)
+        }
+
+        async fn get(&self, key: &str) -> Result<Value> {
+            let bodies = self.0.lock().unwrap();
+            bodies.get(key).cloned().ok_or_else(|| eyre!("not found"))
+        }
+    }
+
+    #[tokio::test]
+    async fn must_offload_and_resolve() {
+        let media = Value::Array(vec![json!({ "url": "x".repeat(100) }); 4]);
+        let event = Event::from_serializable(
+            "twitter",
+            Uuid::new(),
+            json!({ "text": "hi", "media": media.clone(), "x-delay-note": "y".repeat(100) }),
+        )
+        .unwrap();
+        let store = MemoryStore::default();
+
+        let mut offloaded = event.clone();
+        offload(&mut offloaded, &store, 64).await.unwrap();
+        assert_eq!(offloaded.fields["text"], "hi");
+        assert_eq!(body_ref(&offloaded.fields["media"]), Some("0"));
+        // Meta fields are kept.
+        assert_eq!(offloaded.fields["x-delay-note"], event.fields["x-delay-note"]);
+
+        let mut lazy = offloaded.clone();
+        let resolved = resolve_field(&mut lazy, "media", &store).await.unwrap();
+        assert_eq!(resolved, Some(&media));
+        assert!(resolve_field(&mut lazy, "missing", &store).await.unwrap().is_none());
+
+        resolve(&mut offloaded, &store).await.unwrap();
+        assert_eq!(offloaded, event);
+    }
+}
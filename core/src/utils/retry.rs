@@ -0,0 +1,251 @@
+//! Retries of failed operations with exponential backoff and jitter.
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use sg_core::utils::RetryPolicy;
+//! let policy = RetryPolicy::new(3)
+//!     .with_backoff(Duration::from_millis(100), Duration::from_secs(5))
+//!     .with_jitter(0.2);
+//! assert_eq!(policy.backoff(2), Duration::from_millis(400));
+//! ```
+
+use std::{future::Future, thread, time::Duration};
+
+use rand::Rng;
+use tokio::time::sleep;
+use tracing::debug;
+
+/// Policy of retrying failed operations.
+///
+/// The delay before each retry doubles from `initial_backoff` up to
+/// `max_backoff`, and is randomly shortened by up to `jitter` of it, so that
+/// clients failing together don't retry together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each subsequent one.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+    /// Fraction of each delay that may be randomly cut off, from 0 to 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::never()
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    #[must_use]
+    pub const fn never() -> Self {
+        Self::new(0)
+    }
+
+    /// Retry at most `max_retries` times, starting with a delay of 200ms, up
+    /// to 10s and without jitter.
+    #[must_use]
+    pub const fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.0,
+        }
+    }
+
+    /// Set the delay before the first retry, and the upper bound of delays.
+    #[must_use]
+    pub const fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Set the fraction of each delay that may be randomly cut off. It's
+    /// clamped to between 0 and 1.
+    #[must_use]
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before the retry numbered `retry`, counting from 0, without
+    /// jitter.
+    #[must_use]
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+
+    /// Delay before the retry numbered `retry`, counting from 0, with jitter.
+    #[must_use]
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        if self.jitter <= 0.0 {
+            return backoff;
+        }
+        let cut = rand::thread_rng().gen_range(0.0..=self.jitter);
+        // Unbounded delays are too long to be scaled in floats.
+        Duration::try_from_secs_f64(backoff.as_secs_f64() * (1.0 - cut)).unwrap_or(backoff)
+    }
+
+    /// Run `attempt` until it succeeds or retries run out.
+    ///
+    /// # Errors
+    /// Returns the error of the last attempt.
+    pub async fn run<T, E, F, Fut>(&self, attempt: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::fmt::Debug,
+    {
+        self.run_if(attempt, |_| true).await
+    }
+
+    /// Run `attempt` until it succeeds, fails with an error `retry_if` rejects
+    /// or retries run out.
+    ///
+    /// # Errors
+    /// Returns the error of the last attempt.
+    pub async fn run_if<T, E, F, Fut, P>(&self, mut attempt: F, mut retry_if: P) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        P: FnMut(&E) -> bool,
+        E: std::fmt::Debug,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(error) if retry < self.max_retries && retry_if(&error) => {
+                    let delay = self.delay(retry);
+                    debug!(?error, ?delay, "Attempt failed, retry later");
+                    sleep(delay).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Like [`RetryPolicy::run_if`], but blocks the thread between attempts.
+    ///
+    /// # Errors
+    /// Returns the error of the last attempt.
+    pub fn run_blocking_if<T, E, F, P>(&self, mut attempt: F, mut retry_if: P) -> Result<T, E>
+    where
+        F: FnMut() -> Result<T, E>,
+        P: FnMut(&E) -> bool,
+        E: std::fmt::Debug,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt() {
+                Err(error) if retry < self.max_retries && retry_if(&error) => {
+                    let delay = self.delay(retry);
+                    debug!(?error, ?delay, "Attempt failed, retry later");
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::utils::RetryPolicy;
+
+    #[test]
+    fn must_backoff() {
+        let policy = RetryPolicy::new(8);
+        assert_eq!(policy.backoff(0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(1600));
+        assert_eq!(policy.backoff(7), Duration::from_secs(10));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+
+        let unbounded = policy.with_backoff(Duration::from_secs(1), Duration::MAX);
+        assert_eq!(unbounded.backoff(3), Duration::from_secs(8));
+        assert_eq!(unbounded.backoff(u32::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn must_jitter() {
+        let policy = RetryPolicy::new(8).with_jitter(0.5);
+        for retry in 0..8 {
+            let (backoff, delay) = (policy.backoff(retry), policy.delay(retry));
+            assert!(
+                delay <= backoff && delay >= backoff / 2,
+                "{delay:?} of {backoff:?}"
+            );
+        }
+        assert!(RetryPolicy::new(1).with_jitter(2.0).jitter <= 1.0);
+    }
+
+    #[tokio::test]
+    async fn must_retry_until_success() {
+        let policy = RetryPolicy::new(2).with_backoff(Duration::from_millis(1), Duration::MAX);
+
+        let mut attempts = 0;
+        let result: Result<_, &str> = policy
+            .run(|| {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 3 {
+                        Err("failed")
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result, Ok(3));
+
+        let mut attempts = 0;
+        let result: Result<(), _> = policy
+            .run(|| {
+                attempts += 1;
+                async { Err("failed") }
+            })
+            .await;
+        assert_eq!(result, Err("failed"));
+        assert_eq!(attempts, 3, "Attempted once and retried twice");
+    }
+
+    #[tokio::test]
+    async fn must_retry_if() {
+        let policy = RetryPolicy::new(5).with_backoff(Duration::from_millis(1), Duration::MAX);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = policy
+            .run_if(
+                || {
+                    attempts += 1;
+                    let attempt = attempts;
+                    async move { Err(attempt) }
+                },
+                |attempt| *attempt < 2,
+            )
+            .await;
+        assert_eq!(result, Err(2), "Permanent errors are not retried");
+
+        let mut attempts = 0;
+        let result: Result<(), _> = policy.run_blocking_if(
+            || {
+                attempts += 1;
+                Err(attempts)
+            },
+            |_| true,
+        );
+        assert_eq!(result, Err(6));
+    }
+}
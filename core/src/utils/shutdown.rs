@@ -0,0 +1,226 @@
+//! Graceful shutdown shared by all binaries.
+
+use std::{
+    fmt::{Debug, Formatter},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::Result;
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::{sync::watch, time::timeout};
+use tracing::{error, info, warn};
+
+use crate::utils::ScopedJoinHandle;
+
+/// Time a shutdown hook may take before it's abandoned.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Hook = Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send>;
+
+/// A cancellation token with ordered shutdown hooks.
+///
+/// Clones share the same state. Long running futures are wrapped with
+/// [`until`](Self::until) to stop once shutdown is triggered, e.g. by
+/// [`listen_signals`](Self::listen_signals). Before exit, binaries call
+/// [`run_hooks`](Self::run_hooks) to flush what's left, e.g. pending events.
+#[derive(Clone)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    hooks: Arc<Mutex<Vec<(String, Hook)>>>,
+}
+
+impl Debug for Shutdown {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("triggered", &self.is_triggered())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    /// Create a token not yet triggered.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            triggered: Arc::new(watch::channel(false).0),
+            hooks: Default::default(),
+        }
+    }
+
+    /// Trigger shutdown. Does nothing if already triggered.
+    pub fn trigger(&self) {
+        self.triggered
+            .send_if_modified(|triggered| !std::mem::replace(triggered, true));
+    }
+
+    /// Whether shutdown is triggered.
+    #[must_use]
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Wait until shutdown is triggered.
+    pub async fn triggered(&self) {
+        let mut rx = self.triggered.subscribe();
+        while !*rx.borrow_and_update() {
+            // The sender lives as long as `self`, so this never fails.
+            drop(rx.changed().await);
+        }
+    }
+
+    /// Run `fut` until it completes or shutdown is triggered, whichever comes
+    /// first. Returns `None` if `fut` is cancelled.
+    pub async fn until<F: Future>(&self, fut: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            () = self.triggered() => None,
+            output = fut => Some(output),
+        }
+    }
+
+    /// Trigger shutdown on SIGINT or SIGTERM, until the returned handle is
+    /// dropped.
+    #[must_use = "signals are no longer listened to once the handle is dropped"]
+    pub fn listen_signals(&self) -> ScopedJoinHandle<()> {
+        let this = self.clone();
+        ScopedJoinHandle(tokio::spawn(async move {
+            match signal().await {
+                Ok(()) => info!("Received signal, shutting down"),
+                Err(error) => error!(?error, "Failed to listen for signals, shutting down"),
+            }
+            this.trigger();
+        }))
+    }
+
+    /// Add a hook to run on shutdown. Hooks run one by one, in the order they
+    /// are added.
+    ///
+    /// Adding a hook under an existing `name` replaces it in place, so that
+    /// components restarted in the same process don't pile up hooks.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub fn on_shutdown<F, Fut>(&self, name: impl Into<String>, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let hook: Hook = Box::new(move || hook().boxed());
+        let mut hooks = self.hooks.lock().unwrap();
+        match hooks.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, existing)) => *existing = hook,
+            None => hooks.push((name, hook)),
+        }
+    }
+
+    /// Trigger shutdown and run all hooks in order.
+    ///
+    /// Each hook may take up to 10 seconds. Failed or timed out hooks are
+    /// logged and don't stop later ones.
+    ///
+    /// # Panics
+    /// Panics if the lock is poisoned.
+    pub async fn run_hooks(&self) {
+        self.trigger();
+        let hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        for (name, hook) in hooks {
+            match timeout(HOOK_TIMEOUT, hook()).await {
+                Ok(Ok(())) => info!(hook = %name, "Shutdown hook finished"),
+                Ok(Err(error)) => error!(hook = %name, ?error, "Shutdown hook failed"),
+                Err(_) => warn!(hook = %name, "Shutdown hook timed out"),
+            }
+        }
+    }
+}
+
+/// Wait for SIGINT, or SIGTERM on unix.
+async fn signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::pending,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use eyre::bail;
+    use tokio::{task::yield_now, time::timeout};
+
+    use crate::utils::Shutdown;
+
+    #[tokio::test]
+    async fn must_cancel_on_trigger() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_triggered());
+        assert_eq!(shutdown.until(async { 42 }).await, Some(42));
+
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.until(pending::<()>()).await }
+        });
+        yield_now().await;
+        shutdown.trigger();
+        shutdown.trigger();
+
+        let cancelled = timeout(Duration::from_secs(1), waiter).await.unwrap();
+        assert_eq!(cancelled.unwrap(), None);
+        assert!(shutdown.is_triggered());
+        // Futures started after shutdown are cancelled at once.
+        assert_eq!(shutdown.until(async { 42 }).await, None);
+    }
+
+    #[tokio::test]
+    async fn must_run_hooks_in_order() {
+        let shutdown = Shutdown::new();
+        let ran = Arc::new(Mutex::new(vec![]));
+        for name in ["a", "b", "c"] {
+            let ran = ran.clone();
+            shutdown.on_shutdown(name, move || async move {
+                ran.lock().unwrap().push(name);
+                if name == "a" {
+                    bail!("failed hooks don't stop later ones");
+                }
+                Ok(())
+            });
+        }
+        // Replace `b` in place.
+        let replaced = ran.clone();
+        shutdown.on_shutdown("b", move || async move {
+            replaced.lock().unwrap().push("b2");
+            Ok(())
+        });
+
+        shutdown.run_hooks().await;
+        assert!(shutdown.is_triggered());
+        assert_eq!(*ran.lock().unwrap(), ["a", "b2", "c"]);
+
+        // Hooks run only once.
+        shutdown.run_hooks().await;
+        assert_eq!(ran.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,299 @@
+//! Tracing setup shared by all binaries, with optional export of spans to an
+//! OpenTelemetry collector.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::LowerHex,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use eyre::{Result, WrapErr};
+use opentelemetry::{
+    global,
+    runtime::TokioCurrentThread,
+    sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::{self, TracerProvider},
+        Resource,
+    },
+    trace::{SpanKind, StatusCode, TraceError, TracerProvider as _},
+    Array,
+    Key,
+    KeyValue,
+    Value,
+};
+use serde_json::{json, Value as Json};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Environment variable of the collector endpoint. Spans are only exported if
+/// it's set.
+const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Install the global tracing subscriber, which logs to stdout with `filter`
+/// applied.
+///
+/// If `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are also exported to the
+/// collector at that endpoint with OTLP over HTTP, under the name `service`.
+/// Keep the returned guard until exit, so that pending spans are flushed.
+///
+/// # Errors
+/// Returns error if a global subscriber is already installed, or the endpoint
+/// is invalid.
+pub fn init_tracing(service: &'static str, filter: EnvFilter) -> Result<TracingGuard> {
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    let endpoint = match env::var(ENDPOINT_ENV) {
+        Ok(endpoint) if !endpoint.is_empty() => endpoint,
+        _ => {
+            registry.try_init()?;
+            return Ok(TracingGuard { exporting: false });
+        }
+    };
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(OtlpExporter::new(&endpoint)?, TokioCurrentThread)
+        .with_config(
+            trace::config().with_resource(Resource::new([KeyValue::new("service.name", service)])),
+        )
+        .build();
+    let tracer = provider.tracer(service);
+    global::set_tracer_provider(provider);
+    registry
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+
+    Ok(TracingGuard { exporting: true })
+}
+
+/// Flushes pending spans when dropped.
+#[derive(Debug)]
+#[must_use = "pending spans are flushed when the guard is dropped"]
+pub struct TracingGuard {
+    exporting: bool,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if self.exporting {
+            global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Exports spans with OTLP over HTTP, in JSON encoding.
+#[derive(Debug)]
+struct OtlpExporter {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl OtlpExporter {
+    fn new(endpoint: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        reqwest::Url::parse(&url).wrap_err("Invalid OTLP endpoint")?;
+        Ok(Self { client, url })
+    }
+}
+
+#[async_trait]
+impl SpanExporter for OtlpExporter {
+    async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+        self.client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(encode_spans(&batch).to_string())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| TraceError::Other(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+/// Encode spans as an OTLP `ExportTraceServiceRequest`, grouped by
+/// instrumentation library.
+fn encode_spans(batch: &[SpanData]) -> Json {
+    let resource = batch
+        .first()
+        .and_then(|span| span.resource.as_deref())
+        .map(encode_attributes)
+        .unwrap_or_default();
+
+    let mut scopes: BTreeMap<&str, Vec<Json>> = BTreeMap::new();
+    for span in batch {
+        scopes
+            .entry(&span.instrumentation_lib.name)
+            .or_default()
+            .push(encode_span(span));
+    }
+
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": resource },
+            "scopeSpans": scopes
+                .into_iter()
+                .map(|(name, spans)| json!({ "scope": { "name": name }, "spans": spans }))
+                .collect::<Vec<_>>(),
+        }]
+    })
+}
+
+fn encode_span(span: &SpanData) -> Json {
+    let parent = span.parent_span_id;
+    let kind = match span.span_kind {
+        SpanKind::Internal => 1,
+        SpanKind::Server => 2,
+        SpanKind::Client => 3,
+        SpanKind::Producer => 4,
+        SpanKind::Consumer => 5,
+    };
+    let status = match span.status_code {
+        StatusCode::Unset => 0,
+        StatusCode::Ok => 1,
+        StatusCode::Error => 2,
+    };
+    json!({
+        "traceId": hex(span.span_context.trace_id(), 32),
+        "spanId": hex(span.span_context.span_id(), 16),
+        "parentSpanId": if parent == opentelemetry::trace::SpanId::INVALID {
+            String::new()
+        } else {
+            hex(parent, 16)
+        },
+        "name": span.name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(span.start_time),
+        "endTimeUnixNano": unix_nanos(span.end_time),
+        "attributes": encode_attributes(&span.attributes),
+        "events": span.events.iter().map(|event| json!({
+            "timeUnixNano": unix_nanos(event.timestamp),
+            "name": event.name,
+            "attributes": encode_attributes(
+                event.attributes.iter().map(|kv| (&kv.key, &kv.value))
+            ),
+        })).collect::<Vec<_>>(),
+        "status": { "code": status, "message": span.status_message },
+    })
+}
+
+fn encode_attributes<'a>(attributes: impl IntoIterator<Item = (&'a Key, &'a Value)>) -> Vec<Json> {
+    attributes
+        .into_iter()
+        .map(|(key, value)| json!({ "key": key.as_str(), "value": encode_value(value) }))
+        .collect()
+}
+
+fn encode_value(value: &Value) -> Json {
+    fn array<T>(values: &[T], f: impl Fn(&T) -> Json) -> Json {
+        json!({ "arrayValue": { "values": values.iter().map(f).collect::<Vec<_>>() } })
+    }
+    // 64-bit integers are encoded as strings in OTLP JSON.
+    match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::I64(i) => json!({ "intValue": i.to_string() }),
+        Value::F64(f) => json!({ "doubleValue": f }),
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Array(Array::Bool(v)) => array(v, |b| json!({ "boolValue": b })),
+        Value::Array(Array::I64(v)) => array(v, |i| json!({ "intValue": i.to_string() })),
+        Value::Array(Array::F64(v)) => array(v, |f| json!({ "doubleValue": f })),
+        Value::Array(Array::String(v)) => array(v, |s| json!({ "stringValue": s })),
+    }
+}
+
+fn hex(id: impl LowerHex, width: usize) -> String {
+    format!("{id:0width$x}")
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use opentelemetry::{
+        sdk::{
+            export::trace::SpanData,
+            trace::{EvictedHashMap, EvictedQueue},
+            InstrumentationLibrary,
+            Resource,
+        },
+        trace::{SpanContext, SpanId, SpanKind, StatusCode, TraceFlags, TraceId, TraceState},
+        KeyValue,
+    };
+    use serde_json::json;
+
+    use crate::utils::telemetry::encode_spans;
+
+    #[test]
+    fn must_encode_spans() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        let mut attributes = EvictedHashMap::new(8, 8);
+        attributes.insert(KeyValue::new("event_id", "42"));
+        attributes.insert(KeyValue::new("count", 2_i64));
+        let span = SpanData {
+            span_context: SpanContext::new(
+                TraceId::from_bytes([1; 16]),
+                SpanId::from_bytes([2; 8]),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::INVALID,
+            span_kind: SpanKind::Internal,
+            name: Cow::Borrowed("publish"),
+            start_time: start,
+            end_time: start + Duration::from_millis(5),
+            attributes,
+            events: EvictedQueue::new(8),
+            links: EvictedQueue::new(8),
+            status_code: StatusCode::Unset,
+            status_message: Cow::Borrowed(""),
+            resource: Some(Arc::new(Resource::new([KeyValue::new(
+                "service.name",
+                "twitter",
+            )]))),
+            instrumentation_lib: InstrumentationLibrary::new("twitter", None),
+        };
+
+        let encoded = encode_spans(&[span]);
+        let resource = &encoded["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"],
+            json!([{ "key": "service.name", "value": { "stringValue": "twitter" } }])
+        );
+        let scope = &resource["scopeSpans"][0];
+        assert_eq!(scope["scope"]["name"], "twitter");
+        let span = &scope["spans"][0];
+        assert_eq!(span["traceId"], "01".repeat(16));
+        assert_eq!(span["spanId"], "02".repeat(8));
+        assert_eq!(span["parentSpanId"], "");
+        assert_eq!(span["kind"], 1);
+        assert_eq!(span["startTimeUnixNano"], "1000000000");
+        assert_eq!(span["endTimeUnixNano"], "1005000000");
+        let mut attributes = span["attributes"].as_array().unwrap().clone();
+        attributes.sort_by_key(|kv| kv["key"].as_str().unwrap().to_string());
+        assert_eq!(
+            attributes,
+            [
+                json!({ "key": "count", "value": { "intValue": "2" } }),
+                json!({ "key": "event_id", "value": { "stringValue": "42" } }),
+            ]
+        );
+    }
+}
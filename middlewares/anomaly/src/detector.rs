@@ -0,0 +1,252 @@
+//! Detection of floods of event sources.
+//!
+//! A source is the task publishing events of a kind for an entity. It floods
+//! once it publishes more events in the window than its limit, and the flood
+//! is over once it stays under the limit for the cooldown.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use sg_core::models::{AnomalyPayload, Id};
+use tracing::{info, warn};
+
+use crate::config::{Action, Config};
+
+/// Thresholds of floods.
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    pub window: Duration,
+    pub max_events: usize,
+    pub kind_max_events: HashMap<String, usize>,
+    pub cooldown: Duration,
+    pub action: Action,
+}
+
+impl From<&Config> for Thresholds {
+    fn from(config: &Config) -> Self {
+        Self {
+            window: config.window,
+            max_events: config.max_events,
+            kind_max_events: config.kind_max_events.clone(),
+            cooldown: config.cooldown,
+            action: config.action,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Max count of events of a source of given kind in the window.
+    #[must_use]
+    pub fn max_events(&self, kind: &str) -> usize {
+        self.kind_max_events
+            .get(kind)
+            .copied()
+            .unwrap_or(self.max_events)
+    }
+}
+
+/// What to do with an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// Anomaly to report, if the event starts a flood.
+    pub anomaly: Option<AnomalyPayload>,
+    /// Whether to pass the event on.
+    pub pass: bool,
+}
+
+#[derive(Debug, Default)]
+struct Source {
+    /// Times of recent events in the window, at most one more than the limit.
+    recent: VecDeque<Instant>,
+    flood: Option<Flood>,
+}
+
+impl Source {
+    /// End the flood if the source stayed under the limit for `cooldown`.
+    fn cool_down(&mut self, kind: &str, entity: Id, now: Instant, cooldown: Duration) {
+        if let Some(flood) = &self.flood {
+            if now.duration_since(flood.last_exceeded) >= cooldown {
+                info!(%kind, ?entity, dropped = flood.dropped, "Source flood over");
+                self.flood = None;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Flood {
+    /// Last time the source went over the limit.
+    last_exceeded: Instant,
+    /// Count of events dropped.
+    dropped: u64,
+}
+
+/// Rates of event sources.
+#[derive(Debug)]
+pub struct Detector {
+    thresholds: Thresholds,
+    sources: HashMap<(String, Id), Source>,
+}
+
+impl Detector {
+    #[must_use]
+    pub fn new(thresholds: Thresholds) -> Self {
+        Self {
+            thresholds,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Count an event of `kind` for `entity` received at `now`, and decide
+    /// what to do with it.
+    pub fn observe(&mut self, kind: &str, entity: Id, now: Instant) -> Verdict {
+        let Thresholds {
+            window,
+            cooldown,
+            action,
+            ..
+        } = self.thresholds;
+        let limit = self.thresholds.max_events(kind);
+        let source = self.sources.entry((kind.to_string(), entity)).or_default();
+
+        while source
+            .recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= window)
+        {
+            source.recent.pop_front();
+        }
+        source.recent.push_back(now);
+        if source.recent.len() > limit + 1 {
+            source.recent.pop_front();
+        }
+
+        let mut anomaly = None;
+        if source.recent.len() > limit {
+            match &mut source.flood {
+                Some(flood) => flood.last_exceeded = now,
+                None => {
+                    let count = source.recent.len();
+                    warn!(%kind, ?entity, count, limit, ?window, "Source flooding");
+                    source.flood = Some(Flood {
+                        last_exceeded: now,
+                        dropped: 0,
+                    });
+                    anomaly = Some(AnomalyPayload {
+                        kind: kind.to_string(),
+                        count: count as u64,
+                        limit: limit as u64,
+                        window: window.as_secs(),
+                        suppressed: action == Action::Suppress,
+                    });
+                }
+            }
+        } else {
+            source.cool_down(kind, entity, now, cooldown);
+        }
+
+        let pass = match &mut source.flood {
+            Some(flood) if action == Action::Suppress => {
+                flood.dropped += 1;
+                false
+            }
+            _ => true,
+        };
+        Verdict { anomaly, pass }
+    }
+
+    /// End floods past their cooldown, and forget sources with no event in
+    /// the window and not flooding, so that removed tasks don't hold memory.
+    pub fn sweep(&mut self, now: Instant) {
+        let Thresholds {
+            window, cooldown, ..
+        } = self.thresholds;
+        self.sources.retain(|(kind, entity), source| {
+            source.cool_down(kind, *entity, now, cooldown);
+            source.flood.is_some()
+                || source
+                    .recent
+                    .back()
+                    .is_some_and(|at| now.duration_since(*at) < window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use sg_core::models::Id;
+
+    use crate::{
+        config::Action,
+        detector::{Detector, Thresholds},
+    };
+
+    fn detector(action: Action) -> Detector {
+        Detector::new(Thresholds {
+            window: Duration::from_secs(60),
+            max_events: 3,
+            kind_max_events: HashMap::from([(String::from("bililive"), 1)]),
+            cooldown: Duration::from_secs(600),
+            action,
+        })
+    }
+
+    #[test]
+    fn must_suppress_floods() {
+        let mut detector = detector(Action::Suppress);
+        let (entity, now) = (Id::new(), Instant::now());
+        let at = |secs| now + Duration::from_secs(secs);
+
+        for secs in 0..3 {
+            let verdict = detector.observe("twitter", entity, at(secs));
+            assert!(verdict.pass && verdict.anomaly.is_none());
+        }
+        // Other sources are counted on their own.
+        assert!(detector.observe("twitter", Id::new(), at(3)).pass);
+
+        let verdict = detector.observe("twitter", entity, at(3));
+        assert!(!verdict.pass);
+        let anomaly = verdict.anomaly.unwrap();
+        assert_eq!((anomaly.count, anomaly.limit, anomaly.window), (4, 3, 60));
+        assert!(anomaly.suppressed);
+
+        // Reported once per flood, and suppressed until the cooldown passes
+        // under the limit.
+        let verdict = detector.observe("twitter", entity, at(4));
+        assert!(!verdict.pass && verdict.anomaly.is_none());
+        assert!(!detector.observe("twitter", entity, at(300)).pass);
+        assert!(detector.observe("twitter", entity, at(604)).pass);
+
+        // Floods again.
+        for secs in 605..607 {
+            assert!(detector.observe("twitter", entity, at(secs)).pass);
+        }
+        let verdict = detector.observe("twitter", entity, at(607));
+        assert!(verdict.anomaly.is_some());
+    }
+
+    #[test]
+    fn must_alert_per_kind() {
+        let mut detector = detector(Action::Alert);
+        let (entity, now) = (Id::new(), Instant::now());
+
+        assert!(detector.observe("bililive", entity, now).anomaly.is_none());
+        let verdict = detector.observe("bililive", entity, now);
+        assert!(verdict.pass);
+        let anomaly = verdict.anomaly.unwrap();
+        assert_eq!((anomaly.count, anomaly.limit), (2, 1));
+        assert!(!anomaly.suppressed);
+
+        detector.sweep(now + Duration::from_secs(60));
+        assert_eq!(detector.sources.len(), 1);
+        detector.sweep(now + Duration::from_secs(600));
+        assert!(detector.sources.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use std::{sync::Arc, time::Instant};
+
+use eyre::{Result, WrapErr};
+use futures_util::StreamExt;
+use sg_core::{
+    models::{kind, Event},
+    mq::{MessageQueue, Middlewares, Priority, RabbitMQ},
+    utils::{init_tracing, FigmentExt, Shutdown},
+};
+use tracing::{debug, error, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    config::Config,
+    detector::{Detector, Thresholds},
+};
+
+mod config;
+mod detector;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _guard = init_tracing("anomaly", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("MIDDLEWARE_").wrap_err("Failed to load config")?;
+
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
+
+    let mq = Arc::new(
+        RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
+            .await
+            .wrap_err("Failed to connect to AMQP")?
+            .with_prefetch(config.amqp_prefetch)
+            .with_encoding(config.amqp_encoding),
+    );
+    shutdown.on_shutdown("message queue", {
+        let mq = mq.clone();
+        move || async move { mq.close().await }
+    });
+
+    let mut detector = Detector::new(Thresholds::from(&config));
+    let mut last_sweep = Instant::now();
+    let mut consumer = mq.consume(Some("anomaly"), None).await;
+
+    // Stop receiving on shutdown, but finish the event in hand.
+    while let Some(Some(Ok((next, event, delivery)))) = shutdown.until(consumer.next()).await {
+        let span = event.consume_span();
+        async {
+            let now = Instant::now();
+            if now.duration_since(last_sweep) >= config.window {
+                detector.sweep(now);
+                last_sweep = now;
+            }
+
+            let verdict = detector.observe(&event.kind, event.entity.into(), now);
+            if let Some(anomaly) = verdict.anomaly {
+                let alert = Event::builder(kind::ANOMALY, event.entity)
+                    .fields(anomaly)
+                    .caused_by(&event)
+                    .build();
+                let published = match alert {
+                    Ok(alert) => {
+                        let middlewares = Middlewares::default().with_priority(Priority::High);
+                        mq.publish(alert, middlewares).await
+                    }
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = published {
+                    error!(?error, "Failed to publish anomaly");
+                }
+            }
+
+            // Acknowledged only once passed on, so that the event is not
+            // lost if the middleware stops in between.
+            let result = if verdict.pass {
+                match mq.publish(event, next).await {
+                    Ok(()) => delivery.ack().await,
+                    Err(error) => {
+                        error!(?error, "Failed to pass event on");
+                        delivery.nack(true).await
+                    }
+                }
+            } else {
+                debug!("Dropping event of flooding source");
+                delivery.ack().await
+            };
+            if let Err(error) = result {
+                error!(?error, "Failed to acknowledge event");
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+
+    shutdown.run_hooks().await;
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Admin HTTP API.
+use std::{future, sync::Arc};
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    routing::{delete, get, post},
+    Extension,
+    Json,
+    Router,
+};
+use eyre::Result;
+use serde::Serialize;
+use tower_http::auth::RequireAuthorizationLayer;
+use tracing::{error, info};
+
+use crate::{config::Config, scheduler::Scheduler, DelayedMessage};
+
+/// A delayed message waiting to be delivered.
+#[derive(Debug, Serialize)]
+pub struct PendingMessage {
+    /// `x-delay-id` of the message.
+    pub id: i64,
+    /// Unix timestamp to deliver the message at.
+    pub deliver_at: i64,
+    /// Kind of the delayed event.
+    pub kind: String,
+    /// Entity of the delayed event.
+    pub entity: String,
+}
+
+impl From<DelayedMessage> for PendingMessage {
+    fn from(message: DelayedMessage) -> Self {
+        Self {
+            id: message.id,
+            deliver_at: message.deliver_at.timestamp(),
+            kind: message.body.0.kind,
+            entity: message.body.0.entity.to_string(),
+        }
+    }
+}
+
+/// Serve the admin API until it fails. Never returns if no token is set.
+///
+/// # Errors
+/// Returns an error if the server fails to bind or serve.
+pub async fn serve(scheduler: Arc<Scheduler>, config: &Config) -> Result<()> {
+    let Some(token) = &config.admin_token else {
+        info!("Admin API disabled");
+        return future::pending().await;
+    };
+    info!("Admin API listening on {}", config.admin_bind);
+
+    axum::Server::try_bind(&config.admin_bind)?
+        .serve(router(scheduler, token).into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Build the admin router. All routes require the given bearer token.
+pub fn router(scheduler: Arc<Scheduler>, token: &str) -> Router {
+    Router::new()
+        .route("/messages", get(list_messages))
+        .route("/messages/:id", delete(cancel_message))
+        .route("/messages/:id/deliver", post(deliver_message))
+        .layer(Extension(scheduler))
+        .layer(RequireAuthorizationLayer::bearer(token))
+}
+
+async fn list_messages(
+    Extension(scheduler): Extension<Arc<Scheduler>>,
+) -> Result<Json<Vec<PendingMessage>>, StatusCode> {
+    let messages = scheduler.pending().map_err(|error| {
+        error!(?error, "Failed to list delayed messages");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(messages.into_iter().map(Into::into).collect()))
+}
+
+async fn cancel_message(
+    Path(id): Path<i64>,
+    Extension(scheduler): Extension<Arc<Scheduler>>,
+) -> StatusCode {
+    if scheduler.cancel(id).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn deliver_message(
+    Path(id): Path<i64>,
+    Extension(scheduler): Extension<Arc<Scheduler>>,
+) -> StatusCode {
+    match scheduler.deliver_now(id).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => {
+            error!(%id, ?error, "Failed to deliver delayed message");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
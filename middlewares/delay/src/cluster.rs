@@ -0,0 +1,192 @@
+//! Coordination of replicas of the delay middleware.
+//!
+//! Every replica consumes its own copy of each delayed message, so all of
+//! them hold the same messages. Replicas announce themselves with heartbeats
+//! through the message queue, and each message is delivered by one of the
+//! live replicas, picked by rendezvous hashing of its `x-delay-id`, which then
+//! tells the others to drop it. The owner may not hold a message, e.g. if it
+//! was down when the message was published, or may stop, so the others keep
+//! it and take their turn to deliver it, ranked by the same hashing.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde_json::json;
+use sg_core::{
+    models::{Event, Id},
+    mq::{MessageQueue, Middlewares, Priority},
+    utils::stable_hash,
+};
+use tokio::time::{interval, sleep};
+use tracing::{error, info};
+
+/// Field of heartbeat events, carrying the id of the replica.
+pub const HEARTBEAT: &str = "x-delay-heartbeat";
+
+/// Kind of heartbeat events.
+const HEARTBEAT_KIND: &str = "delay/heartbeat";
+
+/// Number of heartbeats a replica may miss before it's considered gone.
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// Membership of this replica among the others.
+#[derive(Debug)]
+pub struct Cluster {
+    instance_id: String,
+    heartbeat_interval: Duration,
+    started_at: Instant,
+    /// Last heartbeat of each replica, including this one.
+    peers: Mutex<HashMap<String, Instant>>,
+}
+
+impl Cluster {
+    #[must_use]
+    pub fn new(instance_id: String, heartbeat_interval: Duration) -> Self {
+        Self {
+            instance_id,
+            heartbeat_interval,
+            started_at: Instant::now(),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Time after which a replica without heartbeats is considered gone.
+    #[must_use]
+    pub fn timeout(&self) -> Duration {
+        self.heartbeat_interval * MISSED_HEARTBEATS
+    }
+
+    /// Record a heartbeat of a replica.
+    pub fn heartbeat(&self, instance_id: &str) {
+        let previous = self
+            .peers
+            .lock()
+            .insert(instance_id.to_string(), Instant::now());
+        if previous.map_or(true, |at| at.elapsed() > self.timeout()) {
+            info!(%instance_id, "Replica joined");
+        }
+    }
+
+    /// Wait, once the message with the given id is due, until it's the turn
+    /// of this replica to deliver it.
+    ///
+    /// The owner delivers it right away, and the others one `timeout` after
+    /// another by their rank, unless they're told it's delivered in between.
+    /// Nothing is delivered until this replica has listened long enough to
+    /// know all live replicas.
+    pub async fn wait_turn(&self, delay_id: i64) {
+        sleep(self.until_started()).await;
+        let rank = self.rank(delay_id, Instant::now());
+        sleep(self.timeout().saturating_mul(rank)).await;
+    }
+
+    /// Time until this replica has listened long enough to know all live
+    /// replicas.
+    fn until_started(&self) -> Duration {
+        self.timeout().saturating_sub(self.started_at.elapsed())
+    }
+
+    /// Number of live replicas with a higher score for the message than this
+    /// one, as of `now`. It's zero for the owner.
+    fn rank(&self, delay_id: i64, now: Instant) -> u32 {
+        let timeout = self.timeout();
+        let own = (
+            score(&self.instance_id, delay_id),
+            self.instance_id.as_str(),
+        );
+        let peers = self.peers.lock();
+        let ahead = peers
+            .iter()
+            .filter(|(_, &at)| now.saturating_duration_since(at) <= timeout)
+            .filter(|(instance_id, _)| (score(instance_id, delay_id), instance_id.as_str()) > own)
+            .count();
+        u32::try_from(ahead).unwrap_or(u32::MAX)
+    }
+
+    /// Publish heartbeats of this replica forever.
+    pub async fn run(&self, mq: &impl MessageQueue) {
+        let middlewares = "delay"
+            .parse::<Middlewares>()
+            .unwrap()
+            .with_priority(Priority::High);
+        let mut ticker = interval(self.heartbeat_interval);
+        loop {
+            ticker.tick().await;
+            let event = Event::from_serializable(
+                HEARTBEAT_KIND,
+                Id::nil(),
+                json!({ HEARTBEAT: self.instance_id }),
+            )
+            .expect("fields are a map");
+            if let Err(error) = mq.publish(event, middlewares.clone()).await {
+                error!(?error, "Failed to publish heartbeat");
+            }
+        }
+    }
+}
+
+/// Score of a replica for a message in rendezvous hashing.
+fn score(instance_id: &str, delay_id: i64) -> u64 {
+    stable_hash(instance_id.bytes().chain(delay_id.to_le_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::cluster::Cluster;
+
+    fn cluster(instance_id: &str, peers: &[&str]) -> Cluster {
+        let cluster = Cluster::new(instance_id.to_string(), Duration::from_secs(5));
+        for peer in peers {
+            cluster.heartbeat(peer);
+        }
+        cluster
+    }
+
+    #[test]
+    fn must_partition() {
+        let instances = ["a", "b", "c"];
+        let replicas: Vec<_> = instances
+            .iter()
+            .map(|instance_id| cluster(instance_id, &instances))
+            .collect();
+
+        let now = Instant::now();
+        let mut owned = [0; 3];
+        for delay_id in 0..300 {
+            let mut ranks: Vec<_> = replicas
+                .iter()
+                .map(|replica| replica.rank(delay_id, now))
+                .collect();
+            owned[ranks.iter().position(|&rank| rank == 0).unwrap()] += 1;
+            ranks.sort_unstable();
+            assert_eq!(ranks, [0, 1, 2], "Replicas must agree on the order");
+        }
+        assert!(
+            owned.iter().all(|&count| count > 70),
+            "Messages must be spread over replicas: {owned:?}"
+        );
+    }
+
+    #[test]
+    fn must_take_over() {
+        let replica = cluster("a", &["a", "b"]);
+        let later = Instant::now() + replica.timeout() + Duration::from_secs(1);
+        for delay_id in 0..100 {
+            assert_eq!(replica.rank(delay_id, later), 0);
+        }
+    }
+
+    #[test]
+    fn must_wait_for_peers() {
+        let replica = cluster("a", &[]);
+        assert!(
+            replica.until_started() > Duration::ZERO,
+            "Peers are unknown right after start"
+        );
+    }
+}
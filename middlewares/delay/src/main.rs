@@ -3,7 +3,7 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
-use std::sync::Arc;
+use std::{future, sync::Arc};
 
 use chrono::NaiveDateTime;
 use diesel::{
@@ -15,19 +15,22 @@ use futures_util::StreamExt;
 use sg_core::{
     models::Event,
     mq::{MessageQueue, Middlewares, RabbitMQ},
-    utils::FigmentExt,
+    utils::{init_tracing, FigmentExt, Shutdown},
 };
 use tap::Pipe;
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
 use crate::{
+    cluster::{Cluster, HEARTBEAT},
     config::Config,
     db::DelayedMessage,
-    scheduler::Scheduler,
+    scheduler::{Options, Scheduler},
     schema::delayed_messages::dsl::delayed_messages,
 };
 
+mod admin;
+mod cluster;
 mod config;
 mod db;
 mod scheduler;
@@ -38,12 +41,12 @@ embed_migrations!();
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    let _guard = init_tracing("delay", EnvFilter::from_default_env())?;
 
-    let config = Config::from_env("MIDDLEWARE_")
-        .wrap_err("Failed to load config from environment variables")?;
+    let config = Config::from_sources("MIDDLEWARE_").wrap_err("Failed to load config")?;
+
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
 
     let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(
         &config.database_url,
@@ -52,27 +55,99 @@ async fn main() -> Result<()> {
 
     embedded_migrations::run(&pool.get()?).wrap_err("Failed to run migration script")?;
 
-    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
-        .await
-        .wrap_err("Failed to connect to AMQP")?;
-    let mut consumer = mq.consume(Some("delay")).await;
-
-    let scheduler = Arc::new(Scheduler::new(pool, mq));
+    let mq: Arc<dyn MessageQueue> = Arc::new(
+        RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
+            .await
+            .wrap_err("Failed to connect to AMQP")?
+            .with_prefetch(config.amqp_prefetch)
+            .with_encoding(config.amqp_encoding),
+    );
+    let mut consumer = mq.consume(Some("delay"), None).await;
+
+    let options = Options::from(&config);
+    let cluster = options.cluster.clone();
+    let scheduler = Scheduler::new(pool, mq.clone(), options);
     scheduler.cleanup();
     scheduler.load();
 
-    while let Some(Ok((next, event))) = consumer.next().await {
-        let event_id = event.id;
-        info!(%event_id, ?next, "Received event");
+    // Replicas announce themselves to share deliveries.
+    let heartbeat = {
+        let (cluster, mq) = (cluster.clone(), mq.clone());
+        async move {
+            match cluster {
+                Some(cluster) => cluster.run(&mq).await,
+                None => future::pending().await,
+            }
+        }
+    };
 
-        if let Err(error) = handle_event(next, event, &scheduler) {
-            error!(%event_id, ?error, "Failed to process event");
+    // Buffered writes to the database are lost unless flushed before exit.
+    shutdown.on_shutdown("delayed messages", {
+        let scheduler = scheduler.clone();
+        move || async move {
+            scheduler.flush();
+            scheduler.ack_flushed().await;
+            Ok(())
         }
-    }
+    });
+    shutdown.on_shutdown("message queue", move || async move { mq.close().await });
+
+    let consume = async {
+        while let Some(Ok((next, event, delivery))) = consumer.next().await {
+            let event_id = event.id;
+            let handled = {
+                let _span = event.consume_span().entered();
+                info!(%event_id, ?next, "Received event");
+                handle_event(next, event, &scheduler, cluster.as_deref())
+            };
+
+            // Acknowledged only once the scheduled message is written to the
+            // database, so that the event is not lost if the middleware stops
+            // in between.
+            match handled {
+                Ok(()) => scheduler.ack_after_flush(delivery),
+                Err(error) => {
+                    error!(%event_id, ?error, "Failed to process event");
+                    // Malformed events would fail again if requeued.
+                    if let Err(error) = delivery.nack(false).await {
+                        error!(%event_id, ?error, "Failed to acknowledge event");
+                    }
+                }
+            }
+            // The buffer may have been flushed as it's full.
+            scheduler.ack_flushed().await;
+        }
+    };
+
+    let serve = async {
+        tokio::select! {
+            r = admin::serve(scheduler.clone(), &config) => r,
+            () = consume => Ok(()),
+            () = heartbeat => Ok(()),
+        }
+    };
+    let result = shutdown.until(serve).await.transpose();
+    shutdown.run_hooks().await;
+    result?;
     Ok(())
 }
 
-fn handle_event(next: Middlewares, mut event: Event, scheduler: &Arc<Scheduler>) -> Result<()> {
+fn handle_event(
+    next: Middlewares,
+    mut event: Event,
+    scheduler: &Arc<Scheduler>,
+    cluster: Option<&Cluster>,
+) -> Result<()> {
+    if let Some(instance_id) = event.fields.get(HEARTBEAT) {
+        let instance_id = instance_id
+            .as_str()
+            .wrap_err("Not a string: `x-delay-heartbeat`")?;
+        if let Some(cluster) = cluster {
+            cluster.heartbeat(instance_id);
+        }
+        return Ok(());
+    }
+
     let id = event
         .fields
         .remove("x-delay-id")
@@ -1,6 +1,7 @@
 use std::{
     collections::HashMap,
     sync::{Arc, Weak},
+    time::Duration,
 };
 
 use chrono::Utc;
@@ -8,26 +9,98 @@ use diesel::{
     associations::HasTable,
     dsl::now,
     r2d2::{ConnectionManager, Pool},
+    Connection,
     ExpressionMethods,
+    OptionalExtension,
     QueryDsl,
     RunQueryDsl,
     SqliteConnection,
 };
+use eyre::Result;
 use parking_lot::Mutex;
-use sg_core::{mq::MessageQueue, utils::ScopedJoinHandle};
-use tokio::time::sleep;
-use tracing::{error, info};
+use serde_json::json;
+use sg_core::{
+    models::{kind, Event, Id},
+    mq::{Delivery, MessageQueue, Middlewares},
+    utils::{stable_hash, ScopedJoinHandle},
+};
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tracing::{debug, error, info};
 
 use crate::{
+    cluster::Cluster,
+    config::Config,
     delayed_messages,
     schema::delayed_messages::{deliver_at, id},
     DelayedMessage,
 };
 
+/// Tunables of the scheduler.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Flush pending writes once this many are buffered, or this many events
+    /// wait for them.
+    pub batch_size: usize,
+    /// Max time a write may stay in the buffer.
+    pub flush_interval: Duration,
+    /// Publish a `system/delay_delivered` event after each delivery.
+    pub confirm_delivery: bool,
+    /// Replicas sharing deliveries with this one. It delivers every message
+    /// by itself if not set.
+    pub cluster: Option<Arc<Cluster>>,
+    /// Delay deliveries by up to this, so that messages due at the same time
+    /// are spread over it. Disabled if zero.
+    pub spread_interval: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            batch_size: 64,
+            flush_interval: Duration::from_millis(100),
+            confirm_delivery: false,
+            cluster: None,
+            spread_interval: Duration::ZERO,
+        }
+    }
+}
+
+impl From<&Config> for Options {
+    fn from(config: &Config) -> Self {
+        Self {
+            batch_size: config.batch_size,
+            flush_interval: config.flush_interval,
+            confirm_delivery: config.confirm_delivery,
+            cluster: config.instance_id.as_ref().map(|instance_id| {
+                Arc::new(Cluster::new(instance_id.clone(), config.heartbeat_interval))
+            }),
+            spread_interval: config.spread_interval,
+        }
+    }
+}
+
 pub struct Scheduler {
     pool: Pool<ConnectionManager<SqliteConnection>>,
     mq: Arc<dyn MessageQueue>,
+    options: Options,
     delayed_messages: Mutex<HashMap<i64, DelayedTask>>,
+    /// Writes not yet flushed to the database.
+    pending: Mutex<Batch>,
+    /// Events whose writes are flushed, to be acknowledged.
+    flushed: Mutex<Vec<Delivery>>,
+    /// Serializes flushes so that batches are applied in order.
+    flush_lock: Mutex<()>,
+    _flusher: ScopedJoinHandle<()>,
+}
+
+/// Buffered database writes, and the events that caused them.
+#[derive(Default)]
+struct Batch {
+    /// `None` means deletion.
+    writes: HashMap<i64, Option<DelayedMessage>>,
+    /// Acknowledged once `writes` are committed, so that events are delivered
+    /// again if the middleware stops before.
+    deliveries: Vec<Delivery>,
 }
 
 pub struct DelayedTask {
@@ -39,6 +112,8 @@ impl DelayedTask {
         scheduler: Weak<Scheduler>,
         mq: impl MessageQueue + 'static,
         message: DelayedMessage,
+        confirm_delivery: bool,
+        cluster: Option<Arc<Cluster>>,
     ) -> Self {
         let task = tokio::spawn(async move {
             let delay = message.deliver_at - Utc::now().naive_utc();
@@ -47,8 +122,13 @@ impl DelayedTask {
             match delay.to_std() {
                 Ok(delay) => {
                     sleep(delay).await;
-                    if let Err(error) = mq.publish(message.body.0, message.middlewares.0).await {
-                        error!(%event_id, %x_delay_id, ?error, "Unable to deliver delayed message");
+                    // Aborted meanwhile if another replica delivers the message.
+                    if let Some(cluster) = &cluster {
+                        cluster.wait_turn(x_delay_id).await;
+                    }
+                    deliver(&mq, message, confirm_delivery).await;
+                    if let Some(scheduler) = scheduler.upgrade() {
+                        scheduler.cancel_on_replicas(x_delay_id).await;
                     }
                 }
                 Err(error) => {
@@ -56,7 +136,7 @@ impl DelayedTask {
                 }
             }
             if let Some(scheduler) = scheduler.upgrade() {
-                scheduler.remove_task(message.id);
+                scheduler.remove_task(x_delay_id);
             }
         });
         Self {
@@ -65,20 +145,83 @@ impl DelayedTask {
     }
 }
 
+/// Offset of deliveries of `entity` within `spread_interval`.
+///
+/// It's fixed per entity, so that messages of different entities due at the
+/// same time are spread, while those of one entity keep their order. It's the
+/// same on all replicas, so that they agree on when a message is due.
+fn spread_offset(entity: Id, spread_interval: Duration) -> chrono::Duration {
+    let millis = u64::try_from(spread_interval.as_millis()).unwrap_or(u64::MAX);
+    if millis == 0 {
+        return chrono::Duration::zero();
+    }
+    let offset = stable_hash(entity.bytes()) % millis;
+    chrono::Duration::milliseconds(i64::try_from(offset).unwrap_or_default())
+}
+
+/// Publish a delayed message down its middleware chain, confirming the
+/// delivery if asked to.
+async fn deliver(mq: &impl MessageQueue, message: DelayedMessage, confirm_delivery: bool) {
+    let x_delay_id = message.id;
+    let event = message.body.0;
+    let event_id = event.id;
+    let confirmation = confirm_delivery.then(|| {
+        Event::builder(kind::DELAY_DELIVERED, event.entity)
+            .field("x-delay-delivered", x_delay_id)
+            .caused_by(&event)
+            .build()
+            .expect("fields are a map")
+    });
+    let next = message.middlewares.0.clone();
+    if let Err(error) = mq.publish(event, message.middlewares.0).await {
+        error!(%event_id, %x_delay_id, ?error, "Unable to deliver delayed message");
+    } else if let Some(confirmation) = confirmation {
+        if let Err(error) = mq.publish(confirmation, next).await {
+            error!(%event_id, %x_delay_id, ?error, "Unable to confirm delivery");
+        }
+    }
+}
+
 impl Scheduler {
     pub fn new(
         pool: Pool<ConnectionManager<SqliteConnection>>,
         mq: impl MessageQueue + 'static,
-    ) -> Self {
-        Self {
-            pool,
-            mq: Arc::new(mq),
-            delayed_messages: Mutex::new(HashMap::new()),
-        }
+        options: Options,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this: &Weak<Self>| {
+            let this = this.clone();
+            let flush_interval = options.flush_interval;
+            let flusher = tokio::spawn(async move {
+                let mut ticker = interval(flush_interval);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    ticker.tick().await;
+                    match this.upgrade() {
+                        Some(scheduler) => {
+                            scheduler.flush();
+                            scheduler.ack_flushed().await;
+                        }
+                        None => break,
+                    }
+                }
+            });
+            Self {
+                pool,
+                mq: Arc::new(mq),
+                options,
+                delayed_messages: Mutex::new(HashMap::new()),
+                pending: Mutex::new(Batch::default()),
+                flushed: Mutex::new(Vec::new()),
+                flush_lock: Mutex::new(()),
+                _flusher: ScopedJoinHandle(flusher),
+            }
+        })
     }
 
-    #[allow(clippy::cognitive_complexity)]
-    pub fn add_task(self: &Arc<Self>, msg: DelayedMessage, persist: bool) {
+    /// Schedule a delayed message. New messages are spread by
+    /// [`Options::spread_interval`] and persisted, while loaded ones are
+    /// already.
+    pub fn add_task(self: &Arc<Self>, mut msg: DelayedMessage, persist: bool) {
         if msg.deliver_at <= Utc::now().naive_utc() {
             let x_delay_id = msg.id;
             let event_id = msg.body.0.id;
@@ -87,26 +230,18 @@ impl Scheduler {
         }
 
         if persist {
-            let conn = self.pool.get().unwrap();
-            let r = diesel::insert_into(delayed_messages::table())
-                .values(&msg)
-                .execute(&conn);
-            match r {
-                Ok(count) if count == 0 => {
-                    error!(
-                        error = "No rows inserted",
-                        "Unable to persist delayed message."
-                    );
-                }
-                Err(error) => {
-                    error!(?error, "Unable to persist delayed message.");
-                }
-                _ => (),
-            }
+            msg.deliver_at += spread_offset(msg.body.0.entity, self.options.spread_interval);
+            self.stage(msg.id, Some(msg.clone()));
         }
 
         let msg_id = msg.id;
-        let task = DelayedTask::new(Arc::downgrade(self), self.mq.clone(), msg);
+        let task = DelayedTask::new(
+            Arc::downgrade(self),
+            self.mq.clone(),
+            msg,
+            self.options.confirm_delivery,
+            self.options.cluster.clone(),
+        );
         if self.delayed_messages.lock().insert(msg_id, task).is_some() {
             info!(id = %msg_id, "Overwriting existing delayed message");
         } else {
@@ -114,20 +249,181 @@ impl Scheduler {
         }
     }
 
-    pub fn remove_task(&self, task_id: i64) {
-        if self.delayed_messages.lock().remove(&task_id).is_some() {
-            let conn = self.pool.get().expect("No db conn available");
-            if let Err(error) =
-                diesel::delete(delayed_messages.filter(id.eq(task_id))).execute(&conn)
-            {
-                error!(?error, "Failed to remove task from database");
-            }
-        }
-
+    /// Cancel a delayed message. Returns whether it was pending.
+    pub fn remove_task(&self, task_id: i64) -> bool {
         if self.delayed_messages.lock().remove(&task_id).is_some() {
+            self.stage(task_id, None);
             info!(id = %task_id, "Removed delayed message");
+            true
         } else {
             info!(id = %task_id, "No delayed message to remove");
+            false
+        }
+    }
+
+    /// Cancel a delayed message, on other replicas as well. Returns whether it
+    /// was pending here.
+    pub async fn cancel(&self, task_id: i64) -> bool {
+        let removed = self.remove_task(task_id);
+        if removed {
+            self.cancel_on_replicas(task_id).await;
+        }
+        removed
+    }
+
+    /// Ask other replicas to drop a delayed message cancelled or delivered
+    /// here. They receive it through the message queue like any
+    /// cancellation.
+    async fn cancel_on_replicas(&self, task_id: i64) {
+        if self.options.cluster.is_none() {
+            return;
+        }
+        let event = Event::from_serializable(
+            "delay/cancel",
+            Id::nil(),
+            json!({ "x-delay-id": task_id, "x-delay-cancel": true }),
+        )
+        .expect("fields are a map");
+        let middlewares = "delay".parse::<Middlewares>().unwrap();
+        if let Err(error) = self.mq.publish(event, middlewares).await {
+            error!(id = %task_id, ?error, "Unable to cancel delayed message on replicas");
+        }
+    }
+
+    /// List pending delayed messages in the order they are delivered.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub fn pending(&self) -> Result<Vec<DelayedMessage>> {
+        self.flush();
+        let conn = self.pool.get()?;
+        Ok(delayed_messages.order(deliver_at.asc()).load(&conn)?)
+    }
+
+    /// Deliver a pending delayed message now. Returns whether it was pending.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub async fn deliver_now(&self, task_id: i64) -> Result<bool> {
+        self.flush();
+        let conn = self.pool.get()?;
+        let Some(message) = delayed_messages
+            .find(task_id)
+            .first::<DelayedMessage>(&conn)
+            .optional()?
+        else {
+            return Ok(false);
+        };
+        if !self.remove_task(task_id) {
+            return Ok(false);
+        }
+
+        info!(id = %task_id, "Delivering delayed message now");
+        deliver(&self.mq, message, self.options.confirm_delivery).await;
+        self.cancel_on_replicas(task_id).await;
+        Ok(true)
+    }
+
+    /// Buffer a write, flushing the buffer if it's full.
+    fn stage(&self, msg_id: i64, op: Option<DelayedMessage>) {
+        let len = {
+            let mut pending = self.pending.lock();
+            pending.writes.insert(msg_id, op);
+            pending.writes.len()
+        };
+        if len >= self.options.batch_size {
+            self.flush();
+        }
+    }
+
+    /// Acknowledge a handled event once the writes buffered so far are
+    /// flushed, flushing the buffer if enough events are waiting.
+    pub fn ack_after_flush(&self, delivery: Delivery) {
+        let len = {
+            let mut pending = self.pending.lock();
+            pending.deliveries.push(delivery);
+            pending.deliveries.len()
+        };
+        if len >= self.options.batch_size {
+            self.flush();
+        }
+    }
+
+    /// Acknowledge events whose writes are flushed.
+    pub async fn ack_flushed(&self) {
+        let deliveries = std::mem::take(&mut *self.flushed.lock());
+        for delivery in deliveries {
+            if let Err(error) = delivery.ack().await {
+                error!(?error, "Failed to acknowledge event");
+            }
+        }
+    }
+
+    /// Write all buffered inserts and deletes to the database in one
+    /// transaction. Events waiting for them are acknowledged by
+    /// [`Self::ack_flushed`] afterwards.
+    ///
+    /// Failed writes are put back to the buffer unless superseded.
+    pub fn flush(&self) {
+        let _guard = self.flush_lock.lock();
+        let Batch { writes, deliveries } = std::mem::take(&mut *self.pending.lock());
+        if writes.is_empty() {
+            self.flushed.lock().extend(deliveries);
+            return;
+        }
+
+        let mut deletes = vec![];
+        let mut upserts = vec![];
+        for (msg_id, op) in &writes {
+            match op {
+                Some(msg) => upserts.push(msg),
+                None => deletes.push(*msg_id),
+            }
+        }
+
+        let r = self
+            .pool
+            .get()
+            .map_err(eyre::Report::from)
+            .and_then(|conn| {
+                conn.transaction::<_, diesel::result::Error, _>(|| {
+                    if !deletes.is_empty() {
+                        diesel::delete(delayed_messages.filter(id.eq_any(&deletes)))
+                            .execute(&conn)?;
+                    }
+                    // SQLite has no multi-row insert in diesel 1.x, but rows inserted in one
+                    // transaction are committed together. Replaced if already there, as events
+                    // are delivered again if they aren't acknowledged after the commit.
+                    for &msg in &upserts {
+                        diesel::replace_into(delayed_messages::table())
+                            .values(msg)
+                            .execute(&conn)?;
+                    }
+                    Ok(())
+                })
+                .map_err(eyre::Report::from)
+            });
+        match r {
+            Ok(()) => {
+                debug!(
+                    inserted = upserts.len(),
+                    deleted = deletes.len(),
+                    "Flushed delayed messages to database"
+                );
+                self.flushed.lock().extend(deliveries);
+            }
+            Err(error) => {
+                error!(
+                    ?error,
+                    count = writes.len(),
+                    "Failed to flush delayed messages to database"
+                );
+                let mut pending = self.pending.lock();
+                for (msg_id, op) in writes {
+                    pending.writes.entry(msg_id).or_insert(op);
+                }
+                pending.deliveries.extend(deliveries);
+            }
         }
     }
 
@@ -162,6 +458,12 @@ impl Scheduler {
     }
 }
 
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -172,14 +474,21 @@ mod tests {
         RunQueryDsl,
         SqliteConnection,
     };
+    use futures_util::StreamExt;
     use sg_core::{
-        models::Event,
-        mq::{mock::MockMQ, Middlewares},
+        models::{kind, Event},
+        mq::{mock::MockMQ, MessageQueue, Middlewares},
     };
-    use tokio::time::sleep;
+    use tokio::time::{sleep, timeout};
     use uuid::Uuid;
 
-    use crate::{delayed_messages, embedded_migrations, DelayedMessage, Scheduler};
+    use crate::{
+        delayed_messages,
+        embedded_migrations,
+        scheduler::Options,
+        DelayedMessage,
+        Scheduler,
+    };
 
     #[derive(Debug, Eq, PartialEq)]
     enum TestAction {
@@ -215,7 +524,7 @@ mod tests {
         let mq = MockMQ::default();
 
         {
-            let scheduler = Arc::new(Scheduler::new(pool, mq));
+            let scheduler = Scheduler::new(pool, mq, Options::default());
 
             let msg = DelayedMessage::new(
                 114_514,
@@ -252,7 +561,7 @@ mod tests {
         // Now load the db again.
         let pool = Pool::new(ConnectionManager::new(&db_path)).unwrap();
         let mq = MockMQ::default();
-        let scheduler = Arc::new(Scheduler::new(pool, mq));
+        let scheduler = Scheduler::new(pool, mq, Options::default());
         if action == TestAction::Cleanup {
             scheduler.cleanup();
         }
@@ -289,4 +598,236 @@ mod tests {
             }
         }
     }
+
+    fn new_message(delay_id: i64, delay: chrono::Duration) -> DelayedMessage {
+        DelayedMessage::new(
+            delay_id,
+            Middlewares::default(),
+            Event::from_serializable("", Uuid::nil(), ()).unwrap(),
+            Utc::now().naive_utc() + delay,
+        )
+    }
+
+    #[tokio::test]
+    async fn must_batch_writes() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_string_lossy().to_string();
+        let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(&db_path)).unwrap();
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+        let scheduler = Scheduler::new(
+            pool.clone(),
+            MockMQ::default(),
+            Options {
+                batch_size: 2,
+                flush_interval: std::time::Duration::from_secs(3600),
+                ..Options::default()
+            },
+        );
+        let count = || {
+            delayed_messages
+                .load::<DelayedMessage>(&pool.get().unwrap())
+                .unwrap()
+                .len()
+        };
+
+        scheduler.add_task(new_message(1, chrono::Duration::seconds(60)), true);
+        assert_eq!(count(), 0, "Write should be buffered");
+
+        scheduler.add_task(new_message(2, chrono::Duration::seconds(60)), true);
+        assert_eq!(count(), 2, "Full buffer should be flushed");
+
+        scheduler.remove_task(1);
+        assert_eq!(count(), 2, "Deletion should be buffered");
+
+        scheduler.flush();
+        assert_eq!(count(), 1, "Deletion should be flushed");
+    }
+
+    #[tokio::test]
+    async fn must_ack_after_flush() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_string_lossy().to_string();
+        let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(&db_path)).unwrap();
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+        let mq = MockMQ::default();
+        let mut consumer = mq.consume(None, None).await;
+        mq.publish(
+            Event::from_serializable("", Uuid::nil(), ()).unwrap(),
+            Middlewares::default(),
+        )
+        .await
+        .unwrap();
+        let (_, _, delivery) = consumer.next().await.unwrap().unwrap();
+
+        let scheduler = Scheduler::new(
+            pool,
+            MockMQ::default(),
+            Options {
+                flush_interval: std::time::Duration::from_secs(3600),
+                ..Options::default()
+            },
+        );
+        scheduler.add_task(new_message(1, chrono::Duration::seconds(60)), true);
+        scheduler.ack_after_flush(delivery);
+        assert!(
+            scheduler.flushed.lock().is_empty(),
+            "Event should wait for the write"
+        );
+
+        scheduler.flush();
+        assert_eq!(
+            scheduler.flushed.lock().len(),
+            1,
+            "Event should be acknowledged once written"
+        );
+        scheduler.ack_flushed().await;
+        assert!(scheduler.flushed.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn must_confirm_delivery() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_string_lossy().to_string();
+        let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(&db_path)).unwrap();
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+        let mq: Arc<dyn MessageQueue> = Arc::new(MockMQ::default());
+        let mut consumer = mq.consume(None, None).await;
+
+        let scheduler = Scheduler::new(
+            pool,
+            mq,
+            Options {
+                confirm_delivery: true,
+                ..Options::default()
+            },
+        );
+        scheduler.add_task(
+            new_message(114_514, chrono::Duration::milliseconds(100)),
+            true,
+        );
+
+        let (_, delivered, _) = timeout(std::time::Duration::from_secs(1), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(delivered.fields.is_empty());
+
+        let (_, confirmation, _) = timeout(std::time::Duration::from_secs(1), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(confirmation.kind, kind::DELAY_DELIVERED);
+        assert_eq!(confirmation.entity, delivered.entity);
+        assert_eq!(confirmation.fields["x-delay-delivered"], 114_514);
+        assert_eq!(confirmation.caused_by, Some(delivered.id));
+    }
+
+    #[tokio::test]
+    async fn must_list_and_deliver_now() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_string_lossy().to_string();
+        let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(&db_path)).unwrap();
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+        let mq: Arc<dyn MessageQueue> = Arc::new(MockMQ::default());
+        let mut consumer = mq.consume(None, None).await;
+
+        let scheduler = Scheduler::new(pool, mq, Options::default());
+        scheduler.add_task(new_message(2, chrono::Duration::seconds(120)), true);
+        scheduler.add_task(new_message(1, chrono::Duration::seconds(60)), true);
+
+        let pending: Vec<_> = scheduler
+            .pending()
+            .unwrap()
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(
+            pending,
+            [1, 2],
+            "Messages should be listed by delivery time"
+        );
+
+        assert!(scheduler.deliver_now(2).await.unwrap());
+        let (_, delivered, _) = timeout(std::time::Duration::from_secs(1), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivered.entity, Uuid::nil().into());
+        assert!(
+            !scheduler.deliver_now(2).await.unwrap(),
+            "Delivered message should no longer be pending"
+        );
+
+        assert!(scheduler.remove_task(1));
+        assert!(scheduler.pending().unwrap().is_empty());
+        assert!(!scheduler.deliver_now(1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn must_spread_deliveries() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_string_lossy().to_string();
+        let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(&db_path)).unwrap();
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+        let spread_interval = std::time::Duration::from_secs(60);
+        let scheduler = Scheduler::new(
+            pool,
+            MockMQ::default(),
+            Options {
+                spread_interval,
+                ..Options::default()
+            },
+        );
+        let at = Utc::now().naive_utc() + chrono::Duration::seconds(600);
+        let entities: Vec<_> = (1..=10).map(Uuid::from_u128).collect();
+        for (delay_id, entity) in (0..).zip(&entities) {
+            let event = Event::from_serializable("", *entity, ()).unwrap();
+            scheduler.add_task(
+                DelayedMessage::new(delay_id, Middlewares::default(), event, at),
+                true,
+            );
+        }
+        // A later message of the first entity.
+        let event = Event::from_serializable("", entities[0], ()).unwrap();
+        let later = at + chrono::Duration::seconds(1);
+        scheduler.add_task(
+            DelayedMessage::new(10, Middlewares::default(), event, later),
+            true,
+        );
+
+        let pending = scheduler.pending().unwrap();
+        let spread = chrono::Duration::from_std(spread_interval).unwrap();
+        assert!(
+            pending
+                .iter()
+                .all(|msg| msg.deliver_at >= at && msg.deliver_at < later + spread),
+            "Deliveries should be delayed by less than the interval"
+        );
+        assert!(
+            pending
+                .iter()
+                .any(|msg| msg.deliver_at != pending[0].deliver_at),
+            "Deliveries should be spread"
+        );
+        let deliver_at = |delay_id| {
+            pending
+                .iter()
+                .find(|msg| msg.id == delay_id)
+                .unwrap()
+                .deliver_at
+        };
+        assert_eq!(
+            deliver_at(10) - deliver_at(0),
+            chrono::Duration::seconds(1),
+            "Deliveries of an entity should keep their order"
+        );
+    }
 }
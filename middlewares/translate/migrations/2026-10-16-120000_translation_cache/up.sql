@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE translations
+(
+    source_hash TEXT NOT NULL,
+    language    TEXT NOT NULL,
+    translated  TEXT NOT NULL,
+    PRIMARY KEY (source_hash, language) ON CONFLICT REPLACE
+);
+
+CREATE TABLE glossary
+(
+    term     TEXT NOT NULL,
+    language TEXT NOT NULL,
+    target   TEXT NOT NULL,
+    PRIMARY KEY (term, language) ON CONFLICT REPLACE
+);
@@ -0,0 +1,105 @@
+//! Admin HTTP API.
+use std::{future, sync::Arc};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    routing::{get, put},
+    Extension,
+    Json,
+    Router,
+};
+use eyre::Result;
+use isolanguage_1::LanguageCode;
+use serde::Deserialize;
+use tower_http::auth::RequireAuthorizationLayer;
+use tracing::{error, info};
+
+use crate::{
+    config::Config,
+    glossary::{GlossaryEntry, GlossaryStore},
+};
+
+/// Target of a glossary term set with the admin API.
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    /// Language of the target, or every language if not set.
+    #[serde(default)]
+    pub language: Option<LanguageCode>,
+    /// Translation of the term.
+    pub target: String,
+}
+
+/// Language of a glossary target to remove, or the one for every language if
+/// not set.
+#[derive(Debug, Deserialize)]
+pub struct TargetLanguage {
+    #[serde(default)]
+    pub language: Option<LanguageCode>,
+}
+
+/// Serve the admin API until it fails. Never returns if no token is set.
+///
+/// # Errors
+/// Returns an error if the server fails to bind or serve.
+pub async fn serve(glossary: Arc<GlossaryStore>, config: &Config) -> Result<()> {
+    let Some(token) = &config.admin_token else {
+        info!("Admin API disabled");
+        return future::pending().await;
+    };
+    info!("Admin API listening on {}", config.admin_bind);
+
+    axum::Server::try_bind(&config.admin_bind)?
+        .serve(router(glossary, token).into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Build the admin router. All routes require the given bearer token.
+pub fn router(glossary: Arc<GlossaryStore>, token: &str) -> Router {
+    Router::new()
+        .route("/glossary", get(list_entries))
+        .route("/glossary/:term", put(set_target).delete(remove_target))
+        .layer(Extension(glossary))
+        .layer(RequireAuthorizationLayer::bearer(token))
+}
+
+async fn list_entries(
+    Extension(glossary): Extension<Arc<GlossaryStore>>,
+) -> Json<Vec<GlossaryEntry>> {
+    Json(glossary.entries())
+}
+
+async fn set_target(
+    Path(term): Path<String>,
+    Extension(glossary): Extension<Arc<GlossaryStore>>,
+    Json(target): Json<Target>,
+) -> StatusCode {
+    let entry = GlossaryEntry {
+        term,
+        language: target.language,
+        target: target.target,
+    };
+    match glossary.insert(entry) {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(error) => {
+            error!(?error, "Failed to set glossary target");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn remove_target(
+    Path(term): Path<String>,
+    Query(TargetLanguage { language }): Query<TargetLanguage>,
+    Extension(glossary): Extension<Arc<GlossaryStore>>,
+) -> StatusCode {
+    match glossary.remove(&term, language) {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => {
+            error!(%term, ?error, "Failed to remove glossary target");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
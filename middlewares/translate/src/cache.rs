@@ -0,0 +1,234 @@
+//! Cache of translations, keyed by hash of the source text and the target
+//! language.
+//!
+//! Recent translations are kept in memory, and all of them in the database,
+//! so that retweets and repeated phrases are translated once, even across
+//! restarts.
+
+use std::{num::NonZeroUsize, sync::Arc};
+
+use async_trait::async_trait;
+use diesel::{OptionalExtension, QueryDsl, RunQueryDsl};
+use eyre::Result;
+use isolanguage_1::LanguageCode;
+use lru::LruCache;
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    db::{CachedTranslation, DbPool},
+    glossary::GlossaryStore,
+    schema::translations::{self, dsl},
+    translate::Translator,
+};
+
+/// Translations in memory and in the database.
+pub struct Cache {
+    recent: Mutex<LruCache<(String, LanguageCode), String>>,
+    pool: DbPool,
+}
+
+impl Cache {
+    /// Create a cache keeping `capacity` recent translations in memory.
+    pub fn new(pool: DbPool, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            recent: Mutex::new(LruCache::new(capacity)),
+            pool,
+        }
+    }
+
+    /// Cached translation of `text` into `language`. Translations only in
+    /// the database are brought into memory.
+    pub fn get(&self, text: &str, language: LanguageCode) -> Option<String> {
+        let key = (source_hash(text), language);
+        if let Some(translated) = self.recent.lock().get(&key) {
+            return Some(translated.clone());
+        }
+        match self.load(&key.0, language) {
+            Ok(Some(translated)) => {
+                self.recent.lock().put(key, translated.clone());
+                Some(translated)
+            }
+            Ok(None) => None,
+            Err(error) => {
+                warn!(?error, "Failed to load cached translation");
+                None
+            }
+        }
+    }
+
+    /// Cache the translation of `text` into `language`.
+    pub fn put(&self, text: &str, language: LanguageCode, translated: &str) {
+        let source_hash = source_hash(text);
+        let row = CachedTranslation {
+            source_hash: source_hash.clone(),
+            language: language.code().to_string(),
+            translated: translated.to_string(),
+        };
+        if let Err(error) = self.store(&row) {
+            warn!(?error, "Failed to store translation");
+        }
+        self.recent
+            .lock()
+            .put((source_hash, language), translated.to_string());
+    }
+
+    fn load(&self, source_hash: &str, language: LanguageCode) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+        Ok(dsl::translations
+            .find((source_hash, language.code()))
+            .select(dsl::translated)
+            .first(&conn)
+            .optional()?)
+    }
+
+    fn store(&self, row: &CachedTranslation) -> Result<()> {
+        let conn = self.pool.get()?;
+        diesel::insert_into(translations::table)
+            .values(row)
+            .execute(&conn)?;
+        Ok(())
+    }
+}
+
+/// Hash of a source text, as key of its translations.
+fn source_hash(text: &str) -> String {
+    format!("{:x}", md5::compute(text))
+}
+
+/// Translator applying the glossary to texts, and caching translations of
+/// another translator.
+pub struct CachingTranslator {
+    inner: Box<dyn Translator>,
+    cache: Cache,
+    glossary: Arc<GlossaryStore>,
+}
+
+impl CachingTranslator {
+    pub fn new(inner: Box<dyn Translator>, cache: Cache, glossary: Arc<GlossaryStore>) -> Self {
+        Self {
+            inner,
+            cache,
+            glossary,
+        }
+    }
+}
+
+#[async_trait]
+impl Translator for CachingTranslator {
+    async fn translate_text(&self, text: &str, to: LanguageCode) -> Result<String> {
+        // Translations are cached with terms masked, so that edited targets
+        // apply to cached ones too.
+        let masked = self.glossary.mask(text, to);
+        let translated = if let Some(translated) = self.cache.get(&masked.text, to) {
+            debug!(%to, "Translation cached");
+            translated
+        } else {
+            let translated = self.inner.translate_text(&masked.text, to).await?;
+            self.cache.put(&masked.text, to, &translated);
+            translated
+        };
+        Ok(masked.unmask(&translated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use async_trait::async_trait;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use eyre::Result;
+    use isolanguage_1::LanguageCode;
+    use tempfile::tempdir;
+
+    use crate::{
+        cache::{Cache, CachingTranslator},
+        embedded_migrations,
+        glossary::{GlossaryEntry, GlossaryStore},
+        translate::{MockTranslator, Translator},
+    };
+
+    /// Mock translator counting texts it translates.
+    #[derive(Clone, Default)]
+    struct CountingTranslator(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Translator for CountingTranslator {
+        async fn translate_text(&self, text: &str, to: LanguageCode) -> Result<String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            MockTranslator.translate_text(text, to).await
+        }
+    }
+
+    #[tokio::test]
+    async fn must_cache_translations() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("db.sqlite");
+        let pool = Pool::new(ConnectionManager::new(db_path.to_str().unwrap())).unwrap();
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+        let glossary = Arc::new(GlossaryStore::load(pool.clone()).unwrap());
+        glossary
+            .insert(GlossaryEntry {
+                term: String::from("すいせい"),
+                language: None,
+                target: String::from("Suisei"),
+            })
+            .unwrap();
+
+        let counter = CountingTranslator::default();
+        let translator = CachingTranslator::new(
+            Box::new(counter.clone()),
+            Cache::new(pool.clone(), 1),
+            glossary.clone(),
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                translator
+                    .translate_text("すいせいです", LanguageCode::En)
+                    .await
+                    .unwrap(),
+                "en:Suiseiです"
+            );
+        }
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        // Evicted from memory, but still in the database.
+        translator
+            .translate_text("こんにちは", LanguageCode::En)
+            .await
+            .unwrap();
+        translator
+            .translate_text("すいせいです", LanguageCode::En)
+            .await
+            .unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+
+        // Edited targets apply to cached translations.
+        glossary
+            .insert(GlossaryEntry {
+                term: String::from("すいせい"),
+                language: Some(LanguageCode::En),
+                target: String::from("Hoshimachi Suisei"),
+            })
+            .unwrap();
+        let restarted = CachingTranslator::new(
+            Box::new(counter.clone()),
+            Cache::new(pool.clone(), 1),
+            Arc::new(GlossaryStore::load(pool).unwrap()),
+        );
+        assert_eq!(
+            restarted
+                .translate_text("すいせいです", LanguageCode::En)
+                .await
+                .unwrap(),
+            "en:Hoshimachi Suiseiです"
+        );
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+    }
+}
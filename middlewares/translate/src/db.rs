@@ -0,0 +1,33 @@
+#![allow(clippy::extra_unused_lifetimes)]
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Insertable,
+    Queryable,
+    SqliteConnection,
+};
+
+use crate::schema::{glossary, translations};
+
+/// Pool of connections to the database.
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Translation of a text, keyed by hash of the text and ISO 639-1 code of the
+/// language.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "translations"]
+pub struct CachedTranslation {
+    pub source_hash: String,
+    pub language: String,
+    pub translated: String,
+}
+
+/// Target of a glossary term in a language, given by its ISO 639-1 code.
+/// Targets for every language have an empty language.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "glossary"]
+pub struct GlossaryRow {
+    pub term: String,
+    pub language: String,
+    pub target: String,
+}
@@ -0,0 +1,321 @@
+//! Glossary of terms translated in a fixed way, e.g. names of vtubers.
+//!
+//! Before translation, terms in the text are replaced by placeholders, which
+//! translators leave alone. After translation, placeholders are replaced by
+//! targets of the terms in the target language.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use eyre::{Result, WrapErr};
+use isolanguage_1::LanguageCode;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    db::{DbPool, GlossaryRow},
+    schema::glossary::{self, dsl},
+};
+
+/// Translation of a term.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlossaryEntry {
+    /// Term in source texts, e.g. `すいせい`.
+    pub term: String,
+    /// Language of the target. If not set, the target applies to languages
+    /// without their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<LanguageCode>,
+    /// Translation of the term, e.g. `Suisei`.
+    pub target: String,
+}
+
+impl From<GlossaryEntry> for GlossaryRow {
+    fn from(entry: GlossaryEntry) -> Self {
+        Self {
+            term: entry.term,
+            language: entry
+                .language
+                .map_or_else(String::new, |language| language.code().to_string()),
+            target: entry.target,
+        }
+    }
+}
+
+impl TryFrom<GlossaryRow> for GlossaryEntry {
+    type Error = eyre::Report;
+
+    fn try_from(row: GlossaryRow) -> Result<Self> {
+        let language = if row.language.is_empty() {
+            None
+        } else {
+            Some(
+                serde_json::from_value(Value::String(row.language))
+                    .wrap_err("Invalid language of glossary term")?,
+            )
+        };
+        Ok(Self {
+            term: row.term,
+            language,
+            target: row.target,
+        })
+    }
+}
+
+/// Targets of terms.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Glossary {
+    /// Targets of each term by language, `None` for every language.
+    terms: HashMap<String, HashMap<Option<LanguageCode>, String>>,
+}
+
+impl Glossary {
+    /// Set the target of a term, replacing the one in the same language.
+    pub fn insert(&mut self, entry: GlossaryEntry) {
+        self.terms
+            .entry(entry.term)
+            .or_default()
+            .insert(entry.language, entry.target);
+    }
+
+    /// Remove the target of a term in a language. Return whether it existed.
+    pub fn remove(&mut self, term: &str, language: Option<LanguageCode>) -> bool {
+        let Some(targets) = self.terms.get_mut(term) else {
+            return false;
+        };
+        let removed = targets.remove(&language).is_some();
+        if targets.is_empty() {
+            self.terms.remove(term);
+        }
+        removed
+    }
+
+    /// All entries, ordered by term.
+    #[must_use]
+    pub fn entries(&self) -> Vec<GlossaryEntry> {
+        let mut entries: Vec<_> = self
+            .terms
+            .iter()
+            .flat_map(|(term, targets)| {
+                targets.iter().map(|(language, target)| GlossaryEntry {
+                    term: term.clone(),
+                    language: *language,
+                    target: target.clone(),
+                })
+            })
+            .collect();
+        entries.sort_unstable_by_key(|entry| {
+            (
+                entry.term.clone(),
+                entry.language.map(|language| language.code()),
+            )
+        });
+        entries
+    }
+
+    /// Target of a term in a language, falling back to the one for every
+    /// language.
+    #[must_use]
+    pub fn target(&self, term: &str, language: LanguageCode) -> Option<&str> {
+        let targets = self.terms.get(term)?;
+        targets
+            .get(&Some(language))
+            .or_else(|| targets.get(&None))
+            .map(String::as_str)
+    }
+
+    /// Replace terms with a target in `language` by placeholders. Longer terms
+    /// are replaced first, so that terms containing others win.
+    #[must_use]
+    pub fn mask(&self, text: &str, language: LanguageCode) -> Masked {
+        let mut terms: Vec<_> = self
+            .terms
+            .keys()
+            .filter_map(|term| Some((term.as_str(), self.target(term, language)?)))
+            .collect();
+        terms.sort_unstable_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        let mut masked = Masked {
+            text: text.to_string(),
+            targets: vec![],
+        };
+        for (term, target) in terms {
+            if masked.text.contains(term) {
+                masked.text = masked
+                    .text
+                    .replace(term, &placeholder(masked.targets.len()));
+                masked.targets.push(target.to_string());
+            }
+        }
+        masked
+    }
+}
+
+/// Text with glossary terms replaced by placeholders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Masked {
+    /// Text to translate.
+    pub text: String,
+    /// Targets of replaced terms, in the order of placeholders.
+    targets: Vec<String>,
+}
+
+impl Masked {
+    /// Replace placeholders in the translation of the masked text by targets
+    /// of the terms.
+    #[must_use]
+    pub fn unmask(&self, translated: &str) -> String {
+        self.targets
+            .iter()
+            .enumerate()
+            .fold(translated.to_string(), |text, (i, target)| {
+                text.replace(&placeholder(i), target)
+            })
+    }
+}
+
+/// Placeholder of the `i`th term replaced in a text.
+fn placeholder(i: usize) -> String {
+    format!("{{{{{i}}}}}")
+}
+
+/// Glossary kept in memory and persisted in the database.
+pub struct GlossaryStore {
+    glossary: RwLock<Glossary>,
+    pool: DbPool,
+}
+
+impl GlossaryStore {
+    /// Load the glossary from the database.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails or has invalid entries.
+    pub fn load(pool: DbPool) -> Result<Self> {
+        let rows: Vec<GlossaryRow> = dsl::glossary.load(&pool.get()?)?;
+        let mut glossary = Glossary::default();
+        for row in rows {
+            glossary.insert(row.try_into()?);
+        }
+        Ok(Self {
+            glossary: RwLock::new(glossary),
+            pool,
+        })
+    }
+
+    /// Import entries from a JSON file, replacing targets of the same terms
+    /// in the same languages. Return the number of entries imported.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or parsed, or the database
+    /// query fails.
+    pub fn import(&self, path: &Path) -> Result<usize> {
+        let entries: Vec<GlossaryEntry> = serde_json::from_slice(&fs::read(path)?)
+            .wrap_err_with(|| format!("Invalid glossary file {}", path.display()))?;
+        let count = entries.len();
+        for entry in entries {
+            self.insert(entry)?;
+        }
+        Ok(count)
+    }
+
+    /// All entries, ordered by term.
+    #[must_use]
+    pub fn entries(&self) -> Vec<GlossaryEntry> {
+        self.glossary.read().entries()
+    }
+
+    /// Replace terms with a target in `language` by placeholders.
+    #[must_use]
+    pub fn mask(&self, text: &str, language: LanguageCode) -> Masked {
+        self.glossary.read().mask(text, language)
+    }
+
+    /// Set the target of a term, replacing the one in the same language.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub fn insert(&self, entry: GlossaryEntry) -> Result<()> {
+        diesel::insert_into(glossary::table)
+            .values(GlossaryRow::from(entry.clone()))
+            .execute(&self.pool.get()?)?;
+        self.glossary.write().insert(entry);
+        Ok(())
+    }
+
+    /// Remove the target of a term in a language. Return whether it existed.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub fn remove(&self, term: &str, language: Option<LanguageCode>) -> Result<bool> {
+        let code = language.map_or("", |language| language.code());
+        diesel::delete(
+            dsl::glossary
+                .filter(dsl::term.eq(term))
+                .filter(dsl::language.eq(code)),
+        )
+        .execute(&self.pool.get()?)?;
+        Ok(self.glossary.write().remove(term, language))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use isolanguage_1::LanguageCode;
+
+    use crate::glossary::{Glossary, GlossaryEntry};
+
+    fn glossary() -> Glossary {
+        let mut glossary = Glossary::default();
+        for (term, language, target) in [
+            ("すいせい", None, "Suisei"),
+            ("すいせい", Some(LanguageCode::Zh), "彗星"),
+            ("ほしまち", None, "Hoshimachi"),
+            ("ほしまちすいせい", None, "Hoshimachi Suisei"),
+        ] {
+            glossary.insert(GlossaryEntry {
+                term: term.to_string(),
+                language,
+                target: target.to_string(),
+            });
+        }
+        glossary
+    }
+
+    #[test]
+    fn must_mask() {
+        let glossary = glossary();
+
+        let masked = glossary.mask("ほしまちすいせいです。すいせいは", LanguageCode::En);
+        assert_eq!(masked.text, "{{0}}です。{{1}}は");
+        assert_eq!(
+            masked.unmask("It's {{0}}. {{1}} is"),
+            "It's Hoshimachi Suisei. Suisei is"
+        );
+
+        let masked = glossary.mask("すいせいは", LanguageCode::Zh);
+        assert_eq!(masked.unmask("{{0}}是"), "彗星是");
+
+        let masked = glossary.mask("こんにちは", LanguageCode::En);
+        assert_eq!(masked.text, "こんにちは");
+        assert_eq!(masked.unmask("Hello"), "Hello");
+    }
+
+    #[test]
+    fn must_edit() {
+        let mut glossary = glossary();
+        assert_eq!(glossary.entries().len(), 4);
+        assert_eq!(
+            glossary.target("すいせい", LanguageCode::Ja),
+            Some("Suisei")
+        );
+
+        assert!(glossary.remove("すいせい", None));
+        assert!(!glossary.remove("すいせい", None));
+        assert_eq!(glossary.target("すいせい", LanguageCode::Ja), None);
+        assert_eq!(glossary.target("すいせい", LanguageCode::Zh), Some("彗星"));
+
+        assert!(glossary.remove("すいせい", Some(LanguageCode::Zh)));
+        assert_eq!(glossary.entries().len(), 2);
+    }
+}
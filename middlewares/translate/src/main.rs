@@ -1,57 +1,134 @@
+// Derives and macros of diesel 1.x expand to non-local impls.
+#![allow(non_local_definitions)]
+
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
+use std::sync::Arc;
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
 use eyre::{Result, WrapErr};
 use futures_util::StreamExt;
 use sg_core::{
     mq::{MessageQueue, RabbitMQ},
-    utils::FigmentExt,
+    utils::{init_tracing, FigmentExt, Shutdown},
 };
-use tracing::error;
+use tracing::{error, info, Instrument};
 use tracing_subscriber::EnvFilter;
 
 use crate::{
+    cache::{Cache, CachingTranslator},
     config::Config,
+    glossary::GlossaryStore,
     translate::{BaiduTranslator, MockTranslator, Translator},
 };
 
+mod admin;
+mod cache;
 mod config;
+mod db;
+mod glossary;
+mod schema;
 mod translate;
 
+embed_migrations!();
+
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    let _guard = init_tracing("translate", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("MIDDLEWARE_").wrap_err("Failed to load config")?;
+
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
 
-    let config = Config::from_env("MIDDLEWARE_")
-        .wrap_err("Failed to load config from environment variables")?;
+    let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(
+        &config.database_url,
+    ))
+    .wrap_err("Failed to connect to SQLite database")?;
+
+    embedded_migrations::run(&pool.get()?).wrap_err("Failed to run migration script")?;
+
+    let glossary = Arc::new(GlossaryStore::load(pool.clone()).wrap_err("Failed to load glossary")?);
+    if let Some(path) = &config.glossary_path {
+        let count = glossary
+            .import(path)
+            .wrap_err("Failed to import glossary")?;
+        info!(count, "Glossary imported");
+    }
+    tokio::spawn({
+        let (glossary, config) = (glossary.clone(), config.clone());
+        async move {
+            if let Err(error) = admin::serve(glossary, &config).await {
+                error!(?error, "Admin API failed");
+            }
+        }
+    });
 
     let translator: Box<dyn Translator> = if config.debug {
         Box::new(MockTranslator)
     } else {
         Box::new(BaiduTranslator::new(
             config.baidu_app_id,
-            config.baidu_app_secret,
+            config.baidu_app_secret.clone().into_inner(),
         ))
     };
+    let translator = CachingTranslator::new(
+        translator,
+        Cache::new(pool, config.cache_capacity),
+        glossary,
+    );
 
-    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
-        .await
-        .wrap_err("Failed to connect to AMQP")?;
+    let mq = Arc::new(
+        RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
+            .await
+            .wrap_err("Failed to connect to AMQP")?
+            .with_prefetch(config.amqp_prefetch)
+            .with_encoding(config.amqp_encoding),
+    );
+    shutdown.on_shutdown("message queue", {
+        let mq = mq.clone();
+        move || async move { mq.close().await }
+    });
 
-    let mut consumer = mq.consume(Some("translate")).await;
+    let mut consumer = mq.consume(Some("translate"), None).await;
 
-    while let Some(Ok((next, event))) = consumer.next().await {
-        let event = match translator.translate_event(event.clone()).await {
-            Ok(translated) => translated,
-            Err(e) => {
-                error!(?e, "Failed to translate event, ignore");
-                event
+    // Stop receiving on shutdown, but finish the event in hand.
+    while let Some(Some(Ok((next, event, delivery)))) = shutdown.until(consumer.next()).await {
+        let span = event.consume_span();
+        async {
+            let languages = config.languages(&event.kind);
+            let event = match translator.translate_event(event.clone(), languages).await {
+                Ok(translated) => translated,
+                Err(e) => {
+                    error!(?e, "Failed to translate event, ignore");
+                    event
+                }
+            };
+            // Acknowledged only once passed on, so that the event is not
+            // lost if the middleware stops in between.
+            let result = match mq.publish(event, next).await {
+                Ok(()) => delivery.ack().await,
+                Err(error) => {
+                    error!(?error, "Failed to publish translated event");
+                    delivery.nack(true).await
+                }
+            };
+            if let Err(error) = result {
+                error!(?error, "Failed to acknowledge event");
             }
-        };
-        if let Err(error) = mq.publish(event, next).await {
-            error!(?error, "Failed to publish translated event");
         }
+        .instrument(span)
+        .await;
     }
 
+    shutdown.run_hooks().await;
+
     Ok(())
 }
@@ -0,0 +1,17 @@
+table! {
+    glossary (term, language) {
+        term -> Text,
+        language -> Text,
+        target -> Text,
+    }
+}
+
+table! {
+    translations (source_hash, language) {
+        source_hash -> Text,
+        language -> Text,
+        translated -> Text,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(glossary, translations,);
@@ -1,13 +1,20 @@
 use async_trait::async_trait;
-use eyre::{ContextCompat, Result};
+use eyre::{ContextCompat, Result, WrapErr};
+use isolanguage_1::LanguageCode;
 use reqwest::Client;
-use serde_json::Value;
-use sg_core::models::Event;
+use serde_json::{Map, Value};
+use sg_core::models::{Event, TRANSLATIONS};
 use tracing::warn;
 
+/// Meta field of target languages of an event, overriding the configured ones.
+const TRANSLATE_LANGUAGES: &str = "x-translate-languages";
+
 #[async_trait]
 pub trait Translator: Send + Sync {
-    async fn translate_event(&self, mut event: Event) -> Result<Event> {
+    /// Translate fields listed in `x-translate-fields` into each language in
+    /// `x-translate-languages`, or `languages` if it's not set. Translations
+    /// are put into `translations`, leaving the source text untouched.
+    async fn translate_event(&self, mut event: Event, languages: &[LanguageCode]) -> Result<Event> {
         let translate_fields: Vec<_> = event
             .fields
             .remove("x-translate-fields")
@@ -17,30 +24,46 @@ pub trait Translator: Send + Sync {
             .iter()
             .map(|v| Ok(v.as_str().wrap_err("Not a string")?.to_string()))
             .collect::<Result<_>>()?;
+        let languages: Vec<LanguageCode> = match event.fields.remove(TRANSLATE_LANGUAGES) {
+            Some(languages) => serde_json::from_value(languages)
+                .wrap_err("Not an array of language codes: `x-translate-languages`")?,
+            None => languages.to_vec(),
+        };
 
-        let mut fields = Value::Object(event.fields);
-        for field in translate_fields {
-            if let Some(Value::String(src)) = fields.pointer_mut(&field) {
-                match self.translate_text(src).await {
-                    Ok(t) => {
-                        *src = t;
-                    }
-                    Err(error) => {
-                        warn!(?error, %src, "Failed to translate text");
+        let fields = Value::Object(event.fields.clone());
+        let mut translations = match event.fields.remove(TRANSLATIONS) {
+            Some(Value::Object(translations)) => translations,
+            _ => Map::new(),
+        };
+        for language in languages {
+            let mut translated = Map::new();
+            for field in &translate_fields {
+                if let Some(Value::String(src)) = fields.pointer(field) {
+                    match self.translate_text(src, language).await {
+                        Ok(t) => {
+                            translated.insert(field.clone(), Value::String(t));
+                        }
+                        Err(error) => {
+                            warn!(?error, %src, %language, "Failed to translate text");
+                        }
                     }
+                } else {
+                    warn!(?fields, %field, "Field not found in event");
                 }
-            } else {
-                warn!(?fields, %field, "Field not found in event");
+            }
+            if !translated.is_empty() {
+                translations.insert(language.code().to_string(), Value::Object(translated));
             }
         }
 
-        event.fields = match fields {
-            Value::Object(o) => o,
-            _ => unreachable!(),
-        };
+        if !translations.is_empty() {
+            event
+                .fields
+                .insert(TRANSLATIONS.to_string(), Value::Object(translations));
+        }
         Ok(event)
     }
-    async fn translate_text(&self, text: &str) -> Result<String>;
+    async fn translate_text(&self, text: &str, to: LanguageCode) -> Result<String>;
 }
 
 pub struct BaiduTranslator {
@@ -61,14 +84,14 @@ impl BaiduTranslator {
 
 #[async_trait]
 impl Translator for BaiduTranslator {
-    async fn translate_text(&self, text: &str) -> Result<String> {
+    async fn translate_text(&self, text: &str, to: LanguageCode) -> Result<String> {
         let salt: usize = rand::random();
         let pre_sign = format!("{}{}{}{}", self.app_id, text, salt, self.app_secret);
         let sign = format!("{:x}", md5::compute(pre_sign));
         let resp: Value = self
             .client
             .get("https://fanyi-api.baidu.com/api/trans/vip/translate")
-            .query(&[("q", text), ("from", "auto"), ("to", "zh")])
+            .query(&[("q", text), ("from", "auto"), ("to", baidu_language(to))])
             .query(&[("appid", self.app_id)])
             .query(&[("salt", salt)])
             .query(&[("sign", sign)])
@@ -86,17 +109,39 @@ impl Translator for BaiduTranslator {
     }
 }
 
+/// Language code used by Baidu translate, which differs from ISO 639-1 for
+/// some languages.
+const fn baidu_language(language: LanguageCode) -> &'static str {
+    match language {
+        LanguageCode::Ar => "ara",
+        LanguageCode::Bg => "bul",
+        LanguageCode::Da => "dan",
+        LanguageCode::Es => "spa",
+        LanguageCode::Et => "est",
+        LanguageCode::Fi => "fin",
+        LanguageCode::Fr => "fra",
+        LanguageCode::Ja => "jp",
+        LanguageCode::Ko => "kor",
+        LanguageCode::Ro => "rom",
+        LanguageCode::Sl => "slo",
+        LanguageCode::Sv => "swe",
+        LanguageCode::Vi => "vie",
+        _ => language.code(),
+    }
+}
+
 pub struct MockTranslator;
 
 #[async_trait]
 impl Translator for MockTranslator {
-    async fn translate_text(&self, text: &str) -> Result<String> {
-        Ok(format!("test{}", text))
+    async fn translate_text(&self, text: &str, to: LanguageCode) -> Result<String> {
+        Ok(format!("{}:{}", to.code(), text))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use isolanguage_1::LanguageCode;
     use serde_json::json;
     use sg_core::models::Event;
     use uuid::Uuid;
@@ -120,9 +165,15 @@ mod tests {
             .as_object()
             .unwrap()
             .clone(),
+            signature: None,
+            correlation_id: Some(Uuid::from_u128(1).into()),
+            caused_by: Some(Uuid::from_u128(2).into()),
         };
         let translator = MockTranslator;
-        let translated = translator.translate_event(e).await.unwrap();
+        let translated = translator
+            .translate_event(e, &[LanguageCode::Zh, LanguageCode::Ja])
+            .await
+            .unwrap();
         assert_eq!(
             translated,
             Event {
@@ -130,19 +181,53 @@ mod tests {
                 kind: "".to_string(),
                 entity: Uuid::nil().into(),
                 fields: json!({
-                    "a": "testa",
-                    "b": ["testb1", "b2"],
+                    "a": "a",
+                    "b": ["b1", "b2"],
                     "c": {
-                        "cc": "testd"
+                        "cc": "d"
+                    },
+                    "translations": {
+                        "zh": { "/a": "zh:a", "/b/0": "zh:b1", "/c/cc": "zh:d" },
+                        "ja": { "/a": "ja:a", "/b/0": "ja:b1", "/c/cc": "ja:d" }
                     }
                 })
                 .as_object()
                 .unwrap()
                 .clone(),
+                signature: None,
+                correlation_id: Some(Uuid::from_u128(1).into()),
+                caused_by: Some(Uuid::from_u128(2).into()),
             }
         );
     }
 
+    #[tokio::test]
+    async fn must_prefer_event_languages() {
+        let e = Event::from_serializable(
+            "",
+            Uuid::nil(),
+            json!({
+                "a": "a",
+                "x-translate-fields": ["/a"],
+                "x-translate-languages": ["en"]
+            }),
+        )
+        .unwrap();
+        let translated = MockTranslator
+            .translate_event(e, &[LanguageCode::Zh])
+            .await
+            .unwrap();
+        assert_eq!(
+            translated.fields,
+            *json!({
+                "a": "a",
+                "translations": { "en": { "/a": "en:a" } }
+            })
+            .as_object()
+            .unwrap()
+        );
+    }
+
     #[tokio::test]
     async fn test_baidu_translate() {
         if let (Some(app_id), Some(app_secret)) = (
@@ -151,7 +236,7 @@ mod tests {
         ) {
             let translator = BaiduTranslator::new(app_id.parse().unwrap(), app_secret.to_string());
             let translated = translator
-                .translate_text("Apples are good for our health.")
+                .translate_text("Apples are good for our health.", LanguageCode::Zh)
                 .await
                 .unwrap();
             assert!(!translated.is_empty());
@@ -169,6 +254,7 @@ mod tests {
                 .translate_text(
                     "Hoshimachi Suisei is a Japanese virtual YouTuber. She began posting videos \
                      as an independent creator in March 2018.",
+                    LanguageCode::Zh,
                 )
                 .await
                 .unwrap();
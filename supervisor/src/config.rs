@@ -0,0 +1,135 @@
+//! Supervisor config.
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sg_core::utils::Config;
+
+/// Supervisor config.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Config)]
+pub struct Config {
+    /// Components to run in this process.
+    pub components: Vec<Component>,
+    /// When to restart a stopped component.
+    #[config(default_str = "on_failure")]
+    pub restart: RestartPolicy,
+    /// Delay before restarting a component.
+    #[serde(with = "humantime_serde")]
+    #[config(default_str = "5s")]
+    pub restart_delay: Duration,
+    /// Bind address for the health endpoint.
+    #[config(default_str = "127.0.0.1:9000")]
+    pub health_bind: SocketAddr,
+}
+
+/// A component that can be embedded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    /// Api server.
+    Api,
+    /// Coordinator.
+    Coordinator,
+    /// Twitter worker.
+    Twitter,
+    /// Bililive worker.
+    Bililive,
+    /// Webhook bot.
+    Webhook,
+}
+
+impl Component {
+    /// Name of the component.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Api => "api",
+            Self::Coordinator => "coordinator",
+            Self::Twitter => "twitter",
+            Self::Bililive => "bililive",
+            Self::Webhook => "webhook",
+        }
+    }
+
+    /// Prefixes of environment variables the config of the component is
+    /// loaded from, later ones taking precedence.
+    ///
+    /// Variables prefixed with `SG_` are shared by all components. Workers
+    /// share `WORKER_`, and can be configured separately with
+    /// `WORKER_<KIND>_`.
+    #[must_use]
+    pub const fn env_prefixes(self) -> &'static [&'static str] {
+        match self {
+            Self::Api => &["SG_", "API_"],
+            Self::Coordinator => &["SG_", "COORDINATOR_"],
+            Self::Twitter => &["SG_", "WORKER_", "WORKER_TWITTER_"],
+            Self::Bililive => &["SG_", "WORKER_", "WORKER_BILILIVE_"],
+            Self::Webhook => &["SG_", "BOT_"],
+        }
+    }
+}
+
+/// When to restart a stopped component.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Never restart.
+    Never,
+    /// Restart if the component panicked.
+    OnPanic,
+    /// Restart if the component panicked or returned an error.
+    OnFailure,
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::result_large_err)]
+
+    use std::time::Duration;
+
+    use figment::Jail;
+    use sg_core::utils::FigmentExt;
+
+    use crate::config::{Component, Config, RestartPolicy};
+
+    #[test]
+    fn must_default() {
+        Jail::expect_with(|jail| {
+            jail.set_env("SUPERVISOR_COMPONENTS", "[api]");
+            assert_eq!(
+                Config::from_env("SUPERVISOR_").unwrap(),
+                Config {
+                    components: vec![Component::Api],
+                    restart: RestartPolicy::OnFailure,
+                    restart_delay: Duration::from_secs(5),
+                    health_bind: "127.0.0.1:9000".parse().unwrap(),
+                }
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn must_from_env() {
+        Jail::expect_with(|jail| {
+            jail.set_env("SUPERVISOR_COMPONENTS", "[coordinator, twitter, webhook]");
+            jail.set_env("SUPERVISOR_RESTART", "on_panic");
+            jail.set_env("SUPERVISOR_RESTART_DELAY", "1s");
+            jail.set_env("SUPERVISOR_HEALTH_BIND", "0.0.0.0:9001");
+            assert_eq!(
+                Config::from_env("SUPERVISOR_").unwrap(),
+                Config {
+                    components: vec![
+                        Component::Coordinator,
+                        Component::Twitter,
+                        Component::Webhook
+                    ],
+                    restart: RestartPolicy::OnPanic,
+                    restart_delay: Duration::from_secs(1),
+                    health_bind: "0.0.0.0:9001".parse().unwrap(),
+                }
+            );
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,26 @@
+//! Health endpoint.
+
+use std::collections::HashMap;
+
+use axum::{http::StatusCode, routing::get, Extension, Json, Router};
+
+use crate::supervisor::{Status, Statuses};
+
+/// Build the health router. `GET /health` responds with statuses of all
+/// components, with `503 Service Unavailable` if any of them is not running.
+pub fn router(statuses: Statuses) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .layer(Extension(statuses))
+}
+
+async fn health(
+    Extension(statuses): Extension<Statuses>,
+) -> (StatusCode, Json<HashMap<&'static str, Status>>) {
+    let code = if statuses.healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(statuses.snapshot()))
+}
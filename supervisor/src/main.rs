@@ -0,0 +1,126 @@
+//! Supervisor binary, running selected components in a single process.
+
+#![allow(clippy::module_name_repetitions)]
+#![deny(missing_docs)]
+
+use std::collections::HashSet;
+
+use eyre::{Result, WrapErr};
+use futures_util::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use sg_core::utils::{init_tracing, FigmentExt, Shutdown};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    config::{Component, Config},
+    supervisor::Supervisor,
+};
+
+pub mod config;
+pub mod health;
+pub mod supervisor;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _guard = init_tracing("supervisor", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("SUPERVISOR_").wrap_err("Failed to load config")?;
+
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
+
+    let supervisor = Supervisor::new(&config);
+    let mut seen = HashSet::new();
+    let components = config
+        .components
+        .iter()
+        .filter(|component| seen.insert(**component))
+        .map(|component| supervise(&supervisor, *component, &shutdown))
+        .collect::<Result<Vec<_>>>()?;
+
+    let server = axum::Server::try_bind(&config.health_bind)
+        .wrap_err("Failed to bind health endpoint")?
+        .serve(health::router(supervisor.statuses().clone()).into_make_service());
+
+    let result = tokio::select! {
+        r = server => r,
+        _ = join_all(components) => {
+            info!("All components stopped");
+            Ok(())
+        }
+        () = shutdown.triggered() => Ok(()),
+    };
+    shutdown.run_hooks().await;
+    result?;
+    Ok(())
+}
+
+/// Load config of the component and supervise it.
+///
+/// Configs are loaded beforehand so that invalid ones fail fast. Coordinator
+/// and workers stop on shutdown, others are dropped with the process.
+fn supervise(
+    supervisor: &Supervisor,
+    component: Component,
+    shutdown: &Shutdown,
+) -> Result<BoxFuture<'static, ()>> {
+    let prefixes = component.env_prefixes();
+    let err = || format!("Failed to load config of {}", component.name());
+    let supervisor = supervisor.clone();
+    let shutdown = shutdown.clone();
+    Ok(match component {
+        Component::Api => {
+            let config = api::server::Config::from_sources_layered(prefixes).wrap_err_with(err)?;
+            async move {
+                supervisor
+                    .supervise(component, || api::server::serve_with_config(config.clone()))
+                    .await;
+            }
+            .boxed()
+        }
+        Component::Coordinator => {
+            let config =
+                coordinator::config::Config::from_env_layered(prefixes).wrap_err_with(err)?;
+            async move {
+                supervisor
+                    .supervise(component, || coordinator::run(config.clone(), shutdown.clone()))
+                    .await;
+            }
+            .boxed()
+        }
+        Component::Twitter => {
+            let config =
+                twitter::config::Config::from_sources_layered(prefixes).wrap_err_with(err)?;
+            async move {
+                supervisor
+                    .supervise(component, || twitter::run(config.clone(), shutdown.clone()))
+                    .await;
+            }
+            .boxed()
+        }
+        Component::Bililive => {
+            let config = bililive_worker::config::Config::from_sources_layered(prefixes)
+                .wrap_err_with(err)?;
+            async move {
+                supervisor
+                    .supervise(component, || bililive_worker::run(config.clone(), shutdown.clone()))
+                    .await;
+            }
+            .boxed()
+        }
+        Component::Webhook => {
+            let config =
+                webhook::config::Config::from_sources_layered(prefixes).wrap_err_with(err)?;
+            async move {
+                supervisor
+                    .supervise(component, || webhook::run(config.clone()))
+                    .await;
+            }
+            .boxed()
+        }
+    })
+}
@@ -0,0 +1,244 @@
+//! Restart components according to the restart policy.
+
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use eyre::Result;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::config::{Component, Config, RestartPolicy};
+
+/// How a component stopped.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    /// Returned without error.
+    Exited,
+    /// Returned an error.
+    Failed,
+    /// Panicked.
+    Panicked,
+}
+
+impl RestartPolicy {
+    /// Whether a component stopped with `outcome` should be restarted.
+    #[must_use]
+    pub const fn should_restart(self, outcome: Outcome) -> bool {
+        matches!(
+            (self, outcome),
+            (Self::OnPanic, Outcome::Panicked)
+                | (Self::OnFailure, Outcome::Panicked | Outcome::Failed)
+        )
+    }
+}
+
+/// State of a component.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Running.
+    Running,
+    /// Stopped, waiting to be restarted.
+    Restarting,
+    /// Stopped for good.
+    Stopped,
+}
+
+/// Status of a component.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Status {
+    /// Current state.
+    pub state: State,
+    /// Times the component has been restarted.
+    pub restarts: usize,
+}
+
+/// Statuses of all supervised components.
+#[derive(Debug, Clone, Default)]
+pub struct Statuses(Arc<Mutex<HashMap<&'static str, Status>>>);
+
+impl Statuses {
+    /// Snapshot of all statuses.
+    #[must_use]
+    pub fn snapshot(&self) -> HashMap<&'static str, Status> {
+        self.0.lock().clone()
+    }
+
+    /// Whether all components are running.
+    #[must_use]
+    pub fn healthy(&self) -> bool {
+        self.0
+            .lock()
+            .values()
+            .all(|status| status.state == State::Running)
+    }
+
+    fn set(&self, component: Component, state: State) {
+        self.0
+            .lock()
+            .entry(component.name())
+            .or_insert(Status { state, restarts: 0 })
+            .state = state;
+    }
+
+    fn restarted(&self, component: Component) {
+        if let Some(status) = self.0.lock().get_mut(component.name()) {
+            status.restarts += 1;
+        }
+    }
+}
+
+/// Runs components and restarts them when they stop.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+    delay: Duration,
+    statuses: Statuses,
+}
+
+impl Supervisor {
+    /// Create a supervisor from config.
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        Self {
+            policy: config.restart,
+            delay: config.restart_delay,
+            statuses: Statuses::default(),
+        }
+    }
+
+    /// Statuses of supervised components.
+    #[must_use]
+    pub const fn statuses(&self) -> &Statuses {
+        &self.statuses
+    }
+
+    /// Run a component in a separate task, restarting it according to the
+    /// policy. Returns when the component stops for good.
+    pub async fn supervise<F, Fut>(&self, component: Component, run: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let span = info_span!("component", name = component.name());
+        loop {
+            self.statuses.set(component, State::Running);
+            info!(parent: &span, "Component started");
+
+            let outcome = match tokio::spawn(run().instrument(span.clone())).await {
+                Ok(Ok(())) => {
+                    info!(parent: &span, "Component exited");
+                    Outcome::Exited
+                }
+                Ok(Err(error)) => {
+                    error!(parent: &span, ?error, "Component failed");
+                    Outcome::Failed
+                }
+                // The task is never aborted, so it must have panicked.
+                Err(error) => {
+                    error!(parent: &span, %error, "Component panicked");
+                    Outcome::Panicked
+                }
+            };
+
+            if !self.policy.should_restart(outcome) {
+                self.statuses.set(component, State::Stopped);
+                warn!(parent: &span, "Component stopped");
+                return;
+            }
+
+            self.statuses.set(component, State::Restarting);
+            sleep(self.delay).await;
+            self.statuses.restarted(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use eyre::{bail, Result};
+
+    use crate::{
+        config::{Component, Config, RestartPolicy},
+        supervisor::{Outcome, State, Status, Supervisor},
+    };
+
+    fn supervisor(restart: RestartPolicy) -> Supervisor {
+        Supervisor::new(&Config {
+            components: vec![],
+            restart,
+            restart_delay: Duration::ZERO,
+            health_bind: "127.0.0.1:0".parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn must_follow_policy() {
+        for (policy, restarted) in [
+            (RestartPolicy::Never, [false, false, false]),
+            (RestartPolicy::OnPanic, [false, false, true]),
+            (RestartPolicy::OnFailure, [false, true, true]),
+        ] {
+            for (outcome, restarted) in [Outcome::Exited, Outcome::Failed, Outcome::Panicked]
+                .into_iter()
+                .zip(restarted)
+            {
+                assert_eq!(
+                    policy.should_restart(outcome),
+                    restarted,
+                    "{policy:?} {outcome:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn must_restart_on_panic() {
+        let supervisor = supervisor(RestartPolicy::OnPanic);
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        supervisor
+            .supervise(Component::Api, || {
+                let runs = runs.clone();
+                async move {
+                    match runs.fetch_add(1, Ordering::SeqCst) {
+                        0 | 1 => panic!("boom"),
+                        2 => bail!("failed"),
+                        _ => unreachable!("Errors are not restarted"),
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            supervisor.statuses().snapshot()["api"],
+            Status {
+                state: State::Stopped,
+                restarts: 2
+            }
+        );
+        assert!(!supervisor.statuses().healthy());
+    }
+
+    #[tokio::test]
+    async fn must_not_restart_exited() {
+        let supervisor = supervisor(RestartPolicy::OnFailure);
+        supervisor
+            .supervise(Component::Webhook, || async { Result::<()>::Ok(()) })
+            .await;
+        assert_eq!(
+            supervisor.statuses().snapshot()["webhook"].state,
+            State::Stopped
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Synthesize events of known kinds.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::{ensure, Result};
+use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
+use serde_json::Value;
+use sg_core::models::{validate_kind, Event, FieldType, KindSpec, KINDS};
+use uuid::Uuid;
+
+/// Field marking events of a load generator run, with the id of the run.
+pub const RUN_FIELD: &str = "x-loadgen-run";
+/// Field holding when an event was published, as Unix timestamp in
+/// milliseconds.
+pub const SENT_AT_FIELD: &str = "x-loadgen-sent-at";
+
+/// Generator of events of random kinds and entities, with payloads matching
+/// their kinds.
+pub struct Generator {
+    run: Uuid,
+    kinds: Vec<&'static KindSpec>,
+    entities: Vec<Uuid>,
+    string_length: usize,
+    rng: SmallRng,
+}
+
+impl Generator {
+    /// Generate events of `kinds`, or of every known kind if empty, for
+    /// `entities` random entities.
+    ///
+    /// # Errors
+    /// Returns an error if a kind is unknown, or if there are no entities.
+    pub fn new(kinds: &[String], entities: usize, string_length: usize) -> Result<Self> {
+        ensure!(entities > 0, "at least one entity is required");
+        let kinds = if kinds.is_empty() {
+            KINDS.iter().collect()
+        } else {
+            kinds
+                .iter()
+                .map(|kind| validate_kind(kind))
+                .collect::<Result<_>>()?
+        };
+        let mut rng = SmallRng::from_entropy();
+        let entities = (0..entities).map(|_| Uuid::from_u128(rng.gen())).collect();
+        Ok(Self {
+            run: Uuid::new_v4(),
+            kinds,
+            entities,
+            string_length,
+            rng,
+        })
+    }
+
+    /// Id of the run, in the [`RUN_FIELD`] of generated events.
+    #[must_use]
+    pub const fn run(&self) -> Uuid {
+        self.run
+    }
+
+    /// Generate an event, marked as sent now.
+    ///
+    /// # Errors
+    /// Returns an error if the event can't be built.
+    pub fn next_event(&mut self) -> Result<Event> {
+        let kind = self.kinds[self.rng.gen_range(0..self.kinds.len())];
+        let entity = self.entities[self.rng.gen_range(0..self.entities.len())];
+        let mut builder = Event::builder(kind.name, entity);
+        for field in kind.fields {
+            let value = self.value_of(field.ty);
+            builder = builder.field(field.name, value);
+        }
+        builder
+            .field(RUN_FIELD, self.run.to_string())
+            .field(SENT_AT_FIELD, now_millis())
+            .build()
+    }
+
+    fn value_of(&mut self, ty: FieldType) -> Value {
+        match ty {
+            FieldType::Boolean => Value::from(self.rng.gen::<bool>()),
+            FieldType::Integer => Value::from(self.rng.gen_range(0..1_000_000_u32)),
+            FieldType::String => Value::from(
+                (&mut self.rng)
+                    .sample_iter(Alphanumeric)
+                    .take(self.string_length)
+                    .map(char::from)
+                    .collect::<String>(),
+            ),
+            FieldType::Array(items) => {
+                let len = self.rng.gen_range(0..4);
+                Value::from((0..len).map(|_| self.value_of(*items)).collect::<Vec<_>>())
+            }
+        }
+    }
+}
+
+/// Now as Unix timestamp in milliseconds.
+#[must_use]
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    u64::try_from(now.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use sg_core::models::{kind, validate_kind, FieldType};
+
+    use crate::generator::{Generator, RUN_FIELD, SENT_AT_FIELD};
+
+    #[test]
+    fn must_generate_payloads_of_kinds() {
+        let kinds = vec![String::from(kind::TWITTER), String::from(kind::BILILIVE)];
+        let mut generator = Generator::new(&kinds, 3, 16).unwrap();
+        let run = generator.run().to_string();
+
+        for _ in 0..50 {
+            let event = generator.next_event().unwrap();
+            assert!(kinds.contains(&event.kind));
+            assert_eq!(event.fields[RUN_FIELD], run);
+            assert!(event.fields[SENT_AT_FIELD].is_u64());
+
+            let spec = validate_kind(&event.kind).unwrap();
+            for field in spec.fields {
+                let value = &event.fields[field.name];
+                match field.ty {
+                    FieldType::Boolean => assert!(value.is_boolean()),
+                    FieldType::Integer => assert!(value.is_u64()),
+                    FieldType::String => assert_eq!(value.as_str().unwrap().len(), 16),
+                    FieldType::Array(_) => assert!(value.is_array()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn must_reject_unknown_kinds() {
+        assert!(Generator::new(&[String::from("myspace")], 1, 16).is_err());
+        assert!(Generator::new(&[], 0, 16).is_err());
+    }
+}
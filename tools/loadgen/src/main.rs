@@ -0,0 +1,191 @@
+//! Load generator, publishing synthetic events into the message queue and
+//! measuring how long they take to come out at the other end.
+//!
+//! Events are of the configured kinds, for random entities, at a steady rate
+//! with optional bursts on top. They're consumed where bots receive them, or
+//! at a middleware, on a queue of the generator's own, so that the latency is
+//! measured along the same route real events take.
+
+use std::time::{Duration, Instant};
+
+use eyre::{ensure, Result, WrapErr};
+use futures_util::{future, StreamExt};
+use serde_json::Value;
+use sg_core::{
+    mq::{Deliveries, MessageQueue, Middlewares, RabbitMQ},
+    utils::{init_tracing, FigmentExt},
+};
+use tokio::{
+    sync::mpsc,
+    time::{interval, interval_at, sleep, Interval},
+};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    config::Config,
+    generator::{now_millis, Generator, RUN_FIELD, SENT_AT_FIELD},
+    stats::{Latencies, Report},
+};
+
+mod config;
+mod generator;
+mod stats;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _guard = init_tracing("loadgen", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("LOADGEN_").wrap_err("Failed to load config")?;
+    ensure!(config.rate > 0, "Rate must be positive");
+
+    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
+        .await
+        .wrap_err("Failed to connect to AMQP")?
+        .with_encoding(config.amqp_encoding);
+
+    let report = run(&config, &mq).await?;
+    mq.close().await?;
+
+    println!("{report}");
+    Ok(())
+}
+
+/// Publish events as configured, and wait for them to be received.
+async fn run(config: &Config, mq: &impl MessageQueue) -> Result<Report> {
+    let mut generator = Generator::new(&config.kinds, config.entities, config.string_length)?;
+    let route = config.route.parse::<Middlewares>().unwrap();
+
+    // Consume before publishing, so that no event is missed.
+    let consumer = mq.consume(config.consume_at.as_deref(), None).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let receiver = tokio::spawn(receive(consumer, generator.run().to_string(), tx));
+
+    info!(run = %generator.run(), "Publishing events");
+    // Ticks missed while publishing are caught up on, so that the average
+    // rate holds even above the resolution of the timer.
+    let mut ticks = interval(Duration::from_secs(1) / config.rate);
+    let mut bursts = config
+        .burst_interval
+        .map(|period| interval_at(tokio::time::Instant::now() + period, period));
+    let deadline = sleep(config.duration);
+    tokio::pin!(deadline);
+
+    let (mut published, mut failed) = (0, 0);
+    let start = Instant::now();
+    loop {
+        let count = tokio::select! {
+            () = &mut deadline => break,
+            _ = ticks.tick() => 1,
+            () = next_burst(&mut bursts) => config.burst_size,
+        };
+        for _ in 0..count {
+            let event = generator.next_event()?;
+            match mq.publish(event, route.clone()).await {
+                Ok(()) => published += 1,
+                Err(error) => {
+                    warn!(?error, "Failed to publish event");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    info!(published, "Waiting for events in flight");
+    let mut latencies = Latencies::default();
+    let drain = sleep(config.drain_timeout);
+    tokio::pin!(drain);
+    while (latencies.len() as u64) < published {
+        tokio::select! {
+            () = &mut drain => break,
+            latency = rx.recv() => match latency {
+                Some(latency) => latencies.record(latency),
+                None => break,
+            },
+        }
+    }
+    receiver.abort();
+
+    Ok(Report {
+        published,
+        failed,
+        elapsed,
+        latencies,
+    })
+}
+
+async fn next_burst(bursts: &mut Option<Interval>) {
+    match bursts {
+        Some(bursts) => {
+            bursts.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Acknowledge consumed events, and send the latencies of those of the run.
+async fn receive(mut consumer: Deliveries, run: String, tx: mpsc::UnboundedSender<Duration>) {
+    while let Some(delivery) = consumer.next().await {
+        let (_, event, delivery) = match delivery {
+            Ok(delivery) => delivery,
+            Err(error) => {
+                warn!(?error, "Failed to consume event");
+                continue;
+            }
+        };
+        if let Err(error) = delivery.ack().await {
+            warn!(?error, "Failed to acknowledge event");
+        }
+
+        // Events of other runs, or real ones, pass the same queue.
+        if event.fields.get(RUN_FIELD).and_then(Value::as_str) != Some(run.as_str()) {
+            continue;
+        }
+        let Some(sent_at) = event.fields.get(SENT_AT_FIELD).and_then(Value::as_u64) else {
+            continue;
+        };
+        let latency = Duration::from_millis(now_millis().saturating_sub(sent_at));
+        if tx.send(latency).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sg_core::{
+        mq::{mock::MockMQ, Encoding},
+        utils::Redacted,
+    };
+
+    use crate::{config::Config, run};
+
+    #[tokio::test]
+    async fn must_measure_latencies() {
+        let config = Config {
+            amqp_url: Redacted(String::new()),
+            amqp_exchange: String::new(),
+            amqp_encoding: Encoding::Json,
+            kinds: vec![],
+            entities: 3,
+            rate: 100,
+            duration: Duration::from_millis(100),
+            burst_interval: Some(Duration::from_millis(50)),
+            burst_size: 5,
+            route: String::new(),
+            consume_at: None,
+            drain_timeout: Duration::from_secs(1),
+            string_length: 8,
+        };
+        let report = run(&config, &MockMQ::default()).await.unwrap();
+
+        // About 10 events at the steady rate, and a burst of 5.
+        assert!(report.published >= 10, "{report}");
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.latencies.len() as u64, report.published);
+    }
+}
@@ -0,0 +1,114 @@
+//! End-to-end latencies and the report of a run.
+
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+/// Latencies of received events.
+#[derive(Debug, Default)]
+pub struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    /// Record the latency of an event.
+    pub fn record(&mut self, latency: Duration) {
+        self.0.push(latency);
+    }
+
+    /// Number of recorded latencies.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no latency is recorded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The latency `percent` percent of events are at most, by nearest rank.
+    /// `None` if no latency is recorded.
+    #[must_use]
+    pub fn percentile(&mut self, percent: u8) -> Option<Duration> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.0.sort_unstable();
+        let rank = (self.0.len() * usize::from(percent.min(100)) + 99) / 100;
+        Some(self.0[rank.saturating_sub(1)])
+    }
+}
+
+/// Summary of a run.
+#[derive(Debug)]
+pub struct Report {
+    /// Number of events published.
+    pub published: u64,
+    /// Number of events failed to publish.
+    pub failed: u64,
+    /// How long publishing took.
+    pub elapsed: Duration,
+    /// Latencies of events received.
+    pub latencies: Latencies,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Percentiles sort the latencies, so they're computed on a copy.
+        let mut latencies = Latencies(self.latencies.0.clone());
+        let received = latencies.len() as u64;
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.published as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+
+        writeln!(f, "published: {} ({rate:.1}/s)", self.published)?;
+        writeln!(f, "failed:    {}", self.failed)?;
+        writeln!(f, "received:  {received}")?;
+        writeln!(f, "lost:      {}", self.published.saturating_sub(received))?;
+        for (name, percent) in [("p50", 50), ("p90", 90), ("p99", 99), ("max", 100)] {
+            match latencies.percentile(percent) {
+                Some(latency) => writeln!(f, "{name}:       {latency:?}")?,
+                None => writeln!(f, "{name}:       -")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::stats::{Latencies, Report};
+
+    #[test]
+    fn must_take_percentiles() {
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.percentile(50), None);
+
+        for ms in (1..=100).rev() {
+            latencies.record(Duration::from_millis(ms));
+        }
+        assert_eq!(latencies.percentile(50), Some(Duration::from_millis(50)));
+        assert_eq!(latencies.percentile(99), Some(Duration::from_millis(99)));
+        assert_eq!(latencies.percentile(100), Some(Duration::from_millis(100)));
+        assert_eq!(latencies.percentile(0), Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn must_report() {
+        let mut latencies = Latencies::default();
+        latencies.record(Duration::from_millis(3));
+        let report = Report {
+            published: 2,
+            failed: 1,
+            elapsed: Duration::from_secs(1),
+            latencies,
+        };
+        assert_eq!(
+            report.to_string(),
+            "published: 2 (2.0/s)\nfailed:    1\nreceived:  1\nlost:      1\n\
+             p50:       3ms\np90:       3ms\np99:       3ms\nmax:       3ms\n"
+        );
+    }
+}
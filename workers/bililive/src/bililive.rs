@@ -1,7 +1,8 @@
 use eyre::Result;
 use once_cell::sync::Lazy;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use sg_core::models::LiveStartPayload;
 
 static HTTP: Lazy<Client> = Lazy::new(Client::new);
 
@@ -15,13 +16,15 @@ struct RawLiveRoom {
     title: String,
     user_cover: String,
     room_id: u64,
+    live_status: u8,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct LiveRoom {
     title: String,
     link: String,
     cover: Option<String>,
+    live: bool,
 }
 
 impl LiveRoom {
@@ -40,6 +43,19 @@ impl LiveRoom {
             } else {
                 Some(room.data.user_cover)
             },
+            live: room.data.live_status == 1,
         })
     }
+
+    pub const fn is_live(&self) -> bool {
+        self.live
+    }
+
+    pub fn into_payload(self) -> LiveStartPayload {
+        LiveStartPayload {
+            title: self.title,
+            link: self.link,
+            cover: self.cover,
+        }
+    }
 }
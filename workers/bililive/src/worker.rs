@@ -1,4 +1,8 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use bililive::RetryConfig;
 use eyre::{Result, WrapErr};
@@ -6,22 +10,29 @@ use futures_util::StreamExt;
 use parking_lot::Mutex;
 use serde::Deserialize;
 use sg_core::{
+    async_trait::async_trait,
+    error::Categorized,
+    lifecycle::WorkerHooks,
     models::{Event, Task},
-    mq::{MessageQueue, Middlewares},
-    protocol::WorkerRpc,
+    mq::{MessageQueue, Middlewares, Priority},
+    protocol::{add_each, remove_each, TaskLiveness, TaskReporter, WorkerRpc},
     utils::ScopedJoinHandle,
 };
 use tap::TapOptional;
 use tarpc::context::Context;
 use tokio::time::sleep;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 use uuid::Uuid;
 
 use crate::bililive::LiveRoom;
 
+/// Time after which a live start is stale, and dropped rather than delivered.
+const LIVE_START_TTL: Duration = Duration::from_secs(30 * 60);
+
 #[derive(Clone)]
 pub struct BililiveWorker {
     mq: Arc<dyn MessageQueue>,
+    reporter: TaskReporter,
 
     #[allow(clippy::type_complexity)]
     tasks: Arc<Mutex<HashMap<Uuid, (Task, ScopedJoinHandle<()>)>>>,
@@ -33,15 +44,31 @@ impl BililiveWorker {
     pub fn new(mq: impl MessageQueue + 'static) -> Self {
         Self {
             mq: Arc::new(mq),
+            reporter: TaskReporter::new(),
             tasks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    /// Reporter of task status, to join coordinators with.
+    #[must_use]
+    pub fn reporter(&self) -> TaskReporter {
+        self.reporter.clone()
+    }
+}
+
+#[async_trait]
+impl WorkerHooks for BililiveWorker {
+    async fn on_shutdown(&self) -> Result<()> {
+        // Flush events pending in the message queue.
+        self.mq.close().await
+    }
 }
 
 #[tarpc::server]
 impl WorkerRpc for BililiveWorker {
-    async fn ping(self, _: Context, id: u64) -> u64 {
-        id
+    async fn heartbeat(self, _: Context) -> Vec<TaskLiveness> {
+        let running: Vec<Uuid> = self.tasks.lock().keys().copied().collect();
+        self.reporter.liveness(running)
     }
 
     async fn add_task(self, _: Context, task: Task) -> bool {
@@ -54,23 +81,30 @@ impl WorkerRpc for BililiveWorker {
         info!(task_id = ?task.id, "Adding task");
 
         // Extract uid from the task.
-        let uid = match task.params.get("uid") {
-            Some(v) if v.is_u64() => v.as_u64().unwrap(),
-            Some(_) => {
-                error!("UID field: type mismatch. Expected: u64");
-                return false;
-            }
-            None => {
-                error!("UID field: missing");
-                return false;
-            }
+        let Some(uid) = uid(&task) else {
+            return false;
         };
 
+        let task_id = task.id.into();
         let fut = async move {
             loop {
                 info!(?uid, "Spawning bililive task");
-                if let Err(error) = bililive_task(uid, task.entity.into(), &*self.mq).await {
+                if let Err(error) = bililive_task(
+                    uid,
+                    task.entity.into(),
+                    &*self.mq,
+                    &self.reporter,
+                    task_id,
+                )
+                .await
+                {
                     error!(?error, "Bililive task failed");
+                    self.reporter.failing(task_id, format!("{error:#}")).await;
+                    if !error.is_retryable() {
+                        // It stays failing until the task is added again.
+                        warn!(%task_id, category = %error.category(), "Giving up task");
+                        break;
+                    }
 
                     // Sleep to avoid looping if the task always fails.
                     sleep(Duration::from_secs(60)).await;
@@ -92,6 +126,14 @@ impl WorkerRpc for BililiveWorker {
             .is_some()
     }
 
+    async fn add_tasks(self, ctx: Context, tasks: Vec<Task>) -> Vec<bool> {
+        add_each(self, ctx, tasks).await
+    }
+
+    async fn remove_tasks(self, ctx: Context, ids: Vec<Uuid>) -> Vec<bool> {
+        remove_each(self, ctx, ids).await
+    }
+
     async fn tasks(self, _: Context) -> Vec<Task> {
         self.tasks
             .lock()
@@ -100,6 +142,40 @@ impl WorkerRpc for BililiveWorker {
             .cloned()
             .collect()
     }
+
+    async fn backfill(self, _: Context, task: Task, _: SystemTime) -> bool {
+        let Some(uid) = uid(&task) else {
+            return false;
+        };
+
+        info!(task_id = ?task.id, "Backfilling task");
+
+        // Live streams have no history. Publish the ongoing one if any.
+        tokio::spawn(async move {
+            if let Err(error) = bililive_backfill(uid, task.entity.into(), &*self.mq).await {
+                error!(?error, "Failed to backfill bililive task");
+            }
+        });
+
+        true
+    }
+}
+
+// Extract uid from the task. The api stores it as a numeric string.
+fn uid(task: &Task) -> Option<u64> {
+    match task.params.get("uid") {
+        Some(v) => {
+            let uid = v.as_u64().or_else(|| v.as_str()?.parse().ok());
+            if uid.is_none() {
+                error!("UID field: type mismatch. Expected: u64 or numeric string");
+            }
+            uid
+        }
+        None => {
+            error!("UID field: missing");
+            None
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize)]
@@ -107,7 +183,13 @@ struct Command {
     cmd: String,
 }
 
-async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Result<()> {
+async fn bililive_task(
+    uid: u64,
+    entity_id: Uuid,
+    mq: impl MessageQueue,
+    reporter: &TaskReporter,
+    task_id: Uuid,
+) -> Result<()> {
     let config = bililive::ConfigBuilder::new()
         .fetch_conf()
         .await
@@ -120,11 +202,13 @@ async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Resu
     let mut stream = bililive::connect::tokio::connect_with_retry(config, RetryConfig::default())
         .await
         .wrap_err("Unable to connect to bilibili live server")?;
+    reporter.healthy(task_id).await;
 
     while let Some(msg) = stream.next().await {
         match msg {
             Ok(msg) => {
                 trace!(msg = ?msg, "Received message");
+                reporter.active(task_id);
                 if msg.json().ok()
                     == Some(Command {
                         cmd: String::from("LIVE"),
@@ -134,8 +218,10 @@ async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Resu
 
                     match LiveRoom::new(room_id).await {
                         Ok(room) => {
-                            let event = Event::from_serializable("bililive", entity_id, room)?;
-                            if let Err(error) = mq.publish(event, Middlewares::default()).await {
+                            let event = Event::from_payload(entity_id, &room.into_payload())?
+                                .with_ttl(LIVE_START_TTL);
+                            let middlewares = Middlewares::default().with_priority(Priority::High);
+                            if let Err(error) = mq.publish(event, middlewares).await {
                                 error!(?error, "Failed to publish bililive event");
                             };
                         }
@@ -153,3 +239,27 @@ async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Resu
 
     Ok(())
 }
+
+async fn bililive_backfill(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Result<()> {
+    let room_id = bililive::ConfigBuilder::new()
+        .fetch_conf()
+        .await
+        .wrap_err("Unable to fetch bilibili server config")?
+        .by_uid(uid)
+        .await
+        .wrap_err("Unable to fetch live room id by uid")?
+        .build()
+        .room_id();
+
+    let room = LiveRoom::new(room_id)
+        .await
+        .wrap_err("Unable to get live room")?;
+    if room.is_live() {
+        let event = Event::from_payload(entity_id, &room.into_payload())?.into_backfill();
+        mq.publish(event, Middlewares::default())
+            .await
+            .wrap_err("Failed to publish bililive event")?;
+    }
+
+    Ok(())
+}
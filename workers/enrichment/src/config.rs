@@ -0,0 +1,103 @@
+//! Enrichment worker config.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sg_core::utils::{Config, Redacted};
+use url::Url;
+
+/// Enrichment worker config.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Config)]
+pub struct Config {
+    /// Api url.
+    #[config(default_str = "http://127.0.0.1:8000/v1/")]
+    pub api_url: Url,
+    /// Api username.
+    pub api_username: String,
+    /// Api password.
+    pub api_password: Redacted<String>,
+    /// MongoDB connection string.
+    #[config(default_str = "mongodb://localhost:27017")]
+    pub mongo_uri: Redacted<String>,
+    /// MongoDB database name.
+    #[config(default_str = "stargazer-reborn")]
+    pub mongo_db: String,
+    /// MongoDB collection name for `Tasks`.
+    #[config(default_str = "tasks")]
+    pub mongo_collection: String,
+    /// Interval between refreshes of all profiles.
+    #[serde(with = "humantime_serde")]
+    #[config(default_str = "6h")]
+    pub refresh_interval: Duration,
+    /// Twitter API token. Twitter profiles aren't refreshed if not set.
+    #[config(default)]
+    pub twitter_token: Option<Redacted<String>>,
+    /// YouTube Data API key. YouTube profiles aren't refreshed if not set.
+    #[config(default)]
+    pub youtube_api_key: Option<Redacted<String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::result_large_err)]
+
+    use std::time::Duration;
+
+    use figment::Jail;
+    use sg_core::utils::{FigmentExt, Redacted};
+
+    use crate::config::Config;
+
+    #[test]
+    fn must_default() {
+        Jail::expect_with(|jail| {
+            jail.set_env("ENRICHMENT_API_USERNAME", "enrichment");
+            jail.set_env("ENRICHMENT_API_PASSWORD", "password");
+            assert_eq!(
+                Config::from_env("ENRICHMENT_").unwrap(),
+                Config {
+                    api_url: "http://127.0.0.1:8000/v1/".parse().unwrap(),
+                    api_username: String::from("enrichment"),
+                    api_password: Redacted(String::from("password")),
+                    mongo_uri: Redacted(String::from("mongodb://localhost:27017")),
+                    mongo_db: String::from("stargazer-reborn"),
+                    mongo_collection: String::from("tasks"),
+                    refresh_interval: Duration::from_secs(6 * 60 * 60),
+                    twitter_token: None,
+                    youtube_api_key: None,
+                }
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn must_from_env() {
+        Jail::expect_with(|jail| {
+            jail.set_env("ENRICHMENT_API_URL", "https://api.example.com/v1/");
+            jail.set_env("ENRICHMENT_API_USERNAME", "enrichment");
+            jail.set_env("ENRICHMENT_API_PASSWORD", "password");
+            jail.set_env("ENRICHMENT_MONGO_URI", "mongodb://suichan:27017");
+            jail.set_env("ENRICHMENT_MONGO_DB", "db");
+            jail.set_env("ENRICHMENT_MONGO_COLLECTION", "coll");
+            jail.set_env("ENRICHMENT_REFRESH_INTERVAL", "1h");
+            jail.set_env("ENRICHMENT_TWITTER_TOKEN", "blabla");
+            jail.set_env("ENRICHMENT_YOUTUBE_API_KEY", "key");
+            assert_eq!(
+                Config::from_env("ENRICHMENT_").unwrap(),
+                Config {
+                    api_url: "https://api.example.com/v1/".parse().unwrap(),
+                    api_username: String::from("enrichment"),
+                    api_password: Redacted(String::from("password")),
+                    mongo_uri: Redacted(String::from("mongodb://suichan:27017")),
+                    mongo_db: String::from("db"),
+                    mongo_collection: String::from("coll"),
+                    refresh_interval: Duration::from_secs(60 * 60),
+                    twitter_token: Some(Redacted(String::from("blabla"))),
+                    youtube_api_key: Some(Redacted(String::from("key"))),
+                }
+            );
+            Ok(())
+        });
+    }
+}
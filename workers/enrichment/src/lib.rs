@@ -0,0 +1,136 @@
+//! Worker keeping names and avatars of entities on their platforms up to
+//! date.
+//!
+//! Every `refresh_interval`, it fetches the profile of the account each task
+//! watches, and writes changed ones back with the `update_entity_meta` method
+//! of the api.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use api::{
+    client::{Client, LogInterceptor, RetryPolicy},
+    rpc::Page,
+};
+use eyre::{Result, WrapErr};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, Uuid},
+    Collection,
+};
+use sg_core::models::{Profile, Task};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::{config::Config, profile::Fetcher};
+
+pub mod config;
+pub mod profile;
+
+/// Number of entities queried at a time.
+const PAGE_SIZE: u32 = 100;
+
+/// Refresh profiles of entities forever.
+///
+/// # Errors
+/// Returns error if the api or the database is unreachable.
+pub async fn run(config: Config) -> Result<()> {
+    let client = Client::new(config.api_url.clone())
+        .wrap_err("Invalid api url")?
+        .with_retry(RetryPolicy::new(3))
+        .with_interceptor(LogInterceptor);
+    client
+        .login_and_store(&config.api_username, &*config.api_password)
+        .await
+        .wrap_err("Failed to login to api")?;
+
+    let tasks = mongodb::Client::with_uri_str(&*config.mongo_uri)
+        .await
+        .wrap_err_with(|| format!("Failed to connect to MongoDB at {}", config.mongo_uri))?
+        .database(&config.mongo_db)
+        .collection(&config.mongo_collection);
+    let fetcher = Fetcher::new(
+        config.twitter_token.map(|token| token.into_inner()),
+        config.youtube_api_key.map(|key| key.into_inner()),
+    );
+    info!(kinds = ?fetcher.kinds(), "Refreshing profiles");
+
+    let mut interval = interval(config.refresh_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match refresh(&client, &tasks, &fetcher).await {
+            Ok(updated) => info!(updated, "Refreshed profiles"),
+            Err(error) => error!(?error, "Failed to refresh profiles"),
+        }
+    }
+}
+
+/// Fetch profiles of all tasks, and update those changed. Only the first
+/// task of each kind counts for an entity. Return the count of updated
+/// profiles.
+async fn refresh(client: &Client, tasks: &Collection<Task>, fetcher: &Fetcher) -> Result<usize> {
+    let mut profiles = current_profiles(client).await?;
+
+    let mut cursor = tasks
+        .find(doc! { "kind": { "$in": fetcher.kinds() } }, None)
+        .await?;
+    let mut seen = HashSet::new();
+    let mut updated = 0;
+    while let Some(task) = cursor.try_next().await? {
+        if !seen.insert((task.entity, task.kind.clone())) {
+            continue;
+        }
+        // Tasks of deleted entities may linger for a moment.
+        let Some(current) = profiles.get_mut(&task.entity) else {
+            continue;
+        };
+        let profile = match fetcher.fetch(&task).await {
+            Ok(Some(profile)) => profile,
+            Ok(None) => continue,
+            Err(error) => {
+                warn!(task_id = %task.id, kind = %task.kind, ?error, "Failed to fetch profile");
+                continue;
+            }
+        };
+        if current.get(&task.kind) == Some(&profile) {
+            continue;
+        }
+
+        match client
+            .update_entity_meta(task.entity, task.kind.clone(), profile.clone())
+            .await
+        {
+            Ok(_) => {
+                info!(
+                    entity_id = %task.entity,
+                    kind = %task.kind,
+                    name = %profile.name,
+                    "Updated profile"
+                );
+                current.insert(task.kind, profile);
+                updated += 1;
+            }
+            Err(error) => warn!(entity_id = %task.entity, ?error, "Failed to update profile"),
+        }
+    }
+    Ok(updated)
+}
+
+/// Profiles of all entities, as stored in their meta.
+async fn current_profiles(client: &Client) -> Result<HashMap<Uuid, BTreeMap<String, Profile>>> {
+    let mut profiles = HashMap::new();
+    let mut page = Page::first(PAGE_SIZE);
+    loop {
+        let entities = client.get_entities(page.clone(), Page::first(1)).await?;
+        profiles.extend(
+            entities
+                .vtbs
+                .into_iter()
+                .map(|entity| (entity.id, entity.meta.profiles)),
+        );
+        match entities.next_vtbs {
+            Some(cursor) => page = page.next(cursor),
+            None => return Ok(profiles),
+        }
+    }
+}
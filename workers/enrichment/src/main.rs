@@ -0,0 +1,16 @@
+//! Enrichment worker binary.
+
+use enrichment::config::Config;
+use eyre::{Result, WrapErr};
+use sg_core::utils::{init_tracing, FigmentExt};
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _guard = init_tracing("enrichment", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("ENRICHMENT_").wrap_err("Failed to load config")?;
+
+    enrichment::run(config).await
+}
@@ -0,0 +1,255 @@
+//! Fetch profiles of accounts on platforms.
+
+use egg_mode::{user::UserID, Token};
+use eyre::{bail, eyre, Result, WrapErr};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use sg_core::models::{kind, Profile, Task};
+use url::Url;
+
+#[derive(Debug, Deserialize)]
+struct BilibiliResponse {
+    code: i64,
+    #[serde(default)]
+    message: String,
+    data: Option<BilibiliAccount>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BilibiliAccount {
+    name: String,
+    face: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct YoutubeChannels {
+    #[serde(default)]
+    items: Vec<YoutubeChannel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YoutubeChannel {
+    snippet: YoutubeSnippet,
+}
+
+#[derive(Debug, Deserialize)]
+struct YoutubeSnippet {
+    title: String,
+    #[serde(default)]
+    thumbnails: YoutubeThumbnails,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct YoutubeThumbnails {
+    default: Option<YoutubeThumbnail>,
+    medium: Option<YoutubeThumbnail>,
+    high: Option<YoutubeThumbnail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct YoutubeThumbnail {
+    url: String,
+}
+
+/// Fetcher of profiles on platforms that tasks watch.
+pub struct Fetcher {
+    http: Client,
+    twitter: Option<Token>,
+    youtube_api_key: Option<String>,
+}
+
+impl Fetcher {
+    /// Create a fetcher. Twitter and youtube profiles are only fetched if
+    /// given a token or an API key respectively.
+    #[must_use]
+    pub fn new(twitter_token: Option<String>, youtube_api_key: Option<String>) -> Self {
+        Self {
+            http: Client::new(),
+            twitter: twitter_token.map(Token::Bearer),
+            youtube_api_key,
+        }
+    }
+
+    /// Task kinds whose profiles can be fetched.
+    #[must_use]
+    pub fn kinds(&self) -> Vec<&'static str> {
+        let mut kinds = vec![kind::BILILIVE];
+        if self.twitter.is_some() {
+            kinds.push(kind::TWITTER);
+        }
+        if self.youtube_api_key.is_some() {
+            kinds.push(kind::YOUTUBE);
+        }
+        kinds
+    }
+
+    /// Fetch the profile of the account the task watches, or `None` if the
+    /// kind is not supported.
+    ///
+    /// # Errors
+    /// Returns an error if the task params are invalid, or the platform fails
+    /// to respond with the profile.
+    pub async fn fetch(&self, task: &Task) -> Result<Option<Profile>> {
+        match task.kind.as_str() {
+            kind::BILILIVE => {
+                let uid = task
+                    .params
+                    .get("uid")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| eyre!("`uid` must be an u64"))?;
+                self.bilibili(uid).await.map(Some)
+            }
+            kind::TWITTER => match &self.twitter {
+                Some(token) => {
+                    let id = match task.params.get("id") {
+                        Some(Value::Number(id)) if id.is_u64() => UserID::ID(id.as_u64().unwrap()),
+                        Some(Value::String(screen_name)) => UserID::from(screen_name.clone()),
+                        _ => bail!("`id` must be an u64 or a string"),
+                    };
+                    twitter(id, token).await.map(Some)
+                }
+                None => Ok(None),
+            },
+            kind::YOUTUBE => match &self.youtube_api_key {
+                Some(key) => {
+                    let channel_id = task
+                        .params
+                        .get("channel_id")
+                        .and_then(Value::as_str)
+                        .ok_or_else(|| eyre!("`channel_id` must be a string"))?;
+                    self.youtube(channel_id, key).await.map(Some)
+                }
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    async fn bilibili(&self, uid: u64) -> Result<Profile> {
+        let resp: BilibiliResponse = self
+            .http
+            .get("https://api.bilibili.com/x/space/acc/info")
+            .query(&[("mid", uid)])
+            .send()
+            .await?
+            .json()
+            .await
+            .wrap_err("Invalid response from bilibili")?;
+        bilibili_profile(resp)
+    }
+
+    async fn youtube(&self, channel_id: &str, key: &str) -> Result<Profile> {
+        let resp: YoutubeChannels = self
+            .http
+            .get("https://www.googleapis.com/youtube/v3/channels")
+            .query(&[("part", "snippet"), ("id", channel_id), ("key", key)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .wrap_err("Invalid response from youtube")?;
+        youtube_profile(resp)
+    }
+}
+
+async fn twitter(id: UserID, token: &Token) -> Result<Profile> {
+    let user = egg_mode::user::show(id, token).await?.response;
+    Ok(Profile {
+        name: user.name,
+        avatar: avatar(&user.profile_image_url_https),
+    })
+}
+
+fn bilibili_profile(resp: BilibiliResponse) -> Result<Profile> {
+    match resp {
+        BilibiliResponse {
+            code: 0,
+            data: Some(account),
+            ..
+        } => Ok(Profile {
+            name: account.name,
+            avatar: avatar(&account.face),
+        }),
+        BilibiliResponse { code, message, .. } => {
+            bail!("Bilibili responded with code {code}: {message}")
+        }
+    }
+}
+
+fn youtube_profile(resp: YoutubeChannels) -> Result<Profile> {
+    let Some(channel) = resp.items.into_iter().next() else {
+        bail!("Youtube channel not found");
+    };
+    let YoutubeThumbnails {
+        default,
+        medium,
+        high,
+    } = channel.snippet.thumbnails;
+    Ok(Profile {
+        name: channel.snippet.title,
+        avatar: high
+            .or(medium)
+            .or(default)
+            .and_then(|thumbnail| avatar(&thumbnail.url)),
+    })
+}
+
+/// Parse an avatar url, treating empty or invalid ones as no avatar.
+fn avatar(url: &str) -> Option<Url> {
+    Url::parse(url).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sg_core::models::Profile;
+
+    use crate::profile::{bilibili_profile, youtube_profile};
+
+    #[test]
+    fn must_parse_bilibili() {
+        let resp = json!({
+            "code": 0,
+            "message": "0",
+            "data": { "mid": 2, "name": "Suisei", "face": "https://i0.hdslb.com/suisei.jpg" },
+        });
+        assert_eq!(
+            bilibili_profile(serde_json::from_value(resp).unwrap()).unwrap(),
+            Profile {
+                name: "Suisei".to_owned(),
+                avatar: Some("https://i0.hdslb.com/suisei.jpg".parse().unwrap()),
+            }
+        );
+
+        let resp = json!({ "code": -404, "message": "啥都木有", "data": null });
+        assert!(bilibili_profile(serde_json::from_value(resp).unwrap()).is_err());
+    }
+
+    #[test]
+    fn must_parse_youtube() {
+        let resp = json!({
+            "items": [{
+                "id": "UC5CwaMl1eIgY8h02uZw7u8A",
+                "snippet": {
+                    "title": "Suisei Channel",
+                    "thumbnails": {
+                        "default": { "url": "https://yt3.ggpht.com/88" },
+                        "high": { "url": "https://yt3.ggpht.com/800" },
+                    },
+                },
+            }],
+        });
+        assert_eq!(
+            youtube_profile(serde_json::from_value(resp).unwrap()).unwrap(),
+            Profile {
+                name: "Suisei Channel".to_owned(),
+                avatar: Some("https://yt3.ggpht.com/800".parse().unwrap()),
+            }
+        );
+
+        let resp = json!({ "pageInfo": { "totalResults": 0 } });
+        assert!(youtube_profile(serde_json::from_value(resp).unwrap()).is_err());
+    }
+}
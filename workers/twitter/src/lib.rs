@@ -0,0 +1,90 @@
+//! Twitter worker.
+
+#![allow(clippy::module_name_repetitions)]
+#![deny(missing_docs)]
+
+use std::{iter, sync::Arc};
+
+use eyre::{Result, WrapErr};
+use sg_core::{
+    checkpoint::MongoTaskCheckpointStore,
+    lifecycle::Hooks,
+    models::kind,
+    mq::{MessageQueue, Offloading, RabbitMQ, Signing, Through},
+    protocol::{JoinOptions, WorkerRpcExt},
+    store::MongoBodyStore,
+    utils::Shutdown,
+};
+
+use crate::{config::Config, worker::TwitterWorker};
+
+pub mod config;
+pub mod quota;
+pub mod twitter;
+pub mod worker;
+
+/// Connect to AMQP and serve coordinators until shut down, failing over between
+/// them when disconnected.
+///
+/// Pending events are flushed by a hook added to `shutdown`.
+///
+/// # Errors
+/// Returns error if AMQP, the event body store or the checkpoint store is
+/// unreachable, the signing key is invalid or the worker fails.
+pub async fn run(config: Config, shutdown: Shutdown) -> Result<()> {
+    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
+        .await
+        .wrap_err("Failed to connect to AMQP")?
+        .with_encoding(config.amqp_encoding);
+    let mq: Arc<dyn MessageQueue> = match &config.signing_key {
+        Some(key) => Arc::new(Signing::new(
+            mq,
+            key.parse().wrap_err("Invalid signing key")?,
+        )),
+        None => Arc::new(mq),
+    };
+    let mq: Arc<dyn MessageQueue> = match &config.body_store_uri {
+        Some(uri) => Arc::new(Offloading::new(
+            mq,
+            Arc::new(MongoBodyStore::new(uri).await?),
+            config.offload_threshold,
+        )),
+        None => mq,
+    };
+    let mq: Arc<dyn MessageQueue> = match &config.entry_middleware {
+        Some(middleware) => Arc::new(Through::new(mq, middleware)),
+        None => mq,
+    };
+
+    let mut worker = TwitterWorker::new(config.clone(), mq);
+    if let Some(uri) = &config.checkpoint_store_uri {
+        let store = MongoTaskCheckpointStore::new(uri, config.checkpoint_ttl).await?;
+        worker = worker.with_checkpoints(Arc::new(store));
+    }
+    let reporter = worker.reporter();
+    let hooks = Hooks::new(worker.clone());
+    hooks.on_shutdown_of(&shutdown, "twitter: message queue");
+    let worker = worker.join_any(
+        iter::once(config.coordinator_url)
+            .chain(config.coordinator_fallback_urls)
+            .collect(),
+        config.id,
+        kind::TWITTER,
+        JoinOptions {
+            zone: config.zone,
+            labels: config.labels,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            reconnect_delay: Some(config.reconnect_delay),
+            reporter,
+            hooks,
+            ..JoinOptions::default()
+        },
+    );
+    shutdown
+        .until(worker)
+        .await
+        .transpose()
+        .wrap_err("Failed to start worker")?;
+
+    Ok(())
+}
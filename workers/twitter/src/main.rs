@@ -1,36 +1,20 @@
 //! Twitter worker binary.
 
-#![allow(clippy::module_name_repetitions)]
-#![deny(missing_docs)]
-
 use eyre::{Result, WrapErr};
-use sg_core::{mq::RabbitMQ, protocol::WorkerRpcExt, utils::FigmentExt};
+use sg_core::utils::{init_tracing, FigmentExt, Shutdown};
 use tracing_subscriber::EnvFilter;
-
-use crate::{config::Config, worker::TwitterWorker};
-
-pub mod config;
-pub mod twitter;
-pub mod worker;
+use twitter::config::Config;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-
-    let config =
-        Config::from_env("WORKER_").wrap_err("Failed to load config from environment variables")?;
-
-    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
-        .await
-        .wrap_err("Failed to connect to AMQP")?;
+    let _guard = init_tracing("twitter", EnvFilter::from_default_env())?;
 
-    TwitterWorker::new(config.clone(), mq)
-        .join(config.coordinator_url, config.id, "twitter")
-        .await
-        .wrap_err("Failed to start worker")?;
+    let config = Config::from_sources("WORKER_").wrap_err("Failed to load config")?;
 
-    Ok(())
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
+    let result = twitter::run(config, shutdown.clone()).await;
+    shutdown.run_hooks().await;
+    result
 }
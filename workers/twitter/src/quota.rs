@@ -0,0 +1,235 @@
+//! Token pool and rate limits of the Twitter API.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use egg_mode::{RateLimit, Token};
+use parking_lot::Mutex;
+
+/// Endpoint user timelines are fetched from.
+pub const USER_TIMELINE: &str = "statuses/user_timeline";
+
+/// Requests left to a token on an endpoint until its window resets, as told by
+/// the `X-Rate-Limit-*` headers of the last response.
+#[derive(Debug, Copy, Clone)]
+struct Budget {
+    remaining: u32,
+    reset: SystemTime,
+}
+
+impl Budget {
+    fn remaining(self, now: SystemTime) -> Option<u32> {
+        (self.reset > now).then_some(self.remaining)
+    }
+}
+
+/// Tokens requests are spread over, each with its own budget per endpoint.
+pub struct TokenPool {
+    tokens: Vec<Token>,
+    budgets: Mutex<HashMap<(&'static str, usize), Budget>>,
+}
+
+impl TokenPool {
+    /// Creates a pool of tokens.
+    ///
+    /// # Panics
+    /// Panics if there's no token.
+    #[must_use]
+    pub fn new(tokens: Vec<Token>) -> Self {
+        assert!(!tokens.is_empty(), "token pool must not be empty");
+        Self {
+            tokens,
+            budgets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take the token with the most requests left on `endpoint`, counting one
+    /// request against it.
+    ///
+    /// # Errors
+    /// Returns how long to wait until a budget resets if all tokens are
+    /// exhausted.
+    pub fn acquire(
+        &self,
+        endpoint: &'static str,
+        now: SystemTime,
+    ) -> Result<(usize, Token), Duration> {
+        let mut budgets = self.budgets.lock();
+        let index = (0..self.tokens.len())
+            .max_by_key(|&index| {
+                // Tokens not used on the endpoint yet, or past their window, have
+                // their whole budget.
+                budgets
+                    .get(&(endpoint, index))
+                    .and_then(|budget| budget.remaining(now))
+                    .map_or(u64::MAX, u64::from)
+            })
+            .unwrap_or_default();
+
+        let remaining = budgets
+            .get(&(endpoint, index))
+            .and_then(|budget| budget.remaining(now));
+        if remaining == Some(0) {
+            let reset = budgets
+                .iter()
+                .filter(|((e, _), _)| *e == endpoint)
+                .map(|(_, budget)| budget.reset)
+                .min()
+                .unwrap_or(now);
+            return Err(reset.duration_since(now).unwrap_or_default());
+        }
+        if let Some(budget) = budgets.get_mut(&(endpoint, index)) {
+            budget.remaining = budget.remaining.saturating_sub(1);
+        }
+        Ok((index, self.tokens[index].clone()))
+    }
+
+    /// Record the rate limit status of a response to a request made with the
+    /// token at `index`.
+    pub fn record(&self, endpoint: &'static str, index: usize, status: &RateLimit) {
+        self.budgets.lock().insert(
+            (endpoint, index),
+            Budget {
+                remaining: u32::try_from(status.remaining).unwrap_or_default(),
+                reset: unix_time(status.reset),
+            },
+        );
+    }
+
+    /// Mark the token at `index` exhausted on `endpoint` until `reset`, after
+    /// a request is rejected for its rate limit.
+    pub fn exhaust(&self, endpoint: &'static str, index: usize, reset: i32) {
+        self.budgets.lock().insert(
+            (endpoint, index),
+            Budget {
+                remaining: 0,
+                reset: unix_time(reset),
+            },
+        );
+    }
+
+    /// Delay between polls of each of `tasks` tasks sharing `endpoint`, so
+    /// that the pool lasts until budgets reset.
+    ///
+    /// It's `interval` while budgets allow, and stretched as they deplete. Only
+    /// known budgets are accounted for, so the delay is `interval` until each
+    /// token has been used on the endpoint.
+    #[must_use]
+    pub fn delay(
+        &self,
+        endpoint: &'static str,
+        interval: Duration,
+        tasks: usize,
+        now: SystemTime,
+    ) -> Duration {
+        let budgets = self.budgets.lock();
+        let mut rate = 0.;
+        let mut reset = None;
+        for index in 0..self.tokens.len() {
+            let Some(budget) = budgets.get(&(endpoint, index)) else {
+                return interval;
+            };
+            let Ok(window) = budget.reset.duration_since(now) else {
+                return interval;
+            };
+            rate += f64::from(budget.remaining) / window.as_secs_f64().max(1.);
+            reset = Some(reset.map_or(window, |reset: Duration| reset.min(window)));
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let delay = if rate > 0. {
+            Duration::from_secs_f64(tasks as f64 / rate)
+        } else {
+            reset.unwrap_or_default()
+        };
+        delay.max(interval)
+    }
+}
+
+fn unix_time(secs: i32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use egg_mode::{RateLimit, Token};
+
+    use crate::quota::{TokenPool, USER_TIMELINE};
+
+    fn pool() -> TokenPool {
+        TokenPool::new(vec![
+            Token::Bearer(String::from("a")),
+            Token::Bearer(String::from("b")),
+        ])
+    }
+
+    #[test]
+    fn must_rotate_tokens() {
+        let pool = pool();
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let status = |remaining| RateLimit {
+            limit: 900,
+            remaining,
+            reset: 1900,
+        };
+
+        pool.record(USER_TIMELINE, 0, &status(10));
+        assert_eq!(pool.acquire(USER_TIMELINE, now).unwrap().0, 1);
+        pool.record(USER_TIMELINE, 1, &status(1));
+        assert_eq!(pool.acquire(USER_TIMELINE, now).unwrap().0, 0);
+
+        // Exhausted tokens are skipped until their window resets.
+        pool.exhaust(USER_TIMELINE, 0, 1300);
+        assert_eq!(pool.acquire(USER_TIMELINE, now).unwrap().0, 1);
+        assert_eq!(
+            pool.acquire(USER_TIMELINE, now).unwrap_err(),
+            Duration::from_secs(300)
+        );
+        let later = UNIX_EPOCH + Duration::from_secs(1300);
+        assert_eq!(pool.acquire(USER_TIMELINE, later).unwrap().0, 0);
+
+        // Budgets are per endpoint.
+        assert!(pool.acquire("users/show", now).is_ok());
+    }
+
+    #[test]
+    fn must_slow_down_as_budgets_deplete() {
+        let pool = pool();
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let interval = Duration::from_secs(60);
+        let status = |remaining| RateLimit {
+            limit: 900,
+            remaining,
+            reset: 1900,
+        };
+
+        // Unknown budgets don't slow polling.
+        pool.record(USER_TIMELINE, 0, &status(90));
+        assert_eq!(pool.delay(USER_TIMELINE, interval, 100, now), interval);
+
+        // 180 requests in 900 seconds for 10 tasks.
+        pool.record(USER_TIMELINE, 1, &status(90));
+        assert_eq!(pool.delay(USER_TIMELINE, interval, 10, now), interval);
+        // And for 100 tasks.
+        assert_eq!(
+            pool.delay(USER_TIMELINE, interval, 100, now),
+            Duration::from_secs(500)
+        );
+
+        // Wait for the window to reset once exhausted.
+        pool.exhaust(USER_TIMELINE, 0, 1900);
+        pool.exhaust(USER_TIMELINE, 1, 1600);
+        assert_eq!(
+            pool.delay(USER_TIMELINE, interval, 10, now),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            pool.delay(USER_TIMELINE, interval, 10, SystemTime::now()),
+            interval
+        );
+    }
+}
@@ -1,35 +1,55 @@
 //! Worker implementation.
 
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    iter,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use egg_mode::{tweet::user_timeline, user::UserID, Token};
+use egg_mode::{
+    error::Error,
+    tweet::{user_timeline, Tweet as RawTweet},
+    user::UserID,
+    Token,
+};
 use eyre::Result;
-use futures_util::StreamExt;
 use parking_lot::Mutex;
-use serde_json::Value;
+use serde_json::{json, Value};
 use sg_core::{
-    models::{Event, Task},
-    mq::MessageQueue,
-    protocol::WorkerRpc,
+    async_trait::async_trait,
+    checkpoint::TaskCheckpointStore,
+    error::{Categorized, Error as CoreError},
+    lifecycle::WorkerHooks,
+    models::Task,
+    mq::{MessageQueue, Middlewares, Priority},
+    protocol::{add_each, remove_each, TaskLiveness, TaskReporter, WorkerRpc},
     utils::ScopedJoinHandle,
 };
 use tap::TapOptional;
 use tarpc::context::Context;
-use tokio::time::{interval, sleep};
-use tracing::{error, info};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use crate::{
-    twitter::{TimelineStream, Tweet},
+    quota::{TokenPool, USER_TIMELINE},
+    twitter::tweet_event,
     Config,
 };
 
+/// Field of checkpoints holding the id of the newest tweet seen.
+const SINCE_ID: &str = "since_id";
+
 /// Twitter worker.
 #[derive(Clone)]
 pub struct TwitterWorker {
-    token: Arc<Token>,
+    pool: Arc<TokenPool>,
     mq: Arc<dyn MessageQueue>,
     interval: Duration,
+    backfill_limit: usize,
+    reporter: TaskReporter,
+    checkpoints: Option<Arc<dyn TaskCheckpointStore>>,
 
     #[allow(clippy::type_complexity)]
     tasks: Arc<Mutex<HashMap<Uuid, (Task, ScopedJoinHandle<()>)>>>,
@@ -40,18 +60,49 @@ impl TwitterWorker {
     #[must_use]
     pub fn new(config: Config, mq: impl MessageQueue + 'static) -> Self {
         Self {
-            token: Arc::new(Token::Bearer(config.twitter_token)),
+            pool: Arc::new(TokenPool::new(
+                iter::once(config.twitter_token)
+                    .chain(config.twitter_tokens)
+                    .map(|token| Token::Bearer(token.into_inner()))
+                    .collect(),
+            )),
             mq: Arc::new(mq),
             interval: config.poll_interval,
+            backfill_limit: config.backfill_limit,
+            reporter: TaskReporter::new(),
+            checkpoints: None,
             tasks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    /// Save the newest tweet seen of each task to `checkpoints`, and resume
+    /// tasks from there, e.g. after a restart.
+    #[must_use]
+    pub fn with_checkpoints(mut self, checkpoints: Arc<dyn TaskCheckpointStore>) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// Reporter of task status, to join coordinators with.
+    #[must_use]
+    pub fn reporter(&self) -> TaskReporter {
+        self.reporter.clone()
+    }
+}
+
+#[async_trait]
+impl WorkerHooks for TwitterWorker {
+    async fn on_shutdown(&self) -> Result<()> {
+        // Flush events pending in the message queue.
+        self.mq.close().await
+    }
 }
 
 #[tarpc::server]
 impl WorkerRpc for TwitterWorker {
-    async fn ping(self, _: Context, id: u64) -> u64 {
-        id
+    async fn heartbeat(self, _: Context) -> Vec<TaskLiveness> {
+        let running: Vec<Uuid> = self.tasks.lock().keys().copied().collect();
+        self.reporter.liveness(running)
     }
 
     async fn add_task(self, _: Context, task: Task) -> bool {
@@ -64,36 +115,37 @@ impl WorkerRpc for TwitterWorker {
         info!(task_id = ?task.id, "Adding task");
 
         // Extract the twitter id from the task.
-        let id = match task.params.get("id") {
-            Some(Value::Number(id)) if id.is_u64() => UserID::ID(id.as_u64().unwrap()),
-            Some(Value::String(screen_name)) => UserID::from(screen_name.to_string()),
-            Some(_) => {
-                error!("ID field: type mismatch. Expected: u64 or String");
-                return false;
-            }
-            None => {
-                error!("ID field: missing");
-                return false;
-            }
+        let Some(id) = user_id(&task) else {
+            return false;
         };
 
         // Prepare the worker future.
-        let token = self.token.clone();
         let poll_interval = self.interval;
+        let task_id = task.id.into();
 
         let fut = async move {
             loop {
                 info!(user_id=?id, "Spawning twitter task");
                 if let Err(error) = twitter_task(
                     id.clone(),
-                    &token,
+                    &self.pool,
                     task.entity.into(),
                     &*self.mq,
                     poll_interval,
+                    || self.tasks.lock().len(),
+                    &self.reporter,
+                    self.checkpoints.as_deref(),
+                    task_id,
                 )
                 .await
                 {
                     error!(?error, "Failed to fetch timeline");
+                    self.reporter.failing(task_id, format!("{error:#}")).await;
+                    if !error.is_retryable() {
+                        // It stays failing until the task is added again.
+                        warn!(%task_id, category = %error.category(), "Giving up task");
+                        break;
+                    }
 
                     // Sleep to avoid looping if the task always fails.
                     sleep(poll_interval).await;
@@ -115,6 +167,14 @@ impl WorkerRpc for TwitterWorker {
             .is_some()
     }
 
+    async fn add_tasks(self, ctx: Context, tasks: Vec<Task>) -> Vec<bool> {
+        add_each(self, ctx, tasks).await
+    }
+
+    async fn remove_tasks(self, ctx: Context, ids: Vec<Uuid>) -> Vec<bool> {
+        remove_each(self, ctx, ids).await
+    }
+
     async fn tasks(self, _: Context) -> Vec<Task> {
         self.tasks
             .lock()
@@ -123,36 +183,221 @@ impl WorkerRpc for TwitterWorker {
             .cloned()
             .collect()
     }
+
+    async fn backfill(self, _: Context, task: Task, since: SystemTime) -> bool {
+        let Some(id) = user_id(&task) else {
+            return false;
+        };
+
+        info!(task_id = ?task.id, "Backfilling task");
+
+        tokio::spawn(async move {
+            if let Err(error) = twitter_backfill(
+                id,
+                &self.pool,
+                task.entity.into(),
+                &*self.mq,
+                since,
+                self.backfill_limit,
+            )
+            .await
+            {
+                error!(?error, "Failed to backfill timeline");
+            }
+        });
+
+        true
+    }
+}
+
+// Extract the twitter id from the task.
+fn user_id(task: &Task) -> Option<UserID> {
+    match task.params.get("id") {
+        Some(Value::Number(id)) if id.is_u64() => Some(UserID::ID(id.as_u64().unwrap())),
+        Some(Value::String(screen_name)) => Some(UserID::from(screen_name.to_string())),
+        Some(_) => {
+            error!("ID field: type mismatch. Expected: u64 or String");
+            None
+        }
+        None => {
+            error!("ID field: missing");
+            None
+        }
+    }
+}
+
+// Tweets are translated before delivery, and may wait behind time-sensitive
+// events.
+fn tweet_middlewares() -> Middlewares {
+    "translate"
+        .parse::<Middlewares>()
+        .unwrap()
+        .with_priority(Priority::Low)
+}
+
+// Fetch tweets of the given user, newer than `since_id` if given, with the
+// token of the pool having the most requests left.
+async fn fetch_timeline(
+    pool: &TokenPool,
+    user_id: &UserID,
+    since_id: Option<u64>,
+    page_size: i32,
+) -> Result<Vec<RawTweet>> {
+    loop {
+        let (index, token) = match pool.acquire(USER_TIMELINE, SystemTime::now()) {
+            Ok(lease) => lease,
+            Err(wait) => {
+                warn!(?wait, "All twitter tokens are rate limited");
+                sleep(wait).await;
+                continue;
+            }
+        };
+        let timeline =
+            user_timeline(user_id.clone(), false, true, &token).with_page_size(page_size);
+        match timeline.call(since_id, None).await {
+            Ok(resp) => {
+                pool.record(USER_TIMELINE, index, &resp.rate_limit_status);
+                return Ok(resp.response);
+            }
+            // Try another token.
+            Err(Error::RateLimit(reset)) => pool.exhaust(USER_TIMELINE, index, reset),
+            Err(error) => return Err(categorized(error).into()),
+        }
+    }
 }
 
-// Fetch the timeline for the given user and send the tweets to the message
+// Categorize a failed request. Requests for missing or suspended users fail
+// the same way on every poll, while other errors, e.g. of a revoked token in
+// the pool, may pass with another token later.
+fn categorized(error: Error) -> CoreError {
+    let missing = match &error {
+        Error::BadStatus(status) => status.as_u16() == 404,
+        Error::TwitterError(_, errors) => errors
+            .errors
+            .iter()
+            .any(|error| matches!(error.code, 34 | 50 | 63)),
+        _ => false,
+    };
+    if missing {
+        CoreError::permanent(error)
+    } else {
+        CoreError::transient(error)
+    }
+}
+
+// Poll the timeline for the given user and send new tweets to the message
 // queue.
+//
+// Given a checkpoint store, tweets since the newest one seen before a restart
+// are published too.
+//
+// Polls are spread to keep the token pool from running out before its budgets
+// reset, which may stretch the poll interval.
+#[allow(clippy::too_many_arguments)]
 async fn twitter_task(
     user_id: UserID,
-    token: &Token,
+    pool: &TokenPool,
     entity_id: Uuid,
     mq: impl MessageQueue,
     poll_interval: Duration,
+    running: impl Fn() -> usize,
+    reporter: &TaskReporter,
+    checkpoints: Option<&dyn TaskCheckpointStore>,
+    task_id: Uuid,
 ) -> Result<()> {
-    let mut ticker = interval(poll_interval);
-
-    // Construct a stream of tweets.
-    let mut stream = TimelineStream::new(user_timeline(user_id, false, true, token)).await?;
-    while let Some(resp) = stream.next().await {
-        // Parse income tweets.
-        for raw_tweet in resp?.response {
-            let tweet_id = raw_tweet.id;
-            let tweet = Tweet::from(raw_tweet);
-            let event = Event::from_serializable("twitter", entity_id, tweet)?;
-
-            // Send tweet to message queue.
-            if let Err(error) = mq.publish(event, "translate".parse().unwrap()).await {
-                error!(?error, %tweet_id, "Failed to publish tweet");
+    // Tweets up to the newest one seen are published, or predate the task.
+    let mut since_id = match checkpoints {
+        Some(checkpoints) => load_since_id(checkpoints, task_id).await,
+        None => None,
+    };
+    let mut lagging = false;
+    loop {
+        // Twitter's default page size.
+        let tweets = fetch_timeline(pool, &user_id, since_id, 20).await?;
+        reporter.healthy(task_id).await;
+
+        let newest = tweets.iter().map(|raw_tweet| raw_tweet.id).max();
+        if since_id.is_some() {
+            // Publish from the oldest to the newest.
+            for raw_tweet in tweets.into_iter().rev() {
+                let tweet_id = raw_tweet.id;
+                let event = tweet_event(entity_id, raw_tweet)?;
+
+                // Send tweet to message queue.
+                if let Err(error) = mq.publish(event, tweet_middlewares()).await {
+                    error!(?error, %tweet_id, "Failed to publish tweet");
+                }
+            }
+        }
+        if newest > since_id {
+            since_id = newest;
+            if let Some(checkpoints) = checkpoints {
+                let checkpoint = json!({ SINCE_ID: since_id });
+                if let Err(error) = checkpoints.set(&task_id.into(), &checkpoint).await {
+                    warn!(?error, %task_id, "Failed to save checkpoint");
+                }
             }
         }
 
-        // Tick.
-        ticker.tick().await;
+        let delay = pool.delay(USER_TIMELINE, poll_interval, running(), SystemTime::now());
+        if (delay > poll_interval) != lagging {
+            lagging = delay > poll_interval;
+            if lagging {
+                warn!(
+                    %task_id,
+                    ?poll_interval,
+                    ?delay,
+                    "Rate limits keep task from its poll interval"
+                );
+            } else {
+                info!(%task_id, "Task back to its poll interval");
+            }
+        }
+        sleep(delay).await;
+    }
+}
+
+// Newest tweet seen of the task before a restart. Tasks without one start from
+// the newest tweet, as if they were new.
+async fn load_since_id(checkpoints: &dyn TaskCheckpointStore, task_id: Uuid) -> Option<u64> {
+    match checkpoints.get(&task_id.into()).await {
+        Ok(checkpoint) => checkpoint?.get(SINCE_ID)?.as_u64(),
+        Err(error) => {
+            warn!(?error, %task_id, "Failed to load checkpoint");
+            None
+        }
+    }
+}
+
+// Fetch recent tweets of the given user since given time and send them to the
+// message queue as backfill.
+async fn twitter_backfill(
+    user_id: UserID,
+    pool: &TokenPool,
+    entity_id: Uuid,
+    mq: impl MessageQueue,
+    since: SystemTime,
+    limit: usize,
+) -> Result<()> {
+    let since = since.duration_since(UNIX_EPOCH).map_or(0, |since| {
+        i64::try_from(since.as_secs()).unwrap_or(i64::MAX)
+    });
+    let page_size = i32::try_from(limit).unwrap_or(i32::MAX);
+
+    let tweets = fetch_timeline(pool, &user_id, None, page_size).await?;
+
+    // Publish from the oldest to the newest.
+    for raw_tweet in tweets
+        .into_iter()
+        .rev()
+        .filter(|raw_tweet| raw_tweet.created_at.timestamp() >= since)
+    {
+        let tweet_id = raw_tweet.id;
+        let event = tweet_event(entity_id, raw_tweet)?.into_backfill();
+
+        if let Err(error) = mq.publish(event, tweet_middlewares()).await {
+            error!(?error, %tweet_id, "Failed to publish tweet");
+        }
     }
 
     Ok(())
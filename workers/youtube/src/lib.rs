@@ -0,0 +1,87 @@
+//! Youtube worker.
+//!
+//! It polls recent uploads of channels for upcoming live streams and
+//! premieres, publishes an event when one is scheduled, and schedules a
+//! reminder 30 minutes before it starts through the delay middleware.
+
+#![allow(clippy::module_name_repetitions)]
+
+use std::{iter, sync::Arc};
+
+use eyre::{Result, WrapErr};
+use sg_core::{
+    lifecycle::Hooks,
+    models::kind,
+    mq::{MessageQueue, Offloading, RabbitMQ, Signing, Through},
+    protocol::{JoinOptions, WorkerRpcExt},
+    store::MongoBodyStore,
+    utils::Shutdown,
+};
+
+use crate::{config::Config, worker::YoutubeWorker};
+
+pub mod config;
+mod worker;
+mod youtube;
+
+/// Connect to AMQP and serve coordinators until shut down, failing over between
+/// them when disconnected.
+///
+/// Pending events are flushed by a hook added to `shutdown`.
+///
+/// # Errors
+/// Returns error if AMQP or the event body store is unreachable, the signing
+/// key is invalid or the worker fails.
+pub async fn run(config: Config, shutdown: Shutdown) -> Result<()> {
+    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
+        .await
+        .wrap_err("Failed to connect to AMQP")?
+        .with_encoding(config.amqp_encoding);
+    let mq: Arc<dyn MessageQueue> = match &config.signing_key {
+        Some(key) => Arc::new(Signing::new(
+            mq,
+            key.parse().wrap_err("Invalid signing key")?,
+        )),
+        None => Arc::new(mq),
+    };
+    let mq: Arc<dyn MessageQueue> = match &config.body_store_uri {
+        Some(uri) => Arc::new(Offloading::new(
+            mq,
+            Arc::new(MongoBodyStore::new(uri).await?),
+            config.offload_threshold,
+        )),
+        None => mq,
+    };
+    let mq: Arc<dyn MessageQueue> = match &config.entry_middleware {
+        Some(middleware) => Arc::new(Through::new(mq, middleware)),
+        None => mq,
+    };
+
+    let worker = YoutubeWorker::new(&config, mq);
+    let reporter = worker.reporter();
+    let hooks = Hooks::new(worker.clone());
+    hooks.on_shutdown_of(&shutdown, "youtube: message queue");
+    let worker = worker.join_any(
+        iter::once(config.coordinator_url)
+            .chain(config.coordinator_fallback_urls)
+            .collect(),
+        config.id,
+        kind::YOUTUBE,
+        JoinOptions {
+            zone: config.zone,
+            labels: config.labels,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            reconnect_delay: Some(config.reconnect_delay),
+            reporter,
+            hooks,
+            ..JoinOptions::default()
+        },
+    );
+    shutdown
+        .until(worker)
+        .await
+        .transpose()
+        .wrap_err("Failed to start worker")?;
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use eyre::{Result, WrapErr};
+use sg_core::utils::{init_tracing, FigmentExt, Shutdown};
+use tracing_subscriber::EnvFilter;
+use youtube::config::Config;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let _guard = init_tracing("youtube", EnvFilter::from_default_env())?;
+
+    let config = Config::from_sources("WORKER_").wrap_err("Failed to load config")?;
+
+    let shutdown = Shutdown::new();
+    let _signals = shutdown.listen_signals();
+    let result = youtube::run(config, shutdown.clone()).await;
+    shutdown.run_hooks().await;
+    result
+}
@@ -0,0 +1,418 @@
+//! Worker implementation.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::Result;
+use parking_lot::Mutex;
+use sg_core::{
+    async_trait::async_trait,
+    error::Categorized,
+    lifecycle::WorkerHooks,
+    models::{
+        BroadcastReminderPayload,
+        BroadcastStartPayload,
+        Event,
+        MembersBroadcastStartPayload,
+        PremiereStartPayload,
+        Task,
+    },
+    mq::{MessageQueue, Middlewares, Priority},
+    protocol::{add_each, remove_each, TaskLiveness, TaskReporter, WorkerRpc},
+    utils::{stable_hash, ScopedJoinHandle},
+};
+use tap::TapOptional;
+use tarpc::context::Context;
+use tokio::time::{interval, sleep};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    youtube::{Broadcast, Youtube},
+    Config,
+};
+
+/// Time before the start of a broadcast to remind of it, as in
+/// [`kind::YOUTUBE_BROADCAST_REMINDER`](sg_core::models::kind::YOUTUBE_BROADCAST_REMINDER).
+const REMIND_BEFORE: Duration = Duration::from_secs(30 * 60);
+
+/// Youtube worker.
+#[derive(Clone)]
+pub struct YoutubeWorker {
+    youtube: Arc<Youtube>,
+    mq: Arc<dyn MessageQueue>,
+    interval: Duration,
+    reporter: TaskReporter,
+
+    #[allow(clippy::type_complexity)]
+    tasks: Arc<Mutex<HashMap<Uuid, (Task, ScopedJoinHandle<()>)>>>,
+}
+
+impl YoutubeWorker {
+    /// Creates a new worker.
+    #[must_use]
+    pub fn new(config: &Config, mq: impl MessageQueue + 'static) -> Self {
+        Self {
+            youtube: Arc::new(Youtube::new(config.youtube_api_key.clone().into_inner())),
+            mq: Arc::new(mq),
+            interval: config.poll_interval,
+            reporter: TaskReporter::new(),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reporter of task status, to join coordinators with.
+    #[must_use]
+    pub fn reporter(&self) -> TaskReporter {
+        self.reporter.clone()
+    }
+}
+
+#[async_trait]
+impl WorkerHooks for YoutubeWorker {
+    async fn on_shutdown(&self) -> Result<()> {
+        // Flush events pending in the message queue.
+        self.mq.close().await
+    }
+}
+
+#[tarpc::server]
+impl WorkerRpc for YoutubeWorker {
+    async fn heartbeat(self, _: Context) -> Vec<TaskLiveness> {
+        let running: Vec<Uuid> = self.tasks.lock().keys().copied().collect();
+        self.reporter.liveness(running)
+    }
+
+    async fn add_task(self, _: Context, task: Task) -> bool {
+        let mut tasks = self.tasks.lock();
+        if tasks.contains_key(&task.id.into()) {
+            // If the task is already running, do nothing.
+            return false;
+        }
+
+        info!(task_id = ?task.id, "Adding task");
+
+        // Extract the channel id from the task.
+        let Some(channel_id) = channel_id(&task) else {
+            return false;
+        };
+
+        let poll_interval = self.interval;
+        let task_id = task.id.into();
+        let fut = async move {
+            loop {
+                info!(%channel_id, "Spawning youtube task");
+                if let Err(error) = youtube_task(
+                    &channel_id,
+                    &self.youtube,
+                    task.entity.into(),
+                    &*self.mq,
+                    poll_interval,
+                    &self.reporter,
+                    task_id,
+                )
+                .await
+                {
+                    error!(?error, "Failed to fetch upcoming broadcasts");
+                    self.reporter.failing(task_id, format!("{error:#}")).await;
+                    if !error.is_retryable() {
+                        // It stays failing until the task is added again.
+                        warn!(%task_id, category = %error.category(), "Giving up task");
+                        break;
+                    }
+
+                    // Sleep to avoid looping if the task always fails.
+                    sleep(poll_interval).await;
+                }
+            }
+        };
+
+        // Spawn the worker and insert it into the tasks map.
+        tasks.insert(task.id.into(), (task, ScopedJoinHandle(tokio::spawn(fut))));
+
+        true
+    }
+
+    async fn remove_task(self, _: Context, id: Uuid) -> bool {
+        self.tasks
+            .lock()
+            .remove(&id)
+            .tap_some(|_| info!(task_id=?id, "Removing task"))
+            .is_some()
+    }
+
+    async fn add_tasks(self, ctx: Context, tasks: Vec<Task>) -> Vec<bool> {
+        add_each(self, ctx, tasks).await
+    }
+
+    async fn remove_tasks(self, ctx: Context, ids: Vec<Uuid>) -> Vec<bool> {
+        remove_each(self, ctx, ids).await
+    }
+
+    async fn tasks(self, _: Context) -> Vec<Task> {
+        self.tasks
+            .lock()
+            .values()
+            .map(|(task, _)| task)
+            .cloned()
+            .collect()
+    }
+
+    async fn backfill(self, _: Context, task: Task, _: SystemTime) -> bool {
+        let Some(channel_id) = channel_id(&task) else {
+            return false;
+        };
+
+        info!(task_id = ?task.id, "Backfilling task");
+
+        // Broadcasts have no history. Publish the upcoming ones, whose
+        // reminders are scheduled by the task itself.
+        tokio::spawn(async move {
+            if let Err(error) =
+                youtube_backfill(&channel_id, &self.youtube, task.entity.into(), &*self.mq).await
+            {
+                error!(?error, "Failed to backfill youtube task");
+            }
+        });
+
+        true
+    }
+}
+
+// Extract the channel id from the task.
+fn channel_id(task: &Task) -> Option<String> {
+    match task.params.get("channel_id") {
+        Some(v) => {
+            let channel_id = v.as_str().map(ToString::to_string);
+            if channel_id.is_none() {
+                error!("Channel ID field: type mismatch. Expected: String");
+            }
+            channel_id
+        }
+        None => {
+            error!("Channel ID field: missing");
+            None
+        }
+    }
+}
+
+// Poll upcoming and live broadcasts of the given channel, publish newly
+// scheduled and started ones, and keep reminders in the delay middleware up to
+// date.
+async fn youtube_task(
+    channel_id: &str,
+    youtube: &Youtube,
+    entity_id: Uuid,
+    mq: impl MessageQueue,
+    poll_interval: Duration,
+    reporter: &TaskReporter,
+    task_id: Uuid,
+) -> Result<()> {
+    let mut ticker = interval(poll_interval);
+
+    // Upcoming broadcasts by video id, as of the last poll. Those found in the
+    // first poll were published before the task (re)started, or are left to
+    // backfill, so only their reminders are scheduled again.
+    let mut known: HashMap<String, Broadcast> = HashMap::new();
+    // Ids of broadcasts live as of the last poll, whose start was published
+    // already, or missed if they were live in the first poll.
+    let mut started: HashSet<String> = HashSet::new();
+    let mut first = true;
+    loop {
+        ticker.tick().await;
+        let broadcasts = youtube.broadcasts(channel_id).await?;
+        reporter.healthy(task_id).await;
+
+        let now = SystemTime::now();
+        let mut upcoming = HashMap::new();
+        let mut live = HashSet::new();
+        for broadcast in broadcasts {
+            if broadcast.live {
+                if !first && !started.contains(&broadcast.video_id) {
+                    info!(video_id = %broadcast.video_id, "Broadcast started");
+                    publish_started(&mq, entity_id, &broadcast).await;
+                }
+                live.insert(broadcast.video_id);
+                continue;
+            }
+
+            let previous = known.remove(&broadcast.video_id);
+            if previous.as_ref().map(|previous| previous.start_at) != Some(broadcast.start_at) {
+                if !first {
+                    info!(video_id = %broadcast.video_id, "Broadcast scheduled");
+                    publish_scheduled(&mq, entity_id, &broadcast, false).await;
+                }
+                remind(&mq, entity_id, &broadcast, now, previous.is_some()).await;
+            }
+            upcoming.insert(broadcast.video_id.clone(), broadcast);
+        }
+
+        // Broadcasts no longer upcoming either started or were deleted. Cancel
+        // reminders of deleted ones.
+        for broadcast in known.into_values() {
+            if remind_at(&broadcast) > now {
+                cancel_reminder(&mq, entity_id, &broadcast).await;
+            }
+        }
+
+        known = upcoming;
+        started = live;
+        first = false;
+    }
+}
+
+// Publish upcoming broadcasts of the given channel as backfill.
+async fn youtube_backfill(
+    channel_id: &str,
+    youtube: &Youtube,
+    entity_id: Uuid,
+    mq: impl MessageQueue,
+) -> Result<()> {
+    for broadcast in youtube.broadcasts(channel_id).await? {
+        if !broadcast.live {
+            publish_scheduled(&mq, entity_id, &broadcast, true).await;
+        }
+    }
+    Ok(())
+}
+
+async fn publish_scheduled(
+    mq: &impl MessageQueue,
+    entity_id: Uuid,
+    broadcast: &Broadcast,
+    backfill: bool,
+) {
+    let video_id = &broadcast.video_id;
+    let event = match Event::from_payload(entity_id, &broadcast.payload) {
+        Ok(event) if backfill => event.into_backfill(),
+        Ok(event) => event,
+        Err(error) => {
+            error!(?error, %video_id, "Failed to build broadcast event");
+            return;
+        }
+    };
+    if let Err(error) = mq.publish(event, Middlewares::default()).await {
+        error!(?error, %video_id, "Failed to publish broadcast event");
+    }
+}
+
+// Publish the start of a broadcast, as a distinct kind for members-only streams
+// and premieres so that users can filter them separately.
+async fn publish_started(mq: &impl MessageQueue, entity_id: Uuid, broadcast: &Broadcast) {
+    let video_id = &broadcast.video_id;
+    let payload = broadcast.payload.clone();
+    let event = if broadcast.members_only {
+        Event::from_payload(entity_id, &MembersBroadcastStartPayload(payload))
+    } else if broadcast.premiere {
+        Event::from_payload(entity_id, &PremiereStartPayload(payload))
+    } else {
+        Event::from_payload(entity_id, &BroadcastStartPayload(payload))
+    };
+    let event = match event {
+        Ok(event) => event,
+        Err(error) => {
+            error!(?error, %video_id, "Failed to build start event");
+            return;
+        }
+    };
+    if let Err(error) = mq.publish(event, Middlewares::default()).await {
+        error!(?error, %video_id, "Failed to publish start event");
+    }
+}
+
+// Schedule the reminder of a broadcast in the delay middleware, replacing any
+// earlier one. If it's too late to remind, cancel the earlier one instead,
+// since the broadcast was rescheduled.
+async fn remind(
+    mq: &impl MessageQueue,
+    entity_id: Uuid,
+    broadcast: &Broadcast,
+    now: SystemTime,
+    rescheduled: bool,
+) {
+    let at = remind_at(broadcast);
+    if at <= now {
+        if rescheduled {
+            cancel_reminder(mq, entity_id, broadcast).await;
+        }
+        return;
+    }
+
+    let at = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let middlewares = "delay"
+        .parse::<Middlewares>()
+        .unwrap()
+        .with_priority(Priority::High);
+    publish_reminder(
+        mq,
+        entity_id,
+        broadcast,
+        "x-delay-at",
+        at.into(),
+        middlewares,
+        Some(broadcast.start_at),
+    )
+    .await;
+}
+
+async fn cancel_reminder(mq: &impl MessageQueue, entity_id: Uuid, broadcast: &Broadcast) {
+    let middlewares = "delay".parse::<Middlewares>().unwrap();
+    publish_reminder(
+        mq,
+        entity_id,
+        broadcast,
+        "x-delay-cancel",
+        true.into(),
+        middlewares,
+        None,
+    )
+    .await;
+}
+
+// Publish a reminder event to the delay middleware, with `field` telling it
+// what to do besides the id of the reminder. Reminders are stale once
+// `expires_at` passes, if set.
+async fn publish_reminder(
+    mq: &impl MessageQueue,
+    entity_id: Uuid,
+    broadcast: &Broadcast,
+    field: &str,
+    value: serde_json::Value,
+    middlewares: Middlewares,
+    expires_at: Option<SystemTime>,
+) {
+    let video_id = &broadcast.video_id;
+    let payload = BroadcastReminderPayload(broadcast.payload.clone());
+    let mut event = match Event::from_payload(entity_id, &payload) {
+        Ok(event) => event,
+        Err(error) => {
+            error!(?error, %video_id, "Failed to build reminder event");
+            return;
+        }
+    };
+    event
+        .fields
+        .insert("x-delay-id".to_owned(), delay_id(video_id).into());
+    event.fields.insert(field.to_owned(), value);
+    if let Some(at) = expires_at {
+        event = event.with_expiry(at);
+    }
+    if let Err(error) = mq.publish(event, middlewares).await {
+        error!(?error, %video_id, "Failed to publish reminder event");
+    }
+}
+
+fn remind_at(broadcast: &Broadcast) -> SystemTime {
+    broadcast.start_at - REMIND_BEFORE
+}
+
+// Id of the reminder of a broadcast in the delay middleware, so that
+// rescheduling or cancelling it replaces the earlier one, even from a
+// restarted worker.
+#[allow(clippy::cast_possible_wrap)]
+fn delay_id(video_id: &str) -> i64 {
+    stable_hash(video_id.bytes()) as i64
+}
@@ -0,0 +1,411 @@
+//! Upcoming and live broadcasts of youtube channels.
+
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use reqwest::{Client, Response, StatusCode};
+use serde::Deserialize;
+use sg_core::{
+    error::{Category, Error},
+    models::BroadcastPayload,
+};
+
+/// Base url of the youtube Data API.
+const API: &str = "https://www.googleapis.com/youtube/v3";
+
+/// Number of recent uploads looked through for upcoming broadcasts. It's the
+/// max page size, which costs as much quota as smaller ones.
+const RECENT_UPLOADS: &str = "50";
+
+/// Max count of videos looked up in one request.
+const MAX_VIDEOS: usize = 50;
+
+/// Duration of videos of live streams. Premieres have the duration of the
+/// uploaded video instead.
+const LIVE_DURATION: &str = "P0D";
+
+#[derive(Debug, Deserialize)]
+struct PlaylistItems {
+    #[serde(default)]
+    items: Vec<PlaylistItem>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistItem {
+    content_details: PlaylistItemDetails,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistItemDetails {
+    video_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Videos {
+    #[serde(default)]
+    items: Vec<Video>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Video {
+    id: String,
+    snippet: VideoSnippet,
+    content_details: Option<VideoContentDetails>,
+    live_streaming_details: Option<LiveStreamingDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VideoSnippet {
+    title: String,
+    live_broadcast_content: String,
+    #[serde(default)]
+    thumbnails: Thumbnails,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoContentDetails {
+    duration: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Thumbnails {
+    default: Option<Thumbnail>,
+    medium: Option<Thumbnail>,
+    high: Option<Thumbnail>,
+    maxres: Option<Thumbnail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Thumbnail {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LiveStreamingDetails {
+    scheduled_start_time: Option<String>,
+    actual_start_time: Option<String>,
+}
+
+/// A scheduled or live stream or premiere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broadcast {
+    /// Id of the video of the broadcast.
+    pub video_id: String,
+    /// Scheduled start time, or actual start time if it's live.
+    pub start_at: SystemTime,
+    /// Payload of events about the broadcast.
+    pub payload: BroadcastPayload,
+    /// Whether the broadcast is live instead of upcoming.
+    pub live: bool,
+    /// Whether the broadcast is for members of the channel only.
+    pub members_only: bool,
+    /// Whether the broadcast is a premiere of an uploaded video instead of a
+    /// live stream.
+    pub premiere: bool,
+}
+
+/// Client of the youtube Data API.
+pub struct Youtube {
+    http: Client,
+    key: String,
+}
+
+impl Youtube {
+    /// Create a client authorized by the API key.
+    #[must_use]
+    pub fn new(key: String) -> Self {
+        Self {
+            http: Client::new(),
+            key,
+        }
+    }
+
+    /// Broadcasts of the channel scheduled or live, found in its recent
+    /// uploads and members-only uploads. It costs 3 units of quota, or 4 if
+    /// there are members-only uploads not among recent ones, while searching
+    /// for upcoming videos costs 100.
+    ///
+    /// # Errors
+    /// Returns an error if the channel id is invalid, or youtube fails to
+    /// respond with the uploads.
+    pub async fn broadcasts(&self, channel_id: &str) -> Result<Vec<Broadcast>> {
+        let invalid = || Error::permanent(format!("Invalid youtube channel id: {channel_id}"));
+        let playlist_id = uploads_playlist(channel_id).ok_or_else(invalid)?;
+        let members_playlist_id = members_playlist(channel_id).ok_or_else(invalid)?;
+
+        let mut ids = self
+            .playlist_videos(&playlist_id)
+            .await?
+            .ok_or_else(|| Error::permanent(format!("Youtube channel not found: {channel_id}")))?;
+        // Channels without memberships have no members-only playlist.
+        let members_only: HashSet<_> = self
+            .playlist_videos(&members_playlist_id)
+            .await?
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        let older: Vec<_> = members_only
+            .iter()
+            .filter(|id| !ids.contains(id))
+            .cloned()
+            .collect();
+        ids.extend(older);
+
+        let mut broadcasts = vec![];
+        for ids in ids.chunks(MAX_VIDEOS) {
+            let resp = self
+                .http
+                .get(format!("{API}/videos"))
+                .query(&[
+                    ("part", "snippet,contentDetails,liveStreamingDetails"),
+                    ("id", &ids.join(",")),
+                    ("key", &self.key),
+                ])
+                .send()
+                .await?;
+            let videos: Videos = checked(resp)
+                .await?
+                .json()
+                .await
+                .wrap_err("Invalid response from youtube")?;
+            broadcasts.extend(pending_broadcasts(videos, &members_only));
+        }
+        Ok(broadcasts)
+    }
+
+    /// Ids of the most recent videos in a playlist, or `None` if the playlist
+    /// doesn't exist.
+    async fn playlist_videos(&self, playlist_id: &str) -> Result<Option<Vec<String>>> {
+        let resp = self
+            .http
+            .get(format!("{API}/playlistItems"))
+            .query(&[
+                ("part", "contentDetails"),
+                ("playlistId", playlist_id),
+                ("maxResults", RECENT_UPLOADS),
+                ("key", &self.key),
+            ])
+            .send()
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let items: PlaylistItems = checked(resp)
+            .await?
+            .json()
+            .await
+            .wrap_err("Invalid response from youtube")?;
+        Ok(Some(
+            items
+                .items
+                .into_iter()
+                .map(|item| item.content_details.video_id)
+                .collect(),
+        ))
+    }
+}
+
+/// Fail on error statuses of a response. Running out of quota is transient,
+/// since quota is reset daily, unlike other forbidden requests, e.g. with a
+/// revoked key.
+async fn checked(resp: Response) -> Result<Response> {
+    if resp.status() == StatusCode::FORBIDDEN {
+        let body = resp.text().await?;
+        let category = if body.contains("quotaExceeded") {
+            Category::Transient
+        } else {
+            Category::Auth
+        };
+        return Err(Error::new(category, format!("Forbidden by youtube: {body}")).into());
+    }
+    Ok(resp.error_for_status()?)
+}
+
+/// Id of the playlist of uploads of a channel, e.g. `UUxxx` for `UCxxx`.
+fn uploads_playlist(channel_id: &str) -> Option<String> {
+    channel_id
+        .strip_prefix("UC")
+        .map(|suffix| format!("UU{suffix}"))
+}
+
+/// Id of the playlist of members-only uploads of a channel, e.g. `UUMOxxx`
+/// for `UCxxx`.
+fn members_playlist(channel_id: &str) -> Option<String> {
+    channel_id
+        .strip_prefix("UC")
+        .map(|suffix| format!("UUMO{suffix}"))
+}
+
+/// Pick upcoming and live broadcasts out of videos, skipping those without a
+/// valid start time. Broadcasts of `members_only` videos are for members
+/// only.
+fn pending_broadcasts(videos: Videos, members_only: &HashSet<String>) -> Vec<Broadcast> {
+    videos
+        .items
+        .into_iter()
+        .filter_map(|video| {
+            let live = match video.snippet.live_broadcast_content.as_str() {
+                "upcoming" => false,
+                "live" => true,
+                _ => return None,
+            };
+            let details = video.live_streaming_details?;
+            let start_at = if live {
+                details.actual_start_time.or(details.scheduled_start_time)
+            } else {
+                details.scheduled_start_time
+            };
+            let start_at = start_at.and_then(|time| humantime::parse_rfc3339_weak(&time).ok())?;
+            let premiere = video
+                .content_details
+                .is_some_and(|details| details.duration != LIVE_DURATION);
+            let Thumbnails {
+                default,
+                medium,
+                high,
+                maxres,
+            } = video.snippet.thumbnails;
+            let payload = BroadcastPayload {
+                title: video.snippet.title,
+                link: format!("https://www.youtube.com/watch?v={}", video.id),
+                cover: maxres
+                    .or(high)
+                    .or(medium)
+                    .or(default)
+                    .map(|thumbnail| thumbnail.url),
+                start_at: start_at.duration_since(UNIX_EPOCH).ok()?.as_secs(),
+            };
+            Some(Broadcast {
+                members_only: members_only.contains(&video.id),
+                video_id: video.id,
+                start_at,
+                payload,
+                live,
+                premiere,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use serde_json::json;
+
+    use crate::youtube::{members_playlist, pending_broadcasts, uploads_playlist};
+
+    #[test]
+    fn must_find_uploads() {
+        assert_eq!(
+            uploads_playlist("UC5CwaMl1eIgY8h02uZw7u8A").as_deref(),
+            Some("UU5CwaMl1eIgY8h02uZw7u8A")
+        );
+        assert_eq!(
+            members_playlist("UC5CwaMl1eIgY8h02uZw7u8A").as_deref(),
+            Some("UUMO5CwaMl1eIgY8h02uZw7u8A")
+        );
+        assert_eq!(uploads_playlist("@suisei"), None);
+    }
+
+    #[test]
+    fn must_parse_upcoming() {
+        let videos = json!({
+            "items": [
+                {
+                    "id": "upcoming",
+                    "snippet": {
+                        "title": "Premiere",
+                        "liveBroadcastContent": "upcoming",
+                        "thumbnails": {
+                            "default": { "url": "https://i.ytimg.com/vi/upcoming/default.jpg" },
+                            "high": { "url": "https://i.ytimg.com/vi/upcoming/hqdefault.jpg" },
+                        },
+                    },
+                    "liveStreamingDetails": { "scheduledStartTime": "2023-11-14T22:13:20Z" },
+                },
+                {
+                    "id": "live",
+                    "snippet": { "title": "Live", "liveBroadcastContent": "live" },
+                    "liveStreamingDetails": { "scheduledStartTime": "2023-11-14T22:13:20Z" },
+                },
+                {
+                    "id": "ended",
+                    "snippet": { "title": "Ended", "liveBroadcastContent": "none" },
+                    "liveStreamingDetails": { "scheduledStartTime": "2023-11-14T22:13:20Z" },
+                },
+                {
+                    "id": "video",
+                    "snippet": { "title": "Video", "liveBroadcastContent": "none" },
+                },
+                {
+                    "id": "unscheduled",
+                    "snippet": { "title": "Unscheduled", "liveBroadcastContent": "upcoming" },
+                    "liveStreamingDetails": {},
+                },
+            ],
+        });
+        let broadcasts =
+            pending_broadcasts(serde_json::from_value(videos).unwrap(), &HashSet::new());
+
+        assert_eq!(broadcasts.len(), 2);
+        let broadcast = &broadcasts[0];
+        assert_eq!(broadcast.video_id, "upcoming");
+        assert!(!broadcast.live);
+        assert!(broadcasts[1].live);
+        assert_eq!(
+            broadcast.start_at,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+        assert_eq!(broadcast.payload.start_at, 1_700_000_000);
+        assert_eq!(
+            broadcast.payload.link,
+            "https://www.youtube.com/watch?v=upcoming"
+        );
+        assert_eq!(
+            broadcast.payload.cover.as_deref(),
+            Some("https://i.ytimg.com/vi/upcoming/hqdefault.jpg")
+        );
+    }
+    #[test]
+    fn must_detect_members_only_and_premieres() {
+        let videos = json!({
+            "items": [
+                {
+                    "id": "members",
+                    "snippet": { "title": "Members", "liveBroadcastContent": "live" },
+                    "contentDetails": { "duration": "P0D" },
+                    "liveStreamingDetails": {
+                        "scheduledStartTime": "2023-11-14T22:00:00Z",
+                        "actualStartTime": "2023-11-14T22:13:20Z",
+                    },
+                },
+                {
+                    "id": "premiere",
+                    "snippet": { "title": "Premiere", "liveBroadcastContent": "upcoming" },
+                    "contentDetails": { "duration": "PT3M41S" },
+                    "liveStreamingDetails": { "scheduledStartTime": "2023-11-14T22:13:20Z" },
+                },
+            ],
+        });
+        let members_only = HashSet::from([String::from("members")]);
+        let broadcasts = pending_broadcasts(serde_json::from_value(videos).unwrap(), &members_only);
+
+        let (members, premiere) = (&broadcasts[0], &broadcasts[1]);
+        assert!(members.live && members.members_only && !members.premiere);
+        // Live broadcasts start at their actual start time.
+        assert_eq!(members.payload.start_at, 1_700_000_000);
+        assert!(!premiere.live && !premiere.members_only && premiere.premiere);
+    }
+}